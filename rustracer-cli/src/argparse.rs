@@ -1,4 +1,20 @@
-use clap::{App, Arg, ArgMatches};
+use clap::{App, Arg, ArgMatches, SubCommand};
+
+/// Smallest relative error `--estimate-spp` accepts: below that, the suggested counts would only
+/// ever be the maximum the estimate allows.
+const MIN_TARGET_ERROR: f32 = 1e-3;
+
+/// Parse the relative error given to `--estimate-spp`, which must be between `MIN_TARGET_ERROR`
+/// and 1.
+pub fn parse_target_error(e: &str) -> Result<f32, String> {
+    match e.parse::<f32>() {
+        Ok(e) if (MIN_TARGET_ERROR..=1.0).contains(&e) => Ok(e),
+        _ => Err(format!(
+            "the target relative error must be a number between {} and 1",
+            MIN_TARGET_ERROR
+        )),
+    }
+}
 
 pub fn parse_args() -> ArgMatches {
     App::new("rustracer")
@@ -12,27 +28,210 @@ pub fn parse_args() -> ArgMatches {
                 .help("Output file name")
                 .default_value("image.png"),
         )
+        .arg(
+            Arg::with_name("outdir")
+                .long("outdir")
+                .help("Directory to write the output images in (created if missing)")
+                .takes_value(true),
+        )
         .arg(
             Arg::with_name("nthreads")
                 .long("nthreads")
+                .visible_alias("threads")
                 .short('t')
                 .help("Number of worker threads")
                 .takes_value(true),
         )
+        .arg(
+            Arg::with_name("numa")
+                .long("numa")
+                .help("Pin worker threads to NUMA nodes and allocate their memory locally"),
+        )
+        .arg(
+            Arg::with_name("max-memory")
+                .long("max-memory")
+                .help("Abort if building the scene uses more than this much memory (e.g. 512M, 8G)")
+                .value_name("SIZE")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("time-budget")
+                .long("time-budget")
+                .help("Render progressively and stop after this many seconds")
+                .value_name("SECONDS")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("dump-geometry")
+                .long("dump-geometry")
+                .help("Write the scene's triangles in world space to a PLY or OBJ file")
+                .value_name("FILE")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("dump-samples")
+                .long("dump-samples")
+                .help("Write every sample of the pixels given by --dump-pixels to a CSV or JSON file")
+                .value_name("FILE")
+                .takes_value(true)
+                .requires("dump-pixels"),
+        )
+        .arg(
+            Arg::with_name("dump-pixels")
+                .long("dump-pixels")
+                .help("Pixels whose samples are dumped, as X,Y or X0,Y0,X1,Y1 (exclusive upper bounds)")
+                .value_name("PIXELS")
+                .takes_value(true)
+                .requires("dump-samples"),
+        )
+        .arg(
+            Arg::with_name("auto-frame")
+                .long("auto-frame")
+                .help("Move the camera so that it frames the whole scene"),
+        )
+        .arg(
+            Arg::with_name("secondary-differentials")
+                .long("secondary-differentials")
+                .help("Filter the textures and environment maps seen by BSDF-sampled rays"),
+        )
+        .arg(
+            Arg::with_name("cache-scene")
+                .long("cache-scene")
+                .help("Cache the tokens of the scene files (<file>.rtcache) to lex them faster next time"),
+        )
+        .arg(
+            Arg::with_name("raw-roughness")
+                .long("raw-roughness")
+                .help("Interpret material roughness as the microfacet alpha by default (pbrt-v4 scenes)"),
+        )
+        .arg(
+            Arg::with_name("quick")
+                .long("quick")
+                .help("Render a fast, low quality approximation of the scene"),
+        )
+        .arg(
+            Arg::with_name("tile-heatmap")
+                .long("tile-heatmap")
+                .help("Write an image of the time spent rendering each tile (<output>_time.png)"),
+        )
+        .arg(
+            Arg::with_name("interactive")
+                .long("interactive")
+                .short('i')
+                .help("After rendering, orbit the camera with keyboard commands and re-render"),
+        )
+        .arg(
+            Arg::with_name("watch")
+                .long("watch")
+                .help("Render the scene again whenever it or any of the files it uses changes")
+                .conflicts_with("interactive"),
+        )
+        .arg(
+            Arg::with_name("turntable")
+                .long("turntable")
+                .help("Render this many numbered frames of the camera turning around the scene")
+                .value_name("FRAMES")
+                .takes_value(true)
+                .conflicts_with_all(&["interactive", "watch"]),
+        )
+        .arg(
+            Arg::with_name("estimate-spp")
+                .long("estimate-spp")
+                .help("Render a sparse pilot pass and suggest the samples per pixel needed to get under this relative error (e.g. 0.05)")
+                .value_name("ERROR")
+                .takes_value(true)
+                .validator(parse_target_error)
+                .conflicts_with_all(&["interactive", "watch", "turntable"]),
+        )
+        .arg(
+            Arg::with_name("render-estimate")
+                .long("render-estimate")
+                .help("Render the scene with the samples per pixel suggested by --estimate-spp")
+                .requires("estimate-spp"),
+        )
         .arg(
             Arg::with_name("verbose")
+                .long("verbose")
                 .short('v')
-                .help("log debug information"),
+                .help("Log more information: debug with -v, trace with -vv")
+                .multiple_occurrences(true),
+        )
+        .arg(
+            Arg::with_name("log")
+                .long("log")
+                .help("Set the log level of some subsystems (e.g. --log bvh=debug,texture=warn)")
+                .value_name("SUBSYSTEM=LEVEL,...")
+                .takes_value(true),
         )
         .arg(
             Arg::with_name("display")
                 .short('p')
                 .help("Display image as it is rendered"),
         )
+        .arg(
+            Arg::with_name("list-capabilities")
+                .long("list-capabilities")
+                .help("List the types of cameras, shapes, materials, etc. supported by this build"),
+        )
         .arg(
             Arg::with_name("INPUT")
-                .required(true)
+                .required_unless_present("list-capabilities")
                 .help("PBRT scene file to render"),
         )
+        .subcommand_negates_reqs(true)
+        .subcommand(
+            SubCommand::with_name("gen-test-scene")
+                .about("Write one of the built-in test scenes")
+                .arg(
+                    Arg::with_name("list")
+                        .long("list")
+                        .short('l')
+                        .help("List the available test scenes and their parameters"),
+                )
+                .arg(
+                    Arg::with_name("param")
+                        .long("param")
+                        .short('D')
+                        .help("Set a parameter of the scene (e.g. -D spp=64)")
+                        .value_name("NAME=VALUE")
+                        .takes_value(true)
+                        .multiple_occurrences(true)
+                        .number_of_values(1),
+                )
+                .arg(
+                    Arg::with_name("output")
+                        .long("output")
+                        .short('o')
+                        .help("Scene file to write (defaults to the standard output)")
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::with_name("SCENE")
+                        .required_unless_present("list")
+                        .help("Name of the test scene"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("probe")
+                .about("Estimate the direct illuminance at some points of a scene")
+                .arg(
+                    Arg::with_name("samples")
+                        .long("samples")
+                        .short('n')
+                        .help("Number of samples per light")
+                        .default_value("1024"),
+                )
+                .arg(
+                    Arg::with_name("INPUT")
+                        .required(true)
+                        .help("PBRT scene file"),
+                )
+                .arg(
+                    Arg::with_name("POINT")
+                        .required(true)
+                        .multiple_values(true)
+                        .help("Points to probe, as X,Y,Z or X,Y,Z,NX,NY,NZ (the normal defaults to +y)"),
+                ),
+        )
         .get_matches()
 }
@@ -0,0 +1,34 @@
+use anyhow::Result;
+use rustracer_core::renderer::RenderContext;
+use rustracer_core::sppestimate::{self, SppEstimateOptions, MAX_SPP};
+
+/// Suggest the number of samples per pixel needed for the pixels to get under `target_error`
+/// (see the `sppestimate` module), and render the scene with it if `render` is true.
+pub fn run(mut context: RenderContext, target_error: f32, render: bool) -> Result<()> {
+    let options = SppEstimateOptions::new(target_error);
+    let estimate = sppestimate::estimate_spp(&mut context, &options);
+    println!(
+        "Pilot pass of {} pixels: the median pixel needs {:.0} spp, {:.0}% of them need at most {:.0} spp for a relative error of {}",
+        estimate.pixels,
+        estimate.median_spp.ceil(),
+        options.percentile * 100.0,
+        estimate.percentile_spp.ceil(),
+        target_error
+    );
+    if estimate.percentile_spp > MAX_SPP as f32 {
+        println!(
+            "Warning: the suggestion is capped to {} spp, the target error may not be reached",
+            MAX_SPP
+        );
+    }
+    println!(
+        "Suggested samples per pixel: {} (the scene asks for {})",
+        estimate.spp,
+        context.sampler.spp()
+    );
+    if render {
+        context.sampler = context.sampler.with_spp(estimate.spp);
+        context.render()?;
+    }
+    Ok(())
+}
@@ -0,0 +1,49 @@
+use std::fs;
+use std::io::{self, Write};
+
+use anyhow::{anyhow, Context, Result};
+use clap::ArgMatches;
+
+use rustracer_core::testscenes::{self, TEST_SCENES};
+
+pub fn run(matches: &ArgMatches) -> Result<()> {
+    if matches.is_present("list") {
+        for scene in TEST_SCENES {
+            println!("{}: {}", scene.name, scene.description);
+            for (name, default) in scene.params {
+                println!("    {} (default: {})", name, default);
+            }
+        }
+        return Ok(());
+    }
+
+    let name = matches.value_of("SCENE").unwrap();
+    let scene = testscenes::find(name).ok_or_else(|| {
+        anyhow!(
+            "Unknown test scene \"{}\" (available: {})",
+            name,
+            TEST_SCENES
+                .iter()
+                .map(|s| s.name)
+                .collect::<Vec<_>>()
+                .join(", ")
+        )
+    })?;
+    let params = matches
+        .values_of("param")
+        .into_iter()
+        .flatten()
+        .map(|p| {
+            p.split_once('=')
+                .map(|(n, v)| (n.trim().to_owned(), v.trim().to_owned()))
+                .ok_or_else(|| anyhow!("Invalid parameter \"{}\": expected NAME=VALUE", p))
+        })
+        .collect::<Result<Vec<_>>>()?;
+    let text = scene.generate(&params)?;
+
+    match matches.value_of("output") {
+        Some(path) => fs::write(path, text).with_context(|| format!("Failed to write {}", path))?,
+        None => io::stdout().write_all(text.as_bytes())?,
+    }
+    Ok(())
+}
@@ -0,0 +1,62 @@
+use std::io::{self, BufRead, Write};
+
+use anyhow::Result;
+use rustracer_core::camera::Orbit;
+use rustracer_core::renderer::RenderContext;
+
+/// Angle (in degrees) the camera turns by for each rotation command.
+const ROTATION_STEP: f32 = 15.0;
+/// Factor the distance to the target is multiplied by for each zoom command.
+const ZOOM_STEP: f32 = 0.8;
+
+const HELP: &str = "Commands (several can be combined on one line, e.g. \"aa+\"):
+  a/d  orbit left/right    w/s  orbit up/down
+  +/-  zoom in/out         r    reset the camera
+  q    quit                h    show this help";
+
+/// Orbit the camera around the centre of the scene according to commands read from stdin,
+/// re-rendering the scene after each one.
+pub fn run(mut context: RenderContext) -> Result<()> {
+    let bounds = context.scene.world_bounds();
+    let target = bounds.p_min + (bounds.p_max - bounds.p_min) * 0.5;
+    let initial = Orbit::from_camera(&*context.camera, &target);
+    let mut orbit = initial;
+
+    println!("{}", HELP);
+    let stdin = io::stdin();
+    loop {
+        print!("> ");
+        io::stdout().flush()?;
+        let mut line = String::new();
+        if stdin.lock().read_line(&mut line)? == 0 {
+            return Ok(());
+        }
+
+        let mut moved = false;
+        for c in line.trim().chars() {
+            match c {
+                'a' => orbit.rotate(-ROTATION_STEP, 0.0),
+                'd' => orbit.rotate(ROTATION_STEP, 0.0),
+                'w' => orbit.rotate(0.0, ROTATION_STEP),
+                's' => orbit.rotate(0.0, -ROTATION_STEP),
+                '+' => orbit.zoom(ZOOM_STEP),
+                '-' => orbit.zoom(1.0 / ZOOM_STEP),
+                'r' => orbit = initial,
+                'q' => return Ok(()),
+                'h' => {
+                    println!("{}", HELP);
+                    continue;
+                }
+                _ => {
+                    println!("Unknown command '{}'. Type 'h' for help.", c);
+                    continue;
+                }
+            }
+            moved = true;
+        }
+
+        if moved {
+            context.rerender_from(orbit.camera_to_world())?;
+        }
+    }
+}
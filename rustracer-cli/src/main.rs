@@ -1,20 +1,52 @@
 #![recursion_limit = "128"]
 
 mod argparse;
+mod estimate;
+mod gen_test_scene;
+mod interactive;
+mod probe;
+mod turntable;
+mod watch;
 
-use anyhow::Result;
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::time::Duration;
+
+use anyhow::{anyhow, Result};
 use clap::ArgMatches;
 
 use flexi_logger::FileSpec;
-use rustracer_core::{init_stats, pbrt, PbrtOptions};
+use log::LevelFilter;
+use rustracer_core::bounds::Bounds2i;
+use rustracer_core::sampledump::SampleDumpOptions;
+use rustracer_core::{cancel, fileutil, init_stats, pbrt, PbrtOptions, CAPABILITIES};
 
 fn main() {
+    let matches = argparse::parse_args();
+    // The scene goes to stdout, so don't print anything else
+    if let Some(matches) = matches.subcommand_matches("gen-test-scene") {
+        if let Err(ref e) = gen_test_scene::run(matches) {
+            eprintln!("Application error: {}", e);
+            ::std::process::exit(1);
+        }
+        return;
+    }
+    if matches.is_present("list-capabilities") {
+        for (directive, names) in CAPABILITIES {
+            println!("{}: {}", directive, names.join(", "));
+        }
+        return;
+    }
+
     println!("Rustracer 0.1 [Detected {} cores]", num_cpus::get());
     println!("Copyright (c)2016-2018 Antoine Büsch.");
     println!("Based on the original PBRTv3 code by Matt Pharr, Greg Humphreys, and Wenzel Jacob.");
-    let matches = argparse::parse_args();
 
-    flexi_logger::Logger::try_with_str("rustracer=info,rustracer_core=info")
+    let spec = log_spec(&matches).unwrap_or_else(|e| {
+        println!("Application error: {}", e);
+        ::std::process::exit(1);
+    });
+    flexi_logger::Logger::try_with_str(&spec)
         .unwrap()
         .log_to_file(FileSpec::default().suppress_timestamp().directory("/tmp"))
         .format(flexi_logger::opt_format)
@@ -22,23 +54,169 @@ fn main() {
         .unwrap_or_else(|e| panic!("Failed to initialize logger: {}", e));
 
     if let Err(ref e) = run(&matches) {
-        println!("Application error: {}", e);
+        println!("Application error: {:#}", e);
         ::std::process::exit(1);
     }
 }
 
+/// Build the logger specification from the command line: the default level depends on the
+/// number of `-v` flags, and `--log` overrides it for some subsystems (i.e. modules of
+/// `rustracer_core`, like `bvh` or `texture`).
+fn log_spec(matches: &ArgMatches) -> Result<String> {
+    let level = match matches.occurrences_of("verbose") {
+        0 => LevelFilter::Info,
+        1 => LevelFilter::Debug,
+        _ => LevelFilter::Trace,
+    };
+    let mut spec = format!("rustracer={},rustracer_core={}", level, level);
+    for entry in matches
+        .value_of("log")
+        .into_iter()
+        .flat_map(|l| l.split(','))
+    {
+        let (subsystem, level) = entry
+            .split_once('=')
+            .map(|(s, l)| (s.trim(), l.trim()))
+            .filter(|(s, _)| !s.is_empty())
+            .ok_or_else(|| {
+                anyhow!(
+                    "Invalid log setting \"{}\": expected SUBSYSTEM=LEVEL",
+                    entry
+                )
+            })?;
+        let level = LevelFilter::from_str(level)
+            .map_err(|_| anyhow!("Invalid log level \"{}\" for {}", level, subsystem))?;
+        spec.push_str(&format!(
+            ",rustracer_core::{}={}",
+            subsystem.replace('.', "::"),
+            level
+        ));
+    }
+    Ok(spec)
+}
+
+/// Parse a size in bytes, with an optional binary suffix (K, M, G or T).
+fn parse_size(size: &str) -> Result<u64> {
+    let size = size.trim();
+    let (number, unit) = match size.find(|c: char| c.is_ascii_alphabetic()) {
+        Some(i) => size.split_at(i),
+        None => (size, ""),
+    };
+    let scale: u64 = match unit.to_ascii_uppercase().trim_end_matches(['B', 'I']) {
+        "" => 1,
+        "K" => 1 << 10,
+        "M" => 1 << 20,
+        "G" => 1 << 30,
+        "T" => 1 << 40,
+        _ => return Err(anyhow!("Invalid size unit \"{}\" in \"{}\"", unit, size)),
+    };
+    let number: f64 = number
+        .trim()
+        .parse()
+        .map_err(|_| anyhow!("Invalid size \"{}\"", size))?;
+    if number < 0.0 {
+        return Err(anyhow!("Invalid size \"{}\"", size));
+    }
+    Ok((number * scale as f64) as u64)
+}
+
+/// Parse a pixel (`x,y`) or a rectangle of pixels (`x0,y0,x1,y1`, exclusive of `x1` and `y1`).
+fn parse_pixel_bounds(pixels: &str) -> Result<Bounds2i> {
+    let coords = pixels
+        .split(',')
+        .map(|c| c.trim().parse::<i32>())
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(|_| anyhow!("Invalid pixels \"{}\"", pixels))?;
+    match coords[..] {
+        [x, y] => Ok(Bounds2i::from_elements(x, y, x + 1, y + 1)),
+        [x0, y0, x1, y1] if x0 < x1 && y0 < y1 => Ok(Bounds2i::from_elements(x0, y0, x1, y1)),
+        _ => Err(anyhow!(
+            "Invalid pixels \"{}\": expected X,Y or X0,Y0,X1,Y1 with X0 < X1 and Y0 < Y1",
+            pixels
+        )),
+    }
+}
+
+/// Parse a (possibly fractional) number of seconds.
+fn parse_duration(seconds: &str) -> Result<Duration> {
+    seconds
+        .trim()
+        .parse::<f64>()
+        .ok()
+        .and_then(|s| Duration::try_from_secs_f64(s).ok())
+        .ok_or_else(|| anyhow!("Invalid duration \"{}\"", seconds))
+}
+
 fn run(matches: &ArgMatches) -> Result<()> {
     init_stats();
+    if let Some(matches) = matches.subcommand_matches("probe") {
+        return probe::run(matches);
+    }
     let nthreads = matches
         .value_of("nthreads")
         .and_then(|v| v.parse::<u8>().ok())
         .unwrap_or(0);
+    let turntable = matches
+        .value_of("turntable")
+        .map(|n| match n.parse::<u32>() {
+            Ok(n) if n > 0 => Ok(n),
+            _ => Err(anyhow!("Invalid number of turntable frames \"{}\"", n)),
+        })
+        .transpose()?;
+    let target_error = matches
+        .value_of("estimate-spp")
+        .map(argparse::parse_target_error)
+        .transpose()
+        .map_err(|e| anyhow!(e))?;
     let opts = PbrtOptions {
-        num_threads: nthreads as u8,
+        num_threads: nthreads,
+        quick_render: matches.is_present("quick"),
+        tile_heatmap: matches.is_present("tile-heatmap"),
+        interactive: matches.is_present("interactive"),
+        defer_render: turntable.is_some() || target_error.is_some(),
+        numa: matches.is_present("numa"),
+        max_memory: matches.value_of("max-memory").map(parse_size).transpose()?,
+        auto_frame: matches.is_present("auto-frame"),
+        raw_roughness: matches.is_present("raw-roughness"),
+        cache_scene: matches.is_present("cache-scene"),
+        secondary_differentials: matches.is_present("secondary-differentials"),
+        time_budget: matches
+            .value_of("time-budget")
+            .map(parse_duration)
+            .transpose()?,
+        dump_geometry: matches.value_of("dump-geometry").map(PathBuf::from),
+        dump_samples: match (
+            matches.value_of("dump-samples"),
+            matches.value_of("dump-pixels"),
+        ) {
+            (Some(path), Some(pixels)) => Some(SampleDumpOptions {
+                pixels: parse_pixel_bounds(pixels)?,
+                path: PathBuf::from(path),
+            }),
+            _ => None,
+        },
         ..PbrtOptions::default()
     };
+    if let Some(outdir) = matches.value_of("outdir") {
+        fileutil::set_output_directory(outdir);
+    }
     let filename = matches.value_of("INPUT").unwrap();
-    pbrt::parse_scene(opts, filename)?;
+    if matches.is_present("watch") {
+        return watch::run(opts, filename);
+    }
+    if !opts.interactive {
+        // Ctrl-C stops building the BVHs or rendering, rather than only after they're done
+        cancel::install_interrupt_handler();
+    }
+    if let Some(context) = pbrt::parse_scene(opts, filename)? {
+        match (turntable, target_error) {
+            (Some(frames), _) => turntable::run(context, frames)?,
+            (None, Some(target_error)) => {
+                estimate::run(context, target_error, matches.is_present("render-estimate"))?
+            }
+            (None, None) => interactive::run(context)?,
+        }
+    }
 
     Ok(())
 }
@@ -0,0 +1,60 @@
+//! `probe` subcommand: report the light arriving at some points of a scene, to check a lighting
+//! setup numerically rather than from a render.
+
+use anyhow::{anyhow, Context, Result};
+use clap::ArgMatches;
+
+use rustracer_core::{pbrt, Normal3f, PbrtOptions, Point3f};
+
+pub fn run(matches: &ArgMatches) -> Result<()> {
+    let n_samples = matches
+        .value_of("samples")
+        .unwrap()
+        .parse::<usize>()
+        .context("Invalid number of samples")?;
+    let probes = matches
+        .values_of("POINT")
+        .into_iter()
+        .flatten()
+        .map(parse_probe)
+        .collect::<Result<Vec<_>>>()?;
+
+    let opts = PbrtOptions {
+        defer_render: true,
+        ..PbrtOptions::default()
+    };
+    let filename = matches.value_of("INPUT").unwrap();
+    let mut context =
+        pbrt::parse_scene(opts, filename)?.ok_or_else(|| anyhow!("The scene has no WorldEnd"))?;
+    for (p, n) in probes {
+        let e = context
+            .scene
+            .incident_illuminance(&p, &n, &mut *context.sampler, n_samples);
+        println!(
+            "Point {} with normal {}: illuminance {:.6} (irradiance {})",
+            p,
+            n,
+            e.y(),
+            e
+        );
+    }
+
+    Ok(())
+}
+
+/// Parse a probe, given as `x,y,z` or `x,y,z,nx,ny,nz`. The normal defaults to +y.
+fn parse_probe(probe: &str) -> Result<(Point3f, Normal3f)> {
+    let values = probe
+        .split(',')
+        .map(|v| v.trim().parse::<f32>())
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(|_| anyhow!("Invalid point \"{}\"", probe))?;
+    match values[..] {
+        [x, y, z] => Ok((Point3f::new(x, y, z), Normal3f::new(0.0, 1.0, 0.0))),
+        [x, y, z, nx, ny, nz] => Ok((Point3f::new(x, y, z), Normal3f::new(nx, ny, nz))),
+        _ => Err(anyhow!(
+            "Invalid point \"{}\": expected X,Y,Z or X,Y,Z,NX,NY,NZ",
+            probe
+        )),
+    }
+}
@@ -0,0 +1,23 @@
+use anyhow::Result;
+use log::info;
+use rustracer_core::camera::Orbit;
+use rustracer_core::renderer::RenderContext;
+
+/// Render `frames` frames of the camera turning once around the centre of the scene, about its
+/// up axis. The scene and its acceleration structures are built once and reused for every frame,
+/// and each frame is written to its own numbered images (see `Film::set_frame_number()`).
+pub fn run(mut context: RenderContext, frames: u32) -> Result<()> {
+    let bounds = context.scene.world_bounds();
+    let target = bounds.p_min + (bounds.p_max - bounds.p_min) * 0.5;
+    let mut orbit = Orbit::from_camera(&*context.camera, &target);
+    let step = 360.0 / frames as f32;
+
+    for frame in 0..frames {
+        println!("Rendering turntable frame {}/{}", frame + 1, frames);
+        info!("Rendering turntable frame {}", frame);
+        context.camera.get_film_mut().set_frame_number(frame);
+        context.rerender_from(orbit.camera_to_world())?;
+        orbit.rotate(step, 0.0);
+    }
+    Ok(())
+}
@@ -0,0 +1,95 @@
+use std::collections::hash_map::DefaultHasher;
+use std::collections::HashSet;
+use std::fs;
+use std::hash::{Hash, Hasher};
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::{channel, Receiver};
+use std::time::Duration;
+
+use anyhow::{anyhow, Result};
+use notify_debouncer_mini::notify::RecursiveMode;
+use notify_debouncer_mini::{new_debouncer, DebounceEventResult};
+use rustracer_core::fileutil::Dependencies;
+use rustracer_core::{pbrt, PbrtOptions};
+
+/// How long the files have to be left alone before a render starts, so that an editor saving
+/// several files (or writing one in several steps) only triggers one render.
+const DEBOUNCE_TIMEOUT: Duration = Duration::from_millis(250);
+
+/// State of a file the scene depends on, used to detect changes.
+struct FileState {
+    path: PathBuf,
+    hash: Option<u64>,
+}
+
+impl FileState {
+    fn new(path: &Path) -> FileState {
+        let path = fs::canonicalize(path).unwrap_or_else(|_| path.to_owned());
+        FileState {
+            hash: content_hash(&path),
+            path,
+        }
+    }
+}
+
+/// Render the scene, then render it again every time the scene file or any of the files it
+/// depends on is modified. A modification alone doesn't trigger a render if the content of the
+/// files is the same. The scene is loaded from scratch every time, except for the tokens of the
+/// unchanged scene files with `--cache-scene`, whose cache is keyed by their hash.
+///
+/// The directories of the files are watched rather than the files themselves, as editors often
+/// save a file by replacing it.
+pub fn run(opts: PbrtOptions, filename: &str) -> Result<()> {
+    loop {
+        let dependencies = Dependencies::default();
+        if let Err(e) = pbrt::parse_scene_recording(opts.clone(), filename, &dependencies) {
+            println!("Failed to render scene: {}", e);
+        }
+        let mut files: Vec<FileState> = dependencies
+            .paths()
+            .iter()
+            .map(|p| FileState::new(p))
+            .collect();
+        if files.is_empty() {
+            files.push(FileState::new(Path::new(filename)));
+        }
+
+        let (tx, rx) = channel();
+        let mut debouncer = new_debouncer(DEBOUNCE_TIMEOUT, tx)?;
+        let directories: HashSet<&Path> = files.iter().filter_map(|f| f.path.parent()).collect();
+        for dir in directories {
+            debouncer
+                .watcher()
+                .watch(dir, RecursiveMode::NonRecursive)?;
+        }
+        println!(
+            "Watching {} file(s) for changes. Press Ctrl-C to exit.",
+            files.len()
+        );
+
+        wait_for_changes(&mut files, &rx)?;
+        println!("Scene modified, rendering again...");
+    }
+}
+
+fn wait_for_changes(files: &mut [FileState], events: &Receiver<DebounceEventResult>) -> Result<()> {
+    loop {
+        let changed: HashSet<PathBuf> = match events.recv()? {
+            Ok(events) => events.into_iter().map(|e| e.path).collect(),
+            Err(e) => return Err(anyhow!("Failed to watch the scene files: {}", e)),
+        };
+        for f in files.iter_mut().filter(|f| changed.contains(&f.path)) {
+            let hash = content_hash(&f.path);
+            if hash != f.hash {
+                return Ok(());
+            }
+        }
+    }
+}
+
+fn content_hash(path: &Path) -> Option<u64> {
+    let content = fs::read(path).ok()?;
+    let mut hasher = DefaultHasher::new();
+    content.hash(&mut hasher);
+    Some(hasher.finish())
+}
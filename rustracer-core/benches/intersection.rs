@@ -0,0 +1,162 @@
+//! Ray intersection: a single triangle, and the traversal of the acceleration structures of two
+//! canned scenes (analytic spheres in a single BVH, instanced triangle meshes in a TLAS).
+
+use std::sync::Arc;
+
+use criterion::{black_box, criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+
+use rustracer_core::bounds::Bounds3f;
+use rustracer_core::pbrt;
+use rustracer_core::ray::Ray;
+use rustracer_core::rng::RNG;
+use rustracer_core::scene::Scene;
+use rustracer_core::shapes::{AttributeIndices, Shape, Triangle, TriangleMesh};
+use rustracer_core::{PbrtOptions, Point3f, Transform, Vector3f};
+
+const N_RAYS: usize = 4096;
+
+/// Unit icosphere with 1280 triangles
+const ICOSPHERE: &str = include_str!("assets/icosphere.pbrt");
+
+/// A 10x10x10 grid of spheres.
+fn spheres_scene() -> String {
+    let mut world = String::new();
+    for i in 0..1000 {
+        world.push_str(&format!(
+            "AttributeBegin\n  Translate {} {} {}\n  Shape \"sphere\" \"float radius\" [0.4]\nAttributeEnd\n",
+            i % 10,
+            (i / 10) % 10,
+            i / 100
+        ));
+    }
+    world
+}
+
+/// A 5x5x5 grid of instances of the icosphere.
+fn instances_scene() -> String {
+    let mut world = format!("ObjectBegin \"icosphere\"\n{}ObjectEnd\n", ICOSPHERE);
+    for i in 0..125 {
+        world.push_str(&format!(
+            "AttributeBegin\n  Translate {} {} {}\n  ObjectInstance \"icosphere\"\nAttributeEnd\n",
+            2 * (i % 5),
+            2 * ((i / 5) % 5),
+            2 * (i / 25)
+        ));
+    }
+    world
+}
+
+fn load_scene(world: &str) -> Arc<Scene> {
+    let description = format!(
+        "Film \"image\" \"integer xresolution\" [16] \"integer yresolution\" [16]\n\
+         Camera \"perspective\"\nSampler \"02sequence\"\nWorldBegin\n{}WorldEnd\n",
+        world
+    );
+    let opts = PbrtOptions {
+        defer_render: true,
+        ..PbrtOptions::default()
+    };
+    pbrt::parse_scene_string(opts, &description)
+        .unwrap_or_else(|e| panic!("failed to load the benchmark scene: {:#}", e))
+        .expect("no render context")
+        .scene
+}
+
+/// Rays from random points on a sphere around `bounds` towards random points inside them, so
+/// that most of them hit something.
+fn rays(bounds: &Bounds3f) -> Vec<Ray> {
+    let mut rng = RNG::new();
+    let (centre, radius) = bounds.bounding_sphere();
+    (0..N_RAYS)
+        .map(|_| {
+            let o = centre
+                + 2.0
+                    * radius
+                    * Vector3f::new(
+                        rng.uniform_f32() - 0.5,
+                        rng.uniform_f32() - 0.5,
+                        rng.uniform_f32() - 0.5,
+                    )
+                    .normalize();
+            let target = bounds.lerp(&Point3f::new(
+                rng.uniform_f32(),
+                rng.uniform_f32(),
+                rng.uniform_f32(),
+            ));
+            Ray::new(o, (target - o).normalize())
+        })
+        .collect()
+}
+
+fn triangle(c: &mut Criterion) {
+    let mesh = Arc::new(TriangleMesh::new(
+        &Transform::default(),
+        &[0, 1, 2],
+        &[
+            Point3f::new(0.0, 0.0, 0.0),
+            Point3f::new(1.0, 0.0, 0.0),
+            Point3f::new(0.0, 1.0, 0.0),
+        ],
+        None,
+        None,
+        None,
+        AttributeIndices::default(),
+        None,
+        None,
+        false,
+    ));
+    let tri = Triangle::new(mesh, 0, false);
+    // About half of the rays hit the triangle
+    let rays = rays(&Bounds3f::from_points(
+        &Point3f::new(0.0, 0.0, 0.0),
+        &Point3f::new(1.0, 1.0, 0.0),
+    ));
+
+    let mut group = c.benchmark_group("triangle");
+    group.throughput(Throughput::Elements(rays.len() as u64));
+    group.bench_function("intersect", |b| {
+        b.iter(|| {
+            rays.iter()
+                .filter(|r| black_box(tri.intersect(r)).is_some())
+                .count()
+        })
+    });
+    group.bench_function("intersect_p", |b| {
+        b.iter(|| {
+            rays.iter()
+                .filter(|r| tri.intersect_p(black_box(r)))
+                .count()
+        })
+    });
+    group.finish();
+}
+
+fn bvh(c: &mut Criterion) {
+    let mut group = c.benchmark_group("bvh");
+    for (name, world) in &[
+        ("spheres", spheres_scene()),
+        ("instances", instances_scene()),
+    ] {
+        let scene = load_scene(world);
+        let rays = rays(&scene.world_bounds());
+        group.throughput(Throughput::Elements(rays.len() as u64));
+        group.bench_with_input(BenchmarkId::new("intersect", name), &rays, |b, rays| {
+            b.iter(|| {
+                rays.iter()
+                    .filter(|r| scene.intersect(&mut black_box(**r)).is_some())
+                    .count()
+            })
+        });
+        group.bench_with_input(BenchmarkId::new("intersect_p", name), &rays, |b, rays| {
+            b.iter(|| {
+                rays.iter()
+                    .filter(|r| scene.intersect_p(black_box(r)))
+                    .count()
+            })
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, triangle, bvh);
+criterion_main!(benches);
@@ -0,0 +1,123 @@
+//! Rendering a small scene with the path integrator, through the two render paths: with the
+//! integrator, camera and sampler as trait objects (as for the scenes loaded by the parser), and
+//! monomorphized for their concrete types with `RenderBuilder`.
+
+use std::sync::Arc;
+
+use criterion::{criterion_group, criterion_main, Criterion, Throughput};
+
+use rustracer_core::bounds::Bounds2f;
+use rustracer_core::camera::{Camera, PerspectiveCamera, Shutter};
+use rustracer_core::film::Film;
+use rustracer_core::filter::BoxFilter;
+use rustracer_core::integrator::{PathIntegrator, SamplerIntegrator};
+use rustracer_core::pbrt;
+use rustracer_core::renderer::{self, RenderBuilder, WorkerThreads};
+use rustracer_core::sampler::zerotwosequence::ZeroTwoSequence;
+use rustracer_core::sampler::Sampler;
+use rustracer_core::scene::Scene;
+use rustracer_core::{init_stats, PbrtOptions, Point2f, Point2i, Transform, Vector3f};
+
+const RESOLUTION: i32 = 32;
+const SPP: usize = 16;
+
+/// A 4x4 grid of matte spheres lit by an emissive one.
+fn scene() -> Arc<Scene> {
+    let mut world = String::from(
+        "AttributeBegin\n  AreaLightSource \"diffuse\" \"rgb L\" [4 4 4]\n  Translate 0 4 0\n  \
+         Shape \"sphere\"\nAttributeEnd\n",
+    );
+    for i in 0..16 {
+        world.push_str(&format!(
+            "AttributeBegin\n  Translate {} {} 2\n  Shape \"sphere\" \"float radius\" [0.4]\nAttributeEnd\n",
+            i % 4 - 2,
+            i / 4 - 2,
+        ));
+    }
+    let description = format!(
+        "Camera \"perspective\"\nSampler \"02sequence\"\nWorldBegin\n{}WorldEnd\n",
+        world
+    );
+    let opts = PbrtOptions {
+        defer_render: true,
+        ..PbrtOptions::default()
+    };
+    pbrt::parse_scene_string(opts, &description)
+        .unwrap_or_else(|e| panic!("failed to load the benchmark scene: {:#}", e))
+        .expect("no render context")
+        .scene
+}
+
+fn camera() -> PerspectiveCamera {
+    let film = Film::new(
+        Point2i::new(RESOLUTION, RESOLUTION),
+        Bounds2f::from_points(&Point2f::new(0.0, 0.0), &Point2f::new(1.0, 1.0)),
+        &BoxFilter::new(0.5, 0.5),
+        35.0,
+        "unused.png",
+        1.0,
+        f32::INFINITY,
+    );
+    PerspectiveCamera::new(
+        Transform::translate(&Vector3f::new(0.0, 0.0, -5.0)),
+        Bounds2f::from_points(&Point2f::new(-1.0, -1.0), &Point2f::new(1.0, 1.0)),
+        Shutter::new(0.0, 1.0),
+        0.0,
+        1e6,
+        60.0,
+        Box::new(film),
+    )
+}
+
+fn integrator(camera: &dyn Camera) -> PathIntegrator {
+    PathIntegrator::new(
+        camera.get_film().get_sample_bounds(),
+        5,
+        1.0,
+        "spatial".into(),
+    )
+}
+
+fn render(c: &mut Criterion) {
+    init_stats();
+    let scene = scene();
+    let mut group = c.benchmark_group("render");
+    group.sample_size(10);
+    group.throughput(Throughput::Elements(
+        (RESOLUTION * RESOLUTION) as u64 * SPP as u64,
+    ));
+    group.bench_function("dyn", |b| {
+        b.iter(|| {
+            let camera: Box<dyn Camera> = Box::new(camera());
+            let mut integrator: Box<dyn SamplerIntegrator> = Box::new(integrator(camera.as_ref()));
+            let mut sampler: Box<dyn Sampler> = Box::new(ZeroTwoSequence::new(SPP, 4));
+            renderer::render_tiles(
+                &scene,
+                integrator.as_mut(),
+                camera.as_ref(),
+                WorkerThreads::new(1),
+                sampler.as_mut(),
+                16,
+            );
+            camera
+        })
+    });
+    group.bench_function("static", |b| {
+        b.iter(|| {
+            let camera = camera();
+            let integrator = integrator(&camera);
+            RenderBuilder::new(
+                Arc::clone(&scene),
+                integrator,
+                camera,
+                ZeroTwoSequence::new(SPP, 4),
+            )
+            .with_threads(WorkerThreads::new(1))
+            .render_in_memory()
+        })
+    });
+    group.finish();
+}
+
+criterion_group!(benches, render);
+criterion_main!(benches);
@@ -0,0 +1,63 @@
+//! Sample generation: (0, 2)-sequence samples for a tile of pixels, and correlated multi-jittered
+//! patterns.
+
+use criterion::{black_box, criterion_group, criterion_main, Criterion, Throughput};
+
+use rustracer_core::sampler::cmj::cmj_2d;
+use rustracer_core::sampler::zerotwosequence::ZeroTwoSequence;
+use rustracer_core::sampler::Sampler;
+use rustracer_core::{Point2f, Point2i};
+
+const TILE_SIZE: i32 = 16;
+const SPP: usize = 16;
+/// Dimensions used by each sample after the camera sample, as by a path of a few bounces
+const N_DIMENSIONS: usize = 16;
+
+fn zero_two_sequence(c: &mut Criterion) {
+    let mut sampler = ZeroTwoSequence::new(SPP, 4);
+    let mut group = c.benchmark_group("sampler");
+    group.throughput(Throughput::Elements(
+        (TILE_SIZE * TILE_SIZE) as u64 * SPP as u64,
+    ));
+    group.bench_function("02sequence", |b| {
+        b.iter(|| {
+            let mut sum = 0.0;
+            for y in 0..TILE_SIZE {
+                for x in 0..TILE_SIZE {
+                    let p = Point2i::new(x, y);
+                    sampler.start_pixel(p);
+                    loop {
+                        let s = sampler.get_camera_sample(p);
+                        sum += s.p_film.x + s.time;
+                        for _ in 0..N_DIMENSIONS / 2 {
+                            sum += sampler.get_2d().x;
+                        }
+                        if !sampler.start_next_sample() {
+                            break;
+                        }
+                    }
+                }
+            }
+            sum
+        })
+    });
+    group.finish();
+}
+
+fn cmj(c: &mut Criterion) {
+    let mut samples = vec![Point2f::new(0.0, 0.0); 256];
+    let mut group = c.benchmark_group("sampler");
+    group.throughput(Throughput::Elements(samples.len() as u64));
+    let mut pattern = 0;
+    group.bench_function("cmj_2d", |b| {
+        b.iter(|| {
+            pattern += 1;
+            cmj_2d(&mut samples, black_box(pattern));
+            samples[0]
+        })
+    });
+    group.finish();
+}
+
+criterion_group!(benches, zero_two_sequence, cmj);
+criterion_main!(benches);
@@ -0,0 +1,54 @@
+//! Spectrum arithmetic, as done by the integrators for every path vertex.
+
+use criterion::{black_box, criterion_group, criterion_main, Criterion, Throughput};
+
+use rustracer_core::rng::RNG;
+use rustracer_core::spectrum::Spectrum;
+
+const N_SPECTRA: usize = 4096;
+
+fn spectra() -> Vec<Spectrum> {
+    let mut rng = RNG::new();
+    (0..N_SPECTRA)
+        .map(|_| Spectrum::rgb(rng.uniform_f32(), rng.uniform_f32(), rng.uniform_f32()))
+        .collect()
+}
+
+fn arithmetic(c: &mut Criterion) {
+    let spectra = spectra();
+    let mut group = c.benchmark_group("spectrum");
+    group.throughput(Throughput::Elements(spectra.len() as u64));
+    // The path throughput update: beta * f * cos / pdf
+    group.bench_function("throughput", |b| {
+        b.iter(|| {
+            spectra.windows(2).fold(Spectrum::white(), |beta, s| {
+                let beta = beta * s[0] * s[1].y() / 0.5;
+                if beta.max_component_value() < 1e-3 {
+                    Spectrum::white()
+                } else {
+                    beta
+                }
+            })
+        })
+    });
+    group.bench_function("accumulate", |b| {
+        b.iter(|| {
+            spectra
+                .iter()
+                .fold(Spectrum::black(), |l, s| l + black_box(*s) * 0.25)
+        })
+    });
+    group.bench_function("sqrt_clamp", |b| {
+        b.iter(|| {
+            spectra
+                .iter()
+                .map(|s| (*s - Spectrum::grey(0.5)).clamp().sqrt())
+                .filter(|s| !s.is_black())
+                .count()
+        })
+    });
+    group.finish();
+}
+
+criterion_group!(benches, arithmetic);
+criterion_main!(benches);
@@ -0,0 +1,75 @@
+//! MIP map lookups, with each filter mode, in a 512x512 RGB checkerboard.
+
+use criterion::{black_box, criterion_group, criterion_main, Criterion, Throughput};
+
+use rustracer_core::mipmap::{FilterMode, MIPMap, WrapMode};
+use rustracer_core::rng::RNG;
+use rustracer_core::spectrum::Spectrum;
+use rustracer_core::{Point2f, Point2i, Vector2f};
+
+const N_LOOKUPS: usize = 4096;
+const RES: i32 = 512;
+
+fn checkerboard(filter_mode: FilterMode) -> MIPMap<Spectrum> {
+    let texels: Vec<Spectrum> = (0..RES * RES)
+        .map(|i| {
+            if ((i % RES) / 8 + (i / RES) / 8) % 2 == 0 {
+                Spectrum::rgb(0.9, 0.8, 0.1)
+            } else {
+                Spectrum::rgb(0.1, 0.2, 0.7)
+            }
+        })
+        .collect();
+    MIPMap::new(
+        Point2i::new(RES, RES),
+        &texels,
+        filter_mode,
+        8.0,
+        WrapMode::Repeat,
+        None,
+    )
+}
+
+/// Lookup positions, with footprints ranging from a fraction of a texel to the whole texture and
+/// up to 8:1 anisotropy.
+fn lookups() -> Vec<(Point2f, Vector2f, Vector2f)> {
+    let mut rng = RNG::new();
+    (0..N_LOOKUPS)
+        .map(|_| {
+            let st = Point2f::new(rng.uniform_f32(), rng.uniform_f32());
+            let width = f32::powf(2.0, -10.0 * rng.uniform_f32());
+            let angle = std::f32::consts::PI * rng.uniform_f32();
+            let (sin, cos) = angle.sin_cos();
+            let aniso = 1.0 + 7.0 * rng.uniform_f32();
+            let dst0 = Vector2f::new(cos, sin) * width * aniso;
+            let dst1 = Vector2f::new(-sin, cos) * width;
+            (st, dst0, dst1)
+        })
+        .collect()
+}
+
+fn mipmap(c: &mut Criterion) {
+    let lookups = lookups();
+    let mut group = c.benchmark_group("mipmap");
+    group.throughput(Throughput::Elements(lookups.len() as u64));
+    for (name, filter_mode) in &[
+        ("nearest", FilterMode::Nearest),
+        ("trilinear", FilterMode::Trilinear),
+        ("ewa", FilterMode::Ewa),
+    ] {
+        let mipmap = checkerboard(*filter_mode);
+        group.bench_function(*name, |b| {
+            b.iter(|| {
+                lookups
+                    .iter()
+                    .fold(Spectrum::black(), |sum, &(st, dst0, dst1)| {
+                        sum + mipmap.lookup_diff(black_box(st), dst0, dst1)
+                    })
+            })
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, mipmap);
+criterion_main!(benches);
@@ -0,0 +1,97 @@
+/* C API for the rustracer renderer. See rustracer-core/src/capi.rs.
+ *
+ * This header is generated by cbindgen from src/capi.rs, don't edit it by hand. Regenerate it with:
+ *   cbindgen --config cbindgen.toml --crate rustracer-core --output include/rustracer.h
+ */
+
+#ifndef RUSTRACER_H
+#define RUSTRACER_H
+
+#include <stddef.h>
+#include <stdint.h>
+
+/*
+ Opaque handle to a renderer instance.
+ */
+typedef struct rt_context rt_context;
+
+#ifdef __cplusplus
+extern "C" {
+#endif // __cplusplus
+
+/*
+ Create a new renderer instance using `num_threads` threads to render (0 means one per core).
+ It must be freed with `rt_context_free()`.
+ */
+rt_context *rt_context_new(uint32_t num_threads);
+
+/*
+ Free a renderer instance.
+
+ # Safety
+
+ `ctx` must have been returned by `rt_context_new()` and not freed already, or be null.
+ */
+void rt_context_free(rt_context *ctx);
+
+/*
+ Load a scene from a string in the pbrt format, replacing any previously loaded scene.
+
+ # Safety
+
+ `ctx` must be a valid context and `scene` a valid NUL-terminated string.
+ */
+int32_t rt_context_load_string(rt_context *ctx, const char *scene);
+
+/*
+ Load a scene from a pbrt file, replacing any previously loaded scene.
+
+ # Safety
+
+ `ctx` must be a valid context and `filename` a valid NUL-terminated string.
+ */
+int32_t rt_context_load_file(rt_context *ctx, const char *filename);
+
+/*
+ Render the loaded scene. The result can then be retrieved with `rt_context_copy_image()`.
+
+ # Safety
+
+ `ctx` must be a valid context.
+ */
+int32_t rt_context_render(rt_context *ctx);
+
+/*
+ Get the resolution of the rendered image.
+
+ # Safety
+
+ `ctx` must be a valid context, and `width` and `height` valid pointers.
+ */
+int32_t rt_context_image_size(rt_context *ctx, int32_t *width, int32_t *height);
+
+/*
+ Copy the linear RGB values of the rendered image, in scanline order, into `buffer`, which
+ must hold at least `3 * width * height` floats.
+
+ # Safety
+
+ `ctx` must be a valid context, and `buffer` must point to at least `len` floats.
+ */
+int32_t rt_context_copy_image(rt_context *ctx, float *buffer, size_t len);
+
+/*
+ Return a description of the last error, or an empty string. The string is owned by the
+ context and is valid until the next call to a function taking it.
+
+ # Safety
+
+ `ctx` must be a valid context.
+ */
+const char *rt_context_last_error(const rt_context *ctx);
+
+#ifdef __cplusplus
+}  // extern "C"
+#endif  // __cplusplus
+
+#endif  /* RUSTRACER_H */
@@ -1,40 +1,50 @@
-use std::cell::RefCell;
+use std::cell::{Cell, RefCell};
 use std::collections::HashMap;
+use std::ops::Index;
+use std::rc::Rc;
 use std::sync::Arc;
+use std::time::Instant;
 
 use anyhow::{anyhow, bail, format_err, Result};
 use indicatif::HumanDuration;
 use log::{debug, error, info, warn};
 
-use crate::bvh::BVH;
-use crate::camera::{Camera, PerspectiveCamera};
+use crate::bvh::{self, BuildParams, Instance, Tlas};
+use crate::camera::{BakeCamera, Camera, PerspectiveCamera, StereoCamera};
+use crate::cancel;
+use crate::cryptomatte::{MatteIds, MatteNames};
 use crate::film::Film;
 use crate::filter::{BoxFilter, Filter, GaussianFilter, MitchellNetravali, TriangleFilter};
 use crate::geometry::Matrix4x4;
+use crate::geometrydump::GeometryDump;
 use crate::integrator::{
-    DirectLightingIntegrator, Normal, PathIntegrator, SamplerIntegrator, Whitted,
+    BakeIntegrator, DirectLightingIntegrator, Normal, PathIntegrator, SamplerIntegrator,
+    WavefrontPathIntegrator, Whitted,
 };
 use crate::light::{
-    AreaLight, DiffuseAreaLight, DistantLight, InfiniteAreaLight, Light, PointLight,
+    AreaLightRef, DiffuseAreaLight, DiffuseMeshLight, DistantLight, InfiniteAreaLight, LightRef,
+    PointLight,
 };
 use crate::material::{
-    DisneyMaterial, FourierMaterial, GlassMaterial, Material, MatteMaterial, Metal, MirrorMaterial,
-    MixMaterial, Plastic, SubstrateMaterial, TranslucentMaterial, UberMaterial,
+    DisneyMaterial, FourierMaterial, GlassMaterial, LayeredMaterial, MaterialRef, MatteMaterial,
+    Metal, MirrorMaterial, MixMaterial, PbrMaterial, Plastic, SubstrateMaterial,
+    TranslucentMaterial, UberMaterial,
 };
 use crate::paramset::{ParamSet, TextureParams};
-use crate::primitive::{GeometricPrimitive, Primitive, TransformedPrimitive};
-use crate::renderer;
+use crate::primitive::{GeometricPrimitive, PrimitiveRef, TransformedPrimitive};
+use crate::proxy;
+use crate::renderer::{RenderContext, WorkerThreads};
 use crate::sampler::zerotwosequence::ZeroTwoSequence;
 use crate::sampler::Sampler;
 use crate::scene::Scene;
-use crate::shapes::plymesh;
-use crate::shapes::{Cylinder, Disk, Shape, Sphere, TriangleMesh};
+use crate::shapes::{hairfile, plymesh, ShapeRef};
+use crate::shapes::{Cylinder, Disk, Sphere, TriangleMesh};
 use crate::spectrum::Spectrum;
-use crate::stat_counter;
 use crate::texture::{
-    CheckerboardTexture, ConstantTexture, FbmTexture, ImageTexture, MixTexture, ScaleTexture,
-    Texture, UVTexture,
+    supersample_if_requested, CheckerboardTexture, ConstantTexture, FbmTexture, ImageTexture,
+    MixTexture, ScaleTexture, TextureRef, UVTexture,
 };
+use crate::transform::AnimatedTransform;
 use crate::{PbrtOptions, Point3f, Transform, Vector3f};
 
 stat_counter!("Scene/Materials created", n_materials_created);
@@ -46,8 +56,9 @@ pub fn init_stats() {
     n_object_instances_used::init();
 }
 
-#[derive(Debug, Copy, Clone)]
+#[derive(Debug, Copy, Clone, Default)]
 pub enum ApiState {
+    #[default]
     Uninitialized,
     OptionsBlock,
     WorldBlock,
@@ -85,12 +96,6 @@ impl ApiState {
     }
 }
 
-impl Default for ApiState {
-    fn default() -> Self {
-        ApiState::Uninitialized
-    }
-}
-
 #[derive(Debug, Copy, Clone, PartialEq, Eq)]
 pub enum ParamType {
     Int,
@@ -156,8 +161,10 @@ impl ParamListEntry {
 }
 
 pub struct RenderOptions {
-    _transform_start_time: f32,
-    _transform_end_time: f32,
+    transform_start_time: f32,
+    transform_end_time: f32,
+    /// Whether some shapes or instances were created with an animated transform
+    has_motion: bool,
     film_name: String,
     film_params: ParamSet,
     filter_name: String,
@@ -166,17 +173,103 @@ pub struct RenderOptions {
     sampler_params: ParamSet,
     accelerator_name: String,
     accelerator_params: ParamSet,
+    /// Build parameters of the BLASes and the TLAS, from the accelerator parameters (set by
+    /// `world_begin()`)
+    bvh_params: Option<BuildParams>,
     integrator_name: String,
     integrator_params: ParamSet,
     camera_name: String,
     camera_params: ParamSet,
     camera_to_world: Transform,
-    lights: Vec<Arc<dyn Light>>,
-    primitives: Vec<Arc<dyn Primitive>>,
-    instances: HashMap<String, Vec<Arc<dyn Primitive>>>,
+    /// Whether the scene placed the camera, i.e. the CTM wasn't the identity at `Camera`
+    camera_transform_given: bool,
+    lights: Vec<LightRef>,
+    /// Top-level instances: one per mesh or object instance
+    primitives: Vec<Instance>,
+    instances: HashMap<String, Vec<PrimitiveRef>>,
+    /// Shapes of each object instance, for the bake camera
+    instance_shapes: HashMap<String, Vec<ShapeRef>>,
     current_instance: Option<String>,
+    /// Number of meshes whose loading is deferred (see the `proxy` module)
+    deferred_meshes: usize,
+    /// The triangles of the scene, collected when `PbrtOptions::dump_geometry` is set
+    geometry_dump: Option<GeometryDump>,
+    /// Names of the objects and materials in the Cryptomatte mattes
+    matte_names: MatteNames,
+    /// Number of `Shape` directives so far, to name the unnamed objects
+    shape_count: usize,
 }
 
+/// Type names accepted by the directives of the scene format, which must be kept in sync with the
+/// `make_*()` functions below. Types that are recognised but not implemented (e.g. the "cone"
+/// shape) are left out, as is the "hairfile" shape: its files are read, but it creates no shapes
+/// until there is a curve shape.
+pub const CAPABILITIES: &[(&str, &[&str])] = &[
+    ("Accelerator", &["bvh"]),
+    ("AreaLightSource", &["area", "diffuse"]),
+    ("Camera", &["perspective", "stereo", "bake"]),
+    ("Film", &["image"]),
+    (
+        "Integrator",
+        &[
+            "whitted",
+            "directlighting",
+            "path",
+            "wavefront",
+            "bake",
+            "normal",
+        ],
+    ),
+    ("LightSource", &["point", "distant", "infinite"]),
+    (
+        "Material",
+        &[
+            "matte",
+            "plastic",
+            "glass",
+            "mirror",
+            "metal",
+            "substrate",
+            "translucent",
+            "uber",
+            "disney",
+            "pbr",
+            "mix",
+            "layered",
+            "fourier",
+        ],
+    ),
+    ("PixelFilter", &["box", "mitchell", "gaussian", "triangle"]),
+    ("Sampler", &["02sequence", "lowdiscrepancy"]),
+    (
+        "Shape",
+        &[
+            "sphere",
+            "cylinder",
+            "disk",
+            "trianglemesh",
+            "plymesh",
+            "proxy",
+        ],
+    ),
+    (
+        "Texture \"float\"",
+        &["constant", "scale", "imagemap", "fbm", "mix"],
+    ),
+    (
+        "Texture \"spectrum\"",
+        &[
+            "constant",
+            "scale",
+            "mix",
+            "imagemap",
+            "uv",
+            "checkerboard",
+            "fbm",
+        ],
+    ),
+];
+
 impl RenderOptions {
     pub fn make_filter(&self) -> Result<Box<dyn Filter>> {
         debug!("Making filter");
@@ -191,10 +284,10 @@ impl RenderOptions {
         Ok(filter)
     }
 
-    pub fn make_film(&self, filter: &dyn Filter) -> Result<Box<Film>> {
+    pub fn make_film(&self, filter: &dyn Filter, opts: &PbrtOptions) -> Result<Box<Film>> {
         debug!("Making film");
         let film = if self.film_name == "image" {
-            Film::create(&self.film_params, filter)
+            Film::create(&self.film_params, filter, opts)?
         } else {
             bail!("Film \"{}\" unknown.", self.film_name);
         };
@@ -202,11 +295,11 @@ impl RenderOptions {
         Ok(film)
     }
 
-    pub fn make_sampler(&self) -> Result<Box<dyn Sampler>> {
+    pub fn make_sampler(&self, opts: &PbrtOptions) -> Result<Box<dyn Sampler>> {
         debug!("Making sampler");
         let sampler = if self.sampler_name == "lowdiscrepancy" || self.sampler_name == "02sequence"
         {
-            ZeroTwoSequence::create(&self.sampler_params)
+            ZeroTwoSequence::create(&self.sampler_params, opts)
         } else {
             bail!("Sampler \"{}\" unknown.", self.sampler_name);
         };
@@ -214,13 +307,18 @@ impl RenderOptions {
         Ok(sampler)
     }
 
-    pub fn make_camera(&self) -> Result<Box<dyn Camera>> {
+    pub fn make_camera(&self, opts: &PbrtOptions) -> Result<Box<dyn Camera>> {
         debug!("Making camera");
         let filter = self.make_filter()?;
-        let film = self.make_film(filter.as_ref())?;
+        let mut film = self.make_film(filter.as_ref(), opts)?;
+        film.set_matte_names(self.matte_names.clone());
 
         let camera = if self.camera_name == "perspective" {
             PerspectiveCamera::create(&self.camera_params, &self.camera_to_world, film)
+        } else if self.camera_name == "stereo" {
+            StereoCamera::create(&self.camera_params, &self.camera_to_world, film)?
+        } else if self.camera_name == "bake" {
+            BakeCamera::create(&self.camera_params, &self.instance_shapes, film)?
         } else {
             bail!("Camera \"{}\" unknown.", self.camera_name);
         };
@@ -228,58 +326,136 @@ impl RenderOptions {
         Ok(camera)
     }
 
-    pub fn make_integrator(&self, camera: &dyn Camera) -> Result<Box<dyn SamplerIntegrator>> {
+    /// Cryptomatte IDs of the shapes of a `Shape` directive (see the `cryptomatte` module for
+    /// how they are named).
+    fn matte_ids(&mut self, shape: &str, params: &ParamSet, material: &str) -> MatteIds {
+        let index = self.shape_count;
+        self.shape_count += 1;
+        let name = params.find_one_string("name", String::new());
+        let object = if !name.is_empty() {
+            name
+        } else if let Some(instance) = &self.current_instance {
+            instance.clone()
+        } else {
+            format!("{}.{}", shape, index)
+        };
+        self.matte_names.add(&object, material)
+    }
+
+    /// Whether the camera should be placed automatically to frame the whole scene: either because
+    /// it was requested, or because the scene doesn't position the camera itself.
+    pub fn should_auto_frame(&self, opts: &PbrtOptions) -> bool {
+        (self.camera_name == "perspective" || self.camera_name == "stereo")
+            && (opts.auto_frame || !self.camera_transform_given)
+    }
+
+    /// Move the camera so that the bounding sphere of the scene fits in its field of view. The
+    /// camera keeps its viewing direction and up vector, and looks at the centre of the scene.
+    pub fn frame_scene(&mut self, scene: &Scene) {
+        let (center, radius) = scene.world_bounds().bounding_sphere();
+        if radius <= 0.0 || !radius.is_finite() {
+            warn!("Scene has no finite bounds: not moving the camera to frame it");
+            return;
+        }
+        let half_fov = 0.5 * PerspectiveCamera::fov(&self.camera_params).to_radians();
+        let distance = radius / half_fov.sin();
+        let dir = (&self.camera_to_world * &Vector3f::new(0.0, 0.0, 1.0)).normalize();
+        let up = &self.camera_to_world * &Vector3f::new(0.0, 1.0, 0.0);
+        let eye = center - distance * dir;
+        info!(
+            "Framing the scene: camera at {} looking at {} (distance {})",
+            eye, center, distance
+        );
+        self.camera_to_world = Transform::look_at(&eye, &center, &up).inverse();
+    }
+
+    pub fn make_integrator(
+        &self,
+        camera: &dyn Camera,
+        opts: &PbrtOptions,
+    ) -> Result<Box<dyn SamplerIntegrator>> {
         debug!("Making integrator");
         let integrator: Box<dyn SamplerIntegrator> = if self.integrator_name == "whitted" {
-            Whitted::create(&self.integrator_params)
+            Whitted::create(&self.integrator_params, camera, opts)
         } else if self.integrator_name == "directlighting" {
-            DirectLightingIntegrator::create(&self.integrator_params)
+            DirectLightingIntegrator::create(&self.integrator_params, camera, opts)
         } else if self.integrator_name == "path" {
-            PathIntegrator::create(&self.integrator_params, camera)
+            PathIntegrator::create(&self.integrator_params, camera, opts)
+        } else if self.integrator_name == "wavefront" {
+            WavefrontPathIntegrator::create(&self.integrator_params, camera, opts)
+        } else if self.integrator_name == "bake" {
+            BakeIntegrator::create(&self.integrator_params, camera)
         } else if self.integrator_name == "normal" {
             Box::new(Normal::default())
         } else {
             bail!("Integrator \"{}\" unknown.", self.integrator_name);
         };
+        if !camera.get_film().aovs().is_empty() && self.integrator_name != "path" {
+            warn!(
+                "Only the \"path\" integrator supports light path expression AOVs. They will be black with \"{}\".",
+                self.integrator_name
+            );
+        }
+        if !camera.get_film().geometry_aovs().is_empty() && self.integrator_name == "wavefront" {
+            warn!(
+                "The \"wavefront\" integrator doesn't support geometric AOVs. They will be black."
+            );
+        }
+        if camera.get_film().sample_dump().is_some() {
+            if self.integrator_name == "wavefront" {
+                warn!("The \"wavefront\" integrator doesn't support dumping samples. None will be recorded.");
+            } else if self.integrator_name != "path" {
+                warn!(
+                    "Only the \"path\" integrator records the path lengths and light contributions of the dumped samples. There will only be their radiance with \"{}\".",
+                    self.integrator_name
+                );
+            }
+        }
+        if camera.get_film().has_cryptomatte() && self.integrator_name == "wavefront" {
+            warn!("The \"wavefront\" integrator doesn't support Cryptomatte. The mattes will be empty.");
+        }
 
         Ok(integrator)
     }
 
-    pub fn make_scene(&self) -> Result<Arc<Scene>> {
+    pub fn make_scene(&self, opts: &PbrtOptions) -> Result<Arc<Scene>> {
         info!(
             "Making scene with {} primitives and {} lights",
             self.primitives.len(),
             self.lights.len()
         );
-        let accelerator = make_accelerator(
-            &self.accelerator_name,
-            &self.primitives,
-            &self.accelerator_params,
-        );
-        Ok(Arc::new(Scene::new(accelerator, self.lights.clone())))
+        let accelerator = Arc::new(Tlas::new(self.primitives.clone(), self.bvh_params(opts)));
+        Ok(Arc::new(
+            Scene::new(accelerator, self.lights.clone()).with_motion(self.has_motion),
+        ))
     }
-}
 
-pub fn make_accelerator(
-    accelerator_name: &str,
-    prims: &[Arc<dyn Primitive>],
-    accelerator_params: &ParamSet,
-) -> Arc<dyn Primitive> {
-    if accelerator_name == "kdtree" {
-        unimplemented!()
-    } else if accelerator_name == "bvh" {
-        Arc::new(BVH::create(prims, accelerator_params))
-    } else {
-        warn!("Accelerator \"{}\" unknown.", accelerator_name);
-        Arc::new(BVH::create(prims, accelerator_params))
+    /// Build the bottom-level structure for the primitives of a mesh or of an object.
+    pub fn make_blas(&self, prims: &[PrimitiveRef], opts: &PbrtOptions) -> PrimitiveRef {
+        bvh::build_blas(prims, &self.bvh_params(opts))
+    }
+
+    fn bvh_params(&self, opts: &PbrtOptions) -> BuildParams {
+        self.bvh_params
+            .unwrap_or_else(|| self.accelerator_build_params(opts))
+    }
+
+    fn accelerator_build_params(&self, opts: &PbrtOptions) -> BuildParams {
+        if self.accelerator_name == "kdtree" {
+            warn!("Accelerator \"kdtree\" is not supported yet. Using \"bvh\".");
+        } else if self.accelerator_name != "bvh" {
+            warn!("Accelerator \"{}\" unknown.", self.accelerator_name);
+        }
+        BuildParams::create(&self.accelerator_params, opts)
     }
 }
 
 impl Default for RenderOptions {
     fn default() -> Self {
         RenderOptions {
-            _transform_start_time: 0.0,
-            _transform_end_time: 1.0,
+            transform_start_time: 0.0,
+            transform_end_time: 1.0,
+            has_motion: false,
             film_name: "image".to_owned(),
             film_params: ParamSet::default(),
             filter_name: "box".to_owned(),
@@ -288,34 +464,57 @@ impl Default for RenderOptions {
             sampler_params: ParamSet::default(),
             accelerator_name: "bvh".to_owned(),
             accelerator_params: ParamSet::default(),
+            bvh_params: None,
             integrator_name: "path".to_owned(),
             integrator_params: ParamSet::default(),
             camera_name: "perspective".to_owned(),
             camera_params: ParamSet::default(),
             camera_to_world: Transform::default(),
+            camera_transform_given: false,
             lights: Vec::new(),
             primitives: Vec::new(),
             instances: HashMap::new(),
+            instance_shapes: HashMap::new(),
             current_instance: None,
+            deferred_meshes: 0,
+            geometry_dump: None,
+            matte_names: MatteNames::new(),
+            shape_count: 0,
         }
     }
 }
 
+/// The attributes saved and restored by `AttributeBegin` / `AttributeEnd`.
+///
+/// Scenes exported from modelling packages often wrap every single shape in an attribute block,
+/// so the potentially large members are shared behind an `Arc` (or an `Rc` for the parameter
+/// lists, which never leave the parser's thread) and only copied when they are modified inside a
+/// block (see `Arc::make_mut()`). Pushing the state is then just a handful of
+/// reference count increments.
 #[derive(Clone)]
 pub struct GraphicsState {
-    float_textures: HashMap<String, Arc<dyn Texture<f32>>>,
-    spectrum_textures: HashMap<String, Arc<dyn Texture<Spectrum>>>,
-    material_param: ParamSet,
+    float_textures: Arc<HashMap<String, TextureRef<f32>>>,
+    spectrum_textures: Arc<HashMap<String, TextureRef<Spectrum>>>,
+    material_param: Rc<ParamSet>,
     material: String,
-    named_material: HashMap<String, Arc<dyn Material>>,
+    named_material: Arc<HashMap<String, MaterialRef>>,
     current_named_material: String,
-    area_light_params: ParamSet,
+    area_light_params: Rc<ParamSet>,
     area_light: String,
     reverse_orientation: bool,
 }
 
 impl GraphicsState {
-    pub fn create_material(&self, params: &ParamSet) -> Arc<dyn Material> {
+    /// Name of the current material: the named material if there is one, or else its type.
+    fn material_name(&self) -> &str {
+        if self.current_named_material.is_empty() {
+            &self.material
+        } else {
+            &self.current_named_material
+        }
+    }
+
+    pub fn create_material(&self, params: &ParamSet, opts: &PbrtOptions) -> MaterialRef {
         let mp = TextureParams::new(
             params,
             &self.material_param,
@@ -332,10 +531,10 @@ impl GraphicsState {
                         "No material named \"{}\". Using matte material instead.",
                         cur_mat_name
                     );
-                    make_material("matte", &mp, &self.named_material)
+                    make_material("matte", &mp, &self.named_material, opts)
                 })
         } else {
-            make_material(&self.material, &mp, &self.named_material)
+            make_material(&self.material, &mp, &self.named_material, opts)
         }
     }
 }
@@ -343,26 +542,62 @@ impl GraphicsState {
 impl Default for GraphicsState {
     fn default() -> Self {
         GraphicsState {
-            float_textures: HashMap::new(),
-            spectrum_textures: HashMap::new(),
-            material_param: ParamSet::default(),
+            float_textures: Arc::new(HashMap::new()),
+            spectrum_textures: Arc::new(HashMap::new()),
+            material_param: Rc::new(ParamSet::default()),
             material: "matte".to_owned(),
-            named_material: HashMap::new(),
+            named_material: Arc::new(HashMap::new()),
             current_named_material: String::new(),
-            area_light_params: ParamSet::default(),
+            area_light_params: Rc::new(ParamSet::default()),
             area_light: String::new(),
             reverse_orientation: false,
         }
     }
 }
 
+/// The current transform at the start and at the end of the shutter interval (indices 0 and 1).
+/// When they differ, the shapes and instances created with them move over the interval.
+#[derive(Debug, Clone, Default)]
+pub struct TransformSet([Transform; 2]);
+
+impl TransformSet {
+    fn is_animated(&self) -> bool {
+        self.0[0].m != self.0[1].m
+    }
+
+    fn inverse(&self) -> TransformSet {
+        TransformSet([self.0[0].inverse(), self.0[1].inverse()])
+    }
+}
+
+impl Index<usize> for TransformSet {
+    type Output = Transform;
+
+    fn index(&self, i: usize) -> &Transform {
+        &self.0[i]
+    }
+}
+
+/// Which of the transforms of a `TransformSet` the transform directives apply to, as set by
+/// `ActiveTransform`.
+#[derive(Debug, Clone, Copy)]
+pub struct ActiveTransforms([bool; 2]);
+
+impl Default for ActiveTransforms {
+    fn default() -> Self {
+        ActiveTransforms([true, true])
+    }
+}
+
 #[derive(Default)]
 pub struct State {
     api_state: ApiState,
     render_options: RenderOptions,
-    cur_transform: Transform,
-    named_coordinate_systems: HashMap<String, Transform>,
-    pushed_transforms: Vec<Transform>,
+    cur_transform: TransformSet,
+    active_transforms: ActiveTransforms,
+    named_coordinate_systems: HashMap<String, TransformSet>,
+    pushed_transforms: Vec<TransformSet>,
+    pushed_active_transforms: Vec<ActiveTransforms>,
     graphics_state: GraphicsState,
     pushed_graphics_states: Vec<GraphicsState>,
 }
@@ -376,6 +611,7 @@ impl State {
     pub fn save_transform(&mut self) {
         let t = self.cur_transform.clone();
         self.pushed_transforms.push(t);
+        self.pushed_active_transforms.push(self.active_transforms);
     }
 
     pub fn restore_graphics_state(&mut self) {
@@ -384,6 +620,26 @@ impl State {
 
     pub fn restore_transform(&mut self) {
         self.cur_transform = self.pushed_transforms.pop().unwrap();
+        self.active_transforms = self.pushed_active_transforms.pop().unwrap();
+    }
+
+    /// Replace each active transform `t` with `f(t)`.
+    fn update_transforms<F: Fn(&Transform) -> Transform>(&mut self, f: F) {
+        for i in 0..2 {
+            if self.active_transforms.0[i] {
+                self.cur_transform.0[i] = f(&self.cur_transform.0[i]);
+            }
+        }
+    }
+
+    /// The motion of the current transform over the shutter interval.
+    fn animated_transform(&self) -> AnimatedTransform {
+        AnimatedTransform::new(
+            self.cur_transform[0].clone(),
+            self.render_options.transform_start_time,
+            self.cur_transform[1].clone(),
+            self.render_options.transform_end_time,
+        )
     }
 }
 
@@ -446,16 +702,16 @@ pub trait Api {
     ) -> Result<()>;
     fn coordinate_system(&self, name: String) -> Result<()>;
     fn coord_sys_transform(&self, name: String) -> Result<()>;
-    // TODO active_transform_all
-    // TODO active_transform_end_time
-    // TODO active_transform_start_time
-    // TODO transform_times
-    fn pixel_filter(&self, name: String, params: &ParamSet) -> Result<()>;
-    fn film(&self, name: String, params: &ParamSet) -> Result<()>;
-    fn sampler(&self, name: String, params: &ParamSet) -> Result<()>;
-    fn accelerator(&self, name: String, params: &ParamSet) -> Result<()>;
-    fn integrator(&self, name: String, params: &ParamSet) -> Result<()>;
-    fn camera(&self, name: String, params: &ParamSet) -> Result<()>;
+    fn active_transform_all(&self) -> Result<()>;
+    fn active_transform_end_time(&self) -> Result<()>;
+    fn active_transform_start_time(&self) -> Result<()>;
+    fn transform_times(&self, start: f32, end: f32) -> Result<()>;
+    fn pixel_filter(&self, name: String, params: ParamSet) -> Result<()>;
+    fn film(&self, name: String, params: ParamSet) -> Result<()>;
+    fn sampler(&self, name: String, params: ParamSet) -> Result<()>;
+    fn accelerator(&self, name: String, params: ParamSet) -> Result<()>;
+    fn integrator(&self, name: String, params: ParamSet) -> Result<()>;
+    fn camera(&self, name: String, params: ParamSet) -> Result<()>;
     // TODO make_named_medium
     // TODO medium_interface
     fn world_begin(&self) -> Result<()>;
@@ -464,11 +720,11 @@ pub trait Api {
     fn transform_begin(&self) -> Result<()>;
     fn transform_end(&self) -> Result<()>;
     fn texture(&self, name: String, typ: String, texname: String, params: &ParamSet) -> Result<()>;
-    fn material(&self, name: String, params: &ParamSet) -> Result<()>;
+    fn material(&self, name: String, params: ParamSet) -> Result<()>;
     fn make_named_material(&self, name: String, params: &ParamSet) -> Result<()>;
     fn named_material(&self, name: String) -> Result<()>;
     fn lightsource(&self, name: String, params: &ParamSet) -> Result<()>;
-    fn arealightsource(&self, name: String, params: &ParamSet) -> Result<()>;
+    fn arealightsource(&self, name: String, params: ParamSet) -> Result<()>;
     fn shape(&self, name: String, params: &ParamSet) -> Result<()>;
     fn reverse_orientation(&self) -> Result<()>;
     fn object_begin(&self, name: String) -> Result<()>;
@@ -481,6 +737,9 @@ pub trait Api {
 pub struct RealApi {
     options: PbrtOptions,
     state: RefCell<State>,
+    render_context: RefCell<Option<RenderContext>>,
+    /// When `init()` was called, to report how long parsing the scene description took.
+    parse_start: Cell<Option<Instant>>,
 }
 
 impl RealApi {
@@ -491,12 +750,35 @@ impl RealApi {
         }
     }
 
+    /// Take the rendering context of the last scene, if the API was created with the
+    /// `interactive` or `defer_render` option.
+    pub fn take_render_context(&self) -> Option<RenderContext> {
+        self.render_context.borrow_mut().take()
+    }
+
+    /// Fail if the scene uses more memory than allowed by the `max_memory` option. `stage` says
+    /// what was being built, for the error message.
+    fn check_memory_budget(&self, stage: impl FnOnce() -> String) -> Result<()> {
+        if let Some(max_memory) = self.options.max_memory {
+            let used = crate::stats::total_memory_usage();
+            if used > max_memory {
+                bail!(
+                    "The scene uses an estimated {} of memory after {}, over the limit of {}",
+                    crate::stats::format_memory(used).trim(),
+                    stage(),
+                    crate::stats::format_memory(max_memory).trim()
+                );
+            }
+        }
+        Ok(())
+    }
+
     fn make_light(
         &self,
         name: &str,
         param_set: &ParamSet,
         light_2_world: &Transform,
-    ) -> Result<Arc<dyn Light>> {
+    ) -> Result<LightRef> {
         if name == "point" {
             let light = PointLight::create(light_2_world, param_set);
             Ok(light)
@@ -504,7 +786,7 @@ impl RealApi {
             let light = DistantLight::create(light_2_world, param_set);
             Ok(light)
         } else if name == "infinite" {
-            let light = InfiniteAreaLight::create(light_2_world, param_set);
+            let light = InfiniteAreaLight::create(light_2_world, param_set, &self.options);
             Ok(light)
         } else {
             warn!("Light {} unknown", name);
@@ -520,6 +802,7 @@ impl Api for RealApi {
         state.api_state.verify_uninitialized()?;
 
         state.api_state = ApiState::OptionsBlock;
+        self.parse_start.set(Some(Instant::now()));
         Ok(())
     }
 
@@ -527,7 +810,7 @@ impl Api for RealApi {
         debug!("Identity called");
         let state = &mut *self.state.borrow_mut();
         state.api_state.verify_initialized()?;
-        state.cur_transform = Transform::default();
+        state.update_transforms(|_| Transform::default());
         Ok(())
     }
 
@@ -536,7 +819,7 @@ impl Api for RealApi {
         let state = &mut *self.state.borrow_mut();
         state.api_state.verify_initialized()?;
         let t = Transform::translate(&Vector3f::new(dx, dy, dz));
-        state.cur_transform = &state.cur_transform * &t;
+        state.update_transforms(|ct| ct * &t);
         Ok(())
     }
 
@@ -545,7 +828,7 @@ impl Api for RealApi {
         let state = &mut *self.state.borrow_mut();
         state.api_state.verify_initialized()?;
         let t = Transform::rotate(angle, Vector3f::new(dx, dy, dz));
-        state.cur_transform = &state.cur_transform * &t;
+        state.update_transforms(|ct| ct * &t);
         Ok(())
     }
 
@@ -554,7 +837,7 @@ impl Api for RealApi {
         let state = &mut *self.state.borrow_mut();
         state.api_state.verify_initialized()?;
         let t = Transform::scale(sx, sy, sz);
-        state.cur_transform = &state.cur_transform * &t;
+        state.update_transforms(|ct| ct * &t);
         Ok(())
     }
 
@@ -583,11 +866,11 @@ impl Api for RealApi {
             tr00, tr04, tr08, tr12, tr01, tr05, tr09, tr13, tr02, tr06, tr10, tr14, tr03, tr07,
             tr11, tr15,
         );
-        state.cur_transform = &state.cur_transform
-            * &Transform {
-                m: mat,
-                m_inv: mat.inverse(),
-            };
+        let t = Transform {
+            m: mat,
+            m_inv: mat.inverse(),
+        };
+        state.update_transforms(|ct| ct * &t);
         Ok(())
     }
 
@@ -616,10 +899,11 @@ impl Api for RealApi {
             tr00, tr04, tr08, tr12, tr01, tr05, tr09, tr13, tr02, tr06, tr10, tr14, tr03, tr07,
             tr11, tr15,
         );
-        state.cur_transform = Transform {
+        let t = Transform {
             m: mat,
             m_inv: mat.inverse(),
         };
+        state.update_transforms(|_| t.clone());
         Ok(())
     }
 
@@ -643,7 +927,7 @@ impl Api for RealApi {
             &Point3f::new(lx, ly, lz),
             &Vector3f::new(ux, uy, uz),
         );
-        state.cur_transform = &state.cur_transform * &look_at;
+        state.update_transforms(|ct| ct * &look_at);
         Ok(())
     }
 
@@ -672,59 +956,93 @@ impl Api for RealApi {
         Ok(())
     }
 
-    fn pixel_filter(&self, name: String, params: &ParamSet) -> Result<()> {
+    fn active_transform_all(&self) -> Result<()> {
+        let state = &mut *self.state.borrow_mut();
+        state.api_state.verify_initialized()?;
+        state.active_transforms = ActiveTransforms([true, true]);
+        Ok(())
+    }
+
+    fn active_transform_end_time(&self) -> Result<()> {
+        let state = &mut *self.state.borrow_mut();
+        state.api_state.verify_initialized()?;
+        state.active_transforms = ActiveTransforms([false, true]);
+        Ok(())
+    }
+
+    fn active_transform_start_time(&self) -> Result<()> {
+        let state = &mut *self.state.borrow_mut();
+        state.api_state.verify_initialized()?;
+        state.active_transforms = ActiveTransforms([true, false]);
+        Ok(())
+    }
+
+    fn transform_times(&self, start: f32, end: f32) -> Result<()> {
+        debug!("transform_times called with {} {}", start, end);
+        let state = &mut *self.state.borrow_mut();
+        state.api_state.verify_options()?;
+        state.render_options.transform_start_time = start;
+        state.render_options.transform_end_time = end;
+        Ok(())
+    }
+
+    fn pixel_filter(&self, name: String, params: ParamSet) -> Result<()> {
         let state = &mut *self.state.borrow_mut();
         state.api_state.verify_options()?;
         debug!("pixel_filter called");
         state.render_options.filter_name = name;
-        state.render_options.filter_params = params.clone();
+        state.render_options.filter_params = params;
         Ok(())
     }
 
-    fn film(&self, name: String, params: &ParamSet) -> Result<()> {
+    fn film(&self, name: String, params: ParamSet) -> Result<()> {
         debug!("Film called with {}", name);
         let state = &mut *self.state.borrow_mut();
         state.api_state.verify_options()?;
         state.render_options.film_name = name;
-        state.render_options.film_params = params.clone();
+        state.render_options.film_params = params;
         Ok(())
     }
 
-    fn sampler(&self, name: String, params: &ParamSet) -> Result<()> {
+    fn sampler(&self, name: String, params: ParamSet) -> Result<()> {
         let state = &mut *self.state.borrow_mut();
         state.api_state.verify_options()?;
         debug!("sampler called");
         state.render_options.sampler_name = name;
-        state.render_options.sampler_params = params.clone();
+        state.render_options.sampler_params = params;
         Ok(())
     }
 
-    fn accelerator(&self, name: String, params: &ParamSet) -> Result<()> {
+    fn accelerator(&self, name: String, params: ParamSet) -> Result<()> {
         let state = &mut *self.state.borrow_mut();
         state.api_state.verify_options()?;
         debug!("accelerator called");
         state.render_options.accelerator_name = name;
-        state.render_options.accelerator_params = params.clone();
+        state.render_options.accelerator_params = params;
         Ok(())
     }
 
-    fn integrator(&self, name: String, params: &ParamSet) -> Result<()> {
+    fn integrator(&self, name: String, params: ParamSet) -> Result<()> {
         debug!("Integrator called with {}", name);
         let state = &mut *self.state.borrow_mut();
         state.api_state.verify_options()?;
         state.render_options.integrator_name = name;
-        state.render_options.integrator_params = params.clone();
+        state.render_options.integrator_params = params;
         Ok(())
     }
 
-    fn camera(&self, name: String, params: &ParamSet) -> Result<()> {
+    fn camera(&self, name: String, params: ParamSet) -> Result<()> {
         let state = &mut *self.state.borrow_mut();
         state.api_state.verify_options()?;
         debug!("Camera called with {}", name);
         state.render_options.camera_name = name;
-        state.render_options.camera_params = params.clone();
-        state.render_options.camera_to_world = state.cur_transform.inverse();
-        let c2w = state.render_options.camera_to_world.clone();
+        state.render_options.camera_params = params;
+        if state.cur_transform.is_animated() {
+            warn!("Animated cameras aren't supported, using the transform at the start time");
+        }
+        state.render_options.camera_to_world = state.cur_transform[0].inverse();
+        state.render_options.camera_transform_given = state.cur_transform[0].m != Matrix4x4::new();
+        let c2w = state.cur_transform.inverse();
         state.named_coordinate_systems.insert("camera".into(), c2w);
         Ok(())
     }
@@ -734,11 +1052,16 @@ impl Api for RealApi {
         let state = &mut *self.state.borrow_mut();
         state.api_state.verify_options()?;
         state.api_state = ApiState::WorldBlock;
+        state.render_options.bvh_params =
+            Some(state.render_options.accelerator_build_params(&self.options));
         let cur_transform = state.cur_transform.clone();
         state
             .named_coordinate_systems
             .insert("world".into(), cur_transform);
-        state.cur_transform = Transform::default();
+        state.cur_transform = TransformSet::default();
+        if self.options.dump_geometry.is_some() {
+            state.render_options.geometry_dump = Some(GeometryDump::new());
+        }
         Ok(())
     }
 
@@ -802,12 +1125,10 @@ impl Api for RealApi {
                     &state.graphics_state.float_textures,
                     &state.graphics_state.spectrum_textures,
                 );
-                make_float_texture(&texname, &state.cur_transform, &tp)
+                make_float_texture(&texname, &state.cur_transform[0], &tp)
             };
             if let Ok(ft) = ft {
-                if state
-                    .graphics_state
-                    .float_textures
+                if Arc::make_mut(&mut state.graphics_state.float_textures)
                     .insert(name.clone(), ft)
                     .is_some()
                 {
@@ -822,13 +1143,11 @@ impl Api for RealApi {
                     &state.graphics_state.float_textures,
                     &state.graphics_state.spectrum_textures,
                 );
-                make_spectrum_texture(&texname, &state.cur_transform, &tp)
+                make_spectrum_texture(&texname, &state.cur_transform[0], &tp)
             };
             match ft {
                 Ok(ft) => {
-                    if state
-                        .graphics_state
-                        .spectrum_textures
+                    if Arc::make_mut(&mut state.graphics_state.spectrum_textures)
                         .insert(name.clone(), ft)
                         .is_some()
                     {
@@ -843,7 +1162,7 @@ impl Api for RealApi {
             error!("Texture type \"{}\" unknown.", typ);
         }
 
-        Ok(())
+        self.check_memory_budget(|| format!("creating texture \"{}\"", name))
     }
 
     fn make_named_material(&self, name: String, params: &ParamSet) -> Result<()> {
@@ -863,11 +1182,14 @@ impl Api for RealApi {
             if mat_name.is_empty() {
                 bail!("No parameter string \"type\" found in named_material");
             }
-            make_material(&mat_name, &mp, &state.graphics_state.named_material)
+            make_material(
+                &mat_name,
+                &mp,
+                &state.graphics_state.named_material,
+                &self.options,
+            )
         };
-        if state
-            .graphics_state
-            .named_material
+        if Arc::make_mut(&mut state.graphics_state.named_material)
             .insert(name.clone(), mtl)
             .is_some()
         {
@@ -876,11 +1198,11 @@ impl Api for RealApi {
         Ok(())
     }
 
-    fn material(&self, name: String, params: &ParamSet) -> Result<()> {
+    fn material(&self, name: String, params: ParamSet) -> Result<()> {
         debug!("Material called with {}", name);
         let state = &mut *self.state.borrow_mut();
         state.graphics_state.material = name;
-        state.graphics_state.material_param = params.clone();
+        state.graphics_state.material_param = Rc::new(params);
         state.graphics_state.current_named_material = String::new();
         Ok(())
     }
@@ -897,16 +1219,19 @@ impl Api for RealApi {
         debug!("Lightsource called with {}", name);
         let state = &mut *self.state.borrow_mut();
         state.api_state.verify_world()?;
-        let lt = self.make_light(&name, params, &state.cur_transform)?;
+        if state.cur_transform.is_animated() {
+            warn!("Animated lights aren't supported, using the transform at the start time");
+        }
+        let lt = self.make_light(&name, params, &state.cur_transform[0])?;
         state.render_options.lights.push(lt);
         Ok(())
     }
 
-    fn arealightsource(&self, name: String, params: &ParamSet) -> Result<()> {
+    fn arealightsource(&self, name: String, params: ParamSet) -> Result<()> {
         debug!("Arealightsource called with {}", name);
         let state = &mut *self.state.borrow_mut();
         state.graphics_state.area_light = name;
-        state.graphics_state.area_light_params = params.clone();
+        state.graphics_state.area_light_params = Rc::new(params);
         Ok(())
     }
 
@@ -915,39 +1240,119 @@ impl Api for RealApi {
         let state = &mut *self.state.borrow_mut();
         state.api_state.verify_world()?;
 
-        let mut prims: Vec<Arc<dyn Primitive>> = Vec::new();
-        let mut area_lights: Vec<Arc<dyn Light>> = Vec::new();
+        if name == "proxy" {
+            if !state.graphics_state.area_light.is_empty() {
+                warn!("Proxies can't be area lights. Ignoring the area light.");
+            }
+            if state.cur_transform.is_animated() {
+                warn!("Animated proxies aren't supported, using the transform at the start time");
+            }
+            let material = state.graphics_state.create_material(params, &self.options);
+            let matte_ids =
+                state
+                    .render_options
+                    .matte_ids(&name, params, state.graphics_state.material_name());
+            let bvh_params = state.render_options.bvh_params(&self.options);
+            let proxy: PrimitiveRef = Arc::new(proxy::create(
+                &state.cur_transform[0],
+                state.graphics_state.reverse_orientation,
+                params,
+                &state.graphics_state.float_textures,
+                material,
+                matte_ids,
+                bvh_params,
+            )?);
+            state.render_options.deferred_meshes += 1;
+            if let Some(name) = &state.render_options.current_instance {
+                state
+                    .render_options
+                    .instances
+                    .get_mut(name)
+                    .ok_or_else(|| format_err!("Unable to find instance named {}", name))?
+                    .push(proxy);
+            } else {
+                state.render_options.primitives.push(Instance::new(proxy));
+            }
+            return Ok(());
+        }
+
+        // Animated shapes are created in object space, and moved over the shutter interval by
+        // the primitive holding them
+        let motion = if state.cur_transform.is_animated() {
+            if !state.graphics_state.area_light.is_empty() {
+                warn!("Animated shapes can't be area lights. Ignoring the area light.");
+            }
+            state.render_options.has_motion = true;
+            Some(state.animated_transform())
+        } else {
+            None
+        };
+        let (object2world, world2object) = match motion {
+            Some(_) => (Transform::default(), Transform::default()),
+            None => (
+                state.cur_transform[0].clone(),
+                state.cur_transform[0].inverse(),
+            ),
+        };
+        let mut prims: Vec<PrimitiveRef> = Vec::new();
         let shapes = make_shapes(
             &name,
-            &state.cur_transform,
-            &state.cur_transform.inverse(),
+            &object2world,
+            &world2object,
             state.graphics_state.reverse_orientation,
             params,
             &state.graphics_state,
-        );
+        )
+        .unwrap_or_else(|e| {
+            warn!("{:#} Ignoring it.", e);
+            Vec::new()
+        });
         let mat = if !shapes.is_empty() {
-            Some(state.graphics_state.create_material(params))
+            Some(state.graphics_state.create_material(params, &self.options))
         } else {
             None
         };
-        for s in shapes {
-            let area = if !state.graphics_state.area_light.is_empty() {
-                let ps = state.graphics_state.area_light_params.clone();
-                let (area_light, light) = make_area_light(
+        let matte_ids =
+            state
+                .render_options
+                .matte_ids(&name, params, state.graphics_state.material_name());
+        if let Some(name) = &state.render_options.current_instance {
+            state
+                .render_options
+                .instance_shapes
+                .entry(name.clone())
+                .or_default()
+                .extend(shapes.iter().cloned());
+        }
+        if let (None, Some(dump)) = (
+            &state.render_options.current_instance,
+            &mut state.render_options.geometry_dump,
+        ) {
+            let dump_transform = match motion {
+                Some(_) => state.cur_transform[0].clone(),
+                None => Transform::default(),
+            };
+            dump.add_shapes(&shapes, &dump_transform);
+        }
+        let (shape_area_lights, mut area_lights) =
+            if motion.is_none() && !state.graphics_state.area_light.is_empty() {
+                make_area_lights(
                     &state.graphics_state.area_light,
-                    &state.cur_transform,
-                    &ps,
-                    Arc::clone(&s),
-                )?;
-                area_lights.push(light);
-                Some(area_light)
+                    &state.cur_transform[0],
+                    &state.graphics_state.area_light_params,
+                    &shapes,
+                )?
             } else {
-                None
+                (Vec::new(), Vec::new())
             };
-            let prim: Arc<dyn Primitive> = Arc::new(GeometricPrimitive {
+        let mut shape_area_lights = shape_area_lights.into_iter();
+        let bvh_params = state.render_options.bvh_params(&self.options);
+        for s in shapes {
+            let prim: PrimitiveRef = Arc::new(GeometricPrimitive {
                 shape: s,
-                area_light: area,
+                area_light: shape_area_lights.next(),
                 material: mat.clone(),
+                matte_ids,
             });
             prims.push(prim);
         }
@@ -957,12 +1362,23 @@ impl Api for RealApi {
                 .instances
                 .get_mut(name)
                 .ok_or_else(|| format_err!("Unable to find instance named {}", name))?;
-            inst.append(&mut prims);
-        } else {
-            state.render_options.primitives.append(&mut prims);
+            match motion {
+                Some(motion) if !prims.is_empty() => {
+                    let blas = bvh::build_blas(&prims, &bvh_params);
+                    inst.push(Arc::new(TransformedPrimitive::animated(blas, motion)));
+                }
+                _ => inst.append(&mut prims),
+            }
+        } else if !prims.is_empty() {
+            let blas = state.render_options.make_blas(&prims, &self.options);
+            let instance = match motion {
+                Some(motion) => Instance::with_motion(blas, motion),
+                None => Instance::new(blas).with_lights(area_lights.clone()),
+            };
+            state.render_options.primitives.push(instance);
             state.render_options.lights.append(&mut area_lights);
         }
-        Ok(())
+        self.check_memory_budget(|| format!("creating shape \"{}\"", name))
     }
 
     fn reverse_orientation(&self) -> Result<()> {
@@ -989,29 +1405,81 @@ impl Api for RealApi {
             let _ = state.pushed_transforms.pop();
         }
 
-        let camera = state.render_options.make_camera()?;
-        let mut integrator = state.render_options.make_integrator(&*camera)?;
-        let mut sampler = state.render_options.make_sampler()?;
-        let scene = state.render_options.make_scene()?;
+        if let Some(parse_start) = self.parse_start.take() {
+            println!("Parse time: {}", HumanDuration(parse_start.elapsed()));
+        }
+        if let (Some(path), Some(dump)) = (
+            &self.options.dump_geometry,
+            &mut state.render_options.geometry_dump,
+        ) {
+            dump.skip(state.render_options.deferred_meshes);
+            dump.write(path)?;
+            println!(
+                "Wrote {} triangles to {}",
+                dump.triangle_count(),
+                path.display()
+            );
+        }
 
+        if self.options.quick_render {
+            info!("Quick render mode: lowering resolution, sample counts and ray depth");
+        }
+        // The scene is built before the camera, as auto-framing needs its bounds
+        let scene = state.render_options.make_scene(&self.options)?;
+        if cancel::interrupted() {
+            bail!("Interrupted while building the scene");
+        }
+        self.check_memory_budget(|| "building the scene".to_owned())?;
+        if state.render_options.should_auto_frame(&self.options) {
+            state.render_options.frame_scene(&scene);
+        }
+        let camera = state.render_options.make_camera(&self.options)?;
+        self.check_memory_budget(|| "creating the film".to_owned())?;
+        let integrator = state
+            .render_options
+            .make_integrator(&*camera, &self.options)?;
+        let sampler = state.render_options.make_sampler(&self.options)?;
         let nthreads = if self.options.num_threads == 0 {
             num_cpus::get()
         } else {
             self.options.num_threads as usize
         };
-        let start_time = ::std::time::Instant::now();
-        renderer::render(
-            &scene,
-            &mut *integrator,
-            &*camera,
-            nthreads,
-            sampler.as_mut(),
-            16,
-        )?;
-        crate::stats::report_stats();
-        let duration = start_time.elapsed();
-        println!("Render time: {}", HumanDuration(duration));
-        crate::stats::print_stats();
+        if state.render_options.deferred_meshes > 0 {
+            info!(
+                "Preloading the visible meshes out of {} deferred ones",
+                state.render_options.deferred_meshes
+            );
+            proxy::preload_visible(&scene, &*camera, nthreads);
+            self.check_memory_budget(|| "loading the visible deferred meshes".to_owned())?;
+        }
+        crate::stats::print_memory_report();
+
+        let mut context = RenderContext {
+            scene,
+            camera,
+            integrator,
+            sampler,
+            threads: WorkerThreads {
+                count: nthreads,
+                numa: self.options.numa,
+            },
+            write_heatmap: self.options.tile_heatmap,
+            time_budget: self.options.time_budget,
+        };
+        if !self.options.defer_render {
+            let start_time = Instant::now();
+            let spp = context.render()?;
+            crate::stats::report_stats();
+            let duration = start_time.elapsed();
+            println!("Render time: {}", HumanDuration(duration));
+            if self.options.time_budget.is_some() {
+                println!("Samples per pixel: {}", spp);
+            }
+            crate::stats::print_stats();
+        }
+        if self.options.interactive || self.options.defer_render {
+            *self.render_context.borrow_mut() = Some(context);
+        }
 
         Ok(())
     }
@@ -1060,6 +1528,7 @@ impl Api for RealApi {
                 "ObjectInstance called inside of instance definition",
             ));
         }
+        let params = state.render_options.bvh_params(&self.options);
         let inst = state
             .render_options
             .instances
@@ -1071,20 +1540,23 @@ impl Api for RealApi {
         n_object_instances_used::inc();
 
         if inst.len() > 1 {
-            // Create aggregate for instance primitives
-            let accel = make_accelerator(
-                &state.render_options.accelerator_name,
-                inst,
-                &state.render_options.accelerator_params,
-            );
+            // Create the BLAS for the instance primitives, the first time the object is used
+            let blas = bvh::build_blas(inst, &params);
             inst.clear();
-            inst.push(accel);
+            inst.push(blas);
         }
-        let prim = Arc::new(TransformedPrimitive {
-            primitive: inst[0].clone(),
-            primitive_to_world: state.cur_transform.clone(),
-        });
-        state.render_options.primitives.push(prim);
+        if let Some(dump) = &mut state.render_options.geometry_dump {
+            if let Some(shapes) = state.render_options.instance_shapes.get(&name) {
+                dump.add_shapes(shapes, &state.cur_transform[0]);
+            }
+        }
+        let instance = if state.cur_transform.is_animated() {
+            state.render_options.has_motion = true;
+            Instance::with_motion(inst[0].clone(), state.animated_transform())
+        } else {
+            Instance::with_transform(inst[0].clone(), state.cur_transform[0].clone())
+        };
+        state.render_options.primitives.push(instance);
 
         Ok(())
     }
@@ -1097,22 +1569,16 @@ fn make_shapes(
     reverse_orientation: bool,
     ps: &ParamSet,
     graphics_state: &GraphicsState,
-) -> Vec<Arc<dyn Shape>> {
-    let mut shapes: Vec<Arc<dyn Shape>> = Vec::new();
+) -> Result<Vec<ShapeRef>> {
+    let mut shapes: Vec<ShapeRef> = Vec::new();
     if name == "sphere" {
         shapes.push(Sphere::create(object2world, reverse_orientation, ps));
     } else if name == "cylinder" {
         shapes.push(Cylinder::create(object2world, reverse_orientation, ps));
     } else if name == "disk" {
         shapes.push(Disk::create(object2world, reverse_orientation, ps));
-    } else if name == "cone" {
-        unimplemented!();
-    } else if name == "paraboloid" {
-        unimplemented!();
-    } else if name == "hyperboloid" {
-        unimplemented!();
-    } else if name == "curve" {
-        unimplemented!();
+    } else if name == "cone" || name == "paraboloid" || name == "hyperboloid" || name == "curve" {
+        bail!("Shape \"{}\" is not supported yet.", name);
     } else if name == "trianglemesh" {
         let mut tris = TriangleMesh::create(
             object2world,
@@ -1122,6 +1588,8 @@ fn make_shapes(
             &graphics_state.float_textures,
         );
         shapes.append(&mut tris);
+    } else if name == "hairfile" {
+        shapes.append(&mut hairfile::create(object2world, reverse_orientation, ps));
     } else if name == "plymesh" {
         let mut tris = plymesh::create(
             object2world,
@@ -1132,69 +1600,113 @@ fn make_shapes(
         );
         shapes.append(&mut tris);
     } else {
-        warn!("Unknown shape {}", name);
+        bail!("Shape \"{}\" unknown.", name);
     }
 
-    shapes
+    Ok(shapes)
 }
 
 fn make_material(
     name: &str,
     mp: &TextureParams<'_>,
-    named_materials: &HashMap<String, Arc<dyn Material>>,
-) -> Arc<dyn Material> {
+    named_materials: &HashMap<String, MaterialRef>,
+    opts: &PbrtOptions,
+) -> MaterialRef {
     n_materials_created::inc();
-    if name == "matte" {
+    try_make_material(name, mp, named_materials, opts).unwrap_or_else(|e| {
+        warn!("{:#} Using matte.", e);
+        MatteMaterial::create(mp)
+    })
+}
+
+fn try_make_material(
+    name: &str,
+    mp: &TextureParams<'_>,
+    named_materials: &HashMap<String, MaterialRef>,
+    opts: &PbrtOptions,
+) -> Result<MaterialRef> {
+    let material = if name == "matte" {
         MatteMaterial::create(mp)
     } else if name == "plastic" {
-        Plastic::create(mp)
+        Plastic::create(mp, opts)
     } else if name == "glass" {
-        GlassMaterial::create(mp)
+        GlassMaterial::create(mp, opts)
     } else if name == "mirror" {
         MirrorMaterial::create(mp)
     } else if name == "metal" {
-        Metal::create(mp)
+        Metal::create(mp, opts)
     } else if name == "substrate" {
-        SubstrateMaterial::create(mp)
+        SubstrateMaterial::create(mp, opts)
     } else if name == "translucent" {
-        TranslucentMaterial::create(mp)
+        TranslucentMaterial::create(mp, opts)
     } else if name == "uber" {
-        UberMaterial::create(mp)
+        UberMaterial::create(mp, opts)
     } else if name == "disney" {
         DisneyMaterial::create(mp)
+    } else if name == "pbr" {
+        PbrMaterial::create(mp)
     } else if name == "mix" {
         let name1 = mp.find_string("namedmaterial1", "");
         let name2 = mp.find_string("namedmaterial2", "");
         let mat1 = named_materials.get(&name1).cloned().unwrap_or_else(|| {
             warn!("Named material \"{}\" undefined. Using \"matte\"", name1);
-            make_material("matte", mp, named_materials)
+            make_material("matte", mp, named_materials, opts)
         });
         let mat2 = named_materials.get(&name2).cloned().unwrap_or_else(|| {
             warn!("Named material \"{}\" undefined. Using \"matte\"", name2);
-            make_material("matte", mp, named_materials)
+            make_material("matte", mp, named_materials, opts)
         });
         MixMaterial::create(mp, mat1, mat2)
+    } else if name == "layered" {
+        let base_name = mp.find_string("namedmaterial", "");
+        let base = named_materials.get(&base_name).cloned().unwrap_or_else(|| {
+            warn!(
+                "Named material \"{}\" undefined. Using \"matte\"",
+                base_name
+            );
+            make_material("matte", mp, named_materials, opts)
+        });
+        LayeredMaterial::create(mp, opts, base)
     } else if name == "fourier" {
-        FourierMaterial::create(mp)
+        FourierMaterial::create(mp)?
     } else {
-        warn!("Unknown material {}. Using matte.", name);
-        MatteMaterial::create(mp)
-    }
+        bail!("Material \"{}\" unknown.", name);
+    };
+
+    Ok(material)
 }
 
-fn make_area_light(
+/// Create the area lights for the shapes created by a `Shape` directive. Returns the area light
+/// of each shape, and the lights to add to the scene.
+///
+/// A shape directive that creates several shapes (e.g. a triangle mesh) gets a single
+/// `DiffuseMeshLight` for all its shapes, unless `"bool pershape"` is set.
+#[allow(clippy::type_complexity)]
+fn make_area_lights(
     name: &str,
     light2world: &Transform,
     params: &ParamSet,
-    shape: Arc<dyn Shape>,
-) -> Result<(Arc<dyn AreaLight>, Arc<dyn Light>)> {
-    if name == "area" || name == "diffuse" {
-        let l = DiffuseAreaLight::create(light2world, params, shape);
-        let light: Arc<dyn Light> = l.clone();
-        let area_light: Arc<dyn AreaLight> = l;
-        Ok((area_light, light))
+    shapes: &[ShapeRef],
+) -> Result<(Vec<AreaLightRef>, Vec<LightRef>)> {
+    if name != "area" && name != "diffuse" {
+        bail!("Area light {} unknown", name);
+    }
+    if shapes.len() > 1 && !params.find_one_bool("pershape", false) {
+        let l = DiffuseMeshLight::create(light2world, params, shapes.to_vec());
+        let light: LightRef = l.clone();
+        let area_light: AreaLightRef = l;
+        Ok((vec![area_light; shapes.len()], vec![light]))
     } else {
-        Err(format_err!("Area light {} unknown", name))
+        let total_area = shapes.iter().map(|s| s.area()).sum();
+        Ok(shapes
+            .iter()
+            .map(|s| {
+                let l = DiffuseAreaLight::create(light2world, params, Arc::clone(s), total_area);
+                let area_light: AreaLightRef = l.clone();
+                let light: LightRef = l;
+                (area_light, light)
+            })
+            .unzip())
     }
 }
 
@@ -1202,8 +1714,8 @@ fn make_float_texture(
     name: &str,
     transform: &Transform,
     tp: &TextureParams<'_>,
-) -> Result<Arc<dyn Texture<f32>>> {
-    let tex: Arc<dyn Texture<f32>> = if name == "constant" {
+) -> Result<TextureRef<f32>> {
+    let tex: TextureRef<f32> = if name == "constant" {
         Arc::new(ConstantTexture::create_float(transform, tp))
     } else if name == "scale" {
         Arc::new(ScaleTexture::<f32>::create(tp))
@@ -1217,43 +1729,339 @@ fn make_float_texture(
         bail!("Unkown texture type {}", name);
     };
 
-    Ok(tex)
+    Ok(supersample_if_requested(tex, tp))
 }
 
 fn make_spectrum_texture(
     name: &str,
     transform: &Transform,
     tp: &TextureParams<'_>,
-) -> Result<Arc<dyn Texture<Spectrum>>> {
-    let tex: Arc<dyn Texture<Spectrum>> = if name == "constant" {
+) -> Result<TextureRef<Spectrum>> {
+    let tex: TextureRef<Spectrum> = if name == "constant" {
         Arc::new(ConstantTexture::create_spectrum(transform, tp))
     } else if name == "scale" {
         Arc::new(ScaleTexture::<Spectrum>::create(tp))
     } else if name == "mix" {
         Arc::new(MixTexture::create_spectrum(transform, tp))
-    } else if name == "bilerp" {
-        unimplemented!()
     } else if name == "imagemap" {
         Arc::new(ImageTexture::<Spectrum>::create(transform, tp))
     } else if name == "uv" {
         Arc::new(UVTexture::create_spectrum(transform, tp))
     } else if name == "checkerboard" {
         Arc::new(CheckerboardTexture::create_spectrum(transform, tp))
-    } else if name == "dots" {
-        unimplemented!()
     } else if name == "fbm" {
         Arc::new(FbmTexture::create_spectrum(transform, tp))
-    } else if name == "wrinkled" {
-        unimplemented!()
-    } else if name == "marble" {
-        unimplemented!()
-    } else if name == "windy" {
-        unimplemented!()
-    } else if name == "ptex" {
-        unimplemented!()
+    } else if ["bilerp", "dots", "wrinkled", "marble", "windy", "ptex"].contains(&name) {
+        bail!("Texture \"{}\" is not supported yet.", name);
     } else {
         bail!("Unkown texture type {}", name);
     };
 
-    Ok(tex)
+    Ok(supersample_if_requested(tex, tp))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::pbrt;
+    use crate::ray::Ray;
+
+    #[test]
+    fn test_attribute_blocks_share_unmodified_state() {
+        let api = RealApi::default();
+        api.init().unwrap();
+        api.world_begin().unwrap();
+        api.texture(
+            "outer".to_owned(),
+            "float".to_owned(),
+            "constant".to_owned(),
+            &ParamSet::default(),
+        )
+        .unwrap();
+
+        api.attribute_begin().unwrap();
+        {
+            let state = api.state.borrow();
+            let saved = &state.pushed_graphics_states[0];
+            assert!(Arc::ptr_eq(
+                &saved.float_textures,
+                &state.graphics_state.float_textures
+            ));
+        }
+        api.texture(
+            "inner".to_owned(),
+            "float".to_owned(),
+            "constant".to_owned(),
+            &ParamSet::default(),
+        )
+        .unwrap();
+        {
+            let state = api.state.borrow();
+            let textures = &state.graphics_state.float_textures;
+            assert!(textures.contains_key("outer") && textures.contains_key("inner"));
+            assert!(!state.pushed_graphics_states[0]
+                .float_textures
+                .contains_key("inner"));
+        }
+        api.attribute_end().unwrap();
+
+        let state = api.state.borrow();
+        assert!(state.graphics_state.float_textures.contains_key("outer"));
+        assert!(!state.graphics_state.float_textures.contains_key("inner"));
+    }
+
+    fn capabilities(directive: &str) -> &'static [&'static str] {
+        CAPABILITIES
+            .iter()
+            .find(|(d, _)| *d == directive)
+            .map(|(_, names)| *names)
+            .unwrap()
+    }
+
+    #[test]
+    fn test_capabilities_are_supported() {
+        let mut ro = RenderOptions::default();
+        for name in capabilities("PixelFilter") {
+            ro.filter_name = (*name).to_owned();
+            assert!(ro.make_filter().is_ok(), "filter {}", name);
+        }
+        for name in capabilities("Sampler") {
+            ro.sampler_name = (*name).to_owned();
+            assert!(
+                ro.make_sampler(&PbrtOptions::default()).is_ok(),
+                "sampler {}",
+                name
+            );
+        }
+
+        let ps = ParamSet::default();
+        let (ft, st) = (HashMap::new(), HashMap::new());
+        let tp = TextureParams::new(&ps, &ps, &ft, &st);
+        let t = Transform::default();
+        for name in capabilities("Texture \"float\"") {
+            assert!(
+                make_float_texture(name, &t, &tp).is_ok(),
+                "texture {}",
+                name
+            );
+        }
+        for name in capabilities("Texture \"spectrum\"") {
+            assert!(
+                make_spectrum_texture(name, &t, &tp).is_ok(),
+                "texture {}",
+                name
+            );
+        }
+        let params = ParamSet::default();
+        for name in capabilities("LightSource") {
+            let api = RealApi::default();
+            assert!(api.make_light(name, &params, &t).is_ok(), "light {}", name);
+        }
+
+        // An empty tabulated BSDF for the "fourier" material
+        let bsdf_file = std::env::temp_dir().join("rustracer_capabilities.bsdf");
+        let mut bsdf = b"SCATFUN\x01".to_vec();
+        for v in &[1u32, 0, 0, 0, 1, 1, 0, 0, 0, 0, 0, 0, 0, 0] {
+            bsdf.extend_from_slice(&v.to_ne_bytes());
+        }
+        std::fs::write(&bsdf_file, bsdf).unwrap();
+        let mut mp = ParamSet::default();
+        mp.init(vec![ParamListEntry::new(
+            ParamType::String,
+            "bsdffile".to_owned(),
+            Array::StrArray(vec![bsdf_file.to_string_lossy().into_owned()]),
+        )]);
+        let mtp = TextureParams::new(&mp, &ps, &ft, &st);
+        let opts = PbrtOptions::default();
+        let named_materials = HashMap::new();
+        for name in capabilities("Material") {
+            assert!(
+                try_make_material(name, &mtp, &named_materials, &opts).is_ok(),
+                "material {}",
+                name
+            );
+        }
+        let graphics_state = GraphicsState::default();
+        for name in capabilities("Shape") {
+            // Proxies are created by the `Shape` directive itself
+            assert!(
+                *name == "proxy"
+                    || make_shapes(name, &t, &t, false, &params, &graphics_state).is_ok(),
+                "shape {}",
+                name
+            );
+        }
+
+        // A textured triangle for the "bake" camera, as the default object
+        let mut tri = ParamSet::default();
+        tri.init(vec![
+            ParamListEntry::new(
+                ParamType::Int,
+                "indices".to_owned(),
+                Array::NumArray(vec![0.0, 1.0, 2.0]),
+            ),
+            ParamListEntry::new(
+                ParamType::Point3,
+                "P".to_owned(),
+                Array::NumArray(vec![0.0, 0.0, 0.0, 1.0, 0.0, 0.0, 0.0, 1.0, 0.0]),
+            ),
+            ParamListEntry::new(
+                ParamType::Float,
+                "uv".to_owned(),
+                Array::NumArray(vec![0.0, 0.0, 1.0, 0.0, 0.0, 1.0]),
+            ),
+        ]);
+        let mut ro = RenderOptions::default();
+        ro.instance_shapes.insert(
+            String::new(),
+            make_shapes("trianglemesh", &t, &t, false, &tri, &graphics_state).unwrap(),
+        );
+        for name in capabilities("Camera") {
+            ro.camera_name = (*name).to_owned();
+            assert!(ro.make_camera(&opts).is_ok(), "camera {}", name);
+        }
+        ro.camera_name = "perspective".to_owned();
+        let camera = ro.make_camera(&opts).unwrap();
+        for name in capabilities("Integrator") {
+            ro.integrator_name = (*name).to_owned();
+            assert!(
+                ro.make_integrator(camera.as_ref(), &opts).is_ok(),
+                "integrator {}",
+                name
+            );
+        }
+    }
+
+    #[test]
+    fn test_active_transforms_make_shapes_and_instances_move() {
+        crate::init_stats();
+        let opts = PbrtOptions {
+            num_threads: 1,
+            defer_render: true,
+            ..PbrtOptions::default()
+        };
+        // A sphere moving from x = -5 to x = -3, and an instance of one moving from x = 5 to x = 7
+        let scene = r#"
+LookAt 0 0 -5  0 0 0  0 1 0
+Camera "perspective"
+Sampler "02sequence"
+TransformTimes 0 2
+WorldBegin
+ObjectBegin "ball"
+Shape "sphere" "float radius" [1]
+ObjectEnd
+AttributeBegin
+Translate -5 0 0
+ActiveTransform EndTime
+Translate 2 0 0
+ActiveTransform All
+Shape "sphere" "float radius" [1]
+AttributeEnd
+AttributeBegin
+ActiveTransform StartTime
+Translate 5 0 0
+ActiveTransform EndTime
+Translate 7 0 0
+ObjectInstance "ball"
+AttributeEnd
+Shape "sphere" "float radius" [0.5]
+WorldEnd
+"#;
+        let context = pbrt::parse_scene_string(opts, scene).unwrap().unwrap();
+        let scene = &context.scene;
+        assert!(scene.has_motion());
+        let ray_at = |x: f32, time: f32| {
+            Ray::new(Point3f::new(x, 0.0, -5.0), Vector3f::new(0.0, 0.0, 1.0)).at_time(time)
+        };
+        assert!(scene.intersect_p(&ray_at(-5.0, 0.0)));
+        assert!(!scene.intersect_p(&ray_at(-5.0, 2.0)));
+        assert!(scene.intersect_p(&ray_at(-4.0, 1.0)));
+        assert!(scene.intersect_p(&ray_at(-3.0, 2.0)));
+        assert!(scene.intersect_p(&ray_at(5.0, 0.0)));
+        assert!(!scene.intersect_p(&ray_at(5.0, 2.0)));
+        assert!(scene.intersect_p(&ray_at(7.0, 2.0)));
+        // The static sphere after the attribute blocks doesn't move
+        assert!(scene.intersect_p(&ray_at(0.0, 0.0)));
+        assert!(scene.intersect_p(&ray_at(0.0, 2.0)));
+    }
+
+    #[test]
+    fn test_max_memory_aborts_scene_building() {
+        crate::init_stats();
+        let scene = r#"
+Film "image" "integer xresolution" [4] "integer yresolution" [4]
+Sampler "02sequence" "integer pixelsamples" [1]
+WorldBegin
+Shape "trianglemesh" "integer indices" [0 1 2] "point P" [0 0 0  1 0 0  0 1 0]
+WorldEnd
+"#;
+        let opts = PbrtOptions {
+            defer_render: true,
+            max_memory: Some(64),
+            ..PbrtOptions::default()
+        };
+        let err = pbrt::parse_scene_string(opts, scene).err().unwrap();
+        assert!(
+            format!("{:#}", err).contains("after creating shape \"trianglemesh\""),
+            "{:#}",
+            err
+        );
+
+        let opts = PbrtOptions {
+            defer_render: true,
+            max_memory: Some(1 << 30),
+            ..PbrtOptions::default()
+        };
+        pbrt::parse_scene_string(opts, scene).unwrap();
+    }
+
+    #[test]
+    fn test_camera_is_moved_to_frame_the_scene() {
+        crate::init_stats();
+        let camera_position = |camera: &str, auto_frame: bool| {
+            let opts = PbrtOptions {
+                defer_render: true,
+                auto_frame,
+                ..PbrtOptions::default()
+            };
+            let scene = format!(
+                r#"
+{}
+Camera "perspective" "float fov" [60]
+Film "image" "integer xresolution" [4] "integer yresolution" [4]
+Sampler "02sequence" "integer pixelsamples" [1]
+WorldBegin
+Translate 0 0 10
+Shape "sphere" "float radius" [1]
+WorldEnd
+"#,
+                camera
+            );
+            let context = pbrt::parse_scene_string(opts, &scene).unwrap().unwrap();
+            let c2w = context.camera.camera_to_world();
+            (
+                c2w * &Point3f::new(0.0, 0.0, 0.0),
+                (c2w * &Vector3f::new(0.0, 0.0, 1.0)).normalize(),
+            )
+        };
+        let close = |a: Point3f, b: Point3f| (a - b).length() < 1e-3;
+
+        // No camera transform: the camera backs away from the sphere's bounding box along +z
+        let distance = f32::sqrt(3.0) / f32::sin(30f32.to_radians());
+        let (eye, dir) = camera_position("", false);
+        assert!(
+            close(eye, Point3f::new(0.0, 0.0, 10.0 - distance)),
+            "{}",
+            eye
+        );
+        assert!((dir.z - 1.0).abs() < 1e-4);
+
+        // A camera placed by the scene is left alone, unless auto-framing is requested
+        let look_at = "LookAt 0 10 10  0 0 10  0 0 1";
+        let (eye, _) = camera_position(look_at, false);
+        assert!(close(eye, Point3f::new(0.0, 10.0, 10.0)), "{}", eye);
+        let (eye, dir) = camera_position(look_at, true);
+        assert!(close(eye, Point3f::new(0.0, distance, 10.0)), "{}", eye);
+        assert!((dir.y + 1.0).abs() < 1e-4);
+    }
 }
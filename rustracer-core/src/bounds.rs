@@ -1,13 +1,14 @@
 use std::cmp::PartialOrd;
 use std::f32;
 use std::fmt;
-use std::ops::{DivAssign, Index, SubAssign};
+use std::mem;
+use std::ops::{DivAssign, Index, IndexMut, SubAssign};
 
 use num::{Bounded, Num, Signed};
 
 use crate::geometry::{Point2, Point3, Vector2, Vector3};
 use crate::ray::Ray;
-use crate::{lerp, max, min, Point2f, Point2i, Point3f, Vector3f};
+use crate::{gamma, lerp, max, min, Point2f, Point2i, Point3f, Vector3f};
 
 pub type Bounds3f = Bounds3<f32>;
 
@@ -113,15 +114,24 @@ where
         b
     }
 
-    pub fn intersect_p(&self, ray: &Ray) -> bool {
-        let invdir = Vector3f::new(1.0 / ray.d.x, 1.0 / ray.d.y, 1.0 / ray.d.z);
-        let sign = [
-            (ray.d.x < 0.0) as usize,
-            (ray.d.y < 0.0) as usize,
-            (ray.d.z < 0.0) as usize,
-        ];
+    /// The region covered by both boxes, which is empty (see `is_empty()`) if they don't overlap.
+    pub fn intersection(bbox1: &Bounds3<T>, bbox2: &Bounds3<T>) -> Bounds3<T> {
+        Bounds3 {
+            p_min: Point3::new(
+                max(bbox1.p_min.x, bbox2.p_min.x),
+                max(bbox1.p_min.y, bbox2.p_min.y),
+                max(bbox1.p_min.z, bbox2.p_min.z),
+            ),
+            p_max: Point3::new(
+                min(bbox1.p_max.x, bbox2.p_max.x),
+                min(bbox1.p_max.y, bbox2.p_max.y),
+                min(bbox1.p_max.z, bbox2.p_max.z),
+            ),
+        }
+    }
 
-        self.intersect_p_fast(ray, &invdir, &sign)
+    pub fn is_empty(&self) -> bool {
+        self.p_min.x > self.p_max.x || self.p_min.y > self.p_max.y || self.p_min.z > self.p_max.z
     }
 
     pub fn intersect_p_fast(&self, ray: &Ray, inv_dir: &Vector3f, dir_is_neg: &[usize; 3]) -> bool {
@@ -215,6 +225,32 @@ impl Bounds3<f32> {
         let d = self.diagonal();
         2.0 * (d.x * d.y + d.x * d.z + d.y * d.z)
     }
+
+    /// Intersect `ray` with the box, returning the parametric range `(t0, t1)` of the ray that
+    /// is inside it, clipped to `[0, ray.t_max]`, or `None` if the ray misses it. This is useful
+    /// for culling, e.g. to skip parts of a scene that a camera can't see.
+    pub fn intersect_p(&self, ray: &Ray) -> Option<(f32, f32)> {
+        let mut t0 = 0.0;
+        let mut t1 = ray.t_max;
+        for i in 0..3 {
+            // Update interval for _i_th bounding box slab
+            let inv_ray_dir = 1.0 / ray.d[i];
+            let mut t_near = (self.p_min[i] - ray.o[i]) * inv_ray_dir;
+            let mut t_far = (self.p_max[i] - ray.o[i]) * inv_ray_dir;
+            if t_near > t_far {
+                mem::swap(&mut t_near, &mut t_far);
+            }
+            // Update t_far to ensure robust ray-bounds intersection
+            t_far *= 1.0 + 2.0 * gamma(3);
+            t0 = if t_near > t0 { t_near } else { t0 };
+            t1 = if t_far < t1 { t_far } else { t1 };
+            if t0 > t1 {
+                return None;
+            }
+        }
+
+        Some((t0, t1))
+    }
 }
 
 impl<T> Default for Bounds3<T>
@@ -456,6 +492,16 @@ impl Index<Axis> for Point3<f32> {
     }
 }
 
+impl IndexMut<Axis> for Point3<f32> {
+    fn index_mut(&mut self, axis: Axis) -> &mut f32 {
+        match axis {
+            Axis::X => &mut self.x,
+            Axis::Y => &mut self.y,
+            Axis::Z => &mut self.z,
+        }
+    }
+}
+
 impl Index<Axis> for Vector3<f32> {
     type Output = f32;
 
@@ -509,21 +555,21 @@ mod tests {
     fn bounds2_iterator_degenerate() {
         {
             let b = Bounds2i::from_elements(0, 0, 0, 10);
-            for p in &b {
+            if let Some(p) = (&b).into_iter().next() {
                 panic!("should not have reached this point! p = {}", p);
             }
         }
 
         {
             let b2 = Bounds2i::from_elements(0, 0, 4, 0);
-            for p in &b2 {
+            if let Some(p) = (&b2).into_iter().next() {
                 panic!("should not have reached this point! p = {}", p);
             }
         }
 
         {
             let b3 = Bounds2i::new();
-            for p in &b3 {
+            if let Some(p) = (&b3).into_iter().next() {
                 panic!("should not have reached this point! p = {}", p);
             }
         }
@@ -555,4 +601,31 @@ mod tests {
         let e = Bounds2i::union(&a, &d);
         assert_eq!(Bounds2i::from_elements(-15, -10, 0, 20), e);
     }
+
+    #[test]
+    fn test_bounds3_intersect_p() {
+        let b = Bounds3f::from_points(
+            &Point3f::new(-1.0, -1.0, -1.0),
+            &Point3f::new(1.0, 1.0, 1.0),
+        );
+        let ray = Ray::new(Point3f::new(-3.0, 0.5, 0.0), Vector3f::new(1.0, 0.0, 0.0));
+        let (t0, t1) = b.intersect_p(&ray).unwrap();
+        assert!((t0 - 2.0).abs() < 1e-5);
+        assert!((t1 - 4.0).abs() < 1e-5);
+
+        // Origin inside the box
+        let ray = Ray::new(Point3f::new(0.0, 0.0, 0.0), Vector3f::new(0.0, 0.0, -1.0));
+        let (t0, t1) = b.intersect_p(&ray).unwrap();
+        assert_eq!(t0, 0.0);
+        assert!((t1 - 1.0).abs() < 1e-5);
+
+        let ray = Ray::new(Point3f::new(-3.0, 1.5, 0.0), Vector3f::new(1.0, 0.0, 0.0));
+        assert!(b.intersect_p(&ray).is_none());
+        let ray = Ray::segment(
+            Point3f::new(-3.0, 0.0, 0.0),
+            Vector3f::new(1.0, 0.0, 0.0),
+            1.0,
+        );
+        assert!(b.intersect_p(&ray).is_none());
+    }
 }
@@ -1,7 +1,7 @@
 use std::f32::consts;
 use std::fmt::Debug;
 
-use super::BxDFType;
+use super::{fr_dielectric, BxDFType};
 use crate::geometry::{abs_cos_theta, same_hemisphere};
 use crate::sampling::cosine_sample_hemisphere;
 use crate::spectrum::Spectrum;
@@ -65,7 +65,72 @@ impl<'a> BxDF for ScaledBxDF<'a> {
         (spectrum * self.scale, wi, pdf, bxdftype)
     }
 
+    fn pdf(&self, wo: &Vector3f, wi: &Vector3f) -> f32 {
+        self.bxdf.pdf(wo, wi)
+    }
+
     fn get_type(&self) -> BxDFType {
         self.bxdf.get_type()
     }
 }
+
+/// Wraps a BxDF that lies underneath a smooth dielectric coating: light has to be transmitted
+/// through the coating on the way in and on the way out, so the BxDF gets attenuated by
+/// `(1 - Fr(wo)) * (1 - Fr(wi)) * tint`, where `tint` accounts for absorption in the coating.
+#[derive(Debug, Clone, Copy)]
+pub struct CoatedBxDF<'a> {
+    bxdf: &'a dyn BxDF,
+    eta: f32,
+    tint: Spectrum,
+}
+
+impl<'a> CoatedBxDF<'a> {
+    pub fn new(bxdf: &'a dyn BxDF, eta: f32, tint: Spectrum) -> CoatedBxDF<'a> {
+        CoatedBxDF { bxdf, eta, tint }
+    }
+
+    fn transmittance(&self, wo: &Vector3f, wi: &Vector3f) -> Spectrum {
+        let t_o = 1.0 - fr_dielectric(abs_cos_theta(wo), 1.0, self.eta);
+        let t_i = 1.0 - fr_dielectric(abs_cos_theta(wi), 1.0, self.eta);
+        self.tint * t_o * t_i
+    }
+}
+
+impl<'a> BxDF for CoatedBxDF<'a> {
+    fn f(&self, wo: &Vector3f, wi: &Vector3f) -> Spectrum {
+        self.bxdf.f(wo, wi) * self.transmittance(wo, wi)
+    }
+
+    fn sample_f(&self, wo: &Vector3f, sample: Point2f) -> (Spectrum, Vector3f, f32, BxDFType) {
+        let (spectrum, wi, pdf, bxdftype) = self.bxdf.sample_f(wo, sample);
+        (spectrum * self.transmittance(wo, &wi), wi, pdf, bxdftype)
+    }
+
+    fn pdf(&self, wo: &Vector3f, wi: &Vector3f) -> f32 {
+        self.bxdf.pdf(wo, wi)
+    }
+
+    fn get_type(&self) -> BxDFType {
+        self.bxdf.get_type()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bsdf::LambertianReflection;
+
+    #[test]
+    fn test_coated_bxdf_attenuation() {
+        let base = LambertianReflection::new(Spectrum::white());
+        let coated = CoatedBxDF::new(&base, 1.5, Spectrum::white());
+        let n = Vector3f::new(0.0, 0.0, 1.0);
+        // At normal incidence, a dielectric of IOR 1.5 reflects 4% of the light
+        let expected = consts::FRAC_1_PI * 0.96 * 0.96;
+        assert!((coated.f(&n, &n).r - expected).abs() < 1e-4);
+        // Grazing angles are mostly reflected by the coating
+        let grazing = Vector3f::new(0.999, 0.0, 0.0447).normalize();
+        assert!(coated.f(&grazing, &n).r < 0.5 * coated.f(&n, &n).r);
+        assert_eq!(coated.pdf(&n, &n), base.pdf(&n, &n));
+    }
+}
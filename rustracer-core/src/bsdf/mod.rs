@@ -74,11 +74,7 @@ pub struct Bsdf<'a> {
 }
 
 impl<'a> Bsdf<'a> {
-    pub fn new<'b>(
-        isect: &'b SurfaceInteraction<'_, '_>,
-        eta: f32,
-        bxdfs: &'a [&'a dyn BxDF],
-    ) -> Bsdf<'a> {
+    pub fn new(isect: &SurfaceInteraction, eta: f32, bxdfs: &'a [&'a dyn BxDF]) -> Bsdf<'a> {
         let ss = isect.shading.dpdu.normalize();
         Bsdf {
             eta,
@@ -1,30 +1,88 @@
+use std::any::Any;
 use std::cmp::min;
 use std::mem::replace;
 use std::sync::Arc;
 
+use indicatif::{ProgressBar, ProgressStyle};
 use itertools as it;
-use light_arena::Allocator;
 use log::{info, warn};
 
 use crate::bounds::{Axis, Bounds3f};
+use crate::cancel::CancellationToken;
+use crate::cryptomatte::MatteIds;
 use crate::interaction::SurfaceInteraction;
-use crate::light::AreaLight;
-use crate::material::{Material, TransportMode};
+use crate::light::AreaLightRef;
+use crate::material::MaterialRef;
 use crate::paramset::ParamSet;
-use crate::primitive::{GeometricPrimitive, Primitive};
+use crate::primitive::{GeometricPrimitive, Primitive, PrimitiveRef};
 use crate::ray::Ray;
-use crate::shapes::Shape;
-use crate::{Point3f, Vector3f};
+use crate::shapes::ShapeRef;
+use crate::{PbrtOptions, Point3f, Vector3f};
+
+mod sbvh;
+mod tlas;
+
+pub use self::tlas::{build_blas, Instance, Tlas};
 
 stat_memory_counter!("Memory/BVH tree", tree_bytes);
 stat_ratio!("BVH/Primitives per leaf node", total_primitives_per_leaf);
 stat_counter!("BVH/Interior nodes", interior_nodes);
 stat_counter!("BVH/Leaf nodes", leaf_nodes);
+stat_counter!("BVH/Subtrees collapsed into leaves", collapsed_subtrees);
+stat_ratio!("BVH/SAH cost per tree", sah_cost_per_tree);
+stat_counter!("BVH/Spatial splits", spatial_splits);
+stat_counter!("BVH/Duplicated references", duplicated_references);
+stat_ratio!("BVH/Nodes visited per ray", nodes_visited_per_ray);
+stat_ratio!("BVH/Primitive tests per ray", primitive_tests_per_ray);
+stat_int_distribution!("BVH/Tree depth", tree_depth);
+stat_int_distribution!("BVH/Traversal stack depth", traversal_stack_depth);
+stat_counter!("BVH/Traversal stack overflows", traversal_stack_overflows);
 pub fn init_stats() {
     tree_bytes::init();
     total_primitives_per_leaf::init();
     interior_nodes::init();
     leaf_nodes::init();
+    collapsed_subtrees::init();
+    sah_cost_per_tree::init();
+    spatial_splits::init();
+    duplicated_references::init();
+    nodes_visited_per_ray::init();
+    primitive_tests_per_ray::init();
+    tree_depth::init();
+    traversal_stack_depth::init();
+    traversal_stack_overflows::init();
+    tlas::init_stats();
+}
+
+/// Default maximum number of references duplicated by spatial splits, as a fraction of the
+/// number of primitives.
+pub const DEFAULT_DUPLICATION_BUDGET: f32 = 0.3;
+
+/// Default number of buckets used to approximate the SAH when building the tree.
+pub const DEFAULT_SAH_BUCKETS: usize = 12;
+
+/// Builds for fewer primitives than this are quick enough not to need a progress bar
+const PROGRESS_MIN_PRIMITIVES: usize = 1_000_000;
+
+/// Parameters of the surface area heuristic.
+#[derive(Copy, Clone, Debug)]
+pub struct SahParams {
+    /// Number of buckets used to approximate the SAH
+    pub n_buckets: usize,
+    /// Relative cost of traversing an interior node
+    pub traversal_cost: f32,
+    /// Relative cost of intersecting a primitive
+    pub intersect_cost: f32,
+}
+
+impl Default for SahParams {
+    fn default() -> Self {
+        SahParams {
+            n_buckets: DEFAULT_SAH_BUCKETS,
+            traversal_cost: 1.0,
+            intersect_cost: 1.0,
+        }
+    }
 }
 
 #[derive(Copy, Clone, Debug)]
@@ -32,27 +90,106 @@ pub enum SplitMethod {
     Middle,
     EqualCounts,
     SAH,
+    /// SAH with spatial splits (see the `sbvh` module). `budget` is the maximum number of
+    /// duplicated references, as a fraction of the number of primitives.
+    SBVH {
+        budget: f32,
+    },
+}
+
+/// How to build a BVH, as given by the parameters of the "bvh" accelerator.
+#[derive(Copy, Clone, Debug)]
+pub struct BuildParams {
+    pub max_prims_per_node: usize,
+    pub split_method: SplitMethod,
+    pub sah: SahParams,
+}
+
+impl BuildParams {
+    pub fn create(ps: &ParamSet, opts: &PbrtOptions) -> BuildParams {
+        let split_method_name = ps.find_one_string("splitmethod", "sah".into());
+        let split_method = if split_method_name == "sah" {
+            SplitMethod::SAH
+        } else if split_method_name == "middle" {
+            SplitMethod::Middle
+        } else if split_method_name == "sbvh" {
+            let mut budget = ps.find_one_float("duplicationbudget", DEFAULT_DUPLICATION_BUDGET);
+            if budget < 0.0 {
+                warn!(
+                    "Invalid \"duplicationbudget\" value {}: it can't be negative. Using {}.",
+                    budget, DEFAULT_DUPLICATION_BUDGET
+                );
+                budget = DEFAULT_DUPLICATION_BUDGET;
+            }
+            SplitMethod::SBVH { budget }
+        } else {
+            warn!(
+                "Unknown (or unimplemented) BVH split method {}.  Using \"sah\"",
+                split_method_name
+            );
+            SplitMethod::SAH
+        };
+        let max_prims_per_node = ps.find_one_int("maxnodeprims", 4);
+
+        let default = SahParams::default();
+        let mut n_buckets = ps.find_one_int("sahbuckets", default.n_buckets as i32);
+        if n_buckets < 2 {
+            warn!(
+                "Invalid \"sahbuckets\" value {}: the SAH needs at least 2 buckets. Using {}.",
+                n_buckets, default.n_buckets
+            );
+            n_buckets = default.n_buckets as i32;
+        }
+        let cost = |name: &str, default: f32| {
+            let c = ps.find_one_float(name, default);
+            if c > 0.0 {
+                c
+            } else {
+                warn!(
+                    "Invalid \"{}\" value {}: it must be positive. Using {}.",
+                    name, c, default
+                );
+                default
+            }
+        };
+        let sah = SahParams {
+            n_buckets: opts.sah_buckets(n_buckets as usize),
+            traversal_cost: cost("traversalcost", default.traversal_cost),
+            intersect_cost: cost("intersectcost", default.intersect_cost),
+        };
+        BuildParams {
+            max_prims_per_node: max_prims_per_node as usize,
+            split_method,
+            sah,
+        }
+    }
 }
 
 #[derive(Debug)]
 pub struct BVH {
     #[allow(dead_code)]
     max_prims_per_node: usize,
-    primitives: Vec<Arc<dyn Primitive>>,
+    sah: SahParams,
+    primitives: Vec<PrimitiveRef>,
+    /// Position in `primitives` of each of the primitives the BVH was built from
+    primitive_positions: Vec<usize>,
     nodes: Vec<LinearBVHNode>,
+    /// Number of nodes on the longest path from the root to a leaf
+    depth: usize,
 }
 
 impl BVH {
-    pub fn from_triangles(mut tris: Vec<Arc<dyn Shape>>, material: &Arc<dyn Material>) -> BVH {
-        let prims: Vec<Arc<dyn Primitive>> = tris
+    pub fn from_triangles(mut tris: Vec<ShapeRef>, material: &MaterialRef) -> BVH {
+        let prims: Vec<PrimitiveRef> = tris
             .drain(..)
             .map(|t| {
                 let prim = GeometricPrimitive {
                     shape: Arc::clone(&t),
                     area_light: None,
                     material: Some(Arc::clone(material)),
+                    matte_ids: MatteIds::default(),
                 };
-                let b: Arc<dyn Primitive> = Arc::new(prim);
+                let b: PrimitiveRef = Arc::new(prim);
                 b
             })
             .collect();
@@ -60,28 +197,82 @@ impl BVH {
         BVH::new(1, &prims, SplitMethod::SAH)
     }
 
-    pub fn create(prims: &[Arc<dyn Primitive>], ps: &ParamSet) -> BVH {
-        let split_method_name = ps.find_one_string("splitmethod", "sah".into());
-        let split_method = if split_method_name == "sah" {
-            SplitMethod::SAH
-        } else if split_method_name == "middle" {
-            SplitMethod::Middle
-        } else {
-            warn!(
-                "Unknown (or unimplemented) BVH split method {}.  Using \"sah\"",
-                split_method_name
-            );
-            SplitMethod::SAH
-        };
-        let max_prims_per_node = ps.find_one_int("maxnodeprims", 4);
-        BVH::new(max_prims_per_node as usize, prims, split_method)
+    pub fn create(prims: &[PrimitiveRef], ps: &ParamSet, opts: &PbrtOptions) -> BVH {
+        BVH::build(prims, &BuildParams::create(ps, opts))
+    }
+
+    pub fn build(prims: &[PrimitiveRef], params: &BuildParams) -> BVH {
+        BVH::with_sah_params(
+            params.max_prims_per_node,
+            prims,
+            params.split_method,
+            params.sah,
+        )
     }
 
     pub fn new(
         max_prims_per_node: usize,
-        prims: &[Arc<dyn Primitive>],
+        prims: &[PrimitiveRef],
+        split_method: SplitMethod,
+    ) -> BVH {
+        BVH::with_sah_buckets(max_prims_per_node, prims, split_method, DEFAULT_SAH_BUCKETS)
+    }
+
+    /// Build a BVH using `n_buckets` buckets to evaluate the SAH (if `split_method` is
+    /// `SplitMethod::SAH`). Fewer buckets make for a faster build of a lesser quality tree.
+    pub fn with_sah_buckets(
+        max_prims_per_node: usize,
+        prims: &[PrimitiveRef],
+        split_method: SplitMethod,
+        n_buckets: usize,
+    ) -> BVH {
+        let sah = SahParams {
+            n_buckets,
+            ..SahParams::default()
+        };
+        BVH::with_sah_params(max_prims_per_node, prims, split_method, sah)
+    }
+
+    /// Build a BVH using the given SAH parameters (if `split_method` is `SplitMethod::SAH`).
+    pub fn with_sah_params(
+        max_prims_per_node: usize,
+        prims: &[PrimitiveRef],
         split_method: SplitMethod,
+        sah: SahParams,
     ) -> BVH {
+        BVH::with_cancellation(
+            max_prims_per_node,
+            prims,
+            split_method,
+            sah,
+            &CancellationToken::new(),
+        )
+    }
+
+    /// Build a BVH, stopping early if `cancel` gets cancelled. The tree is then still valid but
+    /// of poor quality (the remaining primitives end up in big leaves), so it should be thrown
+    /// away.
+    pub fn with_cancellation(
+        max_prims_per_node: usize,
+        prims: &[PrimitiveRef],
+        split_method: SplitMethod,
+        sah: SahParams,
+        cancel: &CancellationToken,
+    ) -> BVH {
+        assert!(sah.n_buckets >= 2, "the SAH needs at least 2 buckets");
+        if prims.is_empty() {
+            // e.g. a scene with only an environment light. An empty BVH is valid, it just never
+            // reports any intersection.
+            info!("No primitives: creating an empty BVH");
+            return BVH {
+                max_prims_per_node: min(max_prims_per_node, 255),
+                sah,
+                primitives: Vec::new(),
+                primitive_positions: Vec::new(),
+                nodes: Vec::new(),
+                depth: 0,
+            };
+        }
         info!("Generating BVH with method {:?}:", split_method);
 
         // 1. Get bounds info
@@ -96,54 +287,103 @@ impl BVH {
         info!("\tBuilding tree for {} primitives", prims.len());
         let mut total_nodes = 0;
         let mut ordered_prims = Vec::with_capacity(prims.len());
-        let root: BVHBuildNode = BVH::recursive_build(
-            prims,
-            &mut primitive_info,
-            0usize,
-            prims.len(),
-            max_prims_per_node,
-            &mut total_nodes,
-            &mut ordered_prims,
-            split_method,
-        );
+        let progress = BuildProgress::new(prims.len(), cancel.clone());
+        let root: BVHBuildNode = if let SplitMethod::SBVH { budget } = split_method {
+            let mut builder =
+                sbvh::SbvhBuilder::new(prims, max_prims_per_node, sah, budget, &progress);
+            let root = builder.build();
+            total_nodes = builder.total_nodes;
+            ordered_prims = builder.ordered_prims;
+            root
+        } else {
+            BVH::recursive_build(
+                &mut primitive_info,
+                0usize,
+                prims.len(),
+                max_prims_per_node,
+                &mut total_nodes,
+                &mut ordered_prims,
+                split_method,
+                &sah,
+                &progress,
+            )
+        };
+        progress.finish();
+        if cancel.is_cancelled() {
+            warn!("BVH build cancelled");
+        }
 
         info!("\tCreated {} nodes", total_nodes);
 
-        // 3. Build flatten representation
+        // 3. Collapse the subtrees that are cheaper to intersect as a single leaf
+        let (root, _) = root.collapse(min(max_prims_per_node, 255), &sah);
+        let total_nodes = root.node_count();
+        let depth = root.depth();
+        root.record_stats();
+        tree_depth::report_value(depth as u64);
+        info!(
+            "\tCollapsed tree down to {} nodes (depth {})",
+            total_nodes, depth
+        );
+        if depth > TRAVERSAL_STACK_SIZE {
+            warn!(
+                "BVH is {} nodes deep: traversal will spill over to the heap past {} nodes",
+                depth, TRAVERSAL_STACK_SIZE
+            );
+        }
+
+        // 4. Build flatten representation
         info!("\tFlattening tree");
         let mut nodes = Vec::with_capacity(total_nodes);
         BVH::flatten_bvh(&root, &mut nodes);
         assert_eq!(nodes.len(), total_nodes);
 
+        // With spatial splits, a primitive may appear several times: this keeps the last one
+        let mut primitive_positions = vec![0; prims.len()];
+        for (pos, &prim_num) in ordered_prims.iter().enumerate() {
+            primitive_positions[prim_num] = pos;
+        }
         let bvh = BVH {
             max_prims_per_node: min(max_prims_per_node, 255),
-            primitives: ordered_prims,
+            sah,
+            primitives: ordered_prims
+                .iter()
+                .map(|&prim_num| Arc::clone(&prims[prim_num]))
+                .collect(),
+            primitive_positions,
             nodes,
+            depth,
         };
         tree_bytes::add(
             (total_nodes * ::std::mem::size_of::<LinearBVHNode>()
                 + ::std::mem::size_of_val(&bvh)
-                + prims.len() * ::std::mem::size_of_val(&prims[0])) as u64,
+                + bvh.primitives.len() * ::std::mem::size_of_val(&prims[0])) as u64,
         );
+        let sah_cost = bvh.sah_cost();
+        sah_cost_per_tree::add((sah_cost * 1000.0).round() as u64);
+        sah_cost_per_tree::add_total(1000);
         info!(
-            "BVH created with {} nodes for {} primitives",
+            "BVH created with {} nodes for {} primitives (SAH cost {})",
             total_nodes,
-            bvh.primitives.len()
+            bvh.primitives.len(),
+            sah_cost
         );
 
         bvh
     }
 
     fn recursive_build(
-        primitives: &[Arc<dyn Primitive>],
         primitive_info: &mut Vec<BVHPrimitiveInfo>,
         start: usize,
         end: usize,
         max_prims_per_node: usize,
         total_nodes: &mut usize,
-        ordered_prims: &mut Vec<Arc<dyn Primitive>>,
+        ordered_prims: &mut Vec<usize>,
         split_method: SplitMethod,
+        sah: &SahParams,
+        progress: &BuildProgress,
     ) -> BVHBuildNode {
+        let n_buckets = sah.n_buckets;
         *total_nodes += 1;
         let n_primitives = end - start;
         assert_ne!(start, end);
@@ -151,12 +391,13 @@ impl BVH {
         let bounds = primitive_info[start..end]
             .iter()
             .fold(Bounds3f::new(), |b, pi| Bounds3f::union(&b, &pi.bounds));
-        if n_primitives == 1 {
+        if n_primitives == 1 || progress.is_cancelled() {
             // Create leaf
+            progress.add_leaf(n_primitives);
             let first_prim_offset = ordered_prims.len();
             for pi in primitive_info[start..end].iter() {
                 let prim_num = pi.prim_number;
-                ordered_prims.push(Arc::clone(&primitives[prim_num]));
+                ordered_prims.push(prim_num);
             }
             BVHBuildNode::leaf(first_prim_offset, n_primitives, bounds)
         } else {
@@ -170,10 +411,11 @@ impl BVH {
             let dimension = centroids_bounds.maximum_extent();
             // Partition primitives into 2 sets and build children
             if centroids_bounds[0][dimension] == centroids_bounds[1][dimension] {
+                progress.add_leaf(n_primitives);
                 let first_prim_offset = ordered_prims.len();
                 for pi in primitive_info[start..end].iter() {
                     let prim_num = pi.prim_number;
-                    ordered_prims.push(Arc::clone(&primitives[prim_num]));
+                    ordered_prims.push(prim_num);
                 }
                 return BVHBuildNode::leaf(first_prim_offset, n_primitives, bounds);
             }
@@ -186,8 +428,7 @@ impl BVH {
                     mid = start
                         + it::partition(primitive_info[start..end].iter_mut(), |pi| {
                             pi.centroid[dimension] < pmid
-                        })
-                        + start;
+                        });
                     if mid == start || mid == end {
                         // If partition failed, used Split Equal method
                         primitive_info[start..end].sort_by(|p1, p2| {
@@ -199,6 +440,7 @@ impl BVH {
                     }
                 }
                 SplitMethod::EqualCounts => unimplemented!(),
+                SplitMethod::SBVH { .. } => unreachable!("SBVHs have their own builder"),
                 SplitMethod::SAH => {
                     // Partition primitives using approximate SAH
                     if n_primitives <= 2 {
@@ -211,27 +453,26 @@ impl BVH {
                             primitive_info.swap(start, end - 1);
                         }
                     } else {
-                        const N_BUCKETS: usize = 12;
                         // Allocate `BucketInfo for SAH partition buckets
-                        let mut buckets = [BucketInfo::default(); 12];
+                        let mut buckets = vec![BucketInfo::default(); n_buckets];
 
                         // Initialize `BucketInfo` for SAH partition buckets
                         for prim_inf in primitive_info.iter().take(end).skip(start) {
-                            let mut b = (N_BUCKETS as f32
+                            let mut b = (n_buckets as f32
                                 * centroids_bounds.offset(&prim_inf.centroid)[dimension])
                                 as usize;
-                            if b == N_BUCKETS {
-                                b = N_BUCKETS - 1;
+                            if b == n_buckets {
+                                b = n_buckets - 1;
                             }
-                            assert!(b < N_BUCKETS);
+                            assert!(b < n_buckets);
                             buckets[b].count += 1;
                             buckets[b].bounds =
                                 Bounds3f::union(&buckets[b].bounds, &prim_inf.bounds);
                         }
 
                         // Compute costs for splitting after each bucket
-                        let mut cost = [0.0; N_BUCKETS - 1];
-                        for (i, cost_i) in cost.iter_mut().enumerate().take(N_BUCKETS - 1) {
+                        let mut cost = vec![0.0; n_buckets - 1];
+                        for (i, cost_i) in cost.iter_mut().enumerate().take(n_buckets - 1) {
                             let mut b0 = Bounds3f::new();
                             let mut b1 = Bounds3f::new();
                             let mut count0 = 0;
@@ -240,20 +481,21 @@ impl BVH {
                                 b0 = Bounds3f::union(&b0, &bucket.bounds);
                                 count0 += bucket.count;
                             }
-                            for bucket in buckets.iter().take(N_BUCKETS).skip(i + 1) {
+                            for bucket in buckets.iter().take(n_buckets).skip(i + 1) {
                                 b1 = Bounds3f::union(&b1, &bucket.bounds);
                                 count1 += bucket.count;
                             }
-                            *cost_i = 1.0
-                                + (count0 as f32 * b0.surface_area()
-                                    + count1 as f32 * b1.surface_area())
+                            *cost_i = sah.traversal_cost
+                                + sah.intersect_cost
+                                    * (count0 as f32 * b0.surface_area()
+                                        + count1 as f32 * b1.surface_area())
                                     / bounds.surface_area();
                         }
 
                         // Find bucket to split at that minimizes SAH metric
                         let mut min_cost = cost[0];
                         let mut min_cost_split_bucket = 0;
-                        for (i, cost_i) in cost.iter().enumerate().take(N_BUCKETS - 1).skip(1) {
+                        for (i, cost_i) in cost.iter().enumerate().take(n_buckets - 1).skip(1) {
                             if *cost_i < min_cost {
                                 min_cost = *cost_i;
                                 min_cost_split_bucket = i;
@@ -261,25 +503,26 @@ impl BVH {
                         }
 
                         // Either create leaf of split primitives at selected SAH bucket
-                        let leaf_cost = n_primitives as f32;
+                        let leaf_cost = sah.intersect_cost * n_primitives as f32;
                         if n_primitives > max_prims_per_node || min_cost < leaf_cost {
                             mid = start
                                 + it::partition(primitive_info[start..end].iter_mut(), |pi| {
-                                    let mut b = (N_BUCKETS as f32
+                                    let mut b = (n_buckets as f32
                                         * centroids_bounds.offset(&pi.centroid)[dimension])
                                         as usize;
-                                    if b == N_BUCKETS {
-                                        b = N_BUCKETS - 1;
+                                    if b == n_buckets {
+                                        b = n_buckets - 1;
                                     }
-                                    assert!(b < N_BUCKETS);
+                                    assert!(b < n_buckets);
                                     b <= min_cost_split_bucket
                                 });
                         } else {
                             // Create leaf `BVHBuildNode`
+                            progress.add_leaf(n_primitives);
                             let first_prim_offset = ordered_prims.len();
                             for prim_inf in primitive_info.iter().take(end).skip(start) {
                                 let prim_num = prim_inf.prim_number;
-                                ordered_prims.push(Arc::clone(&primitives[prim_num]));
+                                ordered_prims.push(prim_num);
                             }
                             return BVHBuildNode::leaf(first_prim_offset, n_primitives, bounds);
                         }
@@ -288,7 +531,6 @@ impl BVH {
             }
 
             let right = Box::new(BVH::recursive_build(
-                primitives,
                 primitive_info,
                 mid,
                 end,
@@ -296,9 +538,10 @@ impl BVH {
                 total_nodes,
                 ordered_prims,
                 split_method,
+                sah,
+                progress,
             ));
             let left = Box::new(BVH::recursive_build(
-                primitives,
                 primitive_info,
                 start,
                 mid,
@@ -306,11 +549,71 @@ impl BVH {
                 total_nodes,
                 ordered_prims,
                 split_method,
+                sah,
+                progress,
             ));
             BVHBuildNode::interior(dimension, left, right)
         }
     }
 
+    /// Return the `index`-th primitive the BVH was built from (i.e. in the order they were passed
+    /// to `BVH::new()`), so that it can be modified or replaced. `refit()` must be called after
+    /// changing the bounds of any primitive.
+    pub fn primitive_mut(&mut self, index: usize) -> Option<&mut PrimitiveRef> {
+        let pos = *self.primitive_positions.get(index)?;
+        Some(&mut self.primitives[pos])
+    }
+
+    /// Recompute the bounds of all the nodes, bottom-up, from the current bounds of the
+    /// primitives. This keeps the topology of the tree, so it's much cheaper than rebuilding it
+    /// but the quality of the tree will degrade if primitives move a lot.
+    pub fn refit(&mut self) {
+        // Children are always stored after their parent, so a reverse traversal visits them first
+        for i in (0..self.nodes.len()).rev() {
+            let bounds = match self.nodes[i].data {
+                LinearBVHNodeData::Leaf {
+                    num_prims,
+                    primitives_offset,
+                } => self.primitives[primitives_offset..primitives_offset + num_prims]
+                    .iter()
+                    .fold(Bounds3f::new(), |b, p| {
+                        Bounds3f::union(&b, &p.world_bounds())
+                    }),
+                LinearBVHNodeData::Interior {
+                    second_child_offset,
+                    ..
+                } => Bounds3f::union(
+                    &self.nodes[i + 1].bounds,
+                    &self.nodes[second_child_offset].bounds,
+                ),
+            };
+            self.nodes[i].bounds = bounds;
+        }
+    }
+
+    /// SAH cost of the tree: the expected cost of tracing a ray through it, assuming rays that
+    /// hit the root's bounds are uniformly distributed, relative to the SAH parameters it was
+    /// built with.
+    pub fn sah_cost(&self) -> f32 {
+        let root_area = match self.nodes.first() {
+            Some(root) if root.bounds.surface_area() > 0.0 => root.bounds.surface_area(),
+            Some(_) => return self.sah.intersect_cost * self.primitives.len() as f32,
+            None => return 0.0,
+        };
+        self.nodes
+            .iter()
+            .map(|node| {
+                let cost = match node.data {
+                    LinearBVHNodeData::Interior { .. } => self.sah.traversal_cost,
+                    LinearBVHNodeData::Leaf { num_prims, .. } => {
+                        self.sah.intersect_cost * num_prims as f32
+                    }
+                };
+                cost * node.bounds.surface_area() / root_area
+            })
+            .sum()
+    }
+
     fn flatten_bvh(node: &BVHBuildNode, nodes: &mut Vec<LinearBVHNode>) -> usize {
         let offset = nodes.len();
 
@@ -342,8 +645,8 @@ impl BVH {
                     },
                 };
                 nodes.push(linear_node);
-                BVH::flatten_bvh(&*children[0], nodes);
-                let second_offset = BVH::flatten_bvh(&*children[1], nodes);
+                BVH::flatten_bvh(&children[0], nodes);
+                let second_offset = BVH::flatten_bvh(&children[1], nodes);
                 let _prev = replace(
                     &mut nodes[offset].data,
                     LinearBVHNodeData::Interior {
@@ -360,18 +663,25 @@ impl BVH {
 
 impl Primitive for BVH {
     fn world_bounds(&self) -> Bounds3f {
-        self.nodes[0].bounds
+        self.nodes
+            .first()
+            .map_or_else(Bounds3f::new, |root| root.bounds)
+    }
+
+    fn as_any_mut(&mut self) -> &mut dyn Any {
+        self
     }
 
-    fn intersect(&self, ray: &mut Ray) -> Option<SurfaceInteraction<'_, '_>> {
+    fn intersect(&self, ray: &mut Ray) -> Option<SurfaceInteraction> {
         if self.nodes.is_empty() {
             return None;
         }
         let mut result = None;
+        let mut n_visited = 0;
+        let mut n_tests = 0;
 
-        let mut to_visit_offset = 0;
         let mut current_node_idx = 0;
-        let mut nodes_to_visit = [0; 64];
+        let mut nodes_to_visit = TraversalStack::new();
         let inv_dir = Vector3f::new(1.0 / ray.d.x, 1.0 / ray.d.y, 1.0 / ray.d.z);
         let dir_is_neg = [
             (inv_dir.x < 0.0) as usize,
@@ -379,6 +689,7 @@ impl Primitive for BVH {
             (inv_dir.z < 0.0) as usize,
         ];
         loop {
+            n_visited += 1;
             let linear_node = &self.nodes[current_node_idx];
             if linear_node
                 .bounds
@@ -389,16 +700,16 @@ impl Primitive for BVH {
                         num_prims,
                         primitives_offset,
                     } => {
+                        n_tests += num_prims;
                         for i in 0..num_prims {
                             result = self.primitives[primitives_offset + i]
                                 .intersect(ray)
                                 .or(result);
                         }
-                        if to_visit_offset == 0 {
-                            break;
+                        match nodes_to_visit.pop() {
+                            Some(idx) => current_node_idx = idx,
+                            None => break,
                         }
-                        to_visit_offset -= 1;
-                        current_node_idx = nodes_to_visit[to_visit_offset];
                     }
                     LinearBVHNodeData::Interior {
                         axis,
@@ -411,24 +722,24 @@ impl Primitive for BVH {
                             Axis::Z => 2,
                         };
                         if dir_is_neg[axis_num] != 0 {
-                            nodes_to_visit[to_visit_offset] = current_node_idx + 1;
-                            to_visit_offset += 1;
+                            nodes_to_visit.push(current_node_idx + 1);
                             current_node_idx = second_child_offset;
                         } else {
-                            nodes_to_visit[to_visit_offset] = second_child_offset;
-                            to_visit_offset += 1;
+                            nodes_to_visit.push(second_child_offset);
                             current_node_idx += 1;
                         }
+                        // Only the siblings of the nodes on the current path are stacked
+                        debug_assert!(nodes_to_visit.len() < self.depth);
                     }
                 }
             } else {
-                if to_visit_offset == 0 {
-                    break;
+                match nodes_to_visit.pop() {
+                    Some(idx) => current_node_idx = idx,
+                    None => break,
                 }
-                to_visit_offset -= 1;
-                current_node_idx = nodes_to_visit[to_visit_offset];
             }
         }
+        record_traversal(n_visited, n_tests, &nodes_to_visit);
         result
     }
 
@@ -437,9 +748,11 @@ impl Primitive for BVH {
             return false;
         }
 
-        let mut to_visit_offset = 0;
+        let mut n_visited = 0;
+        let mut n_tests = 0;
+
         let mut current_node_idx = 0;
-        let mut nodes_to_visit = [0; 64];
+        let mut nodes_to_visit = TraversalStack::new();
         let inv_dir = Vector3f::new(1.0 / ray.d.x, 1.0 / ray.d.y, 1.0 / ray.d.z);
         let dir_is_neg = [
             (inv_dir.x < 0.0) as usize,
@@ -447,6 +760,7 @@ impl Primitive for BVH {
             (inv_dir.z < 0.0) as usize,
         ];
         loop {
+            n_visited += 1;
             let linear_node = &self.nodes[current_node_idx];
             if linear_node
                 .bounds
@@ -458,15 +772,16 @@ impl Primitive for BVH {
                         primitives_offset,
                     } => {
                         for i in 0..num_prims {
+                            n_tests += 1;
                             if self.primitives[primitives_offset + i].intersect_p(ray) {
+                                record_traversal(n_visited, n_tests, &nodes_to_visit);
                                 return true;
                             }
                         }
-                        if to_visit_offset == 0 {
-                            break;
+                        match nodes_to_visit.pop() {
+                            Some(idx) => current_node_idx = idx,
+                            None => break,
                         }
-                        to_visit_offset -= 1;
-                        current_node_idx = nodes_to_visit[to_visit_offset];
                     }
                     LinearBVHNodeData::Interior {
                         axis,
@@ -479,43 +794,141 @@ impl Primitive for BVH {
                             Axis::Z => 2,
                         };
                         if dir_is_neg[axis_num] != 0 {
-                            nodes_to_visit[to_visit_offset] = current_node_idx + 1;
-                            to_visit_offset += 1;
+                            nodes_to_visit.push(current_node_idx + 1);
                             current_node_idx = second_child_offset;
                         } else {
-                            nodes_to_visit[to_visit_offset] = second_child_offset;
-                            to_visit_offset += 1;
+                            nodes_to_visit.push(second_child_offset);
                             current_node_idx += 1;
                         }
+                        // Only the siblings of the nodes on the current path are stacked
+                        debug_assert!(nodes_to_visit.len() < self.depth);
                     }
                 }
             } else {
-                if to_visit_offset == 0 {
-                    break;
+                match nodes_to_visit.pop() {
+                    Some(idx) => current_node_idx = idx,
+                    None => break,
                 }
-                to_visit_offset -= 1;
-                current_node_idx = nodes_to_visit[to_visit_offset];
             }
         }
+        record_traversal(n_visited, n_tests, &nodes_to_visit);
         false
     }
 
-    fn area_light(&self) -> Option<Arc<dyn AreaLight>> {
+    fn area_light(&self) -> Option<AreaLightRef> {
         panic!("area_light() should not be called on an Aggregate Primitive!");
     }
 
-    fn material(&self) -> Option<Arc<dyn Material>> {
+    fn material(&self) -> Option<MaterialRef> {
         panic!("material() should not be called on an Aggregate Primitive!");
     }
+}
+
+fn record_traversal(n_visited: usize, n_tests: usize, stack: &TraversalStack) {
+    nodes_visited_per_ray::add(n_visited as u64);
+    primitive_tests_per_ray::add(n_tests as u64);
+    traversal_stack_depth::report_value(stack.max_len as u64);
+}
 
-    fn compute_scattering_functions<'a, 'b>(
-        &self,
-        _isect: &mut SurfaceInteraction<'a, 'b>,
-        _mode: TransportMode,
-        _allow_multiple_lobes: bool,
-        _arena: &'b Allocator<'_>,
-    ) {
-        panic!("compute_scattering_functions() should not be called on an Aggregate Primitive!");
+/// Number of nodes the traversal stack holds before spilling over to the heap. The trees are
+/// usually much shallower than this, but nothing bounds the depth of a tree built from
+/// pathological inputs.
+const TRAVERSAL_STACK_SIZE: usize = 64;
+
+/// Stack of the nodes left to visit when traversing a BVH: a fixed size array, with a vector
+/// for the (rare) entries that don't fit in it.
+struct TraversalStack {
+    nodes: [usize; TRAVERSAL_STACK_SIZE],
+    overflow: Vec<usize>,
+    len: usize,
+    max_len: usize,
+}
+
+impl TraversalStack {
+    fn new() -> TraversalStack {
+        TraversalStack {
+            nodes: [0; TRAVERSAL_STACK_SIZE],
+            overflow: Vec::new(),
+            len: 0,
+            max_len: 0,
+        }
+    }
+
+    #[inline]
+    fn len(&self) -> usize {
+        self.len
+    }
+
+    #[inline]
+    fn push(&mut self, node: usize) {
+        if self.len < TRAVERSAL_STACK_SIZE {
+            self.nodes[self.len] = node;
+        } else {
+            if self.overflow.is_empty() {
+                traversal_stack_overflows::inc();
+            }
+            self.overflow.push(node);
+        }
+        self.len += 1;
+        self.max_len = usize::max(self.max_len, self.len);
+    }
+
+    #[inline]
+    fn pop(&mut self) -> Option<usize> {
+        if self.len == 0 {
+            return None;
+        }
+        self.len -= 1;
+        if self.len < TRAVERSAL_STACK_SIZE {
+            Some(self.nodes[self.len])
+        } else {
+            self.overflow.pop()
+        }
+    }
+}
+
+/// Count a ray traced through the scene for the per-ray traversal stats. This is done by the
+/// scene rather than by each BVH, as a ray can go through several of them (e.g. the TLAS and
+/// BLASes).
+pub(crate) fn count_ray() {
+    nodes_visited_per_ray::inc_total();
+    primitive_tests_per_ray::inc_total();
+}
+
+/// Progress of a BVH build, shown on a progress bar for large builds, and the token telling
+/// whether it should stop early.
+struct BuildProgress {
+    bar: ProgressBar,
+    cancel: CancellationToken,
+}
+
+impl BuildProgress {
+    fn new(n_prims: usize, cancel: CancellationToken) -> BuildProgress {
+        let bar = if n_prims >= PROGRESS_MIN_PRIMITIVES {
+            let bar = ProgressBar::new(n_prims as u64);
+            bar.set_style(
+                ProgressStyle::default_bar()
+                    .progress_chars("=>-")
+                    .template("Building BVH [{elapsed_precise}] [{wide_bar}] {percent}% {eta}"),
+            );
+            bar
+        } else {
+            ProgressBar::hidden()
+        };
+        BuildProgress { bar, cancel }
+    }
+
+    /// Count the primitives put in a new leaf as processed.
+    fn add_leaf(&self, n_prims: usize) {
+        self.bar.inc(n_prims as u64);
+    }
+
+    fn is_cancelled(&self) -> bool {
+        self.cancel.is_cancelled()
+    }
+
+    fn finish(&self) {
+        self.bar.finish_and_clear();
     }
 }
 
@@ -535,11 +948,15 @@ impl BVHPrimitiveInfo {
     }
 }
 
+/// A node of the tree being built. The primitives of any subtree are contiguous in the ordered
+/// primitives, so that it can be turned into a leaf.
 enum BVHBuildNode {
     Interior {
         bounds: Bounds3f,
         children: [Box<BVHBuildNode>; 2],
         split_axis: Axis,
+        first_prim_offset: usize,
+        num_prims: usize,
     },
     Leaf {
         bounds: Bounds3f,
@@ -551,18 +968,19 @@ enum BVHBuildNode {
 impl BVHBuildNode {
     fn interior(axis: Axis, child1: Box<BVHBuildNode>, child2: Box<BVHBuildNode>) -> BVHBuildNode {
         let bbox = Bounds3f::union(child1.bounds(), child2.bounds());
-        interior_nodes::inc();
+        let (first1, n1) = child1.prims();
+        let (first2, n2) = child2.prims();
+        debug_assert!(first1 + n1 == first2 || first2 + n2 == first1);
         BVHBuildNode::Interior {
             bounds: bbox,
             children: [child1, child2],
             split_axis: axis,
+            first_prim_offset: min(first1, first2),
+            num_prims: n1 + n2,
         }
     }
 
     fn leaf(first_prim_offset: usize, num_prims: usize, bbox: Bounds3f) -> BVHBuildNode {
-        leaf_nodes::inc();
-        total_primitives_per_leaf::add(num_prims as u64);
-        total_primitives_per_leaf::inc_total();
         BVHBuildNode::Leaf {
             bounds: bbox,
             first_prim_offset,
@@ -570,6 +988,111 @@ impl BVHBuildNode {
         }
     }
 
+    /// Offset and number of the primitives of the subtree.
+    fn prims(&self) -> (usize, usize) {
+        match *self {
+            BVHBuildNode::Interior {
+                first_prim_offset,
+                num_prims,
+                ..
+            }
+            | BVHBuildNode::Leaf {
+                first_prim_offset,
+                num_prims,
+                ..
+            } => (first_prim_offset, num_prims),
+        }
+    }
+
+    /// Turn the subtrees whose SAH cost exceeds the cost of intersecting all their primitives
+    /// into leaves, bottom-up, as long as they have at most `max_prims_per_node` primitives. The
+    /// builders always split nodes with few primitives (and the "middle" split method ignores
+    /// the SAH altogether), which leaves trees deeper than they need to be.
+    ///
+    /// Returns the new node and its SAH cost, relative to its own surface area.
+    fn collapse(self, max_prims_per_node: usize, sah: &SahParams) -> (BVHBuildNode, f32) {
+        match self {
+            BVHBuildNode::Leaf { num_prims, .. } => {
+                let cost = sah.intersect_cost * num_prims as f32;
+                (self, cost)
+            }
+            BVHBuildNode::Interior {
+                bounds,
+                children: [child1, child2],
+                split_axis,
+                first_prim_offset,
+                num_prims,
+            } => {
+                let (child1, cost1) = child1.collapse(max_prims_per_node, sah);
+                let (child2, cost2) = child2.collapse(max_prims_per_node, sah);
+                let area = bounds.surface_area();
+                let children_cost = if area > 0.0 {
+                    (child1.bounds().surface_area() * cost1
+                        + child2.bounds().surface_area() * cost2)
+                        / area
+                } else {
+                    // Flat bounds: every ray that hits the node hits both children
+                    cost1 + cost2
+                };
+                let split_cost = sah.traversal_cost + children_cost;
+                let leaf_cost = sah.intersect_cost * num_prims as f32;
+                if num_prims <= max_prims_per_node && split_cost > leaf_cost {
+                    collapsed_subtrees::inc();
+                    (
+                        BVHBuildNode::leaf(first_prim_offset, num_prims, bounds),
+                        leaf_cost,
+                    )
+                } else {
+                    (
+                        BVHBuildNode::Interior {
+                            bounds,
+                            children: [Box::new(child1), Box::new(child2)],
+                            split_axis,
+                            first_prim_offset,
+                            num_prims,
+                        },
+                        split_cost,
+                    )
+                }
+            }
+        }
+    }
+
+    /// Number of nodes on the longest path from this node to a leaf.
+    fn depth(&self) -> usize {
+        match self {
+            BVHBuildNode::Interior { children, .. } => {
+                1 + usize::max(children[0].depth(), children[1].depth())
+            }
+            BVHBuildNode::Leaf { .. } => 1,
+        }
+    }
+
+    fn node_count(&self) -> usize {
+        match self {
+            BVHBuildNode::Interior { children, .. } => {
+                1 + children[0].node_count() + children[1].node_count()
+            }
+            BVHBuildNode::Leaf { .. } => 1,
+        }
+    }
+
+    /// Record the node stats for the final tree.
+    fn record_stats(&self) {
+        match self {
+            BVHBuildNode::Interior { children, .. } => {
+                interior_nodes::inc();
+                children[0].record_stats();
+                children[1].record_stats();
+            }
+            BVHBuildNode::Leaf { num_prims, .. } => {
+                leaf_nodes::inc();
+                total_primitives_per_leaf::add(*num_prims as u64);
+                total_primitives_per_leaf::inc_total();
+            }
+        }
+    }
+
     fn bounds(&self) -> &Bounds3f {
         match self {
             BVHBuildNode::Interior { ref bounds, .. } | BVHBuildNode::Leaf { ref bounds, .. } => {
@@ -602,3 +1125,257 @@ struct BucketInfo {
     pub count: usize,
     pub bounds: Bounds3f,
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::primitive::TransformedPrimitive;
+    use crate::scene::Scene;
+    use crate::shapes::{AttributeIndices, Sphere, Triangle, TriangleMesh};
+    use crate::transform::AnimatedTransform;
+    use crate::Transform;
+
+    fn spheres(n: usize) -> Vec<PrimitiveRef> {
+        (0..n)
+            .map(|i| {
+                let o2w = Transform::translate(&Vector3f::new(3.0 * i as f32, 0.0, 0.0));
+                let shape = Arc::new(Sphere::new(o2w, 1.0, -1.0, 1.0, 360.0, false));
+                let prim: PrimitiveRef = Arc::new(GeometricPrimitive {
+                    shape,
+                    area_light: None,
+                    material: None,
+                    matte_ids: MatteIds::default(),
+                });
+                prim
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_sah_costs_shape_the_tree() {
+        let prims = spheres(4);
+
+        // Splitting pays off with the default costs...
+        let bvh = BVH::with_sah_params(4, &prims, SplitMethod::SAH, SahParams::default());
+        assert!(bvh.nodes.len() > 1);
+        assert!(bvh.sah_cost() < 4.0);
+
+        // ... but not if traversing a node is much more expensive than intersecting everything
+        let sah = SahParams {
+            traversal_cost: 100.0,
+            ..SahParams::default()
+        };
+        let bvh = BVH::with_sah_params(4, &prims, SplitMethod::SAH, sah);
+        assert_eq!(bvh.nodes.len(), 1);
+        assert_eq!(bvh.sah_cost(), 4.0);
+    }
+
+    #[test]
+    fn test_collapse_overlapping_leaves() {
+        // Heavily overlapping spheres: splitting them doesn't pay off
+        let prims: Vec<PrimitiveRef> = (0..4)
+            .map(|i| {
+                let o2w = Transform::translate(&Vector3f::new(0.1 * i as f32, 0.0, 0.0));
+                let shape = Arc::new(Sphere::new(o2w, 1.0, -1.0, 1.0, 360.0, false));
+                let prim: PrimitiveRef = Arc::new(GeometricPrimitive {
+                    shape,
+                    area_light: None,
+                    material: None,
+                    matte_ids: MatteIds::default(),
+                });
+                prim
+            })
+            .collect();
+
+        // The "middle" split method always splits down to single primitives...
+        let bvh = BVH::new(4, &prims, SplitMethod::Middle);
+        assert_eq!(bvh.nodes.len(), 1);
+        assert_eq!(bvh.sah_cost(), 4.0);
+        // ... but leaves can't get bigger than the maximum number of primitives per node
+        let bvh = BVH::new(2, &prims, SplitMethod::Middle);
+        assert_eq!(bvh.nodes.len(), 3);
+        let bvh = BVH::new(1, &prims, SplitMethod::Middle);
+        assert_eq!(bvh.nodes.len(), 7);
+
+        // Well separated primitives are left alone
+        let bvh = BVH::new(4, &spheres(4), SplitMethod::Middle);
+        assert_eq!(bvh.nodes.len(), 7);
+    }
+
+    #[test]
+    fn test_deep_tree_traversal() {
+        // Exponentially spaced spheres: each middle split only peels off the furthest one
+        let prims: Vec<PrimitiveRef> = (0..70)
+            .map(|i| {
+                let o2w = Transform::translate(&Vector3f::new(3f32.powi(i), 0.0, 0.0));
+                let shape = Arc::new(Sphere::new(o2w, 0.5, -0.5, 0.5, 360.0, false));
+                let prim: PrimitiveRef = Arc::new(GeometricPrimitive {
+                    shape,
+                    area_light: None,
+                    material: None,
+                    matte_ids: MatteIds::default(),
+                });
+                prim
+            })
+            .collect();
+        let bvh = BVH::new(1, &prims, SplitMethod::Middle);
+        assert!(bvh.depth > TRAVERSAL_STACK_SIZE);
+
+        // A ray along the spine of the tree stacks a node at every level
+        let mut ray = Ray::new(Point3f::new(-10.0, 0.0, 0.0), Vector3f::new(1.0, 0.0, 0.0));
+        assert!(bvh.intersect_p(&ray));
+        let hit = bvh.intersect(&mut ray).unwrap();
+        assert!((hit.hit.p.x - 0.5).abs() < 1e-4);
+        let mut ray = Ray::new(Point3f::new(-10.0, 0.0, 0.0), Vector3f::new(-1.0, 0.0, 0.0));
+        assert!(!bvh.intersect_p(&ray));
+        assert!(bvh.intersect(&mut ray).is_none());
+    }
+
+    #[test]
+    fn test_cancelled_build() {
+        let prims = spheres(64);
+        let cancel = CancellationToken::new();
+        let bvh =
+            BVH::with_cancellation(1, &prims, SplitMethod::SAH, SahParams::default(), &cancel);
+        assert!(bvh.nodes.len() > 1);
+
+        // Cancelling stops the build right away: everything ends up in the root, but the tree is
+        // still usable
+        cancel.cancel();
+        for split_method in &[SplitMethod::SAH, SplitMethod::SBVH { budget: 0.3 }] {
+            let bvh =
+                BVH::with_cancellation(1, &prims, *split_method, SahParams::default(), &cancel);
+            assert_eq!(bvh.nodes.len(), 1);
+            assert_eq!(bvh.primitives.len(), prims.len());
+            let mut ray = Ray::new(Point3f::new(-10.0, 0.0, 0.0), Vector3f::new(1.0, 0.0, 0.0));
+            let hit = bvh.intersect(&mut ray).unwrap();
+            assert!((hit.hit.p.x + 1.0).abs() < 1e-4);
+        }
+    }
+
+    #[test]
+    fn test_traversal_stack_overflow() {
+        let mut stack = TraversalStack::new();
+        for i in 0..2 * TRAVERSAL_STACK_SIZE {
+            stack.push(i);
+        }
+        assert_eq!(stack.len(), 2 * TRAVERSAL_STACK_SIZE);
+        for i in (0..2 * TRAVERSAL_STACK_SIZE).rev() {
+            assert_eq!(stack.pop(), Some(i));
+        }
+        assert_eq!(stack.pop(), None);
+        assert_eq!(stack.max_len, 2 * TRAVERSAL_STACK_SIZE);
+    }
+
+    /// Parallel strands running diagonally across the XY plane, like hair cards
+    fn strands(n: usize) -> Vec<PrimitiveRef> {
+        let mut indices = Vec::new();
+        let mut p = Vec::new();
+        for i in 0..n {
+            let offset = i as f32 * 0.2;
+            p.push(Point3f::new(offset, 0.0, 0.0));
+            p.push(Point3f::new(offset + 0.05, 0.0, 0.0));
+            p.push(Point3f::new(offset + 10.0, 10.0, 0.0));
+            indices.extend_from_slice(&[3 * i, 3 * i + 1, 3 * i + 2]);
+        }
+        let mesh = Arc::new(TriangleMesh::new(
+            &Transform::default(),
+            &indices,
+            &p,
+            None,
+            None,
+            None,
+            AttributeIndices::default(),
+            None,
+            None,
+            false,
+        ));
+        (0..n)
+            .map(|i| {
+                let prim: PrimitiveRef = Arc::new(GeometricPrimitive {
+                    shape: Arc::new(Triangle::new(Arc::clone(&mesh), i, false)),
+                    area_light: None,
+                    material: None,
+                    matte_ids: MatteIds::default(),
+                });
+                prim
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_spatial_splits() {
+        let prims = strands(32);
+        let sah = BVH::new(1, &prims, SplitMethod::SAH);
+        let sbvh = BVH::new(1, &prims, SplitMethod::SBVH { budget: 4.0 });
+        let sbvh_no_budget = BVH::new(1, &prims, SplitMethod::SBVH { budget: 0.0 });
+
+        // Spatial splits duplicate references, within the budget
+        assert!(sbvh.primitives.len() > prims.len());
+        assert!(sbvh.primitives.len() <= 5 * prims.len());
+        assert_eq!(sbvh_no_budget.primitives.len(), prims.len());
+        // ... which produces a cheaper tree for overlapping primitives
+        assert!(sbvh.sah_cost() < 0.9 * sah.sah_cost());
+
+        // Both trees find the same hits
+        for i in 0..50 {
+            for j in 0..50 {
+                let o = Point3f::new(i as f32 * 0.25, j as f32 * 0.25, -1.0);
+                let mut r1 = Ray::new(o, Vector3f::new(0.0, 0.0, 1.0));
+                let mut r2 = r1;
+                let h1 = sah.intersect(&mut r1).map(|si| si.hit.p);
+                let h2 = sbvh.intersect(&mut r2).map(|si| si.hit.p);
+                assert_eq!(h1.is_some(), h2.is_some(), "ray {:?}", o);
+                assert_eq!(r1.t_max, r2.t_max);
+                assert_eq!(sah.intersect_p(&r1), sbvh.intersect_p(&r2));
+            }
+        }
+    }
+
+    #[test]
+    fn test_moving_instances_are_hit_along_their_motion() {
+        crate::init_stats();
+        let sphere = || -> PrimitiveRef {
+            Arc::new(GeometricPrimitive {
+                shape: Arc::new(Sphere::new(
+                    Transform::default(),
+                    1.0,
+                    -1.0,
+                    1.0,
+                    360.0,
+                    false,
+                )),
+                area_light: None,
+                material: None,
+                matte_ids: MatteIds::default(),
+            })
+        };
+        // A sphere that stays still, and one that moves 10 units along x while spinning
+        let motion = AnimatedTransform::new(
+            Transform::default(),
+            0.0,
+            &Transform::translate(&Vector3f::new(10.0, 0.0, 0.0)) * &Transform::rot_y(90.0),
+            1.0,
+        );
+        let prims: Vec<PrimitiveRef> = vec![
+            Arc::new(TransformedPrimitive::new(
+                sphere(),
+                Transform::translate(&Vector3f::new(-5.0, 0.0, 0.0)),
+            )),
+            Arc::new(TransformedPrimitive::animated(sphere(), motion)),
+        ];
+        let bvh = BVH::new(1, &prims, SplitMethod::SAH);
+        assert!(bvh.world_bounds().p_max.x >= 11.0);
+        let scene = Scene::new(Arc::new(bvh), Vec::new());
+
+        let ray_at = |x: f32, time: f32| {
+            Ray::new(Point3f::new(x, 0.0, -5.0), Vector3f::new(0.0, 0.0, 1.0)).at_time(time)
+        };
+        assert!(scene.intersect_p(&ray_at(0.0, 0.0)));
+        assert!(!scene.intersect_p(&ray_at(0.0, 1.0)));
+        assert!(scene.intersect_p(&ray_at(5.0, 0.5)));
+        let isect = scene.intersect(&mut ray_at(10.0, 1.0)).unwrap();
+        assert!((isect.hit.p - Point3f::new(10.0, 0.0, -1.0)).length() < 1e-3);
+        assert!(scene.intersect_p(&ray_at(-5.0, 1.0)));
+    }
+}
@@ -0,0 +1,384 @@
+//! Construction of BVHs with spatial splits ("Spatial Splits in Bounding Volume Hierarchies",
+//! Stich et al. 2009).
+//!
+//! Object splits partition the primitives of a node, so long thin primitives (hair cards,
+//! foliage...) that straddle the split produce heavily overlapping children. A spatial split
+//! instead cuts the node with a plane, and primitives that straddle it are referenced by both
+//! children, each reference only covering the part of the primitive on its side. Each node picks
+//! whichever kind of split has the lowest SAH cost, and the number of duplicated references is
+//! limited by a budget.
+
+use crate::bounds::{Axis, Bounds3f};
+use crate::bvh::{duplicated_references, spatial_splits, BVHBuildNode, BuildProgress, SahParams};
+use crate::primitive::PrimitiveRef;
+use crate::{gamma, Point3f};
+
+/// Spatial splits are only considered if the children of the best object split overlap by more
+/// than this fraction of the surface area of the root
+const MIN_OVERLAP: f32 = 1e-5;
+/// Nodes this deep are made into leaves, so that the traversal stack can't overflow
+const MAX_DEPTH: usize = 48;
+
+/// A primitive, or the part of it that is within `bounds`
+#[derive(Copy, Clone, Debug)]
+struct Reference {
+    prim: usize,
+    bounds: Bounds3f,
+}
+
+#[derive(Debug)]
+struct Split {
+    cost: f32,
+    kind: SplitKind,
+}
+
+#[derive(Debug)]
+enum SplitKind {
+    /// Primitives whose centroid falls in the first `bucket + 1` buckets go to the left child
+    Object {
+        axis: Axis,
+        centroid_bounds: Bounds3f,
+        bucket: usize,
+        overlap: Bounds3f,
+    },
+    /// References are cut by the plane orthogonal to `axis` at `pos`
+    Spatial { axis: Axis, pos: f32 },
+}
+
+pub(super) struct SbvhBuilder<'a> {
+    prims: &'a [PrimitiveRef],
+    max_prims_per_node: usize,
+    sah: SahParams,
+    /// Number of references that can still be duplicated
+    budget: usize,
+    min_overlap_area: f32,
+    progress: &'a BuildProgress,
+    pub total_nodes: usize,
+    pub ordered_prims: Vec<usize>,
+}
+
+impl<'a> SbvhBuilder<'a> {
+    /// `budget` is the maximum number of duplicated references, as a fraction of the number of
+    /// primitives.
+    pub fn new(
+        prims: &'a [PrimitiveRef],
+        max_prims_per_node: usize,
+        sah: SahParams,
+        budget: f32,
+        progress: &'a BuildProgress,
+    ) -> SbvhBuilder<'a> {
+        SbvhBuilder {
+            prims,
+            max_prims_per_node,
+            sah,
+            budget: (budget.max(0.0) * prims.len() as f32) as usize,
+            min_overlap_area: 0.0,
+            progress,
+            total_nodes: 0,
+            ordered_prims: Vec::with_capacity(prims.len()),
+        }
+    }
+
+    pub fn build(&mut self) -> BVHBuildNode {
+        let refs: Vec<Reference> = self
+            .prims
+            .iter()
+            .enumerate()
+            .map(|(prim, p)| Reference {
+                prim,
+                bounds: p.world_bounds(),
+            })
+            .collect();
+        let bounds = refs
+            .iter()
+            .fold(Bounds3f::new(), |b, r| Bounds3f::union(&b, &r.bounds));
+        self.min_overlap_area = MIN_OVERLAP * bounds.surface_area();
+        self.build_node(refs, 0)
+    }
+
+    fn build_node(&mut self, refs: Vec<Reference>, depth: usize) -> BVHBuildNode {
+        self.total_nodes += 1;
+        let bounds = refs
+            .iter()
+            .fold(Bounds3f::new(), |b, r| Bounds3f::union(&b, &r.bounds));
+        if refs.len() == 1 || depth >= MAX_DEPTH || self.progress.is_cancelled() {
+            return self.leaf(&refs, bounds);
+        }
+
+        let object = self.find_object_split(&refs, &bounds);
+        let try_spatial = self.budget > 0
+            && object.as_ref().is_none_or(|split| match split.kind {
+                SplitKind::Object { ref overlap, .. } => {
+                    !overlap.is_empty() && overlap.surface_area() > self.min_overlap_area
+                }
+                SplitKind::Spatial { .. } => false,
+            });
+        let spatial = if try_spatial {
+            self.find_spatial_split(&refs, &bounds)
+        } else {
+            None
+        };
+        let mut splits: Vec<Split> = spatial.into_iter().chain(object).collect();
+        splits.sort_by(|s1, s2| s1.cost.total_cmp(&s2.cost));
+
+        let leaf_cost = self.sah.intersect_cost * refs.len() as f32;
+        for split in splits {
+            if refs.len() <= self.max_prims_per_node && split.cost >= leaf_cost {
+                break;
+            }
+            let axis = match split.kind {
+                SplitKind::Object { axis, .. } | SplitKind::Spatial { axis, .. } => axis,
+            };
+            if let Some((left, right)) = self.partition(&refs, &split.kind) {
+                if let SplitKind::Spatial { .. } = split.kind {
+                    spatial_splits::inc();
+                }
+                let left = Box::new(self.build_node(left, depth + 1));
+                let right = Box::new(self.build_node(right, depth + 1));
+                return BVHBuildNode::interior(axis, left, right);
+            }
+        }
+        self.leaf(&refs, bounds)
+    }
+
+    fn leaf(&mut self, refs: &[Reference], bounds: Bounds3f) -> BVHBuildNode {
+        // Duplicated references make the count overshoot the number of primitives a bit
+        self.progress.add_leaf(refs.len());
+        let first_prim_offset = self.ordered_prims.len();
+        self.ordered_prims.extend(refs.iter().map(|r| r.prim));
+        BVHBuildNode::leaf(first_prim_offset, refs.len(), bounds)
+    }
+
+    fn cost(&self, n0: usize, b0: &Bounds3f, n1: usize, b1: &Bounds3f, bounds: &Bounds3f) -> f32 {
+        self.sah.traversal_cost
+            + self.sah.intersect_cost
+                * (n0 as f32 * b0.surface_area() + n1 as f32 * b1.surface_area())
+                / bounds.surface_area()
+    }
+
+    /// Find the best split according to the SAH, in the same way as the regular BVH builder, but
+    /// also keeping track of the overlap between the children.
+    fn find_object_split(&self, refs: &[Reference], bounds: &Bounds3f) -> Option<Split> {
+        let centroid_bounds = refs.iter().fold(Bounds3f::new(), |b, r| {
+            Bounds3f::union_point(&b, &centroid(&r.bounds))
+        });
+        let axis = centroid_bounds.maximum_extent();
+        if centroid_bounds.p_min[axis] == centroid_bounds.p_max[axis] {
+            return None;
+        }
+
+        let n_buckets = self.sah.n_buckets;
+        let mut counts = vec![0; n_buckets];
+        let mut bucket_bounds = vec![Bounds3f::new(); n_buckets];
+        for r in refs {
+            let b = bucket(&centroid_bounds, axis, &centroid(&r.bounds), n_buckets);
+            counts[b] += 1;
+            bucket_bounds[b] = Bounds3f::union(&bucket_bounds[b], &r.bounds);
+        }
+
+        let (left, right) = sweep(&counts, &counts, &bucket_bounds);
+        (0..n_buckets - 1)
+            .filter(|&i| left[i].0 > 0 && right[i + 1].0 > 0)
+            .map(|i| Split {
+                cost: self.cost(
+                    left[i].0,
+                    &left[i].1,
+                    right[i + 1].0,
+                    &right[i + 1].1,
+                    bounds,
+                ),
+                kind: SplitKind::Object {
+                    axis,
+                    centroid_bounds,
+                    bucket: i,
+                    overlap: Bounds3f::intersection(&left[i].1, &right[i + 1].1),
+                },
+            })
+            .min_by(|s1, s2| s1.cost.total_cmp(&s2.cost))
+    }
+
+    /// Find the best spatial split along the largest axis of the node, by chopping the references
+    /// into regularly spaced bins.
+    fn find_spatial_split(&self, refs: &[Reference], bounds: &Bounds3f) -> Option<Split> {
+        let axis = bounds.maximum_extent();
+        let origin = bounds.p_min[axis];
+        let extent = bounds.p_max[axis] - origin;
+        if extent <= 0.0 {
+            return None;
+        }
+
+        let n_bins = self.sah.n_buckets;
+        let plane = |i: usize| origin + extent * i as f32 / n_bins as f32;
+        let bin = |pos: f32| (((pos - origin) / extent * n_bins as f32) as usize).min(n_bins - 1);
+        let mut entries = vec![0; n_bins];
+        let mut exits = vec![0; n_bins];
+        let mut bin_bounds = vec![Bounds3f::new(); n_bins];
+        for r in refs {
+            let first = bin(r.bounds.p_min[axis]);
+            let last = bin(r.bounds.p_max[axis]);
+            entries[first] += 1;
+            exits[last] += 1;
+            let mut rest = r.bounds;
+            for (i, bounds) in bin_bounds.iter_mut().enumerate().take(last).skip(first) {
+                let (below, above) = self.split_reference(r.prim, &rest, axis, plane(i + 1));
+                *bounds = Bounds3f::union(bounds, &below);
+                rest = above;
+            }
+            bin_bounds[last] = Bounds3f::union(&bin_bounds[last], &rest);
+        }
+
+        let (left, right) = sweep(&entries, &exits, &bin_bounds);
+        (0..n_bins - 1)
+            .filter(|&i| left[i].0 > 0 && right[i + 1].0 > 0)
+            .map(|i| Split {
+                cost: self.cost(
+                    left[i].0,
+                    &left[i].1,
+                    right[i + 1].0,
+                    &right[i + 1].1,
+                    bounds,
+                ),
+                kind: SplitKind::Spatial {
+                    axis,
+                    pos: plane(i + 1),
+                },
+            })
+            .min_by(|s1, s2| s1.cost.total_cmp(&s2.cost))
+    }
+
+    /// The parts of the reference on either side of the plane, each of which may be empty.
+    fn split_reference(
+        &self,
+        prim: usize,
+        bounds: &Bounds3f,
+        axis: Axis,
+        pos: f32,
+    ) -> (Bounds3f, Bounds3f) {
+        let (below, above) = self.prims[prim].split_world_bounds(axis, pos);
+        let mut below = Bounds3f::intersection(&below, bounds);
+        let mut above = Bounds3f::intersection(&above, bounds);
+        // Make both sides overlap slightly, otherwise rays that lie exactly in the split plane
+        // could miss both of them.
+        let eps = gamma(3) * pos.abs().max(1.0);
+        if !below.is_empty() {
+            below.p_max[axis] += eps;
+        }
+        if !above.is_empty() {
+            above.p_min[axis] -= eps;
+        }
+        (below, above)
+    }
+
+    /// Distribute the references between the children, or return `None` if one of them would be
+    /// empty.
+    fn partition(
+        &mut self,
+        refs: &[Reference],
+        kind: &SplitKind,
+    ) -> Option<(Vec<Reference>, Vec<Reference>)> {
+        let mut left = Vec::with_capacity(refs.len());
+        let mut right = Vec::with_capacity(refs.len());
+        match *kind {
+            SplitKind::Object {
+                axis,
+                ref centroid_bounds,
+                bucket: split_bucket,
+                ..
+            } => {
+                for r in refs {
+                    let b = bucket(
+                        centroid_bounds,
+                        axis,
+                        &centroid(&r.bounds),
+                        self.sah.n_buckets,
+                    );
+                    if b <= split_bucket {
+                        left.push(*r);
+                    } else {
+                        right.push(*r);
+                    }
+                }
+            }
+            SplitKind::Spatial { axis, pos } => {
+                for r in refs {
+                    if r.bounds.p_max[axis] <= pos {
+                        left.push(*r);
+                    } else if r.bounds.p_min[axis] >= pos {
+                        right.push(*r);
+                    } else {
+                        let (below, above) = self.split_reference(r.prim, &r.bounds, axis, pos);
+                        match (below.is_empty(), above.is_empty()) {
+                            (false, false) if self.budget > 0 => {
+                                self.budget -= 1;
+                                duplicated_references::inc();
+                                left.push(Reference {
+                                    prim: r.prim,
+                                    bounds: below,
+                                });
+                                right.push(Reference {
+                                    prim: r.prim,
+                                    bounds: above,
+                                });
+                            }
+                            // Out of budget: keep the whole reference on the side of its centroid
+                            (false, false) => {
+                                if centroid(&r.bounds)[axis] < pos {
+                                    left.push(*r);
+                                } else {
+                                    right.push(*r);
+                                }
+                            }
+                            (false, true) => left.push(Reference {
+                                prim: r.prim,
+                                bounds: below,
+                            }),
+                            (true, false) => right.push(Reference {
+                                prim: r.prim,
+                                bounds: above,
+                            }),
+                            (true, true) => left.push(*r),
+                        }
+                    }
+                }
+            }
+        }
+        if left.is_empty() || right.is_empty() {
+            None
+        } else {
+            Some((left, right))
+        }
+    }
+}
+
+fn centroid(bounds: &Bounds3f) -> Point3f {
+    0.5 * bounds.p_min + 0.5 * bounds.p_max
+}
+
+fn bucket(centroid_bounds: &Bounds3f, axis: Axis, p: &Point3f, n_buckets: usize) -> usize {
+    let b = (n_buckets as f32 * centroid_bounds.offset(p)[axis]) as usize;
+    b.min(n_buckets - 1)
+}
+
+/// For each bucket `i`, the number of references and the bounds of the buckets up to and
+/// including `i` (counting `entries`), and of the buckets from `i` onwards (counting `exits`).
+#[allow(clippy::type_complexity)]
+fn sweep(
+    entries: &[usize],
+    exits: &[usize],
+    bounds: &[Bounds3f],
+) -> (Vec<(usize, Bounds3f)>, Vec<(usize, Bounds3f)>) {
+    let mut left = Vec::with_capacity(bounds.len());
+    let mut acc = (0, Bounds3f::new());
+    for (n, b) in entries.iter().zip(bounds) {
+        acc = (acc.0 + n, Bounds3f::union(&acc.1, b));
+        left.push(acc);
+    }
+    let mut right = Vec::with_capacity(bounds.len());
+    let mut acc = (0, Bounds3f::new());
+    for (n, b) in exits.iter().zip(bounds).rev() {
+        acc = (acc.0 + n, Bounds3f::union(&acc.1, b));
+        right.push(acc);
+    }
+    right.reverse();
+    (left, right)
+}
@@ -0,0 +1,204 @@
+//! Two-level acceleration structure.
+//!
+//! Each mesh (i.e. each `Shape` that produces several primitives) and each object instance gets
+//! its own bottom-level BVH (BLAS), which is built once. The top-level BVH (TLAS) is built over
+//! instances of these, each with an optional transform. As there are far fewer instances than
+//! primitives, the TLAS can simply be rebuilt when an instance moves, rather than refitted.
+use std::any::Any;
+use std::sync::Arc;
+
+use anyhow::{anyhow, Result};
+
+use crate::bounds::Bounds3f;
+use crate::bvh::{BuildParams, BVH};
+use crate::interaction::SurfaceInteraction;
+use crate::light::{AreaLightRef, LightRef};
+use crate::material::MaterialRef;
+use crate::primitive::{Primitive, PrimitiveRef, TransformedPrimitive};
+use crate::ray::Ray;
+use crate::transform::AnimatedTransform;
+use crate::Transform;
+
+stat_counter!("BVH/Bottom-level BVHs", n_blas);
+stat_counter!("BVH/Top-level BVH rebuilds", n_tlas_rebuilds);
+pub fn init_stats() {
+    n_blas::init();
+    n_tlas_rebuilds::init();
+}
+
+/// Build the bottom-level structure for the primitives of a mesh or of an object. A single
+/// primitive doesn't need one.
+pub fn build_blas(prims: &[PrimitiveRef], params: &BuildParams) -> PrimitiveRef {
+    if prims.len() == 1 {
+        Arc::clone(&prims[0])
+    } else {
+        n_blas::inc();
+        Arc::new(BVH::build(prims, params))
+    }
+}
+
+/// An instance of a bottom-level structure in the TLAS.
+#[derive(Clone, Debug)]
+pub struct Instance {
+    pub blas: PrimitiveRef,
+    /// Transform from the space of `blas` to world space, if it isn't already in world space
+    pub transform: Option<Transform>,
+    /// Transform of the previous frame, if the instance has moved since (see `end_frame()`)
+    pub previous_transform: Option<Transform>,
+    /// Motion over the shutter interval, which replaces `transform` when there is one
+    pub motion: Option<AnimatedTransform>,
+    /// Area lights of the shapes of `blas`, in the same space, which have to be moved with the
+    /// instance
+    pub lights: Vec<LightRef>,
+}
+
+impl Instance {
+    pub fn new(blas: PrimitiveRef) -> Instance {
+        Instance {
+            blas,
+            transform: None,
+            previous_transform: None,
+            motion: None,
+            lights: Vec::new(),
+        }
+    }
+
+    pub fn with_transform(blas: PrimitiveRef, transform: Transform) -> Instance {
+        Instance {
+            blas,
+            transform: Some(transform),
+            previous_transform: None,
+            motion: None,
+            lights: Vec::new(),
+        }
+    }
+
+    /// An instance moving over the shutter interval. The TLAS is built around the bounds of its
+    /// whole motion.
+    pub fn with_motion(blas: PrimitiveRef, motion: AnimatedTransform) -> Instance {
+        Instance {
+            blas,
+            transform: Some(motion.start().clone()),
+            previous_transform: None,
+            motion: Some(motion),
+            lights: Vec::new(),
+        }
+    }
+
+    /// Record the area lights of the shapes of the instance.
+    pub fn with_lights(mut self, lights: Vec<LightRef>) -> Instance {
+        self.lights = lights;
+        self
+    }
+
+    fn primitive(&self) -> PrimitiveRef {
+        if let Some(ref motion) = self.motion {
+            return Arc::new(TransformedPrimitive::animated(
+                Arc::clone(&self.blas),
+                motion.clone(),
+            ));
+        }
+        match self.transform {
+            Some(ref transform) => {
+                let mut primitive =
+                    TransformedPrimitive::new(Arc::clone(&self.blas), transform.clone());
+                if let Some(ref previous) = self.previous_transform {
+                    primitive.previous_to_world = previous.clone();
+                }
+                Arc::new(primitive)
+            }
+            None => Arc::clone(&self.blas),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct Tlas {
+    instances: Vec<Instance>,
+    params: BuildParams,
+    bvh: BVH,
+}
+
+impl Tlas {
+    pub fn new(instances: Vec<Instance>, params: BuildParams) -> Tlas {
+        let bvh = Tlas::build(&instances, &params);
+        Tlas {
+            instances,
+            params,
+            bvh,
+        }
+    }
+
+    pub fn instances(&self) -> &[Instance] {
+        &self.instances
+    }
+
+    /// Change the transform of the `index`-th instance, and rebuild the TLAS. For instances that
+    /// didn't have a transform (i.e. meshes that are already in world space), the transform is
+    /// applied on top of their world space position.
+    ///
+    /// The area lights of the instance aren't moved here, as they are owned by the scene (see
+    /// `Scene::set_primitive_transform()`).
+    pub fn set_instance_transform(&mut self, index: usize, transform: Transform) -> Result<()> {
+        let instance = self
+            .instances
+            .get_mut(index)
+            .ok_or_else(|| anyhow!("No instance with index {}", index))?;
+        if instance.previous_transform.is_none() {
+            instance.previous_transform = Some(instance.transform.take().unwrap_or_default());
+        }
+        instance.transform = Some(transform);
+        instance.motion = None;
+        self.rebuild();
+        Ok(())
+    }
+
+    /// Start a new frame: the instances that have moved keep their current transform as that of
+    /// the previous frame.
+    pub fn end_frame(&mut self) {
+        let mut moved = false;
+        for instance in &mut self.instances {
+            moved |= instance.previous_transform.take().is_some();
+        }
+        if moved {
+            self.rebuild();
+        }
+    }
+
+    /// Rebuild the TLAS from the current instances. The BLASes are reused as they are.
+    pub fn rebuild(&mut self) {
+        n_tlas_rebuilds::inc();
+        self.bvh = Tlas::build(&self.instances, &self.params);
+    }
+
+    fn build(instances: &[Instance], params: &BuildParams) -> BVH {
+        let prims: Vec<PrimitiveRef> = instances.iter().map(Instance::primitive).collect();
+        BVH::build(&prims, params)
+    }
+}
+
+impl Primitive for Tlas {
+    fn world_bounds(&self) -> Bounds3f {
+        self.bvh.world_bounds()
+    }
+
+    fn as_any_mut(&mut self) -> &mut dyn Any {
+        self
+    }
+
+    fn intersect(&self, ray: &mut Ray) -> Option<SurfaceInteraction> {
+        self.bvh.intersect(ray)
+    }
+
+    fn intersect_p(&self, ray: &Ray) -> bool {
+        self.bvh.intersect_p(ray)
+    }
+
+    fn area_light(&self) -> Option<AreaLightRef> {
+        panic!("area_light() should not be called on an Aggregate Primitive!");
+    }
+
+    fn material(&self) -> Option<MaterialRef> {
+        panic!("material() should not be called on an Aggregate Primitive!");
+    }
+}
@@ -1,125 +1,114 @@
-use log::{error, warn};
+use std::collections::HashMap;
+
+use anyhow::{bail, format_err, Result};
+use log::{error, info, warn};
 use num::Zero;
 
 use crate::bounds::Bounds2f;
 use crate::film::Film;
+use crate::geometry::offset_ray_origin;
 use crate::paramset::ParamSet;
-use crate::ray::{Ray, RayDifferential};
-use crate::sampling;
-use crate::{Point2f, Point3f, Transform, Vector3f};
+use crate::ray::{BackfaceCulling, Ray, RayDifferential};
+use crate::sampling::{self, Distribution1D};
+use crate::shapes::{ShapeRef, UvTriangle};
+use std::f32::consts::PI;
+
+use crate::{
+    clamp, coordinate_system, lerp, Point2f, Point2i, Point3f, Transform, Vector2f, Vector3f,
+};
 
 pub trait Camera: Send + Sync {
     fn get_film(&self) -> &Film;
+    fn get_film_mut(&mut self) -> &mut Film;
+    fn camera_to_world(&self) -> &Transform;
+    /// Move the camera. This is meant for interactive use between renders; the film is left
+    /// untouched, so it needs to be cleared before rendering again.
+    fn set_camera_to_world(&mut self, camera_to_world: Transform);
     fn generate_ray(&self, sample: &CameraSample) -> Ray;
     fn generate_ray_differential(&self, sample: &CameraSample) -> Ray;
+    /// Raster position at which the world space point `p` is seen, or was seen in the previous
+    /// frame if `previous` is true (see `end_frame()`). `None` if the point is behind the camera
+    /// or the camera's projection isn't supported.
+    fn world_to_raster(&self, _p: &Point3f, _previous: bool) -> Option<Point2f> {
+        None
+    }
+    /// Start a new frame: the current position of the camera becomes that of the previous
+    /// frame, which the motion vectors are computed against (see `GeometryAov::Motion`).
+    fn end_frame(&mut self) {}
 }
 
-/// Projective pinhole camera.
+/// Pinhole camera, with a perspective projection or one of the projections of `ProjectionType`
+/// (`"string projection"`).
 pub struct PerspectiveCamera {
     film: Box<Film>,
     camera_to_world: Transform,
-    // camera_to_screen: Matrix4<f32>, // not used?
-    raster_to_camera: Transform,
-    lens_radius: f32,
-    focal_distance: f32,
-    dx_camera: Vector3f,
-    dy_camera: Vector3f,
+    /// Camera to world transform of the previous frame, for the motion vectors
+    previous_camera_to_world: Transform,
+    projection: Projection,
 }
 
 impl PerspectiveCamera {
     pub fn new(
         camera_to_world: Transform,
         screen_window: Bounds2f,
+        shutter: Shutter,
         lens_radius: f32,
         focal_distance: f32,
         fov: f32,
         film: Box<Film>,
     ) -> PerspectiveCamera {
-        let camera_to_screen = Transform::perspective(fov, 1e-2, 1000.0);
-        let screen_to_raster = Transform::scale(
-            film.full_resolution.x as f32,
-            film.full_resolution.y as f32,
-            1.0,
-        ) * Transform::scale(
-            1.0 / (screen_window.p_max.x - screen_window.p_min.x),
-            1.0 / (screen_window.p_min.y - screen_window.p_max.y),
-            1.0,
-        ) * Transform::translate(&Vector3f::new(
-            -screen_window.p_min.x,
-            -screen_window.p_max.y,
-            0.0,
-        ));
-
-        let raster_to_screen = screen_to_raster.inverse();
-        let raster_to_camera = camera_to_screen.inverse() * raster_to_screen;
-
-        // compute differential changes in origin for perspective camera rays
-        let dx_camera = (&raster_to_camera * &Point3f::new(1.0, 0.0, 0.0))
-            - (&raster_to_camera * &Point3f::new(0.0, 0.0, 0.0));
-        let dy_camera = (&raster_to_camera * &Point3f::new(0.0, 1.0, 0.0))
-            - (&raster_to_camera * &Point3f::new(0.0, 0.0, 0.0));
+        let projection = Projection::new(
+            film.full_resolution,
+            screen_window,
+            shutter,
+            lens_radius,
+            focal_distance,
+            fov,
+        );
 
         PerspectiveCamera {
             film,
+            previous_camera_to_world: camera_to_world.clone(),
             camera_to_world,
-            // camera_to_screen: camera_to_screen,
-            raster_to_camera,
-            lens_radius,
-            focal_distance,
-            dx_camera,
-            dy_camera,
+            projection,
         }
     }
 
-    pub fn create(ps: &ParamSet, cam2world: &Transform, film: Box<Film>) -> Box<dyn Camera> {
-        let mut shutteropen = ps.find_one_float("shutteropen", 0.0);
-        let mut shutterclose = ps.find_one_float("shutterclose", 1.0);
-        if shutterclose < shutteropen {
-            warn!(
-                "Shutter close time {} < shutter open time {}.  Swapping them.",
-                shutterclose, shutteropen
-            );
-            ::std::mem::swap(&mut shutteropen, &mut shutterclose);
-        }
-        let lensradius = ps.find_one_float("lensradius", 0.0);
-        let focaldistance = ps.find_one_float("focaldistance", 1e6);
-        let frame = ps.find_one_float(
-            "frameaspectratio",
-            film.full_resolution.x as f32 / film.full_resolution.y as f32,
-        );
-        let mut screen = if frame > 1.0 {
-            Bounds2f::from_points(&Point2f::new(-frame, -1.0), &Point2f::new(frame, 1.0))
-        } else {
-            Bounds2f::from_points(
-                &Point2f::new(-1.0, -1.0 / frame),
-                &Point2f::new(1.0, 1.0 / frame),
-            )
-        };
-        if let Some(sw) = ps.find_float("screenwindow") {
-            if sw.len() == 4 {
-                screen.p_min.x = sw[0];
-                screen.p_max.x = sw[1];
-                screen.p_min.y = sw[2];
-                screen.p_max.y = sw[3];
-            } else {
-                error!("\"screenwindow\" should have 4 values");
-            }
-        }
-        let mut fov = ps.find_one_float("fov", 90.0);
+    pub fn set_projection(&mut self, projection_type: ProjectionType) {
+        self.projection.projection_type = projection_type;
+    }
+
+    /// Field of view (in degrees) requested by the camera parameters, along the shorter axis of
+    /// the image.
+    pub fn fov(ps: &ParamSet) -> f32 {
         let halffov = ps.find_one_float("halffov", -1.0);
         if halffov > 0.0 {
             // hack for structure synth, which exports half of the full fov
-            fov = halffov * 2.0;
+            halffov * 2.0
+        } else {
+            ps.find_one_float("fov", 90.0)
         }
+    }
+
+    pub fn create(ps: &ParamSet, cam2world: &Transform, film: Box<Film>) -> Box<dyn Camera> {
+        let shutter = Shutter::create(ps);
+        let lensradius = ps.find_one_float("lensradius", 0.0);
+        let focaldistance = ps.find_one_float("focaldistance", 1e6);
+        let screen = screen_window(ps, film.full_resolution);
+        let fov = PerspectiveCamera::fov(ps);
 
-        Box::new(PerspectiveCamera::new(
+        let mut camera = PerspectiveCamera::new(
             cam2world.clone(),
             screen,
+            shutter,
             lensradius,
             focaldistance,
             fov,
             film,
-        ))
+        );
+        camera.set_projection(projection_type(ps));
+        camera.projection.backface_culling = backface_culling(ps);
+        Box::new(camera)
     }
 }
 
@@ -128,11 +117,306 @@ impl Camera for PerspectiveCamera {
         &self.film
     }
 
+    fn get_film_mut(&mut self) -> &mut Film {
+        &mut self.film
+    }
+
+    fn camera_to_world(&self) -> &Transform {
+        &self.camera_to_world
+    }
+
+    fn set_camera_to_world(&mut self, camera_to_world: Transform) {
+        self.camera_to_world = camera_to_world;
+    }
+
     fn generate_ray(&self, sample: &CameraSample) -> Ray {
-        let p_film = Point3f::new(sample.p_film.x, sample.p_film.y, 0.0);
+        self.projection
+            .generate_ray(sample.p_film, sample)
+            .transform(&self.camera_to_world)
+            .0
+    }
+
+    fn generate_ray_differential(&self, sample: &CameraSample) -> Ray {
+        self.projection
+            .generate_ray_differential(sample.p_film, sample)
+            .transform(&self.camera_to_world)
+            .0
+    }
+
+    fn world_to_raster(&self, p: &Point3f, previous: bool) -> Option<Point2f> {
+        let camera_to_world = if previous {
+            &self.previous_camera_to_world
+        } else {
+            &self.camera_to_world
+        };
+        self.projection
+            .camera_to_raster(&(&camera_to_world.inverse() * p))
+    }
+
+    fn end_frame(&mut self) {
+        self.previous_camera_to_world = self.camera_to_world.clone();
+    }
+}
+
+/// Efficiency of a shutter over time, i.e. how much of the light reaching the lens it lets
+/// through as it opens and closes (`"string shuttercurve"`).
+#[derive(Debug, Clone)]
+pub enum ShutterCurve {
+    /// Fully open over the whole interval (`"box"`, the default)
+    Box,
+    /// Opens linearly until the middle of the interval, then closes linearly (`"triangle"`)
+    Triangle,
+    /// Piecewise constant efficiency (`"custom"`), given by `"float shutterefficiency"` at
+    /// evenly spaced times over the interval
+    Custom(Distribution1D),
+}
+
+/// Shutter of a camera: when it opens and closes, and how the times of the camera samples are
+/// distributed over this interval.
+#[derive(Debug, Clone)]
+pub struct Shutter {
+    pub open: f32,
+    pub close: f32,
+    pub curve: ShutterCurve,
+    /// Fraction of the interval spent reading the rows of the image out, from top to bottom, for
+    /// a rolling shutter (`"float rollingshutter"`, 0 for a global shutter). Each row is exposed
+    /// for `1 - rolling` of the interval, starting at `rolling * v` for a row at height `v` (0 at
+    /// the top, 1 at the bottom), so that the exposures of all the rows have the same length.
+    pub rolling: f32,
+}
+
+impl Shutter {
+    /// A global shutter, fully open from `open` to `close`.
+    pub fn new(open: f32, close: f32) -> Shutter {
+        Shutter {
+            open,
+            close,
+            curve: ShutterCurve::Box,
+            rolling: 0.0,
+        }
+    }
+
+    pub fn create(ps: &ParamSet) -> Shutter {
+        let mut shutteropen = ps.find_one_float("shutteropen", 0.0);
+        let mut shutterclose = ps.find_one_float("shutterclose", 1.0);
+        if shutterclose < shutteropen {
+            warn!(
+                "Shutter close time {} < shutter open time {}.  Swapping them.",
+                shutterclose, shutteropen
+            );
+            ::std::mem::swap(&mut shutteropen, &mut shutterclose);
+        }
+        let curve = match ps.find_one_string("shuttercurve", "box".into()).as_str() {
+            "box" => ShutterCurve::Box,
+            "triangle" => ShutterCurve::Triangle,
+            "custom" => match ps.find_float("shutterefficiency") {
+                Some(ref e) if !e.is_empty() && e.iter().all(|&v| v >= 0.0) => {
+                    ShutterCurve::Custom(Distribution1D::new(e))
+                }
+                _ => {
+                    error!("\"shutterefficiency\" should have non-negative values for a custom shutter curve. Using a box curve.");
+                    ShutterCurve::Box
+                }
+            },
+            c => {
+                warn!("Unknown shutter curve \"{}\". Using a box curve.", c);
+                ShutterCurve::Box
+            }
+        };
+        let rolling = ps.find_one_float("rollingshutter", 0.0);
+        if !(0.0..=1.0).contains(&rolling) {
+            warn!("\"rollingshutter\" should be between 0 and 1. Clamping it.");
+        }
+
+        Shutter {
+            open: shutteropen,
+            close: shutterclose,
+            curve,
+            rolling: clamp(rolling, 0.0, 1.0),
+        }
+    }
+
+    /// Time of a camera sample, given its time sample `u` and its vertical position `v` in the
+    /// image, from 0 at the top to 1 at the bottom.
+    pub fn time(&self, u: f32, v: f32) -> f32 {
+        let t = match self.curve {
+            ShutterCurve::Box => u,
+            // Inverse of the CDF of the tent function
+            ShutterCurve::Triangle => {
+                if u < 0.5 {
+                    (0.5 * u).sqrt()
+                } else {
+                    1.0 - (0.5 * (1.0 - u)).sqrt()
+                }
+            }
+            ShutterCurve::Custom(ref efficiency) => efficiency.sample_continuous(u).0,
+        };
+        let t = self.rolling * clamp(v, 0.0, 1.0) + (1.0 - self.rolling) * t;
+        lerp(t, self.open, self.close)
+    }
+}
+
+/// Screen window given by the camera parameters for an image of the given resolution.
+fn screen_window(ps: &ParamSet, resolution: Point2i) -> Bounds2f {
+    let frame = ps.find_one_float(
+        "frameaspectratio",
+        resolution.x as f32 / resolution.y as f32,
+    );
+    let mut screen = if frame > 1.0 {
+        Bounds2f::from_points(&Point2f::new(-frame, -1.0), &Point2f::new(frame, 1.0))
+    } else {
+        Bounds2f::from_points(
+            &Point2f::new(-1.0, -1.0 / frame),
+            &Point2f::new(1.0, 1.0 / frame),
+        )
+    };
+    if let Some(sw) = ps.find_float("screenwindow") {
+        if sw.len() == 4 {
+            screen.p_min.x = sw[0];
+            screen.p_max.x = sw[1];
+            screen.p_min.y = sw[2];
+            screen.p_max.y = sw[3];
+        } else {
+            error!("\"screenwindow\" should have 4 values");
+        }
+    }
+    screen
+}
+
+/// How the film maps to directions in camera space (`"string projection"`). The field of view
+/// applies along the shorter axis of the image, as for the perspective projection.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ProjectionType {
+    /// `"perspective"` (the default)
+    Perspective,
+    /// Fisheye lens whose image radius is proportional to the angle from the view axis
+    /// (`"equidistant"`). Images can cover up to 360 degrees.
+    Equidistant,
+    /// Fisheye lens preserving solid angles, i.e. areas on the sphere of directions
+    /// (`"equisolid"`). Images can cover up to 360 degrees.
+    Equisolid,
+    /// Panorama on a cylinder around the vertical axis (`"cylindrical"`): the horizontal
+    /// position maps to the angle around the axis, and the vertical one to the height on the
+    /// cylinder, at the same scale at the horizon.
+    Cylindrical,
+}
+
+/// Back faces ignored by the camera rays: all of them with `"bool backfacecull"`, or else only
+/// those of the triangle meshes that set it.
+fn backface_culling(ps: &ParamSet) -> BackfaceCulling {
+    if ps.find_one_bool("backfacecull", false) {
+        BackfaceCulling::All
+    } else {
+        BackfaceCulling::Flagged
+    }
+}
+
+fn projection_type(ps: &ParamSet) -> ProjectionType {
+    let projection = match ps
+        .find_one_string("projection", "perspective".into())
+        .as_str()
+    {
+        "perspective" => ProjectionType::Perspective,
+        "equidistant" => ProjectionType::Equidistant,
+        "equisolid" => ProjectionType::Equisolid,
+        "cylindrical" => ProjectionType::Cylindrical,
+        p => {
+            warn!("Unknown projection \"{}\". Using \"perspective\".", p);
+            ProjectionType::Perspective
+        }
+    };
+    if projection != ProjectionType::Perspective && ps.find_one_float("lensradius", 0.0) > 0.0 {
+        warn!("Depth of field is only supported by the perspective projection. Ignoring \"lensradius\".");
+    }
+    projection
+}
+
+/// Projection of an image of a given resolution, generating rays in camera space.
+struct Projection {
+    projection_type: ProjectionType,
+    raster_to_camera: Transform,
+    camera_to_raster: Transform,
+    raster_to_screen: Transform,
+    /// Half of the field of view, in radians
+    half_fov: f32,
+    lens_radius: f32,
+    focal_distance: f32,
+    shutter: Shutter,
+    /// Height of the image, to find the time of each row with a rolling shutter
+    height: f32,
+    dx_camera: Vector3f,
+    dy_camera: Vector3f,
+    /// Back faces ignored by the camera rays
+    backface_culling: BackfaceCulling,
+}
+
+impl Projection {
+    fn new(
+        resolution: Point2i,
+        screen_window: Bounds2f,
+        shutter: Shutter,
+        lens_radius: f32,
+        focal_distance: f32,
+        fov: f32,
+    ) -> Projection {
+        let camera_to_screen = Transform::perspective(fov, 1e-2, 1000.0);
+        let screen_to_raster = Transform::scale(resolution.x as f32, resolution.y as f32, 1.0)
+            * Transform::scale(
+                1.0 / (screen_window.p_max.x - screen_window.p_min.x),
+                1.0 / (screen_window.p_min.y - screen_window.p_max.y),
+                1.0,
+            )
+            * Transform::translate(&Vector3f::new(
+                -screen_window.p_min.x,
+                -screen_window.p_max.y,
+                0.0,
+            ));
+
+        let raster_to_screen = screen_to_raster.inverse();
+        let raster_to_camera = camera_to_screen.inverse() * raster_to_screen.clone();
+        let camera_to_raster = raster_to_camera.inverse();
+
+        // compute differential changes in origin for perspective camera rays
+        let dx_camera = (&raster_to_camera * &Point3f::new(1.0, 0.0, 0.0))
+            - (&raster_to_camera * &Point3f::new(0.0, 0.0, 0.0));
+        let dy_camera = (&raster_to_camera * &Point3f::new(0.0, 1.0, 0.0))
+            - (&raster_to_camera * &Point3f::new(0.0, 0.0, 0.0));
+
+        Projection {
+            projection_type: ProjectionType::Perspective,
+            raster_to_camera,
+            camera_to_raster,
+            raster_to_screen,
+            half_fov: 0.5 * fov.to_radians(),
+            lens_radius,
+            focal_distance,
+            shutter,
+            height: resolution.y as f32,
+            dx_camera,
+            dy_camera,
+            backface_culling: BackfaceCulling::Flagged,
+        }
+    }
+
+    /// Camera space ray through the raster position `p_raster`, using the lens and time samples
+    /// of `sample`.
+    fn generate_ray(&self, p_raster: Point2f, sample: &CameraSample) -> Ray {
+        let time = self.shutter.time(sample.time, p_raster.y / self.height);
+        if self.projection_type != ProjectionType::Perspective {
+            let mut ray = match self.direction(p_raster) {
+                Some(d) => Ray::new(Point3f::zero(), d),
+                None => Ray::segment(Point3f::zero(), Vector3f::new(0.0, 0.0, 1.0), 0.0),
+            }
+            .at_time(time);
+            ray.backface_culling = self.backface_culling;
+            return ray;
+        }
+
+        let p_film = Point3f::new(p_raster.x, p_raster.y, 0.0);
         let p_camera: Point3f = &self.raster_to_camera * &p_film;
 
-        let mut ray = Ray::new(Point3f::zero(), Vector3f::from(p_camera).normalize());
+        let mut ray = Ray::new(Point3f::zero(), Vector3f::from(p_camera).normalize()).at_time(time);
+        ray.backface_culling = self.backface_culling;
         // modify ray for depth of field
         if self.lens_radius > 0.0 {
             // Sample point on lens
@@ -144,25 +428,25 @@ impl Camera for PerspectiveCamera {
             ray.o = Point3f::new(p_lens.x, p_lens.y, 0.0);
             ray.d = (p_focus - ray.o).normalize();
         }
-        ray.transform(&self.camera_to_world).0
+        ray
     }
 
-    fn generate_ray_differential(&self, sample: &CameraSample) -> Ray {
-        let p_film = Point3f::new(sample.p_film.x, sample.p_film.y, 0.0);
+    fn generate_ray_differential(&self, p_raster: Point2f, sample: &CameraSample) -> Ray {
+        let p_film = Point3f::new(p_raster.x, p_raster.y, 0.0);
         let p_camera = &self.raster_to_camera * &p_film;
-
-        let mut ray = Ray::new(Point3f::zero(), Vector3f::from(p_camera).normalize());
-        // modify ray for depth of field
-        if self.lens_radius > 0.0 {
-            // Sample point on lens
-            let p_lens = self.lens_radius * sampling::concentric_sample_disk(sample.p_lens);
-            // Compute point on plane of focus
-            let ft = self.focal_distance / ray.d.z;
-            let p_focus = ray.at(ft);
-            // Update ray for effect of lens
-            ray.o = Point3f::new(p_lens.x, p_lens.y, 0.0);
-            ray.d = (p_focus - ray.o).normalize();
+        let mut ray = self.generate_ray(p_raster, sample);
+        if self.projection_type != ProjectionType::Perspective {
+            // Directions of the neighbouring pixels, which are all seen from the same point
+            let neighbour = |d: Vector2f| self.direction(p_raster + d).unwrap_or(ray.d);
+            ray.differential = Some(RayDifferential {
+                rx_origin: ray.o,
+                ry_origin: ray.o,
+                rx_direction: neighbour(Vector2f::new(1.0, 0.0)),
+                ry_direction: neighbour(Vector2f::new(0.0, 1.0)),
+            });
+            return ray;
         }
+
         // compute offset rays for PerspectiveCamera ray differentials
         let diff = if self.lens_radius > 0.0 {
             // Sample point on lens
@@ -197,8 +481,364 @@ impl Camera for PerspectiveCamera {
         };
 
         ray.differential = Some(diff);
+        ray
+    }
+
+    /// Raster position of the camera space point `p`. Only the perspective projection is
+    /// supported.
+    fn camera_to_raster(&self, p: &Point3f) -> Option<Point2f> {
+        if self.projection_type != ProjectionType::Perspective || p.z <= 0.0 {
+            return None;
+        }
+        let p_raster = &self.camera_to_raster * p;
+        Some(Point2f::new(p_raster.x, p_raster.y))
+    }
+
+    /// Camera space direction seen through the raster position `p_raster` with the non-perspective
+    /// projections, or `None` if it is outside of the fisheye's range.
+    fn direction(&self, p_raster: Point2f) -> Option<Vector3f> {
+        let p = &self.raster_to_screen * &Point3f::new(p_raster.x, p_raster.y, 0.0);
+        if self.projection_type == ProjectionType::Cylindrical {
+            let phi = p.x * self.half_fov;
+            let (sin_phi, cos_phi) = phi.sin_cos();
+            return Some(Vector3f::new(sin_phi, p.y * self.half_fov, cos_phi).normalize());
+        }
 
-        ray.transform(&self.camera_to_world).0
+        // Fisheyes: the screen's radius 1 is at half the field of view from the axis
+        let r = (p.x * p.x + p.y * p.y).sqrt();
+        let theta = match self.projection_type {
+            ProjectionType::Equidistant => r * self.half_fov,
+            _ => {
+                let s = r * (0.5 * self.half_fov).sin();
+                if s > 1.0 {
+                    return None;
+                }
+                2.0 * s.asin()
+            }
+        };
+        if theta > PI {
+            return None;
+        }
+        if r == 0.0 {
+            return Some(Vector3f::new(0.0, 0.0, 1.0));
+        }
+        let sin_theta = theta.sin();
+        Some(Vector3f::new(
+            sin_theta * p.x / r,
+            sin_theta * p.y / r,
+            theta.cos(),
+        ))
+    }
+}
+
+/// How the two views of a `StereoCamera` are written (`"string layout"`).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StereoLayout {
+    /// One image per eye, `<output>_L.<ext>` and `<output>_R.<ext>` (the default)
+    Separate,
+    /// A single image with the left view on the left half and the right view on the right half
+    SideBySide,
+}
+
+/// Pair of perspective cameras rendering the views of the left and right eyes side by side on the
+/// same film, so that both are rendered in one pass over the same scene. The film's resolution is
+/// that of both views together: each view gets one half of its width.
+///
+/// Parameters, in addition to those of the perspective camera (which apply to each view):
+///
+/// * `"float interocular"`: distance between the eyes, in scene units (0.065 by default);
+/// * `"float convergence"`: distance at which the views converge, i.e. of the objects that
+///   appear at the depth of the screen. The eyes' image planes are shifted rather than rotated
+///   towards each other, which avoids vertical parallax. By default the views are parallel;
+/// * `"string layout"`: `"separate"` or `"sidebyside"` (see `StereoLayout`).
+pub struct StereoCamera {
+    film: Box<Film>,
+    camera_to_world: Transform,
+    /// Position of each eye in camera space
+    eye_offsets: [Vector3f; 2],
+    /// Eye to world transform of each eye
+    eye_to_world: [Transform; 2],
+    projections: [Projection; 2],
+    /// Width of a view, in pixels
+    view_width: i32,
+}
+
+impl StereoCamera {
+    pub fn new(
+        camera_to_world: Transform,
+        screen_window: Bounds2f,
+        shutter: Shutter,
+        lens_radius: f32,
+        focal_distance: f32,
+        fov: f32,
+        interocular: f32,
+        convergence: f32,
+        film: Box<Film>,
+    ) -> StereoCamera {
+        let view_width = film.full_resolution.x / 2;
+        let resolution = Point2i::new(view_width, film.full_resolution.y);
+        // Horizontal shift of the screen window so that the point of the view axis at the
+        // convergence distance is at the centre of both views
+        let shift = 0.5 * interocular / (convergence * (0.5 * fov.to_radians()).tan());
+        let eye_offsets = [
+            Vector3f::new(-0.5 * interocular, 0.0, 0.0),
+            Vector3f::new(0.5 * interocular, 0.0, 0.0),
+        ];
+        let projections = [shift, -shift].map(|shift| {
+            let mut screen = screen_window;
+            screen.p_min.x += shift;
+            screen.p_max.x += shift;
+            Projection::new(
+                resolution,
+                screen,
+                shutter.clone(),
+                lens_radius,
+                focal_distance,
+                fov,
+            )
+        });
+
+        StereoCamera {
+            film,
+            eye_to_world: StereoCamera::eye_to_world(&camera_to_world, &eye_offsets),
+            camera_to_world,
+            eye_offsets,
+            projections,
+            view_width,
+        }
+    }
+
+    pub fn create(
+        ps: &ParamSet,
+        cam2world: &Transform,
+        mut film: Box<Film>,
+    ) -> Result<Box<dyn Camera>> {
+        if film.full_resolution.x % 2 != 0 {
+            bail!(
+                "The stereo camera needs a film of even width to split between the views, not {}",
+                film.full_resolution.x
+            );
+        }
+        let shutter = Shutter::create(ps);
+        let lensradius = ps.find_one_float("lensradius", 0.0);
+        let focaldistance = ps.find_one_float("focaldistance", 1e6);
+        let screen = screen_window(
+            ps,
+            Point2i::new(film.full_resolution.x / 2, film.full_resolution.y),
+        );
+        let fov = PerspectiveCamera::fov(ps);
+        let interocular = ps.find_one_float("interocular", 0.065);
+        let convergence = ps.find_one_float("convergence", f32::INFINITY);
+        if convergence <= 0.0 {
+            bail!("The stereo camera's convergence distance must be positive");
+        }
+        let layout = match ps.find_one_string("layout", "separate".into()).as_str() {
+            "separate" => StereoLayout::Separate,
+            "sidebyside" => StereoLayout::SideBySide,
+            l => {
+                warn!("Unknown stereo layout \"{}\". Using \"separate\".", l);
+                StereoLayout::Separate
+            }
+        };
+        film.set_split_views(layout == StereoLayout::Separate);
+
+        let mut camera = StereoCamera::new(
+            cam2world.clone(),
+            screen,
+            shutter,
+            lensradius,
+            focaldistance,
+            fov,
+            interocular,
+            convergence,
+            film,
+        );
+        camera.set_projection(projection_type(ps));
+        for projection in &mut camera.projections {
+            projection.backface_culling = backface_culling(ps);
+        }
+        Ok(Box::new(camera))
+    }
+
+    pub fn set_projection(&mut self, projection_type: ProjectionType) {
+        for projection in &mut self.projections {
+            projection.projection_type = projection_type;
+        }
+    }
+
+    fn eye_to_world(camera_to_world: &Transform, eye_offsets: &[Vector3f; 2]) -> [Transform; 2] {
+        eye_offsets.map(|offset| camera_to_world * &Transform::translate(&offset))
+    }
+
+    /// Index of the eye whose view contains the given film position, and the position in the
+    /// view.
+    fn view(&self, p_film: Point2f) -> (usize, Point2f) {
+        if p_film.x < self.view_width as f32 {
+            (0, p_film)
+        } else {
+            (1, Point2f::new(p_film.x - self.view_width as f32, p_film.y))
+        }
+    }
+}
+
+impl Camera for StereoCamera {
+    fn get_film(&self) -> &Film {
+        &self.film
+    }
+
+    fn get_film_mut(&mut self) -> &mut Film {
+        &mut self.film
+    }
+
+    fn camera_to_world(&self) -> &Transform {
+        &self.camera_to_world
+    }
+
+    fn set_camera_to_world(&mut self, camera_to_world: Transform) {
+        self.eye_to_world = StereoCamera::eye_to_world(&camera_to_world, &self.eye_offsets);
+        self.camera_to_world = camera_to_world;
+    }
+
+    fn generate_ray(&self, sample: &CameraSample) -> Ray {
+        let (eye, p_view) = self.view(sample.p_film);
+        self.projections[eye]
+            .generate_ray(p_view, sample)
+            .transform(&self.eye_to_world[eye])
+            .0
+    }
+
+    fn generate_ray_differential(&self, sample: &CameraSample) -> Ray {
+        let (eye, p_view) = self.view(sample.p_film);
+        self.projections[eye]
+            .generate_ray_differential(p_view, sample)
+            .transform(&self.eye_to_world[eye])
+            .0
+    }
+}
+
+/// "Camera" used to bake textures: instead of looking at the scene, it goes over the texels of the
+/// texture layout of an object, and generates rays that carry the point of the surface
+/// corresponding to each texel, to be used by `integrator::BakeIntegrator`.
+///
+/// The film's resolution is the resolution of the texture. The ray of a texel starts on the
+/// surface (offset by its error bounds) and points along the surface normal. Texels that aren't
+/// covered by the texture layout get a ray with a `t_max` of 0.
+pub struct BakeCamera {
+    film: Box<Film>,
+    camera_to_world: Transform,
+    triangles: Vec<UvTriangle>,
+    /// Uniform grid over [0, 1]^2 in texture space, listing the triangles overlapping each cell
+    grid: Vec<Vec<usize>>,
+    grid_res: usize,
+}
+
+impl BakeCamera {
+    pub fn new(triangles: Vec<UvTriangle>, film: Box<Film>) -> BakeCamera {
+        let grid_res = ((triangles.len() as f32).sqrt() as usize).clamp(1, 256);
+        let mut grid = vec![Vec::new(); grid_res * grid_res];
+        let cell =
+            |v: f32| clamp((v * grid_res as f32) as isize, 0, grid_res as isize - 1) as usize;
+        for (i, tri) in triangles.iter().enumerate() {
+            let bounds = tri.uv_bounds();
+            if bounds.p_max.x < 0.0
+                || bounds.p_max.y < 0.0
+                || bounds.p_min.x > 1.0
+                || bounds.p_min.y > 1.0
+            {
+                continue;
+            }
+            for y in cell(bounds.p_min.y)..=cell(bounds.p_max.y) {
+                for x in cell(bounds.p_min.x)..=cell(bounds.p_max.x) {
+                    grid[y * grid_res + x].push(i);
+                }
+            }
+        }
+
+        BakeCamera {
+            film,
+            camera_to_world: Transform::default(),
+            triangles,
+            grid,
+            grid_res,
+        }
+    }
+
+    /// Create a camera baking the object named by the `"string object"` parameter (see
+    /// `ObjectBegin`). The object is baked where it is defined, so it should be instantiated
+    /// without any additional transformation.
+    pub fn create(
+        ps: &ParamSet,
+        objects: &HashMap<String, Vec<ShapeRef>>,
+        film: Box<Film>,
+    ) -> Result<Box<dyn Camera>> {
+        let name = ps.find_one_string("object", "".into());
+        let shapes = objects
+            .get(&name)
+            .ok_or_else(|| format_err!("Unknown object \"{}\" to bake", name))?;
+        let triangles: Vec<_> = shapes.iter().filter_map(|s| s.uv_triangle()).collect();
+        if triangles.is_empty() {
+            bail!(
+                "Object \"{}\" has no triangles with texture coordinates to bake",
+                name
+            );
+        }
+        info!(
+            "Baking {} triangles of object \"{}\" into a {} texture",
+            triangles.len(),
+            name,
+            film.full_resolution
+        );
+        Ok(Box::new(BakeCamera::new(triangles, film)))
+    }
+
+    /// Texture coordinates of a point of the film: the origin of texture space is at the bottom
+    /// left of the image.
+    fn uv(&self, p_film: Point2f) -> Point2f {
+        let res = self.film.full_resolution;
+        Point2f::new(p_film.x / res.x as f32, 1.0 - p_film.y / res.y as f32)
+    }
+
+    fn lookup(&self, uv: Point2f) -> Option<(Point3f, Vector3f, Vector3f)> {
+        if !(0.0..=1.0).contains(&uv.x) || !(0.0..=1.0).contains(&uv.y) {
+            return None;
+        }
+        let cell = |v: f32| usize::min((v * self.grid_res as f32) as usize, self.grid_res - 1);
+        self.grid[cell(uv.y) * self.grid_res + cell(uv.x)]
+            .iter()
+            .find_map(|&i| self.triangles[i].lookup(uv))
+            .map(|(p, p_error, n)| (p, p_error, Vector3f::from(n)))
+    }
+}
+
+impl Camera for BakeCamera {
+    fn get_film(&self) -> &Film {
+        &self.film
+    }
+
+    fn get_film_mut(&mut self) -> &mut Film {
+        &mut self.film
+    }
+
+    fn camera_to_world(&self) -> &Transform {
+        &self.camera_to_world
+    }
+
+    fn set_camera_to_world(&mut self, _camera_to_world: Transform) {
+        warn!("The bake camera can't be moved");
+    }
+
+    fn generate_ray(&self, sample: &CameraSample) -> Ray {
+        match self.lookup(self.uv(sample.p_film)) {
+            Some((p, p_error, n)) => {
+                let o = offset_ray_origin(&p, &p_error, &n.into(), &n);
+                Ray::new(o, n)
+            }
+            None => Ray::segment(Point3f::zero(), Vector3f::new(0.0, 0.0, 1.0), 0.0),
+        }
+    }
+
+    fn generate_ray_differential(&self, sample: &CameraSample) -> Ray {
+        self.generate_ray(sample)
     }
 }
 
@@ -207,3 +847,367 @@ pub struct CameraSample {
     pub p_lens: Point2f,
     pub time: f32,
 }
+
+/// Orbit controls: the camera turns around a target point, always looking at it.
+#[derive(Debug, Clone, Copy)]
+pub struct Orbit {
+    target: Point3f,
+    up: Vector3f,
+    distance: f32,
+    /// Angle around the up axis, in degrees
+    azimuth: f32,
+    /// Angle above the plane perpendicular to the up axis, in degrees
+    elevation: f32,
+}
+
+impl Orbit {
+    /// Maximum elevation, to avoid looking straight along the up vector.
+    const MAX_ELEVATION: f32 = 89.0;
+
+    /// Create orbit controls matching a camera at `eye` looking at `target`.
+    pub fn new(eye: &Point3f, target: &Point3f, up: &Vector3f) -> Orbit {
+        let up = up.normalize();
+        let (u, w) = Orbit::basis(&up);
+        let offset = *eye - *target;
+        let distance = offset.length();
+        let (azimuth, elevation) = if distance > 0.0 {
+            let d = offset / distance;
+            (
+                d.dot(&w).atan2(d.dot(&u)).to_degrees(),
+                clamp(d.dot(&up), -1.0, 1.0).asin().to_degrees(),
+            )
+        } else {
+            (0.0, 0.0)
+        };
+        Orbit {
+            target: *target,
+            up,
+            distance,
+            azimuth,
+            elevation: clamp(elevation, -Orbit::MAX_ELEVATION, Orbit::MAX_ELEVATION),
+        }
+    }
+
+    /// Create orbit controls for the given camera, orbiting around `target`.
+    pub fn from_camera(camera: &dyn Camera, target: &Point3f) -> Orbit {
+        let c2w = camera.camera_to_world();
+        let eye = c2w * &Point3f::new(0.0, 0.0, 0.0);
+        let up = c2w * &Vector3f::new(0.0, 1.0, 0.0);
+        Orbit::new(&eye, target, &up)
+    }
+
+    /// Turn around the target by the given angles (in degrees).
+    pub fn rotate(&mut self, d_azimuth: f32, d_elevation: f32) {
+        self.azimuth = (self.azimuth + d_azimuth) % 360.0;
+        self.elevation = clamp(
+            self.elevation + d_elevation,
+            -Orbit::MAX_ELEVATION,
+            Orbit::MAX_ELEVATION,
+        );
+    }
+
+    /// Multiply the distance to the target by `factor`.
+    pub fn zoom(&mut self, factor: f32) {
+        assert!(factor > 0.0);
+        self.distance *= factor;
+    }
+
+    pub fn eye(&self) -> Point3f {
+        let (u, w) = Orbit::basis(&self.up);
+        let (sin_az, cos_az) = self.azimuth.to_radians().sin_cos();
+        let (sin_el, cos_el) = self.elevation.to_radians().sin_cos();
+        let d = cos_el * (cos_az * u + sin_az * w) + sin_el * self.up;
+        self.target + self.distance * d
+    }
+
+    pub fn camera_to_world(&self) -> Transform {
+        Transform::look_at(&self.eye(), &self.target, &self.up).inverse()
+    }
+
+    /// Two vectors forming an orthonormal basis with `up`.
+    fn basis(up: &Vector3f) -> (Vector3f, Vector3f) {
+        let (u, w) = coordinate_system(up);
+        (u.normalize(), w.normalize())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bounds::Bounds2f;
+    use crate::filter::BoxFilter;
+    use crate::{init_stats, pbrt, Normal3f, PbrtOptions, Point2i};
+
+    fn assert_close(a: Point3f, b: Point3f) {
+        assert!((a - b).length() < 1e-4, "{} != {}", a, b);
+    }
+
+    #[test]
+    fn test_orbit() {
+        let eye = Point3f::new(0.0, 0.0, 5.0);
+        let target = Point3f::new(0.0, 0.0, 0.0);
+        let up = Vector3f::new(0.0, 1.0, 0.0);
+        let mut orbit = Orbit::new(&eye, &target, &up);
+        assert_close(orbit.eye(), eye);
+
+        // The camera looks at the target from the eye position
+        let c2w = orbit.camera_to_world();
+        assert_close(&c2w * &Point3f::new(0.0, 0.0, 0.0), eye);
+        assert_close(&c2w * &Point3f::new(0.0, 0.0, 5.0), target);
+
+        // Quarter turn around the up axis
+        orbit.rotate(90.0, 0.0);
+        assert!((orbit.eye().y).abs() < 1e-4);
+        assert!(((orbit.eye() - target).length() - 5.0).abs() < 1e-4);
+        assert!((orbit.eye().z).abs() < 1e-4);
+        orbit.rotate(270.0, 0.0);
+        assert_close(orbit.eye(), eye);
+
+        // Elevation is clamped so we never look straight down
+        orbit.rotate(0.0, 120.0);
+        assert!(orbit.eye().y < 5.0 && orbit.eye().y > 4.99);
+
+        orbit.zoom(0.5);
+        assert!(((orbit.eye() - target).length() - 2.5).abs() < 1e-4);
+    }
+
+    #[test]
+    fn test_shutter() {
+        let mut shutter = Shutter::new(1.0, 3.0);
+        assert_eq!(shutter.time(0.25, 0.7), 1.5);
+
+        // Samples are concentrated around the middle of the interval
+        shutter.curve = ShutterCurve::Triangle;
+        assert_eq!(shutter.time(0.5, 0.0), 2.0);
+        assert!((shutter.time(0.125, 0.0) - 1.5).abs() < 1e-5);
+        assert!((shutter.time(0.875, 0.0) - 2.5).abs() < 1e-5);
+
+        // Closed for the first half of the interval
+        shutter.curve = ShutterCurve::Custom(Distribution1D::new(&[0.0, 1.0]));
+        assert!((0..10).all(|i| shutter.time(i as f32 / 10.0, 0.0) >= 2.0));
+
+        // The top row is exposed during the first half of the interval, the bottom one during
+        // the second half
+        shutter.curve = ShutterCurve::Box;
+        shutter.rolling = 0.5;
+        assert_eq!(shutter.time(0.0, 0.0), 1.0);
+        assert_eq!(shutter.time(1.0, 0.0), 2.0);
+        assert_eq!(shutter.time(0.0, 1.0), 2.0);
+        assert_eq!(shutter.time(1.0, 1.0), 3.0);
+    }
+
+    #[test]
+    fn test_projections() {
+        let camera = |projection, width: i32, fov| {
+            let film = Film::new(
+                Point2i::new(width, 8),
+                Bounds2f::from_points(&Point2f::new(0.0, 0.0), &Point2f::new(1.0, 1.0)),
+                &BoxFilter::new(0.5, 0.5),
+                35.0,
+                "unused.png",
+                1.0,
+                f32::INFINITY,
+            );
+            let aspect = width as f32 / 8.0;
+            let mut camera = PerspectiveCamera::new(
+                Transform::default(),
+                Bounds2f::from_points(&Point2f::new(-aspect, -1.0), &Point2f::new(aspect, 1.0)),
+                Shutter::new(0.0, 1.0),
+                0.0,
+                1e6,
+                fov,
+                Box::new(film),
+            );
+            camera.set_projection(projection);
+            camera
+        };
+        let ray = |camera: &PerspectiveCamera, x, y| {
+            camera.generate_ray_differential(&CameraSample {
+                p_film: Point2f::new(x, y),
+                p_lens: Point2f::new(0.5, 0.5),
+                time: 0.0,
+            })
+        };
+        let assert_dir = |ray: Ray, d: Vector3f| {
+            assert!(ray.t_max > 0.0);
+            assert!((ray.d - d).length() < 1e-4, "{:?} != {:?}", ray.d, d);
+        };
+
+        // Half of the field of view at the edges of the image
+        for projection in &[ProjectionType::Equidistant, ProjectionType::Equisolid] {
+            let fisheye = camera(*projection, 8, 180.0);
+            let centre = ray(&fisheye, 4.0, 4.0);
+            assert!(centre.differential.unwrap().rx_direction.x > 0.0);
+            assert_dir(centre, Vector3f::new(0.0, 0.0, 1.0));
+            assert_dir(ray(&fisheye, 8.0, 4.0), Vector3f::new(1.0, 0.0, 0.0));
+            assert_dir(ray(&fisheye, 4.0, 0.0), Vector3f::new(0.0, 1.0, 0.0));
+        }
+        // Beyond the range of the fisheye
+        let fisheye = camera(ProjectionType::Equidistant, 8, 360.0);
+        assert_dir(ray(&fisheye, 0.0, 4.0), Vector3f::new(0.0, 0.0, -1.0));
+        assert_eq!(ray(&fisheye, 0.0, 0.0).t_max, 0.0);
+
+        // A 2:1 image covers the whole circle
+        let panorama = camera(ProjectionType::Cylindrical, 16, 180.0);
+        assert_dir(ray(&panorama, 12.0, 4.0), Vector3f::new(1.0, 0.0, 0.0));
+        assert_dir(ray(&panorama, 16.0, 4.0), Vector3f::new(0.0, 0.0, -1.0));
+        assert!(ray(&panorama, 8.0, 0.0).d.y > 0.0);
+    }
+
+    #[test]
+    fn test_stereo_convergence() {
+        let film = Film::new(
+            Point2i::new(8, 4),
+            Bounds2f::from_points(&Point2f::new(0.0, 0.0), &Point2f::new(1.0, 1.0)),
+            &BoxFilter::new(0.5, 0.5),
+            35.0,
+            "unused.png",
+            1.0,
+            f32::INFINITY,
+        );
+        let screen = Bounds2f::from_points(&Point2f::new(-1.0, -1.0), &Point2f::new(1.0, 1.0));
+        let camera = StereoCamera::new(
+            Transform::default(),
+            screen,
+            Shutter::new(0.0, 1.0),
+            0.0,
+            1e6,
+            60.0,
+            0.5,
+            10.0,
+            Box::new(film),
+        );
+        let ray = |x| {
+            camera.generate_ray(&CameraSample {
+                p_film: Point2f::new(x, 2.0),
+                p_lens: Point2f::new(0.5, 0.5),
+                time: 0.0,
+            })
+        };
+
+        // The centre of each view looks from its eye at the point at the convergence distance
+        let (left, right) = (ray(2.0), ray(6.0));
+        assert_close(left.o, Point3f::new(-0.25, 0.0, 0.0));
+        assert_close(right.o, Point3f::new(0.25, 0.0, 0.0));
+        assert_close(left.at(10.0 / left.d.z), Point3f::new(0.0, 0.0, 10.0));
+        assert_close(right.at(10.0 / right.d.z), Point3f::new(0.0, 0.0, 10.0));
+
+        // The views are the mirror of each other
+        let (left, right) = (ray(0.5), ray(7.5));
+        assert!((left.d.x + right.d.x).abs() < 1e-5);
+        assert!((left.d.y - right.d.y).abs() < 1e-5);
+    }
+
+    #[test]
+    fn test_bake_camera() {
+        // A unit square in the z = 0 plane, with texture coordinates matching its xy coordinates
+        let n = [Normal3f::new(0.0, 0.0, 1.0); 3];
+        let triangle = |i: [usize; 3]| {
+            let p = [
+                Point3f::new(0.0, 0.0, 0.0),
+                Point3f::new(1.0, 0.0, 0.0),
+                Point3f::new(1.0, 1.0, 0.0),
+                Point3f::new(0.0, 1.0, 0.0),
+            ];
+            UvTriangle {
+                uv: [
+                    Point2f::new(p[i[0]].x, p[i[0]].y),
+                    Point2f::new(p[i[1]].x, p[i[1]].y),
+                    Point2f::new(p[i[2]].x, p[i[2]].y),
+                ],
+                p: [p[i[0]], p[i[1]], p[i[2]]],
+                n,
+            }
+        };
+        let triangles = vec![triangle([0, 1, 2]), triangle([0, 2, 3])];
+
+        let film = Film::new(
+            Point2i::new(4, 4),
+            Bounds2f::from_points(&Point2f::new(0.0, 0.0), &Point2f::new(1.0, 1.0)),
+            &BoxFilter::new(0.5, 0.5),
+            35.0,
+            "unused.png",
+            1.0,
+            f32::INFINITY,
+        );
+        let camera = BakeCamera::new(triangles, Box::new(film));
+        let sample = |x, y| CameraSample {
+            p_film: Point2f::new(x, y),
+            p_lens: Point2f::new(0.5, 0.5),
+            time: 0.0,
+        };
+
+        // The top left of the image is at uv (0, 1)
+        let ray = camera.generate_ray(&sample(1.0, 1.0));
+        assert_close(ray.o, Point3f::new(0.25, 0.75, 0.0));
+        assert!(ray.o.z >= 0.0);
+        assert_eq!(ray.d, Vector3f::new(0.0, 0.0, 1.0));
+        assert!(ray.t_max > 0.0);
+
+        let ray = camera.generate_ray(&sample(3.5, 0.5));
+        assert_close(ray.o, Point3f::new(0.875, 0.875, 0.0));
+
+        // Texels outside of the mesh's texture layout
+        let camera = BakeCamera::new(camera.triangles[..1].to_vec(), camera.film);
+        let ray = camera.generate_ray(&sample(0.5, 0.5));
+        assert_eq!(ray.t_max, 0.0);
+    }
+
+    #[test]
+    fn test_camera_rays_cull_back_faces() {
+        init_stats();
+        let load = |camera_params: &str| {
+            let opts = PbrtOptions {
+                defer_render: true,
+                ..PbrtOptions::default()
+            };
+            let scene = format!(
+                r#"
+Camera "perspective" {}
+Film "image" "integer xresolution" [4] "integer yresolution" [4]
+Sampler "02sequence" "integer pixelsamples" [1]
+WorldBegin
+AttributeBegin
+ReverseOrientation
+Shape "trianglemesh" "integer indices" [0 2 1 0 3 2] "bool backfacecull" "true"
+    "point P" [-1 -1 1  1 -1 1  1 1 1  -1 1 1]
+AttributeEnd
+Shape "trianglemesh" "integer indices" [0 1 2 0 2 3]
+    "point P" [-1 -1 3  1 -1 3  1 1 3  -1 1 3]
+WorldEnd
+"#,
+                camera_params
+            );
+            pbrt::parse_scene_string(opts, &scene).unwrap().unwrap()
+        };
+        let sample = CameraSample {
+            p_film: Point2f::new(2.0, 2.0),
+            p_lens: Point2f::new(0.5, 0.5),
+            time: 0.0,
+        };
+
+        // Both quads face +z (the first one after ReverseOrientation), so from the origin only back
+        // faces are hit
+        let context = load("");
+        let hit_distance = |z: f32, backface_culling| {
+            let d = Vector3f::new(0.0, 0.0, 1.0 - z);
+            let mut ray = Ray::new(Point3f::new(0.0, 0.0, z), d.normalize());
+            ray.backface_culling = backface_culling;
+            context.scene.intersect(&mut ray).map(|_| ray.t_max)
+        };
+        assert!((hit_distance(0.0, BackfaceCulling::None).unwrap() - 1.0).abs() < 1e-3);
+        assert!((hit_distance(0.0, BackfaceCulling::Flagged).unwrap() - 3.0).abs() < 1e-3);
+        assert_eq!(hit_distance(0.0, BackfaceCulling::All), None);
+        // From the other side, the front face of the second quad is hit
+        assert!((hit_distance(5.0, BackfaceCulling::All).unwrap() - 2.0).abs() < 1e-3);
+
+        // Camera rays only cull the flagged shapes, unless the camera culls all back faces
+        let camera_ray = context.camera.generate_ray_differential(&sample);
+        assert_eq!(camera_ray.backface_culling, BackfaceCulling::Flagged);
+        let context = load(r#""bool backfacecull" "true""#);
+        assert_eq!(
+            context.camera.generate_ray(&sample).backface_culling,
+            BackfaceCulling::All
+        );
+    }
+}
@@ -0,0 +1,57 @@
+//! Cancellation of long running operations (building the acceleration structures, rendering).
+//!
+//! Once `install_interrupt_handler()` has been called, Ctrl-C cancels all the operations in
+//! progress instead of killing the process, so that e.g. a BVH build for millions of primitives
+//! stops right away rather than after the tree is complete. A second Ctrl-C exits immediately.
+
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+
+/// Set by the interrupt handler
+static INTERRUPTED: AtomicBool = AtomicBool::new(false);
+
+/// Whether an operation should stop early. A token is cancelled either explicitly with
+/// `cancel()`, or by an interrupt.
+#[derive(Clone, Debug, Default)]
+pub struct CancellationToken {
+    cancelled: Arc<AtomicBool>,
+}
+
+impl CancellationToken {
+    pub fn new() -> CancellationToken {
+        CancellationToken::default()
+    }
+
+    pub fn cancel(&self) {
+        self.cancelled.store(true, Ordering::Relaxed);
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.cancelled.load(Ordering::Relaxed) || interrupted()
+    }
+}
+
+/// Whether the user pressed Ctrl-C.
+pub fn interrupted() -> bool {
+    INTERRUPTED.load(Ordering::Relaxed)
+}
+
+/// Make SIGINT cancel the operations in progress. If they don't stop by the time a second SIGINT
+/// is received, the process exits.
+#[cfg(unix)]
+pub fn install_interrupt_handler() {
+    extern "C" fn on_interrupt(_signal: libc::c_int) {
+        if INTERRUPTED.swap(true, Ordering::Relaxed) {
+            // Only async-signal-safe functions can be called here
+            unsafe { libc::_exit(130) };
+        }
+    }
+
+    let handler: extern "C" fn(libc::c_int) = on_interrupt;
+    unsafe {
+        libc::signal(libc::SIGINT, handler as libc::sighandler_t);
+    }
+}
+
+#[cfg(not(unix))]
+pub fn install_interrupt_handler() {}
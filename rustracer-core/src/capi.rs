@@ -0,0 +1,274 @@
+//! Minimal C API to embed the renderer in other applications.
+//!
+//! This is only compiled with the `capi` feature. To get a shared or a static library that can
+//! be linked from C or C++, build the crate with the corresponding crate type:
+//!
+//! ```text
+//! cargo rustc -p rustracer-core --release --features capi --crate-type cdylib
+//! cargo rustc -p rustracer-core --release --features capi --crate-type staticlib
+//! ```
+//!
+//! The corresponding header is `include/rustracer.h`, generated by cbindgen from this module
+//! (see `cbindgen.toml`). A typical session looks like:
+//!
+//! ```c
+//! rt_context *ctx = rt_context_new(0);
+//! if (rt_context_load_string(ctx, scene_description) != 0) {
+//!     fprintf(stderr, "%s\n", rt_context_last_error(ctx));
+//! }
+//! rt_context_render(ctx);
+//! int32_t width, height;
+//! rt_context_image_size(ctx, &width, &height);
+//! float *rgb = malloc(3 * width * height * sizeof(float));
+//! rt_context_copy_image(ctx, rgb, 3 * width * height);
+//! rt_context_free(ctx);
+//! ```
+//!
+//! All the functions returning an `int32_t` return 0 on success and -1 on failure, in which case
+//! `rt_context_last_error()` returns a description of the error.
+
+use std::ffi::{CStr, CString};
+use std::os::raw::c_char;
+use std::panic::{catch_unwind, AssertUnwindSafe};
+use std::ptr;
+use std::sync::Once;
+
+use anyhow::{anyhow, Result};
+
+use crate::pbrt;
+use crate::renderer::RenderContext;
+use crate::PbrtOptions;
+
+static INIT_STATS: Once = Once::new();
+
+/// Opaque handle to a renderer instance.
+pub struct RtContext {
+    options: PbrtOptions,
+    context: Option<RenderContext>,
+    last_error: CString,
+}
+
+impl RtContext {
+    /// Run `f`, turning any error or panic into a -1 return value and recording the error.
+    fn wrap<F: FnOnce(&mut RtContext) -> Result<()>>(&mut self, f: F) -> i32 {
+        let res = catch_unwind(AssertUnwindSafe(|| f(self)))
+            .unwrap_or_else(|_| Err(anyhow!("Panic in the renderer")));
+        match res {
+            Ok(()) => 0,
+            Err(e) => {
+                self.last_error =
+                    CString::new(format!("{:#}", e).replace('\0', " ")).unwrap_or_default();
+                -1
+            }
+        }
+    }
+
+    fn render_context(&mut self) -> Result<&mut RenderContext> {
+        self.context
+            .as_mut()
+            .ok_or_else(|| anyhow!("No scene has been loaded"))
+    }
+}
+
+fn to_str<'a>(s: *const c_char) -> Result<&'a str> {
+    if s.is_null() {
+        return Err(anyhow!("Null string"));
+    }
+    // Safety: the caller guarantees `s` is a valid NUL-terminated string
+    Ok(unsafe { CStr::from_ptr(s) }.to_str()?)
+}
+
+/// Create a new renderer instance using `num_threads` threads to render (0 means one per core).
+/// It must be freed with `rt_context_free()`.
+#[no_mangle]
+pub extern "C" fn rt_context_new(num_threads: u32) -> *mut RtContext {
+    INIT_STATS.call_once(crate::init_stats);
+    let ctx = RtContext {
+        options: PbrtOptions {
+            num_threads: num_threads.min(u32::from(u8::MAX)) as u8,
+            defer_render: true,
+            ..PbrtOptions::default()
+        },
+        context: None,
+        last_error: CString::default(),
+    };
+    Box::into_raw(Box::new(ctx))
+}
+
+/// Free a renderer instance.
+///
+/// # Safety
+///
+/// `ctx` must have been returned by `rt_context_new()` and not freed already, or be null.
+#[no_mangle]
+pub unsafe extern "C" fn rt_context_free(ctx: *mut RtContext) {
+    if !ctx.is_null() {
+        drop(Box::from_raw(ctx));
+    }
+}
+
+/// Load a scene from a string in the pbrt format, replacing any previously loaded scene.
+///
+/// # Safety
+///
+/// `ctx` must be a valid context and `scene` a valid NUL-terminated string.
+#[no_mangle]
+pub unsafe extern "C" fn rt_context_load_string(ctx: *mut RtContext, scene: *const c_char) -> i32 {
+    let Some(ctx) = ctx.as_mut() else {
+        return -1;
+    };
+    ctx.wrap(|ctx| {
+        let scene = to_str(scene)?;
+        ctx.context = pbrt::parse_scene_string(ctx.options.clone(), scene)?;
+        Ok(())
+    })
+}
+
+/// Load a scene from a pbrt file, replacing any previously loaded scene.
+///
+/// # Safety
+///
+/// `ctx` must be a valid context and `filename` a valid NUL-terminated string.
+#[no_mangle]
+pub unsafe extern "C" fn rt_context_load_file(ctx: *mut RtContext, filename: *const c_char) -> i32 {
+    let Some(ctx) = ctx.as_mut() else {
+        return -1;
+    };
+    ctx.wrap(|ctx| {
+        let filename = to_str(filename)?;
+        ctx.context = pbrt::parse_scene(ctx.options.clone(), filename)?;
+        Ok(())
+    })
+}
+
+/// Render the loaded scene. The result can then be retrieved with `rt_context_copy_image()`.
+///
+/// # Safety
+///
+/// `ctx` must be a valid context.
+#[no_mangle]
+pub unsafe extern "C" fn rt_context_render(ctx: *mut RtContext) -> i32 {
+    let Some(ctx) = ctx.as_mut() else {
+        return -1;
+    };
+    ctx.wrap(|ctx| {
+        ctx.render_context()?.render_in_memory();
+        Ok(())
+    })
+}
+
+/// Get the resolution of the rendered image.
+///
+/// # Safety
+///
+/// `ctx` must be a valid context, and `width` and `height` valid pointers.
+#[no_mangle]
+pub unsafe extern "C" fn rt_context_image_size(
+    ctx: *mut RtContext,
+    width: *mut i32,
+    height: *mut i32,
+) -> i32 {
+    let Some(ctx) = ctx.as_mut() else {
+        return -1;
+    };
+    ctx.wrap(|ctx| {
+        if width.is_null() || height.is_null() {
+            return Err(anyhow!("Null output pointer"));
+        }
+        let res = ctx.render_context()?.camera.get_film().image_resolution();
+        *width = res.x;
+        *height = res.y;
+        Ok(())
+    })
+}
+
+/// Copy the linear RGB values of the rendered image, in scanline order, into `buffer`, which
+/// must hold at least `3 * width * height` floats.
+///
+/// # Safety
+///
+/// `ctx` must be a valid context, and `buffer` must point to at least `len` floats.
+#[no_mangle]
+pub unsafe extern "C" fn rt_context_copy_image(
+    ctx: *mut RtContext,
+    buffer: *mut f32,
+    len: usize,
+) -> i32 {
+    let Some(ctx) = ctx.as_mut() else {
+        return -1;
+    };
+    ctx.wrap(|ctx| {
+        let rgb = ctx.render_context()?.camera.get_film().rgb();
+        if buffer.is_null() || len < rgb.len() {
+            return Err(anyhow!(
+                "Image buffer too small: {} floats needed, got {}",
+                rgb.len(),
+                len
+            ));
+        }
+        ptr::copy_nonoverlapping(rgb.as_ptr(), buffer, rgb.len());
+        Ok(())
+    })
+}
+
+/// Return a description of the last error, or an empty string. The string is owned by the
+/// context and is valid until the next call to a function taking it.
+///
+/// # Safety
+///
+/// `ctx` must be a valid context.
+#[no_mangle]
+pub unsafe extern "C" fn rt_context_last_error(ctx: *const RtContext) -> *const c_char {
+    match ctx.as_ref() {
+        Some(ctx) => ctx.last_error.as_ptr(),
+        None => ptr::null(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SCENE: &str = r#"
+LookAt 0 0 5  0 0 0  0 1 0
+Camera "perspective" "float fov" [30]
+Film "image" "integer xresolution" [8] "integer yresolution" [6]
+Sampler "02sequence" "integer pixelsamples" [1]
+Integrator "path"
+WorldBegin
+LightSource "point" "point from" [0 0 5] "rgb I" [10 10 10]
+Material "matte" "rgb Kd" [0.5 0.5 0.5]
+Shape "sphere" "float radius" [1]
+WorldEnd
+"#;
+
+    #[test]
+    fn test_render_through_c_api() {
+        unsafe {
+            let ctx = rt_context_new(1);
+            // Nothing to render yet
+            assert_eq!(rt_context_render(ctx), -1);
+            assert!(!CStr::from_ptr(rt_context_last_error(ctx))
+                .to_bytes()
+                .is_empty());
+
+            let scene = CString::new(SCENE).unwrap();
+            assert_eq!(rt_context_load_string(ctx, scene.as_ptr()), 0);
+            assert_eq!(rt_context_render(ctx), 0);
+
+            let (mut width, mut height) = (0, 0);
+            assert_eq!(rt_context_image_size(ctx, &mut width, &mut height), 0);
+            assert_eq!((width, height), (8, 6));
+
+            let mut rgb = vec![0.0f32; 3 * 8 * 6];
+            assert_eq!(rt_context_copy_image(ctx, rgb.as_mut_ptr(), 10), -1);
+            assert_eq!(rt_context_copy_image(ctx, rgb.as_mut_ptr(), rgb.len()), 0);
+            // The sphere is lit in the centre of the image, the background is black
+            let centre = 3 * (3 * 8 + 4);
+            assert!(rgb[centre] > 0.0);
+            assert_eq!(rgb[0], 0.0);
+
+            rt_context_free(ctx);
+        }
+    }
+}
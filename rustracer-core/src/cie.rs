@@ -1,11 +1,205 @@
 //! CIE (Commission Internationale d'Eclairage) data used to convert sampled SPD data into XYZ
-//! linear tri-stimulus representation.
+//! linear tri-stimulus representation, along with a few utilities to work in XYZ space (xyY
+//! conversions, chromatic adaptation and the RGB colour spaces images can be written in).
 #![allow(non_snake_case)]
 
 pub const N_CIE_SAMPLES: usize = 471;
 
 pub const CIE_Y_INTEGRAL: f32 = 106.856895;
 
+/// XYZ coordinates of the D65 white point (the white of linear sRGB), normalized to Y = 1.
+pub const D65_WHITE_XYZ: [f32; 3] = [0.950456, 1.0, 1.088754];
+
+/// XYZ coordinates of the ACES white point (close to D60), normalized to Y = 1.
+pub const ACES_WHITE_XYZ: [f32; 3] = [0.952646, 1.0, 1.008825];
+
+/// A linear RGB colour space, defined by its primaries and white point.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ColourSpace {
+    /// sRGB / Rec.709 primaries with a D65 white point: the space the renderer works in
+    Srgb,
+    /// ACES AP1 primaries with the ACES white point
+    AcesCg,
+    /// Rec.2020 primaries with a D65 white point
+    Rec2020,
+}
+
+impl ColourSpace {
+    pub fn from_name(name: &str) -> Option<ColourSpace> {
+        match name {
+            "srgb" => Some(ColourSpace::Srgb),
+            "acescg" => Some(ColourSpace::AcesCg),
+            "rec2020" => Some(ColourSpace::Rec2020),
+            _ => None,
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            ColourSpace::Srgb => "srgb",
+            ColourSpace::AcesCg => "acescg",
+            ColourSpace::Rec2020 => "rec2020",
+        }
+    }
+
+    /// XYZ coordinates of the white point, normalized to Y = 1.
+    pub fn white(self) -> [f32; 3] {
+        match self {
+            ColourSpace::Srgb | ColourSpace::Rec2020 => D65_WHITE_XYZ,
+            ColourSpace::AcesCg => ACES_WHITE_XYZ,
+        }
+    }
+
+    /// xy chromaticities of the red, green and blue primaries and of the white point.
+    pub fn chromaticities(self) -> [[f32; 2]; 4] {
+        match self {
+            ColourSpace::Srgb => [[0.64, 0.33], [0.30, 0.60], [0.15, 0.06], [0.3127, 0.3290]],
+            ColourSpace::AcesCg => [
+                [0.713, 0.293],
+                [0.165, 0.830],
+                [0.128, 0.044],
+                [0.32168, 0.33767],
+            ],
+            ColourSpace::Rec2020 => [
+                [0.708, 0.292],
+                [0.170, 0.797],
+                [0.131, 0.046],
+                [0.3127, 0.3290],
+            ],
+        }
+    }
+
+    /// Convert XYZ values to linear RGB in this colour space. The XYZ values must already be
+    /// adapted to the colour space's white point (see `bradford_adaptation()`).
+    pub fn from_xyz(self, xyz: &[f32; 3]) -> [f32; 3] {
+        let m = match self {
+            ColourSpace::Srgb => &XYZ_TO_SRGB,
+            ColourSpace::AcesCg => &XYZ_TO_ACESCG,
+            ColourSpace::Rec2020 => &XYZ_TO_REC2020,
+        };
+        mul_mat_vec(m, xyz)
+    }
+
+    /// Convert linear RGB values in this colour space to XYZ.
+    pub fn to_xyz(self, rgb: &[f32; 3]) -> [f32; 3] {
+        let m = match self {
+            ColourSpace::Srgb => &SRGB_TO_XYZ,
+            ColourSpace::AcesCg => &ACESCG_TO_XYZ,
+            ColourSpace::Rec2020 => &REC2020_TO_XYZ,
+        };
+        mul_mat_vec(m, rgb)
+    }
+}
+
+const XYZ_TO_SRGB: [[f32; 3]; 3] = [
+    [3.240479, -1.537150, -0.498535],
+    [-0.969256, 1.875991, 0.041556],
+    [0.055648, -0.204043, 1.057311],
+];
+
+const SRGB_TO_XYZ: [[f32; 3]; 3] = [
+    [0.412453, 0.357580, 0.180423],
+    [0.212671, 0.715160, 0.072169],
+    [0.019334, 0.119193, 0.950227],
+];
+
+const XYZ_TO_ACESCG: [[f32; 3]; 3] = [
+    [1.6410234, -0.3248033, -0.2364247],
+    [-0.6636629, 1.6153316, 0.0167563],
+    [0.0117219, -0.0082844, 0.9883949],
+];
+
+const ACESCG_TO_XYZ: [[f32; 3]; 3] = [
+    [0.6624542, 0.1340042, 0.1561877],
+    [0.2722287, 0.6740818, 0.0536895],
+    [-0.0055746, 0.0040607, 1.0103391],
+];
+
+const XYZ_TO_REC2020: [[f32; 3]; 3] = [
+    [1.7166512, -0.3556708, -0.2533663],
+    [-0.6666844, 1.6164812, 0.0157685],
+    [0.0176399, -0.0427706, 0.9421031],
+];
+
+const REC2020_TO_XYZ: [[f32; 3]; 3] = [
+    [0.6369580, 0.1446169, 0.1688810],
+    [0.2627002, 0.6779981, 0.0593017],
+    [0.0000000, 0.0280727, 1.0609851],
+];
+
+/// Bradford cone response matrix.
+const BRADFORD: [[f32; 3]; 3] = [
+    [0.8951, 0.2664, -0.1614],
+    [-0.7502, 1.7135, 0.0367],
+    [0.0389, -0.0685, 1.0296],
+];
+
+/// Inverse of the Bradford cone response matrix.
+const BRADFORD_INV: [[f32; 3]; 3] = [
+    [0.9869929, -0.1470543, 0.1599627],
+    [0.4323053, 0.5183603, 0.0492912],
+    [-0.0085287, 0.0400428, 0.9684867],
+];
+
+/// Convert XYZ tri-stimulus values to xyY (chromaticity + luminance). Black maps to the
+/// chromaticity of the D65 white point, so that the result can always be converted back.
+pub fn xyz_to_xyy(xyz: &[f32; 3]) -> [f32; 3] {
+    let sum = xyz[0] + xyz[1] + xyz[2];
+    if sum == 0.0 {
+        let white = xyz_to_xyy(&D65_WHITE_XYZ);
+        return [white[0], white[1], 0.0];
+    }
+    [xyz[0] / sum, xyz[1] / sum, xyz[1]]
+}
+
+/// Convert xyY (chromaticity + luminance) values to XYZ tri-stimulus values.
+pub fn xyy_to_xyz(xyy: &[f32; 3]) -> [f32; 3] {
+    let (x, y, lum) = (xyy[0], xyy[1], xyy[2]);
+    if y == 0.0 {
+        return [0.0, 0.0, 0.0];
+    }
+    [x * lum / y, lum, (1.0 - x - y) * lum / y]
+}
+
+/// Compute the matrix adapting XYZ colours viewed under the white point `src_white` to how they
+/// would appear under `dst_white`, using the Bradford transform. Both white points are given as
+/// XYZ; only their chromaticity matters.
+pub fn bradford_adaptation(src_white: &[f32; 3], dst_white: &[f32; 3]) -> [[f32; 3]; 3] {
+    let src_lms = mul_mat_vec(&BRADFORD, &normalize_white(src_white));
+    let dst_lms = mul_mat_vec(&BRADFORD, &normalize_white(dst_white));
+    // M^-1 * diag(dst / src) * M
+    let mut scaled = BRADFORD;
+    for (i, row) in scaled.iter_mut().enumerate() {
+        let s = dst_lms[i] / src_lms[i];
+        row.iter_mut().for_each(|v| *v *= s);
+    }
+    let mut m = [[0.0; 3]; 3];
+    for (i, row) in m.iter_mut().enumerate() {
+        for (j, v) in row.iter_mut().enumerate() {
+            *v = (0..3).map(|k| BRADFORD_INV[i][k] * scaled[k][j]).sum();
+        }
+    }
+    m
+}
+
+/// Apply a chromatic adaptation matrix (as returned by `bradford_adaptation()`) to an XYZ colour.
+pub fn adapt_xyz(m: &[[f32; 3]; 3], xyz: &[f32; 3]) -> [f32; 3] {
+    mul_mat_vec(m, xyz)
+}
+
+fn normalize_white(white: &[f32; 3]) -> [f32; 3] {
+    assert!(white[1] > 0.0, "white point must have a positive luminance");
+    [white[0] / white[1], 1.0, white[2] / white[1]]
+}
+
+fn mul_mat_vec(m: &[[f32; 3]; 3], v: &[f32; 3]) -> [f32; 3] {
+    [
+        m[0][0] * v[0] + m[0][1] * v[1] + m[0][2] * v[2],
+        m[1][0] * v[0] + m[1][1] * v[1] + m[1][2] * v[2],
+        m[2][0] * v[0] + m[2][1] * v[1] + m[2][2] * v[2],
+    ]
+}
+
 pub const CIE_X: [f32; N_CIE_SAMPLES] = [
     // CIE X function values
     0.0001299000,
@@ -0,0 +1,244 @@
+//! ID mattes following the Cryptomatte conventions, to select objects and materials in
+//! compositing software (Nuke, Fusion, etc.) after rendering.
+//!
+//! Each object and material name is hashed to a float ID (see `name_to_id()`). For every pixel,
+//! the film accumulates the filter-weighted coverage of the IDs of the primary hits, and writes
+//! the `depth` IDs covering the most of the pixel, ranked by coverage, to an EXR file with one
+//! set of layers per matte type:
+//!
+//! * `CryptoObject00`, `CryptoObject01`, ...: the IDs of the objects;
+//! * `CryptoMaterial00`, `CryptoMaterial01`, ...: the IDs of the materials.
+//!
+//! Each RGBA layer holds 2 ranks as `(id, coverage, id, coverage)`. The names behind the IDs are
+//! stored in a manifest in the file's metadata, as the Cryptomatte specification requires.
+//!
+//! Objects are named with the `"string name"` parameter of their shapes, or the name of the
+//! object they are part of (`ObjectBegin`), or else after their shape type and the order of
+//! their `Shape` directive (e.g. `trianglemesh.3`). Materials are named after the named material
+//! they use, or else after their type.
+
+use std::collections::BTreeMap;
+use std::fmt::Write;
+
+/// Float IDs of the object and material of a primitive. Samples that don't hit anything have the
+/// ID 0, which isn't written to the manifests.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct MatteIds {
+    pub object: f32,
+    pub material: f32,
+}
+
+/// Types of mattes, in the order of the values of `MatteIds` and of the layers in the file.
+pub const MATTE_TYPES: [&str; 2] = ["CryptoObject", "CryptoMaterial"];
+
+/// The names of the objects and materials of the scene, and their IDs.
+#[derive(Debug, Clone, Default)]
+pub struct MatteNames {
+    objects: BTreeMap<String, f32>,
+    materials: BTreeMap<String, f32>,
+}
+
+impl MatteNames {
+    pub fn new() -> MatteNames {
+        MatteNames::default()
+    }
+
+    /// Record the names of an object and its material, and return their IDs.
+    pub fn add(&mut self, object: &str, material: &str) -> MatteIds {
+        MatteIds {
+            object: *self
+                .objects
+                .entry(object.to_owned())
+                .or_insert_with(|| name_to_id(object)),
+            material: *self
+                .materials
+                .entry(material.to_owned())
+                .or_insert_with(|| name_to_id(material)),
+        }
+    }
+
+    /// Manifest of the given matte type (an index into `MATTE_TYPES`): a JSON object mapping each
+    /// name to the hexadecimal representation of its ID's bits.
+    pub fn manifest(&self, matte_type: usize) -> String {
+        let names = if matte_type == 0 {
+            &self.objects
+        } else {
+            &self.materials
+        };
+        let entries: Vec<String> = names
+            .iter()
+            .map(|(name, id)| format!("{}:\"{:08x}\"", json_string(name), id.to_bits()))
+            .collect();
+        format!("{{{}}}", entries.join(","))
+    }
+}
+
+/// Metadata of the layers of the given matte type, as `(name, value)` pairs.
+pub fn metadata(matte_type: usize, names: &MatteNames) -> Vec<(String, String)> {
+    let type_name = MATTE_TYPES[matte_type];
+    let key = &format!("{:08x}", murmur_hash3_32(type_name.as_bytes(), 0))[..7];
+    vec![
+        (format!("cryptomatte/{}/name", key), type_name.to_owned()),
+        (
+            format!("cryptomatte/{}/hash", key),
+            "MurmurHash3_32".to_owned(),
+        ),
+        (
+            format!("cryptomatte/{}/conversion", key),
+            "uint32_to_float32".to_owned(),
+        ),
+        (
+            format!("cryptomatte/{}/manifest", key),
+            names.manifest(matte_type),
+        ),
+    ]
+}
+
+/// The ID of a name: its 32-bit MurmurHash3 reinterpreted as a float, with the exponent clamped so
+/// that it is never a denormal, an infinity or a NaN (which compositing software could mangle).
+pub fn name_to_id(name: &str) -> f32 {
+    let hash = murmur_hash3_32(name.as_bytes(), 0);
+    let exponent = ((hash >> 23) & 0xff).clamp(1, 254);
+    f32::from_bits((hash & 0x807f_ffff) | (exponent << 23))
+}
+
+/// The x86 32-bit variant of MurmurHash3.
+pub fn murmur_hash3_32(key: &[u8], seed: u32) -> u32 {
+    const C1: u32 = 0xcc9e_2d51;
+    const C2: u32 = 0x1b87_3593;
+
+    let mut h = seed;
+    let mut blocks = key.chunks_exact(4);
+    for block in &mut blocks {
+        let k = u32::from_le_bytes([block[0], block[1], block[2], block[3]]);
+        h ^= k.wrapping_mul(C1).rotate_left(15).wrapping_mul(C2);
+        h = h.rotate_left(13).wrapping_mul(5).wrapping_add(0xe654_6b64);
+    }
+    let tail = blocks.remainder();
+    if !tail.is_empty() {
+        let k = tail
+            .iter()
+            .rev()
+            .fold(0u32, |k, &b| (k << 8) | u32::from(b));
+        h ^= k.wrapping_mul(C1).rotate_left(15).wrapping_mul(C2);
+    }
+
+    h ^= key.len() as u32;
+    h ^= h >> 16;
+    h = h.wrapping_mul(0x85eb_ca6b);
+    h ^= h >> 13;
+    h = h.wrapping_mul(0xc2b2_ae35);
+    h ^= h >> 16;
+    h
+}
+
+/// Quote and escape a string for JSON, escaping non-ASCII characters too as EXR attributes only
+/// allow ASCII text.
+fn json_string(s: &str) -> String {
+    let mut json = String::with_capacity(s.len() + 2);
+    json.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => json.push_str("\\\""),
+            '\\' => json.push_str("\\\\"),
+            ' '..='~' => json.push(c),
+            _ => {
+                let mut units = [0u16; 2];
+                for unit in c.encode_utf16(&mut units) {
+                    write!(json, "\\u{:04x}", unit).unwrap();
+                }
+            }
+        }
+    }
+    json.push('"');
+    json
+}
+
+/// Filter-weighted coverage of a pixel by each ID.
+#[derive(Debug, Clone, Default)]
+pub struct IdCoverage {
+    coverage: Vec<(f32, f32)>,
+}
+
+impl IdCoverage {
+    pub fn add(&mut self, id: f32, weight: f32) {
+        match self.coverage.iter_mut().find(|(i, _)| *i == id) {
+            Some((_, w)) => *w += weight,
+            None => self.coverage.push((id, weight)),
+        }
+    }
+
+    pub fn merge(&mut self, other: &IdCoverage) {
+        for &(id, weight) in &other.coverage {
+            self.add(id, weight);
+        }
+    }
+
+    pub fn clear(&mut self) {
+        self.coverage.clear();
+    }
+
+    /// The IDs and their coverage, normalized by the pixel's filter weight sum, from the one
+    /// covering the most of the pixel to the least.
+    pub fn ranked(&self, filter_weight_sum: f32) -> Vec<(f32, f32)> {
+        let inv_wt = if filter_weight_sum != 0.0 {
+            1.0 / filter_weight_sum
+        } else {
+            0.0
+        };
+        let mut ranked: Vec<_> = self
+            .coverage
+            .iter()
+            .map(|&(id, weight)| (id, weight * inv_wt))
+            .collect();
+        ranked.sort_by(|a, b| b.1.total_cmp(&a.1).then(a.0.total_cmp(&b.0)));
+        ranked
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_murmur_hash() {
+        assert_eq!(murmur_hash3_32(b"", 0), 0);
+        assert_eq!(murmur_hash3_32(b"", 1), 0x514e_28b7);
+        assert_eq!(murmur_hash3_32(b"hello", 0), 0x248b_fa47);
+        assert_eq!(
+            murmur_hash3_32(b"The quick brown fox jumps over the lazy dog", 0),
+            0x2e4f_f723
+        );
+    }
+
+    #[test]
+    fn test_ids() {
+        for name in &["", "sphere.0", "bunny", "matte", "metal/rusty", "caf\u{e9}"] {
+            let id = name_to_id(name);
+            assert!(id.is_normal(), "{} -> {}", name, id);
+        }
+        let mut names = MatteNames::new();
+        let ids = names.add("bunny", "matte");
+        assert_eq!(names.add("bunny", "glass").object, ids.object);
+        assert_ne!(names.add("teapot", "matte").object, ids.object);
+        let manifest = names.manifest(1);
+        assert!(manifest.starts_with("{\"glass\":\""), "{}", manifest);
+        assert!(manifest.contains(&format!("\"matte\":\"{:08x}\"", ids.material.to_bits())));
+        assert_eq!(json_string("caf\u{e9} \"1\""), "\"caf\\u00e9 \\\"1\\\"\"");
+    }
+
+    #[test]
+    fn test_coverage() {
+        let mut coverage = IdCoverage::default();
+        coverage.add(1.0, 0.25);
+        coverage.add(2.0, 0.5);
+        let mut other = IdCoverage::default();
+        other.add(1.0, 0.5);
+        other.add(3.0, 0.25);
+        coverage.merge(&other);
+        assert_eq!(
+            coverage.ranked(2.0),
+            vec![(1.0, 0.375), (2.0, 0.25), (3.0, 0.125)]
+        );
+    }
+}
@@ -1,11 +1,15 @@
+use std::fs;
 use std::path::{Path, PathBuf};
+use std::sync::Arc;
 
+use anyhow::{Context, Result};
 use lazy_static::lazy_static;
 use log::debug;
 use parking_lot::Mutex;
 
 lazy_static! {
     static ref SEARCH_DIR: Mutex<Option<PathBuf>> = Mutex::new(None);
+    static ref OUTPUT_DIR: Mutex<Option<PathBuf>> = Mutex::new(None);
 }
 
 pub fn set_search_directory<P: AsRef<Path>>(d: P) {
@@ -30,6 +34,33 @@ pub fn directory_containing<P: AsRef<Path>>(path: P) -> PathBuf {
         .to_owned()
 }
 
+/// Files a scene depends on (the scene file itself, included files, meshes, textures...),
+/// recorded as they're resolved while the scene is loaded. Clones share the same list, so that
+/// it can be handed to everything that reads files on behalf of the scene.
+#[derive(Debug, Clone, Default)]
+pub struct Dependencies(Arc<Mutex<Vec<PathBuf>>>);
+
+impl Dependencies {
+    /// Resolve `filename` relative to the search directory, and record it if it exists.
+    pub fn resolve(&self, filename: &str) -> String {
+        let resolved = resolve_filename(filename);
+        if let Ok(path) = Path::new(&resolved).canonicalize() {
+            let mut deps = self.0.lock();
+            if !deps.contains(&path) {
+                deps.push(path);
+            }
+        }
+        resolved
+    }
+
+    /// The (canonical) paths of the files recorded so far, in the order they were first
+    /// resolved.
+    pub fn paths(&self) -> Vec<PathBuf> {
+        self.0.lock().clone()
+    }
+}
+
+/// Resolve `filename` relative to the search directory.
 pub fn resolve_filename(filename: &str) -> String {
     debug!("Resolving filename {}", filename);
     let search_directory = SEARCH_DIR.lock();
@@ -46,10 +77,162 @@ pub fn resolve_filename(filename: &str) -> String {
     }
 }
 
+/// Set the directory relative output filenames are resolved against (see `output_filename()`).
+pub fn set_output_directory<P: AsRef<Path>>(d: P) {
+    let d = d.as_ref();
+    *OUTPUT_DIR.lock() = Some(PathBuf::from(d));
+    debug!("Set output directory to {}", d.display());
+}
+
+/// Turn a filename given in a scene file into the path of an output file: `%d`-style patterns
+/// are replaced by the frame number (see `expand_frame_pattern()`), `\` separators are accepted
+/// on every platform, and relative paths are resolved against the output directory if one was
+/// set.
+pub fn output_filename(filename: &str, frame: u32) -> String {
+    let filename = normalize_separators(&expand_frame_pattern(filename, frame));
+    match *OUTPUT_DIR.lock() {
+        Some(ref dir) if !Path::new(&filename).is_absolute() => {
+            dir.join(&filename).to_string_lossy().into_owned()
+        }
+        _ => filename,
+    }
+}
+
+/// Create the directory an output file goes in if it doesn't exist yet.
+pub fn create_parent_directory<P: AsRef<Path>>(path: P) -> Result<()> {
+    let path = path.as_ref();
+    match path.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() && !dir.is_dir() => {
+            debug!("Creating output directory {}", dir.display());
+            fs::create_dir_all(dir)
+                .with_context(|| format!("Failed to create directory {}", dir.display()))
+        }
+        _ => Ok(()),
+    }
+}
+
+/// Replace the printf-style integer patterns (`%d`, `%4d`, `%04d`) in `filename` with `frame`.
+/// `%%` stands for a literal `%`, and any other pattern is left untouched.
+pub fn expand_frame_pattern(filename: &str, frame: u32) -> String {
+    let mut res = String::with_capacity(filename.len());
+    let mut rest = filename;
+    while let Some(i) = rest.find('%') {
+        res.push_str(&rest[..i]);
+        rest = &rest[i + 1..];
+        if let Some(r) = rest.strip_prefix('%') {
+            res.push('%');
+            rest = r;
+            continue;
+        }
+        let width_len = rest
+            .find(|c: char| !c.is_ascii_digit())
+            .unwrap_or(rest.len());
+        if rest[width_len..].starts_with('d') {
+            let width = &rest[..width_len];
+            let n = width.parse().unwrap_or(0);
+            if width.starts_with('0') {
+                res.push_str(&format!("{:0n$}", frame, n = n));
+            } else {
+                res.push_str(&format!("{:n$}", frame, n = n));
+            }
+            rest = &rest[width_len + 1..];
+        } else {
+            res.push('%');
+        }
+    }
+    res.push_str(rest);
+    res
+}
+
+/// Scene files written on Windows may use `\` as a path separator, which other platforms don't
+/// understand.
+fn normalize_separators(filename: &str) -> String {
+    if cfg!(windows) {
+        filename.to_owned()
+    } else {
+        filename.replace('\\', "/")
+    }
+}
+
 pub fn has_extension<P: AsRef<Path>>(filename: P, extension: &str) -> bool {
     filename
         .as_ref()
         .extension()
-        .map(|e| e == extension)
+        .and_then(|e| e.to_str())
+        .map(|e| e.eq_ignore_ascii_case(extension))
         .unwrap_or(false)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{init_stats, pbrt, PbrtOptions};
+
+    #[test]
+    fn test_resolved_files_are_recorded_once() {
+        let file = std::env::temp_dir().join("rustracer_fileutil_dependency.pbrt");
+        fs::write(&file, "WorldBegin\nWorldEnd\n").unwrap();
+        let name = file.to_str().unwrap();
+
+        let deps = Dependencies::default();
+        assert_eq!(deps.resolve(name), name);
+        deps.clone().resolve(name);
+        deps.resolve("this/file/does/not/exist.png");
+        assert_eq!(deps.paths(), vec![file.canonicalize().unwrap()]);
+    }
+
+    #[test]
+    fn test_expand_frame_pattern() {
+        assert_eq!(expand_frame_pattern("image.png", 7), "image.png");
+        assert_eq!(expand_frame_pattern("frame%d.png", 7), "frame7.png");
+        assert_eq!(expand_frame_pattern("frame%04d.exr", 42), "frame0042.exr");
+        assert_eq!(expand_frame_pattern("f%3d.png", 5), "f  5.png");
+        assert_eq!(expand_frame_pattern("100%%_%d.png", 1), "100%_1.png");
+        assert_eq!(expand_frame_pattern("a%s%", 1), "a%s%");
+    }
+
+    #[test]
+    fn test_create_parent_directory() {
+        let dir = std::env::temp_dir().join("rustracer_fileutil_outdir");
+        let _ = fs::remove_dir_all(&dir);
+        let file = dir.join("nested").join("image.png");
+        create_parent_directory(&file).unwrap();
+        assert!(dir.join("nested").is_dir());
+        create_parent_directory(&file).unwrap();
+        create_parent_directory("image.png").unwrap();
+    }
+
+    #[test]
+    fn test_scene_dependencies_are_recorded_while_parsing() {
+        init_stats();
+        let dir = std::env::temp_dir().join("rustracer_scene_dependencies");
+        fs::create_dir_all(&dir).unwrap();
+        let scene = dir.join("scene.pbrt");
+        let include = dir.join("geometry.pbrt");
+        let spd = dir.join("kd.spd");
+        fs::write(&include, "Shape \"sphere\"\n").unwrap();
+        fs::write(&spd, "400 0.5 700 0.5\n").unwrap();
+        fs::write(
+        &scene,
+        "Camera \"perspective\"\nSampler \"02sequence\"\nWorldBegin\nInclude \"geometry.pbrt\"\nMaterial \"plastic\" \"spectrum Kd\" \"kd.spd\" \"spectrum Ks\" \"missing.spd\"\nWorldEnd\n",
+    )
+    .unwrap();
+        let opts = PbrtOptions {
+            num_threads: 1,
+            defer_render: true,
+            ..PbrtOptions::default()
+        };
+
+        let dependencies = Dependencies::default();
+        pbrt::parse_scene_recording(opts, &scene, &dependencies).unwrap();
+        // Missing files aren't recorded
+        assert_eq!(
+            dependencies.paths(),
+            vec![
+                scene.canonicalize().unwrap(),
+                include.canonicalize().unwrap(),
+                spd.canonicalize().unwrap()
+            ]
+        );
+    }
+}
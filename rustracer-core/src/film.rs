@@ -1,5 +1,8 @@
 use std::f32;
+use std::ops::Range;
+use std::path::Path;
 use std::sync::atomic::Ordering;
+use std::time::Instant;
 
 use anyhow::Result;
 use atomic::Atomic;
@@ -7,18 +10,30 @@ use log::{info, warn};
 use parking_lot::Mutex;
 
 use crate::bounds::{Bounds2f, Bounds2i};
+use crate::camera::{Camera, CameraSample};
+use crate::cie::{self, ColourSpace};
+use crate::cryptomatte::{self, IdCoverage, MatteIds, MatteNames, MATTE_TYPES};
+use crate::fileutil;
 use crate::filter::Filter;
-use crate::imageio;
+use crate::imagecompare::{ErrorMetric, ReferenceImage};
+use crate::imageio::{self, ImageMetadata};
+use crate::interaction::SurfaceInteraction;
+use crate::lpe::{self, Lpe};
 use crate::paramset::ParamSet;
-use crate::spectrum::Spectrum;
-use crate::{clamp, Point2f, Point2i, Vector2f};
+use crate::sampledump::SampleDump;
+use crate::spectrum::{blackbody_white_point, Spectrum};
+use crate::{clamp, PbrtOptions, Point2f, Point2i, Vector2f};
 
 const FILTER_SIZE: usize = 16;
 const FILTER_TABLE_SIZE: usize = FILTER_SIZE * FILTER_SIZE;
+/// Number of rows of pixels protected by each of the film's locks (see `Film::merge_tile()`)
+const STRIPE_HEIGHT: usize = 8;
 
 stat_memory_counter!("Memory/Film pixels", film_pixel_memory);
+stat_ratio!("Film/Nanoseconds per merged tile pixel", merge_time);
 pub fn init_stats() {
     film_pixel_memory::init();
+    merge_time::init();
 }
 
 type AtomicU32 = Atomic<u32>;
@@ -34,12 +49,57 @@ impl AtomicFloat {
     }
 }
 
-#[derive(Default)]
-struct Pixel {
-    xyz: [f32; 3],
-    filter_weight_sum: f32,
-    splat_xyz: [AtomicFloat; 3],
-    _pad: f32,
+/// A horizontal stripe of the film's pixels, stored as one plane per channel rather than one
+/// struct per pixel, so that tiles can be merged (and samples added) a row at a time with loops
+/// the compiler can vectorize.
+struct PixelPlanes {
+    xyz: [Vec<f32>; 3],
+    filter_weight_sum: Vec<f32>,
+    /// Number of samples taken in each pixel
+    sample_count: Vec<f32>,
+    splat_xyz: Vec<[AtomicFloat; 3]>,
+    /// One set of planes per AOV: XYZ for the light path expressions, followed by the raw values
+    /// of the geometric AOVs
+    aov_xyz: Vec<[Vec<f32>; 3]>,
+    /// Coverage of each pixel by the object and material IDs, if the film has Cryptomatte mattes
+    mattes: Vec<[IdCoverage; 2]>,
+}
+
+impl PixelPlanes {
+    fn new(n_pixels: usize) -> PixelPlanes {
+        PixelPlanes {
+            xyz: [
+                vec![0.0; n_pixels],
+                vec![0.0; n_pixels],
+                vec![0.0; n_pixels],
+            ],
+            filter_weight_sum: vec![0.0; n_pixels],
+            sample_count: vec![0.0; n_pixels],
+            splat_xyz: (0..n_pixels).map(|_| Default::default()).collect(),
+            aov_xyz: Vec::new(),
+            mattes: Vec::new(),
+        }
+    }
+
+    fn bytes_per_pixel() -> usize {
+        5 * size_of::<f32>() + size_of::<[AtomicFloat; 3]>()
+    }
+
+    fn clear(&mut self) {
+        for plane in self
+            .xyz
+            .iter_mut()
+            .chain(Some(&mut self.filter_weight_sum))
+            .chain(Some(&mut self.sample_count))
+            .chain(self.aov_xyz.iter_mut().flatten())
+        {
+            plane.iter_mut().for_each(|v| *v = 0.0);
+        }
+        self.splat_xyz
+            .iter_mut()
+            .for_each(|s| *s = Default::default());
+        self.mattes.iter_mut().flatten().for_each(IdCoverage::clear);
+    }
 }
 
 pub struct Film {
@@ -47,11 +107,153 @@ pub struct Film {
     pub _diagonal: f32,
     pub filename: String,
     pub cropped_pixel_bounds: Bounds2i,
-    pixels: Mutex<Vec<Pixel>>,
+    /// The pixels, split in stripes of `STRIPE_HEIGHT` rows that each have their own lock
+    stripes: Vec<Mutex<PixelPlanes>>,
     filter_table: [f32; FILTER_TABLE_SIZE],
     filter_radius: Vector2f,
     scale: f32,
     max_sample_luminance: f32,
+    /// Linear RGB colour space the images are written in
+    colour_space: ColourSpace,
+    /// XYZ white point of the scene's illumination, which appears as the white of the output
+    /// colour space
+    scene_white: [f32; 3],
+    /// Chromatic adaptation from `scene_white` to the white of `colour_space`, applied to the
+    /// pixels' XYZ values before writing the image
+    white_balance: Option<[[f32; 3]; 3]>,
+    /// Whether to dither the image when writing it to an 8-bit format
+    dither: bool,
+    negative_lobes: NegativeLobes,
+    sample_placement: SamplePlacement,
+    /// Additional, resized copies of the image written alongside the main one
+    secondary_outputs: Vec<SecondaryOutput>,
+    /// Whether to write the number of samples taken in each pixel alongside the image (see
+    /// `sample_count_filename()`)
+    write_sample_count: bool,
+    /// Light path expressions of the AOVs, and the images they are written to
+    aovs: Vec<Lpe>,
+    aov_filenames: Vec<String>,
+    /// Geometric AOVs, and the images they are written to
+    geometry_aovs: Vec<GeometryAov>,
+    geometry_aov_filenames: Vec<String>,
+    /// Output filenames from before the first call to `set_frame_number()`, which the numbered
+    /// filenames are derived from
+    unnumbered_filenames: Option<Box<OutputFilenames>>,
+    /// Image the render is compared against, in which case the errors are written instead of the
+    /// render (see the `imagecompare` module)
+    reference: Option<ReferenceImage>,
+    /// Whether the left and right halves of the film are the views of a stereo camera, to be
+    /// written to separate images (see `camera::StereoCamera`)
+    split_views: bool,
+    /// Number of IDs written per pixel to the Cryptomatte mattes, or 0 if there are none
+    cryptomatte_depth: usize,
+    /// Names behind the IDs of the mattes
+    matte_names: MatteNames,
+    /// Samples of the pixels being recorded, written alongside the image
+    sample_dump: Option<SampleDump>,
+}
+
+/// How to handle the negative lobes of filters like Mitchell-Netravali, which can produce negative
+/// pixel values and ringing around very bright samples (`"string negativelobes"`).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum NegativeLobes {
+    /// Use the filter as is (the default)
+    Keep,
+    /// Ignore the negative contributions of samples to pixels
+    Clamp,
+    /// Weight the samples with the filter but normalize the pixels by the sum of the absolute
+    /// values of the weights, so that the normalization factor can't get close to 0
+    Absolute,
+}
+
+impl NegativeLobes {
+    /// Weights used for a sample's contribution and for the normalization of the pixel given the
+    /// value of the filter.
+    fn weights(self, f: f32) -> (f32, f32) {
+        match self {
+            NegativeLobes::Keep => (f, f),
+            NegativeLobes::Clamp => (f.max(0.0), f.max(0.0)),
+            NegativeLobes::Absolute => (f, f.abs()),
+        }
+    }
+}
+
+/// Where the camera samples are placed within their pixels, e.g. to render matching stereo pairs
+/// or to compare with rasterized references.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct SamplePlacement {
+    /// Whether to jitter the samples over their pixel (`"bool jitter"`, true by default), or to
+    /// put them all at its centre
+    pub jitter: bool,
+    /// Constant offset in pixels of the camera rays from the positions of their samples
+    /// (`"float pixeloffset" [x y]`), which shifts the image by that amount
+    pub offset: Vector2f,
+}
+
+impl Default for SamplePlacement {
+    fn default() -> Self {
+        SamplePlacement {
+            jitter: true,
+            offset: Vector2f::new(0.0, 0.0),
+        }
+    }
+}
+
+impl SamplePlacement {
+    /// Place the camera sample `s` of pixel `p_pixel`, whose position was picked by the sampler,
+    /// and offset it. Returns the position the sample counts towards on the film.
+    pub fn place(&self, p_pixel: Point2i, s: &mut CameraSample) -> Point2f {
+        if !self.jitter {
+            s.p_film = Point2f::from(p_pixel) + Vector2f::new(0.5, 0.5);
+        }
+        let p_film = s.p_film;
+        s.p_film += self.offset;
+        p_film
+    }
+}
+
+/// Data of the primary hits written to an AOV instead of light (`"position"`, `"objectnormal"` or
+/// `"motion"` in `"aovexpressions"`), e.g. for external relighting or denoising. The values are
+/// averaged over the pixels with the film's filter, but neither scaled nor converted to the
+/// output colour space. Samples that don't hit anything count as 0.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum GeometryAov {
+    /// World space position
+    Position,
+    /// Shading normal in the object space of the hit shape (see `SurfaceInteraction::n_object`)
+    ObjectNormal,
+    /// Motion vector in pixels from where the hit point is in the image to where it was in the
+    /// previous frame, as `(x, y, 0)`, for temporal denoising and reprojection. It accounts for
+    /// the motion of the camera (see `Camera::end_frame()`) and of the primitives (see
+    /// `Scene::end_frame()`), and is 0 for cameras that don't support it.
+    Motion,
+}
+
+impl GeometryAov {
+    /// Value of the AOV at the primary hit `si`, as an RGB triple.
+    pub fn value<C: Camera + ?Sized>(self, si: &SurfaceInteraction, camera: &C) -> Spectrum {
+        match self {
+            GeometryAov::Position => Spectrum::rgb(si.hit.p.x, si.hit.p.y, si.hit.p.z),
+            GeometryAov::ObjectNormal => Spectrum::rgb(si.n_object.x, si.n_object.y, si.n_object.z),
+            GeometryAov::Motion => match (
+                camera.world_to_raster(&si.hit.p, false),
+                camera.world_to_raster(&si.p_previous, true),
+            ) {
+                (Some(p), Some(p_previous)) => {
+                    Spectrum::rgb(p_previous.x - p.x, p_previous.y - p.y, 0.0)
+                }
+                _ => Spectrum::black(),
+            },
+        }
+    }
+}
+
+/// A resized copy of the image written in addition to the main output, e.g. a thumbnail.
+#[derive(Debug, Clone)]
+struct SecondaryOutput {
+    filename: String,
+    /// Scale of the output's resolution relative to the main image
+    scale: f32,
 }
 
 impl Film {
@@ -79,15 +281,17 @@ impl Film {
             "Created film with full resolution {}. Crop window of {} -> cropped_pixel_bounds {}",
             resolution, cropwindow, cropped_pixel_bounds
         );
-        // let mut pixels = Vec::with_capacity(cropped_pixel_bounds.area() as usize);
-        // pixels.resize_default(cropped_pixel_bounds.area() as usize);
-
-        let pixels = (0..cropped_pixel_bounds.area())
-            .map(|_| Pixel::default())
-            .collect::<Vec<_>>();
-
+        let width = (cropped_pixel_bounds.p_max.x - cropped_pixel_bounds.p_min.x).max(0) as usize;
+        let height = (cropped_pixel_bounds.p_max.y - cropped_pixel_bounds.p_min.y).max(0) as usize;
+        let stripes = (0..height)
+            .step_by(STRIPE_HEIGHT)
+            .map(|y| {
+                let rows = usize::min(STRIPE_HEIGHT, height - y);
+                Mutex::new(PixelPlanes::new(rows * width))
+            })
+            .collect();
         film_pixel_memory::add(
-            cropped_pixel_bounds.area() as u64 * ::std::mem::size_of::<Pixel>() as u64,
+            cropped_pixel_bounds.area() as u64 * PixelPlanes::bytes_per_pixel() as u64,
         );
         let mut filter_table = [0f32; FILTER_TABLE_SIZE];
 
@@ -103,7 +307,7 @@ impl Film {
 
         Film {
             full_resolution: resolution,
-            pixels: Mutex::new(pixels),
+            stripes,
             filter_table,
             filter_radius: Vector2f::new(xwidth, ywidth),
             cropped_pixel_bounds,
@@ -111,18 +315,160 @@ impl Film {
             _diagonal: diagonal * 0.001,
             filename: filename.to_owned(),
             max_sample_luminance,
+            colour_space: ColourSpace::Srgb,
+            scene_white: cie::D65_WHITE_XYZ,
+            white_balance: None,
+            dither: false,
+            negative_lobes: NegativeLobes::Keep,
+            sample_placement: SamplePlacement::default(),
+            secondary_outputs: Vec::new(),
+            write_sample_count: false,
+            aovs: Vec::new(),
+            aov_filenames: Vec::new(),
+            geometry_aovs: Vec::new(),
+            geometry_aov_filenames: Vec::new(),
+            unnumbered_filenames: None,
+            reference: None,
+            split_views: false,
+            cryptomatte_depth: 0,
+            matte_names: MatteNames::new(),
+            sample_dump: None,
         }
     }
 
-    pub fn create(ps: &ParamSet, filter: &dyn Filter) -> Box<Film> {
-        let mut filename = ps.find_one_string("filename", "".into());
-        if filename.is_empty() {
-            filename = "image.png".into();
-        } else {
-            filename = String::from("rt-") + &filename;
+    /// Accumulate the radiance of the paths matching each of the given light path expressions in
+    /// a separate image (AOV), written to the given file alongside the main image.
+    pub fn set_aovs(&mut self, aovs: Vec<(String, Lpe)>) {
+        assert!(aovs.len() <= lpe::MAX_EXPRESSIONS);
+        let (filenames, expressions) = aovs.into_iter().unzip();
+        self.aov_filenames = filenames;
+        self.aovs = expressions;
+        self.allocate_aov_planes();
+    }
+
+    /// Record data of the primary hits in separate images (AOVs), written to the given files
+    /// alongside the main image.
+    pub fn set_geometry_aovs(&mut self, aovs: Vec<(String, GeometryAov)>) {
+        let (filenames, aovs) = aovs.into_iter().unzip();
+        self.geometry_aov_filenames = filenames;
+        self.geometry_aovs = aovs;
+        self.allocate_aov_planes();
+    }
+
+    fn allocate_aov_planes(&mut self) {
+        let n_aovs = self.aovs.len() + self.geometry_aovs.len();
+        let mut previous = 0;
+        for stripe in &mut self.stripes {
+            let pixels = stripe.get_mut();
+            let n_pixels = pixels.filter_weight_sum.len();
+            previous = pixels.aov_xyz.len();
+            pixels.aov_xyz = (0..n_aovs)
+                .map(|_| {
+                    [
+                        vec![0.0; n_pixels],
+                        vec![0.0; n_pixels],
+                        vec![0.0; n_pixels],
+                    ]
+                })
+                .collect();
+        }
+        if n_aovs > previous {
+            film_pixel_memory::add(
+                self.cropped_pixel_bounds.area() as u64
+                    * ((n_aovs - previous) * 3 * size_of::<f32>()) as u64,
+            );
+        }
+    }
+
+    /// Accumulate the coverage of the pixels by the object and material IDs of the primary hits,
+    /// and write the `depth` IDs covering the most of each pixel to Cryptomatte mattes (see the
+    /// `cryptomatte` module and `cryptomatte_filename()`). The mattes cover the whole film, even
+    /// if its views are split.
+    pub fn set_cryptomatte(&mut self, depth: usize) {
+        self.cryptomatte_depth = depth;
+        for stripe in &mut self.stripes {
+            let pixels = stripe.get_mut();
+            let n_pixels = pixels.filter_weight_sum.len();
+            pixels.mattes = if depth > 0 {
+                vec![Default::default(); n_pixels]
+            } else {
+                Vec::new()
+            };
         }
-        let xres = ps.find_one_int("xresolution", 1280);
-        let yres = ps.find_one_int("yresolution", 720);
+    }
+
+    pub fn has_cryptomatte(&self) -> bool {
+        self.cryptomatte_depth > 0
+    }
+
+    /// Set the names of the objects and materials of the scene, for the manifests of the mattes.
+    pub fn set_matte_names(&mut self, names: MatteNames) {
+        self.matte_names = names;
+    }
+
+    pub fn sample_dump(&self) -> Option<&SampleDump> {
+        self.sample_dump.as_ref()
+    }
+
+    pub fn aovs(&self) -> &[Lpe] {
+        &self.aovs
+    }
+
+    pub fn geometry_aovs(&self) -> &[GeometryAov] {
+        &self.geometry_aovs
+    }
+
+    /// Write the left and right halves of the image, and of its AOVs, to separate images named
+    /// `<output>_L.<ext>` and `<output>_R.<ext>` instead of a single one.
+    pub fn set_split_views(&mut self, split_views: bool) {
+        self.split_views = split_views;
+    }
+
+    pub fn sample_placement(&self) -> SamplePlacement {
+        self.sample_placement
+    }
+
+    pub fn set_sample_placement(&mut self, placement: SamplePlacement) {
+        self.sample_placement = placement;
+    }
+
+    /// White balance the image so that a blackbody emitter at the given temperature (in Kelvin)
+    /// appears neutral (i.e. maps to the white of the output colour space).
+    pub fn set_white_balance(&mut self, temperature: f32) {
+        self.scene_white = blackbody_white_point(temperature);
+        self.update_white_balance();
+    }
+
+    /// Write the images in the given linear RGB colour space rather than sRGB.
+    pub fn set_colour_space(&mut self, colour_space: ColourSpace) {
+        self.colour_space = colour_space;
+        self.update_white_balance();
+    }
+
+    pub fn colour_space(&self) -> ColourSpace {
+        self.colour_space
+    }
+
+    fn update_white_balance(&mut self) {
+        let dst_white = self.colour_space.white();
+        self.white_balance = if self.scene_white == dst_white {
+            None
+        } else {
+            Some(cie::bradford_adaptation(&self.scene_white, &dst_white))
+        };
+    }
+
+    pub fn create(ps: &ParamSet, filter: &dyn Filter, opts: &PbrtOptions) -> Result<Box<Film>> {
+        let filename = ps.find_one_string("filename", "".into());
+        let filename = if filename.is_empty() {
+            fileutil::output_filename("image.png", opts.frame)
+        } else {
+            output_filename(&filename, opts.frame)
+        };
+        // Fail now rather than after rendering if the image can't be written there
+        fileutil::create_parent_directory(&filename)?;
+        let xres = opts.film_resolution(ps.find_one_int("xresolution", 1280));
+        let yres = opts.film_resolution(ps.find_one_int("yresolution", 720));
         let mut crop = Bounds2f::from_points(&Point2f::new(0.0, 0.0), &Point2f::new(1.0, 1.0));
         if let Some(cr) = ps.find_float("cropwindow") {
             if cr.len() == 4 {
@@ -134,11 +480,42 @@ impl Film {
                 warn!("\"cropwindow\" expected 4 values");
             }
         }
-        let scale = ps.find_one_float("scale", 1.0);
+        let mut scale = ps.find_one_float("scale", 1.0);
+        // Optional physical camera exposure
+        let exposure_compensation = ps.find_one_float("exposurecompensation", 0.0);
+        scale *= 2f32.powf(exposure_compensation);
+        let (iso, shutter_speed, f_number) = (
+            ps.find_float("iso"),
+            ps.find_float("shutterspeed"),
+            ps.find_float("fnumber"),
+        );
+        if iso.is_some() || shutter_speed.is_some() || f_number.is_some() {
+            let first =
+                |v: Option<Vec<f32>>, d: f32| v.and_then(|v| v.first().cloned()).unwrap_or(d);
+            let (iso, shutter_speed, f_number) = (
+                first(iso, 100.0),
+                first(shutter_speed, 1.0),
+                first(f_number, 1.0),
+            );
+            if iso <= 0.0 || shutter_speed <= 0.0 || f_number <= 0.0 {
+                warn!(
+                    "Ignoring invalid exposure settings: iso={}, shutterspeed={}, fnumber={}",
+                    iso, shutter_speed, f_number
+                );
+            } else {
+                let exposure = physical_exposure_scale(iso, shutter_speed, f_number);
+                info!(
+                    "Film exposure: iso={}, shutterspeed={}s, fnumber=f/{} -> scale {}",
+                    iso, shutter_speed, f_number, exposure
+                );
+                scale *= exposure;
+            }
+        }
         let diagonal = ps.find_one_float("diagonal", 35.0);
         let max_sample_luminance = ps.find_one_float("maxsampleluminance", f32::INFINITY);
+        let whitepoint = ps.find_one_float("whitepoint", 0.0);
         // TODO max_sample_luminance
-        Box::new(Film::new(
+        let mut film = Box::new(Film::new(
             Point2i::new(xres, yres),
             crop,
             filter,
@@ -146,7 +523,57 @@ impl Film {
             &filename,
             scale,
             max_sample_luminance,
-        ))
+        ));
+        if whitepoint > 0.0 {
+            info!("White balancing film for a {}K white point", whitepoint);
+            film.set_white_balance(whitepoint);
+        } else if whitepoint < 0.0 {
+            warn!("Ignoring invalid \"whitepoint\" {}", whitepoint);
+        }
+        if let Some(colour_space) = colour_space(ps) {
+            if colour_space != ColourSpace::Srgb && imageio::is_srgb_format(&film.filename) {
+                warn!(
+                    "Writing {} with {} primaries: 8-bit formats are expected to be sRGB. Use an EXR to get linear {} values.",
+                    film.filename,
+                    colour_space.name(),
+                    colour_space.name()
+                );
+            }
+            film.set_colour_space(colour_space);
+        }
+        film.dither = ps.find_one_bool("dither", false);
+        film.negative_lobes = negative_lobes(ps);
+        film.sample_placement = sample_placement(ps);
+        film.secondary_outputs = secondary_outputs(ps, opts.frame);
+        for output in &film.secondary_outputs {
+            fileutil::create_parent_directory(&output.filename)?;
+        }
+        // The number of samples per pixel isn't known in advance with a time budget
+        film.write_sample_count = opts.time_budget.is_some();
+        film.sample_dump = opts
+            .dump_samples
+            .as_ref()
+            .map(SampleDump::new)
+            .transpose()?;
+        let (aovs, geometry_aovs) = aovs(ps, &film.filename);
+        for filename in aovs
+            .iter()
+            .map(|(f, _)| f)
+            .chain(geometry_aovs.iter().map(|(f, _)| f))
+        {
+            fileutil::create_parent_directory(filename)?;
+        }
+        film.set_aovs(aovs);
+        film.set_geometry_aovs(geometry_aovs);
+        if ps.find_one_bool("cryptomatte", false) {
+            let depth = ps.find_one_int("cryptomattedepth", 6);
+            if depth < 1 {
+                warn!("Invalid \"cryptomattedepth\" {}. Using 6 instead.", depth);
+            }
+            film.set_cryptomatte(if depth < 1 { 6 } else { depth as usize });
+        }
+        film.reference = reference_image(ps, &film)?;
+        Ok(film)
     }
 
     pub fn get_film_tile(&self, sample_bounds: &Bounds2i) -> FilmTile {
@@ -166,59 +593,167 @@ impl Film {
             &float_cropped_pixel_bounds,
         ));
 
-        FilmTile::new(
+        let mut tile = FilmTile::new(
             &tile_pixel_bounds,
             self.filter_radius,
             &self.filter_table,
+            self.negative_lobes,
             self.max_sample_luminance,
-        )
+        );
+        let n_pixels = tile.filter_weight_sum.len();
+        tile.colour_aovs = self.aovs.len();
+        tile.aov_sum = (0..self.aovs.len() + self.geometry_aovs.len())
+            .map(|_| {
+                [
+                    vec![0.0; n_pixels],
+                    vec![0.0; n_pixels],
+                    vec![0.0; n_pixels],
+                ]
+            })
+            .collect();
+        if self.has_cryptomatte() {
+            tile.mattes = vec![Default::default(); n_pixels];
+        }
+        tile
     }
 
-    pub fn merge_film_tile(&self, tile: &FilmTile) {
-        let mut pixels = self.pixels.lock();
-        for pixel in &tile.get_pixel_bounds() {
-            let tile_pixel = tile.get_pixel(pixel);
-            let pidx = {
-                let width = self.cropped_pixel_bounds.p_max.x - self.cropped_pixel_bounds.p_min.x;
-                ((pixel.y - self.cropped_pixel_bounds.p_min.y) * width
-                    + (pixel.x - self.cropped_pixel_bounds.p_min.x)) as usize
-            };
-            let xyz = tile_pixel.contrib_sum.to_xyz();
-            pixels[pidx]
-                .xyz
-                .iter_mut()
-                .zip(&xyz)
-                .for_each(|(a, b)| *a += b);
-            pixels[pidx].filter_weight_sum += tile_pixel.filter_weight_sum;
+    /// Reset all the pixels to black, e.g. to render the scene again.
+    pub fn clear(&self) {
+        for stripe in &self.stripes {
+            stripe.lock().clear();
+        }
+        if let Some(ref dump) = self.sample_dump {
+            dump.clear();
         }
     }
 
-    pub fn write_image(&self) -> Result<()> {
+    /// Number the images written from now on with `frame`, e.g. to render the frames of an
+    /// animation with the same film: each output `<output>.<ext>` of the scene is written to
+    /// `<output>_<frame>.<ext>` instead, with the frame number padded to 4 digits.
+    pub fn set_frame_number(&mut self, frame: u32) {
+        let unnumbered = self.unnumbered_filenames.get_or_insert_with(|| {
+            Box::new(OutputFilenames {
+                image: self.filename.clone(),
+                secondary_outputs: self
+                    .secondary_outputs
+                    .iter()
+                    .map(|o| o.filename.clone())
+                    .collect(),
+                aovs: self.aov_filenames.clone(),
+                geometry_aovs: self.geometry_aov_filenames.clone(),
+            })
+        });
+        let number = |filename: &String| suffixed_filename(filename, &format!("{:04}", frame));
+        self.filename = number(&unnumbered.image);
+        for (output, filename) in self
+            .secondary_outputs
+            .iter_mut()
+            .zip(&unnumbered.secondary_outputs)
+        {
+            output.filename = number(filename);
+        }
+        self.aov_filenames = unnumbered.aovs.iter().map(number).collect();
+        self.geometry_aov_filenames = unnumbered.geometry_aovs.iter().map(number).collect();
+    }
+
+    /// Add the contribution of a tile to the film. This can be called concurrently from several
+    /// threads.
+    ///
+    /// Tiles returned by `get_film_tile()` include a margin of the filter's radius around the
+    /// pixels they sample, so neighbouring tiles overlap and can be merged at the same time. The
+    /// rows of the film are protected by striped locks: a tile locks the stripes it covers one at
+    /// a time, so no update is lost, and tiles in different stripes are merged in parallel. The
+    /// order in which tiles are merged depends on the thread scheduling though, so the pixels in
+    /// the overlaps can differ between runs by floating point rounding errors.
+    pub fn merge_tile(&self, tile: &FilmTile) {
+        let start = Instant::now();
+        let bounds = tile.get_pixel_bounds();
+        let tile_width = (bounds.p_max.x - bounds.p_min.x).max(0) as usize;
+        // Do the colour conversion before taking the lock
+        let xyz = rgb_to_xyz_planes(&tile.contrib_sum);
+        let aov_xyz: Vec<_> = tile
+            .aov_sum
+            .iter()
+            .enumerate()
+            .map(|(i, planes)| {
+                if i < tile.colour_aovs {
+                    rgb_to_xyz_planes(planes)
+                } else {
+                    planes.clone()
+                }
+            })
+            .collect();
+        let n_pixels = tile.filter_weight_sum.len();
+
+        let mut y = bounds.p_min.y;
+        while y < bounds.p_max.y {
+            let (stripe, _) = self.get_pixel_idx(Point2i::new(bounds.p_min.x, y));
+            let mut pixels = self.stripes[stripe].lock();
+            let pixels = &mut *pixels;
+            loop {
+                let src = (y - bounds.p_min.y) as usize * tile_width;
+                let src = src..src + tile_width;
+                let (_, dst) = self.get_pixel_idx(Point2i::new(bounds.p_min.x, y));
+                let dst = dst..dst + tile_width;
+                for (plane, tile_plane) in pixels
+                    .xyz
+                    .iter_mut()
+                    .chain(pixels.aov_xyz.iter_mut().flatten())
+                    .zip(xyz.iter().chain(aov_xyz.iter().flatten()))
+                {
+                    add_assign(&mut plane[dst.clone()], &tile_plane[src.clone()]);
+                }
+                add_assign(
+                    &mut pixels.filter_weight_sum[dst.clone()],
+                    &tile.filter_weight_sum[src.clone()],
+                );
+                if !tile.mattes.is_empty() {
+                    for (pixel, tile_pixel) in pixels.mattes[dst.clone()]
+                        .iter_mut()
+                        .zip(&tile.mattes[src.clone()])
+                    {
+                        pixel[0].merge(&tile_pixel[0]);
+                        pixel[1].merge(&tile_pixel[1]);
+                    }
+                }
+                add_assign(&mut pixels.sample_count[dst], &tile.sample_count[src]);
+                y += 1;
+                if y == bounds.p_max.y || self.stripe_of_row(y) != stripe {
+                    break;
+                }
+            }
+        }
+        merge_time::add(start.elapsed().as_nanos() as u64);
+        merge_time::add_total(n_pixels as u64);
+    }
+
+    /// Resolution of the final image, i.e. of the cropped pixel bounds.
+    pub fn image_resolution(&self) -> Point2i {
+        let resolution = self.cropped_pixel_bounds.diagonal();
+        Point2i::new(resolution.x, resolution.y)
+    }
+
+    /// Compute the final, weighted and scaled, linear RGB values of the pixels in scanline
+    /// order.
+    pub fn rgb(&self) -> Vec<f32> {
         info!("Converting image to RGB and computing final weighted pixel values");
         let splat_scale = 1.0; // TODO
-        let pixels = self.pixels.lock();
+        let stripes: Vec<_> = self.stripes.iter().map(|s| s.lock()).collect();
         let mut rgb = Vec::with_capacity(3 * self.cropped_pixel_bounds.area() as usize);
         for p in &self.cropped_pixel_bounds {
             // Convert pixel XYZ color to RGB
-            let pixel_idx = self.get_pixel_idx(p);
-            let pixel = &pixels[pixel_idx];
-            let mut rgb_pixel = Spectrum::from_xyz(&pixel.xyz);
-
-            // Normalize pixel with weight sum
-            let filter_weight_sum = pixel.filter_weight_sum;
-            if filter_weight_sum != 0.0 {
-                let inv_wt = 1.0 / filter_weight_sum;
-                rgb_pixel[0] = f32::max(0.0, rgb_pixel[0] * inv_wt);
-                rgb_pixel[1] = f32::max(0.0, rgb_pixel[1] * inv_wt);
-                rgb_pixel[2] = f32::max(0.0, rgb_pixel[2] * inv_wt);
-            }
+            let (stripe, pixel_idx) = self.get_pixel_idx(p);
+            let pixels = &stripes[stripe];
+            let mut rgb_pixel =
+                self.normalized_rgb(&pixels.xyz, pixel_idx, pixels.filter_weight_sum[pixel_idx]);
 
+            let splat = &pixels.splat_xyz[pixel_idx];
             let splat_xyz = [
-                pixel.splat_xyz[0].as_float(),
-                pixel.splat_xyz[1].as_float(),
-                pixel.splat_xyz[2].as_float(),
+                splat[0].as_float(),
+                splat[1].as_float(),
+                splat[2].as_float(),
             ];
-            let splat_rgb = Spectrum::from_xyz(&splat_xyz);
+            let splat_rgb = self.xyz_to_rgb(&splat_xyz);
             rgb_pixel[0] += splat_scale * splat_rgb[0];
             rgb_pixel[1] += splat_scale * splat_rgb[1];
             rgb_pixel[2] += splat_scale * splat_rgb[2];
@@ -232,20 +767,260 @@ impl Film {
             rgb.push(rgb_pixel[1]);
             rgb.push(rgb_pixel[2]);
         }
+        rgb
+    }
+
+    /// Final linear RGB values of the pixels of the given AOV in scanline order, weighted and
+    /// scaled like those of the main image. The AOVs of the light path expressions come first,
+    /// followed by the geometric AOVs, whose values are only weighted.
+    pub fn aov_rgb(&self, aov: usize) -> Vec<f32> {
+        let stripes: Vec<_> = self.stripes.iter().map(|s| s.lock()).collect();
+        let mut rgb = Vec::with_capacity(3 * self.cropped_pixel_bounds.area() as usize);
+        for p in &self.cropped_pixel_bounds {
+            let (stripe, pixel_idx) = self.get_pixel_idx(p);
+            let pixels = &stripes[stripe];
+            let planes = &pixels.aov_xyz[aov];
+            let weight_sum = pixels.filter_weight_sum[pixel_idx];
+            if aov < self.aovs.len() {
+                let rgb_pixel = self.normalized_rgb(planes, pixel_idx, weight_sum) * self.scale;
+                rgb.push(rgb_pixel[0]);
+                rgb.push(rgb_pixel[1]);
+                rgb.push(rgb_pixel[2]);
+            } else {
+                let inv_wt = if weight_sum != 0.0 {
+                    1.0 / weight_sum
+                } else {
+                    0.0
+                };
+                rgb.extend(planes.iter().map(|plane| plane[pixel_idx] * inv_wt));
+            }
+        }
+        rgb
+    }
+
+    /// Convert the XYZ value of a pixel to RGB and normalize it with its filter weight sum.
+    fn normalized_rgb(
+        &self,
+        planes: &[Vec<f32>; 3],
+        pixel_idx: usize,
+        filter_weight_sum: f32,
+    ) -> Spectrum {
+        let xyz = [
+            planes[0][pixel_idx],
+            planes[1][pixel_idx],
+            planes[2][pixel_idx],
+        ];
+        let mut rgb_pixel = self.xyz_to_rgb(&xyz);
+
+        // Normalize pixel with weight sum
+        if filter_weight_sum != 0.0 {
+            let inv_wt = 1.0 / filter_weight_sum;
+            rgb_pixel[0] = f32::max(0.0, rgb_pixel[0] * inv_wt);
+            rgb_pixel[1] = f32::max(0.0, rgb_pixel[1] * inv_wt);
+            rgb_pixel[2] = f32::max(0.0, rgb_pixel[2] * inv_wt);
+        }
+        rgb_pixel
+    }
+
+    /// Number of samples taken in each pixel, in scanline order. Samples count towards the pixel
+    /// they are in, regardless of the other pixels the filter spreads them over.
+    pub fn sample_counts(&self) -> Vec<f32> {
+        let stripes: Vec<_> = self.stripes.iter().map(|s| s.lock()).collect();
+        self.cropped_pixel_bounds
+            .into_iter()
+            .map(|p| {
+                let (stripe, pixel_idx) = self.get_pixel_idx(p);
+                stripes[stripe].sample_count[pixel_idx]
+            })
+            .collect()
+    }
+
+    pub fn write_image(&self) -> Result<()> {
+        let rgb = match self.reference {
+            Some(ref reference) => reference.compare(&self.rgb()),
+            None => self.rgb(),
+        };
 
         // Write RGB image
         info!(
             "Writing image {} with bounds {}",
             self.filename, self.cropped_pixel_bounds
         );
-        imageio::write_image(
-            &self.filename,
-            &rgb[..],
-            &self.cropped_pixel_bounds,
-            self.full_resolution,
+        let resolution = self.image_resolution();
+        self.write_views(&self.filename, &rgb)?;
+
+        for output in &self.secondary_outputs {
+            let new_resolution = Point2i::new(
+                i32::max(1, (resolution.x as f32 * output.scale).round() as i32),
+                i32::max(1, (resolution.y as f32 * output.scale).round() as i32),
+            );
+            info!(
+                "Writing secondary output {} at resolution {}",
+                output.filename, new_resolution
+            );
+            let resized = imageio::resize(&rgb, resolution, new_resolution);
+            imageio::write_image(
+                &output.filename,
+                &resized,
+                new_resolution,
+                &ImageMetadata {
+                    dither: self.dither,
+                    colour_space: Some(self.colour_space),
+                    ..ImageMetadata::default()
+                },
+            )?;
+        }
+
+        for (i, filename) in self.aov_filenames.iter().enumerate() {
+            info!(
+                "Writing AOV {} for light path expression \"{}\"",
+                filename,
+                self.aovs[i].source()
+            );
+            self.write_views(filename, &self.aov_rgb(i))?;
+        }
+        for (i, filename) in self.geometry_aov_filenames.iter().enumerate() {
+            info!("Writing AOV {} for {:?}", filename, self.geometry_aovs[i]);
+            self.write_views(filename, &self.aov_rgb(self.aovs.len() + i))?;
+        }
+
+        if self.has_cryptomatte() {
+            self.write_cryptomatte()?;
+        }
+
+        if let Some(ref dump) = self.sample_dump {
+            dump.write()?;
+        }
+
+        if self.write_sample_count {
+            let filename = sample_count_filename(&self.filename);
+            info!("Writing sample counts {}", filename);
+            let rgb: Vec<f32> = self
+                .sample_counts()
+                .into_iter()
+                .flat_map(|n| [n, n, n])
+                .collect();
+            imageio::write_image(
+                &filename,
+                &rgb,
+                resolution,
+                &ImageMetadata {
+                    pixel_bounds: Some(self.cropped_pixel_bounds),
+                    full_resolution: Some(self.full_resolution),
+                    ..ImageMetadata::default()
+                },
+            )?;
+        }
+
+        Ok(())
+    }
+
+    /// Write the Cryptomatte mattes: for each matte type, one RGBA layer per 2 ranks of IDs, along
+    /// with the manifests.
+    fn write_cryptomatte(&self) -> Result<()> {
+        let filename = cryptomatte_filename(&self.filename);
+        info!("Writing Cryptomatte mattes {}", filename);
+        let stripes: Vec<_> = self.stripes.iter().map(|s| s.lock()).collect();
+        let n_pixels = self.cropped_pixel_bounds.area() as usize;
+        let n_layers = self.cryptomatte_depth.div_ceil(2);
+        let mut channels = Vec::new();
+        let mut attributes = Vec::new();
+        for (matte_type, type_name) in MATTE_TYPES.iter().enumerate() {
+            // 4 channels per layer: (id, coverage) for 2 consecutive ranks
+            let mut values = vec![vec![0.0; n_pixels]; 4 * n_layers];
+            for (i, p) in self.cropped_pixel_bounds.into_iter().enumerate() {
+                let (stripe, pixel_idx) = self.get_pixel_idx(p);
+                let pixels = &stripes[stripe];
+                let ranked = pixels.mattes[pixel_idx][matte_type]
+                    .ranked(pixels.filter_weight_sum[pixel_idx]);
+                for (rank, (id, coverage)) in
+                    ranked.into_iter().take(self.cryptomatte_depth).enumerate()
+                {
+                    values[2 * rank][i] = id;
+                    values[2 * rank + 1][i] = coverage;
+                }
+            }
+            for (c, values) in values.into_iter().enumerate() {
+                let channel = ["R", "G", "B", "A"][c % 4];
+                channels.push((format!("{}{:02}.{}", type_name, c / 4, channel), values));
+            }
+            attributes.extend(cryptomatte::metadata(matte_type, &self.matte_names));
+        }
+        imageio::write_exr_channels(
+            &filename,
+            &channels,
+            &attributes,
+            self.image_resolution(),
+            &ImageMetadata {
+                pixel_bounds: Some(self.cropped_pixel_bounds),
+                full_resolution: Some(self.full_resolution),
+                ..ImageMetadata::default()
+            },
         )
     }
 
+    /// Write the RGB values of the cropped pixels to `filename`, or to one image per view if the
+    /// views are split.
+    fn write_views(&self, filename: &str, rgb: &[f32]) -> Result<()> {
+        let metadata = ImageMetadata {
+            pixel_bounds: Some(self.cropped_pixel_bounds),
+            full_resolution: Some(self.full_resolution),
+            dither: self.dither,
+            colour_space: Some(self.colour_space),
+        };
+        if !self.split_views {
+            return imageio::write_image(filename, rgb, self.image_resolution(), &metadata);
+        }
+
+        let width =
+            (self.cropped_pixel_bounds.p_max.x - self.cropped_pixel_bounds.p_min.x) as usize;
+        let view_width = self.full_resolution.x / 2;
+        for (i, view) in ["L", "R"].iter().enumerate() {
+            let x_offset = i as i32 * view_width;
+            let view_bounds = Bounds2i::from_points(
+                &Point2i::new(x_offset, 0),
+                &Point2i::new(x_offset + view_width, self.full_resolution.y),
+            );
+            let bounds = Bounds2i::intersect(&self.cropped_pixel_bounds, &view_bounds);
+            if bounds.p_min.x >= bounds.p_max.x || bounds.p_min.y >= bounds.p_max.y {
+                continue;
+            }
+            let columns = (bounds.p_min.x - self.cropped_pixel_bounds.p_min.x) as usize
+                ..(bounds.p_max.x - self.cropped_pixel_bounds.p_min.x) as usize;
+            let view_rgb: Vec<f32> = rgb
+                .chunks(3 * width)
+                .flat_map(|row| &row[3 * columns.start..3 * columns.end])
+                .copied()
+                .collect();
+            let filename = suffixed_filename(filename, view);
+            info!("Writing view {}", filename);
+            imageio::write_image(
+                &filename,
+                &view_rgb,
+                Point2i::new(columns.len() as i32, bounds.p_max.y - bounds.p_min.y),
+                &ImageMetadata {
+                    pixel_bounds: Some(Bounds2i::from_points(
+                        &Point2i::new(bounds.p_min.x - x_offset, bounds.p_min.y),
+                        &Point2i::new(bounds.p_max.x - x_offset, bounds.p_max.y),
+                    )),
+                    full_resolution: Some(Point2i::new(view_width, self.full_resolution.y)),
+                    ..metadata.clone()
+                },
+            )?;
+        }
+        Ok(())
+    }
+
+    /// Convert an XYZ value to linear RGB in the output colour space, white balancing it.
+    fn xyz_to_rgb(&self, xyz: &[f32; 3]) -> Spectrum {
+        let xyz = match self.white_balance {
+            Some(ref m) => cie::adapt_xyz(m, xyz),
+            None => *xyz,
+        };
+        let rgb = self.colour_space.from_xyz(&xyz);
+        Spectrum::rgb(rgb[0], rgb[1], rgb[2])
+    }
+
     pub fn get_sample_bounds(&self) -> Bounds2i {
         let half = Vector2f::new(0.5, 0.5);
         let float_bounds = Bounds2f::from_points(
@@ -256,21 +1031,55 @@ impl Film {
         float_bounds.into()
     }
 
-    fn get_pixel_idx(&self, p: Point2i) -> usize {
+    /// Index of the stripe containing the given pixel, and of the pixel within the stripe.
+    fn get_pixel_idx(&self, p: Point2i) -> (usize, usize) {
         assert!(self.cropped_pixel_bounds.inside_exclusive(&p));
-        let width = self.cropped_pixel_bounds.p_max.x - self.cropped_pixel_bounds.p_min.x;
-        let offset = (p.x - self.cropped_pixel_bounds.p_min.x)
-            + (p.y - self.cropped_pixel_bounds.p_min.y) * width;
-        offset as usize
+        let width =
+            (self.cropped_pixel_bounds.p_max.x - self.cropped_pixel_bounds.p_min.x) as usize;
+        let row = (p.y - self.cropped_pixel_bounds.p_min.y) as usize;
+        let offset =
+            (p.x - self.cropped_pixel_bounds.p_min.x) as usize + (row % STRIPE_HEIGHT) * width;
+        (row / STRIPE_HEIGHT, offset)
+    }
+
+    fn stripe_of_row(&self, y: i32) -> usize {
+        (y - self.cropped_pixel_bounds.p_min.y) as usize / STRIPE_HEIGHT
     }
 }
 
+/// Scale factor mapping scene radiance (in nits) to pixel values for a camera with the given
+/// exposure settings, using the saturation-based sensitivity model: a pixel value of 1 is reached
+/// for a luminance of `1.2 * 2^EV100`, where `EV100 = log2(N^2 / t) - log2(ISO / 100)`.
+///
+/// * `iso`: sensor sensitivity
+/// * `shutter_speed`: exposure time in seconds
+/// * `f_number`: relative aperture of the lens
+pub fn physical_exposure_scale(iso: f32, shutter_speed: f32, f_number: f32) -> f32 {
+    let ev100 = (f_number * f_number / shutter_speed).log2() - (iso / 100.0).log2();
+    1.0 / (1.2 * 2f32.powf(ev100))
+}
+
 pub struct FilmTile {
     pixel_bounds: Bounds2i,
     filter_radius: Vector2f,
     inv_filter_radius: Vector2f,
     filter_table: Box<[f32]>,
-    pub pixels: Vec<FilmTilePixel>,
+    /// Filter values used for the sum of the weights, which can differ from `filter_table`
+    /// depending on how negative lobes are handled
+    weight_table: Box<[f32]>,
+    /// Weighted sum of the RGB samples, one plane per channel
+    contrib_sum: [Vec<f32>; 3],
+    filter_weight_sum: Vec<f32>,
+    /// Number of samples in each pixel
+    sample_count: Vec<f32>,
+    /// Weighted sum of the AOV values of the samples, one set of RGB planes per AOV
+    aov_sum: Vec<[Vec<f32>; 3]>,
+    /// Number of AOVs of light path expressions, which come before the geometric AOVs in
+    /// `aov_sum`
+    colour_aovs: usize,
+    /// Weighted coverage of each pixel by the object and material IDs, if the film has
+    /// Cryptomatte mattes
+    mattes: Vec<[IdCoverage; 2]>,
     max_sample_luminance: f32,
 }
 
@@ -279,10 +1088,11 @@ impl FilmTile {
         pixel_bounds: &Bounds2i,
         filter_radius: Vector2f,
         filter: &[f32],
+        negative_lobes: NegativeLobes,
         max_sample_luminance: f32,
     ) -> FilmTile {
-        let mut filter_table = Vec::new();
-        filter_table.extend_from_slice(filter);
+        let (filter_table, weight_table): (Vec<f32>, Vec<f32>) =
+            filter.iter().map(|&f| negative_lobes.weights(f)).unzip();
         FilmTile {
             pixel_bounds: *pixel_bounds,
             filter_radius,
@@ -290,21 +1100,90 @@ impl FilmTile {
             // Duplicating the filter table in every table is wasteful, but keeping a reference to
             // the data from Film leads to all kind of lifetime issues...
             filter_table: filter_table.into_boxed_slice(),
-            pixels: vec![FilmTilePixel::default(); pixel_bounds.area() as usize],
+            weight_table: weight_table.into_boxed_slice(),
+            contrib_sum: [
+                vec![0.0; pixel_bounds.area() as usize],
+                vec![0.0; pixel_bounds.area() as usize],
+                vec![0.0; pixel_bounds.area() as usize],
+            ],
+            filter_weight_sum: vec![0.0; pixel_bounds.area() as usize],
+            sample_count: vec![0.0; pixel_bounds.area() as usize],
+            aov_sum: Vec::new(),
+            colour_aovs: 0,
+            mattes: Vec::new(),
             max_sample_luminance,
         }
     }
 
     pub fn add_sample(&mut self, p_film: Point2f, colour: Spectrum) {
+        self.add_sample_aovs(p_film, colour, &[]);
+    }
+
+    /// Add a sample along with its values for the AOVs of the film (see `Film::aov_rgb()` for
+    /// their order). AOVs missing from `aovs` are black.
+    pub fn add_sample_aovs(&mut self, p_film: Point2f, colour: Spectrum, aovs: &[Spectrum]) {
         if colour.has_nan() {
             warn!("colour has NaNs! Ignoring");
             return;
         }
-        let L = if colour.y() > self.max_sample_luminance {
-            colour * self.max_sample_luminance / colour.y()
+        // The AOVs are scaled like the colour, so that they still add up to it
+        let luminance_scale = if colour.y() > self.max_sample_luminance {
+            self.max_sample_luminance / colour.y()
         } else {
-            colour
+            1.0
         };
+        let L = colour * luminance_scale;
+        let pixel = Point2i::new(p_film.x.floor() as i32, p_film.y.floor() as i32);
+        if self.pixel_bounds.inside_exclusive(&pixel) {
+            let idx = self.get_pixel_index(pixel);
+            self.sample_count[idx] += 1.0;
+        }
+        let (p0, p1, ifx, ify) = self.footprint(p_film);
+
+        // Add this sample's contribution to all the affected pixels, a row at a time
+        let width = (p1.x - p0.x) as usize;
+        for y in p0.y..p1.y {
+            let filter_row = &self.filter_table[ify[(y - p0.y) as usize] * FILTER_SIZE..];
+            let weight_row = &self.weight_table[ify[(y - p0.y) as usize] * FILTER_SIZE..];
+            let start = self.get_pixel_index(Point2i::new(p0.x, y));
+            let row = start..start + width;
+            add_weighted_row(&mut self.contrib_sum, row.clone(), &L, filter_row, &ifx);
+            for (i, (planes, aov)) in self.aov_sum.iter_mut().zip(aovs).enumerate() {
+                let aov = if i < self.colour_aovs {
+                    *aov * luminance_scale
+                } else {
+                    *aov
+                };
+                add_weighted_row(planes, row.clone(), &aov, filter_row, &ifx);
+            }
+            for (v, &fx) in self.filter_weight_sum[row].iter_mut().zip(&ifx) {
+                *v += weight_row[fx];
+            }
+        }
+    }
+
+    /// Add the coverage of a sample to the Cryptomatte mattes, given the IDs of its primary hit
+    /// (`None` if it didn't hit anything, in which case it only counts in the filter weights).
+    pub fn add_matte_sample(&mut self, p_film: Point2f, ids: Option<MatteIds>) {
+        let ids = match ids {
+            Some(ids) if !self.mattes.is_empty() => ids,
+            _ => return,
+        };
+        let (p0, p1, ifx, ify) = self.footprint(p_film);
+        let width = (p1.x - p0.x) as usize;
+        for y in p0.y..p1.y {
+            let filter_row = &self.filter_table[ify[(y - p0.y) as usize] * FILTER_SIZE..];
+            let start = self.get_pixel_index(Point2i::new(p0.x, y));
+            for (pixel, &fx) in self.mattes[start..start + width].iter_mut().zip(&ifx) {
+                pixel[0].add(ids.object, filter_row[fx]);
+                pixel[1].add(ids.material, filter_row[fx]);
+            }
+        }
+    }
+
+    /// The pixels affected by a sample at `p_film`, from `p0` (inclusive) to `p1` (exclusive),
+    /// along with the column and row of the filter table for each of their columns and rows.
+    fn footprint(&self, p_film: Point2f) -> (Point2i, Point2i, Vec<usize>, Vec<usize>) {
         let float_pixel_bounds: Bounds2f = self.pixel_bounds.into();
         // Convert to discrete pixel space
         let p_film_discrete = p_film - Vector2f::new(0.5, 0.5);
@@ -346,22 +1225,20 @@ impl FilmTile {
                     .abs();
             ify.push(fy.floor().min(filter_table_size - 1.0) as usize);
         }
-
-        // Add this sample's contribution to all the affected pixels
-        for y in p0.y..p1.y {
-            for x in p0.x..p1.x {
-                let offset = ify[(y - p0.y) as usize] * FILTER_SIZE + ifx[(x - p0.x) as usize];
-                let filter_weight = &self.filter_table[offset];
-                let idx = self.get_pixel_index(Point2i::new(x, y));
-                let pixel = &mut self.pixels[idx];
-                pixel.contrib_sum += L * *filter_weight;
-                pixel.filter_weight_sum += *filter_weight;
-            }
-        }
+        (p0, p1, ifx, ify)
     }
 
-    pub fn get_pixel(&self, p: Point2i) -> &FilmTilePixel {
-        &self.pixels[self.get_pixel_index(p)]
+    /// Weighted sum of the samples added to the given pixel, and the sum of their weights.
+    pub fn get_pixel(&self, p: Point2i) -> (Spectrum, f32) {
+        let idx = self.get_pixel_index(p);
+        (
+            Spectrum::rgb(
+                self.contrib_sum[0][idx],
+                self.contrib_sum[1][idx],
+                self.contrib_sum[2][idx],
+            ),
+            self.filter_weight_sum[idx],
+        )
     }
 
     pub fn get_pixel_bounds(&self) -> Bounds2i {
@@ -375,10 +1252,254 @@ impl FilmTile {
     }
 }
 
-#[derive(Clone, Default)]
-pub struct FilmTilePixel {
-    contrib_sum: Spectrum,
-    filter_weight_sum: f32,
+/// Add `colour` weighted by the filter to a row of RGB planes.
+fn add_weighted_row(
+    planes: &mut [Vec<f32>; 3],
+    row: Range<usize>,
+    colour: &Spectrum,
+    filter_row: &[f32],
+    ifx: &[usize],
+) {
+    for (plane, c) in planes.iter_mut().zip(&[colour.r, colour.g, colour.b]) {
+        for (v, &fx) in plane[row.clone()].iter_mut().zip(ifx) {
+            *v += c * filter_row[fx];
+        }
+    }
+}
+
+/// Convert RGB planes to XYZ planes.
+fn rgb_to_xyz_planes(rgb: &[Vec<f32>; 3]) -> [Vec<f32>; 3] {
+    let [r, g, b] = rgb;
+    let mut xyz = [
+        Vec::with_capacity(r.len()),
+        Vec::with_capacity(r.len()),
+        Vec::with_capacity(r.len()),
+    ];
+    for i in 0..r.len() {
+        let c = Spectrum::rgb(r[i], g[i], b[i]).to_xyz();
+        xyz[0].push(c[0]);
+        xyz[1].push(c[1]);
+        xyz[2].push(c[2]);
+    }
+    xyz
+}
+
+/// `dst[i] += src[i]` for each element. This is simple enough for the compiler to turn into SIMD
+/// additions.
+fn add_assign(dst: &mut [f32], src: &[f32]) {
+    assert_eq!(dst.len(), src.len());
+    for (d, s) in dst.iter_mut().zip(src) {
+        *d += s;
+    }
+}
+
+fn negative_lobes(ps: &ParamSet) -> NegativeLobes {
+    let mode = ps.find_one_string("negativelobes", "keep".into());
+    match mode.as_str() {
+        "keep" => NegativeLobes::Keep,
+        "clamp" => NegativeLobes::Clamp,
+        "abs" => NegativeLobes::Absolute,
+        _ => {
+            warn!(
+                "Unknown \"negativelobes\" mode \"{}\". Using \"keep\".",
+                mode
+            );
+            NegativeLobes::Keep
+        }
+    }
+}
+
+/// Parse the film's `"string colorspace"` parameter, if it is given.
+fn colour_space(ps: &ParamSet) -> Option<ColourSpace> {
+    let name = ps.find_one_string("colorspace", "".into());
+    if name.is_empty() {
+        return None;
+    }
+    let colour_space = ColourSpace::from_name(&name);
+    if colour_space.is_none() {
+        warn!("Unknown \"colorspace\" \"{}\". Using \"srgb\".", name);
+    }
+    colour_space
+}
+
+fn sample_placement(ps: &ParamSet) -> SamplePlacement {
+    let mut placement = SamplePlacement {
+        jitter: ps.find_one_bool("jitter", true),
+        ..SamplePlacement::default()
+    };
+    if let Some(offset) = ps.find_float("pixeloffset") {
+        if offset.len() == 2 {
+            placement.offset = Vector2f::new(offset[0], offset[1]);
+        } else {
+            warn!("\"pixeloffset\" expected 2 values");
+        }
+    }
+    placement
+}
+
+/// Parse the film's `"string outputs"` and `"float outputscales"` parameters, which list the
+/// secondary outputs and the scale of each of them relative to the main image.
+fn secondary_outputs(ps: &ParamSet, frame: u32) -> Vec<SecondaryOutput> {
+    let filenames = ps.find_string("outputs").unwrap_or_default();
+    let scales = ps.find_float("outputscales").unwrap_or_default();
+    if !filenames.is_empty() && scales.len() != filenames.len() {
+        warn!(
+            "Expected {} values for \"outputscales\" but got {}. Missing scales default to 1.",
+            filenames.len(),
+            scales.len()
+        );
+    }
+
+    filenames
+        .into_iter()
+        .enumerate()
+        .filter_map(|(i, filename)| {
+            let scale = scales.get(i).cloned().unwrap_or(1.0);
+            if scale > 0.0 {
+                Some(SecondaryOutput {
+                    // Same naming rule as the main output
+                    filename: output_filename(&filename, frame),
+                    scale,
+                })
+            } else {
+                warn!("Ignoring output {} with invalid scale {}", filename, scale);
+                None
+            }
+        })
+        .collect()
+}
+
+/// Load the image given by the film's `"string reference"` parameter, if any, to compare the
+/// render against it with the metric given by `"string errormetric"` (`"signed"` or
+/// `"relative"`).
+fn reference_image(ps: &ParamSet, film: &Film) -> Result<Option<ReferenceImage>> {
+    let path = ps.find_one_filename("reference", "".into());
+    if path.is_empty() {
+        return Ok(None);
+    }
+    let name = ps.find_one_string("errormetric", "signed".into());
+    let metric = ErrorMetric::from_name(&name).unwrap_or_else(|| {
+        warn!(
+            "Unknown \"errormetric\" {}. Using \"signed\" instead.",
+            name
+        );
+        ErrorMetric::Signed
+    });
+    if metric == ErrorMetric::Signed && imageio::is_srgb_format(&film.filename) {
+        warn!(
+            "Writing signed errors to {}: the negative errors will be clamped. Use an EXR to keep them.",
+            film.filename
+        );
+    }
+    info!("Comparing the render against the reference image {}", path);
+    ReferenceImage::load(&path, film.image_resolution(), metric).map(Some)
+}
+
+/// The images a film writes, other than those derived from the main image at write time (like
+/// the Cryptomatte mattes or the sample counts).
+#[derive(Debug, Clone)]
+struct OutputFilenames {
+    image: String,
+    secondary_outputs: Vec<String>,
+    aovs: Vec<String>,
+    geometry_aovs: Vec<String>,
+}
+
+/// AOVs along with the images they are written to
+type AovFiles<T> = Vec<(String, T)>;
+
+/// Parse the film's `"string aovs"` and `"string aovexpressions"` parameters, which list the names
+/// of the AOVs and their light path expressions (see the `lpe` module), or `"position"`,
+/// `"objectnormal"` and `"motion"` for the geometric AOVs. Each AOV is written to `<output>_<name>.<ext>` next
+/// to the main image `filename`.
+fn aovs(ps: &ParamSet, filename: &str) -> (AovFiles<Lpe>, AovFiles<GeometryAov>) {
+    let names = ps.find_string("aovs").unwrap_or_default();
+    let expressions = ps.find_string("aovexpressions").unwrap_or_default();
+    if names.len() != expressions.len() {
+        warn!(
+            "Expected {} values for \"aovexpressions\" but got {}. Ignoring the AOVs without one.",
+            names.len(),
+            expressions.len()
+        );
+    }
+
+    let mut aovs = Vec::new();
+    let mut geometry_aovs = Vec::new();
+    for (name, expression) in names.iter().zip(expressions) {
+        let geometry_aov = match expression.as_str() {
+            "position" => Some(GeometryAov::Position),
+            "objectnormal" => Some(GeometryAov::ObjectNormal),
+            "motion" => Some(GeometryAov::Motion),
+            _ => None,
+        };
+        if let Some(aov) = geometry_aov {
+            geometry_aovs.push((suffixed_filename(filename, name), aov));
+            continue;
+        }
+        if aovs.len() == lpe::MAX_EXPRESSIONS {
+            warn!(
+                "Only {} light path expression AOVs are supported. Ignoring {}.",
+                lpe::MAX_EXPRESSIONS,
+                name
+            );
+            continue;
+        }
+        match Lpe::parse(&expression) {
+            Ok(lpe) => aovs.push((suffixed_filename(filename, name), lpe)),
+            Err(e) => warn!("Ignoring AOV {}: {:#}", name, e),
+        }
+    }
+    (aovs, geometry_aovs)
+}
+
+/// Name of the image for the AOV or view `name` of the given output image:
+/// `<output>_<name>.<ext>`.
+fn suffixed_filename(filename: &str, name: &str) -> String {
+    let path = Path::new(filename);
+    let stem = path
+        .file_stem()
+        .map(|s| s.to_string_lossy().into_owned())
+        .unwrap_or_default();
+    let name = match path.extension() {
+        Some(ext) => format!("{}_{}.{}", stem, name, ext.to_string_lossy()),
+        None => format!("{}_{}", stem, name),
+    };
+    path.with_file_name(name).to_string_lossy().into_owned()
+}
+
+/// Name of the image with the Cryptomatte mattes for the given output image:
+/// `<output>_cryptomatte.exr`.
+pub fn cryptomatte_filename(filename: &str) -> String {
+    let path = Path::new(filename);
+    let stem = path
+        .file_stem()
+        .map(|s| s.to_string_lossy().into_owned())
+        .unwrap_or_default();
+    path.with_file_name(format!("{}_cryptomatte.exr", stem))
+        .to_string_lossy()
+        .into_owned()
+}
+
+/// Name of the image recording the number of samples per pixel for the given output image:
+/// `<output>_spp.exr`. It is always an EXR so that the counts aren't clamped or quantized.
+pub fn sample_count_filename(filename: &str) -> String {
+    let path = Path::new(filename);
+    let stem = path
+        .file_stem()
+        .map(|s| s.to_string_lossy().into_owned())
+        .unwrap_or_default();
+    path.with_file_name(format!("{}_spp.exr", stem))
+        .to_string_lossy()
+        .into_owned()
+}
+
+/// Path of the image written for the output file `filename` of the scene: the file name gets an
+/// `rt-` prefix (to tell it apart from pbrt's renders), and is then resolved as described in
+/// `fileutil::output_filename()`.
+fn output_filename(filename: &str, frame: u32) -> String {
+    let name_start = filename.rfind(['/', '\\']).map_or(0, |i| i + 1);
+    let prefixed = format!("{}rt-{}", &filename[..name_start], &filename[name_start..]);
+    fileutil::output_filename(&prefixed, frame)
 }
 
 fn ceil(p: Point2f) -> Point2f {
@@ -388,3 +1509,374 @@ fn ceil(p: Point2f) -> Point2f {
 fn floor(p: Point2f) -> Point2f {
     Point2f::new(p.x.floor(), p.y.floor())
 }
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::AtomicUsize;
+
+    use super::*;
+    use crate::api::{Array, ParamListEntry, ParamType};
+    use crate::filter::{BoxFilter, GaussianFilter};
+    use crate::testutil::{point_lit_scene, render_scene, threads};
+    use crate::{pbrt, PbrtOptions};
+
+    #[test]
+    fn test_physical_exposure_scale() {
+        // Sunny 16 rule: f/16, ISO 100, 1/100s
+        let scale = physical_exposure_scale(100.0, 0.01, 16.0);
+        assert!((scale * 1.2 * 25600.0 - 1.0).abs() < 1e-4);
+        // One stop more light for each doubling of ISO or exposure time
+        assert!((physical_exposure_scale(200.0, 0.01, 16.0) / scale - 2.0).abs() < 1e-4);
+        assert!((physical_exposure_scale(100.0, 0.02, 16.0) / scale - 2.0).abs() < 1e-4);
+        // ... and two stops less when doubling the f-number
+        assert!((physical_exposure_scale(100.0, 0.01, 32.0) / scale - 0.25).abs() < 1e-4);
+    }
+
+    #[test]
+    fn test_merge_tiles() {
+        let film = Film::new(
+            Point2i::new(8, 6),
+            Bounds2f::from_points(&Point2f::new(0.0, 0.0), &Point2f::new(1.0, 1.0)),
+            &BoxFilter::new(0.5, 0.5),
+            35.0,
+            "unused.png",
+            1.0,
+            f32::INFINITY,
+        );
+        let left = Bounds2i::from_points(&Point2i::new(0, 0), &Point2i::new(4, 6));
+        let right = Bounds2i::from_points(&Point2i::new(4, 0), &Point2i::new(8, 6));
+        let mut left_tile = film.get_film_tile(&left);
+        let mut right_tile = film.get_film_tile(&right);
+        // With a box filter of radius 0.5, a sample only contributes to its own pixel
+        left_tile.add_sample(Point2f::new(2.5, 3.5), Spectrum::rgb(1.0, 0.0, 0.0));
+        left_tile.add_sample(Point2f::new(2.5, 3.5), Spectrum::rgb(0.0, 0.0, 1.0));
+        right_tile.add_sample(Point2f::new(5.5, 0.5), Spectrum::rgb(0.0, 2.0, 0.0));
+        assert_eq!(
+            left_tile.get_pixel(Point2i::new(2, 3)),
+            (Spectrum::rgb(1.0, 0.0, 1.0), 2.0)
+        );
+        film.merge_tile(&left_tile);
+        film.merge_tile(&right_tile);
+
+        let rgb = film.rgb();
+        let pixel = |x: usize, y: usize| &rgb[3 * (y * 8 + x)..3 * (y * 8 + x) + 3];
+        let expected = [
+            ((2, 3), [0.5, 0.0, 0.5]),
+            ((5, 0), [0.0, 2.0, 0.0]),
+            ((0, 0), [0.0, 0.0, 0.0]),
+        ];
+        for ((x, y), colour) in &expected {
+            for (v, e) in pixel(*x, *y).iter().zip(colour) {
+                assert!(
+                    (v - e).abs() < 1e-4,
+                    "pixel ({}, {}): {:?}",
+                    x,
+                    y,
+                    pixel(*x, *y)
+                );
+            }
+        }
+
+        film.clear();
+        assert!(film.rgb().iter().all(|v| *v == 0.0));
+    }
+
+    #[test]
+    fn test_sample_counts() {
+        // A wide filter spreads the samples over several pixels, but not their count
+        let film = Film::new(
+            Point2i::new(8, 6),
+            Bounds2f::from_points(&Point2f::new(0.0, 0.0), &Point2f::new(1.0, 1.0)),
+            &GaussianFilter::new(Vector2f::new(2.0, 2.0), 0.5),
+            35.0,
+            "unused.png",
+            1.0,
+            f32::INFINITY,
+        );
+        let left = Bounds2i::from_points(&Point2i::new(0, 0), &Point2i::new(4, 6));
+        let right = Bounds2i::from_points(&Point2i::new(4, 0), &Point2i::new(8, 6));
+        let mut left_tile = film.get_film_tile(&left);
+        let mut right_tile = film.get_film_tile(&right);
+        for _ in 0..3 {
+            left_tile.add_sample(Point2f::new(3.9, 2.5), Spectrum::grey(1.0));
+        }
+        right_tile.add_sample(Point2f::new(4.1, 2.5), Spectrum::grey(1.0));
+        film.merge_tile(&left_tile);
+        film.merge_tile(&right_tile);
+
+        let counts = film.sample_counts();
+        assert_eq!(counts.len(), 48);
+        assert_eq!(counts[2 * 8 + 3], 3.0);
+        assert_eq!(counts[2 * 8 + 4], 1.0);
+        assert_eq!(counts.iter().sum::<f32>(), 4.0);
+
+        film.clear();
+        assert!(film.sample_counts().iter().all(|n| *n == 0.0));
+        assert_eq!(sample_count_filename("out/image.png"), "out/image_spp.exr");
+    }
+
+    #[test]
+    fn test_colour_space() {
+        let mut film = Film::new(
+            Point2i::new(1, 1),
+            Bounds2f::from_points(&Point2f::new(0.0, 0.0), &Point2f::new(1.0, 1.0)),
+            &BoxFilter::new(0.5, 0.5),
+            35.0,
+            "unused.exr",
+            1.0,
+            f32::INFINITY,
+        );
+        film.set_colour_space(ColourSpace::AcesCg);
+        assert_eq!(film.colour_space(), ColourSpace::AcesCg);
+        let bounds = Bounds2i::from_points(&Point2i::new(0, 0), &Point2i::new(1, 1));
+        let mut tile = film.get_film_tile(&bounds);
+        tile.add_sample(Point2f::new(0.5, 0.5), Spectrum::rgb(0.5, 0.5, 0.5));
+        film.merge_tile(&tile);
+        // White stays white in the ACES white point
+        for c in film.rgb() {
+            assert!((c - 0.5).abs() < 1e-3, "{}", c);
+        }
+
+        film.clear();
+        let mut tile = film.get_film_tile(&bounds);
+        tile.add_sample(Point2f::new(0.5, 0.5), Spectrum::rgb(1.0, 0.0, 0.0));
+        film.merge_tile(&tile);
+        // sRGB red is less saturated in ACEScg's wider gamut
+        let rgb = film.rgb();
+        assert!(rgb[0] < 1.0 && rgb[1] > 0.0 && rgb[2] > 0.0, "{:?}", rgb);
+    }
+
+    #[test]
+    fn test_concurrent_merge_of_overlapping_tiles() {
+        // A wide filter, so that each tile overlaps many of its neighbours
+        let film = Film::new(
+            Point2i::new(64, 48),
+            Bounds2f::from_points(&Point2f::new(0.0, 0.0), &Point2f::new(1.0, 1.0)),
+            &GaussianFilter::new(Vector2f::new(4.0, 4.0), 0.5),
+            35.0,
+            "unused.png",
+            1.0,
+            f32::INFINITY,
+        );
+        let sample_bounds = film.get_sample_bounds();
+        let tiles: Vec<_> = (sample_bounds.p_min.y..sample_bounds.p_max.y)
+            .step_by(5)
+            .flat_map(|y| {
+                (sample_bounds.p_min.x..sample_bounds.p_max.x)
+                    .step_by(7)
+                    .map(move |x| {
+                        Bounds2i::from_points(
+                            &Point2i::new(x, y),
+                            &Point2i::new(
+                                i32::min(x + 7, sample_bounds.p_max.x),
+                                i32::min(y + 5, sample_bounds.p_max.y),
+                            ),
+                        )
+                    })
+            })
+            .collect();
+        let film_tile = |bounds: &Bounds2i| {
+            let mut tile = film.get_film_tile(bounds);
+            for p in bounds {
+                let c = ((p.x * 31 + p.y * 17).rem_euclid(11)) as f32;
+                tile.add_sample(
+                    Point2f::from(p) + Vector2f::new(0.5, 0.5),
+                    Spectrum::rgb(c, 1.0, 0.5 * c),
+                );
+            }
+            tile
+        };
+
+        for tile in &tiles {
+            film.merge_tile(&film_tile(tile));
+        }
+        let expected = film.rgb();
+
+        for _ in 0..4 {
+            film.clear();
+            let next_tile = AtomicUsize::new(0);
+            crossbeam::scope(|scope| {
+                for _ in 0..16 {
+                    scope.spawn(|_| {
+                        while let Some(tile) = tiles.get(next_tile.fetch_add(1, Ordering::Relaxed))
+                        {
+                            film.merge_tile(&film_tile(tile));
+                        }
+                    });
+                }
+            })
+            .unwrap();
+            for (i, (v, e)) in film.rgb().iter().zip(&expected).enumerate() {
+                assert!(
+                    (v - e).abs() <= 1e-4 * f32::max(1.0, e.abs()),
+                    "value {}: {} != {}",
+                    i,
+                    v,
+                    e
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn test_secondary_outputs() {
+        let mut ps = ParamSet::default();
+        ps.init(vec![
+            ParamListEntry::new(
+                ParamType::String,
+                "outputs".to_owned(),
+                Array::StrArray(vec!["a.png".to_owned(), "b.exr".to_owned()]),
+            ),
+            ParamListEntry::new(
+                ParamType::Float,
+                "outputscales".to_owned(),
+                Array::NumArray(vec![0.25, -1.0]),
+            ),
+        ]);
+        let outputs = secondary_outputs(&ps, 0);
+        assert_eq!(outputs.len(), 1);
+        assert_eq!(outputs[0].filename, "rt-a.png");
+        assert_eq!(outputs[0].scale, 0.25);
+    }
+
+    #[test]
+    fn test_frame_number() {
+        let mut film = Film::new(
+            Point2i::new(8, 6),
+            Bounds2f::from_points(&Point2f::new(0.0, 0.0), &Point2f::new(1.0, 1.0)),
+            &GaussianFilter::new(Vector2f::new(2.0, 2.0), 0.5),
+            35.0,
+            "out/rt-image.png",
+            1.0,
+            f32::INFINITY,
+        );
+        film.geometry_aov_filenames = vec!["out/rt-image_P.exr".to_owned()];
+        film.set_frame_number(3);
+        film.set_frame_number(12);
+        assert_eq!(film.filename, "out/rt-image_0012.png");
+        assert_eq!(film.geometry_aov_filenames, vec!["out/rt-image_P_0012.exr"]);
+        assert_eq!(
+            cryptomatte_filename(&film.filename),
+            "out/rt-image_0012_cryptomatte.exr"
+        );
+    }
+
+    #[test]
+    fn test_output_filename() {
+        assert_eq!(output_filename("scene.exr", 0), "rt-scene.exr");
+        assert_eq!(output_filename("out/scene.png", 0), "out/rt-scene.png");
+        assert_eq!(output_filename("f%03d.png", 12), "rt-f012.png");
+        if !cfg!(windows) {
+            assert_eq!(output_filename("out\\scene.png", 0), "out/rt-scene.png");
+        }
+    }
+
+    #[test]
+    fn test_negative_lobes() {
+        // Positive filter up to a distance of 1 pixel along each axis, negative beyond
+        let mut table = [-0.5; FILTER_TABLE_SIZE];
+        for y in 0..FILTER_SIZE / 2 {
+            for x in 0..FILTER_SIZE / 2 {
+                table[y * FILTER_SIZE + x] = 1.0;
+            }
+        }
+        let bounds = Bounds2i::from_points(&Point2i::new(0, 0), &Point2i::new(5, 5));
+        let pixels = |mode| {
+            let mut tile = FilmTile::new(
+                &bounds,
+                Vector2f::new(2.0, 2.0),
+                &table,
+                mode,
+                f32::INFINITY,
+            );
+            tile.add_sample(Point2f::new(2.5, 2.5), Spectrum::from(1.0));
+            (
+                tile.get_pixel(Point2i::new(2, 2)),
+                tile.get_pixel(Point2i::new(3, 2)),
+            )
+        };
+
+        let (centre, lobe) = pixels(NegativeLobes::Keep);
+        assert_eq!(centre, (Spectrum::from(1.0), 1.0));
+        assert_eq!(lobe, (Spectrum::from(-0.5), -0.5));
+        let (centre, lobe) = pixels(NegativeLobes::Clamp);
+        assert_eq!(centre, (Spectrum::from(1.0), 1.0));
+        assert_eq!(lobe, (Spectrum::from(0.0), 0.0));
+        let (centre, lobe) = pixels(NegativeLobes::Absolute);
+        assert_eq!(centre, (Spectrum::from(1.0), 1.0));
+        assert_eq!(lobe, (Spectrum::from(-0.5), 0.5));
+    }
+
+    #[test]
+    fn test_samples_can_be_placed_at_pixel_centres() {
+        let render = |film_params, spp| {
+            render_scene(
+                &point_lit_scene("\"perspective\"", film_params, spp),
+                threads(2),
+            )
+        };
+        let close = |a: Spectrum, b: Spectrum| (0..3).all(|c| (a[c] - b[c]).abs() < 1e-5);
+        // Without jitter, all the samples of a pixel see the same thing
+        let one = render("\"bool jitter\" \"false\"", 1);
+        let many = render("\"bool jitter\" \"false\"", 8);
+        for (a, b) in one.iter().zip(&many) {
+            assert!(close(*a, *b), "{} != {}", a, b);
+        }
+        assert_ne!(render("", 8), many);
+
+        // An offset of a whole pixel shifts the image by one pixel
+        let shifted = render("\"bool jitter\" \"false\" \"float pixeloffset\" [1 0]", 1);
+        for y in 0..12 {
+            for x in 0..15 {
+                let (a, b) = (shifted[y * 16 + x], one[y * 16 + x + 1]);
+                assert!(close(a, b), "({}, {}): {} != {}", x, y, a, b);
+            }
+        }
+    }
+
+    #[test]
+    fn test_geometric_aovs_are_in_the_object_space_of_instances() {
+        crate::init_stats();
+        let opts = PbrtOptions {
+            num_threads: 2,
+            defer_render: true,
+            ..PbrtOptions::default()
+        };
+        let scene = r#"
+LookAt 0 0 5  0 0 0  0 1 0
+Camera "perspective" "float fov" [30]
+Film "image" "integer xresolution" [15] "integer yresolution" [11] "bool jitter" "false"
+    "string aovs" ["P" "N"] "string aovexpressions" ["position" "objectnormal"]
+PixelFilter "box"
+Sampler "02sequence" "integer pixelsamples" [1]
+Integrator "directlighting"
+WorldBegin
+ObjectBegin "ball"
+Shape "sphere" "float radius" [1]
+ObjectEnd
+AttributeBegin
+Translate 0 0 1
+Rotate 90 0 1 0
+ObjectInstance "ball"
+AttributeEnd
+WorldEnd
+"#;
+        let mut context = pbrt::parse_scene_string(opts, scene).unwrap().unwrap();
+        context.render_in_memory();
+        let film = context.camera.get_film();
+        let (position, normal) = (film.aov_rgb(0), film.aov_rgb(1));
+        let centre = 3 * (5 * 15 + 7);
+        let assert_close = |v: &[f32], expected: [f32; 3]| {
+            for (a, b) in v.iter().zip(&expected) {
+                assert!((a - b).abs() < 1e-3, "{:?} != {:?}", v, expected);
+            }
+        };
+
+        // The front of the sphere is at z = 2, and faces the object's -x axis
+        assert_close(&position[centre..centre + 3], [0.0, 0.0, 2.0]);
+        assert_close(&normal[centre..centre + 3], [-1.0, 0.0, 0.0]);
+        // Nothing is hit in the corners
+        assert_close(&position[..3], [0.0, 0.0, 0.0]);
+        assert_close(&normal[..3], [0.0, 0.0, 0.0]);
+    }
+}
@@ -151,7 +151,7 @@ impl Default for Matrix4x4 {
     }
 }
 
-impl<'a, 'b> Mul<&'b Matrix4x4> for &'a Matrix4x4 {
+impl<'b> Mul<&'b Matrix4x4> for &Matrix4x4 {
     type Output = Matrix4x4;
 
     fn mul(self, m2: &'b Matrix4x4) -> Matrix4x4 {
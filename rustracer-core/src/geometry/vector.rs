@@ -106,7 +106,7 @@ where
     }
 }
 
-impl<'a, T> Mul<T> for &'a Vector2<T>
+impl<T> Mul<T> for &Vector2<T>
 where
     T: Mul<Output = T> + Copy,
 {
@@ -134,7 +134,7 @@ where
     }
 }
 
-impl<'a, T> MulAssign<T> for &'a mut Vector2<T>
+impl<T> MulAssign<T> for &mut Vector2<T>
 where
     T: MulAssign + Copy,
 {
@@ -409,7 +409,7 @@ where
     }
 }
 
-impl<'a, T> Neg for &'a Vector3<T>
+impl<T> Neg for &Vector3<T>
 where
     T: Neg<Output = T>,
     T: Copy,
@@ -0,0 +1,184 @@
+//! Export of the scene's triangles to a single mesh file, to inspect the scene in a mesh viewer
+//! and debug transforms and instancing (see `PbrtOptions::dump_geometry`).
+//!
+//! Triangles are collected in world space as the shapes are created, with instances expanded,
+//! and written at `WorldEnd` as a binary PLY or an OBJ file depending on the file's extension.
+//! Vertices aren't shared between triangles. Analytic shapes (spheres, cylinders, etc.) and
+//! deferred meshes aren't exported.
+
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::path::Path;
+
+use anyhow::{bail, Context, Result};
+use log::{info, warn};
+
+use crate::fileutil::{self, has_extension};
+use crate::shapes::ShapeRef;
+use crate::{Point3f, Transform};
+
+/// The world space triangles of the scene, collected as it is built.
+#[derive(Debug, Default)]
+pub struct GeometryDump {
+    /// 3 vertices per triangle
+    vertices: Vec<Point3f>,
+    /// Number of shapes that couldn't be exported as triangles
+    skipped: usize,
+}
+
+impl GeometryDump {
+    pub fn new() -> GeometryDump {
+        GeometryDump::default()
+    }
+
+    /// Add the triangles of the given world space shapes, transformed by `transform` (e.g. the
+    /// transform of an instance).
+    pub fn add_shapes(&mut self, shapes: &[ShapeRef], transform: &Transform) {
+        for shape in shapes {
+            match shape.triangle_vertices() {
+                Some(vertices) => self.vertices.extend(vertices.iter().map(|p| transform * p)),
+                None => self.skipped += 1,
+            }
+        }
+    }
+
+    /// Record shapes that aren't exported, like deferred meshes.
+    pub fn skip(&mut self, count: usize) {
+        self.skipped += count;
+    }
+
+    pub fn triangle_count(&self) -> usize {
+        self.vertices.len() / 3
+    }
+
+    /// Write the triangles to a PLY or OBJ file.
+    pub fn write<P: AsRef<Path>>(&self, path: P) -> Result<()> {
+        let path = path.as_ref();
+        if self.skipped > 0 {
+            warn!(
+                "{} shapes aren't triangles and won't be in {}",
+                self.skipped,
+                path.display()
+            );
+        }
+        info!(
+            "Writing {} triangles to {}",
+            self.triangle_count(),
+            path.display()
+        );
+        fileutil::create_parent_directory(path)?;
+        let file = File::create(path).context(format!("Failed to create {}", path.display()))?;
+        let mut writer = BufWriter::new(file);
+        let result = if has_extension(path, "ply") {
+            self.write_ply(&mut writer)
+        } else if has_extension(path, "obj") {
+            self.write_obj(&mut writer)
+        } else {
+            bail!(
+                "Unsupported geometry format for {}: expected a .ply or .obj file",
+                path.display()
+            );
+        };
+        result
+            .and_then(|_| writer.flush())
+            .context(format!("Failed to write {}", path.display()))
+    }
+
+    fn write_ply<W: Write>(&self, w: &mut W) -> std::io::Result<()> {
+        write!(
+            w,
+            "ply\nformat binary_little_endian 1.0\nelement vertex {}\nproperty float x\nproperty float y\nproperty float z\nelement face {}\nproperty list uchar int vertex_indices\nend_header\n",
+            self.vertices.len(),
+            self.triangle_count()
+        )?;
+        for p in &self.vertices {
+            for c in &[p.x, p.y, p.z] {
+                w.write_all(&c.to_le_bytes())?;
+            }
+        }
+        for i in 0..self.triangle_count() as i32 {
+            w.write_all(&[3])?;
+            for v in 3 * i..3 * i + 3 {
+                w.write_all(&v.to_le_bytes())?;
+            }
+        }
+        Ok(())
+    }
+
+    fn write_obj<W: Write>(&self, w: &mut W) -> std::io::Result<()> {
+        for p in &self.vertices {
+            writeln!(w, "v {} {} {}", p.x, p.y, p.z)?;
+        }
+        // OBJ indices start at 1
+        for i in 0..self.triangle_count() {
+            writeln!(w, "f {} {} {}", 3 * i + 1, 3 * i + 2, 3 * i + 3)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{init_stats, pbrt, PbrtOptions, Point3f};
+
+    #[test]
+    fn test_dump_geometry_expands_instances() {
+        init_stats();
+        let dir = std::env::temp_dir().join("rustracer_dump_geometry");
+        let obj = dir.join("scene.obj");
+        let ply = dir.join("scene.ply");
+        let scene = "Camera \"perspective\"
+Sampler \"02sequence\"
+WorldBegin
+Shape \"trianglemesh\" \"integer indices\" [0 1 2] \"point P\" [0 0 0  1 0 0  0 1 0]
+Shape \"sphere\"
+ObjectBegin \"tri\"
+Translate 0 0 5
+Shape \"trianglemesh\" \"integer indices\" [0 1 2] \"point P\" [0 0 0  1 0 0  0 1 0]
+ObjectEnd
+AttributeBegin
+Translate 10 0 0
+ObjectInstance \"tri\"
+AttributeEnd
+AttributeBegin
+Translate 20 0 0
+ObjectInstance \"tri\"
+AttributeEnd
+WorldEnd
+";
+        for path in &[&obj, &ply] {
+            let opts = PbrtOptions {
+                defer_render: true,
+                dump_geometry: Some(path.to_path_buf()),
+                ..PbrtOptions::default()
+            };
+            pbrt::parse_scene_string(opts, scene).unwrap();
+        }
+
+        // The sphere isn't exported, and each instance gets its own copy of the triangle
+        let obj = std::fs::read_to_string(&obj).unwrap();
+        let vertices: Vec<Vec<f32>> = obj
+            .lines()
+            .filter_map(|l| l.strip_prefix("v "))
+            .map(|l| l.split(' ').map(|c| c.parse().unwrap()).collect())
+            .collect();
+        assert_eq!(vertices.len(), 9);
+        assert_eq!(obj.lines().filter(|l| l.starts_with("f ")).count(), 3);
+        assert_eq!(vertices[4], vec![11.0, 0.0, 5.0]);
+        assert_eq!(vertices[8], vec![20.0, 1.0, 5.0]);
+
+        // The PLY can be loaded back
+        let scene = format!(
+        "Camera \"perspective\"\nSampler \"02sequence\"\nWorldBegin\nShape \"plymesh\" \"string filename\" \"{}\"\nWorldEnd\n",
+        ply.display()
+    );
+        let opts = PbrtOptions {
+            defer_render: true,
+            ..PbrtOptions::default()
+        };
+        let context = pbrt::parse_scene_string(opts, &scene).unwrap().unwrap();
+        let bounds = context.scene.world_bounds();
+        assert_eq!(bounds.p_min, Point3f::new(0.0, 0.0, 0.0));
+        assert_eq!(bounds.p_max, Point3f::new(21.0, 1.0, 5.0));
+    }
+}
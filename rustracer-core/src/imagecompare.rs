@@ -0,0 +1,140 @@
+//! Comparison of renders against a reference image, for convergence studies of samplers and
+//! integrators.
+//!
+//! When the film is given a `"string reference"` image, it renders as usual but writes the
+//! per-pixel error of the render as its output instead of the render itself (see
+//! `ErrorMetric`), and logs the error over the whole image. The reference must have the
+//! resolution of the written image (i.e. of the crop window) and hold linear values in the
+//! film's colour space, like an EXR rendered from the same scene with many more samples.
+
+use anyhow::{bail, Context, Result};
+use log::info;
+
+use crate::imageio;
+use crate::Point2i;
+
+/// Offset added to the reference values the relative errors are divided by, so that black
+/// pixels of the reference don't give infinite errors
+pub const RELATIVE_EPSILON: f32 = 0.01;
+
+/// The error written for each channel of each pixel.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ErrorMetric {
+    /// `render - reference`, which is negative where the render is too dark, so it should be
+    /// written to a high dynamic range format
+    Signed,
+    /// `|render - reference| / (reference + 0.01)`
+    Relative,
+}
+
+impl ErrorMetric {
+    pub fn from_name(name: &str) -> Option<ErrorMetric> {
+        match name {
+            "signed" => Some(ErrorMetric::Signed),
+            "relative" => Some(ErrorMetric::Relative),
+            _ => None,
+        }
+    }
+}
+
+/// Error of a whole image against its reference.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ErrorStats {
+    /// Root mean square error
+    pub rmse: f32,
+    /// Mean of the squared errors divided by the squared reference values (offset like the
+    /// relative errors)
+    pub rel_mse: f32,
+}
+
+/// A reference image and how to compare renders against it.
+#[derive(Debug, Clone)]
+pub struct ReferenceImage {
+    /// Linear RGB values, 3 floats per pixel in scanline order
+    rgb: Vec<f32>,
+    metric: ErrorMetric,
+}
+
+impl ReferenceImage {
+    pub fn new(rgb: Vec<f32>, metric: ErrorMetric) -> ReferenceImage {
+        ReferenceImage { rgb, metric }
+    }
+
+    /// Load the reference image from a file, which must have the given resolution.
+    pub fn load(path: &str, resolution: Point2i, metric: ErrorMetric) -> Result<ReferenceImage> {
+        let (pixels, res) = imageio::read_image_linear(path)
+            .with_context(|| format!("Failed to load reference image {}", path))?;
+        if res != resolution {
+            bail!(
+                "Reference image {} has resolution {} but the film's image is {}",
+                path,
+                res,
+                resolution
+            );
+        }
+        let rgb = pixels.iter().flat_map(|p| [p.r, p.g, p.b]).collect();
+        Ok(ReferenceImage::new(rgb, metric))
+    }
+
+    /// The error of each value of the rendered `rgb` image, according to the metric.
+    pub fn error_image(&self, rgb: &[f32]) -> Vec<f32> {
+        assert_eq!(rgb.len(), self.rgb.len());
+        rgb.iter()
+            .zip(&self.rgb)
+            .map(|(&v, &r)| match self.metric {
+                ErrorMetric::Signed => v - r,
+                ErrorMetric::Relative => (v - r).abs() / (r.abs() + RELATIVE_EPSILON),
+            })
+            .collect()
+    }
+
+    pub fn stats(&self, rgb: &[f32]) -> ErrorStats {
+        assert_eq!(rgb.len(), self.rgb.len());
+        let (se, rel_se) =
+            rgb.iter()
+                .zip(&self.rgb)
+                .fold((0.0f64, 0.0f64), |(se, rel_se), (&v, &r)| {
+                    let e = f64::from(v - r).powi(2);
+                    let d = f64::from(r.abs() + RELATIVE_EPSILON).powi(2);
+                    (se + e, rel_se + e / d)
+                });
+        let n = rgb.len().max(1) as f64;
+        ErrorStats {
+            rmse: (se / n).sqrt() as f32,
+            rel_mse: (rel_se / n) as f32,
+        }
+    }
+
+    /// Compare the rendered `rgb` image against the reference: log the error of the whole image
+    /// and return the per-pixel errors to write instead of the render.
+    pub fn compare(&self, rgb: &[f32]) -> Vec<f32> {
+        let stats = self.stats(rgb);
+        info!(
+            "Error against the reference image: RMSE {}, relMSE {}",
+            stats.rmse, stats.rel_mse
+        );
+        self.error_image(rgb)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_errors() {
+        let reference = vec![1.0, 0.0, 0.5];
+        let rgb = [0.5, 0.0, 1.0];
+        let signed = ReferenceImage::new(reference.clone(), ErrorMetric::Signed);
+        assert_eq!(signed.error_image(&rgb), vec![-0.5, 0.0, 0.5]);
+        let relative = ReferenceImage::new(reference, ErrorMetric::Relative);
+        let errors = relative.error_image(&rgb);
+        assert!((errors[0] - 0.5 / 1.01).abs() < 1e-6);
+        assert_eq!(errors[1], 0.0);
+        assert!((errors[2] - 0.5 / 0.51).abs() < 1e-6);
+
+        let stats = signed.stats(&rgb);
+        assert!((stats.rmse - (0.5f32 / 3.0).sqrt()).abs() < 1e-6);
+        assert_eq!(signed.stats(&[1.0, 0.0, 0.5]).rel_mse, 0.0);
+    }
+}
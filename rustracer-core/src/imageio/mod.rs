@@ -0,0 +1,480 @@
+//! Reading and writing of image files.
+//!
+//! Images are exchanged as linear floating point RGB data. The supported formats are:
+//!
+//! * `png` and `tga`: 8-bit, sRGB-encoded. Values are gamma corrected and clamped to [0, 1] when
+//!   writing, and optionally dithered (see `ImageMetadata::dither`).
+//! * `hdr` (Radiance RGBE), `pfm` (portable float map) and `exr` (OpenEXR): high dynamic range,
+//!   stored linearly.
+//!
+//! The format is picked from the file's extension (case-insensitively).
+
+use std::fs::File;
+use std::io::{BufRead, BufReader, BufWriter, Read, Write};
+use std::path::Path;
+
+use anyhow::*;
+use exr;
+use image::{self, GenericImageView};
+use log::info;
+use rayon::prelude::*;
+
+use crate::bounds::Bounds2i;
+use crate::cie::ColourSpace;
+use crate::fileutil::{create_parent_directory, has_extension};
+use crate::rng::RNG;
+use crate::spectrum::{gamma_correct, Spectrum};
+use crate::{clamp, Point2i};
+
+mod rgbe;
+
+/// Extra information about an image being written.
+#[derive(Debug, Clone, Default)]
+pub struct ImageMetadata {
+    /// If the image is a crop of a larger image, the bounds of the written pixels within it.
+    pub pixel_bounds: Option<Bounds2i>,
+    /// If the image is a crop of a larger image, the resolution of the full image.
+    pub full_resolution: Option<Point2i>,
+    /// Whether to dither the values when quantizing them to 8 bits, to avoid banding in smooth
+    /// gradients. Ignored by the high dynamic range formats.
+    pub dither: bool,
+    /// Colour space of the pixels, recorded by the formats that support it (EXR).
+    pub colour_space: Option<ColourSpace>,
+}
+
+/// Read an image file, returning its pixels in scanline order along with its resolution.
+///
+/// Pixel values are returned as they are stored in the file: 8-bit formats (png, tga) are *not*
+/// converted from sRGB to linear, as image textures handle that themselves depending on their
+/// "gamma" parameter. Use `read_image_linear()` to always get linear values.
+pub fn read_image<P: AsRef<Path>>(path: P) -> Result<(Vec<Spectrum>, Point2i), Error> {
+    info!("Loading image {}", path.as_ref().display());
+    let path = path.as_ref();
+    if path.extension().is_none() {
+        bail!(
+            "Image filename {} doesn't have an extension",
+            path.display()
+        );
+    }
+    if has_extension(path, "tga") || has_extension(path, "png") {
+        read_image_tga_png(path)
+    } else if has_extension(path, "exr") {
+        read_image_exr(path)
+    } else if has_extension(path, "pfm") {
+        read_image_pfm(path)
+    } else if has_extension(path, "hdr") {
+        read_image_hdr(path)
+    } else {
+        Err(format_err!(
+            "Unsupported file format for {}",
+            path.display()
+        ))
+    }
+}
+
+/// Read an image file like `read_image()`, but converting sRGB-encoded formats to linear values.
+pub fn read_image_linear<P: AsRef<Path>>(path: P) -> Result<(Vec<Spectrum>, Point2i), Error> {
+    let path = path.as_ref();
+    let (pixels, res) = read_image(path)?;
+    if is_srgb_format(path) {
+        Ok((
+            pixels.iter().map(|p| p.inverse_gamma_correct()).collect(),
+            res,
+        ))
+    } else {
+        Ok((pixels, res))
+    }
+}
+
+/// Whether the image format of the given file stores sRGB-encoded values rather than linear ones.
+pub fn is_srgb_format<P: AsRef<Path>>(path: P) -> bool {
+    let path = path.as_ref();
+    has_extension(path, "png") || has_extension(path, "tga")
+}
+
+/// Write linear RGB data to an image file.
+///
+/// `rgb` contains 3 floats per pixel in scanline order, for an image of the given `resolution`.
+pub fn write_image<P: AsRef<Path>>(
+    path: P,
+    rgb: &[f32],
+    resolution: Point2i,
+    metadata: &ImageMetadata,
+) -> Result<(), Error> {
+    let path = path.as_ref();
+    let n_pixels = resolution.x.max(0) as usize * resolution.y.max(0) as usize;
+    if rgb.len() != 3 * n_pixels {
+        bail!(
+            "Expected {} values for a {}x{} image but got {}",
+            3 * n_pixels,
+            resolution.x,
+            resolution.y,
+            rgb.len()
+        );
+    }
+    create_parent_directory(path)?;
+
+    if has_extension(path, "png") || has_extension(path, "tga") {
+        write_image_8bit(path, rgb, resolution, metadata.dither)
+    } else if has_extension(path, "exr") {
+        write_image_exr(path, rgb, resolution, metadata)
+    } else if has_extension(path, "pfm") {
+        write_image_pfm(path, rgb, resolution)
+    } else if has_extension(path, "hdr") {
+        write_image_hdr(path, rgb, resolution)
+    } else {
+        Err(format_err!(
+            "Unsupported file format for {}",
+            path.display()
+        ))
+    }
+}
+
+fn write_image_8bit(
+    path: &Path,
+    rgb: &[f32],
+    resolution: Point2i,
+    dither: bool,
+) -> Result<(), Error> {
+    let rgb8: Vec<_> = if dither {
+        let mut rng = RNG::new();
+        rgb.iter()
+            .map(|v| {
+                // Triangular noise in (-1, 1)
+                let noise = rng.uniform_f32() + rng.uniform_f32() - 1.0;
+                quantize(*v, noise)
+            })
+            .collect()
+    } else {
+        rgb.iter().map(|v| quantize(*v, 0.0)).collect()
+    };
+
+    image::save_buffer(
+        path,
+        &rgb8,
+        resolution.x as u32,
+        resolution.y as u32,
+        image::ColorType::Rgb8,
+    )
+    .context(format!("Failed to save image file {}", path.display()))?;
+    Ok(())
+}
+
+/// Resize an image to `new_resolution` with a box filter: each output pixel is the average of the
+/// input pixels it covers (or the nearest one when enlarging).
+pub fn resize(rgb: &[f32], resolution: Point2i, new_resolution: Point2i) -> Vec<f32> {
+    let (w, h) = (resolution.x as usize, resolution.y as usize);
+    let (nw, nh) = (new_resolution.x as usize, new_resolution.y as usize);
+    // Range of input pixels covered by output pixel `i` along an axis
+    let range = |i: usize, n: usize, new_n: usize| {
+        let start = i * n / new_n;
+        let end = usize::max(start + 1, (i + 1) * n / new_n);
+        start..end
+    };
+
+    let mut result = Vec::with_capacity(3 * nw * nh);
+    for y in 0..nh {
+        let ys = range(y, h, nh);
+        for x in 0..nw {
+            let xs = range(x, w, nw);
+            let mut sum = [0.0; 3];
+            for yy in ys.clone() {
+                for xx in xs.clone() {
+                    let offset = 3 * (yy * w + xx);
+                    for c in 0..3 {
+                        sum[c] += rgb[offset + c];
+                    }
+                }
+            }
+            let n = (ys.len() * xs.len()) as f32;
+            result.extend(sum.iter().map(|v| v / n));
+        }
+    }
+    result
+}
+
+/// Gamma correct the linear value `v` and quantize it to 8 bits, after adding `noise` (expressed in
+/// quantization steps). Values that are clamped to black or white aren't dithered, so that they
+/// stay pure.
+fn quantize(v: f32, noise: f32) -> u8 {
+    let v = gamma_correct(v);
+    if v <= 0.0 {
+        0
+    } else if v >= 1.0 {
+        255
+    } else {
+        clamp(255.0 * v + 0.5 + noise, 0.0, 255.0) as u8
+    }
+}
+
+fn write_image_exr(
+    path: &Path,
+    rgb: &[f32],
+    resolution: Point2i,
+    metadata: &ImageMetadata,
+) -> Result<(), Error> {
+    use exr::prelude::*;
+
+    let (width, height) = (resolution.x as usize, resolution.y as usize);
+    let channels = SpecificChannels::rgb(|Vec2(x, y)| {
+        let offset = y * width + x;
+        (rgb[offset * 3], rgb[offset * 3 + 1], rgb[offset * 3 + 2])
+    });
+    let mut image = Image::from_channels((width, height), channels);
+    // Store crop windows as a data window inside the full display window, like pbrt does
+    if let (Some(bounds), Some(full_res)) = (metadata.pixel_bounds, metadata.full_resolution) {
+        image.attributes.display_window =
+            IntegerBounds::from_dimensions((full_res.x as usize, full_res.y as usize));
+        image.layer_data.attributes.layer_position = Vec2(bounds.p_min.x, bounds.p_min.y);
+    }
+    if let Some(colour_space) = metadata.colour_space {
+        let [r, g, b, w] = colour_space.chromaticities();
+        image.attributes.chromaticities = Some(attribute::Chromaticities {
+            red: Vec2(r[0], r[1]),
+            green: Vec2(g[0], g[1]),
+            blue: Vec2(b[0], b[1]),
+            white: Vec2(w[0], w[1]),
+        });
+    }
+    image
+        .write()
+        .to_file(path)
+        .context(format!("Failed to save image file {}", path.display()))?;
+
+    Ok(())
+}
+
+/// Write arbitrary float channels to an EXR file, e.g. for data that isn't RGB.
+///
+/// `channels` holds the name of each channel (e.g. `"layer.R"`) and its values in scanline order,
+/// for an image of the given `resolution`. `attributes` are additional text attributes of the
+/// file's header. Only the crop window of `metadata` is used.
+pub fn write_exr_channels<P: AsRef<Path>>(
+    path: P,
+    channels: &[(String, Vec<f32>)],
+    attributes: &[(String, String)],
+    resolution: Point2i,
+    metadata: &ImageMetadata,
+) -> Result<(), Error> {
+    use exr::prelude::*;
+
+    let path = path.as_ref();
+    let (width, height) = (resolution.x.max(0) as usize, resolution.y.max(0) as usize);
+    let text = |s: &str| {
+        Text::new_or_none(s).ok_or_else(|| format_err!("Invalid EXR attribute text \"{}\"", s))
+    };
+    let mut list = Vec::with_capacity(channels.len());
+    for (name, values) in channels {
+        if values.len() != width * height {
+            bail!(
+                "Expected {} values for channel {} of a {}x{} image but got {}",
+                width * height,
+                name,
+                width,
+                height,
+                values.len()
+            );
+        }
+        list.push(AnyChannel::new(
+            text(name)?,
+            FlatSamples::F32(values.clone()),
+        ));
+    }
+    let layer = Layer::new(
+        (width, height),
+        LayerAttributes::default(),
+        Encoding::FAST_LOSSLESS,
+        AnyChannels::sort(list.into_iter().collect()),
+    );
+    let mut image = Image::from_layer(layer);
+    if let (Some(bounds), Some(full_res)) = (metadata.pixel_bounds, metadata.full_resolution) {
+        image.attributes.display_window =
+            IntegerBounds::from_dimensions((full_res.x as usize, full_res.y as usize));
+        image.layer_data.attributes.layer_position = Vec2(bounds.p_min.x, bounds.p_min.y);
+    }
+    // The attributes are those of the layer rather than the image (which is the same header in a
+    // single layer file), as the exr crate fails to validate the latter
+    for (name, value) in attributes {
+        image
+            .layer_data
+            .attributes
+            .other
+            .insert(text(name)?, AttributeValue::Text(text(value)?));
+    }
+    create_parent_directory(path)?;
+    image
+        .write()
+        .to_file(path)
+        .context(format!("Failed to save image file {}", path.display()))?;
+
+    Ok(())
+}
+
+fn write_image_pfm(path: &Path, rgb: &[f32], resolution: Point2i) -> Result<(), Error> {
+    let file = File::create(path).context(format!("Failed to create {}", path.display()))?;
+    let mut writer = BufWriter::new(file);
+    let (width, height) = (resolution.x as usize, resolution.y as usize);
+
+    // A negative scale means little-endian data
+    write!(writer, "PF\n{} {}\n-1\n", width, height)?;
+    // Flip in Y, as P*M has the origin at the lower left.
+    for y in (0..height).rev() {
+        for v in &rgb[3 * y * width..3 * (y + 1) * width] {
+            writer.write_all(&v.to_le_bytes())?;
+        }
+    }
+    writer.flush()?;
+
+    Ok(())
+}
+
+fn write_image_hdr(path: &Path, rgb: &[f32], resolution: Point2i) -> Result<(), Error> {
+    let file = File::create(path).context(format!("Failed to create {}", path.display()))?;
+    rgbe::write_rgbe(&mut BufWriter::new(file), rgb, resolution)
+        .context(format!("Failed to save image file {}", path.display()))
+}
+
+fn read_image_tga_png<P: AsRef<Path>>(path: P) -> Result<(Vec<Spectrum>, Point2i), Error> {
+    info!("Loading texture {}", path.as_ref().display());
+    let buf = image::open(path)?;
+    let (width, height) = buf.dimensions();
+
+    let rgb = buf.to_rgb8().into_raw();
+    let res = Point2i::new(width as i32, height as i32);
+    let pixels: Vec<Spectrum> = rgb
+        .par_chunks(3)
+        .map(|p| {
+            let r = f32::from(p[0]) / 255.0;
+            let g = f32::from(p[1]) / 255.0;
+            let b = f32::from(p[2]) / 255.0;
+            Spectrum::rgb(r, g, b)
+        })
+        .collect();
+
+    Ok((pixels, res))
+}
+
+fn read_image_hdr<P: AsRef<Path>>(path: P) -> Result<(Vec<Spectrum>, Point2i), Error> {
+    info!("Loading HDR image {}", path.as_ref().display());
+    let file = File::open(path.as_ref())?;
+    let mut reader = BufReader::new(file);
+
+    rgbe::read_rgbe(&mut reader).context(format!(
+        "Failed to read HDR image {}",
+        path.as_ref().display()
+    ))
+}
+
+fn read_image_exr<P: AsRef<Path>>(path: P) -> Result<(Vec<Spectrum>, Point2i), Error> {
+    info!("Loading EXR texture {}", path.as_ref().display());
+
+    let image = exr::prelude::read_first_rgba_layer_from_file(
+        path,
+        |resolution, _| vec![vec![Spectrum::black(); resolution.width()]; resolution.height()],
+        |pixels, pos, (r, g, b, _): (f32, f32, f32, f32)| {
+            pixels[pos.y()][pos.x()] = Spectrum::rgb(r, g, b);
+        },
+    )?;
+    let pixels = image
+        .layer_data
+        .channel_data
+        .pixels
+        .iter()
+        .flatten()
+        .cloned()
+        .collect::<Vec<_>>();
+
+    Ok((
+        pixels,
+        Point2i::new(
+            image.attributes.display_window.size.width() as i32,
+            image.attributes.display_window.size.height() as i32,
+        ),
+    ))
+}
+
+fn is_whitespace(c: char) -> bool {
+    c == ' ' || c == '\n' || c == '\t'
+}
+
+fn read_word<R: BufRead>(f: &mut R) -> String {
+    let mut buf = String::new();
+    for c in f.bytes() {
+        let c = c.unwrap() as char;
+        if is_whitespace(c) {
+            break;
+        }
+        buf.push(c);
+    }
+
+    buf
+}
+
+fn read_image_pfm<P: AsRef<Path>>(path: P) -> Result<(Vec<Spectrum>, Point2i), Error> {
+    info!("Loading PFM file {}", path.as_ref().display());
+    let file = File::open(path.as_ref())?;
+    let mut reader = BufReader::new(file);
+    let mut word;
+
+    word = read_word(&mut reader);
+    let n_channels = if &word == "Pf" {
+        1
+    } else if &word == "PF" {
+        3
+    } else {
+        bail!("Error reading PFM file \"{}\"", path.as_ref().display());
+    };
+
+    // Read the rest of the header
+    // Read width
+    word = read_word(&mut reader);
+    let width: usize = word.parse::<usize>().context("Failed to parse width")?;
+
+    // Read height
+    word = read_word(&mut reader);
+    let height: usize = word.parse::<usize>().context("Failed to parse height")?;
+
+    // Read scale
+    word = read_word(&mut reader);
+    let scale: f32 = word.parse::<f32>().context("Failed to parse scale")?;
+    let file_little_endian = scale < 0.0;
+    let host_little_endian = true;
+
+    info!(
+        "n_channels={}, width={}, height={}, scale={}",
+        n_channels, width, height, scale
+    );
+
+    // Read the rest of the data
+    let n_floats = n_channels * width * height;
+    let mut data = Vec::new();
+    data.resize(n_floats, 0.0f32);
+    // Flip in Y, as P*M has the origin at the lower left.
+    for y in (0..height).rev() {
+        for x in 0..width {
+            for idx in 0..n_channels {
+                let mut buf = [0u8; 4]; // buffer to read a float
+                reader.read_exact(&mut buf)?;
+                if host_little_endian ^ file_little_endian {
+                    buf.as_mut().swap(0, 3);
+                    buf.as_mut().swap(1, 2);
+                }
+                let mut float = f32::from_ne_bytes(buf);
+                if scale.abs() != 1.0 {
+                    float *= scale.abs();
+                }
+                data[y * n_channels * width + x * n_channels + idx] = float;
+            }
+        }
+    }
+
+    let rgb: Vec<Spectrum> = if n_channels == 1 {
+        data.iter().map(|v| Spectrum::from(*v)).collect()
+    } else {
+        data.chunks(3)
+            .map(|rgb| Spectrum::rgb(rgb[0], rgb[1], rgb[2]))
+            .collect()
+    };
+
+    Ok((rgb, Point2i::new(width as i32, height as i32)))
+}
@@ -0,0 +1,468 @@
+//! Reader and writer for Radiance RGBE (`.hdr`) images.
+//!
+//! This handles the parts of the format that are commonly found in the wild but that generic
+//! image libraries tend to skip: all 8 scanline orientations, both run-length encodings ("new"
+//! per-channel RLE and the old repeat-pixel scheme), the `EXPOSURE` header and `xyze` files.
+
+use std::io::{BufRead, Write};
+
+use anyhow::*;
+
+use crate::spectrum::Spectrum;
+use crate::Point2i;
+
+const MIN_RLE_WIDTH: usize = 8;
+const MAX_RLE_WIDTH: usize = 0x7fff;
+
+/// Read an RGBE image, returning linear pixels in scanline order (top to bottom, left to right)
+/// and its resolution.
+pub fn read_rgbe<R: BufRead>(reader: &mut R) -> Result<(Vec<Spectrum>, Point2i), Error> {
+    let header = read_header(reader)?;
+    let (n_scanlines, scanline_len) = if header.x_major {
+        (header.width, header.height)
+    } else {
+        (header.height, header.width)
+    };
+
+    let mut pixels = vec![Spectrum::black(); header.width * header.height];
+    let mut scanline = vec![[0u8; 4]; scanline_len];
+    for s in 0..n_scanlines {
+        read_scanline(reader, &mut scanline)?;
+        for (i, rgbe) in scanline.iter().enumerate() {
+            let (x, y) = header.pixel_coords(s, i);
+            let v = rgbe_to_float(*rgbe);
+            let v = [
+                v[0] / header.exposure,
+                v[1] / header.exposure,
+                v[2] / header.exposure,
+            ];
+            pixels[y * header.width + x] = if header.xyz {
+                Spectrum::from_xyz(&v)
+            } else {
+                Spectrum::rgb(v[0], v[1], v[2])
+            };
+        }
+    }
+
+    Ok((
+        pixels,
+        Point2i::new(header.width as i32, header.height as i32),
+    ))
+}
+
+/// Write linear RGB data (3 floats per pixel, in scanline order) as an RGBE image with the
+/// standard `-Y +X` orientation, run-length encoding the scanlines when possible.
+pub fn write_rgbe<W: Write>(writer: &mut W, rgb: &[f32], resolution: Point2i) -> Result<(), Error> {
+    let (width, height) = (resolution.x as usize, resolution.y as usize);
+    writeln!(writer, "#?RADIANCE")?;
+    writeln!(writer, "FORMAT=32-bit_rle_rgbe")?;
+    writeln!(writer)?;
+    writeln!(writer, "-Y {} +X {}", height, width)?;
+
+    let mut channels: Vec<Vec<u8>> = (0..4).map(|_| Vec::with_capacity(width)).collect();
+    for y in 0..height {
+        let row = &rgb[3 * y * width..3 * (y + 1) * width];
+        if !(MIN_RLE_WIDTH..=MAX_RLE_WIDTH).contains(&width) {
+            for p in row.chunks(3) {
+                writer.write_all(&float_to_rgbe(p[0], p[1], p[2]))?;
+            }
+            continue;
+        }
+
+        for c in &mut channels {
+            c.clear();
+        }
+        for p in row.chunks(3) {
+            let rgbe = float_to_rgbe(p[0], p[1], p[2]);
+            for (c, v) in channels.iter_mut().zip(rgbe.iter()) {
+                c.push(*v);
+            }
+        }
+        writer.write_all(&[2, 2, (width >> 8) as u8, (width & 0xff) as u8])?;
+        for c in &channels {
+            write_rle_channel(writer, c)?;
+        }
+    }
+    writer.flush()?;
+
+    Ok(())
+}
+
+struct Header {
+    width: usize,
+    height: usize,
+    /// Whether scanlines run along the Y axis (i.e. the image is stored transposed)
+    x_major: bool,
+    /// Whether the slow (scanline) axis is traversed in decreasing image coordinates
+    slow_reversed: bool,
+    /// Whether the fast (pixel) axis is traversed in decreasing image coordinates
+    fast_reversed: bool,
+    exposure: f32,
+    xyz: bool,
+}
+
+impl Header {
+    /// Image-space (top-left origin) coordinates of pixel `i` of scanline `s`.
+    fn pixel_coords(&self, s: usize, i: usize) -> (usize, usize) {
+        let (n_slow, n_fast) = if self.x_major {
+            (self.width, self.height)
+        } else {
+            (self.height, self.width)
+        };
+        let s = if self.slow_reversed {
+            n_slow - 1 - s
+        } else {
+            s
+        };
+        let i = if self.fast_reversed {
+            n_fast - 1 - i
+        } else {
+            i
+        };
+        if self.x_major {
+            (s, i)
+        } else {
+            (i, s)
+        }
+    }
+}
+
+fn read_line<R: BufRead>(reader: &mut R) -> Result<String, Error> {
+    let mut buf = Vec::new();
+    let n = reader.read_until(b'\n', &mut buf)?;
+    if n == 0 {
+        bail!("Unexpected end of file in RGBE header");
+    }
+
+    Ok(String::from_utf8_lossy(&buf).trim_end().to_owned())
+}
+
+fn read_header<R: BufRead>(reader: &mut R) -> Result<Header, Error> {
+    let magic = read_line(reader)?;
+    if !magic.starts_with("#?") {
+        bail!("Not a Radiance RGBE file");
+    }
+
+    let mut exposure = 1.0;
+    let mut xyz = false;
+    loop {
+        let line = read_line(reader)?;
+        if line.is_empty() {
+            break;
+        }
+        if let Some(format) = line.strip_prefix("FORMAT=") {
+            match format.trim() {
+                "32-bit_rle_rgbe" => xyz = false,
+                "32-bit_rle_xyze" => xyz = true,
+                f => bail!("Unsupported RGBE pixel format {}", f),
+            }
+        } else if let Some(e) = line.strip_prefix("EXPOSURE=") {
+            // Successive exposure values are cumulative
+            exposure *= e
+                .trim()
+                .parse::<f32>()
+                .context("Failed to parse RGBE exposure")?;
+        }
+    }
+
+    let resolution = read_line(reader)?;
+    let tokens: Vec<&str> = resolution.split_whitespace().collect();
+    if tokens.len() != 4 {
+        bail!("Invalid RGBE resolution line \"{}\"", resolution);
+    }
+    let (slow_axis, fast_axis) = (tokens[0], tokens[2]);
+    let n_slow: usize = tokens[1]
+        .parse()
+        .context("Failed to parse RGBE resolution")?;
+    let n_fast: usize = tokens[3]
+        .parse()
+        .context("Failed to parse RGBE resolution")?;
+    let axis = |t: &str| -> Result<(bool, char), Error> {
+        let mut chars = t.chars();
+        match (chars.next(), chars.next(), chars.next()) {
+            (Some(sign @ ('+' | '-')), Some(a @ ('X' | 'Y')), None) => Ok((sign == '-', a)),
+            _ => Err(format_err!(
+                "Invalid RGBE resolution line \"{}\"",
+                resolution
+            )),
+        }
+    };
+    let (slow_minus, slow_name) = axis(slow_axis)?;
+    let (fast_minus, fast_name) = axis(fast_axis)?;
+    if slow_name == fast_name {
+        bail!("Invalid RGBE resolution line \"{}\"", resolution);
+    }
+    let x_major = slow_name == 'X';
+    // Radiance's Y axis points up, whereas our images are stored top to bottom.
+    let (slow_reversed, fast_reversed) = if x_major {
+        (slow_minus, !fast_minus)
+    } else {
+        (!slow_minus, fast_minus)
+    };
+    let (width, height) = if x_major {
+        (n_slow, n_fast)
+    } else {
+        (n_fast, n_slow)
+    };
+
+    Ok(Header {
+        width,
+        height,
+        x_major,
+        slow_reversed,
+        fast_reversed,
+        exposure,
+        xyz,
+    })
+}
+
+fn read_bytes<R: BufRead, const N: usize>(reader: &mut R) -> Result<[u8; N], Error> {
+    let mut buf = [0u8; N];
+    reader
+        .read_exact(&mut buf)
+        .context("Unexpected end of file in RGBE data")?;
+    Ok(buf)
+}
+
+fn read_scanline<R: BufRead>(reader: &mut R, scanline: &mut [[u8; 4]]) -> Result<(), Error> {
+    let len = scanline.len();
+    if len == 0 {
+        return Ok(());
+    }
+    let first = read_bytes::<_, 4>(reader)?;
+    let is_new_rle = (MIN_RLE_WIDTH..=MAX_RLE_WIDTH).contains(&len)
+        && first[0] == 2
+        && first[1] == 2
+        && first[2] & 0x80 == 0;
+    if !is_new_rle {
+        return read_old_scanline(reader, first, scanline);
+    }
+
+    let encoded_len = ((first[2] as usize) << 8) | first[3] as usize;
+    if encoded_len != len {
+        bail!("RGBE scanline length mismatch");
+    }
+    for c in 0..4 {
+        let mut i = 0;
+        while i < len {
+            let [count] = read_bytes::<_, 1>(reader)?;
+            if count > 128 {
+                // Run
+                let count = (count - 128) as usize;
+                let [value] = read_bytes::<_, 1>(reader)?;
+                if i + count > len {
+                    bail!("Bad RGBE scanline data");
+                }
+                for p in &mut scanline[i..i + count] {
+                    p[c] = value;
+                }
+                i += count;
+            } else {
+                // Literal values
+                let count = count as usize;
+                if count == 0 || i + count > len {
+                    bail!("Bad RGBE scanline data");
+                }
+                for p in &mut scanline[i..i + count] {
+                    let [value] = read_bytes::<_, 1>(reader)?;
+                    p[c] = value;
+                }
+                i += count;
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// Flat scanlines, possibly using the original Radiance RLE scheme where a pixel of (1, 1, 1, n)
+/// repeats the previous pixel n times (with n shifted by 8 bits for each consecutive repeat).
+fn read_old_scanline<R: BufRead>(
+    reader: &mut R,
+    first: [u8; 4],
+    scanline: &mut [[u8; 4]],
+) -> Result<(), Error> {
+    let len = scanline.len();
+    let mut i = 0;
+    let mut shift = 0;
+    let mut rgbe = first;
+    loop {
+        if rgbe[0] == 1 && rgbe[1] == 1 && rgbe[2] == 1 {
+            if i == 0 {
+                bail!("Bad RGBE scanline data");
+            }
+            let count = (rgbe[3] as usize) << shift;
+            if i + count > len {
+                bail!("Bad RGBE scanline data");
+            }
+            let prev = scanline[i - 1];
+            for p in &mut scanline[i..i + count] {
+                *p = prev;
+            }
+            i += count;
+            shift += 8;
+        } else {
+            scanline[i] = rgbe;
+            i += 1;
+            shift = 0;
+        }
+        if i >= len {
+            return Ok(());
+        }
+        rgbe = read_bytes::<_, 4>(reader)?;
+    }
+}
+
+fn write_rle_channel<W: Write>(writer: &mut W, data: &[u8]) -> Result<(), Error> {
+    const MIN_RUN: usize = 4;
+    let mut i = 0;
+    while i < data.len() {
+        // Find the next run long enough to be worth encoding
+        let mut run_start = i;
+        let mut run_len = 0;
+        while run_start < data.len() {
+            run_len = 1;
+            while run_len < 127
+                && run_start + run_len < data.len()
+                && data[run_start + run_len] == data[run_start]
+            {
+                run_len += 1;
+            }
+            if run_len >= MIN_RUN {
+                break;
+            }
+            run_start += run_len;
+        }
+        // Write the literal values before the run
+        while i < run_start {
+            let n = usize::min(128, run_start - i);
+            writer.write_all(&[n as u8])?;
+            writer.write_all(&data[i..i + n])?;
+            i += n;
+        }
+        // Write the run
+        if run_len >= MIN_RUN {
+            writer.write_all(&[(128 + run_len) as u8, data[run_start]])?;
+            i += run_len;
+        }
+    }
+
+    Ok(())
+}
+
+fn rgbe_to_float(rgbe: [u8; 4]) -> [f32; 3] {
+    if rgbe[3] == 0 {
+        return [0.0; 3];
+    }
+    let f = 2f32.powi(rgbe[3] as i32 - (128 + 8));
+    [
+        (rgbe[0] as f32 + 0.5) * f,
+        (rgbe[1] as f32 + 0.5) * f,
+        (rgbe[2] as f32 + 0.5) * f,
+    ]
+}
+
+fn float_to_rgbe(r: f32, g: f32, b: f32) -> [u8; 4] {
+    let (r, g, b) = (r.max(0.0), g.max(0.0), b.max(0.0));
+    let v = r.max(g).max(b);
+    if v.is_nan() || v < 1e-32 || v.is_infinite() {
+        return [0; 4];
+    }
+    // v = m * 2^e, with m in [0.5, 1)
+    let mut e = v.log2().floor() as i32 + 1;
+    if v / 2f32.powi(e) >= 1.0 {
+        e += 1;
+    }
+    let scale = 256.0 / 2f32.powi(e);
+    let to_byte = |c: f32| f32::min(c * scale, 255.0) as u8;
+    [to_byte(r), to_byte(g), to_byte(b), (e + 128) as u8]
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use super::*;
+
+    fn header(resolution: &str) -> Vec<u8> {
+        format!("#?RADIANCE\nFORMAT=32-bit_rle_rgbe\n\n{}\n", resolution).into_bytes()
+    }
+
+    #[test]
+    fn test_orientation() {
+        // 2x2 image stored bottom to top: pixel values encode their (x, y) in image space
+        let mut data = header("+Y 2 +X 2");
+        for y in [1, 0] {
+            for x in 0..2 {
+                data.extend_from_slice(&[(2 * y + x) as u8 + 1, 0, 0, 129]);
+            }
+        }
+        let (pixels, res) = read_rgbe(&mut Cursor::new(data)).unwrap();
+        assert_eq!(res, Point2i::new(2, 2));
+        for (i, p) in pixels.iter().enumerate() {
+            assert_eq!(p[0], (i as f32 + 1.5) / 128.0);
+        }
+    }
+
+    #[test]
+    fn test_transposed() {
+        // 3 wide, 2 high, stored column by column from the left
+        let mut data = header("+X 3 -Y 2");
+        for x in 0..3 {
+            for y in 0..2 {
+                data.extend_from_slice(&[(3 * y + x) as u8, 0, 0, 129]);
+            }
+        }
+        let (pixels, res) = read_rgbe(&mut Cursor::new(data)).unwrap();
+        assert_eq!(res, Point2i::new(3, 2));
+        for (i, p) in pixels.iter().enumerate() {
+            assert_eq!(p[0], (i as f32 + 0.5) / 128.0);
+        }
+    }
+
+    #[test]
+    fn test_exposure_and_old_rle() {
+        let mut data = b"#?RGBE\nEXPOSURE=2\nEXPOSURE=2\n\n-Y 1 +X 4\n".to_vec();
+        // One pixel followed by a repeat count of 3
+        data.extend_from_slice(&[64, 64, 64, 129, 1, 1, 1, 3]);
+        let (pixels, _) = read_rgbe(&mut Cursor::new(data)).unwrap();
+        for p in &pixels {
+            assert_eq!(p[1], 64.5 / 128.0 / 4.0);
+        }
+    }
+
+    #[test]
+    fn test_rle_round_trip() {
+        let res = Point2i::new(300, 2);
+        let rgb: Vec<f32> = (0..300 * 2 * 3)
+            .map(|i| {
+                if i % 90 < 45 {
+                    1.0
+                } else {
+                    (i % 7) as f32 * 10.0
+                }
+            })
+            .collect();
+        let mut buf = Vec::new();
+        write_rgbe(&mut buf, &rgb, res).unwrap();
+        // The long constant runs should compress
+        assert!(buf.len() < 300 * 2 * 4);
+
+        let (pixels, read_res) = read_rgbe(&mut Cursor::new(buf)).unwrap();
+        assert_eq!(read_res, res);
+        for (p, expected) in pixels.iter().zip(rgb.chunks(3)) {
+            // The exponent is shared, so the error is relative to the largest component
+            let max = expected[0].max(expected[1]).max(expected[2]);
+            for c in 0..3 {
+                assert!((p[c] - expected[c]).abs() <= max / 128.0);
+            }
+        }
+    }
+
+    #[test]
+    fn test_float_to_rgbe() {
+        assert_eq!(float_to_rgbe(0.0, 0.0, 0.0), [0; 4]);
+        assert_eq!(float_to_rgbe(1.0, 0.5, 0.0), [128, 64, 0, 129]);
+        assert_eq!(float_to_rgbe(0.5, 0.5, 0.5), [128, 128, 128, 128]);
+    }
+}
@@ -0,0 +1,157 @@
+use light_arena::Allocator;
+use log::warn;
+use num::Zero;
+
+use crate::bounds::Bounds2i;
+use crate::camera::Camera;
+use crate::integrator::SamplerIntegrator;
+use crate::interaction::Interaction;
+use crate::paramset::ParamSet;
+use crate::ray::Ray;
+use crate::sampler::Sampler;
+use crate::sampling::cosine_sample_hemisphere;
+use crate::scene::Scene;
+use crate::spectrum::Spectrum;
+use crate::{coordinate_system, Normal3f, Vector3f};
+
+/// What the `BakeIntegrator` writes to the texture.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BakeQuantity {
+    /// Direct irradiance from the lights of the scene
+    Irradiance,
+    /// Fraction of the hemisphere around the normal that isn't occluded within `maxdistance`
+    Visibility,
+}
+
+/// Integrator to use with a `BakeCamera`: evaluates a quantity at the surface point carried by
+/// each camera ray, rather than following the ray into the scene.
+pub struct BakeIntegrator {
+    pixel_bounds: Bounds2i,
+    quantity: BakeQuantity,
+    n_samples: usize,
+    max_distance: f32,
+}
+
+impl BakeIntegrator {
+    pub fn new(
+        quantity: BakeQuantity,
+        n_samples: usize,
+        max_distance: f32,
+        pixel_bounds: Bounds2i,
+    ) -> BakeIntegrator {
+        BakeIntegrator {
+            pixel_bounds,
+            quantity,
+            n_samples,
+            max_distance,
+        }
+    }
+
+    pub fn create(ps: &ParamSet, camera: &dyn Camera) -> Box<dyn SamplerIntegrator> {
+        let q = ps.find_one_string("quantity", "irradiance".into());
+        let quantity = if q == "irradiance" {
+            BakeQuantity::Irradiance
+        } else if q == "visibility" {
+            BakeQuantity::Visibility
+        } else {
+            warn!("Quantity \"{}\" for bake unknown. Using \"irradiance\".", q);
+            BakeQuantity::Irradiance
+        };
+        let n_samples = ps.find_one_int("samples", 16).max(1) as usize;
+        let max_distance = ps.find_one_float("maxdistance", f32::INFINITY);
+        let pixel_bounds = camera.get_film().get_sample_bounds();
+        Box::new(Self::new(quantity, n_samples, max_distance, pixel_bounds))
+    }
+
+    fn irradiance(&self, scene: &Scene, it: &Interaction, sampler: &mut dyn Sampler) -> Spectrum {
+        let mut e = Spectrum::black();
+        for light in &scene.lights {
+            for _ in 0..self.n_samples {
+                let (li, wi, pdf, vis) = light.sample_li(it, sampler.get_2d());
+                let cos_theta = wi.dotn(&it.n);
+                if pdf > 0.0 && cos_theta > 0.0 && !li.is_black() && vis.unoccluded(scene) {
+                    e += li * cos_theta / pdf;
+                }
+            }
+        }
+        e / self.n_samples as f32
+    }
+
+    fn visibility(&self, scene: &Scene, it: &Interaction, sampler: &mut dyn Sampler) -> Spectrum {
+        let n = Vector3f::from(it.n);
+        let (s, t) = coordinate_system(&n);
+        let mut n_clear = 0;
+        for _ in 0..self.n_samples {
+            let w = cosine_sample_hemisphere(sampler.get_2d());
+            let mut ray = it.spawn_ray(&(s * w.x + t * w.y + n * w.z));
+            ray.t_max = self.max_distance;
+            if !scene.intersect_p(&ray) {
+                n_clear += 1;
+            }
+        }
+        Spectrum::grey(n_clear as f32 / self.n_samples as f32)
+    }
+}
+
+impl SamplerIntegrator for BakeIntegrator {
+    fn pixel_bounds(&self) -> &Bounds2i {
+        &self.pixel_bounds
+    }
+
+    fn li(
+        &self,
+        scene: &Scene,
+        ray: &mut Ray,
+        sampler: &mut dyn Sampler,
+        _arena: &Allocator<'_>,
+        _depth: u32,
+    ) -> Spectrum {
+        // Texels outside of the object's texture layout
+        if ray.t_max == 0.0 {
+            return Spectrum::black();
+        }
+        let n = Normal3f::from(ray.d);
+        let it = Interaction::new(ray.o, Vector3f::zero(), ray.time, ray.d, n);
+        match self.quantity {
+            BakeQuantity::Irradiance => self.irradiance(scene, &it, sampler),
+            BakeQuantity::Visibility => self.visibility(scene, &it, sampler),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::testutil::{render_scene, threads};
+
+    #[test]
+    fn test_bake_irradiance_into_texture() {
+        // A unit square lit from above, whose left half is in the shadow of a second square
+        let texels = render_scene(
+            r#"
+Camera "bake" "string object" "floor"
+Film "image" "integer xresolution" [8] "integer yresolution" [8]
+Sampler "02sequence" "integer pixelsamples" [4]
+Integrator "bake" "string quantity" "irradiance" "integer samples" [1]
+WorldBegin
+LightSource "distant" "point from" [0 0 1] "point to" [0 0 0] "rgb L" [1 1 1]
+ObjectBegin "floor"
+Shape "trianglemesh" "integer indices" [0 1 2 0 2 3]
+    "point P" [0 0 0  1 0 0  1 1 0  0 1 0]
+    "point2 uv" [0 0  1 0  1 1  0 1]
+ObjectEnd
+ObjectInstance "floor"
+Shape "trianglemesh" "integer indices" [0 1 2 0 2 3]
+    "point P" [-1 -1 1  0.5 -1 1  0.5 2 1  -1 2 1]
+WorldEnd
+"#,
+            threads(2),
+        );
+        for y in 0..8 {
+            for x in 0..8 {
+                let v = texels[y * 8 + x].r;
+                let expected = if x < 4 { 0.0 } else { 1.0 };
+                assert!((v - expected).abs() < 1e-3, "texel ({}, {}): {}", x, y, v);
+            }
+        }
+    }
+}
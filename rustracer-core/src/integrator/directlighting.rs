@@ -1,16 +1,32 @@
 use std::sync::Arc;
 
-use light_arena::Allocator;
+use light_arena::{Allocator, MemoryArena};
 use log::{info, warn};
 
 use crate::bounds::Bounds2i;
-use crate::integrator::{uniform_sample_all_light, uniform_sample_one_light, SamplerIntegrator};
+use crate::camera::{Camera, CameraSample};
+use crate::film::{FilmTile, SamplePlacement};
+use crate::integrator::{
+    mis_heuristic, uniform_sample_all_light, uniform_sample_one_light, SamplerIntegrator,
+};
+use crate::interaction::SurfaceInteraction;
 use crate::material::TransportMode;
 use crate::paramset::ParamSet;
 use crate::ray::Ray;
+use crate::renderer;
 use crate::sampler::Sampler;
+use crate::sampling::MisHeuristic;
 use crate::scene::Scene;
 use crate::spectrum::Spectrum;
+use crate::PbrtOptions;
+
+stat_percent!(
+    "Integrator/Primary hits reused from the cache",
+    primary_hits_reused
+);
+pub fn init_stats() {
+    primary_hits_reused::init();
+}
 
 /// Strategy to use for sampling lights
 #[derive(PartialEq, Eq)]
@@ -31,20 +47,40 @@ pub struct DirectLightingIntegrator {
     max_depth: u8,
     //
     n_light_samples: Vec<usize>,
+    /// Heuristic used to combine the light and BSDF samples
+    mis_heuristic: MisHeuristic,
+    /// Give the BSDF-sampled rays approximate differentials
+    secondary_differentials: bool,
+    /// Reuse the primary hit of a pixel's previous sample when its camera ray is the same (see
+    /// `render_tile()`)
+    cache_primary_hits: bool,
+    /// How far apart the origins and directions of two camera rays can be for them to be
+    /// considered the same
+    primary_hit_epsilon: f32,
+    sample_placement: SamplePlacement,
 }
 
 impl DirectLightingIntegrator {
-    pub fn new(n: u8, strategy: LightStrategy) -> DirectLightingIntegrator {
+    pub fn new(n: u8, strategy: LightStrategy, pixel_bounds: Bounds2i) -> DirectLightingIntegrator {
         DirectLightingIntegrator {
-            pixel_bounds: Bounds2i::new(),
+            pixel_bounds,
             max_depth: n,
             light_strategy: strategy,
             n_light_samples: Vec::new(),
+            mis_heuristic: MisHeuristic::default(),
+            secondary_differentials: false,
+            cache_primary_hits: false,
+            primary_hit_epsilon: 0.0,
+            sample_placement: SamplePlacement::default(),
         }
     }
 
-    pub fn create(ps: &ParamSet) -> Box<dyn SamplerIntegrator> {
-        let max_depth = ps.find_one_int("maxdepth", 5);
+    pub fn create(
+        ps: &ParamSet,
+        camera: &dyn Camera,
+        opts: &PbrtOptions,
+    ) -> Box<dyn SamplerIntegrator> {
+        let max_depth = opts.max_depth(ps.find_one_int("maxdepth", 5));
         let st = ps.find_one_string("strategy", "all".into());
         let strategy = if st == "one" {
             LightStrategy::UniformSampleOne
@@ -57,8 +93,24 @@ impl DirectLightingIntegrator {
             );
             LightStrategy::UniformSampleAll
         };
-        // TODO pixel_bounds
-        Box::new(Self::new(max_depth as u8, strategy))
+        let pixel_bounds = camera.get_film().get_sample_bounds();
+        let mut integrator = Self::new(max_depth as u8, strategy, pixel_bounds);
+        integrator.mis_heuristic = mis_heuristic(ps);
+        integrator.secondary_differentials = opts.secondary_differentials;
+        integrator.primary_hit_epsilon = ps.find_one_float("primaryhitepsilon", 0.0).max(0.0);
+        let film = camera.get_film();
+        integrator.sample_placement = film.sample_placement();
+        integrator.cache_primary_hits = ps.find_one_bool("cacheprimaryhits", false);
+        if integrator.cache_primary_hits
+            && (!film.aovs().is_empty()
+                || !film.geometry_aovs().is_empty()
+                || film.has_cryptomatte()
+                || film.sample_dump().is_some())
+        {
+            warn!("The primary hit cache of the \"directlighting\" integrator doesn't support AOVs, Cryptomatte or sample dumps. Disabling it.");
+            integrator.cache_primary_hits = false;
+        }
+        Box::new(integrator)
     }
 }
 
@@ -69,6 +121,10 @@ impl SamplerIntegrator for DirectLightingIntegrator {
 
     fn preprocess(&mut self, scene: Arc<Scene>, sampler: &mut dyn Sampler) {
         info!("Preprocessing DirectLighting integrator");
+        if self.cache_primary_hits && scene.has_motion() {
+            warn!("The primary hit cache of the \"directlighting\" integrator doesn't support moving primitives. Disabling it.");
+            self.cache_primary_hits = false;
+        }
         if self.light_strategy == LightStrategy::UniformSampleAll {
             // Compute number of samples to use for each light
             for light in &scene.lights {
@@ -93,33 +149,133 @@ impl SamplerIntegrator for DirectLightingIntegrator {
         sampler: &mut dyn Sampler,
         arena: &Allocator<'_>,
         depth: u32,
+    ) -> Spectrum {
+        let hit = scene.intersect(ray);
+        self.li_hit(scene, ray, hit, sampler, arena, depth)
+    }
+
+    /// Render the tile like the renderer does, but keep the primary hit of each pixel's last
+    /// camera ray. When the next sample's camera ray is the same (within `primary_hit_epsilon`),
+    /// its hit is reused rather than traced through the scene again. The rays of a static pinhole
+    /// camera are the same for all the samples of a pixel if the film doesn't jitter them
+    /// (`"bool jitter" "false"`), so that at high sample counts most of the BVH traversals of the
+    /// camera rays are skipped.
+    ///
+    /// This is opt-in (`"bool cacheprimaryhits"`), as it costs a comparison and a copy of the hit
+    /// for every sample, for nothing if the camera rays are all different.
+    fn render_tile(
+        &self,
+        scene: &Scene,
+        tile_bounds: &Bounds2i,
+        sampler: &mut dyn Sampler,
+        camera_ray: &dyn Fn(&CameraSample) -> Ray,
+        film_tile: &mut FilmTile,
+        arena: &mut MemoryArena,
+    ) -> bool {
+        if !self.cache_primary_hits {
+            return false;
+        }
+        for p in tile_bounds {
+            sampler.start_pixel(p);
+            if !self.pixel_bounds.inside_exclusive(&p) {
+                continue;
+            }
+            let mut cached: Option<PrimaryHit> = None;
+            loop {
+                let alloc = arena.allocator();
+                let mut s = sampler.get_camera_sample(p);
+                let p_film = self.sample_placement.place(p, &mut s);
+                let mut ray = camera_ray(&s);
+                primary_hits_reused::inc_total();
+                let hit = match cached {
+                    Some(ref c) if c.matches(&ray, self.primary_hit_epsilon) => {
+                        primary_hits_reused::inc();
+                        ray.t_max = c.ray.t_max;
+                        c.hit.clone().map(|mut isect| {
+                            isect.hit.time = ray.time;
+                            isect
+                        })
+                    }
+                    _ => {
+                        let hit = scene.intersect(&mut ray);
+                        cached = Some(PrimaryHit {
+                            ray,
+                            hit: hit.clone(),
+                        });
+                        hit
+                    }
+                };
+                let l = self.li_hit(scene, &mut ray, hit, sampler, &alloc, 0);
+                renderer::add_camera_sample(
+                    film_tile,
+                    p,
+                    sampler.current_sample_number(),
+                    p_film,
+                    l,
+                );
+                if !sampler.start_next_sample() {
+                    break;
+                }
+            }
+        }
+        true
+    }
+}
+
+impl DirectLightingIntegrator {
+    /// Radiance along `ray`, given its closest `hit`.
+    fn li_hit(
+        &self,
+        scene: &Scene,
+        ray: &mut Ray,
+        hit: Option<SurfaceInteraction>,
+        sampler: &mut dyn Sampler,
+        arena: &Allocator<'_>,
+        depth: u32,
     ) -> Spectrum {
         let mut colour = Spectrum::black();
 
-        match scene.intersect(ray) {
+        match hit {
             Some(mut isect) => {
                 let wo = isect.hit.wo;
 
                 // Compute scattering functions for surface interaction
-                isect.compute_scattering_functions(ray, TransportMode::RADIANCE, false, arena);
-
-                if isect.bsdf.is_none() {
-                    let mut r = isect.spawn_ray(&ray.d);
-                    return self.li(scene, &mut r, sampler, arena, depth);
-                }
-                let bsdf = isect.bsdf.clone().unwrap();
+                let bsdf = match isect.compute_scattering_functions(
+                    ray,
+                    TransportMode::RADIANCE,
+                    false,
+                    arena,
+                ) {
+                    Some(bsdf) => bsdf,
+                    None => {
+                        let mut r = isect.spawn_ray(&ray.d);
+                        return self.li(scene, &mut r, sampler, arena, depth);
+                    }
+                };
 
                 // Compute emitted light if ray hit an area light source
                 colour += isect.le(&wo);
                 if !scene.lights.is_empty() {
                     // Compute direct lighting for DirectLightingIntegrator
                     colour += match self.light_strategy {
-                        LightStrategy::UniformSampleAll => {
-                            uniform_sample_all_light(&isect, scene, sampler, &self.n_light_samples)
-                        }
-                        LightStrategy::UniformSampleOne => {
-                            uniform_sample_one_light(&isect, scene, sampler, None)
-                        }
+                        LightStrategy::UniformSampleAll => uniform_sample_all_light(
+                            &isect,
+                            &bsdf,
+                            scene,
+                            sampler,
+                            &self.n_light_samples,
+                            self.mis_heuristic,
+                            self.secondary_differentials,
+                        ),
+                        LightStrategy::UniformSampleOne => uniform_sample_one_light(
+                            &isect,
+                            &bsdf,
+                            scene,
+                            sampler,
+                            None,
+                            self.mis_heuristic,
+                            self.secondary_differentials,
+                        ),
                     }
                 }
 
@@ -131,9 +287,9 @@ impl SamplerIntegrator for DirectLightingIntegrator {
                 }
             }
             None => {
-                // If we didn't intersect anything, add the backgound radiance from every light
+                // If we didn't intersect anything, add the backgound radiance from the infinite lights
                 colour = scene
-                    .lights
+                    .infinite_lights()
                     .iter()
                     .fold(Spectrum::black(), |c, l| c + l.le(ray));
             }
@@ -142,3 +298,57 @@ impl SamplerIntegrator for DirectLightingIntegrator {
         colour
     }
 }
+
+/// The primary hit of a camera ray, or `None` if it didn't hit anything.
+struct PrimaryHit {
+    ray: Ray,
+    hit: Option<SurfaceInteraction>,
+}
+
+impl PrimaryHit {
+    /// Whether `ray` is the same camera ray, and so has the same hit. The rays' times don't
+    /// matter, as the cache is disabled for scenes with moving primitives (see `preprocess()`).
+    fn matches(&self, ray: &Ray, epsilon: f32) -> bool {
+        (ray.o - self.ray.o).length() <= epsilon
+            && (ray.d - self.ray.d).length() <= epsilon * self.ray.d.length()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::testutil::{render_scene, threads};
+
+    #[test]
+    fn test_cached_primary_hits_render_the_same_image() {
+        let render = |jitter: &str, integrator_params: &str| {
+            let scene = format!(
+                r#"
+LookAt 0 0 5  0 0 0  0 1 0
+Camera "perspective" "float fov" [40]
+Film "image" "integer xresolution" [16] "integer yresolution" [12] "bool jitter" "{}"
+Sampler "02sequence" "integer pixelsamples" [8]
+Integrator "directlighting" "string strategy" "one" {}
+WorldBegin
+AttributeBegin
+Translate 0 3 2
+AreaLightSource "diffuse" "rgb L" [4 4 4]
+Shape "sphere" "float radius" [1]
+AttributeEnd
+Material "matte" "rgb Kd" [0.5 0.5 0.5]
+Shape "sphere" "float radius" [1]
+WorldEnd
+"#,
+                jitter, integrator_params
+            );
+            render_scene(&scene, threads(2))
+        };
+        // The samples of a pixel share their camera ray, and so their hit, without jittering. With
+        // it, none is reused.
+        for jitter in &["false", "true"] {
+            let traced = render(jitter, "");
+            let cached = render(jitter, r#""bool cacheprimaryhits" "true""#);
+            assert!(traced.iter().any(|s| !s.is_black()));
+            assert_eq!(traced, cached, "jitter: {}", jitter);
+        }
+    }
+}
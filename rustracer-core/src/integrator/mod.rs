@@ -1,34 +1,44 @@
 use std::cmp;
 use std::sync::Arc;
 
-use light_arena::Allocator;
-use log::debug;
+use light_arena::{Allocator, MemoryArena};
+use log::{debug, log_enabled, warn, Level};
 
 use crate::bounds::Bounds2i;
-use crate::bsdf::{self, BxDFType};
+use crate::bsdf::{Bsdf, BxDFType};
+use crate::camera::CameraSample;
+use crate::film::FilmTile;
 use crate::interaction::SurfaceInteraction;
-use crate::light::{is_delta_light, Light};
+use crate::light::{is_delta_light, LightFlags, LightRef};
+use crate::lpe::LpeAccumulator;
+use crate::paramset::ParamSet;
 use crate::ray::{Ray, RayDifferential};
 use crate::sampler::Sampler;
-use crate::sampling::{power_heuristic, Distribution1D};
+use crate::sampling::{Distribution1D, MisHeuristic};
 use crate::scene::Scene;
 use crate::spectrum::Spectrum;
-use crate::{Point2f, Vector3f};
+use crate::{PbrtOptions, Point2f, Vector3f};
 
 mod ao;
+mod bake;
 mod directlighting;
 mod normal;
 mod path;
+mod wavefront;
 mod whitted;
 
 pub use self::ao::AmbientOcclusion;
+pub use self::bake::{BakeIntegrator, BakeQuantity};
 pub use self::directlighting::{DirectLightingIntegrator, LightStrategy};
 pub use self::normal::Normal;
 pub use self::path::PathIntegrator;
+pub use self::wavefront::WavefrontPathIntegrator;
 pub use self::whitted::Whitted;
 
 pub fn init_stats() {
+    directlighting::init_stats();
     path::init_stats();
+    wavefront::init_stats();
 }
 
 pub trait SamplerIntegrator: Send + Sync {
@@ -45,128 +55,293 @@ pub trait SamplerIntegrator: Send + Sync {
         depth: u32,
     ) -> Spectrum;
 
-    #[allow(non_snake_case)]
+    /// Same as `li()` for a camera ray, but also accumulate its radiance into `aovs` according to
+    /// their light path expressions. Integrators that don't keep track of the events along their
+    /// paths leave the AOVs black.
+    fn li_aovs(
+        &self,
+        scene: &Scene,
+        ray: &mut Ray,
+        sampler: &mut dyn Sampler,
+        arena: &Allocator<'_>,
+        _aovs: &mut LpeAccumulator<'_>,
+    ) -> Spectrum {
+        self.li(scene, ray, sampler, arena, 0)
+    }
+
+    /// Render all the camera samples of `tile_bounds` into `film_tile` at once, rather than one
+    /// at a time with `li()`. `camera_ray` generates the camera ray for a sample. Returns `false`
+    /// if the integrator doesn't support this, in which case the renderer calls `li()` for each
+    /// sample instead.
+    fn render_tile(
+        &self,
+        _scene: &Scene,
+        _tile_bounds: &Bounds2i,
+        _sampler: &mut dyn Sampler,
+        _camera_ray: &dyn Fn(&CameraSample) -> Ray,
+        _film_tile: &mut FilmTile,
+        _arena: &mut MemoryArena,
+    ) -> bool {
+        false
+    }
+
+    /// Radiance arriving along the perfectly specular reflection of `ray` at `isect`.
     fn specular_reflection(
         &self,
         ray: &mut Ray,
-        isect: &SurfaceInteraction<'_, '_>,
+        isect: &SurfaceInteraction,
         scene: &Scene,
-        bsdf: &bsdf::Bsdf<'_>,
+        bsdf: &Bsdf<'_>,
         sampler: &mut dyn Sampler,
         arena: &Allocator<'_>,
         depth: u32,
     ) -> Spectrum {
-        let flags = BxDFType::BSDF_REFLECTION | BxDFType::BSDF_SPECULAR;
-        let (f, wi, pdf, _bsdf_type) = bsdf.sample_f(&isect.hit.wo, sampler.get_2d(), flags);
-        let ns = &isect.shading.n;
-        if pdf > 0.0 && !f.is_black() && wi.dotn(ns).abs() != 0.0 {
-            let mut r = isect.spawn_ray(&wi);
-            if let Some(diff) = ray.differential {
-                let mut rddiff = RayDifferential {
-                    rx_origin: isect.hit.p + isect.dpdx,
-                    ry_origin: isect.hit.p + isect.dpdy,
-                    ..RayDifferential::default()
-                };
-                // Compute differential reflected direction
-                let dndx = isect.shading.dndu * isect.dudx + isect.dndv * isect.dvdx;
-                let dndy = isect.shading.dndu * isect.dudy + isect.dndv * isect.dvdy;
-                let dwodx = -diff.rx_direction - isect.hit.wo;
-                let dwody = -diff.ry_direction - isect.hit.wo;
-                let dDNdx = dwodx.dotn(ns) + isect.hit.wo.dotn(&dndx);
-                let dDNdy = dwody.dotn(ns) + isect.hit.wo.dotn(&dndy);
-                rddiff.rx_direction =
-                    wi - dwodx + 2.0 * Vector3f::from(isect.hit.wo.dotn(ns) * dndx + dDNdx * *ns);
-                rddiff.ry_direction =
-                    wi - dwody + 2.0 * Vector3f::from(isect.hit.wo.dotn(ns) * dndy + dDNdy * *ns);
-
-                r.differential = Some(rddiff);
-            }
-            let refl = self.li(scene, &mut r, sampler, arena, depth + 1);
-            f * refl * wi.dotn(ns).abs() / pdf
-        } else {
-            Spectrum::black()
+        match specular_reflection_ray(ray, isect, bsdf, sampler) {
+            Some((weight, mut r)) => weight * self.li(scene, &mut r, sampler, arena, depth + 1),
+            None => Spectrum::black(),
         }
     }
 
-    #[allow(non_snake_case)]
+    /// Radiance arriving along the perfectly specular transmission of `ray` at `isect`.
     fn specular_transmission(
         &self,
         ray: &mut Ray,
-        isect: &SurfaceInteraction<'_, '_>,
+        isect: &SurfaceInteraction,
         scene: &Scene,
-        bsdf: &bsdf::Bsdf<'_>,
+        bsdf: &Bsdf<'_>,
         sampler: &mut dyn Sampler,
         arena: &Allocator<'_>,
         depth: u32,
     ) -> Spectrum {
-        let flags = BxDFType::BSDF_TRANSMISSION | BxDFType::BSDF_SPECULAR;
-        let (f, wi, pdf, _bsdf_type) = bsdf.sample_f(&isect.hit.wo, sampler.get_2d(), flags);
-        let ns = &isect.shading.n;
-        if pdf > 0.0 && !f.is_black() && wi.dotn(ns).abs() != 0.0 {
-            let mut r = isect.spawn_ray(&wi);
-            if let Some(diff) = ray.differential {
-                let mut rddiff = RayDifferential {
-                    rx_origin: isect.hit.p + isect.dpdx,
-                    ry_origin: isect.hit.p + isect.dpdy,
-                    ..RayDifferential::default()
-                };
+        match specular_transmission_ray(ray, isect, bsdf, sampler) {
+            Some((weight, mut r)) => weight * self.li(scene, &mut r, sampler, arena, depth + 1),
+            None => Spectrum::black(),
+        }
+    }
+}
 
-                let mut eta = bsdf.eta;
-                let w = -isect.hit.wo;
-                if isect.hit.wo.dotn(ns) < 0.0 {
-                    eta = 1.0 / eta;
-                }
+/// Sample the perfectly specular reflection of `ray` at `isect`: the reflected ray, with its
+/// differentials, and the weight of the radiance arriving along it (`f * |cos| / pdf`). `None` if
+/// the BSDF has no specular reflection.
+#[allow(non_snake_case)]
+pub fn specular_reflection_ray(
+    ray: &Ray,
+    isect: &SurfaceInteraction,
+    bsdf: &Bsdf<'_>,
+    sampler: &mut dyn Sampler,
+) -> Option<(Spectrum, Ray)> {
+    let flags = BxDFType::BSDF_REFLECTION | BxDFType::BSDF_SPECULAR;
+    let (f, wi, pdf, _bsdf_type) = bsdf.sample_f(&isect.hit.wo, sampler.get_2d(), flags);
+    let ns = &isect.shading.n;
+    if pdf > 0.0 && !f.is_black() && wi.dotn(ns).abs() != 0.0 {
+        let mut r = isect.spawn_ray(&wi);
+        if let Some(diff) = ray.differential {
+            let mut rddiff = RayDifferential {
+                rx_origin: isect.hit.p + isect.dpdx,
+                ry_origin: isect.hit.p + isect.dpdy,
+                ..RayDifferential::default()
+            };
+            // Compute differential reflected direction
+            let dndx = isect.shading.dndu * isect.dudx + isect.dndv * isect.dvdx;
+            let dndy = isect.shading.dndu * isect.dudy + isect.dndv * isect.dvdy;
+            let dwodx = -diff.rx_direction - isect.hit.wo;
+            let dwody = -diff.ry_direction - isect.hit.wo;
+            let dDNdx = dwodx.dotn(ns) + isect.hit.wo.dotn(&dndx);
+            let dDNdy = dwody.dotn(ns) + isect.hit.wo.dotn(&dndy);
+            rddiff.rx_direction =
+                wi - dwodx + 2.0 * Vector3f::from(isect.hit.wo.dotn(ns) * dndx + dDNdx * *ns);
+            rddiff.ry_direction =
+                wi - dwody + 2.0 * Vector3f::from(isect.hit.wo.dotn(ns) * dndy + dDNdy * *ns);
+
+            r.differential = Some(rddiff);
+        }
+        Some((f * wi.dotn(ns).abs() / pdf, r))
+    } else {
+        None
+    }
+}
 
-                // Compute differential reflected direction
-                let dndx = isect.shading.dndu * isect.dudx + isect.dndv * isect.dvdx;
-                let dndy = isect.shading.dndu * isect.dudy + isect.dndv * isect.dvdy;
-                let dwodx = -diff.rx_direction - isect.hit.wo;
-                let dwody = -diff.ry_direction - isect.hit.wo;
-                let dDNdx = dwodx.dotn(ns) + isect.hit.wo.dotn(&dndx);
-                let dDNdy = dwody.dotn(ns) + isect.hit.wo.dotn(&dndy);
+/// Sample the perfectly specular transmission of `ray` at `isect`, like
+/// `specular_reflection_ray()`.
+#[allow(non_snake_case)]
+pub fn specular_transmission_ray(
+    ray: &Ray,
+    isect: &SurfaceInteraction,
+    bsdf: &Bsdf<'_>,
+    sampler: &mut dyn Sampler,
+) -> Option<(Spectrum, Ray)> {
+    let flags = BxDFType::BSDF_TRANSMISSION | BxDFType::BSDF_SPECULAR;
+    let (f, wi, pdf, _bsdf_type) = bsdf.sample_f(&isect.hit.wo, sampler.get_2d(), flags);
+    let ns = &isect.shading.n;
+    if pdf > 0.0 && !f.is_black() && wi.dotn(ns).abs() != 0.0 {
+        let mut r = isect.spawn_ray(&wi);
+        if let Some(diff) = ray.differential {
+            let mut rddiff = RayDifferential {
+                rx_origin: isect.hit.p + isect.dpdx,
+                ry_origin: isect.hit.p + isect.dpdy,
+                ..RayDifferential::default()
+            };
+
+            let mut eta = bsdf.eta;
+            let w = -isect.hit.wo;
+            if isect.hit.wo.dotn(ns) < 0.0 {
+                eta = 1.0 / eta;
+            }
 
-                let mu = eta * w.dotn(ns) - wi.dotn(ns);
-                let _dmudx = (eta - (eta * eta * w.dotn(ns)) / wi.dotn(ns)) * dDNdx;
-                let _dmudy = (eta - (eta * eta * w.dotn(ns)) / wi.dotn(ns)) * dDNdy;
+            // Compute differential reflected direction
+            let dndx = isect.shading.dndu * isect.dudx + isect.dndv * isect.dvdx;
+            let dndy = isect.shading.dndu * isect.dudy + isect.dndv * isect.dvdy;
+            let dwodx = -diff.rx_direction - isect.hit.wo;
+            let dwody = -diff.ry_direction - isect.hit.wo;
+            let dDNdx = dwodx.dotn(ns) + isect.hit.wo.dotn(&dndx);
+            let dDNdy = dwody.dotn(ns) + isect.hit.wo.dotn(&dndy);
 
-                rddiff.rx_direction = wi + eta * dwodx - Vector3f::from(mu * dndx + dDNdx * *ns);
-                rddiff.ry_direction = wi + eta * dwody - Vector3f::from(mu * dndy + dDNdy * *ns);
+            let mu = eta * w.dotn(ns) - wi.dotn(ns);
+            let _dmudx = (eta - (eta * eta * w.dotn(ns)) / wi.dotn(ns)) * dDNdx;
+            let _dmudy = (eta - (eta * eta * w.dotn(ns)) / wi.dotn(ns)) * dDNdy;
+
+            rddiff.rx_direction = wi + eta * dwodx - Vector3f::from(mu * dndx + dDNdx * *ns);
+            rddiff.ry_direction = wi + eta * dwody - Vector3f::from(mu * dndy + dDNdy * *ns);
+
+            r.differential = Some(rddiff);
+        }
+        Some((f * wi.dotn(ns).abs() / pdf, r))
+    } else {
+        None
+    }
+}
 
-                r.differential = Some(rddiff);
+/// Parse the integrator parameters selecting the MIS heuristic: `"string misheuristic"` is either
+/// "power" (the default) or "balance", and `"float powerexponent"` is the exponent of the power
+/// heuristic.
+pub fn mis_heuristic(ps: &ParamSet) -> MisHeuristic {
+    let name = ps.find_one_string("misheuristic", "power".into());
+    match name.as_str() {
+        "balance" => MisHeuristic::Balance,
+        "power" => {
+            let beta = ps.find_one_float("powerexponent", 2.0);
+            if beta > 0.0 {
+                MisHeuristic::Power(beta)
+            } else {
+                warn!("\"powerexponent\" must be positive. Got {}. Using 2.", beta);
+                MisHeuristic::default()
             }
-            let refr = self.li(scene, &mut r, sampler, arena, depth + 1);
-            f * refr * wi.dotn(ns).abs() / pdf
+        }
+        _ => {
+            warn!("MIS heuristic \"{}\" unknown. Using \"power\".", name);
+            MisHeuristic::default()
+        }
+    }
+}
+
+/// Type of a bounce along a path, which has its own depth limit (see `BounceLimits`).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BounceType {
+    Diffuse = 0,
+    Glossy = 1,
+    /// Perfectly specular reflection
+    Specular = 2,
+    /// Any kind of transmission
+    Transmission = 3,
+}
+
+impl BounceType {
+    /// Type of a bounce in a direction sampled from the lobes given by `flags`.
+    pub fn from_flags(flags: BxDFType) -> BounceType {
+        if flags.contains(BxDFType::BSDF_TRANSMISSION) {
+            BounceType::Transmission
+        } else if flags.contains(BxDFType::BSDF_SPECULAR) {
+            BounceType::Specular
+        } else if flags.contains(BxDFType::BSDF_GLOSSY) {
+            BounceType::Glossy
         } else {
-            Spectrum::black()
+            BounceType::Diffuse
         }
     }
 }
 
+/// Number of bounces of each type along a path.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct BounceCounts([u8; 4]);
+
+impl BounceCounts {
+    pub fn get(&self, bounce: BounceType) -> u8 {
+        self.0[bounce as usize]
+    }
+
+    /// The counts after one more bounce of the given type.
+    pub fn add(&self, bounce: BounceType) -> BounceCounts {
+        let mut counts = *self;
+        counts.0[bounce as usize] = counts.0[bounce as usize].saturating_add(1);
+        counts
+    }
+}
+
+/// Maximum number of bounces of each type along a path, on top of the overall `"maxdepth"`: a
+/// glass-heavy scene needs long chains of specular bounces, but only a few diffuse ones.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BounceLimits([u8; 4]);
+
+impl BounceLimits {
+    /// No other limit than `max_depth`.
+    pub fn new(max_depth: u8) -> BounceLimits {
+        BounceLimits([max_depth; 4])
+    }
+
+    /// Parse the `"integer maxdiffusedepth"`, `"integer maxglossydepth"`, `"integer
+    /// maxspeculardepth"` and `"integer maxtransmissiondepth"` parameters, which default to
+    /// `max_depth`.
+    pub fn from_params(ps: &ParamSet, opts: &PbrtOptions, max_depth: i32) -> BounceLimits {
+        let limit = |name: &str| {
+            opts.max_depth(ps.find_one_int(name, max_depth))
+                .clamp(0, i32::from(u8::MAX)) as u8
+        };
+        BounceLimits([
+            limit("maxdiffusedepth"),
+            limit("maxglossydepth"),
+            limit("maxspeculardepth"),
+            limit("maxtransmissiondepth"),
+        ])
+    }
+
+    /// Whether a path that went through the given bounces can bounce once more with the given
+    /// type.
+    pub fn allows(&self, counts: &BounceCounts, bounce: BounceType) -> bool {
+        counts.get(bounce) < self.0[bounce as usize]
+    }
+}
+
 pub fn uniform_sample_all_light(
-    it: &SurfaceInteraction<'_, '_>,
+    it: &SurfaceInteraction,
+    bsdf: &Bsdf<'_>,
     scene: &Scene,
     sampler: &mut dyn Sampler,
     n_light_samples: &[usize],
+    heuristic: MisHeuristic,
+    differentials: bool,
 ) -> Spectrum {
     let mut L = Spectrum::black();
     for (j, light) in scene.lights.iter().enumerate() {
         // Accumulate contribution of j_th light to L
         let n_samples = n_light_samples[j];
-        // FIXME find a way to not copy the arrays into a vec...
-        let u_light_array = sampler.get_2d_array(n_samples).map(|a| a.to_vec());
-        let u_scattering_array = sampler.get_2d_array(n_samples).map(|a| a.to_vec());
+        let u_light_array = sampler.next_2d_array(n_samples);
+        let u_scattering_array = sampler.next_2d_array(n_samples);
 
         match (u_scattering_array, u_light_array) {
             (Some(u_scattering_array), Some(u_light_array)) => {
+                let u_scattering_array = sampler.array_2d(u_scattering_array);
+                let u_light_array = sampler.array_2d(u_light_array);
                 let mut Ld = Spectrum::black();
-                for k in 0..n_samples {
+                for (u_scattering, u_light) in u_scattering_array.iter().zip(u_light_array) {
                     Ld += estimate_direct(
                         it,
-                        u_scattering_array[k],
+                        bsdf,
+                        *u_scattering,
                         light,
-                        u_light_array[k],
+                        *u_light,
                         scene,
-                        sampler,
+                        heuristic,
+                        differentials,
                     );
                 }
                 L += Ld / n_samples as f32;
@@ -175,7 +350,16 @@ pub fn uniform_sample_all_light(
                 // Use a single sample for illumination from light
                 let u_light = sampler.get_2d();
                 let u_scattering = sampler.get_2d();
-                L += estimate_direct(it, u_scattering, light, u_light, scene, sampler);
+                L += estimate_direct(
+                    it,
+                    bsdf,
+                    u_scattering,
+                    light,
+                    u_light,
+                    scene,
+                    heuristic,
+                    differentials,
+                );
             }
         }
     }
@@ -183,19 +367,78 @@ pub fn uniform_sample_all_light(
     L
 }
 
+/// Direct lighting at a path vertex, with the part of it that is scattered by the diffuse lobes of
+/// the BSDF, so that light path expressions can tell diffuse and specular reflection apart (see
+/// the `lpe` module).
+#[derive(Debug, Clone, Copy, Default)]
+pub struct DirectLight {
+    pub total: Spectrum,
+    /// Only computed when requested, black otherwise
+    pub diffuse: Spectrum,
+    /// ID of the sampled light, if any
+    pub light: Option<u32>,
+    /// Parts of `total` found by sampling the light and by sampling the BSDF
+    pub light_sample: StrategySample,
+    pub bsdf_sample: StrategySample,
+}
+
+/// Contribution of one of the sampling strategies of direct lighting, with its MIS weight
+/// applied, e.g. to tell which strategy the noise comes from (see the `sampledump` module).
+#[derive(Debug, Clone, Copy, Default)]
+pub struct StrategySample {
+    pub value: Spectrum,
+    pub mis_weight: f32,
+}
+
+/// Estimate direct lighting at `it` from a single light chosen at random (according to `distrib`
+/// if provided, uniformly otherwise).
+///
+/// This always consumes one 1D sample (light selection) followed by two 2D samples (light and
+/// scattering samples) from `sampler`, even if no light ends up being sampled, so that the
+/// integrators can rely on a fixed number of dimensions per bounce.
 pub fn uniform_sample_one_light<'a, D: Into<Option<&'a Distribution1D>>>(
-    it: &SurfaceInteraction<'_, '_>,
+    it: &SurfaceInteraction,
+    bsdf: &Bsdf<'_>,
     scene: &Scene,
     sampler: &mut dyn Sampler,
     distrib: D,
+    heuristic: MisHeuristic,
+    differentials: bool,
 ) -> Spectrum {
+    uniform_sample_one_light_split(
+        it,
+        bsdf,
+        scene,
+        sampler,
+        distrib,
+        heuristic,
+        differentials,
+        false,
+    )
+    .total
+}
+
+/// Same as `uniform_sample_one_light()`, but also computes the diffuse part of the direct
+/// lighting if `split` is true.
+pub fn uniform_sample_one_light_split<'a, D: Into<Option<&'a Distribution1D>>>(
+    it: &SurfaceInteraction,
+    bsdf: &Bsdf<'_>,
+    scene: &Scene,
+    sampler: &mut dyn Sampler,
+    distrib: D,
+    heuristic: MisHeuristic,
+    differentials: bool,
+    split: bool,
+) -> DirectLight {
     let distrib = distrib.into();
     let n_lights = scene.lights.len();
+    let s = sampler.get_1d();
+    let u_light = sampler.get_2d();
+    let u_scattering = sampler.get_2d();
     if n_lights == 0 {
-        Spectrum::black()
+        DirectLight::default()
     } else {
         // Randomly chose a light to sample
-        let s = sampler.get_1d();
         let (light_num, light_pdf) = match distrib {
             Some(distrib) => distrib.sample_discrete(s),
             None => (
@@ -204,42 +447,110 @@ pub fn uniform_sample_one_light<'a, D: Into<Option<&'a Distribution1D>>>(
             ),
         };
 
-        debug!(
-            "sampler.get_1d()={}, n_lights={}, light_num={}, light_pdf={}",
-            s, n_lights, light_num, light_pdf
-        );
+        if log_enabled!(Level::Debug) {
+            debug!(
+                "sampler.get_1d()={}, n_lights={}, light_num={}, light_pdf={}",
+                s, n_lights, light_num, light_pdf
+            );
+        }
 
         if light_pdf == 0.0 {
-            return Spectrum::black();
+            return DirectLight::default();
         }
         let light = &scene.lights[light_num];
-        let u_light = sampler.get_2d();
-        let u_scattering = sampler.get_2d();
-        estimate_direct(it, u_scattering, light, u_light, scene, sampler) / light_pdf
+        let ld = estimate_direct_split(
+            it,
+            bsdf,
+            u_scattering,
+            light,
+            u_light,
+            scene,
+            heuristic,
+            differentials,
+            split,
+        );
+        DirectLight {
+            total: ld.total / light_pdf,
+            diffuse: ld.diffuse / light_pdf,
+            light: Some(light.id()),
+            light_sample: StrategySample {
+                value: ld.light_sample.value / light_pdf,
+                ..ld.light_sample
+            },
+            bsdf_sample: StrategySample {
+                value: ld.bsdf_sample.value / light_pdf,
+                ..ld.bsdf_sample
+            },
+        }
     }
 }
 
+/// Consume the sampler dimensions `uniform_sample_one_light()` would have used, for path vertices
+/// where direct lighting is skipped (e.g. purely specular BSDFs).
+pub fn skip_one_light_sample(sampler: &mut dyn Sampler) {
+    sampler.get_1d();
+    sampler.get_2d();
+    sampler.get_2d();
+}
+
 pub fn estimate_direct(
-    it: &SurfaceInteraction<'_, '_>,
+    it: &SurfaceInteraction,
+    bsdf: &Bsdf<'_>,
     u_scattering: Point2f,
-    light: &Arc<dyn Light>,
+    light: &LightRef,
     u_light: Point2f,
     scene: &Scene,
-    _sampler: &mut dyn Sampler,
+    heuristic: MisHeuristic,
+    differentials: bool,
 ) -> Spectrum {
+    estimate_direct_split(
+        it,
+        bsdf,
+        u_scattering,
+        light,
+        u_light,
+        scene,
+        heuristic,
+        differentials,
+        false,
+    )
+    .total
+}
+
+/// Same as `estimate_direct()`, but also computes the diffuse part of the direct lighting if
+/// `split` is true. Both estimates use the same samples and MIS weights, only the BSDF is
+/// restricted to its diffuse lobes for the latter.
+pub fn estimate_direct_split(
+    it: &SurfaceInteraction,
+    bsdf: &Bsdf<'_>,
+    u_scattering: Point2f,
+    light: &LightRef,
+    u_light: Point2f,
+    scene: &Scene,
+    heuristic: MisHeuristic,
+    differentials: bool,
+    split: bool,
+) -> DirectLight {
     let specular = false;
+    let diffuse_flags =
+        BxDFType::BSDF_DIFFUSE | BxDFType::BSDF_REFLECTION | BxDFType::BSDF_TRANSMISSION;
+    // Diffuse part of the BSDF in direction `wi`
+    let f_diffuse = |wi: &Vector3f| {
+        if split {
+            bsdf.f(&it.hit.wo, wi, diffuse_flags) * wi.dotn(&it.shading.n).abs()
+        } else {
+            Spectrum::black()
+        }
+    };
 
     let bsdf_flags = if specular {
         BxDFType::all()
     } else {
         BxDFType::all() & !BxDFType::BSDF_SPECULAR
     };
-    let mut ld = Spectrum::black();
+    let mut ld = DirectLight::default();
     // Sample light with multiple importance sampling
-    let bsdf = it
-        .bsdf
-        .as_ref()
-        .expect("There should be a BSDF set at this point!");
+    let light_flags = light.flags();
     let (mut li, wi, light_pdf, vis) = light.sample_li(it.into(), u_light);
     // info!(
     //     "EstimateDirect u_light: {} -> Li: {}, wi: {}, pdf: {}",
@@ -258,61 +569,143 @@ pub fn estimate_direct(
             }
             // Add light's contribution to reflected radiance
             if !li.is_black() {
-                if is_delta_light(light.flags()) {
-                    ld += f * li / light_pdf;
+                let weight = if is_delta_light(light_flags) {
+                    1.0
                 } else {
-                    let weight = power_heuristic(1, light_pdf, 1, scattering_pdf);
-                    ld += f * li * weight / light_pdf;
-                }
+                    heuristic.weight(1, light_pdf, 1, scattering_pdf)
+                };
+                let value = f * li * weight / light_pdf;
+                ld.total += value;
+                ld.diffuse += f_diffuse(&wi) * li * weight / light_pdf;
+                ld.light_sample = StrategySample {
+                    value,
+                    mis_weight: weight,
+                };
             }
         }
         // TODO compute phase function for medium interaction when supported
     }
     // Sample BSDF with multiple importance sampling
-    if !is_delta_light(light.flags()) {
+    if !is_delta_light(light_flags) {
         let (mut f, wi, scattering_pdf, sampled_type) =
             bsdf.sample_f(&it.hit.wo, u_scattering, bsdf_flags);
         f *= wi.dotn(&it.shading.n).abs();
         let sampled_specular = sampled_type.contains(BxDFType::BSDF_SPECULAR);
         // TODO compute medium interaction when supported
         if !f.is_black() && scattering_pdf > 0.0 {
-            // Account for light contribution along sampled direction wi
-            let weight = if !sampled_specular {
-                let light_pdf = light.pdf_li(it.into(), &wi);
-                if light_pdf == 0.0 {
-                    return ld;
-                }
-                power_heuristic(1, scattering_pdf, 1, light_pdf)
+            // Find intersection and compute transmittance
+            let mut ray = if differentials {
+                it.spawn_sampled_ray(&wi, scattering_pdf, sampled_specular)
             } else {
-                1.0
+                it.spawn_ray(&wi)
             };
-
-            // Find intersection and compute transmittance
-            let mut ray = it.spawn_ray(&wi);
-            let li = match scene.intersect(&mut ray) {
-                Some(light_isect) => {
+            // Only area lights can be hit, and only infinite lights contribute to escaped rays
+            let (li, light_pdf) = match scene.intersect(&mut ray) {
+                Some(light_isect) if light_flags.contains(LightFlags::AREA) => {
                     // Add light contribution from material sampling
-                    if let Some(area_light) = light_isect.primitive.and_then(|p| p.area_light()) {
-                        // let pa = &*area_light as *const _ as *const usize;
-                        // let pl = &*light as *const _ as *const usize;
-                        // if pa == pl {
-                        if area_light.id() == light.id() {
-                            // info!("  Lights are equal");
-                            light_isect.le(&(-wi))
-                        } else {
-                            Spectrum::black()
-                        }
-                    } else {
-                        Spectrum::black()
+                    match light_isect.area_light.as_ref() {
+                        Some(area_light) if area_light.id() == light.id() => (
+                            light_isect.le(&(-wi)),
+                            light.pdf_li_at(it.into(), &wi, &light_isect.hit),
+                        ),
+                        _ => (Spectrum::black(), 0.0),
                     }
                 }
-                None => light.le(&ray),
+                None if light_flags.contains(LightFlags::INFINITE) => {
+                    (light.le(&ray), light.pdf_li(it.into(), &wi))
+                }
+                _ => (Spectrum::black(), 0.0),
             };
-            if !li.is_black() {
-                ld += f * li * weight / scattering_pdf;
+            if !li.is_black() && (sampled_specular || light_pdf > 0.0) {
+                // Account for light contribution along sampled direction wi
+                let weight = if !sampled_specular {
+                    heuristic.weight(1, scattering_pdf, 1, light_pdf)
+                } else {
+                    1.0
+                };
+                let value = f * li * weight / scattering_pdf;
+                ld.total += value;
+                ld.bsdf_sample = StrategySample {
+                    value,
+                    mis_weight: weight,
+                };
+                if !sampled_specular {
+                    ld.diffuse += f_diffuse(&wi) * li * weight / scattering_pdf;
+                }
             }
         }
     }
 
+    ld.light = Some(light.id());
     ld
 }
+
+#[cfg(test)]
+mod tests {
+    use crate::testutil::{area_light_scene, mean, render_scene, threads};
+
+    #[test]
+    fn test_bounce_limits_apply_per_bounce_type() {
+        // Mean brightness of a mirror ball reflecting a white environment
+        let render = |integrator: &str| {
+            let scene = format!(
+                r#"
+LookAt 0 0 5  0 0 0  0 1 0
+Camera "perspective" "float fov" [10]
+Film "image" "integer xresolution" [8] "integer yresolution" [8]
+Sampler "02sequence" "integer pixelsamples" [1]
+Integrator {}
+WorldBegin
+LightSource "infinite" "rgb L" [1 1 1]
+Material "mirror" "rgb Kr" [0.5 0.5 0.5]
+Shape "sphere" "float radius" [1]
+WorldEnd
+"#,
+                integrator
+            );
+            mean(&render_scene(&scene, threads(1)))
+        };
+        for integrator in ["\"path\"", "\"whitted\""] {
+            let reflected = render(integrator);
+            assert!(
+                (reflected - 0.5).abs() < 0.01,
+                "{}: {}",
+                integrator,
+                reflected
+            );
+            // Other types of bounces don't matter
+            let no_diffuse = render(&format!(
+                r#"{} "integer maxdiffusedepth" [0] "integer maxtransmissiondepth" [0]"#,
+                integrator
+            ));
+            assert_eq!(no_diffuse, reflected, "{}", integrator);
+            let no_specular = render(&format!(r#"{} "integer maxspeculardepth" [0]"#, integrator));
+            assert!(no_specular < 1e-6, "{}: {}", integrator, no_specular);
+        }
+    }
+
+    #[test]
+    fn test_mis_heuristics_converge_to_the_same_image() {
+        let render = |integrator_params| {
+            mean(&render_scene(
+                &area_light_scene("", integrator_params),
+                threads(2),
+            ))
+        };
+        let power = render("");
+        assert!(power > 0.0);
+        for params in &[
+            r#""string misheuristic" "balance""#,
+            r#""string misheuristic" "power" "float powerexponent" [4]"#,
+        ] {
+            let other = render(params);
+            assert!(
+                (power - other).abs() < 0.02 * power,
+                "power: {}, {}: {}",
+                power,
+                params,
+                other
+            );
+        }
+    }
+}
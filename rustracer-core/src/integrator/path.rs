@@ -1,33 +1,94 @@
 use std::sync::Arc;
 
 use light_arena::Allocator;
-use log::{debug, error};
+use log::{debug, error, log_enabled, warn, Level};
 
 use crate::bounds::Bounds2i;
-use crate::bsdf::BxDFType;
+use crate::bsdf::{Bsdf, BxDFType};
 use crate::camera::Camera;
-use crate::integrator::{uniform_sample_one_light, SamplerIntegrator};
+use crate::integrator::{
+    mis_heuristic, skip_one_light_sample, uniform_sample_one_light_split, BounceCounts,
+    BounceLimits, BounceType, SamplerIntegrator,
+};
+use crate::interaction::SurfaceInteraction;
 use crate::lightdistrib::{LightDistribution, SpatialLightDistribution, UniformLightDistribution};
+use crate::lpe::{Event, LpeAccumulator, LpePath};
 use crate::material::TransportMode;
 use crate::paramset::ParamSet;
 use crate::ray::Ray;
+use crate::sampledump::Strategy;
 use crate::sampler::Sampler;
+use crate::sampling::MisHeuristic;
 use crate::scene::Scene;
 use crate::spectrum::Spectrum;
+use crate::{PbrtOptions, Point2f};
 
 stat_percent!("Integrator/Zero-radiance paths", zero_radiance_paths);
 stat_int_distribution!("Integrator/Path length", path_length);
+stat_counter!("Integrator/Paths split", paths_split);
 pub fn init_stats() {
     zero_radiance_paths::init();
     path_length::init();
+    paths_split::init();
 }
 
+/// Largest number of subpaths a path can be split into at once.
+const MAX_SPLIT_FACTOR: u32 = 8;
+/// Largest number of bounces at which paths can be split. As splits compound, a camera ray can
+/// turn into as many as `MAX_SPLIT_FACTOR ^ MAX_SPLIT_DEPTH` paths.
+const MAX_SPLIT_DEPTH: u8 = 3;
+
+/// Unidirectional path tracer with next event estimation and Russian roulette.
+///
+/// To make the most of low-discrepancy samplers, each path consumes the sampler's dimensions in a
+/// fixed order, regardless of which branches are taken. Samplers like `ZeroTwoSequence` count
+/// their 1D and 2D dimensions separately, so the layout is:
+///
+/// * camera sample: 2D dimensions 0 (film position) and 1 (lens), 1D dimension 0 (time);
+/// * then for each bounce `b`:
+///   * light selection: 1D dimension `1 + 2 * b`
+///   * light sample: 2D dimension `2 + 3 * b`
+///   * scattering sample for direct lighting: 2D dimension `3 + 3 * b`
+///   * BSDF sample for the next direction: 2D dimension `4 + 3 * b`
+///   * Russian roulette: 1D dimension `2 + 2 * b`
+///
+/// Dimensions past the sampler's `"integer dimensions"` are drawn at random.
+///
+/// Samples that end up unused (direct lighting on purely specular surfaces, Russian roulette in
+/// the first bounces) are still drawn. Medium boundaries don't count as a bounce and consume no
+/// samples.
+///
+/// For light path expressions, each vertex is classified by the lobe sampled to continue the
+/// path, except for direct lighting which is split between the diffuse and the other lobes of
+/// the BSDF.
+///
+/// Paths can be split into `"integer splitfactor"` subpaths at the vertices of their first
+/// `"integer maxsplitdepth"` bounces where the direct lighting (weighted by the path's
+/// throughput) is brighter than `"float splitthreshold"`, e.g. on a glossy surface reflecting a
+/// bright light, which reduces the variance of the indirect lighting there. Both the split factor
+/// and the split depth are capped (see `MAX_SPLIT_FACTOR` and `MAX_SPLIT_DEPTH`), as the number
+/// of paths grows exponentially with them. The subpaths share
+/// the path's weight and are traced after it, so their later bounces use the sampler's padding
+/// dimensions rather than the layout above.
+///
+/// Besides `"integer maxdepth"`, the number of bounces of each type can be limited (see
+/// `BounceLimits`).
 pub struct PathIntegrator {
     pixel_bounds: Bounds2i,
     max_ray_depth: u8,
+    bounce_limits: BounceLimits,
     rr_threshold: f32,
     light_sampling_strategy: String,
     light_distribution: Option<Box<dyn LightDistribution>>,
+    mis_heuristic: MisHeuristic,
+    /// Give the BSDF-sampled rays approximate differentials
+    secondary_differentials: bool,
+    /// Number of subpaths bright paths are split into (1 to disable splitting)
+    split_factor: u32,
+    /// Paths are only split at the vertices of their first `max_split_depth` bounces
+    max_split_depth: u8,
+    /// Luminance of the throughput-weighted direct lighting above which a path is split
+    split_threshold: f32,
 }
 
 impl PathIntegrator {
@@ -40,14 +101,24 @@ impl PathIntegrator {
         PathIntegrator {
             pixel_bounds,
             max_ray_depth: max_ray_depth as u8,
+            bounce_limits: BounceLimits::new(max_ray_depth as u8),
             rr_threshold,
             light_sampling_strategy,
             light_distribution: None,
+            mis_heuristic: MisHeuristic::default(),
+            secondary_differentials: false,
+            split_factor: 1,
+            max_split_depth: 1,
+            split_threshold: 1.0,
         }
     }
 
-    pub fn create(params: &ParamSet, camera: &dyn Camera) -> Box<dyn SamplerIntegrator> {
-        let max_depth = params.find_one_int("maxdepth", 5);
+    pub fn create(
+        params: &ParamSet,
+        camera: &dyn Camera,
+        opts: &PbrtOptions,
+    ) -> Box<dyn SamplerIntegrator> {
+        let max_depth = opts.max_depth(params.find_one_int("maxdepth", 5));
         let rr_threshold = params.find_one_float("rrthreshold", 1.0);
         let light_strategy = params.find_one_string("lightsamplestrategy", "spatial".into());
         let pb = params.find_int("pixelbounds");
@@ -69,12 +140,29 @@ impl PathIntegrator {
             }
         }
 
-        Box::new(PathIntegrator::new(
-            pixel_bounds,
-            max_depth,
-            rr_threshold,
-            light_strategy,
-        ))
+        let mut integrator =
+            PathIntegrator::new(pixel_bounds, max_depth, rr_threshold, light_strategy);
+        integrator.mis_heuristic = mis_heuristic(params);
+        integrator.bounce_limits = BounceLimits::from_params(params, opts, max_depth);
+        integrator.secondary_differentials = opts.secondary_differentials;
+        let split_factor = params.find_one_int("splitfactor", 1).max(1) as u32;
+        if split_factor > MAX_SPLIT_FACTOR {
+            warn!(
+                "\"splitfactor\" {} is too large, using {}",
+                split_factor, MAX_SPLIT_FACTOR
+            );
+        }
+        integrator.split_factor = split_factor.min(MAX_SPLIT_FACTOR);
+        let max_split_depth = params.find_one_int("maxsplitdepth", 1).max(0);
+        if max_split_depth > i32::from(MAX_SPLIT_DEPTH) {
+            warn!(
+                "\"maxsplitdepth\" {} is too large, using {}",
+                max_split_depth, MAX_SPLIT_DEPTH
+            );
+        }
+        integrator.max_split_depth = max_split_depth.min(i32::from(MAX_SPLIT_DEPTH)) as u8;
+        integrator.split_threshold = params.find_one_float("splitthreshold", 1.0);
+        Box::new(integrator)
     }
 }
 
@@ -101,56 +189,139 @@ impl SamplerIntegrator for PathIntegrator {
         arena: &Allocator<'_>,
         _depth: u32,
     ) -> Spectrum {
+        self.li_aovs(scene, r, sampler, arena, &mut LpeAccumulator::new(&[]))
+    }
+
+    fn li_aovs(
+        &self,
+        scene: &Scene,
+        r: &mut Ray,
+        sampler: &mut dyn Sampler,
+        arena: &Allocator<'_>,
+        aovs: &mut LpeAccumulator<'_>,
+    ) -> Spectrum {
+        let camera_path = PathState {
+            ray: *r,
+            beta: Spectrum::white(),
+            specular_bounce: false,
+            bounces: 0,
+            bounce_counts: BounceCounts::default(),
+            eta_scale: 1.0,
+            lpe_path: aovs.camera_path(),
+        };
+        let mut split_paths = Vec::new();
+        let (mut l, bounces) =
+            self.trace(scene, camera_path, sampler, arena, aovs, &mut split_paths);
+        while let Some(path) = split_paths.pop() {
+            l += self
+                .trace(scene, path, sampler, arena, aovs, &mut split_paths)
+                .0;
+        }
+
+        path_length::report_value(u64::from(bounces));
+        aovs.set_path_length(u32::from(bounces));
+        l
+    }
+}
+
+/// A path being traced: the ray leaving its last vertex, and what was carried along it up to
+/// there.
+#[derive(Debug, Clone, Copy)]
+struct PathState {
+    ray: Ray,
+    beta: Spectrum,
+    specular_bounce: bool,
+    bounces: u8,
+    bounce_counts: BounceCounts,
+    // Added after book publication: etaScale tracks the accumulated effect
+    // of radiance scaling due to rays passing through refractive
+    // boundaries (see the derivation on p. 527 of the third edition). We
+    // track this value in order to remove it from beta when we apply
+    // Russian roulette; this is worthwhile, since it lets us sometimes
+    // avoid terminating refracted rays that are about to be refracted back
+    // out of a medium and thus have their beta value increased.
+    eta_scale: f32,
+    lpe_path: LpePath,
+}
+
+impl PathIntegrator {
+    /// Trace a path until it terminates, returning the radiance it carries and its number of
+    /// bounces. The subpaths it splits into are pushed to `split_paths`, to be traced afterwards.
+    fn trace(
+        &self,
+        scene: &Scene,
+        mut path: PathState,
+        sampler: &mut dyn Sampler,
+        arena: &Allocator<'_>,
+        aovs: &mut LpeAccumulator<'_>,
+        split_paths: &mut Vec<PathState>,
+    ) -> (Spectrum, u8) {
         let mut l = Spectrum::black();
-        let mut beta = Spectrum::white();
-        let mut specular_bounce = false;
-        let mut ray = *r;
-        let mut bounces = 0;
-        // Added after book publication: etaScale tracks the accumulated effect
-        // of radiance scaling due to rays passing through refractive
-        // boundaries (see the derivation on p. 527 of the third edition). We
-        // track this value in order to remove it from beta when we apply
-        // Russian roulette; this is worthwhile, since it lets us sometimes
-        // avoid terminating refracted rays that are about to be refracted back
-        // out of a medium and thus have their beta value increased.
-        let mut eta_scale = 1.0;
         loop {
             // Find next path vertex and accumulate contribution
-            debug!(
-                "Path tracer bounce {}, current L={}, beta={}",
-                bounces, l, beta
-            );
+            if log_enabled!(Level::Debug) {
+                debug!(
+                    "Path tracer bounce {}, current L={}, beta={}",
+                    path.bounces, l, path.beta
+                );
+            }
             // Intersect _ray_ with scene and store intersection in _isect_
-            let mut found_intersection = scene.intersect(&mut ray);
+            let mut found_intersection = scene.intersect(&mut path.ray);
 
             // Possibly add emitted light at intersection
-            if bounces == 0 || specular_bounce {
+            if path.bounces == 0 || path.specular_bounce {
                 // Add emitted light at path vertex or from the environment
                 if let Some(ref isect) = found_intersection {
-                    l += beta * isect.le(&(-ray.d));
+                    let le = path.beta * isect.le(&(-path.ray.d));
+                    aovs.add_light(&path.lpe_path, le);
+                    if let Some(ref light) = isect.area_light {
+                        aovs.record(
+                            u32::from(path.bounces),
+                            light.id(),
+                            Strategy::Emission,
+                            1.0,
+                            le,
+                        );
+                    }
+                    l += le;
                 } else {
-                    for light in &scene.infinite_lights {
-                        l += beta * light.le(&ray);
+                    for light in scene.infinite_lights() {
+                        let le = path.beta * light.le(&path.ray);
+                        aovs.add_light(&path.lpe_path, le);
+                        aovs.record(
+                            u32::from(path.bounces),
+                            light.id(),
+                            Strategy::Emission,
+                            1.0,
+                            le,
+                        );
+                        l += le;
                     }
                 }
             }
 
             // Terminate path if ray escaped or `max_depth` was reached
-            if found_intersection.is_none() || bounces >= self.max_ray_depth {
+            if found_intersection.is_none() || path.bounces >= self.max_ray_depth {
                 break;
             }
 
             // Compute scattering functions and skip over medium boundaries
             let isect = found_intersection.as_mut().unwrap();
-            isect.compute_scattering_functions(&ray, TransportMode::RADIANCE, true, arena);
-            if isect.bsdf.is_none() {
-                // If there's no bsdf, it means we've hit the interface between two
-                // different mediums. We simply continue along the same direction.
-                ray = isect.spawn_ray(&ray.d);
-                bounces -= 1;
-                continue;
-            }
-            let bsdf = isect.bsdf.clone().unwrap();
+            let bsdf = match isect.compute_scattering_functions(
+                &path.ray,
+                TransportMode::RADIANCE,
+                true,
+                arena,
+            ) {
+                Some(bsdf) => bsdf,
+                None => {
+                    // If there's no bsdf, it means we've hit the interface between two
+                    // different mediums. We simply continue along the same direction.
+                    path.ray = isect.spawn_ray(&path.ray.d);
+                    path.bounces -= 1;
+                    continue;
+                }
+            };
             let distrib = self
                 .light_distribution
                 .as_ref()
@@ -158,59 +329,201 @@ impl SamplerIntegrator for PathIntegrator {
                 .lookup(&isect.hit.p);
 
             // Sample illumination from lights to find path contribution.
+            let mut ld = Spectrum::black();
             if bsdf.num_components(BxDFType::all() & !BxDFType::BSDF_SPECULAR) > 0 {
                 zero_radiance_paths::inc_total();
-                let ld = beta * uniform_sample_one_light(isect, scene, sampler, distrib);
+                let split = !aovs.values().is_empty();
+                let direct = uniform_sample_one_light_split(
+                    isect,
+                    &bsdf,
+                    scene,
+                    sampler,
+                    distrib,
+                    self.mis_heuristic,
+                    self.secondary_differentials,
+                    split,
+                );
+                ld = path.beta * direct.total;
+                if split {
+                    let diffuse = path.beta * direct.diffuse;
+                    aovs.add_light(&aovs.scatter(&path.lpe_path, Event::Diffuse), diffuse);
+                    aovs.add_light(&aovs.scatter(&path.lpe_path, Event::Specular), ld - diffuse);
+                }
+                if let (Some(light), true) = (direct.light, aovs.is_recording()) {
+                    for (strategy, sample) in [
+                        (Strategy::LightSample, direct.light_sample),
+                        (Strategy::BsdfSample, direct.bsdf_sample),
+                    ] {
+                        aovs.record(
+                            u32::from(path.bounces) + 1,
+                            light,
+                            strategy,
+                            sample.mis_weight,
+                            path.beta * sample.value,
+                        );
+                    }
+                }
                 if ld.is_black() {
                     zero_radiance_paths::inc();
                 }
                 assert!(ld.y() >= 0.0);
                 l += ld;
+            } else {
+                skip_one_light_sample(sampler);
             }
 
-            // Sample BSDF to get new path direction
-            let wo = -ray.d;
-            let (f, wi, pdf, flags) = bsdf.sample_f(&wo, sampler.get_2d(), BxDFType::all());
-            if f.is_black() || pdf <= 0.0 {
-                break;
+            // Split the path where the direct lighting suggests that the light it carries is
+            // high, to spend more samples on the indirect lighting there
+            let n_paths = if path.bounces < self.max_split_depth && ld.y() > self.split_threshold {
+                self.split_factor
+            } else {
+                1
+            };
+
+            // Sample BSDF to get new path direction(s). The subpaths use the same samples, offset
+            // so that they are stratified.
+            let u_bsdf = sampler.get_2d();
+            let u_rr = sampler.get_1d();
+            for i in 1..n_paths {
+                let offset = i as f32 / n_paths as f32;
+                let u = Point2f::new((u_bsdf.x + offset).fract(), u_bsdf.y);
+                if let Some(split_path) = self.scatter(
+                    isect,
+                    &bsdf,
+                    &path,
+                    u,
+                    (u_rr + offset).fract(),
+                    n_paths,
+                    aovs,
+                ) {
+                    split_paths.push(split_path);
+                }
             }
-            debug!("Update beta. beta={}, f={}, pdf={}", beta, f, pdf);
-            beta = beta * f * wi.dotn(&isect.shading.n).abs() / pdf;
-            assert!(beta.y() >= 0.0);
-            // assert!(!beta.y().is_infinite());
-            specular_bounce = flags.contains(BxDFType::BSDF_SPECULAR);
-            if flags.contains(BxDFType::BSDF_SPECULAR)
-                && flags.contains(BxDFType::BSDF_TRANSMISSION)
-            {
-                let eta = bsdf.eta;
-                // Update the term that tracks radiance scaling for refraction
-                // depending on whether the ray is entering or leaving the
-                // medium.
-                eta_scale *= if wo.dotn(&isect.hit.n) > 0.0 {
-                    eta * eta
-                } else {
-                    1.0 / (eta * eta)
-                };
+            if n_paths > 1 {
+                paths_split::inc();
+            }
+            match self.scatter(isect, &bsdf, &path, u_bsdf, u_rr, n_paths, aovs) {
+                Some(next) => path = next,
+                None => break,
             }
+        }
 
-            ray = isect.spawn_ray(&wi);
-            // Account for subsurface scattering, if applicable TODO
+        (l, path.bounces)
+    }
 
-            // Possibly terminate the path with Russian roulette.
-            // Factor out radiance scaling due to refraction in rr_beta.
-            let rr_beta = beta * eta_scale;
-            if rr_beta.max_component_value() < self.rr_threshold && bounces > 3 {
-                let q = (1.0 - rr_beta.max_component_value()).max(0.05);
-                if sampler.get_1d() < q {
-                    break;
-                }
-                beta = beta / (1.0 - q);
-                assert!(!beta.y().is_infinite());
+    /// Continue `path` in a direction sampled from the BSDF of its last vertex, with its weight
+    /// divided between the `n_paths` subpaths it is split into. `None` if the path terminates.
+    fn scatter(
+        &self,
+        isect: &SurfaceInteraction,
+        bsdf: &Bsdf<'_>,
+        path: &PathState,
+        u_bsdf: Point2f,
+        u_rr: f32,
+        n_paths: u32,
+        aovs: &LpeAccumulator<'_>,
+    ) -> Option<PathState> {
+        let wo = -path.ray.d;
+        let (f, wi, pdf, flags) = bsdf.sample_f(&wo, u_bsdf, BxDFType::all());
+        if f.is_black() || pdf <= 0.0 {
+            return None;
+        }
+        let bounce = BounceType::from_flags(flags);
+        if !self.bounce_limits.allows(&path.bounce_counts, bounce) {
+            return None;
+        }
+        if log_enabled!(Level::Debug) {
+            debug!("Update beta. beta={}, f={}, pdf={}", path.beta, f, pdf);
+        }
+        let mut beta = path.beta * f * wi.dotn(&isect.shading.n).abs() / pdf;
+        assert!(beta.y() >= 0.0);
+        // assert!(!beta.y().is_infinite());
+        let specular_bounce = flags.contains(BxDFType::BSDF_SPECULAR);
+        let lpe_path = aovs.scatter(&path.lpe_path, Event::scattering(flags));
+        let mut eta_scale = path.eta_scale;
+        if flags.contains(BxDFType::BSDF_SPECULAR) && flags.contains(BxDFType::BSDF_TRANSMISSION) {
+            let eta = bsdf.eta;
+            // Update the term that tracks radiance scaling for refraction
+            // depending on whether the ray is entering or leaving the
+            // medium.
+            eta_scale *= if wo.dotn(&isect.hit.n) > 0.0 {
+                eta * eta
+            } else {
+                1.0 / (eta * eta)
+            };
+        }
+
+        let ray = if self.secondary_differentials {
+            isect.spawn_sampled_ray(&wi, pdf, specular_bounce)
+        } else {
+            isect.spawn_ray(&wi)
+        };
+        // Account for subsurface scattering, if applicable TODO
+
+        // Possibly terminate the path with Russian roulette.
+        // Factor out radiance scaling due to refraction in rr_beta. This uses the throughput of
+        // the path before it is split, so that splitting doesn't make its subpaths more likely to
+        // be terminated.
+        let rr_beta = beta * eta_scale;
+        if rr_beta.max_component_value() < self.rr_threshold && path.bounces > 3 {
+            let q = (1.0 - rr_beta.max_component_value()).max(0.05);
+            if u_rr < q {
+                return None;
             }
-            bounces += 1;
+            beta = beta / (1.0 - q);
+            assert!(!beta.y().is_infinite());
         }
+        beta = beta / n_paths as f32;
+        Some(PathState {
+            ray,
+            beta,
+            specular_bounce,
+            bounces: path.bounces + 1,
+            bounce_counts: path.bounce_counts.add(bounce),
+            eta_scale,
+            lpe_path,
+        })
+    }
+}
 
-        path_length::report_value(u64::from(bounces));
-        l
+#[cfg(test)]
+mod tests {
+    use crate::testutil::{mean, render_scene, threads};
+
+    #[test]
+    fn test_split_paths_converge_to_the_unsplit_ones() {
+        let render = |split: &str| {
+            let scene = format!(
+                r#"
+LookAt 0 1 5  0 0 0  0 1 0
+Camera "perspective" "float fov" [40]
+Film "image" "integer xresolution" [32] "integer yresolution" [24]
+Sampler "02sequence" "integer pixelsamples" [16]
+Integrator "path" {}
+WorldBegin
+LightSource "point" "point from" [0 3 3] "rgb I" [20 20 20]
+Material "matte" "rgb Kd" [0.5 0.5 0.5]
+Shape "sphere" "float radius" [1]
+Shape "trianglemesh" "integer indices" [0 1 2 0 2 3]
+    "point P" [-10 -1 -10  10 -1 -10  10 -1 10  -10 -1 10]
+WorldEnd
+"#,
+                split
+            );
+            render_scene(&scene, threads(2))
+        };
+        let path = render("");
+        let split = render(
+            r#""integer splitfactor" [4] "integer maxsplitdepth" [2] "float splitthreshold" [0]"#,
+        );
+        assert_ne!(path, split);
+        let (path_mean, split_mean) = (mean(&path), mean(&split));
+        assert!(path_mean > 0.0);
+        assert!(
+            (path_mean - split_mean).abs() < 0.02 * path_mean,
+            "path: {}, split: {}",
+            path_mean,
+            split_mean
+        );
     }
 }
@@ -0,0 +1,514 @@
+use std::sync::Arc;
+
+use light_arena::{Allocator, MemoryArena};
+use log::error;
+
+use crate::bounds::Bounds2i;
+use crate::bsdf::BxDFType;
+use crate::camera::{Camera, CameraSample};
+use crate::film::{FilmTile, SamplePlacement};
+use crate::integrator::{
+    mis_heuristic, skip_one_light_sample, uniform_sample_one_light, SamplerIntegrator,
+};
+use crate::interaction::SurfaceInteraction;
+use crate::lightdistrib::{LightDistribution, SpatialLightDistribution, UniformLightDistribution};
+use crate::material::TransportMode;
+use crate::paramset::ParamSet;
+use crate::ray::Ray;
+use crate::rng::RNG;
+use crate::sampler::{SampleArray, Sampler};
+use crate::sampling::MisHeuristic;
+use crate::scene::Scene;
+use crate::spectrum::Spectrum;
+use crate::{PbrtOptions, Point2f, Point2i};
+
+stat_int_distribution!("Integrator/Wavefront active paths per bounce", active_paths);
+pub fn init_stats() {
+    active_paths::init();
+}
+
+/// Path tracer that advances all the paths of a tile together, one bounce at a time, instead of
+/// tracing each camera ray to completion: at each bounce, all the active rays are intersected
+/// against the scene (sorted by direction so that consecutive traversals touch the same BVH
+/// nodes), and the hits are then shaded in batches grouped by material.
+///
+/// The estimator is the same as `PathIntegrator`'s. The camera samples come from the scene's
+/// sampler, but as the samples of a pixel are all in flight at the same time, the dimensions
+/// used for bounces are drawn from an independent random stream per path.
+pub struct WavefrontPathIntegrator {
+    pixel_bounds: Bounds2i,
+    max_ray_depth: u8,
+    rr_threshold: f32,
+    light_sampling_strategy: String,
+    light_distribution: Option<Box<dyn LightDistribution>>,
+    mis_heuristic: MisHeuristic,
+    /// Give the BSDF-sampled rays approximate differentials
+    secondary_differentials: bool,
+    /// Maximum number of paths in flight at once
+    max_queue_size: usize,
+    /// Placement of the camera samples in their pixels, from the film
+    sample_placement: SamplePlacement,
+}
+
+impl WavefrontPathIntegrator {
+    pub fn new(
+        pixel_bounds: Bounds2i,
+        max_ray_depth: i32,
+        rr_threshold: f32,
+        light_sampling_strategy: String,
+        max_queue_size: usize,
+    ) -> WavefrontPathIntegrator {
+        WavefrontPathIntegrator {
+            pixel_bounds,
+            max_ray_depth: max_ray_depth as u8,
+            rr_threshold,
+            light_sampling_strategy,
+            light_distribution: None,
+            mis_heuristic: MisHeuristic::default(),
+            secondary_differentials: false,
+            max_queue_size: max_queue_size.max(1),
+            sample_placement: SamplePlacement::default(),
+        }
+    }
+
+    pub fn create(
+        params: &ParamSet,
+        camera: &dyn Camera,
+        opts: &PbrtOptions,
+    ) -> Box<dyn SamplerIntegrator> {
+        let max_depth = opts.max_depth(params.find_one_int("maxdepth", 5));
+        let rr_threshold = params.find_one_float("rrthreshold", 1.0);
+        let light_strategy = params.find_one_string("lightsamplestrategy", "spatial".into());
+        let max_queue_size = params.find_one_int("queuesize", 4096);
+        let pb = params.find_int("pixelbounds");
+        let mut pixel_bounds = camera.get_film().get_sample_bounds();
+        if let Some(pb) = pb {
+            if pb.len() != 4 {
+                error!(
+                    "Expected 4 values for \"pixelbounds\" parameter. Got {}.",
+                    pb.len()
+                );
+            } else {
+                pixel_bounds = Bounds2i::intersect(
+                    &pixel_bounds,
+                    &Bounds2i::from_elements(pb[0], pb[2], pb[1], pb[3]),
+                );
+                if pixel_bounds.area() == 0 {
+                    error!("Degenerate \"pixelbounds\" specified. Ignoring.");
+                }
+            }
+        }
+
+        let mut integrator = WavefrontPathIntegrator::new(
+            pixel_bounds,
+            max_depth,
+            rr_threshold,
+            light_strategy,
+            max_queue_size.max(1) as usize,
+        );
+        integrator.mis_heuristic = mis_heuristic(params);
+        integrator.secondary_differentials = opts.secondary_differentials;
+        integrator.sample_placement = camera.get_film().sample_placement();
+        Box::new(integrator)
+    }
+
+    /// Trace all the paths in `queue` to completion, accumulating their radiance in `queue.l`.
+    fn trace(&self, scene: &Scene, queue: &mut PathQueue, alloc: &Allocator<'_>) {
+        let distribution = self
+            .light_distribution
+            .as_ref()
+            .expect("preprocess() should have been called");
+        let mut active: Vec<usize> = (0..queue.len()).collect();
+
+        while !active.is_empty() {
+            active_paths::report_value(active.len() as u64);
+
+            // Intersect all the active rays in bulk. Rays going in the same general direction
+            // tend to visit the BVH nodes in the same order.
+            active.sort_by_key(|&i| direction_octant(&queue.rays[i]));
+            let mut hits: Vec<(usize, SurfaceInteraction)> = Vec::with_capacity(active.len());
+            for &i in &active {
+                let ray = &mut queue.rays[i];
+                let found_intersection = scene.intersect(ray);
+
+                // Possibly add emitted light at intersection
+                if queue.bounces[i] == 0 || queue.specular_bounce[i] {
+                    // Add emitted light at path vertex or from the environment
+                    if let Some(ref isect) = found_intersection {
+                        queue.l[i] += queue.beta[i] * isect.le(&(-ray.d));
+                    } else {
+                        for light in scene.infinite_lights() {
+                            queue.l[i] += queue.beta[i] * light.le(ray);
+                        }
+                    }
+                }
+
+                // Terminate path if ray escaped or `max_depth` was reached
+                if let Some(isect) = found_intersection {
+                    if queue.bounces[i] < self.max_ray_depth {
+                        hits.push((i, isect));
+                    }
+                }
+            }
+
+            // Shade the hits in batches of the same material
+            hits.sort_by_key(|(_, isect)| material_key(isect));
+            active.clear();
+            for (i, isect) in &mut hits {
+                let i = *i;
+                let ray = queue.rays[i];
+                let bsdf = if let Some(bsdf) =
+                    isect.compute_scattering_functions(&ray, TransportMode::RADIANCE, true, alloc)
+                {
+                    bsdf
+                } else {
+                    // If there's no bsdf, it means we've hit the interface between two
+                    // different mediums. We simply continue along the same direction.
+                    queue.rays[i] = isect.spawn_ray(&ray.d);
+                    active.push(i);
+                    continue;
+                };
+                let sampler = &mut queue.samplers[i];
+                let beta = queue.beta[i];
+
+                // Sample illumination from lights to find path contribution.
+                if bsdf.num_components(BxDFType::all() & !BxDFType::BSDF_SPECULAR) > 0 {
+                    let distrib = distribution.lookup(&isect.hit.p);
+                    let ld = beta
+                        * uniform_sample_one_light(
+                            isect,
+                            &bsdf,
+                            scene,
+                            sampler,
+                            distrib,
+                            self.mis_heuristic,
+                            self.secondary_differentials,
+                        );
+                    queue.l[i] += ld;
+                } else {
+                    skip_one_light_sample(sampler);
+                }
+
+                // Sample BSDF to get new path direction
+                let wo = -ray.d;
+                let u_bsdf = sampler.get_2d();
+                let u_rr = sampler.get_1d();
+                let (f, wi, pdf, flags) = bsdf.sample_f(&wo, u_bsdf, BxDFType::all());
+                if f.is_black() || pdf <= 0.0 {
+                    continue;
+                }
+                let mut beta = beta * f * wi.dotn(&isect.shading.n).abs() / pdf;
+                queue.specular_bounce[i] = flags.contains(BxDFType::BSDF_SPECULAR);
+                if flags.contains(BxDFType::BSDF_SPECULAR)
+                    && flags.contains(BxDFType::BSDF_TRANSMISSION)
+                {
+                    let eta = bsdf.eta;
+                    // Update the term that tracks radiance scaling for refraction
+                    // depending on whether the ray is entering or leaving the
+                    // medium.
+                    queue.eta_scale[i] *= if wo.dotn(&isect.hit.n) > 0.0 {
+                        eta * eta
+                    } else {
+                        1.0 / (eta * eta)
+                    };
+                }
+                queue.rays[i] = if self.secondary_differentials {
+                    isect.spawn_sampled_ray(&wi, pdf, queue.specular_bounce[i])
+                } else {
+                    isect.spawn_ray(&wi)
+                };
+
+                // Possibly terminate the path with Russian roulette.
+                // Factor out radiance scaling due to refraction in rr_beta.
+                let rr_beta = beta * queue.eta_scale[i];
+                if rr_beta.max_component_value() < self.rr_threshold && queue.bounces[i] > 3 {
+                    let q = (1.0 - rr_beta.max_component_value()).max(0.05);
+                    if u_rr < q {
+                        continue;
+                    }
+                    beta = beta / (1.0 - q);
+                }
+                queue.beta[i] = beta;
+                queue.bounces[i] += 1;
+                active.push(i);
+            }
+        }
+    }
+
+    /// Trace the paths in `queue` and splat their radiance into `film_tile`.
+    fn flush(
+        &self,
+        scene: &Scene,
+        queue: &mut PathQueue,
+        film_tile: &mut FilmTile,
+        arena: &mut MemoryArena,
+    ) {
+        self.trace(scene, queue, &arena.allocator());
+        for i in 0..queue.len() {
+            let mut l = queue.l[i];
+            if l.has_nan() || l.y() < -1e-5 || l.y().is_infinite() {
+                error!(
+                    "Invalid radiance value {} returned for pixel {}. Setting to black.",
+                    l, queue.pixels[i]
+                );
+                l = Spectrum::black();
+            }
+            film_tile.add_sample(queue.p_film[i], l);
+        }
+        queue.clear();
+    }
+}
+
+impl SamplerIntegrator for WavefrontPathIntegrator {
+    fn pixel_bounds(&self) -> &Bounds2i {
+        &self.pixel_bounds
+    }
+
+    fn preprocess(&mut self, scene: Arc<Scene>, _sampler: &mut dyn Sampler) {
+        self.light_distribution =
+            if self.light_sampling_strategy == "uniform" || scene.lights.len() == 1 {
+                Some(Box::new(UniformLightDistribution::new(&scene)))
+            } else {
+                Some(Box::new(SpatialLightDistribution::new(scene, 64)))
+            }
+    }
+
+    fn li(
+        &self,
+        scene: &Scene,
+        ray: &mut Ray,
+        sampler: &mut dyn Sampler,
+        arena: &Allocator<'_>,
+        _depth: u32,
+    ) -> Spectrum {
+        // Only used for rays traced outside of render_tile(): this is a wavefront of one path,
+        // whose random stream is seeded from the sampler.
+        let mut queue = PathQueue::default();
+        queue.push(Point2i::default(), 0, Point2f::default(), *ray);
+        queue.samplers[0].reseed(u64::from(sampler.get_1d().to_bits()));
+        self.trace(scene, &mut queue, arena);
+        queue.l[0]
+    }
+
+    fn render_tile(
+        &self,
+        scene: &Scene,
+        tile_bounds: &Bounds2i,
+        sampler: &mut dyn Sampler,
+        camera_ray: &dyn Fn(&CameraSample) -> Ray,
+        film_tile: &mut FilmTile,
+        arena: &mut MemoryArena,
+    ) -> bool {
+        let mut queue = PathQueue::default();
+        for p in tile_bounds {
+            sampler.start_pixel(p);
+            if !self.pixel_bounds.inside_exclusive(&p) {
+                continue;
+            }
+            loop {
+                let mut s = sampler.get_camera_sample(p);
+                let p_film = self.sample_placement.place(p, &mut s);
+                let ray = camera_ray(&s);
+                queue.push(p, sampler.current_sample_number(), p_film, ray);
+                if !sampler.start_next_sample() {
+                    break;
+                }
+            }
+            if queue.len() >= self.max_queue_size {
+                self.flush(scene, &mut queue, film_tile, arena);
+            }
+        }
+        self.flush(scene, &mut queue, film_tile, arena);
+        true
+    }
+}
+
+/// State of the paths in flight, stored as a structure of arrays indexed by path.
+#[derive(Default)]
+struct PathQueue {
+    pixels: Vec<Point2i>,
+    p_film: Vec<Point2f>,
+    rays: Vec<Ray>,
+    l: Vec<Spectrum>,
+    beta: Vec<Spectrum>,
+    eta_scale: Vec<f32>,
+    specular_bounce: Vec<bool>,
+    bounces: Vec<u8>,
+    samplers: Vec<PathSampler>,
+}
+
+impl PathQueue {
+    fn len(&self) -> usize {
+        self.rays.len()
+    }
+
+    fn push(&mut self, pixel: Point2i, sample: usize, p_film: Point2f, ray: Ray) {
+        self.pixels.push(pixel);
+        self.p_film.push(p_film);
+        self.rays.push(ray);
+        self.l.push(Spectrum::black());
+        self.beta.push(Spectrum::white());
+        self.eta_scale.push(1.0);
+        self.specular_bounce.push(false);
+        self.bounces.push(0);
+        self.samplers.push(PathSampler::new(pixel, sample));
+    }
+
+    fn clear(&mut self) {
+        self.pixels.clear();
+        self.p_film.clear();
+        self.rays.clear();
+        self.l.clear();
+        self.beta.clear();
+        self.eta_scale.clear();
+        self.specular_bounce.clear();
+        self.bounces.clear();
+        self.samplers.clear();
+    }
+}
+
+/// Index of the octant the ray's direction points to.
+fn direction_octant(ray: &Ray) -> u8 {
+    u8::from(ray.d.x < 0.0) | u8::from(ray.d.y < 0.0) << 1 | u8::from(ray.d.z < 0.0) << 2
+}
+
+/// Key used to group hits that use the same material.
+fn material_key(isect: &SurfaceInteraction) -> usize {
+    isect
+        .material
+        .as_ref()
+        .map_or(0, |m| Arc::as_ptr(m) as *const () as usize)
+}
+
+/// Independent random stream used for the dimensions of a path after the camera sample. Its
+/// sequence is derived from the pixel and sample number so that renders are deterministic.
+#[derive(Clone)]
+struct PathSampler {
+    rng: RNG,
+}
+
+impl PathSampler {
+    fn new(pixel: Point2i, sample: usize) -> PathSampler {
+        let mut rng = RNG::new();
+        rng.set_sequence(
+            (u64::from(pixel.x as u32) << 32 | u64::from(pixel.y as u32))
+                ^ (sample as u64).rotate_left(17),
+        );
+        PathSampler { rng }
+    }
+}
+
+impl Sampler for PathSampler {
+    fn start_pixel(&mut self, _p: Point2i) {}
+
+    fn get_1d(&mut self) -> f32 {
+        self.rng.uniform_f32()
+    }
+
+    fn get_2d(&mut self) -> Point2f {
+        Point2f::new(self.rng.uniform_f32(), self.rng.uniform_f32())
+    }
+
+    fn get_camera_sample(&mut self, p_raster: Point2i) -> CameraSample {
+        // Same order as the other samplers: film, time, lens
+        let u = self.get_2d();
+        let time = self.get_1d();
+        CameraSample {
+            p_film: Point2f::new(p_raster.x as f32 + u.x, p_raster.y as f32 + u.y),
+            p_lens: self.get_2d(),
+            time,
+        }
+    }
+
+    // Sample arrays can't be requested once the paths are in flight, so none are ever available
+    fn request_1d_array(&mut self, _n: usize) {}
+
+    fn request_2d_array(&mut self, _n: usize) {}
+
+    fn round_count(&self, count: usize) -> usize {
+        count
+    }
+
+    fn next_1d_array(&mut self, _n: usize) -> Option<SampleArray> {
+        None
+    }
+
+    fn next_2d_array(&mut self, _n: usize) -> Option<SampleArray> {
+        None
+    }
+
+    fn array_1d(&self, _array: SampleArray) -> &[f32] {
+        &[]
+    }
+
+    fn array_2d(&self, _array: SampleArray) -> &[Point2f] {
+        &[]
+    }
+
+    fn start_next_sample(&mut self) -> bool {
+        false
+    }
+
+    fn reseed(&mut self, seed: u64) {
+        self.rng.set_sequence(seed);
+    }
+
+    fn spp(&self) -> usize {
+        1
+    }
+
+    fn box_clone(&self) -> Box<dyn Sampler> {
+        Box::new(self.clone())
+    }
+
+    fn round_spp(&self, _spp: usize) -> usize {
+        1
+    }
+
+    fn with_spp(&self, _spp: usize) -> Box<dyn Sampler> {
+        Box::new(self.clone())
+    }
+
+    fn current_sample_number(&self) -> usize {
+        0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::testutil::{mean, render_scene, threads};
+
+    #[test]
+    fn test_wavefront_integrator_converges_to_the_path_integrator() {
+        let render = |integrator: &str| {
+            let scene = format!(
+                r#"
+LookAt 0 0 5  0 0 0  0 1 0
+Camera "perspective" "float fov" [40]
+Film "image" "integer xresolution" [32] "integer yresolution" [24]
+Sampler "02sequence" "integer pixelsamples" [16]
+Integrator "{}" "integer queuesize" [64]
+WorldBegin
+LightSource "point" "point from" [0 3 3] "rgb I" [20 20 20]
+LightSource "infinite" "rgb L" [0.2 0.2 0.2]
+Material "matte" "rgb Kd" [0.5 0.2 0.2]
+Shape "sphere" "float radius" [1]
+WorldEnd
+"#,
+                integrator
+            );
+            render_scene(&scene, threads(2))
+        };
+        let path = render("path");
+        let wavefront = render("wavefront");
+        assert_eq!(path.len(), wavefront.len());
+        let (path_mean, wavefront_mean) = (mean(&path), mean(&wavefront));
+        assert!(path_mean > 0.0);
+        assert!(
+            (path_mean - wavefront_mean).abs() < 0.02 * path_mean,
+            "path: {}, wavefront: {}",
+            path_mean,
+            wavefront_mean
+        );
+    }
+}
@@ -2,20 +2,29 @@ use light_arena::Allocator;
 
 use crate::bounds::Bounds2i;
 use crate::bsdf;
-use crate::integrator::SamplerIntegrator;
+use crate::camera::Camera;
+use crate::integrator::{
+    specular_reflection_ray, specular_transmission_ray, BounceCounts, BounceLimits, BounceType,
+    SamplerIntegrator,
+};
 use crate::material::TransportMode;
 use crate::paramset::ParamSet;
 use crate::ray::Ray;
 use crate::sampler::Sampler;
 use crate::scene::Scene;
 use crate::spectrum::Spectrum;
+use crate::PbrtOptions;
 
 /// Simple integrator using the original Whitted recursive algorithm. Only handles direct illumination. See
 /// ```DirectLightingIntegrator``` for a slighly better integrator that uses better light sampling.
+///
+/// The specular reflections and transmissions can be limited separately with
+/// `"integer maxspeculardepth"` and `"integer maxtransmissiondepth"` (see `BounceLimits`).
 pub struct Whitted {
     pixel_bounds: Bounds2i,
     /// Maximum number of times a ray can bounce before being terminated.
     pub max_ray_depth: u8,
+    bounce_limits: BounceLimits,
 }
 
 impl Whitted {
@@ -23,28 +32,32 @@ impl Whitted {
         Whitted {
             max_ray_depth: n,
             pixel_bounds: Bounds2i::new(),
+            bounce_limits: BounceLimits::new(n),
         }
     }
 
-    pub fn create(ps: &ParamSet) -> Box<dyn SamplerIntegrator> {
-        let max_depth = ps.find_one_int("maxdepth", 5);
-        // TODO pixel_bounds
-        Box::new(Self::new(max_depth as u8))
+    pub fn create(
+        ps: &ParamSet,
+        camera: &dyn Camera,
+        opts: &PbrtOptions,
+    ) -> Box<dyn SamplerIntegrator> {
+        let max_depth = opts.max_depth(ps.find_one_int("maxdepth", 5));
+        let mut integrator = Self::new(max_depth as u8);
+        integrator.pixel_bounds = camera.get_film().get_sample_bounds();
+        integrator.bounce_limits = BounceLimits::from_params(ps, opts, max_depth);
+        Box::new(integrator)
     }
-}
 
-impl SamplerIntegrator for Whitted {
-    fn pixel_bounds(&self) -> &Bounds2i {
-        &self.pixel_bounds
-    }
-
-    fn li(
+    /// Radiance arriving along `ray`, for a path that already went through the bounces counted
+    /// by `depth` and `bounces`.
+    fn li_bounces(
         &self,
         scene: &Scene,
         ray: &mut Ray,
         sampler: &mut dyn Sampler,
         arena: &Allocator<'_>,
         depth: u32,
+        bounces: BounceCounts,
     ) -> Spectrum {
         let mut colour = Spectrum::black();
 
@@ -54,14 +67,18 @@ impl SamplerIntegrator for Whitted {
                 let wo = isect.hit.wo;
 
                 // Compute scattering functions for surface interaction
-                isect.compute_scattering_functions(ray, TransportMode::RADIANCE, false, arena);
-
-                // Yuck, there's got to be a better way to do this FIXME
-                if isect.bsdf.is_none() {
-                    let mut r = isect.spawn_ray(&ray.d);
-                    return self.li(scene, &mut r, sampler, arena, depth);
-                }
-                let bsdf = isect.bsdf.clone().unwrap();
+                let bsdf = match isect.compute_scattering_functions(
+                    ray,
+                    TransportMode::RADIANCE,
+                    false,
+                    arena,
+                ) {
+                    Some(bsdf) => bsdf,
+                    None => {
+                        let mut r = isect.spawn_ray(&ray.d);
+                        return self.li_bounces(scene, &mut r, sampler, arena, depth, bounces);
+                    }
+                };
 
                 // Compute emitted light if ray hit an area light source
                 colour += isect.le(&wo);
@@ -81,15 +98,31 @@ impl SamplerIntegrator for Whitted {
                 }
 
                 if depth + 1 < u32::from(self.max_ray_depth) {
-                    colour +=
-                        self.specular_reflection(ray, &isect, scene, &bsdf, sampler, arena, depth);
-                    colour += self
-                        .specular_transmission(ray, &isect, scene, &bsdf, sampler, arena, depth);
+                    for bounce in [BounceType::Specular, BounceType::Transmission] {
+                        let sample = if bounce == BounceType::Specular {
+                            specular_reflection_ray(ray, &isect, &bsdf, sampler)
+                        } else {
+                            specular_transmission_ray(ray, &isect, &bsdf, sampler)
+                        };
+                        if let Some((weight, mut r)) = sample {
+                            if self.bounce_limits.allows(&bounces, bounce) {
+                                colour += weight
+                                    * self.li_bounces(
+                                        scene,
+                                        &mut r,
+                                        sampler,
+                                        arena,
+                                        depth + 1,
+                                        bounces.add(bounce),
+                                    );
+                            }
+                        }
+                    }
                 }
             }
             None => {
                 colour = scene
-                    .lights
+                    .infinite_lights()
                     .iter()
                     .fold(Spectrum::black(), |c, l| c + l.le(ray));
             }
@@ -98,3 +131,20 @@ impl SamplerIntegrator for Whitted {
         colour
     }
 }
+
+impl SamplerIntegrator for Whitted {
+    fn pixel_bounds(&self) -> &Bounds2i {
+        &self.pixel_bounds
+    }
+
+    fn li(
+        &self,
+        scene: &Scene,
+        ray: &mut Ray,
+        sampler: &mut dyn Sampler,
+        arena: &Allocator<'_>,
+        depth: u32,
+    ) -> Spectrum {
+        self.li_bounces(scene, ray, sampler, arena, depth, BounceCounts::default())
+    }
+}
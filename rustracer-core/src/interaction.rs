@@ -1,17 +1,20 @@
-use std::sync::Arc;
+use std::f32::consts::PI;
 
 use light_arena::Allocator;
 use num::zero;
 
 use crate::bsdf::Bsdf;
+use crate::cryptomatte::MatteIds;
 use crate::geometry::{face_forward_n, offset_ray_origin};
-use crate::material::TransportMode;
-use crate::primitive::Primitive;
-use crate::ray::Ray;
+use crate::light::AreaLightRef;
+use crate::material::{MaterialRef, TransportMode};
+use crate::ray::{Ray, RayDifferential};
 use crate::shapes::Shape;
 use crate::spectrum::Spectrum;
 use crate::transform;
-use crate::{Normal3f, Point2f, Point3f, Transform, Vector2f, Vector3f};
+use crate::{
+    coordinate_system, Normal3f, Point2f, Point3f, Transform, Vector2f, Vector3f, SHADOW_EPSILON,
+};
 
 #[derive(Copy, Clone)]
 pub struct Interaction {
@@ -23,6 +26,8 @@ pub struct Interaction {
     pub wo: Vector3f,
     /// Normal
     pub n: Normal3f,
+    /// Time of the interaction, inherited from the ray that generated it
+    pub time: f32,
 }
 
 impl Interaction {
@@ -32,50 +37,82 @@ impl Interaction {
             p_error: zero(),
             wo: zero(),
             n: zero(),
+            time: 0.0,
         }
     }
 
-    pub fn new(p: Point3f, p_error: Vector3f, wo: Vector3f, n: Normal3f) -> Interaction {
+    pub fn new(p: Point3f, p_error: Vector3f, time: f32, wo: Vector3f, n: Normal3f) -> Interaction {
         Interaction {
             p,
             p_error,
             wo: wo.normalize(),
             n,
+            time,
         }
     }
 
-    pub fn from_point(p: &Point3f) -> Interaction {
+    pub fn from_point(p: &Point3f, time: f32) -> Interaction {
         Interaction {
             p: *p,
             p_error: zero(),
             wo: zero(),
             n: zero(),
+            time,
+        }
+    }
+
+    /// Express the interaction in another space. Directions that aren't set (e.g. the `wo` of a
+    /// point sampled on a shape) stay zero.
+    pub fn transform(&self, t: &Transform) -> Interaction {
+        let (p, p_error) = t.transform_point_with_error(&self.p, &self.p_error);
+        let wo = t * &self.wo;
+        let n = t.transform_normal(&self.n);
+        Interaction {
+            p,
+            p_error,
+            wo: if wo.length_squared() > 0.0 {
+                wo.normalize()
+            } else {
+                wo
+            },
+            n: if n.length_squared() > 0.0 {
+                n.normalize()
+            } else {
+                n
+            },
+            time: self.time,
         }
     }
 
     pub fn spawn_ray(&self, dir: &Vector3f) -> Ray {
         assert!(dir.x != 0.0 || dir.y != 0.0 || dir.z != 0.0);
         let o = offset_ray_origin(&self.p, &self.p_error, &self.n, dir);
-        Ray::new(o, *dir)
+        Ray::new(o, *dir).at_time(self.time)
     }
 
     pub fn spawn_ray_to(&self, p: &Point3f) -> Ray {
         let o = offset_ray_origin(&self.p, &self.p_error, &self.n, &(*p - self.p));
         let d = *p - self.p;
         assert!(d.x != 0.0 || d.y != 0.0 || d.z != 0.0);
-        Ray::segment(o, d, 1.0 - 1e-4)
+        Ray::segment(o, d, 1.0 - SHADOW_EPSILON).at_time(self.time)
     }
 
     pub fn spawn_ray_to_interaction(&self, it: &Interaction) -> Ray {
         let origin = offset_ray_origin(&self.p, &self.p_error, &self.n, &(it.p - self.p));
         let target = offset_ray_origin(&it.p, &it.p_error, &it.n, &(origin - it.p));
         let d = target - origin;
-        Ray::segment(origin, d, 1.0 - 1e-4)
+        Ray::segment(origin, d, 1.0 - SHADOW_EPSILON).at_time(self.time)
     }
 }
 
+/// Record of a ray hitting a surface.
+///
+/// It doesn't borrow the scene or the memory arena, so hits can be stored and used after the arena
+/// has been reset (the material and area light of the primitive are kept alive by the record
+/// itself). The BSDF, which is allocated in the arena, is returned separately by
+/// `compute_scattering_functions()`.
 #[derive(Clone)]
-pub struct SurfaceInteraction<'a, 'b> {
+pub struct SurfaceInteraction {
     pub hit: Interaction,
     /// Texture coordinates
     pub uv: Point2f,
@@ -88,39 +125,50 @@ pub struct SurfaceInteraction<'a, 'b> {
     /// Ray differentials
     pub dpdx: Vector3f,
     pub dpdy: Vector3f,
-    ///
+    /// Screen-space derivatives of the (u,v) coordinates
     pub dudx: f32,
     pub dvdx: f32,
     pub dudy: f32,
     pub dvdy: f32,
-    /// Hit shape
-    pub shape: &'a dyn Shape,
-    /// Hit primitive
-    pub primitive: Option<&'a dyn Primitive>,
+    /// Whether the normals of the hit shape are flipped (see `Shape::flip_normals()`)
+    pub flip_normals: bool,
+    /// Material of the hit primitive (none for the boundary between two media)
+    pub material: Option<MaterialRef>,
+    /// Area light of the hit primitive, if it is emissive
+    pub area_light: Option<AreaLightRef>,
     /// Shading information
     pub shading: Shading,
-    /// BSDF of the surface at the intersection point
-    pub bsdf: Option<Arc<Bsdf<'b>>>,
+    /// Shading normal in the object space of the hit shape, before any bump or normal mapping.
+    /// It isn't changed by `transform()`, so the hits of instances have the normal of their
+    /// prototype.
+    pub n_object: Normal3f,
+    /// World space position of the hit point in the previous frame, which differs from `hit.p`
+    /// if its primitive has moved since (see `Scene::end_frame()`)
+    pub p_previous: Point3f,
+    /// Cryptomatte IDs of the hit primitive
+    pub matte_ids: MatteIds,
 }
 
-impl<'a, 'b> SurfaceInteraction<'a, 'b> {
+impl SurfaceInteraction {
     pub fn new(
         p: Point3f,
         p_error: Vector3f,
         uv: Point2f,
+        time: f32,
         wo: Vector3f,
         dpdu: Vector3f,
         dpdv: Vector3f,
         dndu: Normal3f,
         dndv: Normal3f,
         shape: &dyn Shape,
-    ) -> SurfaceInteraction<'_, '_> {
+    ) -> SurfaceInteraction {
+        let flip_normals = shape.flip_normals();
         let mut n = Normal3f::from(dpdu.cross(&dpdv).normalize());
-        if shape.reverse_orientation() ^ shape.transform_swaps_handedness() {
+        if flip_normals {
             n *= -1.0;
         }
         SurfaceInteraction {
-            hit: Interaction::new(p, p_error, wo.normalize(), n),
+            hit: Interaction::new(p, p_error, time, wo.normalize(), n),
             uv,
             dpdu,
             dpdv,
@@ -132,8 +180,9 @@ impl<'a, 'b> SurfaceInteraction<'a, 'b> {
             dvdx: 0.0,
             dudy: 0.0,
             dvdy: 0.0,
-            shape,
-            primitive: None,
+            flip_normals,
+            material: None,
+            area_light: None,
             // Initialize shading geometry from true geometry
             shading: Shading {
                 n,
@@ -142,77 +191,145 @@ impl<'a, 'b> SurfaceInteraction<'a, 'b> {
                 dndu,
                 dndv,
             },
-            bsdf: None,
+            n_object: n,
+            p_previous: p,
+            matte_ids: MatteIds::default(),
+        }
+    }
+
+    /// A minimal hit record with only a position and texture coordinates, enough to evaluate
+    /// textures that don't depend on the local geometry (e.g. the alpha masks tested by shadow
+    /// rays) without computing the partial derivatives.
+    pub fn at_uv(p: Point3f, uv: Point2f, time: f32) -> SurfaceInteraction {
+        SurfaceInteraction {
+            hit: Interaction::from_point(&p, time),
+            uv,
+            dpdu: zero(),
+            dpdv: zero(),
+            dndu: zero(),
+            dndv: zero(),
+            dpdx: zero(),
+            dpdy: zero(),
+            dudx: 0.0,
+            dvdx: 0.0,
+            dudy: 0.0,
+            dvdy: 0.0,
+            flip_normals: false,
+            material: None,
+            area_light: None,
+            shading: Shading {
+                n: zero(),
+                dpdu: zero(),
+                dpdv: zero(),
+                dndu: zero(),
+                dndv: zero(),
+            },
+            n_object: zero(),
+            p_previous: p,
+            matte_ids: MatteIds::default(),
         }
     }
 
     pub fn le(&self, w: &Vector3f) -> Spectrum {
-        self.primitive
-            .and_then(|p| p.area_light())
+        self.area_light
+            .as_ref()
             .map(|light| light.l(self.into(), w))
             .unwrap_or_else(Spectrum::black)
     }
 
-    pub fn transform(&self, t: &Transform) -> SurfaceInteraction<'a, 'b> {
+    pub fn transform(&self, t: &Transform) -> SurfaceInteraction {
         let (p, p_err) = t.transform_point_with_error(&self.hit.p, &self.hit.p_error);
         let mut si = SurfaceInteraction {
             hit: Interaction::new(
                 p,
                 p_err,
+                self.hit.time,
                 (t * &self.hit.wo).normalize(),
                 t.transform_normal(&self.hit.n).normalize(),
             ),
             uv: self.uv,
             dpdu: t * &self.dpdu,
             dpdv: t * &self.dpdv,
-            dndu: zero(),
-            dndv: zero(),
-            dpdx: zero(),
-            dpdy: zero(),
-            dudx: 0.0,
-            dvdx: 0.0,
-            dudy: 0.0,
-            dvdy: 0.0,
-            shape: self.shape,
-            primitive: self.primitive,
+            dndu: t.transform_normal(&self.dndu),
+            dndv: t.transform_normal(&self.dndv),
+            // The (u,v) derivatives are independent of the space the interaction is expressed
+            // in, only the position's need to be transformed.
+            dpdx: t * &self.dpdx,
+            dpdy: t * &self.dpdy,
+            dudx: self.dudx,
+            dvdx: self.dvdx,
+            dudy: self.dudy,
+            dvdy: self.dvdy,
+            flip_normals: self.flip_normals,
+            material: self.material.clone(),
+            area_light: self.area_light.clone(),
             shading: Shading {
                 n: t.transform_normal(&self.shading.n).normalize(),
                 dpdu: t * &self.shading.dpdu,
                 dpdv: t * &self.shading.dpdv,
-                dndu: zero(),
-                dndv: zero(),
+                dndu: t.transform_normal(&self.shading.dndu),
+                dndv: t.transform_normal(&self.shading.dndv),
             },
-            bsdf: self.bsdf.clone(),
+            n_object: self.n_object,
+            p_previous: t * &self.p_previous,
+            matte_ids: self.matte_ids,
         };
         si.shading.n = face_forward_n(&si.shading.n, &si.hit.n);
 
         si
     }
 
-    pub fn compute_scattering_functions(
+    /// Compute the ray differentials at the intersection point and the BSDF of the surface,
+    /// allocated in `arena`. There is no BSDF if the primitive has no material, i.e. if the ray
+    /// hit the boundary between two media.
+    pub fn compute_scattering_functions<'b>(
         &mut self,
         ray: &Ray,
         transport: TransportMode,
         allow_multiple_lobes: bool,
         arena: &'b Allocator<'_>,
-    ) {
+    ) -> Option<Bsdf<'b>> {
         self.compute_differential(ray);
-        if let Some(primitive) = self.primitive {
-            primitive.compute_scattering_functions(self, transport, allow_multiple_lobes, arena);
-        }
+        let material = self.material.clone()?;
+        Some(material.compute_scattering_functions(self, transport, allow_multiple_lobes, arena))
     }
 
     pub fn spawn_ray(&self, dir: &Vector3f) -> Ray {
         assert!(dir.x != 0.0 || dir.y != 0.0 || dir.z != 0.0);
         let o = offset_ray_origin(&self.hit.p, &self.hit.p_error, &self.hit.n, dir);
-        Ray::new(o, *dir)
+        Ray::new(o, *dir).at_time(self.hit.time)
+    }
+
+    /// Spawn a ray in the direction `dir`, sampled with the given solid angle `pdf`, with
+    /// approximate differentials: their origins are offset by the footprint of the incoming ray
+    /// (if `compute_differential()` was called), and their directions spread by the solid angle
+    /// the sample stands for, i.e. `1 / pdf`. Samples from specular lobes aren't spread.
+    pub fn spawn_sampled_ray(&self, dir: &Vector3f, pdf: f32, specular: bool) -> Ray {
+        let mut ray = self.spawn_ray(dir);
+        let d = dir.normalize();
+        // Half-angle of the cone subtending `1 / pdf` steradians, up to a hemisphere
+        let tan_spread = if specular || pdf <= 0.0 {
+            0.0
+        } else {
+            let cos_spread = (1.0 - 1.0 / (2.0 * PI * pdf)).max(0.0);
+            // Wider cones would make the lookups blur the whole map anyway
+            f32::min((1.0 - cos_spread * cos_spread).sqrt() / cos_spread, 1.0)
+        };
+        let (u, v) = coordinate_system(&d);
+        ray.differential = Some(RayDifferential {
+            rx_origin: ray.o + self.dpdx,
+            ry_origin: ray.o + self.dpdy,
+            rx_direction: (d + u * tan_spread).normalize(),
+            ry_direction: (d + v * tan_spread).normalize(),
+        });
+        ray
     }
 
     pub fn spawn_ray_to(&self, p: &Point3f) -> Ray {
         let d = *p - self.hit.p;
         assert!(d.x != 0.0 || d.y != 0.0 || d.z != 0.0);
         let o = offset_ray_origin(&self.hit.p, &self.hit.p_error, &self.hit.n, &d);
-        Ray::segment(o, d, 1.0 - 1e-4)
+        Ray::segment(o, d, 1.0 - SHADOW_EPSILON).at_time(self.hit.time)
     }
 
     pub fn set_shading_geometry(
@@ -225,7 +342,7 @@ impl<'a, 'b> SurfaceInteraction<'a, 'b> {
     ) {
         // Compute shading.n for SurfaceInteraction
         self.shading.n = Normal3f::from(dpdus.cross(dpdvs).normalize());
-        if self.shape.reverse_orientation() ^ self.shape.transform_swaps_handedness() {
+        if self.flip_normals {
             self.shading.n *= -1.0;
         }
         if is_orientation_authoritative {
@@ -314,14 +431,14 @@ impl<'a, 'b> SurfaceInteraction<'a, 'b> {
     }
 }
 
-impl<'a, 'b> From<SurfaceInteraction<'a, 'b>> for Interaction {
-    fn from(si: SurfaceInteraction<'_, '_>) -> Interaction {
+impl From<SurfaceInteraction> for Interaction {
+    fn from(si: SurfaceInteraction) -> Interaction {
         si.hit
     }
 }
 
-impl<'a, 'b> From<&'a SurfaceInteraction<'a, 'b>> for &'a Interaction {
-    fn from(si: &'a SurfaceInteraction<'a, 'b>) -> &'a Interaction {
+impl<'a> From<&'a SurfaceInteraction> for &'a Interaction {
+    fn from(si: &'a SurfaceInteraction) -> &'a Interaction {
         &si.hit
     }
 }
@@ -26,7 +26,7 @@ pub fn sample_catmull_rom_2d(
         let mut value = 0.0;
         for i in 0..4 {
             if weights[i] != 0.0 {
-                value += array[(offset as usize + i) * size2 + idx] * weights[i];
+                value += array[(offset + i) * size2 + idx] * weights[i];
             }
         }
         value
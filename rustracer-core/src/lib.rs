@@ -16,6 +16,8 @@
 
 use std::f32;
 use std::ops::{Add, Mul, Sub};
+use std::path::PathBuf;
+use std::time::Duration;
 
 use num::{Num, One, Signed};
 
@@ -28,33 +30,48 @@ pub mod bounds;
 mod bsdf;
 pub mod bvh;
 pub mod camera;
-mod cie;
+pub mod cancel;
+#[cfg(feature = "capi")]
+pub mod capi;
+pub mod cie;
+pub mod cryptomatte;
 pub mod efloat;
-mod fileutil;
+pub mod fileutil;
 pub mod film;
 pub mod filter;
 mod floatfile;
 mod geometry;
+pub mod geometrydump;
+pub mod imagecompare;
 pub mod imageio;
 pub mod integrator;
 mod interaction;
 mod interpolation;
 pub mod light;
 pub mod lightdistrib;
+pub mod lpe;
 pub mod material;
 pub mod mipmap;
-mod noise;
+pub mod noise;
+pub mod numa;
 mod paramset;
 pub mod pbrt;
 pub mod primitive;
+pub mod proxy;
+pub mod quaternion;
 pub mod ray;
 pub mod renderer;
 pub mod rng;
+pub mod sampledump;
 pub mod sampler;
 pub mod sampling;
 pub mod scene;
 pub mod shapes;
 pub mod spectrum;
+pub mod sppestimate;
+pub mod testscenes;
+#[cfg(test)]
+mod testutil;
 pub mod texture;
 pub mod transform;
 
@@ -65,15 +82,17 @@ pub fn init_stats() {
     bvh::init_stats();
     film::init_stats();
     integrator::init_stats();
+    light::init_stats();
     lightdistrib::init_stats();
     mipmap::init_stats();
+    proxy::init_stats();
     renderer::init_stats();
+    sampler::init_stats();
     scene::init_stats();
     shapes::init_stats();
 }
 
 use geometry::{Normal3, Point2, Point3, Vector2, Vector3};
-use spectrum::Spectrum;
 
 pub type Vector2f = Vector2<f32>;
 pub type Vector3f = Vector3<f32>;
@@ -83,6 +102,7 @@ pub type Point3f = Point3<f32>;
 pub type Point3i = Point3<i32>;
 pub type Normal3f = Normal3<f32>;
 
+pub use api::CAPABILITIES;
 pub use transform::Transform;
 
 pub const INV_2_PI: f32 = 0.15915494309189533577;
@@ -94,10 +114,100 @@ pub fn gamma(n: u32) -> f32 {
 /// Smallest representable float strictly less than 1
 pub const ONE_MINUS_EPSILON: f32 = 0.99999994f32;
 
-#[derive(Debug, Copy, Clone, Default)]
+/// Fraction of a shadow ray segment that is left untested at its far end, so that the surface the
+/// segment ends on doesn't occlude itself.
+pub const SHADOW_EPSILON: f32 = 0.0001;
+
+#[derive(Debug, Clone, Default)]
 pub struct PbrtOptions {
     pub num_threads: u8,
+    /// Render a fast, low quality approximation of the scene: reduced resolution and sample
+    /// counts, shallower paths and a cheaper acceleration structure build.
     pub quick_render: bool,
+    /// Write an image showing the time spent rendering each tile.
+    pub tile_heatmap: bool,
+    /// Keep the scene around after rendering it so that it can be rendered again with a
+    /// different camera (see `pbrt::parse_scene()`).
+    pub interactive: bool,
+    /// Only build the scene at `WorldEnd` without rendering it. The render context is returned
+    /// by `pbrt::parse_scene()` so the caller can render it itself.
+    pub defer_render: bool,
+    /// Pin the render threads to NUMA nodes (see the `numa` module).
+    pub numa: bool,
+    /// Frame number substituted into the `%d` patterns of output filenames (see
+    /// `fileutil::output_filename()`).
+    pub frame: u32,
+    /// Abort building the scene if the memory it uses (according to the memory stats) goes over
+    /// this many bytes.
+    pub max_memory: Option<u64>,
+    /// Move the perspective camera to frame the whole scene, even if the scene positions it (it
+    /// is always done when the scene doesn't).
+    pub auto_frame: bool,
+    /// Interpret the roughness parameters of microfacet materials as the distribution's alpha
+    /// rather than remapping them from [0, 1], unless a material sets `"bool remaproughness"`.
+    pub raw_roughness: bool,
+    /// Cache the tokens of the scene files in binary files next to them, and read them back from
+    /// there while the scene files don't change (see `pbrt::cache`).
+    pub cache_scene: bool,
+    /// Give the rays sampled from the BSDFs approximate differentials, so that what they hit
+    /// (e.g. the environment map) is filtered (see `SurfaceInteraction::spawn_sampled_ray()`).
+    pub secondary_differentials: bool,
+    /// Render progressively until this much time has passed, instead of rendering the number of
+    /// samples per pixel requested by the scene (see `renderer::render_progressive()`).
+    pub time_budget: Option<Duration>,
+    /// Write the world space triangles of the scene to this PLY or OBJ file at `WorldEnd` (see
+    /// the `geometrydump` module).
+    pub dump_geometry: Option<PathBuf>,
+    /// Record every camera sample of some pixels and write them to a CSV or JSON file at the end
+    /// of the render (see the `sampledump` module).
+    pub dump_samples: Option<sampledump::SampleDumpOptions>,
+}
+
+impl PbrtOptions {
+    /// Film resolution (along one axis) to use given the one requested by the scene.
+    pub fn film_resolution(&self, res: i32) -> i32 {
+        if self.quick_render {
+            i32::max(1, res / 4)
+        } else {
+            res
+        }
+    }
+
+    /// Number of samples per pixel to use given the count requested by the scene.
+    pub fn pixel_samples(&self, spp: i32) -> i32 {
+        if self.quick_render {
+            i32::min(spp, 4)
+        } else {
+            spp
+        }
+    }
+
+    /// Number of samples to take for a light given the count requested by the scene.
+    pub fn light_samples(&self, n: i32) -> i32 {
+        if self.quick_render {
+            i32::max(1, n / 4)
+        } else {
+            n
+        }
+    }
+
+    /// Maximum ray depth to use for an integrator given the one requested by the scene.
+    pub fn max_depth(&self, depth: i32) -> i32 {
+        if self.quick_render {
+            i32::min(depth, 2)
+        } else {
+            depth
+        }
+    }
+
+    /// Number of buckets to use when building a BVH with the SAH, given the requested count.
+    pub fn sah_buckets(&self, n: usize) -> usize {
+        if self.quick_render {
+            usize::min(n, 4)
+        } else {
+            n
+        }
+    }
 }
 
 /// Linear interpolation between 2 values.
@@ -178,7 +288,7 @@ where
         let half = len >> 1;
         let middle = first + half;
         // Bisect range based on value of _pred_ at _middle_
-        if pred(middle as usize) {
+        if pred(middle) {
             first = middle + 1;
             len -= half + 1;
         } else {
@@ -274,26 +384,6 @@ where
     }
 }
 
-pub trait Clampable {
-    fn clamp(self, min: f32, max: f32) -> Self;
-}
-
-impl Clampable for f32 {
-    fn clamp(self, min: f32, max: f32) -> f32 {
-        clamp(self, min, max)
-    }
-}
-
-impl Clampable for Spectrum {
-    fn clamp(self, min: f32, max: f32) -> Spectrum {
-        Spectrum::rgb(
-            Clampable::clamp(self.r, min, max),
-            Clampable::clamp(self.g, min, max),
-            Clampable::clamp(self.b, min, max),
-        )
-    }
-}
-
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -343,4 +433,25 @@ mod tests {
         assert_eq!(round_up_pow_2(1023), 1024);
         assert_eq!(round_up_pow_2(1024), 1024);
     }
+
+    #[test]
+    fn test_quick_render_options() {
+        let normal = PbrtOptions::default();
+        assert_eq!(normal.film_resolution(1280), 1280);
+        assert_eq!(normal.pixel_samples(64), 64);
+        assert_eq!(normal.max_depth(5), 5);
+
+        let quick = PbrtOptions {
+            quick_render: true,
+            ..PbrtOptions::default()
+        };
+        assert_eq!(quick.film_resolution(1280), 320);
+        assert_eq!(quick.film_resolution(2), 1);
+        assert_eq!(quick.pixel_samples(64), 4);
+        assert_eq!(quick.pixel_samples(1), 1);
+        assert_eq!(quick.light_samples(16), 4);
+        assert_eq!(quick.light_samples(1), 1);
+        assert_eq!(quick.max_depth(5), 2);
+        assert_eq!(quick.sah_buckets(12), 4);
+    }
 }
@@ -1,18 +1,20 @@
 use std::f32::consts::PI;
 use std::sync::Arc;
 
+use crate::geometry::distance_squared;
 use crate::interaction::Interaction;
 use crate::light::{AreaLight, Light, LightFlags, VisibilityTester};
 use crate::paramset::ParamSet;
-use crate::shapes::Shape;
+use crate::sampling::Distribution1D;
+use crate::shapes::ShapeRef;
 use crate::spectrum::Spectrum;
-use crate::{Point2f, Transform, Vector3f};
+use crate::{Point2f, Transform, Vector3f, ONE_MINUS_EPSILON};
 
 #[derive(Debug)]
 pub struct DiffuseAreaLight {
     id: u32,
     l_emit: Spectrum,
-    shape: Arc<dyn Shape>,
+    shape: ShapeRef,
     n_samples: u32,
     two_sided: bool,
     area: f32,
@@ -21,11 +23,12 @@ pub struct DiffuseAreaLight {
 impl DiffuseAreaLight {
     pub fn new(
         l_emit: Spectrum,
-        shape: Arc<dyn Shape>,
+        shape: ShapeRef,
         n_samples: u32,
         two_sided: bool,
     ) -> DiffuseAreaLight {
         let area = shape.area();
+        super::light_memory::add(size_of::<DiffuseAreaLight>() as u64);
         DiffuseAreaLight {
             id: super::get_next_id(),
             l_emit,
@@ -36,18 +39,41 @@ impl DiffuseAreaLight {
         }
     }
 
+    /// Create an area light from its parameters. `total_area` is the area of all the shapes
+    /// created by the same `Shape` directive (e.g. all the triangles of a mesh), over which the
+    /// `"power"` parameter is distributed.
     pub fn create(
         _light2world: &Transform,
         ps: &ParamSet,
-        shape: Arc<dyn Shape>,
+        shape: ShapeRef,
+        total_area: f32,
     ) -> Arc<DiffuseAreaLight> {
-        let L = ps.find_one_spectrum("L", Spectrum::white());
-        let sc = ps.find_one_spectrum("scale", Spectrum::white());
-        let nsamples = ps.find_one_int("nsamples", 1);
-        let nsamples = ps.find_one_int("samples", nsamples);
-        let two_sided = ps.find_one_bool("twosided", false);
+        let (l_emit, n_samples, two_sided) = emission_params(ps, total_area);
+        Arc::new(Self::new(l_emit, shape, n_samples, two_sided))
+    }
+}
+
+/// Emitted radiance, number of samples and two-sidedness of a diffuse light of area
+/// `total_area`.
+pub(super) fn emission_params(ps: &ParamSet, total_area: f32) -> (Spectrum, u32, bool) {
+    let L = ps.find_one_spectrum("L", Spectrum::white()) * super::temperature_colour(ps);
+    let mut sc = ps.find_one_spectrum("scale", Spectrum::white());
+    let nsamples = ps.find_one_int("nsamples", 1);
+    let nsamples = ps.find_one_int("samples", nsamples);
+    let two_sided = ps.find_one_bool("twosided", false);
+    let sides = if two_sided { 2.0 } else { 1.0 };
+    if let Some(s) = super::power_scale(ps, &L, sides * PI * total_area) {
+        sc *= s;
+    }
+
+    (L * sc, nsamples as u32, two_sided)
+}
 
-        Arc::new(Self::new(L * sc, shape, nsamples as u32, two_sided))
+fn diffuse_l(l_emit: Spectrum, two_sided: bool, si: &Interaction, w: &Vector3f) -> Spectrum {
+    if two_sided || si.n.dot(w) > 0.0 {
+        l_emit
+    } else {
+        Spectrum::black()
     }
 }
 
@@ -89,10 +115,180 @@ impl Light for DiffuseAreaLight {
 
 impl AreaLight for DiffuseAreaLight {
     fn l(&self, si: &Interaction, w: &Vector3f) -> Spectrum {
-        if self.two_sided || si.n.dot(w) > 0.0 {
-            self.l_emit
+        diffuse_l(self.l_emit, self.two_sided, si, w)
+    }
+}
+
+/// A diffuse area light made of all the shapes of an emissive mesh, so that the scene has a
+/// single light for the whole mesh rather than one per triangle. The shapes all have the same
+/// emission, so they are sampled in proportion to their area, which is also their power.
+///
+/// The light is also the area light of each of the shapes.
+#[derive(Debug)]
+pub struct DiffuseMeshLight {
+    id: u32,
+    l_emit: Spectrum,
+    shapes: Vec<ShapeRef>,
+    distribution: Distribution1D,
+    n_samples: u32,
+    two_sided: bool,
+    area: f32,
+}
+
+impl DiffuseMeshLight {
+    pub fn new(
+        l_emit: Spectrum,
+        shapes: Vec<ShapeRef>,
+        n_samples: u32,
+        two_sided: bool,
+    ) -> DiffuseMeshLight {
+        let areas: Vec<f32> = shapes.iter().map(|s| s.area()).collect();
+        // The shapes, and the function and CDF of the distribution
+        super::light_memory::add(
+            (size_of::<DiffuseMeshLight>() + size_of_val(&shapes[..]) + 2 * size_of_val(&areas[..]))
+                as u64,
+        );
+        DiffuseMeshLight {
+            id: super::get_next_id(),
+            l_emit,
+            shapes,
+            distribution: Distribution1D::new(&areas),
+            n_samples,
+            two_sided,
+            area: areas.iter().sum(),
+        }
+    }
+
+    pub fn create(
+        _light2world: &Transform,
+        ps: &ParamSet,
+        shapes: Vec<ShapeRef>,
+    ) -> Arc<DiffuseMeshLight> {
+        let total_area = shapes.iter().map(|s| s.area()).sum();
+        let (l_emit, n_samples, two_sided) = emission_params(ps, total_area);
+        Arc::new(Self::new(l_emit, shapes, n_samples, two_sided))
+    }
+}
+
+impl Light for DiffuseMeshLight {
+    fn id(&self) -> u32 {
+        self.id
+    }
+
+    fn sample_li(
+        &self,
+        si: &Interaction,
+        u: Point2f,
+    ) -> (Spectrum, Vector3f, f32, VisibilityTester) {
+        // Pick a shape, and reuse the first dimension of the sample to sample a point on it
+        let (x, _, index) = self.distribution.sample_continuous(u[0]);
+        let u_shape = Point2f::new(
+            f32::min(
+                x * self.shapes.len() as f32 - index as f32,
+                ONE_MINUS_EPSILON,
+            ),
+            u[1],
+        );
+        let (p_shape, pdf) = self.shapes[index].sample_si(si, u_shape);
+        assert!(!p_shape.p.x.is_nan() && !p_shape.p.y.is_nan() && !p_shape.p.z.is_nan());
+        let wi = (p_shape.p - si.p).normalize();
+        let vis = VisibilityTester::new(*si, p_shape);
+        let pdf = pdf * self.distribution.pdf_discrete(index);
+
+        (self.l(&p_shape, &(-wi)), wi, pdf, vis)
+    }
+
+    /// This has to test the ray against every shape of the mesh: `pdf_li_at()` should be used
+    /// whenever possible.
+    fn pdf_li(&self, si: &Interaction, wi: &Vector3f) -> f32 {
+        self.shapes
+            .iter()
+            .enumerate()
+            .map(|(i, s)| self.distribution.pdf_discrete(i) * s.pdf_wi(si, wi))
+            .sum()
+    }
+
+    fn pdf_li_at(&self, si: &Interaction, wi: &Vector3f, p_light: &Interaction) -> f32 {
+        // Choosing a shape in proportion to its area and then a point uniformly on it is the same
+        // as choosing a point uniformly on the whole mesh
+        let pdf =
+            distance_squared(&si.p, &p_light.p) / (p_light.n.dot(&(-(*wi))).abs() * self.area);
+        if pdf.is_finite() {
+            pdf
         } else {
-            Spectrum::black()
+            0.0
+        }
+    }
+
+    fn n_samples(&self) -> u32 {
+        self.n_samples
+    }
+
+    fn flags(&self) -> LightFlags {
+        LightFlags::AREA
+    }
+
+    fn power(&self) -> Spectrum {
+        let factor = if self.two_sided { 2.0 } else { 1.0 };
+        factor * self.l_emit * PI * self.area
+    }
+}
+
+impl AreaLight for DiffuseMeshLight {
+    fn l(&self, si: &Interaction, w: &Vector3f) -> Spectrum {
+        diffuse_l(self.l_emit, self.two_sided, si, w)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::testutil::{mean, render_scene, threads};
+    use crate::{pbrt, PbrtOptions};
+
+    #[test]
+    fn test_emissive_mesh_light_converges_to_per_triangle_lights() {
+        // An emissive quad made of triangles of very different sizes, facing down onto a sphere
+        let scene = |area_light_params| {
+            format!(
+                r#"
+LookAt 0 0 5  0 0 0  0 1 0
+Camera "perspective" "float fov" [40]
+Film "image" "integer xresolution" [32] "integer yresolution" [24]
+Sampler "02sequence" "integer pixelsamples" [16]
+Integrator "directlighting"
+WorldBegin
+AttributeBegin
+AreaLightSource "diffuse" "rgb L" [4 4 4] {}
+Shape "trianglemesh" "integer indices" [0 1 4  1 2 4  2 3 4  3 0 4]
+    "point P" [-1 3 -1  1 3 -1  1 3 1  -1 3 1  0.8 3 0.8]
+AttributeEnd
+Material "matte" "rgb Kd" [0.5 0.5 0.5]
+Shape "sphere" "float radius" [1]
+WorldEnd
+"#,
+                area_light_params
+            )
+        };
+        let per_shape_params = r#""bool pershape" "true""#;
+        let mesh = mean(&render_scene(&scene(""), threads(2)));
+        let per_shape = mean(&render_scene(&scene(per_shape_params), threads(2)));
+        // A single light for the whole mesh, or one per triangle
+        for (params, n_lights) in [("", 1), (per_shape_params, 4)] {
+            let opts = PbrtOptions {
+                defer_render: true,
+                ..PbrtOptions::default()
+            };
+            let context = pbrt::parse_scene_string(opts, &scene(params))
+                .unwrap()
+                .unwrap();
+            assert_eq!(context.scene.lights.len(), n_lights);
         }
+        assert!(mesh > 0.0);
+        assert!(
+            (mesh - per_shape).abs() < 0.02 * per_shape,
+            "mesh light: {}, per triangle lights: {}",
+            mesh,
+            per_shape
+        );
     }
 }
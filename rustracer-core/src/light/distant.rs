@@ -5,7 +5,7 @@ use num::Zero;
 use parking_lot::RwLock;
 
 use crate::interaction::Interaction;
-use crate::light::{Light, LightFlags, VisibilityTester};
+use crate::light::{Light, LightFlags, LightRef, VisibilityTester};
 use crate::paramset::ParamSet;
 use crate::scene::Scene;
 use crate::spectrum::Spectrum;
@@ -22,6 +22,7 @@ pub struct DistantLight {
 
 impl DistantLight {
     pub fn new(dir: Vector3f, ec: Spectrum) -> DistantLight {
+        super::light_memory::add(size_of::<DistantLight>() as u64);
         DistantLight {
             id: super::get_next_id(),
             dir: dir.normalize(),
@@ -31,8 +32,9 @@ impl DistantLight {
         }
     }
 
-    pub fn create(l2w: &Transform, params: &ParamSet) -> Arc<dyn Light> {
-        let L = params.find_one_spectrum("L", Spectrum::white());
+    pub fn create(l2w: &Transform, params: &ParamSet) -> LightRef {
+        let L =
+            params.find_one_spectrum("L", Spectrum::white()) * super::temperature_colour(params);
         let scale = params.find_one_spectrum("scale", Spectrum::white());
         let from = params.find_one_point3f("from", Point3f::zero());
         let to = params.find_one_point3f("to", Point3f::new(0.0, 0.0, 1.0));
@@ -65,7 +67,7 @@ impl Light for DistantLight {
             self.emission_colour,
             self.dir,
             1.0,
-            VisibilityTester::new(*isect, Interaction::from_point(&p_outside)),
+            VisibilityTester::new(*isect, Interaction::from_point(&p_outside, isect.time)),
         )
     }
 
@@ -12,14 +12,14 @@ use parking_lot::RwLock;
 use crate::geometry::{spherical_phi, spherical_theta};
 use crate::imageio::read_image;
 use crate::interaction::Interaction;
-use crate::light::{Light, LightFlags, VisibilityTester};
-use crate::mipmap::{MIPMap, WrapMode};
+use crate::light::{Light, LightFlags, LightRef, VisibilityTester};
+use crate::mipmap::{FilterMode, MIPMap, WrapMode};
 use crate::paramset::ParamSet;
 use crate::ray::Ray;
 use crate::sampling::Distribution2D;
 use crate::scene::Scene;
 use crate::spectrum::Spectrum;
-use crate::{Point2f, Point2i, Point3f, Transform, Vector3f};
+use crate::{PbrtOptions, Point2f, Point2i, Point3f, Transform, Vector3f};
 
 impl fmt::Debug for InfiniteAreaLight {
     fn fmt(&self, fmt: &mut fmt::Formatter<'_>) -> fmt::Result {
@@ -71,9 +71,10 @@ impl InfiniteAreaLight {
         let l_map = Box::new(MIPMap::new(
             resolution,
             &texels[..],
-            false,
+            FilterMode::Ewa,
             0.0,
             WrapMode::Repeat,
+            None,
         ));
         // initialize sampling PDFs for infinite area light
         // - compute scalar-valued image img from environment map
@@ -99,6 +100,11 @@ impl InfiniteAreaLight {
             width,
             height,
         ));
+        super::light_memory::add(
+            (size_of::<InfiniteAreaLight>()
+                + size_of::<Distribution2D>()
+                + 2 * width * height * size_of::<f32>()) as u64,
+        );
 
         InfiniteAreaLight {
             id: super::get_next_id(),
@@ -112,12 +118,21 @@ impl InfiniteAreaLight {
         }
     }
 
-    pub fn create(l2w: &Transform, params: &ParamSet) -> Arc<dyn Light> {
-        let L = params.find_one_spectrum("L", Spectrum::white());
+    /// Coordinates in the environment map of the world space direction `w`.
+    fn direction_to_st(&self, w: &Vector3f) -> Point2f {
+        let w = (&self.world_to_light * w).normalize();
+        Point2f::new(
+            spherical_phi(&w) * FRAC_1_PI * 0.5,
+            spherical_theta(&w) * FRAC_1_PI,
+        )
+    }
+
+    pub fn create(l2w: &Transform, params: &ParamSet, opts: &PbrtOptions) -> LightRef {
+        let L =
+            params.find_one_spectrum("L", Spectrum::white()) * super::temperature_colour(params);
         let scale = params.find_one_spectrum("scale", Spectrum::white());
         let mapname = params.find_one_filename("mapname", "".to_owned());
-        let n_samples = params.find_one_int("samples", 1);
-        // TODO quickrender
+        let n_samples = opts.light_samples(params.find_one_int("samples", 1));
         Arc::new(InfiniteAreaLight::new(
             l2w.clone(),
             n_samples as u32,
@@ -153,8 +168,8 @@ impl Light for InfiniteAreaLight {
                 Vector3f::new(0.0, 0.0, 0.0),
                 0.0,
                 VisibilityTester::new(
-                    Interaction::from_point(&Point3f::zero()),
-                    Interaction::from_point(&Point3f::zero()),
+                    Interaction::from_point(&Point3f::zero(), isect.time),
+                    Interaction::from_point(&Point3f::zero(), isect.time),
                 ),
             );
         }
@@ -176,7 +191,7 @@ impl Light for InfiniteAreaLight {
         // Return radiance value for infinite light direction
         let world_radius = self.world_radius.read();
         let target = isect.p + wi * (2.0 * *world_radius);
-        let vis = VisibilityTester::new(*isect, Interaction::from_point(&target));
+        let vis = VisibilityTester::new(*isect, Interaction::from_point(&target, isect.time));
         (self.l_map.lookup(uv, 0.0), wi, pdf, vis)
     }
 
@@ -209,12 +224,18 @@ impl Light for InfiniteAreaLight {
     }
 
     fn le(&self, ray: &Ray) -> Spectrum {
-        let w = (&self.world_to_light * &ray.d).normalize();
-        let st = Point2f::new(
-            spherical_phi(&w) * FRAC_1_PI * 0.5,
-            spherical_theta(&w) * FRAC_1_PI,
-        );
-
-        self.l_map.lookup(st, 0.0)
+        let st = self.direction_to_st(&ray.d);
+        // Filter the map over the footprint of the ray, if it has differentials
+        let width = ray.differential.map_or(0.0, |diff| {
+            let extent = |d: &Vector3f| {
+                let st_d = self.direction_to_st(d);
+                // Directions on either side of the seam of the map are close
+                let ds = (st_d.x - st.x).abs();
+                f32::max(f32::min(ds, 1.0 - ds), (st_d.y - st.y).abs())
+            };
+            2.0 * f32::max(extent(&diff.rx_direction), extent(&diff.ry_direction))
+        });
+
+        self.l_map.lookup(st, width)
     }
 }
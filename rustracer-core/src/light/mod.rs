@@ -1,25 +1,35 @@
 use std::f32;
 use std::fmt::Debug;
+use std::sync::Arc;
 
 use bitflags::bitflags;
 use lazy_static::lazy_static;
+use log::warn;
 use parking_lot::Mutex;
 
 use crate::interaction::Interaction;
+use crate::paramset::ParamSet;
 use crate::ray::Ray;
 use crate::scene::Scene;
-use crate::spectrum::Spectrum;
-use crate::{Point2f, Vector3f};
+use crate::spectrum::{blackbody_colour, Spectrum};
+use crate::{Point2f, Vector3f, SHADOW_EPSILON};
 
 mod diffuse;
 mod distant;
 mod infinite;
 mod point;
+mod transformed;
 
-pub use self::diffuse::DiffuseAreaLight;
+pub use self::diffuse::{DiffuseAreaLight, DiffuseMeshLight};
 pub use self::distant::DistantLight;
 pub use self::infinite::InfiniteAreaLight;
 pub use self::point::PointLight;
+pub use self::transformed::TransformedLight;
+
+stat_memory_counter!("Memory/Lights", light_memory);
+pub fn init_stats() {
+    light_memory::init();
+}
 
 bitflags! {
     pub struct LightFlags: u32 {
@@ -50,11 +60,58 @@ impl VisibilityTester {
     }
 
     pub fn unoccluded(&self, scene: &Scene) -> bool {
+        // The spawned ray's direction spans the whole segment from p0 to p1, so we only need to
+        // test up to (just short of) t = 1.
         let r = self.p0.spawn_ray_to_interaction(&self.p1);
-        !scene.intersect_p(&r)
+        !scene.intersect_p_up_to(&r, 1.0 - SHADOW_EPSILON)
     }
 }
 
+/// Colour filter for a light's emission set by its `"float temperature"` parameter (in Kelvin):
+/// the colour of a blackbody at that temperature, normalized to unit luminance so that it only
+/// changes the hue of the light. Returns white if the parameter isn't set.
+pub fn temperature_colour(params: &ParamSet) -> Spectrum {
+    let temperature = params.find_one_float("temperature", 0.0);
+    if temperature > 0.0 {
+        blackbody_colour(temperature)
+    } else {
+        Spectrum::white()
+    }
+}
+
+/// Maximum luminous efficacy (in lm/W), i.e. that of monochromatic light at 555nm.
+pub const MAX_LUMINOUS_EFFICACY: f32 = 683.0;
+
+/// Scale to apply to a light's emission spectrum so that it emits the radiant power specified by
+/// its `"float power"` parameter (in watts), or `None` if that parameter isn't set.
+///
+/// The emission spectrum is normalized to unit luminance first so that it only specifies the
+/// colour of the light. `power_per_unit` is the power emitted by the light for an emission of 1,
+/// e.g. `4π` for an isotropic point light of unit intensity.
+///
+/// If `"float efficacy"` (in lm/W) is also given, `power` is interpreted as the electrical power
+/// of a real-world fixture (e.g. a 60W bulb with an efficacy of 15 lm/W emits 900 lm), and
+/// converted to radiant power using the maximum luminous efficacy of 683 lm/W.
+pub fn power_scale(params: &ParamSet, emission: &Spectrum, power_per_unit: f32) -> Option<f32> {
+    let power = params.find_one_float("power", -1.0);
+    if power < 0.0 {
+        return None;
+    }
+    let efficacy = params.find_one_float("efficacy", MAX_LUMINOUS_EFFICACY);
+    if efficacy <= 0.0 {
+        warn!("Ignoring invalid light efficacy {}", efficacy);
+        return None;
+    }
+    let luminance = emission.y();
+    if luminance <= 0.0 || power_per_unit <= 0.0 {
+        warn!("Can't set the power of a light with no emission");
+        return None;
+    }
+    let radiant_power = power * efficacy / MAX_LUMINOUS_EFFICACY;
+
+    Some(radiant_power / (luminance * power_per_unit))
+}
+
 pub fn get_next_id() -> u32 {
     let mut counter = COUNTER.lock();
     let id = *counter;
@@ -63,6 +120,11 @@ pub fn get_next_id() -> u32 {
     id
 }
 
+/// Shared reference to a light.
+pub type LightRef = Arc<dyn Light>;
+/// Shared reference to an area light, which is also referenced as a `LightRef` by the scene.
+pub type AreaLightRef = Arc<dyn AreaLight>;
+
 pub trait Light: Debug + Send + Sync {
     fn id(&self) -> u32;
     /// Sample the light source
@@ -71,6 +133,9 @@ pub trait Light: Debug + Send + Sync {
     ///  * the sampled direction wi
     ///  * the pdf for that direction
     ///  * A VisibilityTester
+    ///
+    /// The light is sampled at the time of `isect`, and the returned VisibilityTester's end
+    /// points both share that time.
     fn sample_li(
         &self,
         isect: &Interaction,
@@ -79,6 +144,13 @@ pub trait Light: Debug + Send + Sync {
 
     fn pdf_li(&self, si: &Interaction, wi: &Vector3f) -> f32;
 
+    /// Same as `pdf_li()`, for when the ray leaving `si` in direction `wi` is already known to hit
+    /// the light at `p_light`. Lights made of many shapes override this to avoid looking for the
+    /// shape the ray hits.
+    fn pdf_li_at(&self, si: &Interaction, wi: &Vector3f, _p_light: &Interaction) -> f32 {
+        self.pdf_li(si, wi)
+    }
+
     fn preprocess(&self, _scene: &Scene) {}
 
     fn n_samples(&self) -> u32;
@@ -95,3 +167,65 @@ pub trait Light: Debug + Send + Sync {
 pub trait AreaLight: Light {
     fn l(&self, si: &Interaction, w: &Vector3f) -> Spectrum;
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::api::{Array, ParamListEntry, ParamType};
+    use crate::transform::Transform;
+    use std::f32::consts::PI;
+
+    fn params(floats: &[(&str, f32)]) -> ParamSet {
+        params_with_scale(floats, None)
+    }
+
+    fn params_with_scale(floats: &[(&str, f32)], scale: Option<f32>) -> ParamSet {
+        let mut entries: Vec<ParamListEntry> = floats
+            .iter()
+            .map(|(name, v)| {
+                ParamListEntry::new(
+                    ParamType::Float,
+                    (*name).to_owned(),
+                    Array::NumArray(vec![*v]),
+                )
+            })
+            .collect();
+        if let Some(s) = scale {
+            entries.push(ParamListEntry::new(
+                ParamType::Rgb,
+                "scale".to_owned(),
+                Array::NumArray(vec![s, s, s]),
+            ));
+        }
+        let mut ps = ParamSet::default();
+        ps.init(entries);
+        ps
+    }
+
+    #[test]
+    fn test_power_scale() {
+        let white = Spectrum::white();
+        assert_eq!(power_scale(&params(&[]), &white, 1.0), None);
+
+        // Unit luminance, so the scale is just the power
+        let s = power_scale(&params(&[("power", 100.0)]), &white, 1.0).unwrap();
+        assert!((s - 100.0).abs() < 1e-3);
+        // The emission colour only gives the hue of the light
+        let s = power_scale(&params(&[("power", 100.0)]), &(white * 2.0), 4.0).unwrap();
+        assert!((s - 12.5).abs() < 1e-3);
+        // 60W at 15 lm/W
+        let s = power_scale(&params(&[("power", 60.0), ("efficacy", 15.0)]), &white, 1.0).unwrap();
+        assert!((s - 900.0 / MAX_LUMINOUS_EFFICACY).abs() < 1e-3);
+    }
+
+    #[test]
+    fn test_power_multiplies_scale() {
+        // The power sets the scale of the emission, on top of the user-supplied "scale"
+        let ps = params_with_scale(&[("power", 100.0)], Some(2.0));
+        let light = PointLight::create(&Transform::default(), &ps);
+        assert!((light.power().y() - 200.0).abs() < 1e-2);
+
+        let (l_emit, _, _) = diffuse::emission_params(&ps, 1.0);
+        assert!((l_emit.y() * PI - 200.0).abs() < 1e-2);
+    }
+}
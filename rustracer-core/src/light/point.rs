@@ -4,7 +4,7 @@ use std::sync::Arc;
 use num::Zero;
 
 use crate::interaction::Interaction;
-use crate::light::{Light, LightFlags, VisibilityTester};
+use crate::light::{Light, LightFlags, LightRef, VisibilityTester};
 use crate::paramset::ParamSet;
 use crate::spectrum::Spectrum;
 use crate::{Point2f, Point3f, Transform, Vector3f};
@@ -18,6 +18,7 @@ pub struct PointLight {
 
 impl PointLight {
     pub fn new(p: Point3f, ec: Spectrum) -> PointLight {
+        super::light_memory::add(size_of::<PointLight>() as u64);
         PointLight {
             id: super::get_next_id(),
             pos: p,
@@ -25,10 +26,15 @@ impl PointLight {
         }
     }
 
-    pub fn create(l2w: &Transform, params: &ParamSet) -> Arc<dyn Light> {
-        let I = params.find_one_spectrum("I", Spectrum::white());
-        let scale = params.find_one_spectrum("scale", Spectrum::white());
+    pub fn create(l2w: &Transform, params: &ParamSet) -> LightRef {
+        let I =
+            params.find_one_spectrum("I", Spectrum::white()) * super::temperature_colour(params);
+        let mut scale = params.find_one_spectrum("scale", Spectrum::white());
         let p = params.find_one_point3f("from", Point3f::zero());
+        // The radiant intensity is I / 4π (see `sample_li()`), so I is the emitted power.
+        if let Some(s) = super::power_scale(params, &I, 1.0) {
+            scale *= s;
+        }
 
         let t = &Transform::translate(&Vector3f::new(p.x, p.y, p.z)) * l2w;
         Arc::new(PointLight::new(&t * &Point3f::zero(), I * scale))
@@ -48,7 +54,7 @@ impl Light for PointLight {
         let wi = self.pos - isect.p;
         let r2 = wi.length_squared();
         let l_i = self.emission_colour / (4.0 * PI * r2);
-        let vt = VisibilityTester::new(*isect, Interaction::from_point(&self.pos));
+        let vt = VisibilityTester::new(*isect, Interaction::from_point(&self.pos, isect.time));
 
         (l_i, wi.normalize(), 1.0, vt)
     }
@@ -66,6 +72,7 @@ impl Light for PointLight {
     }
 
     fn power(&self) -> Spectrum {
-        4.0 * PI * self.emission_colour
+        // Integral of the intensity `emission_colour / 4π` over the sphere of directions
+        self.emission_colour
     }
 }
@@ -0,0 +1,77 @@
+use crate::interaction::Interaction;
+use crate::light::{Light, LightFlags, LightRef, VisibilityTester};
+use crate::scene::Scene;
+use crate::spectrum::Spectrum;
+use crate::{Point2f, Transform, Vector3f};
+
+/// An area light moved along with the instance of its shapes (see
+/// `Scene::set_primitive_transform()`). It keeps the id of the original light, which is still the
+/// area light of the shapes that are hit, so that the integrators match the two.
+///
+/// The transform should be rigid, possibly with a uniform scale: the solid angles of the light
+/// samples are otherwise distorted, and the power of the light isn't scaled.
+#[derive(Debug)]
+pub struct TransformedLight {
+    light: LightRef,
+    light_to_world: Transform,
+    world_to_light: Transform,
+}
+
+impl TransformedLight {
+    pub fn new(light: LightRef, light_to_world: Transform) -> TransformedLight {
+        TransformedLight {
+            light,
+            world_to_light: light_to_world.inverse(),
+            light_to_world,
+        }
+    }
+}
+
+impl Light for TransformedLight {
+    fn id(&self) -> u32 {
+        self.light.id()
+    }
+
+    fn sample_li(
+        &self,
+        si: &Interaction,
+        u: Point2f,
+    ) -> (Spectrum, Vector3f, f32, VisibilityTester) {
+        let (li, wi, pdf, vis) = self.light.sample_li(&si.transform(&self.world_to_light), u);
+        let wi = (&self.light_to_world * &wi).normalize();
+        let vis = VisibilityTester::new(*si, vis.p1.transform(&self.light_to_world));
+
+        (li, wi, pdf, vis)
+    }
+
+    fn pdf_li(&self, si: &Interaction, wi: &Vector3f) -> f32 {
+        self.light.pdf_li(
+            &si.transform(&self.world_to_light),
+            &(&self.world_to_light * wi).normalize(),
+        )
+    }
+
+    fn pdf_li_at(&self, si: &Interaction, wi: &Vector3f, p_light: &Interaction) -> f32 {
+        self.light.pdf_li_at(
+            &si.transform(&self.world_to_light),
+            &(&self.world_to_light * wi).normalize(),
+            &p_light.transform(&self.world_to_light),
+        )
+    }
+
+    fn preprocess(&self, scene: &Scene) {
+        self.light.preprocess(scene);
+    }
+
+    fn n_samples(&self) -> u32 {
+        self.light.n_samples()
+    }
+
+    fn flags(&self) -> LightFlags {
+        self.light.flags()
+    }
+
+    fn power(&self) -> Spectrum {
+        self.light.power()
+    }
+}
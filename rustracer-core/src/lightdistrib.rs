@@ -135,6 +135,7 @@ impl SpatialLightDistribution {
             let intr = Interaction::new(
                 po,
                 Vector3f::zero(),
+                0.0,
                 Vector3f::new(1.0, 0.0, 0.0),
                 Normal3f::zero(),
             );
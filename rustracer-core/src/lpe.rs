@@ -0,0 +1,389 @@
+//! Light path expressions (a simplified version of OSL's), to split the radiance of the camera
+//! paths into separate images (AOVs) according to how the light reached the camera.
+//!
+//! A path is described by its events: `C` for the camera, then one event per scattering vertex,
+//! `D` for a diffuse lobe or `S` for a glossy or specular one, and finally `L` for the light
+//! (an emissive surface or the environment). An expression is a sequence of these letters, or
+//! `.` for any event, each optionally followed by `*` (any number of times), `+` (at least once)
+//! or `?` (at most once). For example:
+//!
+//! * `CL`: emission seen directly by the camera;
+//! * `CD*L`: light scattered by diffuse lobes only;
+//! * `CS+L`: light seen through reflections and refractions only;
+//! * `C.*L`: all the light, i.e. the beauty image.
+//!
+//! Expressions are matched incrementally as the integrator builds the paths: each one is compiled
+//! to a nondeterministic automaton with one state per item, and the set of states a path prefix
+//! can be in is kept as a bit mask.
+
+use anyhow::{bail, Result};
+
+use crate::bsdf::BxDFType;
+use crate::sampledump::{Contribution, Strategy};
+use crate::spectrum::Spectrum;
+
+/// Maximum number of expressions that can be tracked at once
+pub const MAX_EXPRESSIONS: usize = 16;
+/// Maximum number of items in an expression, so that its states fit in a `u64`
+const MAX_ITEMS: usize = 63;
+
+/// An event along a light path.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Event {
+    Camera,
+    Diffuse,
+    Specular,
+    Light,
+}
+
+impl Event {
+    /// Event for a scattering by a lobe of the given type.
+    pub fn scattering(lobe: BxDFType) -> Event {
+        if lobe.contains(BxDFType::BSDF_DIFFUSE) {
+            Event::Diffuse
+        } else {
+            Event::Specular
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Atom {
+    Event(Event),
+    /// `.`
+    Any,
+}
+
+impl Atom {
+    fn matches(self, event: Event) -> bool {
+        match self {
+            Atom::Event(e) => e == event,
+            Atom::Any => true,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Repeat {
+    Once,
+    /// `?`
+    Optional,
+    /// `*` (`X+` is compiled to `XX*`)
+    Any,
+}
+
+/// A compiled light path expression.
+#[derive(Debug, Clone)]
+pub struct Lpe {
+    source: String,
+    items: Vec<(Atom, Repeat)>,
+}
+
+impl Lpe {
+    pub fn parse(source: &str) -> Result<Lpe> {
+        let mut items: Vec<(Atom, Repeat)> = Vec::new();
+        for c in source.chars().filter(|c| !c.is_whitespace()) {
+            let atom = match c {
+                'C' => Atom::Event(Event::Camera),
+                'D' => Atom::Event(Event::Diffuse),
+                'S' => Atom::Event(Event::Specular),
+                'L' => Atom::Event(Event::Light),
+                '.' => Atom::Any,
+                '*' | '+' | '?' => {
+                    let last = match items.last_mut() {
+                        Some(last) if last.1 == Repeat::Once => last,
+                        _ => bail!("Misplaced '{}' in light path expression \"{}\"", c, source),
+                    };
+                    match c {
+                        '*' => last.1 = Repeat::Any,
+                        '?' => last.1 = Repeat::Optional,
+                        _ => {
+                            let atom = last.0;
+                            items.push((atom, Repeat::Any));
+                        }
+                    }
+                    continue;
+                }
+                _ => bail!(
+                    "Invalid character '{}' in light path expression \"{}\"",
+                    c,
+                    source
+                ),
+            };
+            items.push((atom, Repeat::Once));
+        }
+        if items.is_empty() {
+            bail!("Empty light path expression");
+        }
+        if items.len() > MAX_ITEMS {
+            bail!("Light path expression \"{}\" is too long", source);
+        }
+
+        Ok(Lpe {
+            source: source.to_owned(),
+            items,
+        })
+    }
+
+    pub fn source(&self) -> &str {
+        &self.source
+    }
+
+    /// Whether the expression matches the whole sequence of events.
+    pub fn matches(&self, events: &[Event]) -> bool {
+        let states = events
+            .iter()
+            .fold(self.start(), |states, &e| self.advance(states, e));
+        self.accepts(states)
+    }
+
+    /// States of the automaton before any event.
+    fn start(&self) -> u64 {
+        self.closure(1)
+    }
+
+    /// States reached from `states` after `event`.
+    fn advance(&self, states: u64, event: Event) -> u64 {
+        let mut next = 0;
+        for (i, (atom, repeat)) in self.items.iter().enumerate() {
+            if states & (1 << i) != 0 && atom.matches(event) {
+                next |= if *repeat == Repeat::Any {
+                    1 << i
+                } else {
+                    1 << (i + 1)
+                };
+            }
+        }
+        self.closure(next)
+    }
+
+    /// Add the states reachable by skipping the optional items.
+    fn closure(&self, mut states: u64) -> u64 {
+        for (i, (_, repeat)) in self.items.iter().enumerate() {
+            if states & (1 << i) != 0 && *repeat != Repeat::Once {
+                states |= 1 << (i + 1);
+            }
+        }
+        states
+    }
+
+    fn accepts(&self, states: u64) -> bool {
+        states & (1 << self.items.len()) != 0
+    }
+}
+
+/// State of each of the expressions of an `LpeAccumulator` after a path prefix.
+#[derive(Debug, Clone, Copy)]
+pub struct LpePath {
+    states: [u64; MAX_EXPRESSIONS],
+}
+
+/// Accumulates the radiance of a camera sample into one value per expression, from the
+/// contributions of the paths that match them.
+///
+/// It can also record each contribution along with the light it came from, and the length of the
+/// path, for the samples of the pixels being dumped (see the `sampledump` module).
+pub struct LpeAccumulator<'a> {
+    expressions: &'a [Lpe],
+    values: Vec<Spectrum>,
+    recording: bool,
+    contributions: Vec<Contribution>,
+    path_length: u32,
+}
+
+impl<'a> LpeAccumulator<'a> {
+    pub fn new(expressions: &'a [Lpe]) -> LpeAccumulator<'a> {
+        assert!(expressions.len() <= MAX_EXPRESSIONS);
+        LpeAccumulator {
+            expressions,
+            values: vec![Spectrum::black(); expressions.len()],
+            recording: false,
+            contributions: Vec::new(),
+            path_length: 0,
+        }
+    }
+
+    /// Whether there is nothing to accumulate, i.e. neither expressions nor a sample to record.
+    pub fn is_empty(&self) -> bool {
+        self.expressions.is_empty() && !self.recording
+    }
+
+    /// Reset the values, for a new camera sample.
+    pub fn clear(&mut self) {
+        self.values.iter_mut().for_each(|v| *v = Spectrum::black());
+        self.contributions.clear();
+        self.path_length = 0;
+    }
+
+    pub fn values(&self) -> &[Spectrum] {
+        &self.values
+    }
+
+    /// Record the contributions and path length of the following samples.
+    pub fn set_recording(&mut self, recording: bool) {
+        self.recording = recording;
+    }
+
+    pub fn is_recording(&self) -> bool {
+        self.recording
+    }
+
+    /// Record the contribution `value` of the light with the given ID, found with `strategy`
+    /// along a path with `length` scattering events.
+    pub fn record(
+        &mut self,
+        length: u32,
+        light: u32,
+        strategy: Strategy,
+        mis_weight: f32,
+        value: Spectrum,
+    ) {
+        if self.recording && !value.is_black() {
+            self.contributions.push(Contribution {
+                length,
+                light,
+                strategy,
+                mis_weight,
+                value,
+            });
+        }
+    }
+
+    pub fn contributions(&self) -> &[Contribution] {
+        &self.contributions
+    }
+
+    pub fn set_path_length(&mut self, length: u32) {
+        self.path_length = length;
+    }
+
+    pub fn path_length(&self) -> u32 {
+        self.path_length
+    }
+
+    /// State of a path that just left the camera.
+    pub fn camera_path(&self) -> LpePath {
+        let mut path = LpePath {
+            states: [0; MAX_EXPRESSIONS],
+        };
+        for (states, e) in path.states.iter_mut().zip(self.expressions) {
+            *states = e.advance(e.start(), Event::Camera);
+        }
+        path
+    }
+
+    /// State of `path` after a scattering `event`.
+    pub fn scatter(&self, path: &LpePath, event: Event) -> LpePath {
+        let mut next = *path;
+        for (states, e) in next.states.iter_mut().zip(self.expressions) {
+            *states = e.advance(*states, event);
+        }
+        next
+    }
+
+    /// Add the radiance `l` reaching the camera from a light along `path` to the values of the
+    /// expressions it matches.
+    pub fn add_light(&mut self, path: &LpePath, l: Spectrum) {
+        if l.is_black() {
+            return;
+        }
+        for ((value, states), e) in self
+            .values
+            .iter_mut()
+            .zip(&path.states)
+            .zip(self.expressions)
+        {
+            if e.accepts(e.advance(*states, Event::Light)) {
+                *value += l;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use Event::*;
+
+    #[test]
+    fn test_matches() {
+        let diffuse = Lpe::parse("CD*L").unwrap();
+        assert!(diffuse.matches(&[Camera, Light]));
+        assert!(diffuse.matches(&[Camera, Diffuse, Diffuse, Light]));
+        assert!(!diffuse.matches(&[Camera, Diffuse, Specular, Light]));
+        assert!(!diffuse.matches(&[Camera, Diffuse]));
+
+        let specular = Lpe::parse("CS+L").unwrap();
+        assert!(!specular.matches(&[Camera, Light]));
+        assert!(specular.matches(&[Camera, Specular, Light]));
+        assert!(specular.matches(&[Camera, Specular, Specular, Light]));
+        assert!(!specular.matches(&[Camera, Specular, Diffuse, Light]));
+
+        let caustics = Lpe::parse("C D S? S L").unwrap();
+        assert!(caustics.matches(&[Camera, Diffuse, Specular, Light]));
+        assert!(caustics.matches(&[Camera, Diffuse, Specular, Specular, Light]));
+        assert!(!caustics.matches(&[Camera, Diffuse, Light]));
+
+        let all = Lpe::parse("C.*L").unwrap();
+        assert!(all.matches(&[Camera, Light]));
+        assert!(all.matches(&[Camera, Specular, Diffuse, Light]));
+    }
+
+    #[test]
+    fn test_parse_errors() {
+        assert!(Lpe::parse("").is_err());
+        assert!(Lpe::parse("*CL").is_err());
+        assert!(Lpe::parse("CD**L").is_err());
+        assert!(Lpe::parse("CD+*L").is_err());
+        assert!(Lpe::parse("CXL").is_err());
+        assert!(Lpe::parse(&"D".repeat(64)).is_err());
+    }
+
+    #[test]
+    fn test_accumulator() {
+        let expressions = vec![
+            Lpe::parse("CL").unwrap(),
+            Lpe::parse("CD*L").unwrap(),
+            Lpe::parse("CS+L").unwrap(),
+        ];
+        let mut aovs = LpeAccumulator::new(&expressions);
+        let camera = aovs.camera_path();
+        aovs.add_light(&camera, Spectrum::grey(1.0));
+        let diffuse = aovs.scatter(&camera, Diffuse);
+        aovs.add_light(&diffuse, Spectrum::grey(2.0));
+        aovs.add_light(&aovs.scatter(&diffuse, Specular), Spectrum::grey(4.0));
+        aovs.add_light(&aovs.scatter(&camera, Specular), Spectrum::grey(8.0));
+        assert_eq!(
+            aovs.values(),
+            &[
+                Spectrum::grey(1.0),
+                Spectrum::grey(3.0),
+                Spectrum::grey(8.0)
+            ]
+        );
+
+        aovs.clear();
+        assert!(aovs.values().iter().all(|v| v.is_black()));
+    }
+
+    #[test]
+    fn test_recording() {
+        let mut aovs = LpeAccumulator::new(&[]);
+        assert!(aovs.is_empty());
+        aovs.record(0, 1, Strategy::Emission, 1.0, Spectrum::grey(1.0));
+        assert!(aovs.contributions().is_empty());
+
+        aovs.set_recording(true);
+        assert!(!aovs.is_empty());
+        aovs.record(1, 2, Strategy::LightSample, 0.5, Spectrum::grey(2.0));
+        aovs.record(1, 2, Strategy::BsdfSample, 0.5, Spectrum::black());
+        aovs.set_path_length(3);
+        assert_eq!(aovs.contributions().len(), 1);
+        assert_eq!(aovs.contributions()[0].strategy, Strategy::LightSample);
+        assert_eq!(aovs.path_length(), 3);
+
+        aovs.clear();
+        assert!(aovs.contributions().is_empty());
+        assert_eq!(aovs.path_length(), 0);
+    }
+}
@@ -12,35 +12,35 @@ use crate::bsdf::{
 };
 use crate::geometry::{abs_cos_theta, same_hemisphere, spherical_direction};
 use crate::interaction::SurfaceInteraction;
-use crate::material::{Material, TransportMode};
+use crate::material::{Material, MaterialRef, TransportMode};
 use crate::paramset::TextureParams;
 use crate::spectrum::Spectrum;
-use crate::texture::{TextureFloat, TextureSpectrum};
+use crate::texture::TextureRef;
 use crate::{clamp, lerp, Point2f, Vector3f};
 
 #[derive(Debug)]
 pub struct DisneyMaterial {
-    color: Arc<TextureSpectrum>,
-    // base_color: Arc<TextureFloat>,
-    metallic: Arc<TextureFloat>,
-    eta: Arc<TextureFloat>,
-    roughness: Arc<TextureFloat>,
-    specular_tint: Arc<TextureFloat>,
-    anisotropic: Arc<TextureFloat>,
-    sheen: Arc<TextureFloat>,
-    sheen_tint: Arc<TextureFloat>,
-    clearcoat: Arc<TextureFloat>,
-    clearcoat_gloss: Arc<TextureFloat>,
-    spec_trans: Arc<TextureFloat>,
-    scatter_distance: Arc<TextureSpectrum>,
-    flatness: Arc<TextureFloat>,
-    diff_trans: Arc<TextureFloat>,
-    bumpmap: Option<Arc<TextureFloat>>,
+    color: TextureRef<Spectrum>,
+    // base_color: TextureRef<f32>,
+    metallic: TextureRef<f32>,
+    eta: TextureRef<f32>,
+    roughness: TextureRef<f32>,
+    specular_tint: TextureRef<f32>,
+    anisotropic: TextureRef<f32>,
+    sheen: TextureRef<f32>,
+    sheen_tint: TextureRef<f32>,
+    clearcoat: TextureRef<f32>,
+    clearcoat_gloss: TextureRef<f32>,
+    spec_trans: TextureRef<f32>,
+    scatter_distance: TextureRef<Spectrum>,
+    flatness: TextureRef<f32>,
+    diff_trans: TextureRef<f32>,
+    bumpmap: Option<TextureRef<f32>>,
     thin: bool,
 }
 
 impl DisneyMaterial {
-    pub fn create(mp: &TextureParams<'_>) -> Arc<dyn Material> {
+    pub fn create(mp: &TextureParams<'_>) -> MaterialRef {
         let color = mp.get_spectrum_texture("color", &Spectrum::from(0.5));
         let metallic = mp.get_float_texture("metallic", 0.0);
         let eta = mp.get_float_texture("eta", 1.5);
@@ -56,7 +56,7 @@ impl DisneyMaterial {
         let thin = mp.find_bool("thin", false);
         let flatness = mp.get_float_texture("flatness", 0.0);
         let diff_trans = mp.get_float_texture("difftrans", 1.0);
-        let bumpmap = mp.get_float_texture_or_none("bumpmap");
+        let bumpmap = super::get_bump_map(mp);
 
         Arc::new(DisneyMaterial {
             color,
@@ -80,13 +80,13 @@ impl DisneyMaterial {
 }
 
 impl Material for DisneyMaterial {
-    fn compute_scattering_functions<'a, 'b>(
+    fn compute_scattering_functions<'b>(
         &self,
-        si: &mut SurfaceInteraction<'a, 'b>,
+        si: &mut SurfaceInteraction,
         mode: TransportMode,
         _allow_multiple_lobes: bool,
         arena: &'b Allocator<'_>,
-    ) {
+    ) -> Bsdf<'b> {
         if let Some(ref bump) = self.bumpmap {
             super::bump(bump, si);
         }
@@ -208,7 +208,7 @@ impl Material for DisneyMaterial {
             bxdfs.add(arena.alloc(LambertianTransmission::new(dt * c)));
         }
 
-        si.bsdf = Some(Arc::new(Bsdf::new(si, 1.0, bxdfs.into_slice())));
+        Bsdf::new(si, 1.0, bxdfs.into_slice())
     }
 }
 
@@ -1,46 +1,49 @@
 use std::sync::Arc;
 
+use anyhow::{Context, Result};
 use light_arena::Allocator;
 
 use crate::bsdf::{Bsdf, BxDFHolder, FourierBSDF, FourierBSDFTable};
 use crate::interaction::SurfaceInteraction;
-use crate::material::{Material, TransportMode};
+use crate::material::{Material, MaterialRef, TransportMode};
 use crate::paramset::TextureParams;
-use crate::texture::TextureFloat;
+use crate::texture::TextureRef;
 
 #[derive(Debug)]
 pub struct FourierMaterial {
     bsdf_table: Box<FourierBSDFTable>,
-    bump_map: Option<Arc<TextureFloat>>,
+    bump_map: Option<TextureRef<f32>>,
 }
 
 impl FourierMaterial {
-    pub fn create(mp: &TextureParams<'_>) -> Arc<dyn Material> {
-        let bump_map = mp.get_float_texture_or_none("bumpmap");
+    pub fn create(mp: &TextureParams<'_>) -> Result<MaterialRef> {
+        let bump_map = super::get_bump_map(mp);
         let filename = mp.find_filename("bsdffile", "");
-        let bsdf_table = Box::new(FourierBSDFTable::read(filename).unwrap()); // TODO error
-        Arc::new(FourierMaterial {
+        let bsdf_table = Box::new(
+            FourierBSDFTable::read(&filename)
+                .with_context(|| format!("Failed to load BSDF file \"{}\"", filename))?,
+        );
+        Ok(Arc::new(FourierMaterial {
             bsdf_table,
             bump_map,
-        })
+        }))
     }
 }
 
 impl Material for FourierMaterial {
-    fn compute_scattering_functions<'a, 'b>(
+    fn compute_scattering_functions<'b>(
         &self,
-        si: &mut SurfaceInteraction<'a, 'b>,
+        si: &mut SurfaceInteraction,
         mode: TransportMode,
         _allow_multiple_lobes: bool,
         arena: &'b Allocator<'_>,
-    ) {
+    ) -> Bsdf<'b> {
         let mut bxdfs = BxDFHolder::new(arena);
 
         if let Some(ref bump) = self.bump_map {
             super::bump(bump, si);
         }
         bxdfs.add(arena.alloc(FourierBSDF::new(&self.bsdf_table, mode)));
-        let bsdf = Bsdf::new(si, 1.0, bxdfs.into_slice());
-        si.bsdf = Some(Arc::new(bsdf));
+        Bsdf::new(si, 1.0, bxdfs.into_slice())
     }
 }
@@ -8,24 +8,25 @@ use crate::bsdf::{
     MicrofacetTransmission, SpecularReflection, SpecularTransmission, TrowbridgeReitzDistribution,
 };
 use crate::interaction::SurfaceInteraction;
-use crate::material::{Material, TransportMode};
+use crate::material::{self, Material, MaterialRef, TransportMode};
 use crate::paramset::TextureParams;
 use crate::spectrum::Spectrum;
-use crate::texture::{TextureFloat, TextureSpectrum};
+use crate::texture::TextureRef;
+use crate::PbrtOptions;
 
 #[derive(Debug)]
 pub struct GlassMaterial {
-    kr: Arc<TextureSpectrum>,
-    kt: Arc<TextureSpectrum>,
-    u_roughness: Arc<TextureFloat>,
-    v_roughness: Arc<TextureFloat>,
-    index: Arc<TextureFloat>,
-    bump_map: Option<Arc<TextureFloat>>,
+    kr: TextureRef<Spectrum>,
+    kt: TextureRef<Spectrum>,
+    u_roughness: TextureRef<f32>,
+    v_roughness: TextureRef<f32>,
+    index: TextureRef<f32>,
+    bump_map: Option<TextureRef<f32>>,
     remap_roughness: bool,
 }
 
 impl GlassMaterial {
-    pub fn create(mp: &TextureParams<'_>) -> Arc<dyn Material> {
+    pub fn create(mp: &TextureParams<'_>, opts: &PbrtOptions) -> MaterialRef {
         info!("Creating Glass material");
         let Kr = mp.get_spectrum_texture("Kr", &Spectrum::white());
         let Kt = mp.get_spectrum_texture("Kt", &Spectrum::white());
@@ -34,8 +35,8 @@ impl GlassMaterial {
             .unwrap_or_else(|| mp.get_float_texture("index", 1.5));
         let rough_u = mp.get_float_texture("uroughness", 0.0);
         let rough_v = mp.get_float_texture("vroughness", 0.0);
-        let bump_map = mp.get_float_texture_or_none("bumpmap");
-        let remap_roughness = mp.find_bool("remaproughness", true);
+        let bump_map = super::get_bump_map(mp);
+        let remap_roughness = material::remap_roughness(mp, opts, &["uroughness", "vroughness"]);
 
         Arc::new(GlassMaterial {
             kr: Kr,
@@ -50,13 +51,13 @@ impl GlassMaterial {
 }
 
 impl Material for GlassMaterial {
-    fn compute_scattering_functions<'a, 'b>(
+    fn compute_scattering_functions<'b>(
         &self,
-        si: &mut SurfaceInteraction<'a, 'b>,
+        si: &mut SurfaceInteraction,
         mode: TransportMode,
         allow_multiple_lobes: bool,
         arena: &'b Allocator<'_>,
-    ) {
+    ) -> Bsdf<'b> {
         if let Some(ref bump) = self.bump_map {
             super::bump(bump, si);
         }
@@ -101,7 +102,6 @@ impl Material for GlassMaterial {
             }
         }
 
-        let bsdf = Bsdf::new(si, eta, bxdfs.into_slice());
-        si.bsdf = Some(Arc::new(bsdf));
+        Bsdf::new(si, eta, bxdfs.into_slice())
     }
 }
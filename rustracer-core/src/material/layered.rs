@@ -0,0 +1,85 @@
+use std::sync::Arc;
+
+use light_arena::Allocator;
+
+use crate::bsdf::{
+    dielectric, Bsdf, BxDFHolder, CoatedBxDF, MicrofacetReflection, SpecularReflection,
+    TrowbridgeReitzDistribution,
+};
+use crate::interaction::SurfaceInteraction;
+use crate::material::{self, Material, MaterialRef, TransportMode};
+use crate::paramset::TextureParams;
+use crate::spectrum::Spectrum;
+use crate::texture::TextureRef;
+use crate::PbrtOptions;
+
+/// A dielectric coating (e.g. varnish or clear coat) layered on top of another material.
+///
+/// The coating reflects light according to the Fresnel equations, and the base material gets
+/// the light that's transmitted through it (tinted by the coating's colour). As the base can be
+/// any material, including another `LayeredMaterial`, several coats can be stacked.
+#[derive(Debug)]
+pub struct LayeredMaterial {
+    base: MaterialRef,
+    ks: TextureRef<Spectrum>,
+    tint: TextureRef<Spectrum>,
+    roughness: TextureRef<f32>,
+    eta: f32,
+    remap_roughness: bool,
+}
+
+impl LayeredMaterial {
+    pub fn create(mp: &TextureParams<'_>, opts: &PbrtOptions, base: MaterialRef) -> MaterialRef {
+        let ks = mp.get_spectrum_texture("Ks", &Spectrum::white());
+        let tint = mp.get_spectrum_texture("tint", &Spectrum::white());
+        let roughness = mp.get_float_texture("roughness", 0.0);
+        let eta = mp.find_float("eta", 1.5);
+        let remap_roughness = material::remap_roughness(mp, opts, &["roughness"]);
+
+        Arc::new(LayeredMaterial {
+            base,
+            ks,
+            tint,
+            roughness,
+            eta,
+            remap_roughness,
+        })
+    }
+}
+
+impl Material for LayeredMaterial {
+    fn compute_scattering_functions<'b>(
+        &self,
+        si: &mut SurfaceInteraction,
+        mode: TransportMode,
+        allow_multiple_lobes: bool,
+        arena: &'b Allocator<'_>,
+    ) -> Bsdf<'b> {
+        let mut bsdf =
+            self.base
+                .compute_scattering_functions(si, mode, allow_multiple_lobes, arena);
+        let ks = self.ks.evaluate(si).clamp();
+        let tint = self.tint.evaluate(si).clamp();
+
+        let mut bxdfs = BxDFHolder::new(arena);
+        if !ks.is_black() {
+            let fresnel = arena.alloc(dielectric(1.0, self.eta));
+            let mut roughness = self.roughness.evaluate(si);
+            if roughness == 0.0 {
+                bxdfs.add(arena.alloc(SpecularReflection::new(ks, fresnel)));
+            } else {
+                if self.remap_roughness {
+                    roughness = TrowbridgeReitzDistribution::roughness_to_alpha(roughness);
+                }
+                let distrib = arena.alloc(TrowbridgeReitzDistribution::new(roughness, roughness));
+                bxdfs.add(arena.alloc(MicrofacetReflection::new(ks, distrib, fresnel)));
+            }
+        }
+        for bxdf in bsdf.bxdfs {
+            bxdfs.add(arena.alloc(CoatedBxDF::new(*bxdf, self.eta, tint)));
+        }
+
+        bsdf.bxdfs = bxdfs.into_slice();
+        bsdf
+    }
+}
@@ -6,24 +6,24 @@ use log::info;
 use crate::bsdf::{Bsdf, BxDFHolder, LambertianReflection, OrenNayar};
 use crate::clamp;
 use crate::interaction::SurfaceInteraction;
-use crate::material::{Material, TransportMode};
+use crate::material::{Material, MaterialRef, TransportMode};
 use crate::paramset::TextureParams;
 use crate::spectrum::Spectrum;
-use crate::texture::{TextureFloat, TextureSpectrum};
+use crate::texture::TextureRef;
 
 #[derive(Debug)]
 pub struct MatteMaterial {
-    kd: Arc<TextureSpectrum>,
-    sigma: Arc<TextureFloat>,
-    bump_map: Option<Arc<TextureFloat>>,
+    kd: TextureRef<Spectrum>,
+    sigma: TextureRef<f32>,
+    bump_map: Option<TextureRef<f32>>,
 }
 
 impl MatteMaterial {
-    pub fn create(mp: &TextureParams<'_>) -> Arc<dyn Material> {
+    pub fn create(mp: &TextureParams<'_>) -> MaterialRef {
         info!("Creating Matte material");
         let kd = mp.get_spectrum_texture("Kd", &Spectrum::grey(0.5));
         let sigma = mp.get_float_texture("sigma", 0.0);
-        let bump_map = mp.get_float_texture_or_none("bumpmap");
+        let bump_map = super::get_bump_map(mp);
 
         Arc::new(MatteMaterial {
             kd,
@@ -34,13 +34,13 @@ impl MatteMaterial {
 }
 
 impl Material for MatteMaterial {
-    fn compute_scattering_functions<'a, 'b>(
+    fn compute_scattering_functions<'b>(
         &self,
-        si: &mut SurfaceInteraction<'a, 'b>,
+        si: &mut SurfaceInteraction,
         _mode: TransportMode,
         _allow_multiple_lobes: bool,
         arena: &'b Allocator<'_>,
-    ) {
+    ) -> Bsdf<'b> {
         let mut bxdfs = BxDFHolder::new(arena);
 
         if let Some(ref bump_map) = self.bump_map {
@@ -57,7 +57,6 @@ impl Material for MatteMaterial {
             }
         }
 
-        let bsdf = Bsdf::new(si, 1.0, bxdfs.into_slice());
-        si.bsdf = Some(Arc::new(bsdf));
+        Bsdf::new(si, 1.0, bxdfs.into_slice())
     }
 }
@@ -4,24 +4,25 @@ use light_arena::Allocator;
 
 use crate::bsdf::{conductor, Bsdf, BxDFHolder, MicrofacetReflection, TrowbridgeReitzDistribution};
 use crate::interaction::SurfaceInteraction;
-use crate::material::{self, Material, TransportMode};
+use crate::material::{self, Material, MaterialRef, TransportMode};
 use crate::paramset::TextureParams;
 use crate::spectrum::Spectrum;
-use crate::texture::{TextureFloat, TextureSpectrum};
+use crate::texture::TextureRef;
+use crate::PbrtOptions;
 
 #[derive(Debug)]
 pub struct Metal {
-    eta: Arc<TextureSpectrum>,
-    k: Arc<TextureSpectrum>,
-    rough: Arc<TextureFloat>,
-    bump: Option<Arc<TextureFloat>>,
-    urough: Option<Arc<TextureFloat>>,
-    vrough: Option<Arc<TextureFloat>>,
+    eta: TextureRef<Spectrum>,
+    k: TextureRef<Spectrum>,
+    rough: TextureRef<f32>,
+    bump: Option<TextureRef<f32>>,
+    urough: Option<TextureRef<f32>>,
+    vrough: Option<TextureRef<f32>>,
     remap_roughness: bool,
 }
 
 impl Metal {
-    pub fn create(mp: &TextureParams<'_>) -> Arc<dyn Material> {
+    pub fn create(mp: &TextureParams<'_>, opts: &PbrtOptions) -> MaterialRef {
         let copper_eta =
             Spectrum::from_sampled(&COPPER_WAVELENGTHS[..], &COPPER_N[..], COPPER_SAMPLES);
         let eta = mp.get_spectrum_texture("eta", &copper_eta);
@@ -31,8 +32,9 @@ impl Metal {
         let rough = mp.get_float_texture("roughness", 0.01);
         let urough = mp.get_float_texture_or_none("uroughness");
         let vrough = mp.get_float_texture_or_none("vroughness");
-        let bump = mp.get_float_texture_or_none("bumpmap");
-        let remap_roughness = mp.find_bool("remaproughness", true);
+        let bump = material::get_bump_map(mp);
+        let remap_roughness =
+            material::remap_roughness(mp, opts, &["roughness", "uroughness", "vroughness"]);
 
         Arc::new(Metal {
             eta,
@@ -47,13 +49,13 @@ impl Metal {
 }
 
 impl Material for Metal {
-    fn compute_scattering_functions<'a, 'b>(
+    fn compute_scattering_functions<'b>(
         &self,
-        si: &mut SurfaceInteraction<'a, 'b>,
+        si: &mut SurfaceInteraction,
         _mode: TransportMode,
         _allow_multiple_lobes: bool,
         arena: &'b Allocator<'_>,
-    ) {
+    ) -> Bsdf<'b> {
         if let Some(ref bump) = self.bump {
             material::bump(bump, si);
         }
@@ -76,8 +78,7 @@ impl Material for Metal {
             fresnel,
         )));
 
-        let bsdf = Bsdf::new(si, 1.0, bxdfs.into_slice());
-        si.bsdf = Some(Arc::new(bsdf));
+        Bsdf::new(si, 1.0, bxdfs.into_slice())
     }
 }
 
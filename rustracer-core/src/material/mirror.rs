@@ -5,35 +5,35 @@ use log::info;
 
 use crate::bsdf::{no_op, Bsdf, BxDFHolder, SpecularReflection};
 use crate::interaction::SurfaceInteraction;
-use crate::material::{Material, TransportMode};
+use crate::material::{Material, MaterialRef, TransportMode};
 use crate::paramset::TextureParams;
 use crate::spectrum::Spectrum;
-use crate::texture::{TextureFloat, TextureSpectrum};
+use crate::texture::TextureRef;
 
 #[derive(Debug)]
 pub struct MirrorMaterial {
-    kr: Arc<TextureSpectrum>,
-    bump_map: Option<Arc<TextureFloat>>,
+    kr: TextureRef<Spectrum>,
+    bump_map: Option<TextureRef<f32>>,
 }
 
 impl MirrorMaterial {
-    pub fn create(mp: &TextureParams<'_>) -> Arc<dyn Material> {
+    pub fn create(mp: &TextureParams<'_>) -> MaterialRef {
         info!("Creating Mirror material");
         let Kr = mp.get_spectrum_texture("Kr", &Spectrum::grey(0.9));
-        let bump_map = mp.get_float_texture_or_none("bumpmap");
+        let bump_map = super::get_bump_map(mp);
 
         Arc::new(MirrorMaterial { kr: Kr, bump_map })
     }
 }
 
 impl Material for MirrorMaterial {
-    fn compute_scattering_functions<'a, 'b>(
+    fn compute_scattering_functions<'b>(
         &self,
-        si: &mut SurfaceInteraction<'a, 'b>,
+        si: &mut SurfaceInteraction,
         _mode: TransportMode,
         _allow_multiple_lobes: bool,
         arena: &'b Allocator<'_>,
-    ) {
+    ) -> Bsdf<'b> {
         if let Some(ref bump) = self.bump_map {
             super::bump(bump, si);
         }
@@ -44,6 +44,6 @@ impl Material for MirrorMaterial {
             bxdfs.add(arena.alloc(SpecularReflection::new(R, fresnel)));
         }
 
-        si.bsdf = Some(Arc::new(Bsdf::new(si, 1.0, bxdfs.into_slice())));
+        Bsdf::new(si, 1.0, bxdfs.into_slice())
     }
 }
@@ -2,26 +2,24 @@ use std::sync::Arc;
 
 use light_arena::Allocator;
 
-use crate::bsdf::{BxDFHolder, ScaledBxDF};
+use crate::bsdf::{Bsdf, BxDFHolder, ScaledBxDF};
 use crate::interaction::SurfaceInteraction;
-use crate::material::{Material, TransportMode};
+use crate::material::{Material, MaterialRef, TransportMode};
 use crate::paramset::TextureParams;
 use crate::spectrum::Spectrum;
-use crate::texture::TextureSpectrum;
+use crate::texture::TextureRef;
 
+/// Blend of two materials. The `amount` parameter is a spectrum texture, so the materials can be
+/// mixed independently for each channel: `amount` of the first one and `1 - amount` of the second.
 #[derive(Debug)]
 pub struct MixMaterial {
-    mat1: Arc<dyn Material>,
-    mat2: Arc<dyn Material>,
-    scale: Arc<TextureSpectrum>,
+    mat1: MaterialRef,
+    mat2: MaterialRef,
+    scale: TextureRef<Spectrum>,
 }
 
 impl MixMaterial {
-    pub fn create(
-        mp: &TextureParams<'_>,
-        m1: Arc<dyn Material>,
-        m2: Arc<dyn Material>,
-    ) -> Arc<dyn Material> {
+    pub fn create(mp: &TextureParams<'_>, m1: MaterialRef, m2: MaterialRef) -> MaterialRef {
         Arc::new(MixMaterial {
             mat1: m1,
             mat2: m2,
@@ -31,35 +29,32 @@ impl MixMaterial {
 }
 
 impl Material for MixMaterial {
-    fn compute_scattering_functions<'a, 'b>(
+    fn compute_scattering_functions<'b>(
         &self,
-        si: &mut SurfaceInteraction<'a, 'b>,
+        si: &mut SurfaceInteraction,
         mode: TransportMode,
         allow_multiple_lobes: bool,
         arena: &'b Allocator<'_>,
-    ) {
+    ) -> Bsdf<'b> {
         let s1 = self.scale.evaluate(si).clamp();
         let s2 = (Spectrum::white() - s1).clamp();
         let mut si2 = si.clone();
-        self.mat1
-            .compute_scattering_functions(si, mode, allow_multiple_lobes, arena);
-        self.mat2
-            .compute_scattering_functions(&mut si2, mode, allow_multiple_lobes, arena);
-        let n1 = si.bsdf.as_ref().unwrap().bxdfs.len();
-        let n2 = si2.bsdf.as_ref().unwrap().bxdfs.len();
+        let mut bsdf1 =
+            self.mat1
+                .compute_scattering_functions(si, mode, allow_multiple_lobes, arena);
+        let bsdf2 =
+            self.mat2
+                .compute_scattering_functions(&mut si2, mode, allow_multiple_lobes, arena);
 
         let mut bxdfs = BxDFHolder::new(arena);
-        for i in 0..n1 {
-            bxdfs.add(arena.alloc(ScaledBxDF::new(si.bsdf.as_ref().unwrap().bxdfs[i], s1)));
+        for bxdf in bsdf1.bxdfs {
+            bxdfs.add(arena.alloc(ScaledBxDF::new(*bxdf, s1)));
         }
-        for i in 0..n2 {
-            bxdfs.add(arena.alloc(ScaledBxDF::new(si2.bsdf.as_ref().unwrap().bxdfs[i], s2)));
+        for bxdf in bsdf2.bxdfs {
+            bxdfs.add(arena.alloc(ScaledBxDF::new(*bxdf, s2)));
         }
 
-        si.bsdf.as_mut().map(|b| {
-            (Arc::get_mut(b))
-                .as_mut()
-                .map(|b| b.bxdfs = bxdfs.into_slice())
-        });
+        bsdf1.bxdfs = bxdfs.into_slice();
+        bsdf1
     }
 }
@@ -2,18 +2,24 @@ use std::fmt::Debug;
 use std::sync::Arc;
 
 use light_arena::Allocator;
+use log::warn;
 
+use crate::bsdf::Bsdf;
 use crate::interaction::SurfaceInteraction;
-use crate::texture::Texture;
-use crate::{Normal3f, Vector2f, Vector3f};
+use crate::paramset::TextureParams;
+use crate::spectrum::Spectrum;
+use crate::texture::{ScaleTexture, TextureRef};
+use crate::{Normal3f, PbrtOptions, Vector2f, Vector3f};
 
 mod disney;
 mod fourier;
 mod glass;
+mod layered;
 mod matte;
 mod metal;
 mod mirror;
 mod mixmat;
+mod pbr;
 mod plastic;
 mod substrate;
 mod translucent;
@@ -22,10 +28,12 @@ mod uber;
 pub use self::disney::DisneyMaterial;
 pub use self::fourier::FourierMaterial;
 pub use self::glass::GlassMaterial;
+pub use self::layered::LayeredMaterial;
 pub use self::matte::MatteMaterial;
 pub use self::metal::Metal;
 pub use self::mirror::MirrorMaterial;
 pub use self::mixmat::MixMaterial;
+pub use self::pbr::PbrMaterial;
 pub use self::plastic::Plastic;
 pub use self::substrate::SubstrateMaterial;
 pub use self::translucent::TranslucentMaterial;
@@ -37,56 +45,265 @@ pub enum TransportMode {
     IMPORTANCE,
 }
 
+/// Shared reference to a material, as held by the primitives and by the named materials.
+pub type MaterialRef = Arc<dyn Material>;
+
 pub trait Material: Debug + Send + Sync {
-    fn compute_scattering_functions<'a, 'b>(
+    /// Compute the BSDF at the intersection point, allocated in `arena`. This may also update the
+    /// shading geometry of `isect` (e.g. for bump mapping).
+    fn compute_scattering_functions<'b>(
         &self,
-        isect: &mut SurfaceInteraction<'a, 'b>,
+        isect: &mut SurfaceInteraction,
         mode: TransportMode,
         allow_multiple_lobes: bool,
         arena: &'b Allocator<'_>,
-    );
+    ) -> Bsdf<'b>;
+}
+
+/// The displacement texture used for bump mapping (`"float bumpmap"`), if any, scaled by
+/// `"float bumpscale"` (which can itself be a texture).
+pub fn get_bump_map(mp: &TextureParams<'_>) -> Option<TextureRef<f32>> {
+    let bump_map = mp.get_float_texture_or_none("bumpmap")?;
+    if mp.get_float_texture_or_none("bumpscale").is_none() && mp.find_float("bumpscale", 1.0) == 1.0
+    {
+        return Some(bump_map);
+    }
+    let bump_scale = mp.get_float_texture("bumpscale", 1.0);
+    Some(Arc::new(ScaleTexture::new(bump_map, bump_scale)))
 }
 
-pub fn bump(d: &Arc<dyn Texture<f32>>, si: &mut SurfaceInteraction<'_, '_>) {
+/// Whether the roughness parameters `names` of a microfacet material are in [0, 1] and need
+/// remapping to the distribution's alpha, or are the alpha itself. This is given by the
+/// material's `"bool remaproughness"`, which defaults to the global option in `opts`.
+///
+/// Constant roughness values that look like they're in the other unit are reported: pbrt-v3
+/// scenes remap roughness by default, pbrt-v4 scenes don't.
+pub fn remap_roughness(mp: &TextureParams<'_>, opts: &PbrtOptions, names: &[&str]) -> bool {
+    let remap = mp.find_bool("remaproughness", !opts.raw_roughness);
+    for name in names {
+        match mp.find_constant_float(name) {
+            Some(r) if r < 0.0 => {
+                warn!("Invalid \"{}\" value {}: it can't be negative.", name, r);
+            }
+            Some(r) if r > 1.0 && remap => {
+                warn!(
+                    "\"{}\" value {} is out of the [0, 1] roughness range. Set \"remaproughness\" to false if it is a microfacet alpha.",
+                    name, r
+                );
+            }
+            Some(r) if r > 1.0 => {
+                warn!(
+                    "\"{}\" value {} is a very rough microfacet alpha. Set \"remaproughness\" to true if it is a [0, 1] roughness.",
+                    name, r
+                );
+            }
+            _ => (),
+        }
+    }
+    remap
+}
+
+pub fn bump(d: &TextureRef<f32>, si: &mut SurfaceInteraction) {
     // Compute offset positions and evaluate displacement texture
     let mut si_eval = si.clone();
 
-    // Shift si_eval du in the u direction
     let mut du = 0.5 * (si.dudx.abs() + si.dudy.abs());
+    let mut dv = 0.5 * (si.dvdx.abs() + si.dvdy.abs());
     // The most common reason for du to be zero is for ray that start from
     // light sources, where no differentials are available. In this case,
     // we try to choose a small enough du so that we still get a decently
     // accurate bump value.
     if du == 0.0 {
         du = 0.0005;
+        si_eval.dudx = du;
     }
-    si_eval.hit.p = si.hit.p + du * si.shading.dpdu;
-    si_eval.uv = si.uv + Vector2f::new(du, 0.0);
-    si_eval.hit.n =
-        (Normal3f::from(si.shading.dpdu.cross(&si.shading.dpdv)) + du * si.dndu).normalize();
-    let u_displace = d.evaluate(&si_eval);
-
-    // Shift si_eval dv in the v direction
-    let mut dv = 0.5 * (si.dvdx.abs() + si.dvdy.abs());
     if dv == 0.0 {
         dv = 0.0005;
+        si_eval.dvdy = dv;
     }
-    si_eval.hit.p = si.hit.p + dv * si.shading.dpdv;
-    si_eval.uv = si.uv + Vector2f::new(0.0, dv);
-    si_eval.hit.n =
-        (Normal3f::from(si.shading.dpdu.cross(&si.shading.dpdv)) + dv * si.dndv).normalize();
-    let v_displace = d.evaluate(&si_eval);
+    // The displacement is evaluated on both sides of the shading point (central differences),
+    // with filtered lookups whose footprint covers the offsets. One-sided differences of
+    // unfiltered lookups give stair-step artifacts on low resolution bump maps.
+    let mut displace_at = |offset: Vector2f, dndt: Normal3f| {
+        let dt = offset.x + offset.y;
+        si_eval.hit.p = si.hit.p + offset.x * si.shading.dpdu + offset.y * si.shading.dpdv;
+        si_eval.uv = si.uv + offset;
+        si_eval.hit.n =
+            (Normal3f::from(si.shading.dpdu.cross(&si.shading.dpdv)) + dt * dndt).normalize();
+        d.evaluate(&si_eval)
+    };
+
+    // Shift si_eval du in the u direction
+    let u_displace = displace_at(Vector2f::new(du, 0.0), si.dndu)
+        - displace_at(Vector2f::new(-du, 0.0), si.dndu);
+    // Shift si_eval dv in the v direction
+    let v_displace = displace_at(Vector2f::new(0.0, dv), si.dndv)
+        - displace_at(Vector2f::new(0.0, -dv), si.dndv);
 
     let displace = d.evaluate(si);
 
     // Compute bump-mapped differential geometry
     let dpdu = si.shading.dpdu
-        + (u_displace - displace) / du * Vector3f::from(si.shading.n)
+        + u_displace / (2.0 * du) * Vector3f::from(si.shading.n)
         + displace * Vector3f::from(si.shading.dndu);
     let dpdv = si.shading.dpdv
-        + (v_displace - displace) / dv * Vector3f::from(si.shading.n)
+        + v_displace / (2.0 * dv) * Vector3f::from(si.shading.n)
         + displace * Vector3f::from(si.shading.dndv);
     let dndu = si.shading.dndu;
     let dndv = si.shading.dndv;
     si.set_shading_geometry(&dpdu, &dpdv, &dndu, &dndv, false);
 }
+
+/// Perturb the shading normal with a tangent space normal map, whose RGB values in [0, 1] encode
+/// the components of the normal in [-1, 1] in the shading frame: X along dp/du, Y towards dp/dv
+/// and Z along the shading normal (the convention of glTF and of most texturing tools).
+pub fn normal_map(map: &TextureRef<Spectrum>, si: &mut SurfaceInteraction) {
+    let c = map.evaluate(si);
+    let local = Vector3f::new(2.0 * c[0] - 1.0, 2.0 * c[1] - 1.0, 2.0 * c[2] - 1.0);
+    let n = Vector3f::from(si.shading.n);
+    let t = si.shading.dpdu - n * n.dot(&si.shading.dpdu);
+    if local.length_squared() == 0.0 || t.length_squared() == 0.0 {
+        return;
+    }
+    let t = t.normalize();
+    let mut b = n.cross(&t);
+    if b.dot(&si.shading.dpdv) < 0.0 {
+        b = -b;
+    }
+    let ns = (local.x * t + local.y * b + local.z * n).normalize();
+
+    // Rotate the shading frame so that its normal is ns, keeping the lengths of the derivatives
+    let dpdu =
+        (si.shading.dpdu - ns * ns.dot(&si.shading.dpdu)).normalize() * si.shading.dpdu.length();
+    let mut dpdv = ns.cross(&dpdu).normalize() * si.shading.dpdv.length();
+    if dpdv.dot(&si.shading.dpdv) < 0.0 {
+        dpdv = -dpdv;
+    }
+    let dndu = si.shading.dndu;
+    let dndv = si.shading.dndv;
+    si.set_shading_geometry(&dpdu, &dpdv, &dndu, &dndv, false);
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use super::*;
+    use crate::api::{Array, ParamListEntry, ParamType};
+    use crate::paramset::ParamSet;
+    use crate::shapes::Sphere;
+    use crate::texture::{ConstantTexture, Texture};
+    use crate::transform::Transform;
+    use crate::{Point2f, Point3f};
+
+    /// Displacement increasing linearly with u
+    #[derive(Debug)]
+    struct Ramp;
+
+    impl Texture<f32> for Ramp {
+        fn evaluate(&self, si: &SurfaceInteraction) -> f32 {
+            2.0 * si.uv.x
+        }
+    }
+
+    #[test]
+    fn test_bump_slope() {
+        let sphere = Sphere::new(Transform::default(), 1.0, -1.0, 1.0, 360.0, false);
+        let bumped_normal = |d: TextureRef<f32>| {
+            let mut si = SurfaceInteraction::new(
+                Point3f::new(0.0, 0.0, 1.0),
+                Vector3f::new(0.0, 0.0, 0.0),
+                Point2f::new(0.3, 0.4),
+                0.0,
+                Vector3f::new(0.0, 0.0, 1.0),
+                Vector3f::new(1.0, 0.0, 0.0),
+                Vector3f::new(0.0, 1.0, 0.0),
+                Normal3f::new(0.0, 0.0, 0.0),
+                Normal3f::new(0.0, 0.0, 0.0),
+                &sphere,
+            );
+            bump(&d, &mut si);
+            si.shading.n
+        };
+
+        let n = bumped_normal(Arc::new(Ramp));
+        let expected = Normal3f::new(-2.0, 0.0, 1.0).normalize();
+        assert!((n - expected).length() < 1e-3, "{:?}", n);
+
+        let scaled = ScaleTexture::new(Arc::new(Ramp), Arc::new(ConstantTexture::new(0.5)));
+        let n = bumped_normal(Arc::new(scaled));
+        let expected = Normal3f::new(-1.0, 0.0, 1.0).normalize();
+        assert!((n - expected).length() < 1e-3, "{:?}", n);
+    }
+
+    #[test]
+    fn test_normal_map() {
+        let sphere = Sphere::new(Transform::default(), 1.0, -1.0, 1.0, 360.0, false);
+        let mapped_normal = |c: Spectrum| {
+            let mut si = SurfaceInteraction::new(
+                Point3f::new(0.0, 0.0, 1.0),
+                Vector3f::new(0.0, 0.0, 0.0),
+                Point2f::new(0.3, 0.4),
+                0.0,
+                Vector3f::new(0.0, 0.0, 1.0),
+                Vector3f::new(2.0, 0.0, 0.0),
+                Vector3f::new(0.0, 3.0, 0.0),
+                Normal3f::new(0.0, 0.0, 0.0),
+                Normal3f::new(0.0, 0.0, 0.0),
+                &sphere,
+            );
+            let map: TextureRef<Spectrum> = Arc::new(ConstantTexture::new(c));
+            normal_map(&map, &mut si);
+            si
+        };
+
+        // A flat normal map leaves the frame unchanged
+        let si = mapped_normal(Spectrum::rgb(0.5, 0.5, 1.0));
+        assert!((si.shading.n - Normal3f::new(0.0, 0.0, 1.0)).length() < 1e-5);
+        assert!((si.shading.dpdu - Vector3f::new(2.0, 0.0, 0.0)).length() < 1e-5);
+        assert!((si.shading.dpdv - Vector3f::new(0.0, 3.0, 0.0)).length() < 1e-5);
+
+        // Normals tilted towards +u and +v
+        let si = mapped_normal(Spectrum::rgb(1.0, 0.5, 1.0));
+        let expected = Normal3f::new(1.0, 0.0, 1.0).normalize();
+        assert!(
+            (si.shading.n - expected).length() < 1e-5,
+            "{:?}",
+            si.shading.n
+        );
+        let si = mapped_normal(Spectrum::rgb(0.5, 1.0, 1.0));
+        let expected = Normal3f::new(0.0, 1.0, 1.0).normalize();
+        assert!(
+            (si.shading.n - expected).length() < 1e-5,
+            "{:?}",
+            si.shading.n
+        );
+    }
+
+    #[test]
+    fn test_remap_roughness() {
+        let ft = HashMap::new();
+        let st = HashMap::new();
+        let empty = ParamSet::default();
+        let remap = |ps: &ParamSet, raw_roughness| {
+            let mp = TextureParams::new(ps, &empty, &ft, &st);
+            let opts = PbrtOptions {
+                raw_roughness,
+                ..PbrtOptions::default()
+            };
+            remap_roughness(&mp, &opts, &["roughness"])
+        };
+
+        // The global option gives the default...
+        assert!(remap(&empty, false));
+        assert!(!remap(&empty, true));
+        // ... which the material can override
+        let mut ps = ParamSet::default();
+        ps.init(vec![ParamListEntry::new(
+            ParamType::Bool,
+            "remaproughness".to_owned(),
+            Array::StrArray(vec!["true".to_owned()]),
+        )]);
+        assert!(remap(&ps, true));
+    }
+}
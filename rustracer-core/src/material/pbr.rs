@@ -0,0 +1,184 @@
+use std::sync::Arc;
+
+use light_arena::Allocator;
+use log::info;
+
+use crate::bsdf::{
+    fr_dielectric, Bsdf, BxDFHolder, Fresnel, LambertianReflection, MicrofacetReflection,
+    TrowbridgeReitzDistribution,
+};
+use crate::interaction::SurfaceInteraction;
+use crate::material::{Material, MaterialRef, TransportMode};
+use crate::paramset::TextureParams;
+use crate::spectrum::Spectrum;
+use crate::texture::TextureRef;
+use crate::{clamp, lerp};
+
+/// Index of refraction of the dielectric part of the material (a reflectance of 4% at normal
+/// incidence), as in glTF
+const ETA: f32 = 1.5;
+/// Smallest alpha of the microfacet distribution, to avoid the singularity of perfectly smooth
+/// surfaces
+const MIN_ALPHA: f32 = 1e-3;
+
+/// Material following the metallic / roughness convention of glTF and of most game assets:
+///
+/// * `"basecolor"`: diffuse albedo of dielectrics, or specular colour of metals;
+/// * `"metallic"`: blends between a dielectric (0) and a metal (1);
+/// * `"roughness"`: perceptual roughness, whose square is the microfacet distribution's alpha;
+/// * `"normalmap"`: optional tangent space normal map (see `material::normal_map()`), which
+///   like other non-colour data should be read with `"float gamma" [1]`.
+///
+/// The dielectric part is a Lambertian lobe under a glossy coat, and the metallic part a glossy
+/// lobe whose reflectance at normal incidence is the base colour (Schlick's approximation).
+#[derive(Debug)]
+pub struct PbrMaterial {
+    base_color: TextureRef<Spectrum>,
+    metallic: TextureRef<f32>,
+    roughness: TextureRef<f32>,
+    normal_map: Option<TextureRef<Spectrum>>,
+    bump_map: Option<TextureRef<f32>>,
+}
+
+impl PbrMaterial {
+    pub fn create(mp: &TextureParams<'_>) -> MaterialRef {
+        info!("Creating PBR material");
+        let base_color = mp.get_spectrum_texture("basecolor", &Spectrum::grey(0.5));
+        let metallic = mp.get_float_texture("metallic", 0.0);
+        let roughness = mp.get_float_texture("roughness", 0.5);
+        let normal_map = mp.get_spectrum_texture_or_none("normalmap");
+        let bump_map = super::get_bump_map(mp);
+
+        Arc::new(PbrMaterial {
+            base_color,
+            metallic,
+            roughness,
+            normal_map,
+            bump_map,
+        })
+    }
+}
+
+impl Material for PbrMaterial {
+    fn compute_scattering_functions<'b>(
+        &self,
+        si: &mut SurfaceInteraction,
+        _mode: TransportMode,
+        _allow_multiple_lobes: bool,
+        arena: &'b Allocator<'_>,
+    ) -> Bsdf<'b> {
+        if let Some(ref normal_map) = self.normal_map {
+            super::normal_map(normal_map, si);
+        }
+        if let Some(ref bump) = self.bump_map {
+            super::bump(bump, si);
+        }
+        let base_color = self.base_color.evaluate(si).clamp();
+        let metallic = clamp(self.metallic.evaluate(si), 0.0, 1.0);
+        let roughness = clamp(self.roughness.evaluate(si), 0.0, 1.0);
+
+        let mut bxdfs = BxDFHolder::new(arena);
+        let kd = base_color * (1.0 - metallic);
+        if !kd.is_black() {
+            bxdfs.add(arena.alloc(LambertianReflection::new(kd)));
+        }
+        let alpha = f32::max(roughness * roughness, MIN_ALPHA);
+        let distrib = arena.alloc(TrowbridgeReitzDistribution::new(alpha, alpha));
+        let fresnel = arena.alloc(MetallicFresnel {
+            base_color,
+            metallic,
+        });
+        bxdfs.add(arena.alloc(MicrofacetReflection::new(
+            Spectrum::white(),
+            distrib,
+            fresnel,
+        )));
+
+        Bsdf::new(si, 1.0, bxdfs.into_slice())
+    }
+}
+
+/// Fresnel reflectance of the specular lobe: that of a dielectric, blended with Schlick's
+/// approximation for a metal of reflectance `base_color` at normal incidence according to the
+/// metalness.
+#[derive(Debug, Clone, Copy)]
+struct MetallicFresnel {
+    base_color: Spectrum,
+    metallic: f32,
+}
+
+impl Fresnel for MetallicFresnel {
+    fn evaluate(&self, cos_theta_i: f32) -> Spectrum {
+        let cos_theta_i = cos_theta_i.abs();
+        let dielectric = Spectrum::grey(fr_dielectric(cos_theta_i, 1.0, ETA));
+        let m = clamp(1.0 - cos_theta_i, 0.0, 1.0);
+        let schlick = lerp((m * m) * (m * m) * m, self.base_color, Spectrum::white());
+        lerp(self.metallic, dielectric, schlick)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use light_arena::MemoryArena;
+
+    use super::*;
+    use crate::bsdf::BxDFType;
+    use crate::shapes::Sphere;
+    use crate::texture::ConstantTexture;
+    use crate::transform::Transform;
+    use crate::{Normal3f, Point2f, Point3f, Vector3f};
+
+    #[test]
+    fn test_metallic_blend() {
+        let sphere = Sphere::new(Transform::default(), 1.0, -1.0, 1.0, 360.0, false);
+        let red = Spectrum::rgb(0.9, 0.1, 0.1);
+        // BSDF for light arriving from `wi` and leaving along the normal
+        let f = |metallic: f32, wi: Vector3f| {
+            let material = PbrMaterial {
+                base_color: Arc::new(ConstantTexture::new(red)),
+                metallic: Arc::new(ConstantTexture::new(metallic)),
+                roughness: Arc::new(ConstantTexture::new(0.3)),
+                normal_map: None,
+                bump_map: None,
+            };
+            let mut si = SurfaceInteraction::new(
+                Point3f::new(0.0, 0.0, 1.0),
+                Vector3f::new(0.0, 0.0, 0.0),
+                Point2f::new(0.3, 0.4),
+                0.0,
+                Vector3f::new(0.0, 0.0, 1.0),
+                Vector3f::new(1.0, 0.0, 0.0),
+                Vector3f::new(0.0, 1.0, 0.0),
+                Normal3f::new(0.0, 0.0, 0.0),
+                Normal3f::new(0.0, 0.0, 0.0),
+                &sphere,
+            );
+            let mut arena = MemoryArena::new(1);
+            let alloc = arena.allocator();
+            let bsdf = material.compute_scattering_functions(
+                &mut si,
+                TransportMode::RADIANCE,
+                true,
+                &alloc,
+            );
+            bsdf.f(&Vector3f::new(0.0, 0.0, 1.0), &wi, BxDFType::all())
+        };
+        let off_specular = Vector3f::new(1.0, 0.0, 1.0).normalize();
+        let specular = Vector3f::new(0.0, 0.0, 1.0);
+
+        // A dielectric is mostly diffuse, with a white highlight
+        let dielectric = f(0.0, off_specular);
+        assert!(
+            (dielectric.r - 0.9 * std::f32::consts::FRAC_1_PI).abs() < 0.02,
+            "{:?}",
+            dielectric
+        );
+        let highlight = f(0.0, specular) - f(0.0, off_specular);
+        assert!(highlight.r > 0.0 && (highlight.r - highlight.g).abs() < 1e-3);
+
+        // A metal has no diffuse lobe and a tinted highlight
+        let metal = f(1.0, specular);
+        assert!(metal.r > 8.0 * metal.g, "{:?}", metal);
+        assert!(f(1.0, off_specular).r < 0.1 * metal.r);
+    }
+}
@@ -8,28 +8,29 @@ use crate::bsdf::{
     TrowbridgeReitzDistribution,
 };
 use crate::interaction::SurfaceInteraction;
-use crate::material::{Material, TransportMode};
+use crate::material::{self, Material, MaterialRef, TransportMode};
 use crate::paramset::TextureParams;
 use crate::spectrum::Spectrum;
-use crate::texture::{TextureFloat, TextureSpectrum};
+use crate::texture::TextureRef;
+use crate::PbrtOptions;
 
 #[derive(Debug)]
 pub struct Plastic {
-    kd: Arc<TextureSpectrum>,
-    ks: Arc<TextureSpectrum>,
-    roughness: Arc<TextureFloat>,
-    bump_map: Option<Arc<TextureFloat>>,
+    kd: TextureRef<Spectrum>,
+    ks: TextureRef<Spectrum>,
+    roughness: TextureRef<f32>,
+    bump_map: Option<TextureRef<f32>>,
     remap_roughness: bool,
 }
 
 impl Plastic {
-    pub fn create(mp: &TextureParams<'_>) -> Arc<dyn Material> {
+    pub fn create(mp: &TextureParams<'_>, opts: &PbrtOptions) -> MaterialRef {
         info!("Creating Plastic material");
         let Kd = mp.get_spectrum_texture("Kd", &Spectrum::grey(0.25));
         let Ks = mp.get_spectrum_texture("Ks", &Spectrum::grey(0.25));
         let roughness = mp.get_float_texture("roughness", 0.1);
-        let bump_map = mp.get_float_texture_or_none("bumpmap");
-        let remap_roughness = mp.find_bool("remaproughness", true);
+        let bump_map = super::get_bump_map(mp);
+        let remap_roughness = material::remap_roughness(mp, opts, &["roughness"]);
 
         Arc::new(Plastic {
             kd: Kd,
@@ -42,13 +43,13 @@ impl Plastic {
 }
 
 impl Material for Plastic {
-    fn compute_scattering_functions<'a, 'b>(
+    fn compute_scattering_functions<'b>(
         &self,
-        si: &mut SurfaceInteraction<'a, 'b>,
+        si: &mut SurfaceInteraction,
         _mode: TransportMode,
         _allow_multiple_lobes: bool,
         arena: &'b Allocator<'_>,
-    ) {
+    ) -> Bsdf<'b> {
         if let Some(ref bump) = self.bump_map {
             super::bump(bump, si);
         }
@@ -69,7 +70,6 @@ impl Material for Plastic {
             bxdfs.add(arena.alloc(MicrofacetReflection::new(ks, distrib, fresnel)));
         }
 
-        let bsdf: Bsdf<'b> = Bsdf::new(si, 1.0, bxdfs.into_slice());
-        si.bsdf = Some(Arc::new(bsdf));
+        Bsdf::new(si, 1.0, bxdfs.into_slice())
     }
 }
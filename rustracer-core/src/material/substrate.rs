@@ -4,29 +4,30 @@ use light_arena::Allocator;
 
 use crate::bsdf::{Bsdf, BxDFHolder, FresnelBlend, TrowbridgeReitzDistribution};
 use crate::interaction::SurfaceInteraction;
-use crate::material::{Material, TransportMode};
+use crate::material::{self, Material, MaterialRef, TransportMode};
 use crate::paramset::TextureParams;
 use crate::spectrum::Spectrum;
-use crate::texture::{TextureFloat, TextureSpectrum};
+use crate::texture::TextureRef;
+use crate::PbrtOptions;
 
 #[derive(Debug)]
 pub struct SubstrateMaterial {
-    kd: Arc<TextureSpectrum>,
-    ks: Arc<TextureSpectrum>,
-    nu: Arc<TextureFloat>,
-    nv: Arc<TextureFloat>,
-    bump_map: Option<Arc<TextureFloat>>,
+    kd: TextureRef<Spectrum>,
+    ks: TextureRef<Spectrum>,
+    nu: TextureRef<f32>,
+    nv: TextureRef<f32>,
+    bump_map: Option<TextureRef<f32>>,
     remap_roughness: bool,
 }
 
 impl SubstrateMaterial {
-    pub fn create(mp: &TextureParams<'_>) -> Arc<dyn Material> {
+    pub fn create(mp: &TextureParams<'_>, opts: &PbrtOptions) -> MaterialRef {
         let kd = mp.get_spectrum_texture("Kd", &Spectrum::grey(0.5));
         let ks = mp.get_spectrum_texture("Ks", &Spectrum::grey(0.5));
         let urough = mp.get_float_texture("uroughness", 0.1);
         let vrough = mp.get_float_texture("vroughness", 0.1);
-        let bump_map = mp.get_float_texture_or_none("bumpmap");
-        let remap_roughness = mp.find_bool("remaproughness", true);
+        let bump_map = super::get_bump_map(mp);
+        let remap_roughness = material::remap_roughness(mp, opts, &["uroughness", "vroughness"]);
 
         Arc::new(SubstrateMaterial {
             kd,
@@ -40,13 +41,13 @@ impl SubstrateMaterial {
 }
 
 impl Material for SubstrateMaterial {
-    fn compute_scattering_functions<'a, 'b>(
+    fn compute_scattering_functions<'b>(
         &self,
-        si: &mut SurfaceInteraction<'a, 'b>,
+        si: &mut SurfaceInteraction,
         _mode: TransportMode,
         _allow_multiple_lobes: bool,
         arena: &'b Allocator<'_>,
-    ) {
+    ) -> Bsdf<'b> {
         if let Some(ref bump) = self.bump_map {
             super::bump(bump, si);
         }
@@ -66,7 +67,6 @@ impl Material for SubstrateMaterial {
             bxdfs.add(arena.alloc(FresnelBlend::new(s, d, distrib)));
         }
 
-        let bsdf = Bsdf::new(si, 1.0, bxdfs.into_slice());
-        si.bsdf = Some(Arc::new(bsdf));
+        Bsdf::new(si, 1.0, bxdfs.into_slice())
     }
 }
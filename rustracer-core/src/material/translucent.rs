@@ -7,31 +7,32 @@ use crate::bsdf::{
     MicrofacetReflection, MicrofacetTransmission, TrowbridgeReitzDistribution,
 };
 use crate::interaction::SurfaceInteraction;
-use crate::material::{Material, TransportMode};
+use crate::material::{self, Material, MaterialRef, TransportMode};
 use crate::paramset::TextureParams;
 use crate::spectrum::Spectrum;
-use crate::texture::{TextureFloat, TextureSpectrum};
+use crate::texture::TextureRef;
+use crate::PbrtOptions;
 
 #[derive(Debug)]
 pub struct TranslucentMaterial {
-    kd: Arc<TextureSpectrum>,
-    ks: Arc<TextureSpectrum>,
-    roughness: Arc<TextureFloat>,
-    reflect: Arc<TextureSpectrum>,
-    transmit: Arc<TextureSpectrum>,
-    bumpmap: Option<Arc<TextureFloat>>,
+    kd: TextureRef<Spectrum>,
+    ks: TextureRef<Spectrum>,
+    roughness: TextureRef<f32>,
+    reflect: TextureRef<Spectrum>,
+    transmit: TextureRef<Spectrum>,
+    bumpmap: Option<TextureRef<f32>>,
     remap_roughness: bool,
 }
 
 impl TranslucentMaterial {
-    pub fn create(mp: &TextureParams<'_>) -> Arc<dyn Material> {
+    pub fn create(mp: &TextureParams<'_>, opts: &PbrtOptions) -> MaterialRef {
         let kd = mp.get_spectrum_texture("Kd", &Spectrum::from(0.25));
         let ks = mp.get_spectrum_texture("Ks", &Spectrum::from(0.25));
         let reflect = mp.get_spectrum_texture("reflect", &Spectrum::from(0.5));
         let transmit = mp.get_spectrum_texture("transmit", &Spectrum::from(0.5));
         let roughness = mp.get_float_texture("roughness", 0.1);
-        let bumpmap = mp.get_float_texture_or_none("bumpmap");
-        let remap_roughness = mp.find_bool("remaproughness", true);
+        let bumpmap = super::get_bump_map(mp);
+        let remap_roughness = material::remap_roughness(mp, opts, &["roughness"]);
 
         Arc::new(TranslucentMaterial {
             kd,
@@ -46,13 +47,13 @@ impl TranslucentMaterial {
 }
 
 impl Material for TranslucentMaterial {
-    fn compute_scattering_functions<'a, 'b>(
+    fn compute_scattering_functions<'b>(
         &self,
-        si: &mut SurfaceInteraction<'a, 'b>,
+        si: &mut SurfaceInteraction,
         mode: TransportMode,
         _allow_multiple_lobes: bool,
         arena: &'b Allocator<'_>,
-    ) {
+    ) -> Bsdf<'b> {
         let mut bxdfs = BxDFHolder::new(arena);
         let eta = 1.5;
 
@@ -96,7 +97,6 @@ impl Material for TranslucentMaterial {
             }
         }
 
-        let bsdf: Bsdf<'b> = Bsdf::new(si, eta, bxdfs.into_slice());
-        si.bsdf = Some(Arc::new(bsdf));
+        Bsdf::new(si, eta, bxdfs.into_slice())
     }
 }
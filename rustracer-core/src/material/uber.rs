@@ -7,28 +7,29 @@ use crate::bsdf::{
     SpecularTransmission, TrowbridgeReitzDistribution,
 };
 use crate::interaction::SurfaceInteraction;
-use crate::material::{Material, TransportMode};
+use crate::material::{self, Material, MaterialRef, TransportMode};
 use crate::paramset::TextureParams;
 use crate::spectrum::Spectrum;
-use crate::texture::{TextureFloat, TextureSpectrum};
+use crate::texture::TextureRef;
+use crate::PbrtOptions;
 
 #[derive(Debug)]
 pub struct UberMaterial {
-    kd: Arc<TextureSpectrum>,
-    ks: Arc<TextureSpectrum>,
-    kr: Arc<TextureSpectrum>,
-    kt: Arc<TextureSpectrum>,
-    opacity: Arc<TextureSpectrum>,
-    roughness: Arc<TextureFloat>,
-    roughnessu: Option<Arc<TextureFloat>>,
-    roughnessv: Option<Arc<TextureFloat>>,
-    eta: Arc<TextureFloat>,
-    bumpmap: Option<Arc<TextureFloat>>,
+    kd: TextureRef<Spectrum>,
+    ks: TextureRef<Spectrum>,
+    kr: TextureRef<Spectrum>,
+    kt: TextureRef<Spectrum>,
+    opacity: TextureRef<Spectrum>,
+    roughness: TextureRef<f32>,
+    roughnessu: Option<TextureRef<f32>>,
+    roughnessv: Option<TextureRef<f32>>,
+    eta: TextureRef<f32>,
+    bumpmap: Option<TextureRef<f32>>,
     remap_roughness: bool,
 }
 
 impl UberMaterial {
-    pub fn create(mp: &TextureParams<'_>) -> Arc<dyn Material> {
+    pub fn create(mp: &TextureParams<'_>, opts: &PbrtOptions) -> MaterialRef {
         let kd = mp.get_spectrum_texture("Kd", &Spectrum::from(0.25));
         let ks = mp.get_spectrum_texture("Ks", &Spectrum::from(0.25));
         let kr = mp.get_spectrum_texture("Kr", &Spectrum::from(0.0));
@@ -40,8 +41,9 @@ impl UberMaterial {
             .get_float_texture_or_none("eta")
             .unwrap_or_else(|| mp.get_float_texture("index", 1.5));
         let opacity = mp.get_spectrum_texture("opacity", &Spectrum::from(1.0));
-        let bumpmap = mp.get_float_texture_or_none("bumpmap");
-        let remap_roughness = mp.find_bool("remaproughness", true);
+        let bumpmap = super::get_bump_map(mp);
+        let remap_roughness =
+            material::remap_roughness(mp, opts, &["roughness", "uroughness", "vroughness"]);
 
         Arc::new(UberMaterial {
             kd,
@@ -60,13 +62,13 @@ impl UberMaterial {
 }
 
 impl Material for UberMaterial {
-    fn compute_scattering_functions<'a, 'b>(
+    fn compute_scattering_functions<'b>(
         &self,
-        si: &mut SurfaceInteraction<'a, 'b>,
+        si: &mut SurfaceInteraction,
         mode: TransportMode,
         _allow_multiple_lobes: bool,
         arena: &'b Allocator<'_>,
-    ) {
+    ) -> Bsdf<'b> {
         let mut bxdfs = BxDFHolder::new(arena);
 
         if let Some(ref bump_map) = self.bumpmap {
@@ -120,7 +122,6 @@ impl Material for UberMaterial {
             bxdfs.add(arena.alloc(SpecularTransmission::new(kt, 1.0, e, mode)));
         }
 
-        let bsdf: Bsdf<'b> = Bsdf::new(si, eta, bxdfs.into_slice());
-        si.bsdf = Some(Arc::new(bsdf));
+        Bsdf::new(si, eta, bxdfs.into_slice())
     }
 }
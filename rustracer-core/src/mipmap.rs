@@ -1,35 +1,49 @@
 use std::cmp;
 use std::f32;
 use std::fmt::{self, Debug};
-use std::ops::{AddAssign, Div, Mul};
 
 use lazy_static::lazy_static;
-use log::{debug, info, trace};
+use log::{debug, info, log_enabled, trace, Level};
 use ndarray::parallel::prelude::*;
 use ndarray::prelude::*;
 use ndarray::Zip;
-use num::{zero, Zero};
+use num::zero;
 
 use crate::blockedarray::BlockedArray;
-use crate::{clamp, is_power_of_2, lerp, round_up_pow_2};
-use crate::{Clampable, Point2f, Point2i, Vector2f};
+use crate::spectrum::CoefficientSpectrum;
+use crate::{clamp, is_power_of_2, round_up_pow_2};
+use crate::{Point2f, Point2i, Vector2f};
 
 stat_counter!("Texture/EWA lookups", n_ewa_lookups);
 stat_counter!("Texture/Trilinear lookups", n_trilerp_lookups);
+stat_counter!("Texture/Nearest lookups", n_nearest_lookups);
 stat_memory_counter!("Memory/Texture MIP maps", mipmap_memory);
 pub fn init_stats() {
     n_ewa_lookups::init();
     n_trilerp_lookups::init();
+    n_nearest_lookups::init();
     mipmap_memory::init();
 }
 
-#[derive(Debug)]
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
 pub enum WrapMode {
     Repeat,
     Black,
     Clamp,
 }
 
+/// How texels are filtered by `MIPMap::lookup_diff()`.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum FilterMode {
+    /// Closest texel of the closest finer MIP level, without any interpolation. This is meant for
+    /// data textures (normal maps, masks...) where blending texels makes no sense.
+    Nearest,
+    /// Bilinear interpolation between the two MIP levels closest to the filter width.
+    Trilinear,
+    /// Elliptically weighted average, taking the anisotropy of the footprint into account.
+    Ewa,
+}
+
 const WEIGHT_LUT_SIZE: usize = 128;
 lazy_static! {
     static ref WEIGHT_LUT: [f32; WEIGHT_LUT_SIZE] = {
@@ -44,7 +58,7 @@ lazy_static! {
 }
 
 pub struct MIPMap<T> {
-    do_trilinear: bool,
+    filter_mode: FilterMode,
     max_anisotropy: f32,
     wrap_mode: WrapMode,
     resolution: Point2i,
@@ -54,22 +68,15 @@ pub struct MIPMap<T> {
 
 impl<T> MIPMap<T>
 where
-    T: Zero,
-    T: Clone,
-    T: Copy,
-    T: Clampable,
-    T: Debug,
-    T: AddAssign<T>,
-    T: Mul<f32, Output = T>,
-    T: Div<f32, Output = T>,
-    T: Send + Sync,
+    T: CoefficientSpectrum,
 {
     pub fn new(
         res: Point2i,
         img: &[T],
-        do_trilinear: bool,
+        filter_mode: FilterMode,
         max_anisotropy: f32,
         wrap_mode: WrapMode,
+        max_level: Option<usize>,
     ) -> MIPMap<T> {
         debug!("Creating MIPMap for texture");
         let (resolution, img_data) = if !is_power_of_2(res.x) || !is_power_of_2(res.y) {
@@ -102,7 +109,7 @@ where
                                 WrapMode::Black => orig_s,
                             };
                             if orig_s >= 0 && orig_s < res.x as isize {
-                                row[s] += img[(t * res.x as usize + orig_s as usize) as usize]
+                                row[s] += img[t * res.x as usize + orig_s as usize]
                                     * s_weights[s].weights[j];
                             }
                         }
@@ -140,14 +147,14 @@ where
         } else {
             (
                 res,
-                ArrayView2::from_shape((res.x as usize, res.y as usize), img)
+                ArrayView2::from_shape((res.y as usize, res.x as usize), img)
                     .unwrap()
                     .to_owned(),
             )
         };
 
         let mut mipmap = MIPMap {
-            do_trilinear,
+            filter_mode,
             max_anisotropy,
             wrap_mode,
             resolution,
@@ -155,8 +162,10 @@ where
             black: zero(),
         };
 
-        // initialize levels of MIPMap for image
-        let n_levels = 1 + (cmp::max(resolution.x, resolution.y) as f32).log2() as usize;
+        // initialize levels of MIPMap for image, possibly stopping before the 1x1 level
+        let n_levels = max_level.map_or(mipmap.full_levels(), |max_level| {
+            cmp::min(mipmap.full_levels(), max_level + 1)
+        });
         debug!("mipmap will have {} levels", n_levels);
         // Initialize most detailed level of the pyramid
         // level 0
@@ -187,7 +196,11 @@ where
         }
 
         mipmap_memory::add(
-            (4 * resolution.x as u64 * resolution.y as u64 * ::std::mem::size_of::<T>() as u64) / 3,
+            mipmap
+                .pyramid
+                .iter()
+                .map(|l| (l.u_size() * l.v_size() * size_of::<T>()) as u64)
+                .sum(),
         );
 
         mipmap
@@ -205,6 +218,25 @@ where
         self.pyramid.len()
     }
 
+    /// Number of levels the pyramid would have if it went all the way down to 1x1. The level of
+    /// detail of a lookup is computed relative to this, so that limiting the number of levels
+    /// doesn't change which level is used for a given filter width.
+    fn full_levels(&self) -> usize {
+        1 + (cmp::max(self.resolution.x, self.resolution.y) as f32).log2() as usize
+    }
+
+    /// Value of the texture filtered over its whole extent, as used for footprints larger than
+    /// the coarsest level.
+    fn coarsest(&self, st: Point2f) -> T {
+        let level = self.levels() - 1;
+        let l = &self.pyramid[level];
+        if l.u_size() == 1 && l.v_size() == 1 {
+            *self.texel(level, 0, 0)
+        } else {
+            self.triangle(level, st)
+        }
+    }
+
     pub fn texel(&self, level: usize, s: isize, t: isize) -> &T {
         let l = &self.pyramid[level];
         let (u_size, v_size) = (l.u_size() as isize, l.v_size() as isize);
@@ -227,16 +259,16 @@ where
     pub fn lookup(&self, st: Point2f, width: f32) -> T {
         n_trilerp_lookups::inc();
         // Compute MIPMap-level for trilinear filtering
-        let level = self.levels() as f32 - 1.0 + width.max(1e-8).log2();
+        let level = self.full_levels() as f32 - 1.0 + width.max(1e-8).log2();
         // Perform trilinear interpolation at appropriate MIPMap level
         if level < 0.0 {
             self.triangle(0, st)
         } else if level >= self.levels() as f32 - 1.0 {
-            *self.texel(self.levels() - 1, 0, 0)
+            self.coarsest(st)
         } else {
             let i_level = level.floor();
             let delta = level - i_level;
-            lerp(
+            T::lerp(
                 delta,
                 self.triangle(i_level as usize, st),
                 self.triangle(i_level as usize + 1, st),
@@ -244,13 +276,30 @@ where
         }
     }
 
+    /// Return the texel closest to `st` in the finer of the two MIP levels closest to the filter
+    /// `width`, without any interpolation.
+    pub fn lookup_nearest(&self, st: Point2f, width: f32) -> T {
+        n_nearest_lookups::inc();
+        let level = self.full_levels() as f32 - 1.0 + width.max(1e-8).log2();
+        let level = clamp(level.floor(), 0.0, self.levels() as f32 - 1.0) as usize;
+        let l = &self.pyramid[level];
+        let s = (st.x * l.u_size() as f32).floor() as isize;
+        let t = (st.y * l.v_size() as f32).floor() as isize;
+        *self.texel(level, s, t)
+    }
+
     pub fn lookup_diff(&self, st: Point2f, mut dst0: Vector2f, mut dst1: Vector2f) -> T {
-        if self.do_trilinear {
-            let width = f32::max(
-                f32::max(f32::abs(dst0[0]), f32::abs(dst0[1])),
-                f32::max(f32::abs(dst1[0]), f32::abs(dst1[1])),
-            );
-            return self.lookup(st, 2.0 * width);
+        if self.filter_mode != FilterMode::Ewa {
+            let width = 2.0
+                * f32::max(
+                    f32::max(f32::abs(dst0[0]), f32::abs(dst0[1])),
+                    f32::max(f32::abs(dst1[0]), f32::abs(dst1[1])),
+                );
+            return if self.filter_mode == FilterMode::Nearest {
+                self.lookup_nearest(st, width)
+            } else {
+                self.lookup(st, width)
+            };
         }
         n_ewa_lookups::inc();
 
@@ -272,16 +321,37 @@ where
         }
 
         // Choose level of detail for EWA lookup and perform EWA filtering
-        let lod = f32::max(0.0, self.levels() as f32 - 1.0 + f32::log2(minor_length));
+        let lod = f32::max(
+            0.0,
+            self.full_levels() as f32 - 1.0 + f32::log2(minor_length),
+        );
         let ilod = f32::floor(lod) as usize;
 
-        lerp(
+        T::lerp(
             lod - ilod as f32,
             self.EWA(ilod, st, dst0, dst1),
             self.EWA(ilod + 1, st, dst0, dst1),
         )
     }
 
+    /// Approximate MIP level and texel block read by `lookup_diff()` for the given lookup. Doing
+    /// lookups sorted by this key makes consecutive ones touch the same part of the pyramid.
+    pub fn lookup_key(&self, st: Point2f, dst0: Vector2f, dst1: Vector2f) -> (usize, usize) {
+        let width = 2.0
+            * f32::max(
+                f32::max(f32::abs(dst0[0]), f32::abs(dst0[1])),
+                f32::max(f32::abs(dst1[0]), f32::abs(dst1[1])),
+            );
+        let max_level = self.levels() - 1;
+        let level =
+            (self.full_levels() as f32 - 1.0 + width.max(1e-8).log2()).clamp(0.0, max_level as f32);
+        let l = &self.pyramid[level as usize];
+        let s = (st.x.rem_euclid(1.0) * l.u_size() as f32) as usize;
+        let t = (st.y.rem_euclid(1.0) * l.v_size() as f32) as usize;
+        let u_blocks = l.u_size().div_ceil(l.block_size());
+        (level as usize, l.block(t) * u_blocks + l.block(s))
+    }
+
     pub fn triangle(&self, level: usize, st: Point2f) -> T {
         let level = clamp(level, 0, self.levels() - 1);
         let s = st.x * self.pyramid[level].u_size() as f32 - 0.5;
@@ -290,16 +360,18 @@ where
         let t0 = t.floor() as isize;
         let ds = s - s0 as f32;
         let dt = t - t0 as f32;
-        trace!(
-            "st={:?}, s={}, t={}, s0={}, t0={}, ds={}, dt={}",
-            st,
-            s,
-            t,
-            s0,
-            t0,
-            ds,
-            dt
-        );
+        if log_enabled!(Level::Trace) {
+            trace!(
+                "st={:?}, s={}, t={}, s0={}, t0={}, ds={}, dt={}",
+                st,
+                s,
+                t,
+                s0,
+                t0,
+                ds,
+                dt
+            );
+        }
 
         *self.texel(level, s0, t0) * (1.0 - ds) * (1.0 - dt)
             + *self.texel(level, s0, t0 + 1) * (1.0 - ds) * dt
@@ -309,7 +381,7 @@ where
 
     fn EWA(&self, level: usize, mut st: Point2f, mut dst0: Vector2f, mut dst1: Vector2f) -> T {
         if level >= self.levels() {
-            return *self.texel(self.levels() - 1, 0, 0);
+            return self.coarsest(st);
         }
         // Convert EWA coordinates to appropriate scale for level
         st[0] = st[0] * self.pyramid[level].u_size() as f32 - 0.5;
@@ -411,7 +483,7 @@ where
 impl<T: Debug> Debug for MIPMap<T> {
     fn fmt(&self, fmt: &mut fmt::Formatter<'_>) -> fmt::Result {
         fmt.debug_struct("MIPMap")
-            .field("do_trilinear", &self.do_trilinear)
+            .field("filter_mode", &self.filter_mode)
             .field("max_anisotropy", &self.max_anisotropy)
             .field("wrap_mode", &self.wrap_mode)
             .field("resolution", &self.resolution)
@@ -510,3 +582,58 @@ fn test_array2_blockedarray() {
     assert_eq!(ba[(2, 0)], 2);
     assert_eq!(ba[(3, 2)], 23);
 }
+
+#[test]
+fn test_max_level() {
+    // 8x4 checkerboard
+    let res = Point2i::new(8, 4);
+    let img: Vec<f32> = (0..32).map(|i| ((i % 8 + i / 8) % 2) as f32).collect();
+    let full = MIPMap::new(
+        res,
+        &img,
+        FilterMode::Trilinear,
+        8.0,
+        WrapMode::Repeat,
+        None,
+    );
+    assert_eq!(full.levels(), 4);
+    let limited = MIPMap::new(
+        res,
+        &img,
+        FilterMode::Trilinear,
+        8.0,
+        WrapMode::Repeat,
+        Some(1),
+    );
+    assert_eq!(limited.levels(), 2);
+    assert_eq!(limited.pyramid[1].u_size(), 4);
+    assert_eq!(limited.pyramid[1].v_size(), 2);
+
+    // Levels that are kept are selected the same way
+    let st = Point2f::new(0.3, 0.6);
+    assert_eq!(full.lookup(st, 0.2), limited.lookup(st, 0.2));
+    // Wider footprints are filtered from the coarsest level that was kept
+    assert_eq!(limited.lookup(st, 1.0), limited.triangle(1, st));
+}
+
+#[test]
+fn test_nearest_lookup() {
+    let res = Point2i::new(4, 4);
+    let img: Vec<f32> = (0..16).map(|i| i as f32).collect();
+    let mipmap = MIPMap::new(
+        res,
+        &img,
+        FilterMode::Nearest,
+        8.0,
+        WrapMode::Clamp,
+        Some(0),
+    );
+    // No interpolation between texels, whatever the footprint
+    let st = Point2f::new(0.49, 0.76);
+    assert_eq!(mipmap.lookup_nearest(st, 0.0), 13.0);
+    assert_eq!(mipmap.lookup_nearest(st, 1.0), 13.0);
+    assert_eq!(
+        mipmap.lookup_diff(st, Vector2f::new(0.3, 0.0), Vector2f::new(0.0, 0.3)),
+        13.0
+    );
+}
@@ -1,3 +1,11 @@
+//! Procedural noise functions.
+//!
+//! All the functions in this module are deterministic: they only rely on IEEE-754 additions,
+//! multiplications and `floor()` (which are exactly rounded on every platform) plus integer
+//! arithmetic, and deliberately avoid `libm` functions like `log2()` whose last bits can vary
+//! between platforms. This means a given point and seed always produce the exact same value, on
+//! any machine and across renders.
+
 use crate::{clamp, lerp, Point3f, Vector3f};
 
 /// Perlin noise
@@ -6,6 +14,12 @@ pub fn noise3(p: &Point3f) -> f32 {
 }
 
 pub fn noise(x: f32, y: f32, z: f32) -> f32 {
+    noise_seeded(x, y, z, 0)
+}
+
+/// Perlin noise, with a seed selecting a different (but equally deterministic) noise pattern.
+/// A seed of 0 gives the same result as `noise()`.
+pub fn noise_seeded(x: f32, y: f32, z: f32, seed: u32) -> f32 {
     // Compute noise cell coordinates and offsets
     let mut ix = x.floor() as i32;
     let mut iy = y.floor() as i32;
@@ -14,6 +28,12 @@ pub fn noise(x: f32, y: f32, z: f32) -> f32 {
     let dy = y - iy as f32;
     let dz = z - iz as f32;
 
+    // Offset the lattice based on the seed
+    let h = mix_bits(seed);
+    ix = ix.wrapping_add((h & 0xff) as i32);
+    iy = iy.wrapping_add(((h >> 8) & 0xff) as i32);
+    iz = iz.wrapping_add(((h >> 16) & 0xff) as i32);
+
     // Compute gradient weights
     ix &= NOISE_PERM_SIZE as i32 - 1;
     iy &= NOISE_PERM_SIZE as i32 - 1;
@@ -43,9 +63,21 @@ pub fn noise(x: f32, y: f32, z: f32) -> f32 {
 
 /// Fractional Brownian Motion
 pub fn fbm(p: &Point3f, dpdx: &Vector3f, dpdy: &Vector3f, omega: f32, max_octaves: u32) -> f32 {
+    fbm_seeded(p, dpdx, dpdy, omega, max_octaves, 0)
+}
+
+/// Fractional Brownian Motion, using `noise_seeded()` for each octave.
+pub fn fbm_seeded(
+    p: &Point3f,
+    dpdx: &Vector3f,
+    dpdy: &Vector3f,
+    omega: f32,
+    max_octaves: u32,
+    seed: u32,
+) -> f32 {
     // Compute number of octaves for antialiased FBm
     let len2 = dpdx.length_squared().max(dpdy.length_squared());
-    let n = clamp(-1.0 - 0.5 * len2.log2(), 0.0, max_octaves as f32);
+    let n = clamp(-1.0 - 0.5 * log2(len2), 0.0, max_octaves as f32);
     let n_int = n.floor() as u32;
 
     // TODO replace with fold()?
@@ -54,16 +86,59 @@ pub fn fbm(p: &Point3f, dpdx: &Vector3f, dpdy: &Vector3f, omega: f32, max_octave
     let mut lambda = 1.0;
     let mut o = 1.0;
     for _ in 0..n_int {
-        sum += o * noise3(&(lambda * *p));
+        let pl = lambda * *p;
+        sum += o * noise_seeded(pl.x, pl.y, pl.z, seed);
         lambda *= 1.99;
         o *= omega;
     }
     let n_partial = n - n_int as f32;
-    sum += o * smooth_step(0.3, 0.7, n_partial) * noise3(&(lambda * *p));
+    let pl = lambda * *p;
+    sum += o * smooth_step(0.3, 0.7, n_partial) * noise_seeded(pl.x, pl.y, pl.z, seed);
 
     sum
 }
 
+/// Base-2 logarithm that gives bit-identical results on all platforms (unlike `f32::log2()`). It
+/// is accurate to about 1e-5, which is plenty to choose the number of noise octaves.
+fn log2(v: f32) -> f32 {
+    if v.is_nan() || v < 0.0 {
+        return f32::NAN;
+    }
+    if v == 0.0 {
+        return f32::NEG_INFINITY;
+    }
+    if v.is_infinite() {
+        return f32::INFINITY;
+    }
+    // Normalize denormals so the exponent extraction below is valid
+    let (v, bias) = if v < f32::MIN_POSITIVE {
+        (v * 8_388_608.0, 23)
+    } else {
+        (v, 0)
+    };
+    let bits = v.to_bits();
+    let exponent = ((bits >> 23) & 0xff) as i32 - 127 - bias;
+    // Mantissa in [1, 2)
+    let m = f32::from_bits((bits & 0x007f_ffff) | 0x3f80_0000);
+    // ln(m) = 2 * atanh((m - 1) / (m + 1)), with |y| <= 1/3
+    let y = (m - 1.0) / (m + 1.0);
+    let y2 = y * y;
+    let ln_m = 2.0 * y * (1.0 + y2 * (1.0 / 3.0 + y2 * (1.0 / 5.0 + y2 * (1.0 / 7.0))));
+    exponent as f32 + ln_m * std::f32::consts::LOG2_E
+}
+
+/// Integer hash (`fmix32` finalizer from MurmurHash3). Maps 0 to 0.
+#[inline]
+fn mix_bits(v: u32) -> u32 {
+    let mut h = v;
+    h ^= h >> 16;
+    h = h.wrapping_mul(0x85eb_ca6b);
+    h ^= h >> 13;
+    h = h.wrapping_mul(0xc2b2_ae35);
+    h ^= h >> 16;
+    h
+}
+
 #[inline]
 fn grad(x: i32, y: i32, z: i32, dx: f32, dy: f32, dz: f32) -> f32 {
     let mut h = NOISE_PERM[NOISE_PERM[NOISE_PERM[x as usize] + y as usize] + z as usize];
@@ -117,3 +192,40 @@ const NOISE_PERM: [usize; 2 * NOISE_PERM_SIZE] = [
     181, 199, 106, 157, 184, 84, 204, 176, 115, 121, 50, 45, 127, 4, 150, 254, 138, 236, 205, 93,
     222, 114, 67, 29, 24, 72, 243, 141, 128, 195, 78, 66, 215, 61, 156, 180,
 ];
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_noise_is_zero_on_lattice() {
+        for seed in 0..4 {
+            assert_eq!(noise_seeded(1.0, 2.0, 3.0, seed), 0.0);
+            assert_eq!(noise_seeded(-7.0, 0.0, 12.0, seed), 0.0);
+        }
+    }
+
+    #[test]
+    fn test_seed_changes_pattern() {
+        let p = Point3f::new(0.3, 1.7, -2.2);
+        assert_eq!(noise3(&p), noise_seeded(p.x, p.y, p.z, 0));
+        assert_ne!(
+            noise_seeded(p.x, p.y, p.z, 0),
+            noise_seeded(p.x, p.y, p.z, 1)
+        );
+        assert_eq!(
+            noise_seeded(p.x, p.y, p.z, 42),
+            noise_seeded(p.x, p.y, p.z, 42)
+        );
+    }
+
+    #[test]
+    fn test_log2() {
+        for &v in &[1e-30f32, 1e-7, 0.01, 0.5, 1.0, 1.5, 2.0, 3.0, 1000.0, 1e20] {
+            assert!((log2(v) - v.log2()).abs() < 1e-4, "log2({})", v);
+        }
+        assert_eq!(log2(1.0), 0.0);
+        assert_eq!(log2(0.25), -2.0);
+        assert_eq!(log2(0.0), f32::NEG_INFINITY);
+    }
+}
@@ -0,0 +1,115 @@
+//! Placement of the render threads on NUMA machines.
+//!
+//! On machines with several sockets, memory is attached to a specific node, and accessing
+//! another node's memory is slower. When NUMA placement is requested, each worker thread is
+//! pinned to the CPUs of one node (the nodes being used in turn), and allocates its sampler,
+//! memory arena and film tiles itself once pinned. With the kernel's default first-touch policy,
+//! this memory ends up local to the thread's node.
+use std::fs;
+use std::io;
+
+use log::{info, warn};
+
+/// The CPUs of each NUMA node, as listed in sysfs. Machines without NUMA information are treated
+/// as a single node containing all the CPUs.
+pub fn numa_nodes() -> Vec<Vec<usize>> {
+    let mut nodes: Vec<(usize, Vec<usize>)> = fs::read_dir("/sys/devices/system/node")
+        .map(|entries| {
+            entries
+                .filter_map(|entry| {
+                    let entry = entry.ok()?;
+                    let name = entry.file_name();
+                    let id = name.to_str()?.strip_prefix("node")?.parse().ok()?;
+                    let cpus = fs::read_to_string(entry.path().join("cpulist")).ok()?;
+                    Some((id, parse_cpu_list(&cpus)))
+                })
+                .filter(|(_, cpus)| !cpus.is_empty())
+                .collect()
+        })
+        .unwrap_or_default();
+    nodes.sort_by_key(|(id, _)| *id);
+    if nodes.is_empty() {
+        vec![(0..num_cpus::get()).collect()]
+    } else {
+        nodes.into_iter().map(|(_, cpus)| cpus).collect()
+    }
+}
+
+/// Parse a list of CPUs in the kernel's format, e.g. `0-3,8-11`. Invalid entries are ignored.
+pub fn parse_cpu_list(s: &str) -> Vec<usize> {
+    let mut cpus = Vec::new();
+    for range in s.trim().split(',').filter(|r| !r.is_empty()) {
+        let bounds = match range.split_once('-') {
+            Some((first, last)) => first
+                .parse::<usize>()
+                .and_then(|f| last.parse().map(|l| (f, l))),
+            None => range.parse::<usize>().map(|c| (c, c)),
+        };
+        match bounds {
+            Ok((first, last)) => cpus.extend(first..=last),
+            Err(_) => warn!("Ignoring invalid CPU range \"{}\"", range),
+        }
+    }
+    cpus
+}
+
+/// Pin the calling thread, the `thread_index`-th worker, to the CPUs of one of `nodes`.
+pub fn pin_worker_thread(thread_index: usize, nodes: &[Vec<usize>]) {
+    if nodes.is_empty() {
+        return;
+    }
+    let node = thread_index % nodes.len();
+    match set_thread_affinity(&nodes[node]) {
+        Ok(()) => info!(
+            "Pinned render thread {} to NUMA node {}",
+            thread_index, node
+        ),
+        Err(e) => warn!("Could not pin render thread {}: {}", thread_index, e),
+    }
+}
+
+#[cfg(target_os = "linux")]
+fn set_thread_affinity(cpus: &[usize]) -> io::Result<()> {
+    // Safety: cpu_set_t is a plain bitmask, which is valid when zeroed, and CPU_SET() is only
+    // called with indices that fit in it.
+    unsafe {
+        let mut set: libc::cpu_set_t = std::mem::zeroed();
+        for &cpu in cpus {
+            if cpu < libc::CPU_SETSIZE as usize {
+                libc::CPU_SET(cpu, &mut set);
+            }
+        }
+        if libc::sched_setaffinity(0, size_of::<libc::cpu_set_t>(), &set) != 0 {
+            return Err(io::Error::last_os_error());
+        }
+    }
+    Ok(())
+}
+
+#[cfg(not(target_os = "linux"))]
+fn set_thread_affinity(_cpus: &[usize]) -> io::Result<()> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        "thread affinity is only supported on Linux",
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_cpu_list() {
+        assert_eq!(parse_cpu_list("0\n"), vec![0]);
+        assert_eq!(parse_cpu_list("0-3,8-9"), vec![0, 1, 2, 3, 8, 9]);
+        assert_eq!(parse_cpu_list("1,x,4"), vec![1, 4]);
+        assert!(parse_cpu_list("").is_empty());
+    }
+
+    #[test]
+    fn test_numa_nodes_cover_some_cpus() {
+        let nodes = numa_nodes();
+        assert!(!nodes.is_empty());
+        assert!(nodes.iter().all(|cpus| !cpus.is_empty()));
+    }
+}
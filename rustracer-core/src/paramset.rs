@@ -9,12 +9,11 @@ use log::{error, warn};
 
 use crate::api::{ParamListEntry, ParamType};
 use crate::cie::CIE_LAMBDA;
-use crate::fileutil::resolve_filename;
+use crate::fileutil::Dependencies;
 use crate::floatfile::read_float_file;
 use crate::spectrum::{blackbody_normalized, Spectrum};
-use crate::texture::ConstantTexture;
-use crate::texture::Texture;
-use crate::{Normal3f, Point2f, Point3f, Vector3f};
+use crate::texture::{ConstantTexture, TextureRef};
+use crate::{Normal3f, Point2f, Point3f, Vector2f, Vector3f};
 
 macro_rules! find_one(
     ($x:ident, $y:ident, $t:ty) => (
@@ -52,13 +51,24 @@ pub struct ParamSet {
     strings: Vec<ParamSetItem<String>>,
     spectra: Vec<ParamSetItem<Spectrum>>,
     point2fs: Vec<ParamSetItem<Point2f>>,
+    vector2fs: Vec<ParamSetItem<Vector2f>>,
     point3fs: Vec<ParamSetItem<Point3f>>,
     vector3fs: Vec<ParamSetItem<Vector3f>>,
     normal3fs: Vec<ParamSetItem<Normal3f>>,
     textures: Vec<ParamSetItem<String>>,
+    /// Where the files named by the parameters are recorded when they're resolved
+    dependencies: Dependencies,
 }
 
 impl ParamSet {
+    /// An empty parameter set recording the files its parameters name in `dependencies`.
+    pub fn with_dependencies(dependencies: Dependencies) -> ParamSet {
+        ParamSet {
+            dependencies,
+            ..ParamSet::default()
+        }
+    }
+
     pub fn init(&mut self, entries: Vec<ParamListEntry>) {
         for entry in entries {
             match entry.param_type {
@@ -86,6 +96,16 @@ impl ParamSet {
                 ParamType::String => {
                     self.add_string(entry.param_name.clone(), entry.values.as_str_array())
                 }
+                ParamType::Xyz => {
+                    let spectra = entry
+                        .values
+                        .as_num_array()
+                        .chunks(3)
+                        .filter(|s| s.len() == 3)
+                        .map(|s| Spectrum::from_xyz(&[s[0], s[1], s[2]]))
+                        .collect();
+                    self.add_rgb_spectrum(entry.param_name.clone(), spectra);
+                }
                 ParamType::Rgb => {
                     let spectra = entry
                         .values
@@ -106,6 +126,16 @@ impl ParamSet {
                         .collect();
                     self.add_point2f(entry.param_name.clone(), points);
                 }
+                ParamType::Vector2 => {
+                    let vectors = entry
+                        .values
+                        .as_num_array()
+                        .chunks(2)
+                        .filter(|s| s.len() == 2)
+                        .map(|s| Vector2f::new(s[0], s[1]))
+                        .collect();
+                    self.add_vector2f(entry.param_name.clone(), vectors);
+                }
                 ParamType::Point3 => {
                     let points = entry
                         .values
@@ -150,14 +180,6 @@ impl ParamSet {
                         &entry.values.as_num_array(),
                     );
                 }
-                ParamType::Vector2 => error!(
-                    "Parameter type {:?} is not implemented yet!",
-                    entry.param_type
-                ),
-                ParamType::Xyz => error!(
-                    "Parameter type {:?} is not implemented yet!",
-                    entry.param_type
-                ),
             }
         }
     }
@@ -167,7 +189,7 @@ impl ParamSet {
         if filename.is_empty() {
             d
         } else {
-            resolve_filename(&filename)
+            self.dependencies.resolve(&filename)
         }
     }
 
@@ -219,6 +241,14 @@ impl ParamSet {
         });
     }
 
+    fn add_vector2f(&mut self, name: String, values: Vec<Vector2f>) {
+        self.vector2fs.push(ParamSetItem {
+            name,
+            values,
+            looked_up: Cell::new(false),
+        });
+    }
+
     fn add_point3f(&mut self, name: String, values: Vec<Point3f>) {
         self.point3fs.push(ParamSetItem {
             name,
@@ -254,7 +284,7 @@ impl ParamSet {
     fn add_sampled_spectrum_files(&mut self, name: String, values: Vec<String>) {
         let mut s = Vec::with_capacity(values.len());
         for filename in values {
-            let filename = resolve_filename(&filename);
+            let filename = self.dependencies.resolve(&filename);
             match read_float_file(&filename) {
                 Err(_) => {
                     warn!(
@@ -313,6 +343,7 @@ impl ParamSet {
     find!(find_string, strings, String);
     find!(find_spectrum, spectra, Spectrum);
     find!(find_point2f, point2fs, Point2f);
+    find!(find_vector2f, vector2fs, Vector2f);
     find!(find_point3f, point3fs, Point3f);
     find!(find_vector3f, vector3fs, Vector3f);
     find!(find_normal3f, normal3fs, Normal3f);
@@ -322,6 +353,7 @@ impl ParamSet {
     find_one!(find_one_string, strings, String);
     find_one!(find_one_spectrum, spectra, Spectrum);
     find_one!(find_one_point2f, point2fs, Point2f);
+    find_one!(find_one_vector2f, vector2fs, Vector2f);
     find_one!(find_one_point3f, point3fs, Point3f);
     find_one!(find_one_vector3f, vector3fs, Vector3f);
     find_one!(find_one_normal3f, normal3fs, Normal3f);
@@ -349,16 +381,16 @@ impl<T: Debug> Default for ParamSetItem<T> {
 pub struct TextureParams<'a> {
     geom_params: &'a ParamSet,
     material_params: &'a ParamSet,
-    float_textures: &'a HashMap<String, Arc<dyn Texture<f32>>>,
-    spectrum_textures: &'a HashMap<String, Arc<dyn Texture<Spectrum>>>,
+    float_textures: &'a HashMap<String, TextureRef<f32>>,
+    spectrum_textures: &'a HashMap<String, TextureRef<Spectrum>>,
 }
 
 impl<'a> TextureParams<'a> {
     pub fn new(
         gp: &'a ParamSet,
         mp: &'a ParamSet,
-        ft: &'a HashMap<String, Arc<dyn Texture<f32>>>,
-        st: &'a HashMap<String, Arc<dyn Texture<Spectrum>>>,
+        ft: &'a HashMap<String, TextureRef<f32>>,
+        st: &'a HashMap<String, TextureRef<Spectrum>>,
     ) -> TextureParams<'a> {
         TextureParams {
             geom_params: gp,
@@ -393,6 +425,16 @@ impl<'a> TextureParams<'a> {
         self.geom_params.find_one_float(n, d)
     }
 
+    pub fn find_point2f(&self, n: &str, d: Point2f) -> Point2f {
+        let d = self.material_params.find_one_point2f(n, d);
+        self.geom_params.find_one_point2f(n, d)
+    }
+
+    pub fn find_vector2f(&self, n: &str, d: Vector2f) -> Vector2f {
+        let d = self.material_params.find_one_vector2f(n, d);
+        self.geom_params.find_one_vector2f(n, d)
+    }
+
     pub fn find_vector3f(&self, n: &str, d: Vector3f) -> Vector3f {
         let d = self.material_params.find_one_vector3f(n, d);
         self.geom_params.find_one_vector3f(n, d)
@@ -403,7 +445,7 @@ impl<'a> TextureParams<'a> {
         self.geom_params.find_one_spectrum(n, d)
     }
 
-    pub fn get_spectrum_texture(&self, n: &str, default: &Spectrum) -> Arc<dyn Texture<Spectrum>> {
+    pub fn get_spectrum_texture(&self, n: &str, default: &Spectrum) -> TextureRef<Spectrum> {
         let mut name = self.geom_params.find_texture(n, "".to_owned());
         if name.is_empty() {
             name = self.material_params.find_texture(n, "".to_owned());
@@ -424,7 +466,7 @@ impl<'a> TextureParams<'a> {
         Arc::new(ConstantTexture::new(val))
     }
 
-    pub fn get_float_texture(&self, n: &str, default: f32) -> Arc<dyn Texture<f32>> {
+    pub fn get_float_texture(&self, n: &str, default: f32) -> TextureRef<f32> {
         let mut name = self.geom_params.find_texture(n, "".to_owned());
         if name.is_empty() {
             name = self.material_params.find_texture(n, "".to_owned());
@@ -442,7 +484,34 @@ impl<'a> TextureParams<'a> {
         Arc::new(ConstantTexture::new(val))
     }
 
-    pub fn get_float_texture_or_none(&self, n: &str) -> Option<Arc<dyn Texture<f32>>> {
+    /// The spectrum texture of parameter `n`, only if it is given as a texture.
+    pub fn get_spectrum_texture_or_none(&self, n: &str) -> Option<TextureRef<Spectrum>> {
+        let mut name = self.geom_params.find_texture(n, "".to_owned());
+        if name.is_empty() {
+            name = self.material_params.find_texture(n, "".to_owned());
+        }
+        if name.is_empty() {
+            return None;
+        }
+        let tex = self.spectrum_textures.get(&name).map(Arc::clone);
+        if tex.is_none() {
+            error!(
+                "Couldn't find spectrum texture {} for parameter {}",
+                name, n
+            );
+        }
+        tex
+    }
+
+    /// Value of the float parameter `n`, if it is given as a number rather than a texture.
+    pub fn find_constant_float(&self, n: &str) -> Option<f32> {
+        self.geom_params
+            .find_float(n)
+            .or_else(|| self.material_params.find_float(n))
+            .map(|val| val[0])
+    }
+
+    pub fn get_float_texture_or_none(&self, n: &str) -> Option<TextureRef<f32>> {
         let mut name = self.geom_params.find_texture(n, "".to_owned());
         if name.is_empty() {
             name = self.material_params.find_texture(n, "".to_owned());
@@ -460,7 +529,7 @@ impl<'a> TextureParams<'a> {
             .find_float(n)
             .or_else(|| self.material_params.find_float(n))
             .map(|val| {
-                let tex: Arc<dyn Texture<f32>> = Arc::new(ConstantTexture::new(val[0]));
+                let tex: TextureRef<f32> = Arc::new(ConstantTexture::new(val[0]));
                 tex
             })
     }
@@ -0,0 +1,414 @@
+//! Binary cache of the tokens of scene files.
+//!
+//! Lexing gigantic generated scene files takes a good part of the time it takes to load them.
+//! When caching is enabled (see `PbrtOptions::cache_scene`), the tokens of each scene file are
+//! written next to it in `<file>.rtcache`, and read back on subsequent runs as long as the hash
+//! of the scene file is the one recorded in the cache. Only the lexing is skipped: the tokens
+//! are still parsed into directives, and the scene built from them, on every run.
+//!
+//! Like `tokenize_file()`, the tokens are streamed: the cache is written as the tokens of the
+//! scene file are consumed by the parser, and read back one token at a time. A cache is checked
+//! in full before its tokens are returned though, so that a corrupt one is ignored rather than
+//! failing the parse halfway.
+//!
+//! The format is a header (magic bytes, format version and hash of the source) followed by the
+//! tokens, each one a tag byte and its value: the line number for directives, a little-endian
+//! `f32` for numbers and a length-prefixed UTF-8 string for strings. An end tag closes the list.
+
+use std::ffi::OsString;
+use std::fs::{self, File};
+use std::io::{BufRead, BufReader, BufWriter, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+
+use anyhow::{bail, Context, Result};
+use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
+use log::{info, warn};
+
+use super::lexer::{Token, TokenSource, TokenStream};
+use crate::fileutil::Dependencies;
+
+const MAGIC: &[u8; 4] = b"RTSC";
+const VERSION: u32 = 2;
+/// Size of the header: magic bytes, version and hash.
+const HEADER_LEN: u64 = 16;
+const TAG_END: u8 = 0xfd;
+const TAG_STR: u8 = 0xfe;
+const TAG_NUMBER: u8 = 0xff;
+
+/// The tokens without a value, in the order of their tags.
+const KEYWORDS: &[Token] = &[
+    Token::ACCELERATOR,
+    Token::ACTIVETRANSFORM,
+    Token::ALL,
+    Token::AREALIGHTSOURCE,
+    Token::ATTRIBUTEBEGIN,
+    Token::ATTRIBUTEEND,
+    Token::CAMERA,
+    Token::CONCATTRANSFORM,
+    Token::COORDINATESYSTEM,
+    Token::COORDSYSTRANSFORM,
+    Token::ENDTIME,
+    Token::FILM,
+    Token::IDENTITY,
+    Token::INCLUDE,
+    Token::LIGHTSOURCE,
+    Token::LOOKAT,
+    Token::MAKENAMEDMEDIUM,
+    Token::MAKENAMEDMATERIAL,
+    Token::MATERIAL,
+    Token::MEDIUMINTERFACE,
+    Token::NAMEDMATERIAL,
+    Token::OBJECTBEGIN,
+    Token::OBJECTEND,
+    Token::OBJECTINSTANCE,
+    Token::PIXELFILTER,
+    Token::REVERSEORIENTATION,
+    Token::ROTATE,
+    Token::SAMPLER,
+    Token::SCALE,
+    Token::SHAPE,
+    Token::STARTTIME,
+    Token::INTEGRATOR,
+    Token::TEXTURE,
+    Token::TRANSFORMBEGIN,
+    Token::TRANSFORMEND,
+    Token::TRANSFORMTIMES,
+    Token::TRANSFORM,
+    Token::TRANSLATE,
+    Token::WORLDBEGIN,
+    Token::WORLDEND,
+    Token::LBRACK,
+    Token::RBRACK,
+    Token::COMMENT,
+];
+
+/// Tokens of a scene file, read from its cache if it's up to date, or lexed from the file (and
+/// written to a new cache) otherwise.
+pub struct CachedTokens {
+    source: Source,
+    line_number: usize,
+}
+
+enum Source {
+    Cache(BufReader<File>),
+    Lexer {
+        stream: TokenStream<BufReader<File>>,
+        cache: Option<CacheWriter>,
+    },
+}
+
+impl Iterator for CachedTokens {
+    type Item = Result<Token>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        match &mut self.source {
+            Source::Cache(r) => read_token(r, &mut self.line_number)
+                .context("Invalid scene cache")
+                .transpose(),
+            Source::Lexer { stream, cache } => {
+                let token = stream.next();
+                self.line_number = stream.line_number();
+                match &token {
+                    Some(Ok(t)) => {
+                        if let Some(Err(e)) = cache.as_mut().map(|w| w.write(t, self.line_number)) {
+                            warn!("Failed to write scene cache: {:#}", e);
+                            *cache = None;
+                        }
+                    }
+                    // The cache is only kept if the whole file was lexed
+                    Some(Err(_)) => *cache = None,
+                    None => {
+                        if let Some(Err(e)) = cache.take().map(CacheWriter::finish) {
+                            warn!("Failed to write scene cache: {:#}", e);
+                        }
+                    }
+                }
+                token
+            }
+        }
+    }
+}
+
+impl TokenSource for CachedTokens {
+    fn line_number(&self) -> usize {
+        self.line_number
+    }
+}
+
+/// Return the tokens of the given scene file, from its cache if it's up to date. Otherwise the
+/// file is lexed and the cache (re)written as the tokens are consumed. The scene file is recorded
+/// in `dependencies`.
+pub fn tokenize_file_cached<P: AsRef<Path>>(
+    filename: P,
+    dependencies: &Dependencies,
+) -> Result<CachedTokens> {
+    let resolved_filename = dependencies.resolve(filename.as_ref().to_str().unwrap());
+    let file = File::open(&resolved_filename).context("Failed to open scene file")?;
+    let hash = hash(BufReader::new(file))?;
+    let cache = cache_filename(&resolved_filename);
+
+    let source = match read_cache(&cache, hash) {
+        Some(reader) => {
+            info!("Reading the tokens of {} from its cache", resolved_filename);
+            Source::Cache(reader)
+        }
+        None => {
+            let file = File::open(&resolved_filename).context("Failed to open scene file")?;
+            let writer = CacheWriter::create(&cache, hash)
+                .map_err(|e| warn!("Failed to write scene cache {}: {:#}", cache.display(), e))
+                .ok();
+            Source::Lexer {
+                stream: TokenStream::new(BufReader::new(file)),
+                cache: writer,
+            }
+        }
+    };
+
+    Ok(CachedTokens {
+        source,
+        line_number: 0,
+    })
+}
+
+/// Path of the cache of the given scene file.
+pub fn cache_filename<P: AsRef<Path>>(filename: P) -> PathBuf {
+    let mut cache = filename.as_ref().as_os_str().to_owned();
+    cache.push(".rtcache");
+    PathBuf::from(cache)
+}
+
+/// 64-bit FNV-1a hash of the source of a scene file. Unlike `DefaultHasher`, it is stable across
+/// versions of the standard library.
+fn hash<R: BufRead>(mut r: R) -> Result<u64> {
+    let mut h = 0xcbf2_9ce4_8422_2325;
+    loop {
+        let buf = r.fill_buf()?;
+        if buf.is_empty() {
+            return Ok(h);
+        }
+        h = buf
+            .iter()
+            .fold(h, |h, &b| (h ^ u64::from(b)).wrapping_mul(0x0100_0000_01b3));
+        let len = buf.len();
+        r.consume(len);
+    }
+}
+
+/// Open the given cache, if it exists, is valid and was created from a source with the given
+/// hash. The returned reader is positioned on the first token.
+fn read_cache(path: &Path, hash: u64) -> Option<BufReader<File>> {
+    let file = File::open(path).ok()?;
+    let mut r = BufReader::new(file);
+    let check = |r: &mut BufReader<File>| -> Result<bool> {
+        if !read_header(r, hash)? {
+            return Ok(false);
+        }
+        check_tokens(r)?;
+        r.seek(SeekFrom::Start(HEADER_LEN))?;
+        Ok(true)
+    };
+    match check(&mut r) {
+        Ok(true) => Some(r),
+        Ok(false) => None,
+        Err(e) => {
+            warn!("Ignoring invalid scene cache {}: {:#}", path.display(), e);
+            None
+        }
+    }
+}
+
+/// Read the header written by `write_header()`. Returns false if the tokens were created from a
+/// different source or by a different version of the format.
+fn read_header<R: Read>(r: &mut R, hash: u64) -> Result<bool> {
+    let mut magic = [0u8; 4];
+    r.read_exact(&mut magic)?;
+    if &magic != MAGIC {
+        bail!("Invalid signature {:?}", magic);
+    }
+    Ok(r.read_u32::<LittleEndian>()? == VERSION && r.read_u64::<LittleEndian>()? == hash)
+}
+
+fn write_header<W: Write>(w: &mut W, hash: u64) -> Result<()> {
+    w.write_all(MAGIC)?;
+    w.write_u32::<LittleEndian>(VERSION)?;
+    w.write_u64::<LittleEndian>(hash)?;
+    Ok(())
+}
+
+/// Check that the rest of the input is a well-formed list of tokens.
+fn check_tokens<R: Read>(r: &mut R) -> Result<()> {
+    let mut line_number = 0;
+    while read_token(r, &mut line_number)?.is_some() {}
+    if r.read(&mut [0u8])? != 0 {
+        bail!("Unexpected data after the tokens");
+    }
+    Ok(())
+}
+
+/// Read a token written by `write_token()`, updating the line number if it's a directive.
+/// Returns `None` at the end of the tokens.
+fn read_token<R: Read>(r: &mut R, line_number: &mut usize) -> Result<Option<Token>> {
+    let token = match r.read_u8()? {
+        TAG_END => return Ok(None),
+        TAG_STR => {
+            // Don't trust the length to allocate the string: a corrupt one could be huge
+            let len = r.read_u32::<LittleEndian>()?;
+            let mut s = Vec::new();
+            r.by_ref().take(u64::from(len)).read_to_end(&mut s)?;
+            if s.len() != len as usize {
+                bail!("Truncated string");
+            }
+            Token::STR(String::from_utf8(s)?)
+        }
+        TAG_NUMBER => Token::NUMBER(r.read_f32::<LittleEndian>()?),
+        tag => match KEYWORDS.get(usize::from(tag)) {
+            Some(token) => token.clone(),
+            None => bail!("Invalid token tag {}", tag),
+        },
+    };
+    if token.is_directive() {
+        *line_number = r.read_u32::<LittleEndian>()? as usize;
+    }
+    Ok(Some(token))
+}
+
+fn write_token<W: Write>(w: &mut W, token: &Token, line_number: usize) -> Result<()> {
+    match token {
+        Token::STR(s) => {
+            w.write_u8(TAG_STR)?;
+            w.write_u32::<LittleEndian>(s.len() as u32)?;
+            w.write_all(s.as_bytes())?;
+        }
+        Token::NUMBER(n) => {
+            w.write_u8(TAG_NUMBER)?;
+            w.write_f32::<LittleEndian>(*n)?;
+        }
+        _ => {
+            let tag = KEYWORDS.iter().position(|k| k == token).unwrap();
+            w.write_u8(tag as u8)?;
+        }
+    }
+    if token.is_directive() {
+        w.write_u32::<LittleEndian>(line_number as u32)?;
+    }
+    Ok(())
+}
+
+/// Cache being written as the tokens of a scene file are lexed. The tokens go to a temporary
+/// file, which only replaces the cache once all of them have been written.
+struct CacheWriter {
+    w: BufWriter<File>,
+    path: PathBuf,
+    tmp_path: PathBuf,
+}
+
+impl CacheWriter {
+    fn create(path: &Path, hash: u64) -> Result<CacheWriter> {
+        let mut tmp_path = OsString::from(path.as_os_str());
+        tmp_path.push(".tmp");
+        let tmp_path = PathBuf::from(tmp_path);
+        let mut w = BufWriter::new(File::create(&tmp_path)?);
+        write_header(&mut w, hash)?;
+        Ok(CacheWriter {
+            w,
+            path: path.to_owned(),
+            tmp_path,
+        })
+    }
+
+    fn write(&mut self, token: &Token, line_number: usize) -> Result<()> {
+        write_token(&mut self.w, token, line_number)
+    }
+
+    fn finish(mut self) -> Result<()> {
+        self.w.write_u8(TAG_END)?;
+        self.w.flush()?;
+        fs::rename(&self.tmp_path, &self.path)?;
+        info!("Wrote scene cache {}", self.path.display());
+        Ok(())
+    }
+}
+
+impl Drop for CacheWriter {
+    fn drop(&mut self) {
+        // Nothing to remove if the cache was completed
+        let _ = fs::remove_file(&self.tmp_path);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tokens_of(scene: &Path) -> Vec<Token> {
+        tokenize_file_cached(scene, &Dependencies::default())
+            .unwrap()
+            .collect::<Result<_>>()
+            .unwrap()
+    }
+
+    #[test]
+    fn test_scene_cache() {
+        let dir = std::env::temp_dir().join("rustracer_scene_cache");
+        fs::create_dir_all(&dir).unwrap();
+        let scene = dir.join("scene.pbrt");
+        let cache = cache_filename(&scene);
+        let _ = fs::remove_file(&cache);
+        let source = "Film \"image\"\n  \"string filename\" \"out.exr\"\n\nShape \"sphere\" \"float radius\" [ -1.5 ]\n";
+        fs::write(&scene, source).unwrap();
+
+        // The cache is only written once all the tokens have been consumed
+        let mut tokens = tokenize_file_cached(&scene, &Dependencies::default()).unwrap();
+        let first = tokens.next().unwrap().unwrap();
+        assert!(!cache.exists());
+        let lexed: Vec<_> = std::iter::once(Ok(first))
+            .chain(tokens)
+            .collect::<Result<_>>()
+            .unwrap();
+        assert!(cache.exists());
+        assert_eq!(lexed[lexed.len() - 2], Token::NUMBER(-1.5));
+
+        // The cache holds the same tokens, and the lines of the directives
+        assert!(read_cache(&cache, hash(source.as_bytes()).unwrap()).is_some());
+        let mut cached = tokenize_file_cached(&scene, &Dependencies::default()).unwrap();
+        assert!(matches!(cached.source, Source::Cache(_)));
+        assert_eq!(cached.next().unwrap().unwrap(), Token::FILM);
+        assert_eq!(cached.line_number(), 1);
+        assert_eq!(cached.nth(3).unwrap().unwrap(), Token::SHAPE);
+        assert_eq!(cached.line_number(), 4);
+        let rest: Vec<_> = cached.collect::<Result<_>>().unwrap();
+        assert_eq!(rest, lexed[5..]);
+
+        // Changing the source invalidates the cache
+        fs::write(&scene, "WorldBegin\n").unwrap();
+        let new_hash = hash(&b"WorldBegin\n"[..]).unwrap();
+        assert!(read_cache(&cache, new_hash).is_none());
+        assert_eq!(tokens_of(&scene), vec![Token::WORLDBEGIN]);
+        assert!(read_cache(&cache, new_hash).is_some());
+    }
+
+    #[test]
+    fn test_corrupt_scene_cache_is_ignored() {
+        let dir = std::env::temp_dir().join("rustracer_corrupt_scene_cache");
+        fs::create_dir_all(&dir).unwrap();
+        let scene = dir.join("scene.pbrt");
+        let cache = cache_filename(&scene);
+        let source = "Shape \"sphere\"\n";
+        fs::write(&scene, source).unwrap();
+        let hash = hash(source.as_bytes()).unwrap();
+
+        // A string claiming to be 4GB long, and the token after the end is missing
+        let mut corrupt = Vec::new();
+        write_header(&mut corrupt, hash).unwrap();
+        write_token(&mut corrupt, &Token::SHAPE, 1).unwrap();
+        corrupt.extend_from_slice(&[TAG_STR, 0xff, 0xff, 0xff, 0xff, b's']);
+        fs::write(&cache, &corrupt).unwrap();
+        assert!(read_cache(&cache, hash).is_none());
+        assert_eq!(
+            tokens_of(&scene),
+            vec![Token::SHAPE, Token::STR("sphere".to_owned())]
+        );
+        // The cache was replaced by a valid one
+        assert!(read_cache(&cache, hash).is_some());
+    }
+}
@@ -1,20 +1,22 @@
 use std::{
     fmt,
+    io::BufRead,
     iter::Enumerate,
     ops::{Index, Range, RangeFrom},
     slice::Iter,
 };
 
+use anyhow::{bail, Result};
 use nom::{
     branch::alt,
     bytes::complete::tag,
     character::complete::{
         alphanumeric1, char, line_ending, multispace0, none_of, not_line_ending,
     },
-    combinator::{all_consuming, map, map_res, value},
-    multi::{many0, many1},
+    combinator::{map, map_res, value},
+    multi::many0,
     number::complete::float,
-    sequence::{delimited, preceded, terminated},
+    sequence::{delimited, preceded},
     IResult, InputIter, InputLength, InputTake, Needed, Slice,
 };
 
@@ -84,6 +86,21 @@ impl Token {
             None
         }
     }
+
+    /// Whether this token starts a new directive, as opposed to being one of its arguments.
+    pub fn is_directive(&self) -> bool {
+        !matches!(
+            self,
+            Token::STR(_)
+                | Token::NUMBER(_)
+                | Token::LBRACK
+                | Token::RBRACK
+                | Token::COMMENT
+                | Token::ALL
+                | Token::STARTTIME
+                | Token::ENDTIME
+        )
+    }
 }
 
 impl fmt::Display for Token {
@@ -182,16 +199,94 @@ impl<'a> Slice<RangeFrom<usize>> for Tokens<'a> {
     }
 }
 
-pub fn tokenize(input: &str) -> IResult<&str, Vec<Token>> {
-    all_consuming(terminated(
-        many1(alt((
-            preceded(multispace0, keyword),
-            preceded(multispace0, float_parser),
-            preceded(multispace0, string_parser),
-            preceded(multispace0, comment_parser),
-        ))),
-        multispace0,
-    ))(input)
+/// A source of tokens that knows which line of the scene description each one was read from.
+pub trait TokenSource: Iterator<Item = Result<Token>> {
+    /// Line of the input the last token was read from (starting at 1).
+    fn line_number(&self) -> usize;
+}
+
+/// Iterator over the tokens of a scene description, which are read lazily from a `BufRead`.
+///
+/// The input is consumed one line at a time (or a few lines, for strings spanning several of
+/// them), so memory usage doesn't depend on the size of the file. Comments are skipped.
+pub struct TokenStream<R> {
+    reader: R,
+    line: String,
+    pos: usize,
+    line_number: usize,
+}
+
+impl<R: BufRead> TokenStream<R> {
+    pub fn new(reader: R) -> Self {
+        Self {
+            reader,
+            line: String::new(),
+            pos: 0,
+            line_number: 0,
+        }
+    }
+
+    /// Append the next line of the input to the current one. Returns false at the end of the
+    /// input.
+    fn read_line(&mut self) -> Result<bool> {
+        let read = self.reader.read_line(&mut self.line)?;
+        if read > 0 {
+            self.line_number += 1;
+        }
+        Ok(read > 0)
+    }
+
+    fn next_token(&mut self) -> Result<Option<Token>> {
+        loop {
+            self.pos = self.line.len() - self.line[self.pos..].trim_start().len();
+            if self.pos == self.line.len() {
+                self.line.clear();
+                self.pos = 0;
+                if !self.read_line()? {
+                    return Ok(None);
+                }
+                continue;
+            }
+
+            let rest = &self.line[self.pos..];
+            let res = alt((keyword, float_parser, string_parser, comment_parser))(rest)
+                .map(|(remaining, token)| (remaining.len(), token));
+            match res {
+                Ok((remaining, token)) => {
+                    self.pos = self.line.len() - remaining;
+                    if token != Token::COMMENT {
+                        return Ok(Some(token));
+                    }
+                }
+                // A comment on the last line of the input
+                Err(_) if rest.starts_with('#') => self.pos = self.line.len(),
+                // A string spanning several lines
+                Err(_) if rest.starts_with('"') => {
+                    if !self.read_line()? {
+                        bail!("Unterminated string on line {}", self.line_number);
+                    }
+                }
+                Err(_) => {
+                    let bad = rest.split_whitespace().next().unwrap_or_default();
+                    bail!("Invalid token \"{}\" on line {}", bad, self.line_number);
+                }
+            }
+        }
+    }
+}
+
+impl<R: BufRead> Iterator for TokenStream<R> {
+    type Item = Result<Token>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.next_token().transpose()
+    }
+}
+
+impl<R: BufRead> TokenSource for TokenStream<R> {
+    fn line_number(&self) -> usize {
+        self.line_number
+    }
 }
 
 pub fn keyword(input: &str) -> IResult<&str, Token> {
@@ -302,8 +397,41 @@ WorldBegin
   AttributeEnd
 WorldEnd
         "##;
-        let (rest, _tokens) = tokenize(scene).unwrap();
-        assert_eq!(rest, "");
+        let tokens = TokenStream::new(scene.as_bytes())
+            .collect::<Result<Vec<_>>>()
+            .unwrap();
+        assert!(!tokens.contains(&Token::COMMENT));
+        assert_eq!(tokens.iter().filter(|t| **t == Token::SHAPE).count(), 3);
+        assert_eq!(tokens[0], Token::LOOKAT);
+        assert_eq!(tokens[tokens.len() - 1], Token::WORLDEND);
+    }
+
+    #[test]
+    fn test_token_stream() {
+        let scene = "Shape \"sphere\" # trailing comment\n\"string name\" \"split\nstring\"\n[ -1.5 ]\n# no newline";
+        let mut tokens = TokenStream::new(scene.as_bytes());
+        assert_eq!(tokens.next().unwrap().unwrap(), Token::SHAPE);
+        assert_eq!(tokens.line_number(), 1);
+        assert_eq!(
+            tokens.next().unwrap().unwrap(),
+            Token::STR("sphere".to_owned())
+        );
+        assert_eq!(
+            tokens.next().unwrap().unwrap(),
+            Token::STR("string name".to_owned())
+        );
+        assert_eq!(
+            tokens.next().unwrap().unwrap(),
+            Token::STR("split\nstring".to_owned())
+        );
+        assert_eq!(tokens.line_number(), 3);
+        assert_eq!(tokens.next().unwrap().unwrap(), Token::LBRACK);
+        assert_eq!(tokens.next().unwrap().unwrap(), Token::NUMBER(-1.5));
+        assert_eq!(tokens.next().unwrap().unwrap(), Token::RBRACK);
+        assert!(tokens.next().is_none());
+
+        let mut tokens = TokenStream::new("Shape \"sphere\" @".as_bytes());
+        assert!(tokens.nth(2).unwrap().is_err());
     }
 
     #[test]
@@ -1,46 +1,87 @@
+mod cache;
 mod lexer;
 mod parser;
 
 use std::fs::File;
-use std::io::prelude::*;
+use std::io::BufReader;
 use std::path::Path;
 
 use anyhow::*;
 
 use crate::api::{Api, RealApi};
-use crate::fileutil;
-use crate::pbrt::lexer::Tokens;
+use crate::fileutil::{self, Dependencies};
+use crate::pbrt::lexer::{TokenSource, TokenStream};
+use crate::pbrt::parser::ParseContext;
+use crate::renderer::RenderContext;
 use crate::PbrtOptions;
 
-pub fn parse_scene<P: AsRef<Path>>(opts: PbrtOptions, filename: P) -> Result<()> {
+/// Parse and render the given scene file.
+///
+/// If `opts.interactive` is set, the context of the last rendered scene is returned so that it
+/// can be rendered again (see `RenderContext::rerender_from()`).
+pub fn parse_scene<P: AsRef<Path>>(
+    opts: PbrtOptions,
+    filename: P,
+) -> Result<Option<RenderContext>> {
+    parse_scene_recording(opts, filename, &Dependencies::default())
+}
+
+/// Same as `parse_scene()`, but the files the scene depends on (the scene file itself, included
+/// files, meshes, textures, etc.) are recorded in `dependencies` as they're read, including when
+/// the scene fails to load.
+pub fn parse_scene_recording<P: AsRef<Path>>(
+    opts: PbrtOptions,
+    filename: P,
+    dependencies: &Dependencies,
+) -> Result<Option<RenderContext>> {
     let filename = filename.as_ref();
-    let tokens = tokenize_file(filename)?;
-    fileutil::set_search_directory(fileutil::directory_containing(filename));
+    let context = ParseContext {
+        use_cache: opts.cache_scene,
+        dependencies: dependencies.clone(),
+    };
+    if opts.cache_scene {
+        let tokens = cache::tokenize_file_cached(filename, dependencies)?;
+        fileutil::set_search_directory(fileutil::directory_containing(filename));
+        parse_tokens(opts, tokens, &context)
+    } else {
+        let tokens = tokenize_file(filename, dependencies)?;
+        fileutil::set_search_directory(fileutil::directory_containing(filename));
+        parse_tokens(opts, tokens, &context)
+    }
+}
+
+/// Same as `parse_scene()`, but the scene description is given as a string. Relative file names
+/// in the scene are resolved from the current directory.
+pub fn parse_scene_string(opts: PbrtOptions, scene: &str) -> Result<Option<RenderContext>> {
+    let context = ParseContext {
+        use_cache: opts.cache_scene,
+        dependencies: Dependencies::default(),
+    };
+    parse_tokens(opts, TokenStream::new(scene.as_bytes()), &context)
+}
+
+fn parse_tokens<S: TokenSource>(
+    opts: PbrtOptions,
+    tokens: S,
+    context: &ParseContext,
+) -> Result<Option<RenderContext>> {
     let api = RealApi::with_options(opts);
     api.init()?;
-    parser::parse(Tokens::new(&tokens[..]), &api)
-        .map_err(|e| format_err!("Failed to parse scene file: {:?}", e))?;
+    parser::parse_stream(tokens, &api, context)?;
 
-    Ok(())
+    Ok(api.take_render_context())
 }
 
-pub fn tokenize_file<P: AsRef<Path>>(filename: P) -> Result<Vec<lexer::Token>> {
-    let resolved_filename = fileutil::resolve_filename(filename.as_ref().to_str().unwrap());
-    let mut file = File::open(&resolved_filename).context("Failed to open scene file")?;
-    let mut file_content = String::new();
-    file.read_to_string(&mut file_content)
-        .context("Failed to read content of scene file")?;
-
-    // TODO handle errors
-    let (_rest, tokens) = lexer::tokenize(&file_content[..])
-        .map_err(|e| format_err!("Failed to tokenize scene file: {:?}", e))?;
-    // strip comments
-    let filtered_tokens = tokens
-        .into_iter()
-        .filter(|x| *x != lexer::Token::COMMENT)
-        .collect::<Vec<_>>();
-
-    Ok(filtered_tokens)
+/// Open the given file and return a stream of the tokens it contains, which are only read as
+/// they're consumed. The file is recorded in `dependencies`.
+pub fn tokenize_file<P: AsRef<Path>>(
+    filename: P,
+    dependencies: &Dependencies,
+) -> Result<TokenStream<BufReader<File>>> {
+    let resolved_filename = dependencies.resolve(filename.as_ref().to_str().unwrap());
+    let file = File::open(&resolved_filename).context("Failed to open scene file")?;
+
+    Ok(TokenStream::new(BufReader::new(file)))
 }
 
 #[ignore]
@@ -82,5 +123,5 @@ WorldBegin
 WorldEnd
         "##;
 
-    parse_scene(PbrtOptions::default(), scene).unwrap();
+    parse_scene_string(PbrtOptions::default(), scene).unwrap();
 }
@@ -1,6 +1,7 @@
+use std::cell::RefCell;
 use std::ops::RangeFrom;
 
-use anyhow::format_err;
+use anyhow::{bail, format_err, Result};
 use log::info;
 use nom::{
     branch::alt,
@@ -13,31 +14,106 @@ use nom::{
     Finish, IResult,
 };
 
-use super::lexer::{Token, Tokens};
+use super::lexer::{Token, TokenSource, Tokens};
 use crate::api::{Api, Array, ParamListEntry, ParamType};
+use crate::cancel;
+use crate::fileutil::Dependencies;
 use crate::paramset::ParamSet;
 
-pub fn parse<'input, 'api, A: Api>(
+/// State shared by the files of a scene while it is parsed.
+pub struct ParseContext {
+    /// Whether to read the tokens of included files from their cache (see the `cache` module)
+    pub use_cache: bool,
+    /// Where the files the scene depends on are recorded, by the parser for included files and
+    /// by the parameter sets it creates for the files they name
+    pub dependencies: Dependencies,
+}
+
+/// Parse the scene description in `tokens` and feed it to `api`.
+///
+/// The tokens are consumed one directive at a time (i.e. a keyword and the arguments that follow
+/// it), so only the current directive is ever held in memory, and included files are parsed as
+/// soon as they're encountered.
+pub fn parse_stream<S: TokenSource, A: Api>(
+    mut tokens: S,
+    api: &A,
+    context: &ParseContext,
+) -> Result<()> {
+    let mut directive = Vec::new();
+    let mut line_number = 0;
+    loop {
+        let next = tokens.next().transpose()?;
+        let at_boundary = next.as_ref().is_none_or(Token::is_directive);
+        if at_boundary && !directive.is_empty() {
+            let api_error = RefCell::new(None);
+            parse(Tokens::new(&directive), api, context, &api_error).map_err(
+                |e| match api_error.take() {
+                    Some(api_error) => api_error.context(format!(
+                        "Failed to process {} directive on line {}",
+                        directive[0], line_number
+                    )),
+                    None => format_err!(
+                        "Failed to parse {} directive on line {}: {:?}",
+                        directive[0],
+                        line_number,
+                        e
+                    ),
+                },
+            )?;
+            directive.clear();
+            // e.g. while building the BVH of a big mesh
+            if cancel::interrupted() {
+                bail!("Interrupted on line {}", line_number);
+            }
+        }
+        match next {
+            Some(token) => {
+                if directive.is_empty() {
+                    line_number = tokens.line_number();
+                }
+                directive.push(token);
+            }
+            None => return Ok(()),
+        }
+    }
+}
+
+/// Parse a directive and feed it to `api`. If the API returns an error, it is stored in
+/// `api_error` since the parser would otherwise discard it.
+fn parse<'input, A: Api>(
     input: Tokens<'input>,
-    api: &'api A,
+    api: &A,
+    context: &ParseContext,
+    api_error: &RefCell<Option<anyhow::Error>>,
 ) -> IResult<Tokens<'input>, ()> {
+    let check = |result: Result<()>| {
+        result.map_err(|e| {
+            api_error.borrow_mut().get_or_insert(e);
+        })
+    };
+    let dependencies = &context.dependencies;
+    let param_list = |i: Tokens<'input>| parse_param_list(i, dependencies);
     let accelerator = map_res(
         tuple((token(Token::ACCELERATOR), string_, param_list)),
-        |(_, typ, params)| api.accelerator(typ, &params),
+        |(_, typ, params)| check(api.accelerator(typ, params)),
     );
-    let attribute_begin = map_res(token(Token::ATTRIBUTEBEGIN), |_| api.attribute_begin());
-    let attribute_end = map_res(token(Token::ATTRIBUTEEND), |_| api.attribute_end());
-    let transform_begin = map_res(token(Token::TRANSFORMBEGIN), |_| api.transform_begin());
-    let transform_end = map_res(token(Token::TRANSFORMEND), |_| api.transform_end());
+    let attribute_begin = map_res(token(Token::ATTRIBUTEBEGIN), |_| {
+        check(api.attribute_begin())
+    });
+    let attribute_end = map_res(token(Token::ATTRIBUTEEND), |_| check(api.attribute_end()));
+    let transform_begin = map_res(token(Token::TRANSFORMBEGIN), |_| {
+        check(api.transform_begin())
+    });
+    let transform_end = map_res(token(Token::TRANSFORMEND), |_| check(api.transform_end()));
     let object_begin = map_res(pair(token(Token::OBJECTBEGIN), string_), |(_, name)| {
-        api.object_begin(name)
+        check(api.object_begin(name))
     });
-    let object_end = map_res(token(Token::OBJECTEND), |_| api.object_end());
+    let object_end = map_res(token(Token::OBJECTEND), |_| check(api.object_end()));
     let object_instance = map_res(pair(token(Token::OBJECTINSTANCE), string_), |(_, name)| {
-        api.object_instance(name)
+        check(api.object_instance(name))
     });
-    let world_begin = map_res(token(Token::WORLDBEGIN), |_| api.world_begin());
-    let world_end = map_res(token(Token::WORLDEND), |_| api.world_end());
+    let world_begin = map_res(token(Token::WORLDBEGIN), |_| check(api.world_begin()));
+    let world_end = map_res(token(Token::WORLDEND), |_| check(api.world_end()));
     let look_at = map_res(
         tuple((
             token(Token::LOOKAT),
@@ -51,100 +127,128 @@ pub fn parse<'input, 'api, A: Api>(
             num,
             num,
         )),
-        |(_, ex, ey, ez, lx, ly, lz, ux, uy, uz)| api.look_at(ex, ey, ez, lx, ly, lz, ux, uy, uz),
+        |(_, ex, ey, ez, lx, ly, lz, ux, uy, uz)| {
+            check(api.look_at(ex, ey, ez, lx, ly, lz, ux, uy, uz))
+        },
     );
     let coordinate_system = map_res(
         pair(token(Token::COORDINATESYSTEM), string_),
-        |(_, name)| api.coordinate_system(name),
+        |(_, name)| check(api.coordinate_system(name)),
     );
     let coord_sys_transform = map_res(
         pair(token(Token::COORDSYSTRANSFORM), string_),
-        |(_, name)| api.coord_sys_transform(name),
+        |(_, name)| check(api.coord_sys_transform(name)),
     );
     let camera = map_res(
         tuple((token(Token::CAMERA), string_, param_list)),
-        |(_, typ, params)| api.camera(typ, &params),
+        |(_, typ, params)| check(api.camera(typ, params)),
     );
     let film = map_res(
         tuple((token(Token::FILM), string_, param_list)),
-        |(_, typ, params)| api.film(typ, &params),
+        |(_, typ, params)| check(api.film(typ, params)),
     );
     let include = map_res(pair(token(Token::INCLUDE), string_), |(_, name)| {
         info!("Parsing included file: {}", name);
-        super::tokenize_file(&name).and_then(|tokens| {
-            parse(Tokens::new(&tokens[..]), api)
-                .map(|_| ())
-                .map_err(|e| format_err!("Failed to parse included file: {:?}", e))
-        })
+        if context.use_cache {
+            check(
+                super::cache::tokenize_file_cached(&name, dependencies)
+                    .and_then(|tokens| parse_stream(tokens, api, context)),
+            )
+        } else {
+            check(
+                super::tokenize_file(&name, dependencies)
+                    .and_then(|tokens| parse_stream(tokens, api, context)),
+            )
+        }
     });
     let integrator = map_res(
         tuple((token(Token::INTEGRATOR), string_, param_list)),
-        |(_, typ, params)| api.integrator(typ, &params),
+        |(_, typ, params)| check(api.integrator(typ, params)),
     );
     let arealightsource = map_res(
         tuple((token(Token::AREALIGHTSOURCE), string_, param_list)),
-        |(_, typ, params)| api.arealightsource(typ, &params),
+        |(_, typ, params)| check(api.arealightsource(typ, params)),
     );
     let lightsource = map_res(
         tuple((token(Token::LIGHTSOURCE), string_, param_list)),
-        |(_, typ, params)| api.lightsource(typ, &params),
+        |(_, typ, params)| check(api.lightsource(typ, &params)),
     );
     let material = map_res(
         tuple((token(Token::MATERIAL), string_, param_list)),
-        |(_, typ, params)| api.material(typ, &params),
+        |(_, typ, params)| check(api.material(typ, params)),
     );
     let make_named_material = map_res(
         tuple((token(Token::MAKENAMEDMATERIAL), string_, param_list)),
-        |(_, typ, params)| api.make_named_material(typ, &params),
+        |(_, typ, params)| check(api.make_named_material(typ, &params)),
     );
     let named_material = map_res(pair(token(Token::NAMEDMATERIAL), string_), |(_, name)| {
-        api.named_material(name)
+        check(api.named_material(name))
     });
     let sampler = map_res(
         tuple((token(Token::SAMPLER), string_, param_list)),
-        |(_, typ, params)| api.sampler(typ, &params),
+        |(_, typ, params)| check(api.sampler(typ, params)),
     );
     let shape = map_res(
         tuple((token(Token::SHAPE), string_, param_list)),
-        |(_, typ, params)| api.shape(typ, &params),
+        |(_, typ, params)| check(api.shape(typ, &params)),
     );
     let reverse_orientation = map_res(token(Token::REVERSEORIENTATION), |_| {
-        api.reverse_orientation()
+        check(api.reverse_orientation())
     });
     let filter = map_res(
         tuple((token(Token::PIXELFILTER), string_, param_list)),
-        |(_, typ, params)| api.pixel_filter(typ, &params),
+        |(_, typ, params)| check(api.pixel_filter(typ, params)),
     );
     let scale = map_res(
         tuple((token(Token::SCALE), num, num, num)),
-        |(_, sx, sy, sz)| api.scale(sx, sy, sz),
+        |(_, sx, sy, sz)| check(api.scale(sx, sy, sz)),
     );
     let rotate = map_res(
         tuple((token(Token::ROTATE), num, num, num, num)),
-        |(_, angle, dx, dy, dz)| api.rotate(angle, dx, dy, dz),
+        |(_, angle, dx, dy, dz)| check(api.rotate(angle, dx, dy, dz)),
     );
     let texture = map_res(
         tuple((token(Token::TEXTURE), string_, string_, string_, param_list)),
-        |(_, name, typ, texname, params)| api.texture(name, typ, texname, &params),
+        |(_, name, typ, texname, params)| check(api.texture(name, typ, texname, &params)),
     );
     let concat_transform = map_res(
         pair(token(Token::CONCATTRANSFORM), num_array),
         |(_, nums)| {
-            api.concat_transform(
+            check(api.concat_transform(
                 nums[0], nums[1], nums[2], nums[3], nums[4], nums[5], nums[6], nums[7], nums[8],
                 nums[9], nums[10], nums[11], nums[12], nums[13], nums[14], nums[15],
-            )
+            ))
         },
     );
     let transform = map_res(pair(token(Token::TRANSFORM), num_array), |(_, nums)| {
-        api.transform(
+        check(api.transform(
             nums[0], nums[1], nums[2], nums[3], nums[4], nums[5], nums[6], nums[7], nums[8],
             nums[9], nums[10], nums[11], nums[12], nums[13], nums[14], nums[15],
-        )
+        ))
     });
     let translate = map_res(
         tuple((token(Token::TRANSLATE), num, num, num)),
-        |(_, dx, dy, dz)| api.translate(dx, dy, dz),
+        |(_, dx, dy, dz)| check(api.translate(dx, dy, dz)),
+    );
+    let identity = map_res(token(Token::IDENTITY), |_| check(api.identity()));
+    let active_transform = map_res(
+        pair(
+            token(Token::ACTIVETRANSFORM),
+            alt((
+                token(Token::ALL),
+                token(Token::STARTTIME),
+                token(Token::ENDTIME),
+            )),
+        ),
+        |(_, which)| match which {
+            Token::STARTTIME => check(api.active_transform_start_time()),
+            Token::ENDTIME => check(api.active_transform_end_time()),
+            _ => check(api.active_transform_all()),
+        },
+    );
+    let transform_times = map_res(
+        tuple((token(Token::TRANSFORMTIMES), num, num)),
+        |(_, start, end)| check(api.transform_times(start, end)),
     );
 
     let (rest, _) = all_consuming(many1(alt((
@@ -181,15 +285,22 @@ pub fn parse<'input, 'api, A: Api>(
             concat_transform,
             transform,
             translate,
+            identity,
+            active_transform,
+            transform_times,
         )),
     ))))(input)?;
 
     Ok((rest, ()))
 }
 
-fn param_list(input: Tokens<'_>) -> IResult<Tokens<'_>, ParamSet> {
+/// Parse the parameters of a directive. The files they name are recorded in `dependencies`.
+fn parse_param_list<'a>(
+    input: Tokens<'a>,
+    dependencies: &Dependencies,
+) -> IResult<Tokens<'a>, ParamSet> {
     map(many0(param_list_entry), |x| {
-        let mut ps = ParamSet::default();
+        let mut ps = ParamSet::with_dependencies(dependencies.clone());
         ps.init(x);
         ps
     })(input)
@@ -206,6 +317,7 @@ fn param_type(input: &str) -> IResult<&str, ParamType> {
         value(ParamType::Vector3, tag("vector3")),
         value(ParamType::Point3, tag("point")),
         value(ParamType::Vector3, tag("vector")),
+        value(ParamType::Normal, tag("normal3")),
         value(ParamType::Normal, tag("normal")),
         value(ParamType::Rgb, tag("color")),
         value(ParamType::Rgb, tag("rgb")),
@@ -282,7 +394,7 @@ where
         (&t, b)
     }) {
         Some((t, true)) => Ok((i.slice(1..), t.clone())),
-        _ => Err(nom::Err::Error(Error::new(i, nom::error::ErrorKind::Char))),
+        _ => Err(nom::Err::Error(Error::new(i, ErrorKind::Char))),
     }
 }
 
@@ -296,9 +408,11 @@ where
 
 #[cfg(test)]
 mod tests {
-    use nom::{error::ParseError, Parser};
+    use nom::{error::ParseError, InputLength, Parser};
 
     use super::*;
+    use crate::spectrum::Spectrum;
+    use crate::{Normal3f, Point2f, Point3f, Vector2f, Vector3f};
 
     fn test_parse<'a, O, E>(input: &'a [Token], mut p: impl Parser<Tokens<'a>, O, E>, v: O)
     where
@@ -329,7 +443,7 @@ mod tests {
 
     #[test]
     fn test_param_list_entry() {
-        let p = vec![
+        let p = [
             Token::STR("float fov".to_owned()),
             Token::LBRACK,
             Token::NUMBER(50.0),
@@ -351,6 +465,94 @@ mod tests {
         assert_eq!(res, ("xxx", ParamType::Float));
     }
 
+    /// Parse a single parameter declared as `"<decl>" [values]`.
+    fn parse_param(decl: &str, values: &[Token]) -> ParamSet {
+        let mut p = vec![Token::STR(decl.to_owned()), Token::LBRACK];
+        p.extend_from_slice(values);
+        p.push(Token::RBRACK);
+        let (rest, ps) = parse_param_list(Tokens::new(&p[..]), &Dependencies::default()).unwrap();
+        assert_eq!(rest.input_len(), 0, "{} wasn't fully parsed", decl);
+        ps
+    }
+
+    fn nums(values: &[f32]) -> Vec<Token> {
+        values.iter().map(|v| Token::NUMBER(*v)).collect()
+    }
+
+    fn strs(values: &[&str]) -> Vec<Token> {
+        values.iter().map(|v| Token::STR((*v).to_owned())).collect()
+    }
+
+    #[test]
+    fn test_param_types_round_trip() {
+        let ps = parse_param("integer n", &nums(&[1.0, -2.0]));
+        assert_eq!(ps.find_int("n"), Some(vec![1, -2]));
+        let ps = parse_param("bool b", &strs(&["true", "false"]));
+        assert_eq!(ps.find_bool("b"), Some(vec![true, false]));
+        let ps = parse_param("float f", &nums(&[0.5]));
+        assert_eq!(ps.find_one_float("f", 0.0), 0.5);
+        let ps = parse_param("string s", &strs(&["a", "b"]));
+        assert_eq!(
+            ps.find_string("s"),
+            Some(vec!["a".to_owned(), "b".to_owned()])
+        );
+        let ps = parse_param("texture t", &strs(&["tex"]));
+        assert_eq!(ps.find_texture("t", "".to_owned()), "tex");
+
+        let ps = parse_param("point2 uv", &nums(&[0.0, 1.0, 2.0, 3.0]));
+        assert_eq!(
+            ps.find_point2f("uv"),
+            Some(vec![Point2f::new(0.0, 1.0), Point2f::new(2.0, 3.0)])
+        );
+        let ps = parse_param("vector2 d", &nums(&[4.0, 5.0, 6.0, 7.0]));
+        assert_eq!(
+            ps.find_vector2f("d"),
+            Some(vec![Vector2f::new(4.0, 5.0), Vector2f::new(6.0, 7.0)])
+        );
+        for decl in &["point3 P", "point P"] {
+            let ps = parse_param(decl, &nums(&[1.0, 2.0, 3.0]));
+            assert_eq!(
+                ps.find_point3f("P"),
+                Some(vec![Point3f::new(1.0, 2.0, 3.0)])
+            );
+        }
+        for decl in &["vector3 v", "vector v"] {
+            let ps = parse_param(decl, &nums(&[1.0, 2.0, 3.0]));
+            assert_eq!(
+                ps.find_vector3f("v"),
+                Some(vec![Vector3f::new(1.0, 2.0, 3.0)])
+            );
+        }
+        for decl in &["normal3 N", "normal N"] {
+            let ps = parse_param(decl, &nums(&[0.0, 0.0, 1.0]));
+            assert_eq!(
+                ps.find_normal3f("N"),
+                Some(vec![Normal3f::new(0.0, 0.0, 1.0)])
+            );
+        }
+
+        for decl in &["rgb Kd", "color Kd"] {
+            let ps = parse_param(decl, &nums(&[0.1, 0.2, 0.3]));
+            assert_eq!(
+                ps.find_one_spectrum("Kd", Spectrum::black()),
+                Spectrum::rgb(0.1, 0.2, 0.3)
+            );
+        }
+        let xyz = Spectrum::rgb(0.1, 0.2, 0.3).to_xyz();
+        let ps = parse_param("xyz Kd", &nums(&xyz));
+        let rgb = ps.find_one_spectrum("Kd", Spectrum::black());
+        for (c, e) in [rgb[0], rgb[1], rgb[2]].iter().zip(&[0.1, 0.2, 0.3]) {
+            assert!((c - e).abs() < 1e-4, "{:?}", rgb);
+        }
+        let ps = parse_param("blackbody L", &nums(&[6500.0, 2.0]));
+        assert!(!ps.find_one_spectrum("L", Spectrum::black()).is_black());
+
+        let spd = std::env::temp_dir().join("rustracer_parser_spectrum.spd");
+        std::fs::write(&spd, "400 1\n500 1\n600 1\n700 1\n").unwrap();
+        let ps = parse_param("spectrum L", &strs(&[spd.to_str().unwrap()]));
+        assert!(!ps.find_one_spectrum("L", Spectrum::black()).is_black());
+    }
+
     #[test]
     fn test_param_list_entry_header() {
         let p = vec![Token::STR("float fov".to_owned())];
@@ -1,40 +1,51 @@
+use std::any::Any;
+use std::borrow::Cow;
 use std::fmt::Debug;
 use std::sync::Arc;
 
-use light_arena::Allocator;
-
-use crate::bounds::Bounds3f;
+use crate::bounds::{Axis, Bounds3f};
+use crate::cryptomatte::MatteIds;
 use crate::interaction::SurfaceInteraction;
-use crate::light::AreaLight;
-use crate::material::{Material, TransportMode};
+use crate::light::AreaLightRef;
+use crate::material::MaterialRef;
 use crate::ray::Ray;
-use crate::shapes::Shape;
+use crate::shapes::{self, ShapeRef};
+use crate::transform::AnimatedTransform;
 use crate::Transform;
 
+/// Shared reference to a primitive. The same primitives are referenced by the acceleration
+/// structures of the scene and of its instances.
+pub type PrimitiveRef = Arc<dyn Primitive>;
+
 pub trait Primitive: Debug + Send + Sync {
     fn world_bounds(&self) -> Bounds3f;
 
-    fn intersect(&self, ray: &mut Ray) -> Option<SurfaceInteraction<'_, '_>>;
+    /// See `Shape::split_world_bounds()`.
+    fn split_world_bounds(&self, axis: Axis, pos: f32) -> (Bounds3f, Bounds3f) {
+        shapes::split_bounds(&self.world_bounds(), axis, pos)
+    }
+
+    /// Allow downcasting to the concrete primitive type in order to modify it (see
+    /// `Scene::set_primitive_transform()`).
+    fn as_any_mut(&mut self) -> &mut dyn Any;
+
+    fn intersect(&self, ray: &mut Ray) -> Option<SurfaceInteraction>;
 
     fn intersect_p(&self, ray: &Ray) -> bool;
 
-    fn area_light(&self) -> Option<Arc<dyn AreaLight>>;
+    fn area_light(&self) -> Option<AreaLightRef>;
 
-    fn material(&self) -> Option<Arc<dyn Material>>;
-    fn compute_scattering_functions<'a, 'b>(
-        &self,
-        isect: &mut SurfaceInteraction<'a, 'b>,
-        mode: TransportMode,
-        allow_multiple_lobes: bool,
-        arena: &'b Allocator<'_>,
-    );
+    fn material(&self) -> Option<MaterialRef>;
 }
 
 #[derive(Debug)]
 pub struct GeometricPrimitive {
-    pub shape: Arc<dyn Shape>,
-    pub area_light: Option<Arc<dyn AreaLight>>,
-    pub material: Option<Arc<dyn Material>>,
+    pub shape: ShapeRef,
+    pub area_light: Option<AreaLightRef>,
+    pub material: Option<MaterialRef>,
+    /// IDs of the primitive's object and material in the Cryptomatte mattes (see the
+    /// `cryptomatte` module)
+    pub matte_ids: MatteIds,
 }
 
 impl Primitive for GeometricPrimitive {
@@ -42,77 +53,185 @@ impl Primitive for GeometricPrimitive {
         self.shape.world_bounds()
     }
 
-    fn intersect(&self, ray: &mut Ray) -> Option<SurfaceInteraction<'_, '_>> {
-        self.shape.intersect(ray).map(|(mut isect, t_hit)| {
-            isect.primitive = Some(self);
-            ray.t_max = t_hit;
-            isect
-        })
+    fn split_world_bounds(&self, axis: Axis, pos: f32) -> (Bounds3f, Bounds3f) {
+        self.shape.split_world_bounds(axis, pos)
+    }
+
+    fn as_any_mut(&mut self) -> &mut dyn Any {
+        self
+    }
+
+    fn intersect(&self, ray: &mut Ray) -> Option<SurfaceInteraction> {
+        let (mut isect, t_hit) = self.shape.intersect(ray)?;
+        isect.material = self.material.clone();
+        isect.area_light = self.area_light.clone();
+        isect.matte_ids = self.matte_ids;
+        ray.t_max = t_hit;
+        Some(isect)
     }
 
     fn intersect_p(&self, ray: &Ray) -> bool {
         self.shape.intersect_p(ray)
     }
 
-    fn area_light(&self) -> Option<Arc<dyn AreaLight>> {
+    fn area_light(&self) -> Option<AreaLightRef> {
         self.area_light.clone()
     }
 
-    fn material(&self) -> Option<Arc<dyn Material>> {
+    fn material(&self) -> Option<MaterialRef> {
         self.material.clone()
     }
-
-    fn compute_scattering_functions<'a, 'b>(
-        &self,
-        isect: &mut SurfaceInteraction<'a, 'b>,
-        mode: TransportMode,
-        allow_multiple_lobes: bool,
-        arena: &'b Allocator<'_>,
-    ) {
-        if let Some(ref material) = self.material() {
-            material.compute_scattering_functions(isect, mode, allow_multiple_lobes, arena);
-        }
-    }
 }
 
 #[derive(Debug)]
 pub struct TransformedPrimitive {
-    pub primitive: Arc<dyn Primitive>,
+    pub primitive: PrimitiveRef,
     pub primitive_to_world: Transform,
+    /// Transform of the previous frame, for the motion vectors (see `Scene::end_frame()`)
+    pub previous_to_world: Transform,
+    /// Motion over the shutter interval, which replaces `primitive_to_world` when there is one
+    pub motion: Option<AnimatedTransform>,
+}
+
+impl TransformedPrimitive {
+    pub fn new(primitive: PrimitiveRef, primitive_to_world: Transform) -> TransformedPrimitive {
+        TransformedPrimitive {
+            primitive,
+            previous_to_world: primitive_to_world.clone(),
+            primitive_to_world,
+            motion: None,
+        }
+    }
+
+    /// A primitive moving over the shutter interval. Its bounds cover the whole motion (see
+    /// `AnimatedTransform::motion_bounds()`), so that acceleration structures built around it
+    /// don't clip it.
+    pub fn animated(primitive: PrimitiveRef, motion: AnimatedTransform) -> TransformedPrimitive {
+        let mut transformed = TransformedPrimitive::new(primitive, motion.start().clone());
+        transformed.motion = Some(motion);
+        transformed
+    }
+
+    /// Primitive to world transform at the given time.
+    fn primitive_to_world(&self, time: f32) -> Cow<'_, Transform> {
+        match self.motion {
+            Some(ref motion) => Cow::Owned(motion.interpolate(time)),
+            None => Cow::Borrowed(&self.primitive_to_world),
+        }
+    }
 }
 
 impl Primitive for TransformedPrimitive {
     fn world_bounds(&self) -> Bounds3f {
-        &self.primitive_to_world * &self.primitive.world_bounds()
+        match self.motion {
+            Some(ref motion) => motion.motion_bounds(&self.primitive.world_bounds()),
+            None => &self.primitive_to_world * &self.primitive.world_bounds(),
+        }
     }
 
-    fn intersect(&self, ray: &mut Ray) -> Option<SurfaceInteraction<'_, '_>> {
-        let mut r = self.primitive_to_world.inverse() * *ray;
+    fn as_any_mut(&mut self) -> &mut dyn Any {
+        self
+    }
+
+    fn intersect(&self, ray: &mut Ray) -> Option<SurfaceInteraction> {
+        let primitive_to_world = self.primitive_to_world(ray.time);
+        let mut r = primitive_to_world.inverse() * *ray;
         self.primitive.intersect(&mut r).map(|isect| {
             ray.t_max = r.t_max;
-            isect.transform(&self.primitive_to_world)
+            let mut si = isect.transform(&primitive_to_world);
+            // Motion within the shutter interval isn't motion between frames
+            let previous_to_world = if self.motion.is_some() {
+                &primitive_to_world
+            } else {
+                &self.previous_to_world
+            };
+            si.p_previous = previous_to_world * &isect.p_previous;
+            si
         })
     }
 
     fn intersect_p(&self, ray: &Ray) -> bool {
-        let r = self.primitive_to_world.inverse() * *ray;
+        let r = self.primitive_to_world(ray.time).inverse() * *ray;
         self.primitive.intersect_p(&r)
     }
 
-    fn area_light(&self) -> Option<Arc<dyn AreaLight>> {
+    fn area_light(&self) -> Option<AreaLightRef> {
         None
     }
 
-    fn material(&self) -> Option<Arc<dyn Material>> {
+    fn material(&self) -> Option<MaterialRef> {
         None
     }
-    fn compute_scattering_functions<'a, 'b>(
-        &self,
-        _isect: &mut SurfaceInteraction<'a, 'b>,
-        _mode: TransportMode,
-        _allow_multiple_lobes: bool,
-        _arena: &'b Allocator<'_>,
-    ) {
-        panic!("TransformedPrimitive::compute_scattering_functions() should not be called!");
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ray::RayDifferential;
+    use crate::shapes::Sphere;
+    use crate::{init_stats, Point3f, Vector3f};
+
+    #[test]
+    fn test_instanced_shapes_have_the_same_differentials() {
+        init_stats();
+        let object_to_world = &Transform::translate(&Vector3f::new(0.5, -0.25, 2.0))
+            * &(&Transform::rotate(30.0, Vector3f::new(1.0, 1.0, 0.0))
+                * &Transform::scale(2.0, 2.0, 2.0));
+        let sphere = |t: Transform| -> PrimitiveRef {
+            Arc::new(GeometricPrimitive {
+                shape: Arc::new(Sphere::new(t, 1.0, -1.0, 1.0, 360.0, false)),
+                area_light: None,
+                material: None,
+                matte_ids: MatteIds::default(),
+            })
+        };
+        let direct = sphere(object_to_world.clone());
+        let instanced = TransformedPrimitive::new(sphere(Transform::default()), object_to_world);
+
+        let mut ray = Ray::new(Point3f::new(0.3, 0.2, -5.0), Vector3f::new(0.0, 0.0, 1.0));
+        ray.differential = Some(RayDifferential {
+            rx_origin: Point3f::new(0.31, 0.2, -5.0),
+            ry_origin: Point3f::new(0.3, 0.21, -5.0),
+            rx_direction: ray.d,
+            ry_direction: ray.d,
+        });
+
+        let mut r1 = ray;
+        let mut r2 = ray;
+        let mut expected = direct.intersect(&mut r1).unwrap();
+        let mut actual = instanced.intersect(&mut r2).unwrap();
+        expected.compute_differential(&ray);
+        actual.compute_differential(&ray);
+
+        let close = |a: f32, b: f32| (a - b).abs() < 1e-3 * f32::max(1.0, a.abs());
+        assert!(close(expected.uv.x, actual.uv.x) && close(expected.uv.y, actual.uv.y));
+        assert!(expected.dudx != 0.0 && expected.dvdy != 0.0);
+        // The normal derivatives used for bump mapping must survive the object to world transform
+        assert!(Vector3f::from(expected.dndu).length() > 0.0);
+        for (a, b) in [
+            (expected.dudx, actual.dudx),
+            (expected.dvdx, actual.dvdx),
+            (expected.dudy, actual.dudy),
+            (expected.dvdy, actual.dvdy),
+        ] {
+            assert!(close(a, b), "{} != {}", a, b);
+        }
+        for (a, b) in [
+            (expected.dpdx, actual.dpdx),
+            (expected.dpdy, actual.dpdy),
+            (expected.dndu.into(), actual.dndu.into()),
+            (expected.dndv.into(), actual.dndv.into()),
+        ] {
+            assert!((a - b).length() < 1e-3, "{:?} != {:?}", a, b);
+        }
+
+        // Differentials computed in object space survive the transformation to world space
+        let object_ray = instanced.primitive_to_world.inverse() * ray;
+        let mut r3 = object_ray;
+        let mut object_isect = instanced.primitive.intersect(&mut r3).unwrap();
+        object_isect.compute_differential(&object_ray);
+        let transformed = object_isect.transform(&instanced.primitive_to_world);
+        assert!(close(expected.dudx, transformed.dudx));
+        assert!((expected.dpdx - transformed.dpdx).length() < 1e-3);
     }
 }
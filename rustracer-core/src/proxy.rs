@@ -0,0 +1,320 @@
+//! Geometry loaded on demand, for scenes too big to load up front.
+//!
+//! `Shape "proxy"` stands for a PLY mesh of which only the bounds are recorded when the scene is
+//! parsed. The mesh is loaded, and its bottom-level BVH built, the first time a ray hits these
+//! bounds. Before rendering, a coarse grid of camera rays is traced (see `preload_visible()`) so
+//! that the meshes the camera sees are loaded by several threads at once, rather than by the
+//! first render threads that happen to hit them.
+//!
+//! Parameters:
+//!
+//! * `"string filename"`: the PLY file;
+//! * `"float bounds"`: bounds of the mesh in object space, as `[x0 y0 z0 x1 y1 z1]`. Without
+//!   them, the mesh has to be loaded while parsing to compute them (it is dropped afterwards).
+//!
+//! The other parameters are those of `Shape "plymesh"`. Proxies can't be area lights.
+
+use std::any::Any;
+use std::collections::HashMap;
+use std::fmt;
+use std::path::Path;
+use std::sync::{Arc, OnceLock};
+use std::time::Instant;
+
+use anyhow::{bail, Result};
+use log::{info, warn};
+use parking_lot::Mutex;
+
+use crate::bounds::Bounds3f;
+use crate::bvh::{self, BuildParams};
+use crate::camera::{Camera, CameraSample};
+use crate::cryptomatte::MatteIds;
+use crate::interaction::SurfaceInteraction;
+use crate::light::AreaLightRef;
+use crate::material::MaterialRef;
+use crate::paramset::ParamSet;
+use crate::primitive::{GeometricPrimitive, Primitive, PrimitiveRef};
+use crate::ray::Ray;
+use crate::scene::Scene;
+use crate::shapes::{plymesh, ShapeRef};
+use crate::texture::TextureRef;
+use crate::{Point2f, Point3f, Transform};
+
+stat_counter!("Scene/Deferred meshes", n_deferred);
+stat_counter!("Scene/Deferred meshes loaded", n_deferred_loaded);
+pub fn init_stats() {
+    n_deferred::init();
+    n_deferred_loaded::init();
+}
+
+/// Spacing in pixels of the camera rays traced by `preload_visible()`
+const PRELOAD_SPACING: i32 = 8;
+
+/// Creates the bottom-level structure of a deferred mesh, or `None` if it has no primitives.
+type Loader = Box<dyn FnOnce() -> Option<PrimitiveRef> + Send>;
+
+/// A mesh that is only loaded when a ray first hits its bounds. Loading is thread-safe: the
+/// first thread to need the mesh loads it while the others wait for it.
+pub struct DeferredPrimitive {
+    filename: String,
+    /// World space bounds of the mesh
+    bounds: Bounds3f,
+    material: Option<MaterialRef>,
+    loader: Mutex<Option<Loader>>,
+    blas: OnceLock<Option<PrimitiveRef>>,
+}
+
+impl DeferredPrimitive {
+    pub fn new<F>(
+        filename: &str,
+        bounds: Bounds3f,
+        material: Option<MaterialRef>,
+        loader: F,
+    ) -> DeferredPrimitive
+    where
+        F: FnOnce() -> Option<PrimitiveRef> + Send + 'static,
+    {
+        n_deferred::inc();
+        DeferredPrimitive {
+            filename: filename.to_owned(),
+            bounds,
+            material,
+            loader: Mutex::new(Some(Box::new(loader))),
+            blas: OnceLock::new(),
+        }
+    }
+
+    pub fn is_loaded(&self) -> bool {
+        self.blas.get().is_some()
+    }
+
+    /// The bottom-level structure of the mesh, loading it if needed.
+    fn blas(&self) -> Option<&PrimitiveRef> {
+        self.blas
+            .get_or_init(|| {
+                let start = Instant::now();
+                let load = self
+                    .loader
+                    .lock()
+                    .take()
+                    .expect("deferred mesh loader already used");
+                let blas = load();
+                n_deferred_loaded::inc();
+                info!(
+                    "Loaded deferred mesh {} in {:?}",
+                    self.filename,
+                    start.elapsed()
+                );
+                blas
+            })
+            .as_ref()
+    }
+}
+
+impl fmt::Debug for DeferredPrimitive {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("DeferredPrimitive")
+            .field("filename", &self.filename)
+            .field("bounds", &self.bounds)
+            .field("loaded", &self.is_loaded())
+            .finish()
+    }
+}
+
+impl Primitive for DeferredPrimitive {
+    fn world_bounds(&self) -> Bounds3f {
+        self.bounds
+    }
+
+    fn as_any_mut(&mut self) -> &mut dyn Any {
+        self
+    }
+
+    fn intersect(&self, ray: &mut Ray) -> Option<SurfaceInteraction> {
+        self.bounds.intersect_p(ray)?;
+        self.blas()?.intersect(ray)
+    }
+
+    fn intersect_p(&self, ray: &Ray) -> bool {
+        self.bounds.intersect_p(ray).is_some() && self.blas().is_some_and(|b| b.intersect_p(ray))
+    }
+
+    fn area_light(&self) -> Option<AreaLightRef> {
+        None
+    }
+
+    fn material(&self) -> Option<MaterialRef> {
+        self.material.clone()
+    }
+}
+
+/// Create the deferred mesh of a `Shape "proxy"` directive.
+pub fn create(
+    o2w: &Transform,
+    reverse_orientation: bool,
+    params: &ParamSet,
+    float_textures: &Arc<HashMap<String, TextureRef<f32>>>,
+    material: MaterialRef,
+    matte_ids: MatteIds,
+    bvh_params: BuildParams,
+) -> Result<DeferredPrimitive> {
+    let filename = params.find_one_filename("filename", "".into());
+    if filename.is_empty() {
+        bail!("No \"filename\" given for the proxy");
+    }
+    if !Path::new(&filename).is_file() {
+        bail!("Proxy mesh {} not found", filename);
+    }
+
+    let load_shapes = {
+        let (o2w, params, float_textures) =
+            (o2w.clone(), params.clone(), Arc::clone(float_textures));
+        move || -> Vec<ShapeRef> {
+            plymesh::create(
+                &o2w,
+                &o2w.inverse(),
+                reverse_orientation,
+                &params,
+                &float_textures,
+            )
+        }
+    };
+    let bounds = match params.find_float("bounds") {
+        Some(b) if b.len() == 6 => {
+            o2w * &Bounds3f::from_points(
+                &Point3f::new(b[0], b[1], b[2]),
+                &Point3f::new(b[3], b[4], b[5]),
+            )
+        }
+        b => {
+            if b.is_some() {
+                warn!("Expected 6 values for the \"bounds\" of proxy {}", filename);
+            }
+            warn!(
+                "Loading proxy mesh {} to compute its bounds. Give them with \"float bounds\" to defer loading it.",
+                filename
+            );
+            load_shapes()
+                .iter()
+                .map(|s| s.world_bounds())
+                .reduce(|b1, b2| Bounds3f::union(&b1, &b2))
+                .unwrap_or_default()
+        }
+    };
+
+    let prim_material = Arc::clone(&material);
+    Ok(DeferredPrimitive::new(
+        &filename,
+        bounds,
+        Some(material),
+        move || {
+            let prims: Vec<PrimitiveRef> = load_shapes()
+                .into_iter()
+                .map(|shape| {
+                    Arc::new(GeometricPrimitive {
+                        shape,
+                        area_light: None,
+                        material: Some(Arc::clone(&prim_material)),
+                        matte_ids,
+                    }) as PrimitiveRef
+                })
+                .collect();
+            if prims.is_empty() {
+                None
+            } else {
+                Some(bvh::build_blas(&prims, &bvh_params))
+            }
+        },
+    ))
+}
+
+/// Trace camera rays every few pixels, so that the deferred meshes the camera sees are loaded
+/// before rendering starts. The rays are spread over `threads` threads so that several meshes
+/// can be loaded at once.
+pub fn preload_visible(scene: &Scene, camera: &dyn Camera, threads: usize) {
+    let start = Instant::now();
+    let bounds = camera.get_film().get_sample_bounds();
+    let rows: Vec<i32> = (bounds.p_min.y..bounds.p_max.y)
+        .step_by(PRELOAD_SPACING as usize)
+        .collect();
+    let next_row = Mutex::new(rows.into_iter());
+    crossbeam::scope(|scope| {
+        for _ in 0..threads.max(1) {
+            scope.spawn(|_| loop {
+                let y = match next_row.lock().next() {
+                    Some(y) => y,
+                    None => break,
+                };
+                for x in (bounds.p_min.x..bounds.p_max.x).step_by(PRELOAD_SPACING as usize) {
+                    let sample = CameraSample {
+                        p_film: Point2f::new(x as f32 + 0.5, y as f32 + 0.5),
+                        p_lens: Point2f::new(0.5, 0.5),
+                        time: 0.0,
+                    };
+                    let mut ray = camera.generate_ray(&sample);
+                    scene.intersect(&mut ray);
+                }
+            });
+        }
+    })
+    .unwrap();
+    info!(
+        "Preloaded the visible deferred meshes in {:?}",
+        start.elapsed()
+    );
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    use super::*;
+    use crate::shapes::{Shape, Sphere};
+    use crate::Vector3f;
+
+    #[test]
+    fn test_deferred_loading() {
+        let loads = Arc::new(AtomicUsize::new(0));
+        let sphere = Arc::new(Sphere::new(
+            Transform::default(),
+            1.0,
+            -1.0,
+            1.0,
+            360.0,
+            false,
+        ));
+        let bounds = sphere.world_bounds();
+        let proxy = DeferredPrimitive::new("sphere", bounds, None, {
+            let loads = Arc::clone(&loads);
+            move || {
+                loads.fetch_add(1, Ordering::SeqCst);
+                Some(Arc::new(GeometricPrimitive {
+                    shape: sphere,
+                    area_light: None,
+                    material: None,
+                    matte_ids: MatteIds::default(),
+                }) as PrimitiveRef)
+            }
+        });
+
+        // Rays that miss the bounds don't load the mesh
+        let miss = Ray::new(Point3f::new(5.0, 0.0, -5.0), Vector3f::new(0.0, 0.0, 1.0));
+        assert!(!proxy.intersect_p(&miss));
+        assert!(!proxy.is_loaded());
+
+        // The mesh is loaded once, by whichever thread gets there first
+        crossbeam::scope(|scope| {
+            for _ in 0..4 {
+                scope.spawn(|_| {
+                    let mut hit =
+                        Ray::new(Point3f::new(0.0, 0.0, -5.0), Vector3f::new(0.0, 0.0, 1.0));
+                    let isect = proxy.intersect(&mut hit).unwrap();
+                    assert!((isect.hit.p.z + 1.0).abs() < 1e-4);
+                });
+            }
+        })
+        .unwrap();
+        assert!(proxy.is_loaded());
+        assert_eq!(loads.load(Ordering::SeqCst), 1);
+    }
+}
@@ -0,0 +1,197 @@
+//! Quaternions, to interpolate rotations (see `AnimatedTransform`).
+
+use std::ops::{Add, Mul, Neg, Sub};
+
+use crate::geometry::Matrix4x4;
+use crate::{clamp, Transform, Vector3f};
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Quaternion {
+    pub v: Vector3f,
+    pub w: f32,
+}
+
+impl Quaternion {
+    pub fn new(v: Vector3f, w: f32) -> Quaternion {
+        Quaternion { v, w }
+    }
+
+    /// The rotation of the upper 3x3 part of `t`, which must be a rotation matrix.
+    pub fn from_transform(t: &Transform) -> Quaternion {
+        let m = t.m.m;
+        let trace = m[0][0] + m[1][1] + m[2][2];
+        if trace > 0.0 {
+            // Compute w from the matrix trace, then the vector part
+            let s = (trace + 1.0).sqrt();
+            let w = s / 2.0;
+            let s = 0.5 / s;
+            Quaternion::new(
+                Vector3f::new(
+                    (m[2][1] - m[1][2]) * s,
+                    (m[0][2] - m[2][0]) * s,
+                    (m[1][0] - m[0][1]) * s,
+                ),
+                w,
+            )
+        } else {
+            // Compute the largest of x, y or z, then the remaining components
+            let next = [1, 2, 0];
+            let mut i = 0;
+            if m[1][1] > m[0][0] {
+                i = 1;
+            }
+            if m[2][2] > m[i][i] {
+                i = 2;
+            }
+            let j = next[i];
+            let k = next[j];
+            let mut s = ((m[i][i] - (m[j][j] + m[k][k])) + 1.0).sqrt();
+            let mut q = [0.0; 3];
+            q[i] = s * 0.5;
+            if s != 0.0 {
+                s = 0.5 / s;
+            }
+            let w = (m[k][j] - m[j][k]) * s;
+            q[j] = (m[j][i] + m[i][j]) * s;
+            q[k] = (m[k][i] + m[i][k]) * s;
+            Quaternion::new(Vector3f::new(q[0], q[1], q[2]), w)
+        }
+    }
+
+    /// The rotation represented by this quaternion, which must be normalized.
+    pub fn to_transform(&self) -> Transform {
+        let (x, y, z, w) = (self.v.x, self.v.y, self.v.z, self.w);
+        let (xx, yy, zz) = (x * x, y * y, z * z);
+        let (xy, xz, yz) = (x * y, x * z, y * z);
+        let (wx, wy, wz) = (x * w, y * w, z * w);
+        let m = Matrix4x4::from_elements(
+            1.0 - 2.0 * (yy + zz),
+            2.0 * (xy + wz),
+            2.0 * (xz - wy),
+            0.0,
+            2.0 * (xy - wz),
+            1.0 - 2.0 * (xx + zz),
+            2.0 * (yz + wx),
+            0.0,
+            2.0 * (xz + wy),
+            2.0 * (yz - wx),
+            1.0 - 2.0 * (xx + yy),
+            0.0,
+            0.0,
+            0.0,
+            0.0,
+            1.0,
+        );
+        // Transpose for the left-handed coordinate system
+        Transform {
+            m: m.transpose(),
+            m_inv: m,
+        }
+    }
+
+    pub fn dot(&self, q: &Quaternion) -> f32 {
+        self.v.dot(&q.v) + self.w * q.w
+    }
+
+    pub fn normalize(&self) -> Quaternion {
+        *self * (1.0 / self.dot(self).sqrt())
+    }
+
+    /// Angle between the two rotations, in radians.
+    pub fn angle(&self, q: &Quaternion) -> f32 {
+        2.0 * clamp(self.dot(q).abs(), 0.0, 1.0).acos()
+    }
+
+    /// Spherical linear interpolation between `q1` and `q2`, which rotates at a constant angular
+    /// velocity.
+    pub fn slerp(t: f32, q1: &Quaternion, q2: &Quaternion) -> Quaternion {
+        let cos_theta = q1.dot(q2);
+        if cos_theta > 0.9995 {
+            // The quaternions are so close that linear interpolation is accurate enough, and
+            // avoids dividing by a tiny sine
+            (*q1 * (1.0 - t) + *q2 * t).normalize()
+        } else {
+            let theta = clamp(cos_theta, -1.0, 1.0).acos();
+            let theta_p = theta * t;
+            let q_perp = (*q2 - *q1 * cos_theta).normalize();
+            *q1 * theta_p.cos() + q_perp * theta_p.sin()
+        }
+    }
+}
+
+impl Default for Quaternion {
+    fn default() -> Quaternion {
+        Quaternion::new(Vector3f::new(0.0, 0.0, 0.0), 1.0)
+    }
+}
+
+impl Add for Quaternion {
+    type Output = Quaternion;
+
+    fn add(self, q: Quaternion) -> Quaternion {
+        Quaternion::new(self.v + q.v, self.w + q.w)
+    }
+}
+
+impl Sub for Quaternion {
+    type Output = Quaternion;
+
+    fn sub(self, q: Quaternion) -> Quaternion {
+        Quaternion::new(self.v - q.v, self.w - q.w)
+    }
+}
+
+impl Mul<f32> for Quaternion {
+    type Output = Quaternion;
+
+    fn mul(self, f: f32) -> Quaternion {
+        Quaternion::new(self.v * f, self.w * f)
+    }
+}
+
+impl Neg for Quaternion {
+    type Output = Quaternion;
+
+    fn neg(self) -> Quaternion {
+        Quaternion::new(-&self.v, -self.w)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use approx::assert_relative_eq;
+
+    #[test]
+    fn test_round_trip() {
+        for t in &[
+            Transform::default(),
+            Transform::rot_x(30.0),
+            Transform::rotate(170.0, Vector3f::new(1.0, -2.0, 0.5)),
+            Transform::rot_z(-180.0),
+        ] {
+            let q = Quaternion::from_transform(t);
+            assert_relative_eq!(q.dot(&q), 1.0, epsilon = 1e-5);
+            let r = q.to_transform();
+            for i in 0..3 {
+                for j in 0..3 {
+                    assert_relative_eq!(r.m.m[i][j], t.m.m[i][j], epsilon = 1e-5);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_slerp() {
+        let q1 = Quaternion::from_transform(&Transform::rot_z(10.0));
+        let q2 = Quaternion::from_transform(&Transform::rot_z(100.0));
+        assert_relative_eq!(q1.angle(&q2), 90f32.to_radians(), epsilon = 1e-5);
+        let half = Quaternion::slerp(0.5, &q1, &q2).to_transform();
+        let expected = Transform::rot_z(55.0);
+        for i in 0..3 {
+            for j in 0..3 {
+                assert_relative_eq!(half.m.m[i][j], expected.m.m[i][j], epsilon = 1e-5);
+            }
+        }
+    }
+}
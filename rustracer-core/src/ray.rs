@@ -1,4 +1,3 @@
-use std::f32::INFINITY;
 use std::fmt;
 use std::ops::Mul;
 
@@ -6,12 +5,31 @@ use num::zero;
 
 use crate::{Point3f, Transform, Vector3f};
 
+/// Which back-facing surfaces a ray ignores. Only camera rays cull back faces, so that the
+/// lighting doesn't change: interior scenes modeled with enclosing shells can be seen from
+/// outside of the shells, as in the viewports of DCC applications.
+///
+/// Only triangles are culled, and their back faces are given by the winding order of their
+/// vertices (flipped by ReverseOrientation) as in a rasterizer, whatever their shading normals.
+#[derive(Debug, Copy, Clone, Default, PartialEq, Eq)]
+pub enum BackfaceCulling {
+    #[default]
+    None,
+    /// The back faces of the triangle meshes with `"bool backfacecull"` set
+    Flagged,
+    /// All back faces (`"bool backfacecull"` on the camera)
+    All,
+}
+
 #[derive(Debug, Copy, Clone)]
 pub struct Ray {
     pub o: Point3f,
     pub d: Vector3f,
     pub t_max: f32,
+    /// Time at which the ray was emitted (within the camera's shutter interval)
+    pub time: f32,
     pub differential: Option<RayDifferential>,
+    pub backface_culling: BackfaceCulling,
 }
 
 impl Ray {
@@ -22,8 +40,10 @@ impl Ray {
         Ray {
             o,
             d,
-            t_max: INFINITY,
+            t_max: f32::INFINITY,
+            time: 0.0,
             differential: None,
+            backface_culling: BackfaceCulling::None,
         }
     }
 
@@ -35,10 +55,18 @@ impl Ray {
             o,
             d,
             t_max: tmax,
+            time: 0.0,
             differential: None,
+            backface_culling: BackfaceCulling::None,
         }
     }
 
+    /// Return a copy of this ray emitted at the given time.
+    pub fn at_time(mut self, time: f32) -> Ray {
+        self.time = time;
+        self
+    }
+
     pub fn at(&self, t: f32) -> Point3f {
         self.o + t * self.d
     }
@@ -65,7 +93,9 @@ impl Ray {
             o,
             d,
             t_max,
+            time: self.time,
             differential: diff,
+            backface_culling: self.backface_culling,
         };
         (r, o_error, d_error)
     }
@@ -87,6 +117,12 @@ impl Mul<Ray> for Transform {
         let mut new_ray = rhs;
         new_ray.o = &self * &rhs.o;
         new_ray.d = &self * &rhs.d;
+        new_ray.differential = rhs.differential.map(|d| RayDifferential {
+            rx_origin: &self * &d.rx_origin,
+            ry_origin: &self * &d.ry_origin,
+            rx_direction: &self * &d.rx_direction,
+            ry_direction: &self * &d.ry_direction,
+        });
 
         new_ray
     }
@@ -94,7 +130,11 @@ impl Mul<Ray> for Transform {
 
 impl fmt::Display for Ray {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> Result<(), fmt::Error> {
-        write!(f, "[o={}, d={}, t_max={}]", self.o, self.d, self.t_max)
+        write!(
+            f,
+            "[o={}, d={}, t_max={}, time={}]",
+            self.o, self.d, self.t_max, self.time
+        )
     }
 }
 
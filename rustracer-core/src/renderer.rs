@@ -1,33 +1,362 @@
+use std::cell::Cell;
+use std::collections::HashMap;
+use std::path::Path;
+use std::sync::atomic::{AtomicU64, Ordering};
 use std::sync::Arc;
+use std::time::{Duration, Instant};
 
-use anyhow::Result;
+use anyhow::{bail, Result};
 use light_arena::MemoryArena;
 use log::{error, info};
 use parking_lot::Mutex;
 
 use crate::bounds::Bounds2i;
-use crate::camera::Camera;
+use crate::camera::{Camera, CameraSample};
+use crate::cancel;
+use crate::film::FilmTile;
+use crate::imageio::{self, ImageMetadata};
 use crate::integrator::SamplerIntegrator;
+use crate::lpe::LpeAccumulator;
+use crate::numa::{self, pin_worker_thread};
+use crate::sampledump::{Contribution, SampleRecord};
 use crate::sampler::Sampler;
 use crate::scene::Scene;
 use crate::spectrum::Spectrum;
 use crate::stats;
-use crate::Point2i;
+use crate::{Point2f, Point2i, Transform};
 
 stat_counter!("Integrator/Camera rays traced", n_camera_ray);
+// Camera rays per microsecond of rendering, i.e. millions of rays per second, for each of the
+// render paths
+stat_ratio!(
+    "Integrator/Camera Mrays per sec (dyn dispatch)",
+    n_mrays_dyn
+);
+stat_ratio!(
+    "Integrator/Camera Mrays per sec (static dispatch)",
+    n_mrays_static
+);
 pub fn init_stats() {
     n_camera_ray::init();
+    n_mrays_dyn::init();
+    n_mrays_static::init();
 }
 
+/// Everything needed to render a scene. This is kept around after `WorldEnd` in interactive mode
+/// so that the scene can be rendered again (e.g. from a different point of view) without having
+/// to reload it or rebuild its acceleration structures.
+pub struct RenderContext {
+    pub scene: Arc<Scene>,
+    pub camera: Box<dyn Camera>,
+    pub integrator: Box<dyn SamplerIntegrator>,
+    pub sampler: Box<dyn Sampler>,
+    pub threads: WorkerThreads,
+    pub write_heatmap: bool,
+    /// Render progressively until this much time has passed (see `render()`).
+    pub time_budget: Option<Duration>,
+}
+
+impl RenderContext {
+    /// Render the scene and write the resulting image. Returns the number of samples per pixel
+    /// that were rendered.
+    pub fn render(&mut self) -> Result<usize> {
+        render(
+            &self.scene,
+            &mut *self.integrator,
+            &*self.camera,
+            self.threads,
+            self.sampler.as_mut(),
+            16,
+            self.write_heatmap,
+            self.time_budget,
+        )
+    }
+
+    /// Render the scene into the film, without writing any image. The pixels can then be
+    /// retrieved with `Film::rgb()`.
+    pub fn render_in_memory(&mut self) {
+        self.camera.get_film().clear();
+        render_tiles(
+            &self.scene,
+            &mut *self.integrator,
+            &*self.camera,
+            self.threads,
+            self.sampler.as_mut(),
+            16,
+        );
+    }
+
+    /// Move the camera and render the scene again from scratch. Its previous position becomes
+    /// that of the previous frame, for the motion vectors.
+    pub fn rerender_from(&mut self, camera_to_world: Transform) -> Result<()> {
+        self.camera.end_frame();
+        self.camera.set_camera_to_world(camera_to_world);
+        self.camera.get_film().clear();
+        self.render()?;
+        Ok(())
+    }
+}
+
+/// Builder for rendering a scene built in code, when the concrete types of the integrator, camera
+/// and sampler are known. The render loop is then monomorphized for these types, rather than
+/// going through a vtable for every camera sample, camera ray and radiance estimate as for the
+/// scenes loaded by the parser (see `RenderContext`).
+///
+/// ```ignore
+/// let camera = RenderBuilder::new(scene, integrator, camera, sampler)
+///     .with_threads(WorkerThreads::new(4))
+///     .render()?;
+/// ```
+pub struct RenderBuilder<I, C, S> {
+    scene: Arc<Scene>,
+    integrator: I,
+    camera: C,
+    sampler: S,
+    threads: WorkerThreads,
+    block_size: i32,
+}
+
+impl<I, C, S> RenderBuilder<I, C, S>
+where
+    I: SamplerIntegrator,
+    C: Camera,
+    S: Sampler + Clone,
+{
+    /// Render with one thread per CPU, in tiles of 16x16 pixels.
+    pub fn new(scene: Arc<Scene>, integrator: I, camera: C, sampler: S) -> RenderBuilder<I, C, S> {
+        RenderBuilder {
+            scene,
+            integrator,
+            camera,
+            sampler,
+            threads: WorkerThreads::new(num_cpus::get()),
+            block_size: 16,
+        }
+    }
+
+    pub fn with_threads(mut self, threads: WorkerThreads) -> RenderBuilder<I, C, S> {
+        self.threads = threads;
+        self
+    }
+
+    pub fn with_block_size(mut self, block_size: i32) -> RenderBuilder<I, C, S> {
+        self.block_size = block_size;
+        self
+    }
+
+    /// Render the scene into the camera's film, without writing any image. Returns the camera,
+    /// whose film holds the rendered pixels (see `Film::rgb()`).
+    pub fn render_in_memory(mut self) -> C {
+        self.integrator
+            .preprocess(Arc::clone(&self.scene), &mut self.sampler);
+        let start = Instant::now();
+        let (_, n_rays) = render_tiles_with(
+            &self.scene,
+            &self.integrator,
+            &self.camera,
+            self.threads,
+            &self.sampler,
+            self.block_size,
+            0,
+        );
+        n_mrays_static::add(n_rays);
+        n_mrays_static::add_total(start.elapsed().as_micros() as u64);
+        self.camera
+    }
+
+    /// Render the scene and write the resulting image. Returns the camera, as
+    /// `render_in_memory()` does.
+    pub fn render(self) -> Result<C> {
+        let camera = self.render_in_memory();
+        if cancel::interrupted() {
+            bail!("Rendering interrupted");
+        }
+        camera.get_film().write_image()?;
+        Ok(camera)
+    }
+}
+
+/// Number and placement of the threads used to render.
+#[derive(Debug, Clone, Copy)]
+pub struct WorkerThreads {
+    pub count: usize,
+    /// Pin each thread to a NUMA node, with its working memory allocated locally (see
+    /// `numa::pin_worker_thread()`).
+    pub numa: bool,
+}
+
+impl WorkerThreads {
+    pub fn new(count: usize) -> WorkerThreads {
+        WorkerThreads { count, numa: false }
+    }
+}
+
+/// Wall-clock time spent rendering a tile.
+#[derive(Debug, Clone, Copy)]
+pub struct TileTime {
+    pub bounds: Bounds2i,
+    pub duration: Duration,
+}
+
+/// Render the scene and write the resulting image. If `write_heatmap` is true, the time spent on
+/// each tile is also written as a heatmap image next to the output image (see
+/// `heatmap_filename()`).
+///
+/// If there is a `time_budget`, the image is rendered progressively instead (see
+/// `render_progressive()`). Returns the number of samples per pixel that were rendered.
 pub fn render(
     scene: &Arc<Scene>,
     integrator: &mut dyn SamplerIntegrator,
     camera: &dyn Camera,
-    num_threads: usize,
+    threads: WorkerThreads,
+    sampler: &mut dyn Sampler,
+    block_size: i32,
+    write_heatmap: bool,
+    time_budget: Option<Duration>,
+) -> Result<usize> {
+    let (tile_times, spp) = match time_budget {
+        Some(budget) => render_progressive(
+            scene, integrator, camera, threads, sampler, block_size, budget,
+        ),
+        None => (
+            render_tiles(scene, integrator, camera, threads, sampler, block_size),
+            sampler.spp(),
+        ),
+    };
+    if cancel::interrupted() {
+        bail!("Rendering interrupted");
+    }
+
+    let film = camera.get_film();
+    film.write_image()?;
+    if write_heatmap {
+        let filename = heatmap_filename(&film.filename);
+        info!("Writing tile timing heatmap {}", filename);
+        let (rgb, resolution) = tile_heatmap(&film.get_sample_bounds(), &tile_times);
+        imageio::write_image(&filename, &rgb, resolution, &ImageMetadata::default())?;
+    }
+    Ok(spp)
+}
+
+/// Render the scene into the camera's film, and return the time spent on each tile.
+///
+/// This is the path used for scenes loaded by the parser, where the integrator, camera and
+/// sampler are only known as trait objects. See `RenderBuilder` for the monomorphized version.
+pub fn render_tiles(
+    scene: &Arc<Scene>,
+    integrator: &mut dyn SamplerIntegrator,
+    camera: &dyn Camera,
+    threads: WorkerThreads,
+    sampler: &mut dyn Sampler,
+    block_size: i32,
+) -> Vec<TileTime> {
+    integrator.preprocess(Arc::clone(scene), sampler);
+    let sampler: &dyn Sampler = sampler;
+    let start = Instant::now();
+    let (tile_times, n_rays) =
+        render_tiles_with(scene, &*integrator, camera, threads, sampler, block_size, 0);
+    n_mrays_dyn::add(n_rays);
+    n_mrays_dyn::add_total(start.elapsed().as_micros() as u64);
+    tile_times
+}
+
+/// Render the scene into the camera's film in successive passes of the sampler's number of
+/// samples per pixel, until `budget` is exhausted. The passes are accumulated in the film, whose
+/// pixels are normalized by their total filter weight when the image is written, so the image
+/// can be written after any number of passes.
+///
+/// A pass isn't started if it is unlikely to finish within the budget, given the average time
+/// of the previous ones; at least one pass is always rendered. Returns the time spent on each
+/// tile during the last pass, and the number of samples per pixel rendered.
+pub fn render_progressive(
+    scene: &Arc<Scene>,
+    integrator: &mut dyn SamplerIntegrator,
+    camera: &dyn Camera,
+    threads: WorkerThreads,
     sampler: &mut dyn Sampler,
     block_size: i32,
-) -> Result<()> {
+    budget: Duration,
+) -> (Vec<TileTime>, usize) {
     integrator.preprocess(Arc::clone(scene), sampler);
+    let sampler: &dyn Sampler = sampler;
+    let start = Instant::now();
+    let mut passes = 0;
+    let mut n_rays = 0;
+    let tile_times = loop {
+        let (pass_times, pass_rays) = render_tiles_with(
+            scene,
+            &*integrator,
+            camera,
+            threads,
+            sampler,
+            block_size,
+            passes,
+        );
+        passes += 1;
+        n_rays += pass_rays;
+        let elapsed = start.elapsed();
+        info!(
+            "Rendered pass {} ({} spp) in {:?}",
+            passes,
+            passes * sampler.spp(),
+            elapsed
+        );
+        if cancel::interrupted() || elapsed + elapsed / passes as u32 > budget {
+            break pass_times;
+        }
+    };
+    n_mrays_dyn::add(n_rays);
+    n_mrays_dyn::add_total(start.elapsed().as_micros() as u64);
+    (tile_times, passes * sampler.spp())
+}
+
+/// A sampler that each worker thread can get its own copy of, whether its type is statically
+/// known or not.
+trait ThreadSampler: Sampler {
+    fn clone_for_thread(&self) -> Box<Self>;
+
+    /// The integrators take the sampler as a trait object.
+    fn as_dyn(&mut self) -> &mut dyn Sampler;
+}
+
+impl<'a> ThreadSampler for dyn Sampler + 'a {
+    fn clone_for_thread(&self) -> Box<Self> {
+        self.box_clone()
+    }
+
+    fn as_dyn(&mut self) -> &mut dyn Sampler {
+        self
+    }
+}
+
+impl<S: Sampler + Clone> ThreadSampler for S {
+    fn clone_for_thread(&self) -> Box<S> {
+        Box::new(self.clone())
+    }
+
+    fn as_dyn(&mut self) -> &mut dyn Sampler {
+        self
+    }
+}
+
+/// Render loop shared by `render_tiles()`, `render_progressive()` and `RenderBuilder`, which is
+/// monomorphized for the latter. Each worker thread gets its own clone of `sampler`, seeded
+/// differently for each tile and each `pass`. Returns the time spent on each tile and the number
+/// of camera rays traced.
+fn render_tiles_with<I, C, S>(
+    scene: &Arc<Scene>,
+    integrator: &I,
+    camera: &C,
+    threads: WorkerThreads,
+    sampler: &S,
+    block_size: i32,
+    pass: usize,
+) -> (Vec<TileTime>, u64)
+where
+    I: SamplerIntegrator + ?Sized,
+    C: Camera + ?Sized,
+    S: ThreadSampler + ?Sized,
+{
     let sample_bounds = camera.get_film().get_sample_bounds();
     let sample_extent = sample_bounds.diagonal();
     let pixel_bounds = integrator.pixel_bounds();
@@ -41,7 +370,14 @@ pub fn render(
     );
 
     let num_blocks = n_tiles.x * n_tiles.y;
-    info!("Rendering scene using {} threads", num_threads);
+    info!("Rendering scene using {} threads", threads.count);
+    let numa_nodes = if threads.numa {
+        let nodes = numa::numa_nodes();
+        info!("Spreading threads over {} NUMA node(s)", nodes.len());
+        nodes
+    } else {
+        Vec::new()
+    };
     let image_bounds =
         Bounds2i::from_points(&Point2i::new(0, 0), &Point2i::new(n_tiles.x, n_tiles.y));
     let tiles_iter = Arc::new(Mutex::new(image_bounds.into_iter()));
@@ -52,19 +388,46 @@ pub fn render(
             .template("[{elapsed_precise}] [{wide_bar}] {percent}% [{pos}/{len}] {eta}"),
     );
     pb.tick();
+    let tile_times = Mutex::new(Vec::with_capacity(num_blocks as usize));
+    let n_rays = AtomicU64::new(0);
 
     crossbeam::scope(|scope| {
         // We only want to use references to these in the thread, not move the structs themselves...
-        let integrator = &integrator;
-        let camera = &camera;
         let pb = &pb;
+        let tile_times = &tile_times;
+        let n_rays = &n_rays;
+
+        let numa_nodes = &numa_nodes;
 
         // Spawn worker threads
-        for _ in 0..num_threads {
-            let mut sampler = sampler.box_clone();
+        for thread_index in 0..threads.count {
             let tiles_iter = Arc::clone(&tiles_iter);
             scope.spawn(move |_| {
+                // Pin the thread before allocating anything, so that its memory is local to its
+                // NUMA node
+                pin_worker_thread(thread_index, numa_nodes);
+                let mut sampler = sampler.clone_for_thread();
+                let mut aovs = LpeAccumulator::new(camera.get_film().aovs());
+                let geometry_aovs = camera.get_film().geometry_aovs();
+                let cryptomatte = camera.get_film().has_cryptomatte();
+                let sample_dump = camera.get_film().sample_dump();
+                // Index of each light in the scene, to identify them in the sample dump
+                let light_indices: HashMap<u32, u32> = if sample_dump.is_some() {
+                    (0..)
+                        .zip(&scene.lights)
+                        .map(|(i, light)| (light.id(), i))
+                        .collect()
+                } else {
+                    HashMap::new()
+                };
+                let mut records = Vec::new();
+                let mut aov_values = Vec::new();
+                let placement = camera.get_film().sample_placement();
+                let mut thread_rays = 0;
                 loop {
+                    if cancel::interrupted() {
+                        break;
+                    }
                     let maybe_tile = {
                         let mut iter = tiles_iter.lock();
                         iter.next()
@@ -75,12 +438,13 @@ pub fn render(
                         break;
                     };
                     // Render section of image corresponding to `tile`
+                    let tile_start = Instant::now();
 
                     // Allocate MemoryArena for tile
                     let mut arena = MemoryArena::new(1);
 
                     // Get sampler instance for tile
-                    let seed = tile.y * n_tiles.x + tile.x;
+                    let seed = pass * num_blocks as usize + (tile.y * n_tiles.x + tile.x) as usize;
                     sampler.reseed(seed as u64);
 
                     // Compute sample bounds for tile
@@ -93,51 +457,407 @@ pub fn render(
                     info!("Starting image tile {}", tile_bounds);
 
                     let mut film_tile = camera.get_film().get_film_tile(&tile_bounds);
-                    for p in &tile_bounds {
-                        sampler.start_pixel(p);
-
-                        // Do this check after the start_pixel() call; this keeps
-                        // the usage of RNG values from (most) Samplers that use
-                        // RNGs consistent, which improves reproducability /
-                        // debugging
-                        if !pixel_bounds.inside_exclusive(&p) {
-                            continue;
-                        }
+                    // Integrators may render the whole tile at once
+                    let spp = sampler.spp();
+                    let wavefront_rays = Cell::new(0);
+                    let camera_ray = |s: &CameraSample| {
+                        let mut ray = camera.generate_ray_differential(s);
+                        ray.scale_differentials(1.0 / (spp as f32).sqrt());
+                        n_camera_ray::inc();
+                        wavefront_rays.set(wavefront_rays.get() + 1);
+                        ray
+                    };
+                    let rendered = integrator.render_tile(
+                        scene,
+                        &tile_bounds,
+                        sampler.as_dyn(),
+                        &camera_ray,
+                        &mut film_tile,
+                        &mut arena,
+                    );
+                    thread_rays += wavefront_rays.get();
+                    if !rendered {
+                        for p in &tile_bounds {
+                            sampler.start_pixel(p);
 
-                        loop {
-                            let alloc = arena.allocator();
-                            let s = sampler.get_camera_sample(p);
-                            let mut ray = camera.generate_ray_differential(&s);
-                            ray.scale_differentials(1.0 / (sampler.spp() as f32).sqrt());
-                            n_camera_ray::inc();
-                            let mut sample_colour =
-                                integrator.li(scene, &mut ray, sampler.as_mut(), &alloc, 0);
-                            if sample_colour.has_nan() {
-                                error!("Not-a-number radiance value returned for pixel {}, sample {}. Setting to black.", p, sampler.current_sample_number());
-                                sample_colour = Spectrum::black();
+                            // Do this check after the start_pixel() call; this keeps
+                            // the usage of RNG values from (most) Samplers that use
+                            // RNGs consistent, which improves reproducability /
+                            // debugging
+                            if !pixel_bounds.inside_exclusive(&p) {
+                                continue;
                             }
-                            if sample_colour.y() < -1e-5 {
-                                error!("Negative luminance value, {}, returned for pixel {}, sample {}. Setting to black.", sample_colour.y(), p, sampler.current_sample_number());
-                                sample_colour = Spectrum::black();
-                            }
-                            if sample_colour.y().is_infinite() {
-                                error!("Infinite luminance value returned for pixel {}, sample {}. Setting to black.", p, sampler.current_sample_number());
-                                sample_colour = Spectrum::black();
-                            }
-                            film_tile.add_sample(s.p_film, sample_colour);
-                            if !sampler.start_next_sample() {
-                                break;
+                            let record_pixel = sample_dump.is_some_and(|d| d.contains(p));
+                            aovs.set_recording(record_pixel);
+
+                            loop {
+                                let alloc = arena.allocator();
+                                let mut s = sampler.get_camera_sample(p);
+                                let p_film = placement.place(p, &mut s);
+                                let mut ray = camera.generate_ray_differential(&s);
+                                ray.scale_differentials(1.0 / (sampler.spp() as f32).sqrt());
+                                n_camera_ray::inc();
+                                thread_rays += 1;
+                                // The geometric AOVs and the mattes are computed before the
+                                // integrator moves on from the primary hit
+                                let primary_hit = if geometry_aovs.is_empty() && !cryptomatte {
+                                    None
+                                } else {
+                                    scene.intersect(&mut ray.clone())
+                                };
+                                aovs.clear();
+                                let sample_colour = if aovs.is_empty() {
+                                    integrator.li(scene, &mut ray, sampler.as_dyn(), &alloc, 0)
+                                } else {
+                                    integrator.li_aovs(
+                                        scene,
+                                        &mut ray,
+                                        sampler.as_dyn(),
+                                        &alloc,
+                                        &mut aovs,
+                                    )
+                                };
+                                let sample_colour = match valid_radiance(
+                                    sample_colour,
+                                    p,
+                                    sampler.current_sample_number(),
+                                ) {
+                                    Some(l) => l,
+                                    None => {
+                                        aovs.clear();
+                                        Spectrum::black()
+                                    }
+                                };
+                                aov_values.clear();
+                                aov_values.extend_from_slice(aovs.values());
+                                aov_values.extend(geometry_aovs.iter().map(|aov| {
+                                    primary_hit
+                                        .as_ref()
+                                        .map_or_else(Spectrum::black, |si| aov.value(si, camera))
+                                }));
+                                film_tile.add_sample_aovs(p_film, sample_colour, &aov_values);
+                                film_tile.add_matte_sample(
+                                    p_film,
+                                    primary_hit.as_ref().map(|si| si.matte_ids),
+                                );
+                                if record_pixel {
+                                    records.push(SampleRecord {
+                                        pixel: p,
+                                        sample: (pass * sampler.spp()
+                                            + sampler.current_sample_number())
+                                            as u64,
+                                        p_film,
+                                        radiance: sample_colour,
+                                        path_length: aovs.path_length(),
+                                        contributions: aovs
+                                            .contributions()
+                                            .iter()
+                                            .map(|c| Contribution {
+                                                light: light_indices[&c.light],
+                                                ..*c
+                                            })
+                                            .collect(),
+                                    });
+                                }
+                                if !sampler.start_next_sample() {
+                                    break;
+                                }
                             }
                         }
                     }
-                    camera.get_film().merge_film_tile(&film_tile);
+                    camera.get_film().merge_tile(&film_tile);
+                    if let Some(dump) = sample_dump {
+                        if !records.is_empty() {
+                            dump.add(std::mem::take(&mut records));
+                        }
+                    }
+                    tile_times.lock().push(TileTime {
+                        bounds: tile_bounds,
+                        duration: tile_start.elapsed(),
+                    });
                     pb.inc(1);
                 }
+                n_rays.fetch_add(thread_rays, Ordering::Relaxed);
                 stats::report_stats();
             });
         }
-    }).unwrap();
+    })
+    .unwrap();
     pb.finish();
 
-    camera.get_film().write_image()
+    (tile_times.into_inner(), n_rays.into_inner())
+}
+
+/// Radiance of a camera sample as it should be added to the film: NaNs, negative and infinite
+/// luminances are reported and the sample is discarded (`None`), as a single one would ruin the
+/// pixel. `p` and `sample` identify the sample in the error messages.
+pub fn valid_radiance(l: Spectrum, p: Point2i, sample: usize) -> Option<Spectrum> {
+    if l.has_nan() {
+        error!(
+            "Not-a-number radiance value returned for pixel {}, sample {}. Setting to black.",
+            p, sample
+        );
+        None
+    } else if l.y() < -1e-5 {
+        error!(
+            "Negative luminance value, {}, returned for pixel {}, sample {}. Setting to black.",
+            l.y(),
+            p,
+            sample
+        );
+        None
+    } else if l.y().is_infinite() {
+        error!(
+            "Infinite luminance value returned for pixel {}, sample {}. Setting to black.",
+            p, sample
+        );
+        None
+    } else {
+        Some(l)
+    }
+}
+
+/// Add the radiance `l` of a camera sample of pixel `p` to the film tile at `p_film`, or black if
+/// it isn't valid (see `valid_radiance()`).
+pub fn add_camera_sample(
+    film_tile: &mut FilmTile,
+    p: Point2i,
+    sample: usize,
+    p_film: Point2f,
+    l: Spectrum,
+) {
+    let l = valid_radiance(l, p, sample).unwrap_or_else(Spectrum::black);
+    film_tile.add_sample(p_film, l);
+}
+
+/// Name of the tile timing heatmap image for the given output image: `<output>_time.png`.
+pub fn heatmap_filename(filename: &str) -> String {
+    let path = Path::new(filename);
+    let stem = path
+        .file_stem()
+        .map(|s| s.to_string_lossy().into_owned())
+        .unwrap_or_default();
+    path.with_file_name(format!("{}_time.png", stem))
+        .to_string_lossy()
+        .into_owned()
+}
+
+/// Build an RGB image covering `bounds` where each tile is coloured according to the time spent
+/// rendering it, normalized by the time of the slowest tile: black is instantaneous, then red,
+/// yellow and white for the slowest tile.
+pub fn tile_heatmap(bounds: &Bounds2i, times: &[TileTime]) -> (Vec<f32>, Point2i) {
+    let extent = bounds.diagonal();
+    let mut rgb = vec![0.0; 3 * extent.x as usize * extent.y as usize];
+    let max_time = times
+        .iter()
+        .map(|t| t.duration)
+        .max()
+        .unwrap_or_default()
+        .as_secs_f32();
+    if max_time > 0.0 {
+        for t in times {
+            let v = 3.0 * t.duration.as_secs_f32() / max_time;
+            let colour = [
+                v.min(1.0),
+                (v - 1.0).clamp(0.0, 1.0),
+                (v - 2.0).clamp(0.0, 1.0),
+            ];
+            for p in &Bounds2i::intersect(&t.bounds, bounds) {
+                let offset =
+                    3 * ((p.y - bounds.p_min.y) * extent.x + (p.x - bounds.p_min.x)) as usize;
+                rgb[offset..offset + 3].copy_from_slice(&colour);
+            }
+        }
+    }
+    (rgb, Point2i::new(extent.x, extent.y))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use super::*;
+    use crate::bounds::Bounds2f;
+    use crate::camera::{PerspectiveCamera, Shutter};
+    use crate::cryptomatte::MatteIds;
+    use crate::film::Film;
+    use crate::filter::BoxFilter;
+    use crate::integrator::PathIntegrator;
+    use crate::light::DiffuseAreaLight;
+    use crate::material::MatteMaterial;
+    use crate::paramset::{ParamSet, TextureParams};
+    use crate::primitive::{GeometricPrimitive, PrimitiveRef};
+    use crate::sampler::zerotwosequence::ZeroTwoSequence;
+    use crate::shapes::{ShapeRef, Sphere};
+    use crate::Vector3f;
+
+    fn camera() -> PerspectiveCamera {
+        let film = Film::new(
+            Point2i::new(16, 12),
+            Bounds2f::from_points(&Point2f::new(0.0, 0.0), &Point2f::new(1.0, 1.0)),
+            &BoxFilter::new(0.5, 0.5),
+            35.0,
+            "unused.png",
+            1.0,
+            f32::INFINITY,
+        );
+        PerspectiveCamera::new(
+            Transform::translate(&Vector3f::new(0.0, 0.0, -5.0)),
+            Bounds2f::from_points(&Point2f::new(-1.0, -0.75), &Point2f::new(1.0, 0.75)),
+            Shutter::new(0.0, 1.0),
+            0.0,
+            1e6,
+            40.0,
+            Box::new(film),
+        )
+    }
+
+    fn integrator(camera: &dyn Camera) -> PathIntegrator {
+        PathIntegrator::new(
+            camera.get_film().get_sample_bounds(),
+            2,
+            1.0,
+            "uniform".into(),
+        )
+    }
+
+    /// An emissive matte sphere in front of the camera
+    fn scene() -> Arc<Scene> {
+        let sphere: ShapeRef = Arc::new(Sphere::new(
+            Transform::default(),
+            1.0,
+            -1.0,
+            1.0,
+            360.0,
+            false,
+        ));
+        let light = Arc::new(DiffuseAreaLight::new(
+            Spectrum::grey(1.0),
+            Arc::clone(&sphere),
+            1,
+            false,
+        ));
+        let ps = ParamSet::default();
+        let material = MatteMaterial::create(&TextureParams::new(
+            &ps,
+            &ps,
+            &HashMap::new(),
+            &HashMap::new(),
+        ));
+        let prim: PrimitiveRef = Arc::new(GeometricPrimitive {
+            shape: sphere,
+            area_light: Some(light.clone()),
+            material: Some(material),
+            matte_ids: MatteIds::default(),
+        });
+        Arc::new(Scene::new(prim, vec![light]))
+    }
+
+    #[test]
+    fn test_progressive_render() {
+        crate::init_stats();
+        let scene = scene();
+        let mean = |rgb: &[f32]| rgb.iter().sum::<f32>() / rgb.len() as f32;
+
+        let single_camera = camera();
+        let mut single_integrator = integrator(&single_camera);
+        render_tiles(
+            &scene,
+            &mut single_integrator,
+            &single_camera,
+            WorkerThreads::new(2),
+            &mut ZeroTwoSequence::new(4, 4),
+            8,
+        );
+
+        let camera = camera();
+        let mut integrator = integrator(&camera);
+        let (tile_times, spp) = render_progressive(
+            &scene,
+            &mut integrator,
+            &camera,
+            WorkerThreads::new(2),
+            &mut ZeroTwoSequence::new(4, 4),
+            8,
+            Duration::from_millis(100),
+        );
+        // Whole passes of the sampler's samples per pixel
+        assert!(spp >= 4);
+        assert_eq!(spp % 4, 0);
+        assert_eq!(tile_times.len(), 4);
+        // The accumulated passes are normalized like a single one
+        let single = mean(&single_camera.get_film().rgb());
+        let progressive = mean(&camera.get_film().rgb());
+        assert!(single > 0.0);
+        assert!(
+            (progressive - single).abs() < 0.1 * single,
+            "{} vs {}",
+            progressive,
+            single
+        );
+    }
+
+    #[test]
+    fn test_static_and_dyn_render_paths_match() {
+        crate::init_stats();
+        let scene = scene();
+
+        // Single-threaded so that the film tiles are merged in the same order
+        let dyn_camera: Box<dyn Camera> = Box::new(camera());
+        let mut dyn_integrator: Box<dyn SamplerIntegrator> =
+            Box::new(integrator(dyn_camera.as_ref()));
+        let mut dyn_sampler: Box<dyn Sampler> = Box::new(ZeroTwoSequence::new(4, 4));
+        render_tiles(
+            &scene,
+            dyn_integrator.as_mut(),
+            dyn_camera.as_ref(),
+            WorkerThreads::new(1),
+            dyn_sampler.as_mut(),
+            8,
+        );
+
+        let camera = camera();
+        let integrator = integrator(&camera);
+        let static_camera =
+            RenderBuilder::new(scene, integrator, camera, ZeroTwoSequence::new(4, 4))
+                .with_threads(WorkerThreads::new(1))
+                .with_block_size(8)
+                .render_in_memory();
+
+        let dyn_rgb = dyn_camera.get_film().rgb();
+        assert!(dyn_rgb.iter().any(|v| *v > 0.0));
+        assert_eq!(dyn_rgb, static_camera.get_film().rgb());
+    }
+
+    #[test]
+    fn test_heatmap_filename() {
+        assert_eq!(heatmap_filename("image.png"), "image_time.png");
+        assert_eq!(
+            heatmap_filename("out/rt-scene.exr"),
+            "out/rt-scene_time.png"
+        );
+    }
+
+    #[test]
+    fn test_tile_heatmap() {
+        let bounds = Bounds2i::from_points(&Point2i::new(0, 0), &Point2i::new(4, 2));
+        let times = [
+            TileTime {
+                bounds: Bounds2i::from_points(&Point2i::new(0, 0), &Point2i::new(2, 2)),
+                duration: Duration::from_millis(10),
+            },
+            TileTime {
+                bounds: Bounds2i::from_points(&Point2i::new(2, 0), &Point2i::new(4, 2)),
+                duration: Duration::from_millis(40),
+            },
+        ];
+        let (rgb, res) = tile_heatmap(&bounds, &times);
+        assert_eq!(res, Point2i::new(4, 2));
+        assert_eq!(rgb.len(), 24);
+        // Slowest tile is white
+        assert_eq!(&rgb[9..12], &[1.0, 1.0, 1.0]);
+        // A tile 4 times faster is dark red
+        assert!((rgb[0] - 0.75).abs() < 1e-5);
+        assert_eq!(&rgb[1..3], &[0.0, 0.0]);
+    }
 }
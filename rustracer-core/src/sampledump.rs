@@ -0,0 +1,240 @@
+//! Export of every camera sample of selected pixels, to analyze where their variance comes from
+//! offline (see `PbrtOptions::dump_samples`).
+//!
+//! For each sample, the dump records its radiance, the length of its path, and the contributions
+//! of the lights to its radiance: the light (by index in the scene's lights), the length of the
+//! path it came along, the strategy that found it (emission seen directly or through specular
+//! bounces, light sampling or BSDF sampling for direct lighting) and its MIS weight. Only the
+//! "path" integrator reports the path lengths and contributions; the other integrators only give
+//! the radiance.
+//!
+//! The samples are written at the end of the render, ordered by pixel and sample number, to a
+//! CSV file (one row per contribution, or a single row for samples without any) or a JSON file
+//! (one object per sample) depending on the file's extension.
+
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::path::PathBuf;
+
+use anyhow::{bail, Context, Result};
+use log::info;
+use parking_lot::Mutex;
+
+use crate::bounds::Bounds2i;
+use crate::fileutil::{self, has_extension};
+use crate::spectrum::Spectrum;
+use crate::{Point2f, Point2i};
+
+/// Which pixels to record and where to write their samples.
+#[derive(Debug, Clone)]
+pub struct SampleDumpOptions {
+    pub pixels: Bounds2i,
+    /// A `.csv` or `.json` file
+    pub path: PathBuf,
+}
+
+/// How a light's contribution to a sample was found.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Strategy {
+    /// Emission hit by the camera ray or after specular bounces
+    Emission,
+    /// Direct lighting, by sampling the light
+    LightSample,
+    /// Direct lighting, by sampling the BSDF
+    BsdfSample,
+}
+
+impl Strategy {
+    pub fn name(self) -> &'static str {
+        match self {
+            Strategy::Emission => "emission",
+            Strategy::LightSample => "light",
+            Strategy::BsdfSample => "bsdf",
+        }
+    }
+}
+
+/// Contribution of a light to the radiance of a sample.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Contribution {
+    /// Number of scattering events between the camera and the light
+    pub length: u32,
+    /// The light, as its `Light::id()` while rendering and then as its index in the scene's
+    /// lights once recorded
+    pub light: u32,
+    pub strategy: Strategy,
+    pub mis_weight: f32,
+    /// Contribution to the sample's radiance, with the path throughput and MIS weight applied
+    pub value: Spectrum,
+}
+
+/// A camera sample of one of the recorded pixels.
+#[derive(Debug, Clone, PartialEq)]
+pub struct SampleRecord {
+    pub pixel: Point2i,
+    /// Index of the sample in the pixel, counting the samples of the previous passes of a
+    /// progressive render
+    pub sample: u64,
+    /// Position of the sample on the film
+    pub p_film: Point2f,
+    pub radiance: Spectrum,
+    /// Number of scattering events along the path
+    pub path_length: u32,
+    pub contributions: Vec<Contribution>,
+}
+
+/// The samples of the recorded pixels, collected from the render threads.
+#[derive(Debug)]
+pub struct SampleDump {
+    pixels: Bounds2i,
+    path: PathBuf,
+    records: Mutex<Vec<SampleRecord>>,
+}
+
+impl SampleDump {
+    /// Fails if the file's format isn't supported, so that it isn't only found out after
+    /// rendering.
+    pub fn new(options: &SampleDumpOptions) -> Result<SampleDump> {
+        let path = &options.path;
+        if !has_extension(path, "csv") && !has_extension(path, "json") {
+            bail!(
+                "Unsupported sample dump format for {}: expected a .csv or .json file",
+                path.display()
+            );
+        }
+        fileutil::create_parent_directory(path)?;
+        Ok(SampleDump {
+            pixels: options.pixels,
+            path: path.clone(),
+            records: Mutex::new(Vec::new()),
+        })
+    }
+
+    /// Whether the samples of the given pixel are recorded.
+    pub fn contains(&self, p: Point2i) -> bool {
+        self.pixels.inside_exclusive(&p)
+    }
+
+    pub fn add(&self, mut records: Vec<SampleRecord>) {
+        self.records.lock().append(&mut records);
+    }
+
+    pub fn clear(&self) {
+        self.records.lock().clear();
+    }
+
+    /// The recorded samples, ordered by pixel (in scanline order) and sample number.
+    pub fn records(&self) -> Vec<SampleRecord> {
+        let mut records = self.records.lock().clone();
+        records.sort_by_key(|r| (r.pixel.y, r.pixel.x, r.sample));
+        records
+    }
+
+    /// Write the samples to a CSV or JSON file.
+    pub fn write(&self) -> Result<()> {
+        let path = &self.path;
+        let records = self.records();
+        info!("Writing {} samples to {}", records.len(), path.display());
+        let file = File::create(path).context(format!("Failed to create {}", path.display()))?;
+        let mut writer = BufWriter::new(file);
+        let result = if has_extension(path, "csv") {
+            write_csv(&mut writer, &records)
+        } else {
+            write_json(&mut writer, &records)
+        };
+        result
+            .and_then(|_| writer.flush())
+            .context(format!("Failed to write {}", path.display()))
+    }
+}
+
+fn write_csv<W: Write>(w: &mut W, records: &[SampleRecord]) -> std::io::Result<()> {
+    writeln!(
+        w,
+        "x,y,sample,film_x,film_y,r,g,b,path_length,length,light,strategy,mis_weight,contribution_r,contribution_g,contribution_b"
+    )?;
+    for r in records {
+        let sample = format!(
+            "{},{},{},{},{},{},{},{},{}",
+            r.pixel.x,
+            r.pixel.y,
+            r.sample,
+            r.p_film.x,
+            r.p_film.y,
+            r.radiance.r,
+            r.radiance.g,
+            r.radiance.b,
+            r.path_length
+        );
+        if r.contributions.is_empty() {
+            writeln!(w, "{},,,,,,,", sample)?;
+        }
+        for c in &r.contributions {
+            writeln!(
+                w,
+                "{},{},{},{},{},{},{},{}",
+                sample,
+                c.length,
+                c.light,
+                c.strategy.name(),
+                c.mis_weight,
+                c.value.r,
+                c.value.g,
+                c.value.b
+            )?;
+        }
+    }
+    Ok(())
+}
+
+fn write_json<W: Write>(w: &mut W, records: &[SampleRecord]) -> std::io::Result<()> {
+    writeln!(w, "[")?;
+    for (i, r) in records.iter().enumerate() {
+        let contributions: Vec<String> = r
+            .contributions
+            .iter()
+            .map(|c| {
+                format!(
+                    "{{\"length\": {}, \"light\": {}, \"strategy\": \"{}\", \"mis_weight\": {}, \"value\": {}}}",
+                    c.length,
+                    c.light,
+                    c.strategy.name(),
+                    json_float(c.mis_weight),
+                    json_rgb(&c.value)
+                )
+            })
+            .collect();
+        writeln!(
+            w,
+            "  {{\"pixel\": [{}, {}], \"sample\": {}, \"film\": [{}, {}], \"radiance\": {}, \"path_length\": {}, \"contributions\": [{}]}}{}",
+            r.pixel.x,
+            r.pixel.y,
+            r.sample,
+            json_float(r.p_film.x),
+            json_float(r.p_film.y),
+            json_rgb(&r.radiance),
+            r.path_length,
+            contributions.join(", "),
+            if i + 1 < records.len() { "," } else { "" }
+        )?;
+    }
+    writeln!(w, "]")
+}
+
+/// JSON has no representation for infinities and NaNs, so they are written as `null`.
+fn json_float(v: f32) -> String {
+    if v.is_finite() {
+        v.to_string()
+    } else {
+        "null".to_owned()
+    }
+}
+
+fn json_rgb(c: &Spectrum) -> String {
+    format!(
+        "[{}, {}, {}]",
+        json_float(c.r),
+        json_float(c.g),
+        json_float(c.b)
+    )
+}
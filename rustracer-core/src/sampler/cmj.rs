@@ -0,0 +1,152 @@
+//! Correlated multi-jittered sampling ("Correlated Multi-Jittered Sampling", Kensler 2013).
+//!
+//! A pattern of `N` points is laid out on an `m x n` grid (with `m * n >= N`) so that each point
+//! is in its own cell, and both its 1D projections are stratified into `N` strata. Unlike (0, 2)
+//! sequences, this works for any number of samples, and patterns are cheap to compute
+//! independently of each other from a pattern index.
+
+use crate::rng::RNG;
+use crate::{Point2f, ONE_MINUS_EPSILON};
+
+/// Fill `samples` with a correlated multi-jittered pattern. Different values of `pattern` give
+/// independent patterns.
+pub fn cmj_2d(samples: &mut [Point2f], pattern: u32) {
+    let count = samples.len() as u32;
+    for (s, sample) in samples.iter_mut().enumerate() {
+        *sample = cmj_sample(s as u32, count, pattern);
+    }
+}
+
+/// Fill `samples` with stratified 1D samples (one per stratum, in random order).
+pub fn stratified_1d(samples: &mut [f32], rng: &mut RNG) {
+    let count = samples.len() as u32;
+    let inv_count = 1.0 / count as f32;
+    let pattern = rng.uniform_u32();
+    for (s, sample) in samples.iter_mut().enumerate() {
+        let stratum = permute(s as u32, count, pattern);
+        *sample = ((stratum as f32 + rng.uniform_f32()) * inv_count).min(ONE_MINUS_EPSILON);
+    }
+}
+
+/// The `s`-th of `count` samples of the given pattern.
+pub fn cmj_sample(s: u32, count: u32, pattern: u32) -> Point2f {
+    assert!(s < count);
+    let m = (count as f32).sqrt() as u32;
+    let n = count.div_ceil(m);
+    let s = permute(s, count, pattern.wrapping_mul(0x5163_3e2d));
+    let sx = permute(s % m, m, pattern.wrapping_mul(0x68bc_21eb));
+    let sy = permute(s / m, n, pattern.wrapping_mul(0x02e5_be93));
+    let jx = rand_float(s, pattern.wrapping_mul(0x967a_889b));
+    let jy = rand_float(s, pattern.wrapping_mul(0x368c_c8b7));
+    Point2f::new(
+        ((sx as f32 + (sy as f32 + jx) / n as f32) / m as f32).min(ONE_MINUS_EPSILON),
+        ((s as f32 + jy) / count as f32).min(ONE_MINUS_EPSILON),
+    )
+}
+
+/// A pseudo-random permutation of `[0, l)`, selected by `p`, applied to `i`.
+fn permute(mut i: u32, l: u32, p: u32) -> u32 {
+    let mut w = l - 1;
+    w |= w >> 1;
+    w |= w >> 2;
+    w |= w >> 4;
+    w |= w >> 8;
+    w |= w >> 16;
+    loop {
+        i ^= p;
+        i = i.wrapping_mul(0xe170_893d);
+        i ^= p >> 16;
+        i ^= (i & w) >> 4;
+        i ^= p >> 8;
+        i = i.wrapping_mul(0x0929_eb3f);
+        i ^= p >> 23;
+        i ^= (i & w) >> 1;
+        i = i.wrapping_mul(1 | p >> 27);
+        i = i.wrapping_mul(0x6935_fa69);
+        i ^= (i & w) >> 11;
+        i = i.wrapping_mul(0x74dc_b303);
+        i ^= (i & w) >> 2;
+        i = i.wrapping_mul(0x9e50_1cc3);
+        i ^= (i & w) >> 2;
+        i = i.wrapping_mul(0xc860_a3df);
+        i &= w;
+        i ^= i >> 5;
+        if i < l {
+            break;
+        }
+    }
+    (i.wrapping_add(p)) % l
+}
+
+/// A pseudo-random value in `[0, 1)` for `i`, selected by `p`.
+fn rand_float(mut i: u32, p: u32) -> f32 {
+    i ^= p;
+    i ^= i >> 17;
+    i ^= i >> 10;
+    i = i.wrapping_mul(0xb365_34e5);
+    i ^= i >> 12;
+    i ^= i >> 21;
+    i = i.wrapping_mul(0x93fc_4795);
+    i ^= 0xdf6e_307f;
+    i ^= i >> 17;
+    i = i.wrapping_mul(1 | p >> 18);
+    (i as f32 * (1.0 / 4_294_967_808.0)).min(ONE_MINUS_EPSILON)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutil::{area_light_scene, mean, render_scene, threads};
+
+    #[test]
+    fn test_cmj_is_stratified() {
+        for &count in &[1, 2, 5, 9, 16, 30] {
+            for pattern in 0..8 {
+                let mut samples = vec![Point2f::new(0.0, 0.0); count];
+                cmj_2d(&mut samples, pattern);
+
+                // Each of the 1D projections has one sample per stratum
+                let mut strata_x = vec![0; count];
+                let mut strata_y = vec![0; count];
+                for p in &samples {
+                    assert!((0.0..1.0).contains(&p.x) && (0.0..1.0).contains(&p.y));
+                    strata_x[(p.x * count as f32) as usize] += 1;
+                    strata_y[(p.y * count as f32) as usize] += 1;
+                }
+                assert!(strata_y.iter().all(|&c| c == 1), "{:?}", strata_y);
+                // The x projection is only fully stratified for complete grids
+                let m = (count as f32).sqrt() as usize;
+                if m * m == count {
+                    assert!(strata_x.iter().all(|&c| c == 1), "{:?}", strata_x);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_stratified_1d() {
+        let mut rng = RNG::new();
+        let mut samples = vec![0.0; 7];
+        stratified_1d(&mut samples, &mut rng);
+        let mut strata: Vec<_> = samples.iter().map(|v| (v * 7.0) as usize).collect();
+        strata.sort_unstable();
+        assert_eq!(strata, (0..7).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn test_cmj_light_samples_converge_to_the_same_image() {
+        let render =
+            |sampler_params| render_scene(&area_light_scene(sampler_params, ""), threads(2));
+        let zerotwo = render(r#""string arraypattern" "02sequence""#);
+        let cmj = render(r#""string arraypattern" "cmj""#);
+        let (zerotwo_mean, cmj_mean) = (mean(&zerotwo), mean(&cmj));
+        assert!(cmj.iter().all(|s| !s.has_nan() && !s.is_infinite()));
+        assert!(zerotwo_mean > 0.0);
+        assert!(
+            (zerotwo_mean - cmj_mean).abs() < 0.02 * zerotwo_mean,
+            "02sequence: {}, cmj: {}",
+            zerotwo_mean,
+            cmj_mean
+        );
+    }
+}
@@ -1,6 +1,26 @@
+//! Low-discrepancy sequence primitives.
+//!
+//! These are the building blocks used by the quasi-random samplers: radical inverses (the basis
+//! of the Halton and Hammersley sequences) with optional digit permutations, Owen scrambling, and
+//! the generator-matrix based van der Corput and Sobol' (0, 2)-sequences. They don't depend on
+//! any sampler state so they can be reused by other samplers or tools.
+//!
+//! All the functions returning sample values return values in `[0, 1)`.
+
 use crate::rng::RNG;
 use crate::{Point2f, Point2i, ONE_MINUS_EPSILON};
 
+/// The first prime numbers, used as bases for `radical_inverse()`.
+pub const PRIMES: [u32; 64] = [
+    2, 3, 5, 7, 11, 13, 17, 19, 23, 29, 31, 37, 41, 43, 47, 53, 59, 61, 67, 71, 73, 79, 83, 89, 97,
+    101, 103, 107, 109, 113, 127, 131, 137, 139, 149, 151, 157, 163, 167, 173, 179, 181, 191, 193,
+    197, 199, 211, 223, 227, 229, 233, 239, 241, 251, 257, 263, 269, 271, 277, 281, 283, 293, 307,
+    311,
+];
+
+/// Generate `n_pixel_samples` sets of `n_samples_per_pixel_sample` 1D samples using a randomly
+/// scrambled van der Corput sequence, shuffling the samples within each set and the sets
+/// themselves.
 pub fn van_der_corput(
     n_samples_per_pixel_sample: u32,
     n_pixel_samples: u32,
@@ -22,6 +42,7 @@ pub fn van_der_corput(
     shuffle(samples, n_pixel_samples, n_samples_per_pixel_sample, rng);
 }
 
+/// 2D equivalent of `van_der_corput()`, using the first two dimensions of the Sobol' sequence.
 pub fn sobol_2d(
     n_samples_per_pixel_sample: u32,
     n_pixel_samples: u32,
@@ -49,21 +70,128 @@ pub fn sobol_2d(
     shuffle(samples, n_pixel_samples, n_samples_per_pixel_sample, rng);
 }
 
-pub fn radical_inverse(base: u32, a: u64) -> f32 {
-    match base {
+/// Return the `a`-th value of the van der Corput sequence scrambled with `scramble`.
+pub fn van_der_corput_sample(a: u32, scramble: u32) -> f32 {
+    sample_generator_matrix(&CVAN_DER_CORPUT, a, scramble)
+}
+
+/// Return the `a`-th point of the (0, 2)-sequence made of the first two dimensions of the Sobol'
+/// sequence, each dimension being scrambled with the corresponding component of `scramble`.
+pub fn sobol_2d_sample(a: u32, scramble: Point2i) -> Point2f {
+    Point2f::new(
+        sample_generator_matrix(&CSOBOL[0], a, scramble.x as u32),
+        sample_generator_matrix(&CSOBOL[1], a, scramble.y as u32),
+    )
+}
+
+/// Multiply the generator matrix `c` by the bits of `a`.
+pub fn multiply_generator(c: &[u32], a: u32) -> u32 {
+    let mut a = a;
+    let mut v = 0;
+    let mut i = 0;
+    while a != 0 {
+        if a & 1 != 0 {
+            v ^= c[i];
+        }
+        a >>= 1;
+        i += 1;
+    }
+    v
+}
+
+/// Return the `a`-th sample of the sequence defined by the generator matrix `c`, scrambled by
+/// XOR-ing the result with `scramble`.
+pub fn sample_generator_matrix(c: &[u32], a: u32, scramble: u32) -> f32 {
+    to_unit_float(multiply_generator(c, a) ^ scramble)
+}
+
+/// Compute the radical inverse of `a` in the base given by the `base_index`-th prime number
+/// (i.e. 0 is base 2, 1 is base 3, etc).
+///
+/// # Panics
+///
+/// Panics if `base_index` is not smaller than `PRIMES.len()`.
+pub fn radical_inverse(base_index: u32, a: u64) -> f32 {
+    match base_index {
         0 => reverse_bits_64(a) as f32 * 5.4210108624275222e-20,
-        1 => radical_inverse_specialized(3, a),
-        2 => radical_inverse_specialized(5, a),
-        3 => radical_inverse_specialized(7, a),
-        4 => radical_inverse_specialized(11, a),
-        5 => radical_inverse_specialized(13, a),
-        _ => unimplemented!(),
+        _ => radical_inverse_specialized(PRIMES[base_index as usize], a),
+    }
+}
+
+/// Like `radical_inverse()`, but with the digits permuted according to `perm` (which must be a
+/// permutation of the digits of the base, see `radical_inverse_permutation()`). This includes the
+/// infinite suffix of permuted zero digits.
+///
+/// # Panics
+///
+/// Panics if `base_index` is not smaller than `PRIMES.len()`.
+pub fn scrambled_radical_inverse(base_index: u32, a: u64, perm: &[u16]) -> f32 {
+    let base = PRIMES[base_index as usize];
+    assert!(perm.len() >= base as usize);
+    let mut a = a;
+    let inv_base: f32 = 1.0 / base as f32;
+    let mut reversed_digits: u64 = 0;
+    let mut inv_base_n = 1.0;
+    while a != 0 {
+        let next = a / u64::from(base);
+        let digit = a - next * u64::from(base);
+        reversed_digits = reversed_digits * u64::from(base) + u64::from(perm[digit as usize]);
+        inv_base_n *= inv_base;
+        a = next;
+    }
+    f32::min(
+        inv_base_n * (reversed_digits as f32 + inv_base * f32::from(perm[0]) / (1.0 - inv_base)),
+        ONE_MINUS_EPSILON,
+    )
+}
+
+/// Compute random digit permutations for all the bases in `PRIMES`, stored one after the other.
+/// Use `radical_inverse_permutation()` to retrieve the permutation for a given base.
+pub fn compute_radical_inverse_permutations(rng: &mut RNG) -> Vec<u16> {
+    let mut perms = Vec::with_capacity(PRIMES.iter().sum::<u32>() as usize);
+    for &base in &PRIMES {
+        let start = perms.len();
+        perms.extend(0..base as u16);
+        shuffle(&mut perms[start..], base, 1, rng);
     }
+    perms
 }
 
-fn reverse_bits_32(n: u32) -> u32 {
+/// Return the digit permutation for the `base_index`-th prime from permutations computed by
+/// `compute_radical_inverse_permutations()`.
+pub fn radical_inverse_permutation(perms: &[u16], base_index: u32) -> &[u16] {
+    let offset = PRIMES[..base_index as usize].iter().sum::<u32>() as usize;
+    &perms[offset..offset + PRIMES[base_index as usize] as usize]
+}
+
+/// Apply Owen scrambling to the bits of `v` (interpreted as a fixed-point number in `[0, 1)`).
+///
+/// Each bit is flipped depending on a hash of `seed` and all the preceding (more significant)
+/// bits, which is equivalent to randomly permuting the elementary intervals at every level while
+/// preserving the stratification properties of the original sequence.
+pub fn owen_scramble(v: u32, seed: u32) -> u32 {
+    let mut v = v;
+    if seed & 1 != 0 {
+        v ^= 1 << 31;
+    }
+    for b in 1..32 {
+        // Flip the b-th most significant bit depending on the bits above it
+        let mask = !0u32 << (32 - b);
+        if (mix_bits(u64::from((v & mask) ^ seed)) as u32) & (1 << b) != 0 {
+            v ^= 1 << (31 - b);
+        }
+    }
+    v
+}
+
+/// Convert a 0.32 fixed-point number to a float in `[0, 1)`.
+pub fn to_unit_float(v: u32) -> f32 {
+    (v as f32 * 2.3283064365386963e-10f32).min(ONE_MINUS_EPSILON)
+}
+
+pub fn reverse_bits_32(n: u32) -> u32 {
     let mut n = n;
-    n = (n << 16) | (n >> 16);
+    n = n.rotate_right(16);
     n = ((n & 0x00ff00ff) << 8) | ((n & 0xff00ff00) >> 8);
     n = ((n & 0x0f0f0f0f) << 4) | ((n & 0xf0f0f0f0) >> 4);
     n = ((n & 0x33333333) << 2) | ((n & 0xcccccccc) >> 2);
@@ -71,7 +199,7 @@ fn reverse_bits_32(n: u32) -> u32 {
     n
 }
 
-fn reverse_bits_64(n: u64) -> u64 {
+pub fn reverse_bits_64(n: u64) -> u64 {
     let n0 = reverse_bits_32(n as u32);
     let n1 = reverse_bits_32((n >> 32) as u32);
     (u64::from(n0) << 32) | u64::from(n1)
@@ -93,25 +221,29 @@ fn radical_inverse_specialized(base: u32, a: u64) -> f32 {
     f32::min(reversed_digits as f32 * inv_base_n, ONE_MINUS_EPSILON)
 }
 
-fn gray_code_sample(c: &[u32], n: u32, scramble: u32, p: &mut [f32]) {
+/// Generate the first `n` samples of the sequence defined by the generator matrix `c` in Gray
+/// code order, which only requires a single XOR per sample.
+pub fn gray_code_sample(c: &[u32], n: u32, scramble: u32, p: &mut [f32]) {
     let mut v = scramble;
     for i in 0..n {
-        p[i as usize] = (v as f32 * 2.3283064365386963e-10f32).min(ONE_MINUS_EPSILON);
+        p[i as usize] = to_unit_float(v);
         v ^= c[(i + 1).trailing_zeros() as usize];
     }
 }
 
-fn gray_code_sample_2d(c0: &[u32], c1: &[u32], n: u32, scramble: Point2i, p: &mut [Point2f]) {
+/// 2D equivalent of `gray_code_sample()`.
+pub fn gray_code_sample_2d(c0: &[u32], c1: &[u32], n: u32, scramble: Point2i, p: &mut [Point2f]) {
     let mut v = [scramble.x as u32, scramble.y as u32];
     for i in 0..n {
-        p[i as usize].x = (v[0] as f32 * 2.3283064365386963e-10f32).min(ONE_MINUS_EPSILON);
-        p[i as usize].y = (v[1] as f32 * 2.3283064365386963e-10f32).min(ONE_MINUS_EPSILON);
+        p[i as usize].x = to_unit_float(v[0]);
+        p[i as usize].y = to_unit_float(v[1]);
         v[0] ^= c0[(i + 1).trailing_zeros() as usize];
         v[1] ^= c1[(i + 1).trailing_zeros() as usize];
     }
 }
 
-fn shuffle<T>(samp: &mut [T], count: u32, n_dimensions: u32, rng: &mut RNG) {
+/// Randomly shuffle `count` blocks of `n_dimensions` consecutive values.
+pub fn shuffle<T>(samp: &mut [T], count: u32, n_dimensions: u32, rng: &mut RNG) {
     for i in 0..count {
         let other: u32 = i + rng.uniform_u32_bounded(count - i);
         for j in 0..n_dimensions {
@@ -123,7 +255,18 @@ fn shuffle<T>(samp: &mut [T], count: u32, n_dimensions: u32, rng: &mut RNG) {
     }
 }
 
-const CVAN_DER_CORPUT: [u32; 32] = [
+fn mix_bits(v: u64) -> u64 {
+    let mut v = v;
+    v ^= v >> 31;
+    v = v.wrapping_mul(0x7fb5d329728ea185);
+    v ^= v >> 27;
+    v = v.wrapping_mul(0x81dadef4bc2dd44d);
+    v ^= v >> 33;
+    v
+}
+
+/// Generator matrix for the van der Corput sequence (i.e. the identity matrix).
+pub const CVAN_DER_CORPUT: [u32; 32] = [
     0b10000000000000000000000000000000,
     0b1000000000000000000000000000000,
     0b100000000000000000000000000000,
@@ -157,8 +300,8 @@ const CVAN_DER_CORPUT: [u32; 32] = [
     0b10,
     0b1,
 ];
-/// Generator matrices for Sobol 2D
-const CSOBOL: [[u32; 32]; 2] = [
+/// Generator matrices for the first two dimensions of the Sobol' sequence.
+pub const CSOBOL: [[u32; 32]; 2] = [
     [
         0x80000000, 0x40000000, 0x20000000, 0x10000000, 0x8000000, 0x4000000, 0x2000000, 0x1000000,
         0x800000, 0x400000, 0x200000, 0x100000, 0x80000, 0x40000, 0x20000, 0x10000, 0x8000, 0x4000,
@@ -172,3 +315,94 @@ const CSOBOL: [[u32; 32]; 2] = [
         0x88888888, 0xcccccccc, 0xaaaaaaaa, 0xffffffff,
     ],
 ];
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_radical_inverse() {
+        // Base 2
+        assert_eq!(radical_inverse(0, 0), 0.0);
+        assert_eq!(radical_inverse(0, 1), 0.5);
+        assert_eq!(radical_inverse(0, 2), 0.25);
+        assert_eq!(radical_inverse(0, 3), 0.75);
+        assert_eq!(radical_inverse(0, 6), 0.375);
+        // Base 3
+        assert!((radical_inverse(1, 1) - 1.0 / 3.0).abs() < 1e-6);
+        assert!((radical_inverse(1, 2) - 2.0 / 3.0).abs() < 1e-6);
+        assert!((radical_inverse(1, 3) - 1.0 / 9.0).abs() < 1e-6);
+        assert!((radical_inverse(1, 5) - 7.0 / 9.0).abs() < 1e-6);
+        // Base 5 and 311
+        assert!((radical_inverse(2, 7) - 0.44).abs() < 1e-6);
+        assert!((radical_inverse(63, 1) - 1.0 / 311.0).abs() < 1e-6);
+    }
+
+    #[test]
+    fn test_scrambled_radical_inverse() {
+        // The identity permutation leaves the radical inverse unchanged
+        let identity: Vec<u16> = (0..5).collect();
+        for a in 0..100 {
+            let expected = radical_inverse(2, a);
+            assert!((scrambled_radical_inverse(2, a, &identity) - expected).abs() < 1e-6);
+        }
+
+        // A random permutation still generates a stratified sequence
+        let mut rng = RNG::new();
+        let perms = compute_radical_inverse_permutations(&mut rng);
+        let perm = radical_inverse_permutation(&perms, 1);
+        let mut sorted = perm.to_vec();
+        sorted.sort_unstable();
+        assert_eq!(sorted, vec![0, 1, 2]);
+        let mut strata = [false; 9];
+        for a in 0..9 {
+            let v = scrambled_radical_inverse(1, a, perm);
+            assert!((0.0..1.0).contains(&v));
+            strata[(v * 9.0) as usize] = true;
+        }
+        assert!(strata.iter().all(|s| *s));
+    }
+
+    #[test]
+    fn test_generator_matrices() {
+        for a in 0..256 {
+            assert_eq!(
+                van_der_corput_sample(a, 0),
+                radical_inverse(0, u64::from(a))
+            );
+        }
+        // Second dimension of Sobol': 0, 1/2, 3/4, 1/4, 5/8, 1/8, 3/8, 7/8
+        let expected = [0.0, 0.5, 0.75, 0.25, 0.625, 0.125, 0.375, 0.875];
+        for (a, e) in expected.iter().enumerate() {
+            assert_eq!(sobol_2d_sample(a as u32, Point2i::new(0, 0)).y, *e);
+        }
+
+        // Gray code ordering generates the same set of points
+        let mut gray = vec![Point2f::new(0.0, 0.0); 16];
+        gray_code_sample_2d(&CSOBOL[0], &CSOBOL[1], 16, Point2i::new(0, 0), &mut gray);
+        let mut direct: Vec<_> = (0..16)
+            .map(|a| sobol_2d_sample(a, Point2i::new(0, 0)))
+            .collect();
+        let key = |p: &Point2f| (p.x * 16.0) as u32 * 16 + (p.y * 16.0) as u32;
+        gray.sort_by_key(key);
+        direct.sort_by_key(key);
+        assert_eq!(gray, direct);
+    }
+
+    #[test]
+    fn test_owen_scramble() {
+        // Scrambling preserves stratification: the first 2^k points of the van der Corput
+        // sequence still cover each of the 2^k strata exactly once.
+        for seed in &[0, 1, 0xdeadbeef, 12345] {
+            let mut strata = [false; 16];
+            for a in 0..16 {
+                let v = owen_scramble(multiply_generator(&CVAN_DER_CORPUT, a), *seed);
+                strata[(v >> 28) as usize] = true;
+            }
+            assert!(strata.iter().all(|s| *s));
+        }
+        // Deterministic, and actually scrambles
+        assert_eq!(owen_scramble(0x12345678, 42), owen_scramble(0x12345678, 42));
+        assert_ne!(owen_scramble(0x12345678, 42), owen_scramble(0x12345678, 43));
+    }
+}
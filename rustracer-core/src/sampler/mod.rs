@@ -1,9 +1,14 @@
 use crate::camera::CameraSample;
 use crate::{Point2f, Point2i};
 
+pub mod cmj;
 pub mod lowdiscrepancy;
 pub mod zerotwosequence;
 
+pub fn init_stats() {
+    zerotwosequence::init_stats();
+}
+
 pub trait Sampler: Send + Sync {
     fn start_pixel(&mut self, p: Point2i);
     fn get_1d(&mut self) -> f32;
@@ -12,11 +17,50 @@ pub trait Sampler: Send + Sync {
     fn request_1d_array(&mut self, n: usize);
     fn request_2d_array(&mut self, n: usize);
     fn round_count(&self, count: usize) -> usize;
-    fn get_1d_array(&mut self, n: usize) -> Option<&[f32]>;
-    fn get_2d_array(&mut self, n: usize) -> Option<&[Point2f]>;
+    /// Move on to the next requested array of 1D samples, returning a handle to access it with
+    /// `array_1d()`, or `None` if all the requested arrays have been consumed.
+    fn next_1d_array(&mut self, n: usize) -> Option<SampleArray>;
+    /// Move on to the next requested array of 2D samples, returning a handle to access it with
+    /// `array_2d()`, or `None` if all the requested arrays have been consumed.
+    fn next_2d_array(&mut self, n: usize) -> Option<SampleArray>;
+    /// The samples of a 1D array previously returned by `next_1d_array()`. As this only borrows
+    /// the sampler immutably, several arrays can be used at the same time.
+    fn array_1d(&self, array: SampleArray) -> &[f32];
+    /// The samples of a 2D array previously returned by `next_2d_array()`. As this only borrows
+    /// the sampler immutably, several arrays can be used at the same time.
+    fn array_2d(&self, array: SampleArray) -> &[Point2f];
+
+    fn get_1d_array(&mut self, n: usize) -> Option<&[f32]> {
+        let array = self.next_1d_array(n)?;
+        Some(self.array_1d(array))
+    }
+
+    fn get_2d_array(&mut self, n: usize) -> Option<&[Point2f]> {
+        let array = self.next_2d_array(n)?;
+        Some(self.array_2d(array))
+    }
     fn start_next_sample(&mut self) -> bool;
     fn reseed(&mut self, seed: u64);
     fn spp(&self) -> usize;
+    /// The number of samples per pixel this kind of sampler would actually take if asked for
+    /// `spp`, without creating it.
+    fn round_spp(&self, spp: usize) -> usize;
     fn box_clone(&self) -> Box<dyn Sampler>;
+    /// A new sampler of the same kind taking `spp` samples per pixel, or the closest count it
+    /// supports (see `round_spp()`). The arrays requested from this sampler aren't carried over.
+    fn with_spp(&self, spp: usize) -> Box<dyn Sampler>;
     fn current_sample_number(&self) -> usize;
 }
+
+/// Handle to the samples of an array for the current pixel sample, as returned by
+/// `Sampler::next_1d_array()` / `Sampler::next_2d_array()`. It is only valid until the sampler
+/// moves on to the next sample.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct SampleArray {
+    /// Index of the array, in the order they were requested
+    pub index: usize,
+    /// Offset of the first sample in the sampler's storage for this array
+    pub offset: usize,
+    /// Number of samples in the array
+    pub len: usize,
+}
@@ -1,27 +1,65 @@
-use log::info;
+use std::time::Instant;
+
+use log::{info, warn};
 use num::Zero;
 
 use crate::camera::CameraSample;
 use crate::paramset::ParamSet;
 use crate::rng::RNG;
+use crate::sampler::cmj::{cmj_2d, stratified_1d};
 use crate::sampler::lowdiscrepancy::{sobol_2d, van_der_corput};
-use crate::sampler::Sampler;
-use crate::{Point2f, Point2i};
+use crate::sampler::{SampleArray, Sampler};
+use crate::{PbrtOptions, Point2f, Point2i};
+
+stat_ratio!(
+    "Sampler/Nanoseconds per generated pixel sample",
+    sample_generation_time
+);
+pub fn init_stats() {
+    sample_generation_time::init();
+}
 
+/// How the sample arrays (e.g. the samples of each light, see `Light::n_samples()`) are
+/// generated.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ArrayPattern {
+    /// (0, 2)-sequences, which need power of 2 array sizes
+    ZeroTwo,
+    /// A correlated multi-jittered pattern for each pixel sample, which can have any size
+    CorrelatedMultiJitter,
+}
+
+/// Sampler based on (0, 2)-sequences.
+///
+/// All the samples of a pixel are generated up front by `start_pixel()`, and stored in contiguous
+/// buffers: the sample vector of each pixel sample (all its 1D or 2D dimensions) is a contiguous
+/// slice, and each requested array is a contiguous block of its buffer, made of one slice per
+/// pixel sample. Getting a sample is then just a lookup.
 #[derive(Clone)]
 pub struct ZeroTwoSequence {
     spp: usize,
+    array_pattern: ArrayPattern,
     current_pixel: Point2i,
     current_pixel_sample_index: usize,
     sample_1d_array_sizes: Vec<usize>,
     sample_2d_array_sizes: Vec<usize>,
-    sample_array_1d: Vec<Vec<f32>>,
-    sample_array_2d: Vec<Vec<Point2f>>,
+    /// Start of each requested array in `sample_array_1d`
+    sample_1d_array_starts: Vec<usize>,
+    /// Start of each requested array in `sample_array_2d`
+    sample_2d_array_starts: Vec<usize>,
+    sample_array_1d: Vec<f32>,
+    sample_array_2d: Vec<Point2f>,
     array_1d_offset: usize,
     array_2d_offset: usize,
     // Pixel sampler data
-    samples_1d: Vec<Vec<f32>>,
-    samples_2d: Vec<Vec<Point2f>>,
+    n_sampled_dimensions: usize,
+    /// Sample vectors of the pixel samples, `n_sampled_dimensions` values per pixel sample
+    samples_1d: Vec<f32>,
+    samples_2d: Vec<Point2f>,
+    /// Buffers for the samples of one dimension, before they are interleaved into `samples_1d`
+    /// and `samples_2d`
+    dimension_1d: Vec<f32>,
+    dimension_2d: Vec<Point2f>,
     current_1d_dimension: usize,
     current_2d_dimension: usize,
     rng: RNG,
@@ -30,76 +68,121 @@ pub struct ZeroTwoSequence {
 impl ZeroTwoSequence {
     pub fn new(spp: usize, n_sampled_dimensions: usize) -> ZeroTwoSequence {
         let spp = spp.next_power_of_two();
-        let mut samples1d = Vec::with_capacity(n_sampled_dimensions);
-        let mut samples2d = Vec::with_capacity(n_sampled_dimensions);
-        for _ in 0..n_sampled_dimensions {
-            samples1d.push(vec![0.0; spp]);
-            samples2d.push(vec![Point2f::new(0.0, 0.0); spp]);
-        }
 
         ZeroTwoSequence {
             spp,
+            array_pattern: ArrayPattern::ZeroTwo,
             current_pixel: Point2i::new(0, 0),
             current_pixel_sample_index: 0,
             sample_1d_array_sizes: Vec::new(),
             sample_2d_array_sizes: Vec::new(),
+            sample_1d_array_starts: Vec::new(),
+            sample_2d_array_starts: Vec::new(),
             sample_array_1d: Vec::new(),
             sample_array_2d: Vec::new(),
             array_1d_offset: 0,
             array_2d_offset: 0,
-            samples_1d: samples1d,
-            samples_2d: samples2d,
+            n_sampled_dimensions,
+            samples_1d: vec![0.0; spp * n_sampled_dimensions],
+            samples_2d: vec![Point2f::zero(); spp * n_sampled_dimensions],
+            dimension_1d: vec![0.0; spp],
+            dimension_2d: vec![Point2f::zero(); spp],
             current_1d_dimension: 0,
             current_2d_dimension: 0,
             rng: RNG::new(),
         }
     }
 
-    pub fn create(ps: &ParamSet) -> Box<dyn Sampler> {
-        let nsamples = ps.find_one_int("pixelsamples", 16);
+    pub fn create(ps: &ParamSet, opts: &PbrtOptions) -> Box<dyn Sampler> {
+        let nsamples = opts.pixel_samples(ps.find_one_int("pixelsamples", 16));
         let sd = ps.find_one_int("dimensions", 4);
-        // TODO quickrender
-        Box::new(Self::new(nsamples as usize, sd as usize))
+        let pattern = ps.find_one_string("arraypattern", "02sequence".into());
+        let array_pattern = match pattern.as_str() {
+            "02sequence" => ArrayPattern::ZeroTwo,
+            "cmj" => ArrayPattern::CorrelatedMultiJitter,
+            _ => {
+                warn!(
+                    "Unknown sample array pattern \"{}\". Using \"02sequence\".",
+                    pattern
+                );
+                ArrayPattern::ZeroTwo
+            }
+        };
+        Box::new(Self::new(nsamples as usize, sd as usize).with_array_pattern(array_pattern))
+    }
+
+    pub fn with_array_pattern(mut self, array_pattern: ArrayPattern) -> ZeroTwoSequence {
+        self.array_pattern = array_pattern;
+        self
+    }
+
+    /// The 1D sample vector of the current pixel sample, one value per sampled dimension.
+    pub fn current_sample_1d(&self) -> &[f32] {
+        let start = self.current_pixel_sample_index * self.n_sampled_dimensions;
+        &self.samples_1d[start..start + self.n_sampled_dimensions]
+    }
+
+    /// The 2D sample vector of the current pixel sample, one value per sampled dimension.
+    pub fn current_sample_2d(&self) -> &[Point2f] {
+        let start = self.current_pixel_sample_index * self.n_sampled_dimensions;
+        &self.samples_2d[start..start + self.n_sampled_dimensions]
     }
 }
 
 impl Sampler for ZeroTwoSequence {
     fn start_pixel(&mut self, p: Point2i) {
-        // Generate 1D and 2D pixel sample components using (0, 2)-sequence
-        for i in 0..self.samples_1d.len() {
-            van_der_corput(
-                1,
-                self.spp as u32,
-                &mut self.samples_1d[i][..],
-                &mut self.rng,
-            );
-        }
-        for i in 0..self.samples_2d.len() {
-            sobol_2d(
-                1,
-                self.spp as u32,
-                &mut self.samples_2d[i][..],
-                &mut self.rng,
-            );
+        let generation_start = Instant::now();
+        // Generate 1D and 2D pixel sample components using (0, 2)-sequence, one dimension at a
+        // time, and interleave them into the sample vectors
+        let n_dims = self.n_sampled_dimensions;
+        for dim in 0..n_dims {
+            van_der_corput(1, self.spp as u32, &mut self.dimension_1d, &mut self.rng);
+            for (s, &v) in self.dimension_1d.iter().enumerate() {
+                self.samples_1d[s * n_dims + dim] = v;
+            }
+        }
+        for dim in 0..n_dims {
+            sobol_2d(1, self.spp as u32, &mut self.dimension_2d, &mut self.rng);
+            for (s, &v) in self.dimension_2d.iter().enumerate() {
+                self.samples_2d[s * n_dims + dim] = v;
+            }
         }
 
         // generate 1d and 2d array samples
-        for i in 0..self.sample_1d_array_sizes.len() {
-            van_der_corput(
-                self.sample_1d_array_sizes[i] as u32,
-                self.spp as u32,
-                &mut self.sample_array_1d[i][..],
-                &mut self.rng,
-            );
-        }
-        for i in 0..self.sample_2d_array_sizes.len() {
-            sobol_2d(
-                self.sample_2d_array_sizes[i] as u32,
-                self.spp as u32,
-                &mut self.sample_array_2d[i][..],
-                &mut self.rng,
-            );
+        for (&n, &start) in self
+            .sample_1d_array_sizes
+            .iter()
+            .zip(&self.sample_1d_array_starts)
+        {
+            let array = &mut self.sample_array_1d[start..start + n * self.spp];
+            match self.array_pattern {
+                ArrayPattern::ZeroTwo => {
+                    van_der_corput(n as u32, self.spp as u32, array, &mut self.rng)
+                }
+                ArrayPattern::CorrelatedMultiJitter => {
+                    for samples in array.chunks_mut(n) {
+                        stratified_1d(samples, &mut self.rng);
+                    }
+                }
+            }
+        }
+        for (&n, &start) in self
+            .sample_2d_array_sizes
+            .iter()
+            .zip(&self.sample_2d_array_starts)
+        {
+            let array = &mut self.sample_array_2d[start..start + n * self.spp];
+            match self.array_pattern {
+                ArrayPattern::ZeroTwo => sobol_2d(n as u32, self.spp as u32, array, &mut self.rng),
+                ArrayPattern::CorrelatedMultiJitter => {
+                    for samples in array.chunks_mut(n) {
+                        cmj_2d(samples, self.rng.uniform_u32());
+                    }
+                }
+            }
         }
+        sample_generation_time::add(generation_start.elapsed().as_nanos() as u64);
+        sample_generation_time::add_total(self.spp as u64);
 
         self.current_pixel = p;
         self.current_pixel_sample_index = 0;
@@ -118,46 +201,62 @@ impl Sampler for ZeroTwoSequence {
 
     fn request_1d_array(&mut self, n: usize) {
         self.sample_1d_array_sizes.push(n);
-        let mut vec = Vec::new();
-        vec.resize(n * self.spp, 0.0);
-        self.sample_array_1d.push(vec);
+        self.sample_1d_array_starts.push(self.sample_array_1d.len());
+        let len = self.sample_array_1d.len() + n * self.spp;
+        self.sample_array_1d.resize(len, 0.0);
     }
 
     fn request_2d_array(&mut self, n: usize) {
         info!("Requesting 2d array of {} samples", n);
         self.sample_2d_array_sizes.push(n);
-        let mut vec = Vec::new();
-        vec.resize(n * self.spp, Point2f::zero());
-        self.sample_array_2d.push(vec);
+        self.sample_2d_array_starts.push(self.sample_array_2d.len());
+        let len = self.sample_array_2d.len() + n * self.spp;
+        self.sample_array_2d.resize(len, Point2f::zero());
     }
 
-    fn get_1d_array(&mut self, n: usize) -> Option<&[f32]> {
-        if self.array_1d_offset == self.sample_array_1d.len() {
+    fn next_1d_array(&mut self, n: usize) -> Option<SampleArray> {
+        if self.array_1d_offset == self.sample_1d_array_sizes.len() {
             return None;
         }
         assert_eq!(self.sample_1d_array_sizes[self.array_1d_offset], n);
         assert!(self.current_pixel_sample_index < self.spp);
-        let res =
-            &self.sample_array_1d[self.array_1d_offset][(self.current_pixel_sample_index * n)..];
+        let res = SampleArray {
+            index: self.array_1d_offset,
+            offset: self.sample_1d_array_starts[self.array_1d_offset]
+                + self.current_pixel_sample_index * n,
+            len: n,
+        };
         self.array_1d_offset += 1;
         Some(res)
     }
 
-    fn get_2d_array(&mut self, n: usize) -> Option<&[Point2f]> {
-        if self.array_2d_offset == self.sample_array_2d.len() {
+    fn next_2d_array(&mut self, n: usize) -> Option<SampleArray> {
+        if self.array_2d_offset == self.sample_2d_array_sizes.len() {
             return None;
         }
         assert_eq!(self.sample_2d_array_sizes[self.array_2d_offset], n);
         assert!(self.current_pixel_sample_index < self.spp);
-        let res =
-            &self.sample_array_2d[self.array_2d_offset][(self.current_pixel_sample_index * n)..];
+        let res = SampleArray {
+            index: self.array_2d_offset,
+            offset: self.sample_2d_array_starts[self.array_2d_offset]
+                + self.current_pixel_sample_index * n,
+            len: n,
+        };
         self.array_2d_offset += 1;
         Some(res)
     }
 
+    fn array_1d(&self, array: SampleArray) -> &[f32] {
+        &self.sample_array_1d[array.offset..array.offset + array.len]
+    }
+
+    fn array_2d(&self, array: SampleArray) -> &[Point2f] {
+        &self.sample_array_2d[array.offset..array.offset + array.len]
+    }
+
     fn get_1d(&mut self) -> f32 {
-        if self.current_1d_dimension < self.samples_1d.len() {
-            let res = self.samples_1d[self.current_1d_dimension][self.current_pixel_sample_index];
+        if self.current_1d_dimension < self.n_sampled_dimensions {
+            let res = self.current_sample_1d()[self.current_1d_dimension];
             self.current_1d_dimension += 1;
             res
         } else {
@@ -166,8 +265,8 @@ impl Sampler for ZeroTwoSequence {
     }
 
     fn get_2d(&mut self) -> Point2f {
-        if self.current_2d_dimension < self.samples_2d.len() {
-            let res = self.samples_2d[self.current_2d_dimension][self.current_pixel_sample_index];
+        if self.current_2d_dimension < self.n_sampled_dimensions {
+            let res = self.current_sample_2d()[self.current_2d_dimension];
             self.current_2d_dimension += 1;
             res
         } else {
@@ -192,7 +291,10 @@ impl Sampler for ZeroTwoSequence {
     }
 
     fn round_count(&self, count: usize) -> usize {
-        count.next_power_of_two()
+        match self.array_pattern {
+            ArrayPattern::ZeroTwo => count.next_power_of_two(),
+            ArrayPattern::CorrelatedMultiJitter => count,
+        }
     }
 
     fn reseed(&mut self, seed: u64) {
@@ -207,7 +309,100 @@ impl Sampler for ZeroTwoSequence {
         Box::new(self.clone())
     }
 
+    fn round_spp(&self, spp: usize) -> usize {
+        spp.next_power_of_two()
+    }
+
+    fn with_spp(&self, spp: usize) -> Box<dyn Sampler> {
+        Box::new(
+            ZeroTwoSequence::new(spp, self.n_sampled_dimensions)
+                .with_array_pattern(self.array_pattern),
+        )
+    }
+
     fn current_sample_number(&self) -> usize {
         self.current_pixel_sample_index
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_arrays_can_be_used_together() {
+        let mut sampler = ZeroTwoSequence::new(4, 4);
+        let n = sampler.round_count(3);
+        sampler.request_2d_array(n);
+        sampler.request_2d_array(n);
+        sampler.start_pixel(Point2i::new(0, 0));
+
+        loop {
+            let a = sampler.next_2d_array(n).unwrap();
+            let b = sampler.next_2d_array(n).unwrap();
+            assert!(sampler.next_2d_array(n).is_none());
+            let (a, b) = (sampler.array_2d(a), sampler.array_2d(b));
+            assert_eq!(a.len(), n);
+            assert_eq!(b.len(), n);
+            assert!(a
+                .iter()
+                .chain(b)
+                .all(|p| (0.0..1.0).contains(&p.x) && (0.0..1.0).contains(&p.y)));
+            if !sampler.start_next_sample() {
+                break;
+            }
+        }
+    }
+
+    #[test]
+    fn test_cmj_arrays_keep_their_size() {
+        let mut sampler =
+            ZeroTwoSequence::new(4, 4).with_array_pattern(ArrayPattern::CorrelatedMultiJitter);
+        let n = sampler.round_count(9);
+        assert_eq!(n, 9);
+        sampler.request_2d_array(n);
+        sampler.start_pixel(Point2i::new(0, 0));
+
+        loop {
+            let a = sampler.get_2d_array(n).unwrap();
+            let mut rows: Vec<_> = a.iter().map(|p| (p.y * 9.0) as usize).collect();
+            rows.sort_unstable();
+            assert_eq!(rows, (0..9).collect::<Vec<_>>());
+            if !sampler.start_next_sample() {
+                break;
+            }
+        }
+    }
+
+    #[test]
+    fn test_round_spp_matches_with_spp() {
+        let sampler = ZeroTwoSequence::new(4, 4);
+        for spp in [1, 3, 8, 100] {
+            assert_eq!(sampler.round_spp(spp), sampler.with_spp(spp).spp());
+        }
+    }
+
+    #[test]
+    fn test_sample_vectors_are_stratified() {
+        let spp = 16;
+        let mut sampler = ZeroTwoSequence::new(spp, 3);
+        sampler.start_pixel(Point2i::new(0, 0));
+
+        let mut strata = vec![Vec::new(); 3];
+        loop {
+            let vector = sampler.current_sample_1d().to_vec();
+            assert_eq!(vector.len(), 3);
+            for (dim, &v) in vector.iter().enumerate() {
+                assert_eq!(sampler.get_1d(), v);
+                strata[dim].push((v * spp as f32) as usize);
+            }
+            if !sampler.start_next_sample() {
+                break;
+            }
+        }
+        for mut s in strata {
+            s.sort_unstable();
+            assert_eq!(s, (0..spp).collect::<Vec<_>>());
+        }
+    }
+}
@@ -1,6 +1,6 @@
 use crate::find_interval;
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct Distribution1D {
     pub func: Vec<f32>,
     cdf: Vec<f32>,
@@ -78,7 +78,13 @@ impl Distribution1D {
         (offset, pdf)
     }
 
-    // TODO pdf_discrete
+    pub fn pdf_discrete(&self, index: usize) -> f32 {
+        if self.func_int > 0.0 {
+            self.func[index] / (self.func_int * self.count() as f32)
+        } else {
+            0.0
+        }
+    }
 }
 
 #[test]
@@ -62,6 +62,67 @@ pub fn power_heuristic(nf: u32, f_pdf: f32, ng: u32, g_pdf: f32) -> f32 {
     (f * f) / (f * f + g * g)
 }
 
+#[inline]
+pub fn balance_heuristic(nf: u32, f_pdf: f32, ng: u32, g_pdf: f32) -> f32 {
+    let f = nf as f32 * f_pdf;
+    let g = ng as f32 * g_pdf;
+    f / (f + g)
+}
+
+/// The heuristic used to weight the samples of the different strategies in multiple importance
+/// sampling.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum MisHeuristic {
+    Balance,
+    /// Power heuristic with the given exponent
+    Power(f32),
+}
+
+impl MisHeuristic {
+    /// Weight of a sample drawn from `f`, given `nf` samples drawn from `f` and `ng` from `g`.
+    #[inline]
+    pub fn weight(self, nf: u32, f_pdf: f32, ng: u32, g_pdf: f32) -> f32 {
+        match self {
+            MisHeuristic::Balance => balance_heuristic(nf, f_pdf, ng, g_pdf),
+            MisHeuristic::Power(2.0) => power_heuristic(nf, f_pdf, ng, g_pdf),
+            MisHeuristic::Power(beta) => {
+                let f = (nf as f32 * f_pdf).powf(beta);
+                let g = (ng as f32 * g_pdf).powf(beta);
+                f / (f + g)
+            }
+        }
+    }
+}
+
+impl Default for MisHeuristic {
+    fn default() -> Self {
+        MisHeuristic::Power(2.0)
+    }
+}
+
 // pub fn zero_two_sequence(n: u32, scramble: (u32, u32)) -> (f32, f32) {
 //     (van_der_corput(n, scramble.0), sobol(n, scramble.1))
 // }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_mis_weights_sum_to_one() {
+        let heuristics = [
+            MisHeuristic::Balance,
+            MisHeuristic::default(),
+            MisHeuristic::Power(3.0),
+        ];
+        for &h in &heuristics {
+            for &(f_pdf, g_pdf) in &[(0.5, 2.0), (1.0, 1.0), (3.0, 0.1)] {
+                let sum = h.weight(1, f_pdf, 1, g_pdf) + h.weight(1, g_pdf, 1, f_pdf);
+                assert!((sum - 1.0).abs() < 1e-6, "{:?}: {}", h, sum);
+            }
+        }
+        assert_eq!(MisHeuristic::Balance.weight(1, 1.0, 1, 3.0), 0.25);
+        assert_eq!(MisHeuristic::default().weight(1, 1.0, 1, 3.0), 0.1);
+        assert!((MisHeuristic::Power(3.0).weight(1, 1.0, 1, 3.0) - 1.0 / 28.0).abs() < 1e-6);
+    }
+}
@@ -1,10 +1,16 @@
 use std::sync::Arc;
 
+use anyhow::{anyhow, Result};
+
 use crate::bounds::Bounds3f;
-use crate::interaction::SurfaceInteraction;
-use crate::light::{Light, LightFlags};
-use crate::primitive::Primitive;
+use crate::bvh::{self, Tlas, BVH};
+use crate::interaction::{Interaction, SurfaceInteraction};
+use crate::light::{is_delta_light, LightFlags, LightRef, TransformedLight};
+use crate::primitive::{PrimitiveRef, TransformedPrimitive};
 use crate::ray::Ray;
+use crate::sampler::Sampler;
+use crate::spectrum::Spectrum;
+use crate::{Normal3f, Point2i, Point3f, Transform, Vector3f};
 
 stat_counter!(
     "Intersections/Regular ray intersection tests",
@@ -20,45 +26,567 @@ pub fn init_stats() {
 }
 
 pub struct Scene {
-    pub lights: Vec<Arc<dyn Light>>,
-    pub infinite_lights: Vec<Arc<dyn Light>>,
-    aggregate: Arc<dyn Primitive>,
+    pub lights: Vec<LightRef>,
+    /// Lights partitioned by type, so that integrators only need to go through the ones that are
+    /// relevant to them (e.g. the infinite lights for escaped rays)
+    delta_lights: Vec<LightRef>,
+    area_lights: Vec<LightRef>,
+    infinite_lights: Vec<LightRef>,
+    aggregate: PrimitiveRef,
+    has_motion: bool,
 }
 
 impl Scene {
-    pub fn new(aggregate: Arc<dyn Primitive>, lights: Vec<Arc<dyn Light>>) -> Scene {
+    pub fn new(aggregate: PrimitiveRef, lights: Vec<LightRef>) -> Scene {
         let mut scene = Scene {
             lights: Vec::new(),
+            delta_lights: Vec::new(),
+            area_lights: Vec::new(),
             infinite_lights: Vec::new(),
             aggregate,
+            has_motion: false,
         };
 
-        let mut infinite_lights = Vec::new();
-
         for l in &lights {
             l.preprocess(&scene);
-            if l.flags().contains(LightFlags::INFINITE) {
-                infinite_lights.push(Arc::clone(l));
+            let flags = l.flags();
+            if is_delta_light(flags) {
+                scene.delta_lights.push(Arc::clone(l));
+            } else if flags.contains(LightFlags::AREA) {
+                scene.area_lights.push(Arc::clone(l));
+            } else if flags.contains(LightFlags::INFINITE) {
+                scene.infinite_lights.push(Arc::clone(l));
             }
         }
 
         scene.lights = lights;
-        scene.infinite_lights = infinite_lights;
 
         scene
     }
 
-    pub fn intersect(&self, ray: &mut Ray) -> Option<SurfaceInteraction<'_, '_>> {
+    /// Record whether some of the primitives move over the shutter interval.
+    pub fn with_motion(mut self, has_motion: bool) -> Scene {
+        self.has_motion = has_motion;
+        self
+    }
+
+    /// Whether some of the primitives move over the shutter interval, i.e. whether the time of
+    /// rays matters.
+    pub fn has_motion(&self) -> bool {
+        self.has_motion
+    }
+
+    /// Lights described by a delta distribution (point, spot, distant lights...), which can't be
+    /// hit by a ray.
+    pub fn delta_lights(&self) -> &[LightRef] {
+        &self.delta_lights
+    }
+
+    /// Lights attached to shapes, which can only contribute to rays that hit those shapes.
+    pub fn area_lights(&self) -> &[LightRef] {
+        &self.area_lights
+    }
+
+    /// Lights surrounding the scene, which are the only ones contributing to rays that escape it.
+    pub fn infinite_lights(&self) -> &[LightRef] {
+        &self.infinite_lights
+    }
+
+    pub fn intersect(&self, ray: &mut Ray) -> Option<SurfaceInteraction> {
         n_intersection_tests::inc();
+        bvh::count_ray();
         self.aggregate.intersect(ray)
     }
 
     pub fn intersect_p(&self, ray: &Ray) -> bool {
         n_shadow_tests::inc();
+        bvh::count_ray();
         self.aggregate.intersect_p(ray)
     }
 
+    /// Find the closest intersection along `ray` with a parametric distance less than `t_max`.
+    ///
+    /// `t_max` replaces whatever `ray.t_max` was when the ray was spawned. On a hit, `ray.t_max`
+    /// is updated to the parametric distance of the intersection, as with `intersect()`.
+    pub fn intersect_up_to(&self, ray: &mut Ray, t_max: f32) -> Option<SurfaceInteraction> {
+        ray.t_max = t_max;
+        self.intersect(ray)
+    }
+
+    /// Return true if `ray` hits anything with a parametric distance less than `t_max`,
+    /// regardless of the value of `ray.t_max`.
+    pub fn intersect_p_up_to(&self, ray: &Ray, t_max: f32) -> bool {
+        let mut r = *ray;
+        r.t_max = t_max;
+        self.intersect_p(&r)
+    }
+
+    /// Bounds of all the geometry of the scene, in world space. Together with
+    /// `Bounds3f::intersect_p()` and `Transform::transform_bounds()`, this can be used by
+    /// applications to cull or frame parts of the scene.
     pub fn world_bounds(&self) -> Bounds3f {
         self.aggregate.world_bounds()
     }
+
+    /// Estimate the irradiance at `p` on a surface with normal `n` (pointing towards the side
+    /// receiving the light), by sampling each light `n_samples` times. Its luminance (`y()`) is
+    /// the illuminance.
+    ///
+    /// Only the light arriving directly from the lights is accounted for. `p` shouldn't be
+    /// exactly on a surface, as the surface could then shadow it.
+    ///
+    /// Each round of light samples uses one sample of `sampler`, which is started over on a new
+    /// pixel whenever it runs out of samples.
+    pub fn incident_illuminance(
+        &self,
+        p: &Point3f,
+        n: &Normal3f,
+        sampler: &mut dyn Sampler,
+        n_samples: usize,
+    ) -> Spectrum {
+        let n = n.normalize();
+        let mut it = Interaction::from_point(p, 0.0);
+        it.n = n;
+        it.wo = Vector3f::from(n);
+        let mut pixel = 0;
+        sampler.start_pixel(Point2i::new(pixel, 0));
+        let mut e = Spectrum::black();
+        for _ in 0..n_samples {
+            for light in &self.lights {
+                let (li, wi, pdf, vis) = light.sample_li(&it, sampler.get_2d());
+                let cos_theta = wi.dotn(&n);
+                if pdf > 0.0 && cos_theta > 0.0 && !li.is_black() && vis.unoccluded(self) {
+                    e += li * cos_theta / pdf;
+                }
+            }
+            if !sampler.start_next_sample() {
+                pixel += 1;
+                sampler.start_pixel(Point2i::new(pixel, 0));
+            }
+        }
+
+        e / n_samples.max(1) as f32
+    }
+
+    /// Change the transform of the `index`-th top-level primitive of the scene. It requires the
+    /// scene not to be shared, e.g. by a render in progress.
+    ///
+    /// If the aggregate is a `Tlas` (as for scenes created through the API), this is the
+    /// `index`-th instance (see `Tlas::set_instance_transform()`), and the TLAS is rebuilt. The
+    /// area lights of the instance are replaced by `TransformedLight`s that follow it.
+    ///
+    /// If the aggregate is a `BVH`, this is the `index`-th primitive it was built from, which
+    /// must be a uniquely owned `TransformedPrimitive`. The BVH is refitted rather than rebuilt,
+    /// so this is meant for small, incremental changes such as nudging objects between
+    /// progressive passes. The scene doesn't know which of its lights are attached to the
+    /// primitive, so moving emissive primitives this way is up to the caller.
+    pub fn set_primitive_transform(&mut self, index: usize, transform: Transform) -> Result<()> {
+        let aggregate = Arc::get_mut(&mut self.aggregate)
+            .ok_or_else(|| anyhow!("Can't modify a scene that is currently shared"))?
+            .as_any_mut();
+        if let Some(tlas) = aggregate.downcast_mut::<Tlas>() {
+            tlas.set_instance_transform(index, transform.clone())?;
+            let moved: Vec<LightRef> = tlas.instances()[index]
+                .lights
+                .iter()
+                .map(|l| {
+                    Arc::new(TransformedLight::new(Arc::clone(l), transform.clone())) as LightRef
+                })
+                .collect();
+            self.replace_lights(&moved);
+            self.preprocess_lights();
+            return Ok(());
+        }
+        let bvh = aggregate
+            .downcast_mut::<BVH>()
+            .ok_or_else(|| anyhow!("Scene aggregate is neither a TLAS nor a BVH"))?;
+        let prim = bvh
+            .primitive_mut(index)
+            .ok_or_else(|| anyhow!("No primitive with index {}", index))?;
+        let transformed = Arc::get_mut(prim)
+            .and_then(|p| p.as_any_mut().downcast_mut::<TransformedPrimitive>())
+            .ok_or_else(|| {
+                anyhow!(
+                    "Primitive {} is not a uniquely owned TransformedPrimitive",
+                    index
+                )
+            })?;
+        transformed.primitive_to_world = transform;
+        transformed.motion = None;
+        bvh.refit();
+        self.preprocess_lights();
+
+        Ok(())
+    }
+
+    /// Start a new frame: the current transforms of the primitives moved with
+    /// `set_primitive_transform()` become those of the previous frame. Until then, the motion
+    /// vectors of the moved primitives (see `GeometryAov::Motion`) are computed against where
+    /// they were at the previous call, or when the scene was built.
+    pub fn end_frame(&mut self) -> Result<()> {
+        let aggregate = Arc::get_mut(&mut self.aggregate)
+            .ok_or_else(|| anyhow!("Can't modify a scene that is currently shared"))?
+            .as_any_mut();
+        if let Some(tlas) = aggregate.downcast_mut::<Tlas>() {
+            tlas.end_frame();
+            return Ok(());
+        }
+        if let Some(bvh) = aggregate.downcast_mut::<BVH>() {
+            let mut index = 0;
+            while let Some(prim) = bvh.primitive_mut(index) {
+                if let Some(transformed) = Arc::get_mut(prim)
+                    .and_then(|p| p.as_any_mut().downcast_mut::<TransformedPrimitive>())
+                {
+                    transformed.previous_to_world = transformed.primitive_to_world.clone();
+                }
+                index += 1;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Replace the lights that have the same ids as `lights`.
+    fn replace_lights(&mut self, lights: &[LightRef]) {
+        for light in lights {
+            for l in self.lights.iter_mut().chain(self.area_lights.iter_mut()) {
+                if l.id() == light.id() {
+                    *l = Arc::clone(light);
+                }
+            }
+        }
+    }
+
+    /// The scene bounds may have changed
+    fn preprocess_lights(&self) {
+        for l in &self.lights {
+            l.preprocess(self);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use light_arena::MemoryArena;
+
+    use super::*;
+    use crate::bvh::SplitMethod;
+    use crate::cryptomatte::MatteIds;
+    use crate::material::TransportMode;
+    use crate::primitive::GeometricPrimitive;
+    use crate::shapes::Sphere;
+    use crate::{pbrt, PbrtOptions};
+
+    fn unit_sphere_scene() -> Scene {
+        crate::init_stats();
+        let sphere = Sphere::new(Transform::default(), 1.0, -1.0, 1.0, 360.0, false);
+        let prim: PrimitiveRef = Arc::new(GeometricPrimitive {
+            shape: Arc::new(sphere),
+            area_light: None,
+            material: None,
+            matte_ids: MatteIds::default(),
+        });
+        Scene::new(prim, Vec::new())
+    }
+
+    #[test]
+    fn test_moved_area_lights_follow_their_instance() {
+        crate::init_stats();
+        let opts = PbrtOptions {
+            defer_render: true,
+            ..PbrtOptions::default()
+        };
+        let scene = r#"
+Sampler "02sequence" "integer pixelsamples" [16]
+WorldBegin
+AttributeBegin
+AreaLightSource "diffuse" "rgb L" [1 1 1] "bool twosided" "true"
+Shape "trianglemesh" "integer indices" [0 1 2 0 2 3]
+    "point P" [-1 2 -1  1 2 -1  1 2 1  -1 2 1]
+AttributeEnd
+WorldEnd
+"#;
+        let mut context = pbrt::parse_scene_string(opts, scene).unwrap().unwrap();
+        let sampler = &mut *context.sampler;
+        let scene = Arc::get_mut(&mut context.scene).unwrap();
+        let up = Normal3f::new(0.0, 1.0, 0.0);
+        let origin = Point3f::new(0.0, 0.0, 0.0);
+        let aside = Point3f::new(10.0, 0.0, 0.0);
+        let e0 = scene.incident_illuminance(&origin, &up, sampler, 64).y();
+        assert!(e0 > 0.1, "{}", e0);
+        assert!(scene.incident_illuminance(&aside, &up, sampler, 64).y() < 0.01 * e0);
+
+        scene
+            .set_primitive_transform(0, Transform::translate(&Vector3f::new(10.0, 0.0, 0.0)))
+            .unwrap();
+        let e = scene.incident_illuminance(&aside, &up, sampler, 64).y();
+        assert!((e - e0).abs() < 0.02 * e0, "{} != {}", e, e0);
+        let e = scene.incident_illuminance(&origin, &up, sampler, 64).y();
+        assert!(e < 0.01 * e0, "{}", e);
+    }
+
+    #[test]
+    fn test_intersect_up_to_ignores_spawned_t_max() {
+        let scene = unit_sphere_scene();
+        // The sphere is 4 units away, but the segment was spawned with a t_max of 1
+        let mut ray = Ray::segment(
+            Point3f::new(0.0, 0.0, -5.0),
+            Vector3f::new(0.0, 0.0, 1.0),
+            1.0,
+        );
+
+        assert!(scene.intersect_up_to(&mut ray, 3.9).is_none());
+        let isect = scene.intersect_up_to(&mut ray, 10.0);
+        assert!(isect.is_some());
+        assert!((ray.t_max - 4.0).abs() < 1e-4);
+    }
+
+    #[test]
+    fn test_intersect_p_up_to_respects_distance() {
+        let scene = unit_sphere_scene();
+        let ray = Ray::new(Point3f::new(0.0, 0.0, -5.0), Vector3f::new(0.0, 0.0, 1.0));
+
+        assert!(!scene.intersect_p_up_to(&ray, 3.9));
+        assert!(scene.intersect_p_up_to(&ray, 4.1));
+        // The original ray is left untouched
+        assert!(ray.t_max.is_infinite());
+    }
+
+    #[test]
+    fn test_intersection_time_is_propagated_to_spawned_rays() {
+        let scene = unit_sphere_scene();
+        let mut ray =
+            Ray::new(Point3f::new(0.0, 0.0, -5.0), Vector3f::new(0.0, 0.0, 1.0)).at_time(0.25);
+
+        let isect = scene.intersect(&mut ray).unwrap();
+        assert_eq!(isect.hit.time, 0.25);
+        let spawned = isect.spawn_ray(&Vector3f::new(0.0, 0.0, -1.0));
+        assert_eq!(spawned.time, 0.25);
+        let shadow = isect.hit.spawn_ray_to(&Point3f::new(0.0, 5.0, -5.0));
+        assert_eq!(shadow.time, 0.25);
+    }
+
+    #[test]
+    fn test_moving_a_primitive_refits_the_bvh() {
+        crate::init_stats();
+        let prims: Vec<PrimitiveRef> = (0..4)
+            .map(|i| {
+                let sphere = Sphere::new(Transform::default(), 1.0, -1.0, 1.0, 360.0, false);
+                let prim: PrimitiveRef = Arc::new(TransformedPrimitive::new(
+                    Arc::new(GeometricPrimitive {
+                        shape: Arc::new(sphere),
+                        area_light: None,
+                        material: None,
+                        matte_ids: MatteIds::default(),
+                    }),
+                    Transform::translate(&Vector3f::new(3.0 * i as f32, 0.0, 0.0)),
+                ));
+                prim
+            })
+            .collect();
+        let mut scene = Scene::new(Arc::new(BVH::new(1, &prims, SplitMethod::SAH)), Vec::new());
+        drop(prims);
+
+        let ray_at = |x: f32| Ray::new(Point3f::new(x, 0.0, -5.0), Vector3f::new(0.0, 0.0, 1.0));
+        assert!(scene.intersect_p(&ray_at(3.0)));
+        assert!(!scene.intersect_p(&ray_at(20.0)));
+
+        // Move the second sphere far away, outside of the original bounds of the scene
+        scene
+            .set_primitive_transform(1, Transform::translate(&Vector3f::new(20.0, 0.0, 0.0)))
+            .unwrap();
+        assert!(!scene.intersect_p(&ray_at(3.0)));
+        assert!(scene.intersect_p(&ray_at(20.0)));
+        assert!(scene.intersect_p(&ray_at(6.0)));
+        assert_eq!(scene.world_bounds().p_max.x, 21.0);
+
+        // Hits remember where the sphere was in the previous frame
+        let isect = scene.intersect(&mut ray_at(20.0)).unwrap();
+        let motion = isect.p_previous - isect.hit.p;
+        assert!((motion.x + 17.0).abs() < 1e-4, "{:?}", motion);
+        scene.end_frame().unwrap();
+        let isect = scene.intersect(&mut ray_at(20.0)).unwrap();
+        assert!((isect.p_previous - isect.hit.p).length() < 1e-4);
+
+        assert!(scene
+            .set_primitive_transform(4, Transform::default())
+            .is_err());
+    }
+
+    #[test]
+    fn test_moving_an_instance_rebuilds_the_tlas() {
+        crate::init_stats();
+        let opts = PbrtOptions {
+            num_threads: 1,
+            defer_render: true,
+            ..PbrtOptions::default()
+        };
+        let scene = r#"
+LookAt 0 0 5  0 0 0  0 1 0
+Camera "perspective"
+Sampler "02sequence"
+WorldBegin
+# Instance 0: a mesh, which gets its own BLAS
+Shape "trianglemesh" "integer indices" [0 1 2 0 2 3]
+    "point P" [-1 -1 0  1 -1 0  1 1 0  -1 1 0]
+# Instances 1 and 2: two instances of the same object
+ObjectBegin "spheres"
+Shape "sphere" "float radius" [0.5]
+Translate 0 2 0
+Shape "sphere" "float radius" [0.5]
+ObjectEnd
+AttributeBegin
+  Translate 3 0 0
+  ObjectInstance "spheres"
+AttributeEnd
+AttributeBegin
+  Translate 6 0 0
+  ObjectInstance "spheres"
+AttributeEnd
+WorldEnd
+"#;
+        let context = pbrt::parse_scene_string(opts, scene).unwrap().unwrap();
+        let mut scene = context.scene;
+
+        let ray_at =
+            |x: f32, y: f32| Ray::new(Point3f::new(x, y, -5.0), Vector3f::new(0.0, 0.0, 1.0));
+        assert!(scene.intersect_p(&ray_at(0.0, 0.0)));
+        assert!(scene.intersect_p(&ray_at(3.0, 2.0)));
+        assert!(scene.intersect_p(&ray_at(6.0, 0.0)));
+        assert!(!scene.intersect_p(&ray_at(0.0, 10.0)));
+
+        let scene = Arc::get_mut(&mut scene).unwrap();
+        // The mesh is already in world space: the transform is applied on top of that
+        scene
+            .set_primitive_transform(0, Transform::translate(&Vector3f::new(0.0, 10.0, 0.0)))
+            .unwrap();
+        assert!(!scene.intersect_p(&ray_at(0.0, 0.0)));
+        assert!(scene.intersect_p(&ray_at(0.0, 10.0)));
+        // The transform of an instance is replaced
+        scene
+            .set_primitive_transform(2, Transform::translate(&Vector3f::new(-6.0, 0.0, 0.0)))
+            .unwrap();
+        assert!(!scene.intersect_p(&ray_at(6.0, 0.0)));
+        assert!(scene.intersect_p(&ray_at(-6.0, 2.0)));
+        assert!(scene.intersect_p(&ray_at(3.0, 2.0)));
+        assert_eq!(scene.world_bounds().p_min.x, -6.5);
+
+        // Hits remember where the instance was in the previous frame
+        let isect = scene.intersect(&mut ray_at(-6.0, 2.0)).unwrap();
+        let motion = isect.p_previous - isect.hit.p;
+        assert!((motion.x - 12.0).abs() < 1e-4, "{:?}", motion);
+        scene.end_frame().unwrap();
+        let isect = scene.intersect(&mut ray_at(-6.0, 2.0)).unwrap();
+        assert!((isect.p_previous - isect.hit.p).length() < 1e-4);
+
+        assert!(scene
+            .set_primitive_transform(3, Transform::default())
+            .is_err());
+    }
+
+    #[test]
+    fn test_scene_lights_are_partitioned_by_type() {
+        crate::init_stats();
+        let opts = PbrtOptions {
+            defer_render: true,
+            ..PbrtOptions::default()
+        };
+        let scene = r#"
+Camera "perspective"
+Film "image" "integer xresolution" [4] "integer yresolution" [4]
+Sampler "02sequence" "integer pixelsamples" [1]
+WorldBegin
+LightSource "point" "point from" [0 2 0]
+LightSource "distant" "point from" [0 0 0] "point to" [0 -1 0]
+LightSource "infinite" "rgb L" [0.5 0.5 0.5]
+AttributeBegin
+AreaLightSource "diffuse" "rgb L" [1 1 1]
+Shape "sphere" "float radius" [0.5]
+AttributeEnd
+WorldEnd
+"#;
+        let context = pbrt::parse_scene_string(opts, scene).unwrap().unwrap();
+        let scene = &context.scene;
+        assert_eq!(scene.lights.len(), 4);
+        assert_eq!(scene.delta_lights().len(), 2);
+        assert_eq!(scene.area_lights().len(), 1);
+        assert_eq!(scene.infinite_lights().len(), 1);
+    }
+
+    #[test]
+    fn test_hit_records_outlive_the_scene() {
+        crate::init_stats();
+        let opts = PbrtOptions {
+            defer_render: true,
+            ..PbrtOptions::default()
+        };
+        let scene = r#"
+LookAt 0 0 5  0 0 0  0 1 0
+Camera "perspective"
+Film "image" "integer xresolution" [4] "integer yresolution" [4]
+Sampler "02sequence" "integer pixelsamples" [1]
+WorldBegin
+AttributeBegin
+AreaLightSource "diffuse" "rgb L" [1 1 1]
+Material "matte"
+Shape "sphere" "float radius" [1]
+AttributeEnd
+WorldEnd
+"#;
+        let context = pbrt::parse_scene_string(opts, scene).unwrap().unwrap();
+        let mut ray = Ray::new(Point3f::new(0.0, 0.0, 5.0), Vector3f::new(0.0, 0.0, -1.0));
+        let mut isect = context.scene.intersect(&mut ray).unwrap();
+        drop(context);
+
+        // The hit record keeps the primitive's material and area light alive
+        assert!(!isect.le(&Vector3f::new(0.0, 0.0, 1.0)).is_black());
+        let mut arena = MemoryArena::new(1);
+        let alloc = arena.allocator();
+        let bsdf = isect
+            .compute_scattering_functions(&ray, TransportMode::RADIANCE, true, &alloc)
+            .unwrap();
+        assert_eq!(bsdf.bxdfs.len(), 1);
+    }
+
+    #[test]
+    fn test_incident_illuminance_matches_analytic_values() {
+        use std::f32::consts::PI;
+
+        crate::init_stats();
+        let opts = PbrtOptions {
+            defer_render: true,
+            ..PbrtOptions::default()
+        };
+        let scene = r#"
+Sampler "02sequence" "integer pixelsamples" [16]
+WorldBegin
+LightSource "point" "point from" [0 2 0] "rgb I" [4 4 4]
+AttributeBegin
+Translate 3 1 0
+Shape "sphere" "float radius" [0.5]
+AttributeEnd
+WorldEnd
+"#;
+        let mut context = pbrt::parse_scene_string(opts, scene).unwrap().unwrap();
+        let scene = Arc::clone(&context.scene);
+        let sampler = &mut *context.sampler;
+        let up = Normal3f::new(0.0, 1.0, 0.0);
+        // The intensity of a point light is I / 4π
+        let e = scene.incident_illuminance(&Point3f::new(0.0, 0.0, 0.0), &up, sampler, 4);
+        assert!((e.y() - 4.0 / (4.0 * PI * 4.0)).abs() < 1e-5, "{}", e);
+        // Cosine falloff
+        let e = scene.incident_illuminance(&Point3f::new(1.0, 0.0, 0.0), &up, sampler, 4);
+        let cos_theta = 2.0 / f32::sqrt(5.0);
+        assert!(
+            (e.y() - 4.0 * cos_theta / (4.0 * PI * 5.0)).abs() < 1e-5,
+            "{}",
+            e
+        );
+        // Facing away from the light, or in the shadow of the sphere
+        let down = Normal3f::new(0.0, -1.0, 0.0);
+        let e = scene.incident_illuminance(&Point3f::new(0.0, 0.0, 0.0), &down, sampler, 4);
+        assert!(e.is_black());
+        let e = scene.incident_illuminance(&Point3f::new(6.0, 0.0, 0.0), &up, sampler, 4);
+        assert!(e.is_black());
+    }
 }
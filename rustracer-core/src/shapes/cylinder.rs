@@ -7,7 +7,7 @@ use crate::efloat::{solve_quadratic, EFloat};
 use crate::interaction::{Interaction, SurfaceInteraction};
 use crate::paramset::ParamSet;
 use crate::ray::Ray;
-use crate::shapes::Shape;
+use crate::shapes::{weingarten, Shape, ShapeRef, UvTransform};
 use crate::{clamp, gamma, lerp, Normal3f, Point2f, Point3f, Transform, Vector3f};
 
 #[derive(Debug)]
@@ -20,6 +20,7 @@ pub struct Cylinder {
     phi_max: f32,
     reverse_orientation: bool,
     transform_swaps_handedness: bool,
+    uv_transform: UvTransform,
 }
 
 impl Cylinder {
@@ -27,7 +28,7 @@ impl Cylinder {
         object_to_world: &Transform,
         reverse_orientation: bool,
         params: &ParamSet,
-    ) -> Arc<dyn Shape> {
+    ) -> ShapeRef {
         let radius = params.find_one_float("radius", 1.0);
         let z_min = params.find_one_float("z_min", -1.0);
         let z_max = params.find_one_float("z_max", 1.0);
@@ -42,6 +43,7 @@ impl Cylinder {
             phi_max: clamp(phi_max, 0.0, 360.0).to_radians(),
             reverse_orientation,
             transform_swaps_handedness: object_to_world.swaps_handedness(),
+            uv_transform: UvTransform::from_params(params),
         })
     }
 }
@@ -59,7 +61,7 @@ impl Shape for Cylinder {
     }
 
     #[allow(non_snake_case)]
-    fn intersect(&self, r: &Ray) -> Option<(SurfaceInteraction<'_, '_>, f32)> {
+    fn intersect(&self, r: &Ray) -> Option<(SurfaceInteraction, f32)> {
         // Transform ray to object space
         let (ray, o_err, d_err) = r.transform(&self.world_to_object);
 
@@ -129,7 +131,7 @@ impl Shape for Cylinder {
 
             // Find parametric representation of cylinder hit
             let u = phi / self.phi_max;
-            let v = (p_hit.z - self.z_min) / (self.z_max / self.z_min);
+            let v = (p_hit.z - self.z_min) / (self.z_max - self.z_min);
 
             // Compute cylinder dpdu and dpdv
             let dpdu = Vector3f::new(-self.phi_max * p_hit.y, self.phi_max * p_hit.x, 0.0);
@@ -139,37 +141,15 @@ impl Shape for Cylinder {
             let d2Pduu = -self.phi_max * self.phi_max * Vector3f::new(p_hit.x, p_hit.y, 0.0);
             let d2Pduv = Vector3f::new(0.0, 0.0, 0.0);
             let d2Pdvv = Vector3f::new(0.0, 0.0, 0.0);
-
-            // Compute coefficients for fundamental forms
-            let E = dpdu.dot(&dpdu);
-            let F = dpdu.dot(&dpdv);
-            let G = dpdv.dot(&dpdv);
-            let N = dpdu.cross(&dpdv).normalize();
-            let e = N.dot(&d2Pduu);
-            let f = N.dot(&d2Pduv);
-            let g = N.dot(&d2Pdvv);
-
-            // Compute dndu and dndv from fundamental form coefficients
-            let inv_EGF2 = 1.0 / (E * G - F * F);
-            let dndu = Normal3f::from(
-                (f * F - e * G) * inv_EGF2 * dpdu + (e * F - f * E) * inv_EGF2 * dpdv,
-            );
-            let dndv = Normal3f::from(
-                (g * F - f * G) * inv_EGF2 * dpdu + (f * F - g * E) * inv_EGF2 * dpdv,
-            );
+            let (dndu, dndv) = weingarten(&dpdu, &dpdv, &d2Pduu, &d2Pduv, &d2Pdvv);
 
             let p_error = gamma(3) * Vector3f::new(p_hit.x.abs(), p_hit.y.abs(), 0.0);
 
+            let (uv, dpdu, dpdv, dndu, dndv) =
+                self.uv_transform
+                    .apply(Point2f::new(u, v), dpdu, dpdv, dndu, dndv);
             let isect = SurfaceInteraction::new(
-                p_hit,
-                p_error,
-                Point2f::new(u, v),
-                -ray.d,
-                dpdu,
-                dpdv,
-                dndu,
-                dndv,
-                self,
+                p_hit, p_error, uv, ray.time, -ray.d, dpdu, dpdv, dndu, dndv, self,
             );
 
             Some((isect.transform(&self.object_to_world), t_shape_hit.into()))
@@ -256,12 +236,12 @@ impl Shape for Cylinder {
         (self.z_max - self.z_min) * self.radius * self.phi_max
     }
 
-    fn sample(&self, u: Point2f) -> (Interaction, f32) {
+    fn sample(&self, u: Point2f, time: f32) -> (Interaction, f32) {
         let z = lerp(u[0], self.z_min, self.z_max);
         let phi = u[1] * self.phi_max;
         let mut p_obj = Point3f::new(self.radius * phi.cos(), self.radius * phi.sin(), z);
         let mut n = (&self.object_to_world * &Normal3f::new(p_obj.x, p_obj.y, 0.0)).normalize();
-        if self.reverse_orientation {
+        if self.flip_normals() {
             n *= -1.0;
         }
         // Reproject p_obj to cylinder surface and compute p_obj_error
@@ -273,7 +253,7 @@ impl Shape for Cylinder {
             .object_to_world
             .transform_point_with_error(&p_obj, &p_obj_error);
 
-        let it = Interaction::new(p, p_error, zero(), n);
+        let it = Interaction::new(p, p_error, time, zero(), n);
         (it, 1.0 / self.area())
     }
 
@@ -6,7 +6,7 @@ use crate::interaction::{Interaction, SurfaceInteraction};
 use crate::paramset::ParamSet;
 use crate::ray::Ray;
 use crate::sampling::concentric_sample_disk;
-use crate::shapes::Shape;
+use crate::shapes::{Shape, ShapeRef, UvTransform};
 use crate::{clamp, Normal3f, Point2f, Point3f, Transform, Vector3f};
 
 #[derive(Debug)]
@@ -19,6 +19,7 @@ pub struct Disk {
     world_to_object: Transform,
     reverse_orientation: bool,
     transform_swaps_handedness: bool,
+    uv_transform: UvTransform,
 }
 
 impl Disk {
@@ -41,28 +42,31 @@ impl Disk {
             object_to_world,
             reverse_orientation,
             transform_swaps_handedness,
+            uv_transform: UvTransform::default(),
         }
     }
 
-    pub fn create(o2w: &Transform, reverse_orientation: bool, params: &ParamSet) -> Arc<dyn Shape> {
+    pub fn create(o2w: &Transform, reverse_orientation: bool, params: &ParamSet) -> ShapeRef {
         let height = params.find_one_float("height", 0.0);
         let radius = params.find_one_float("radius", 1.0);
         let inner_radius = params.find_one_float("innerradius", 0.0);
         let phimax = params.find_one_float("phimax", 360.0);
 
-        Arc::new(Disk::new(
+        let mut disk = Disk::new(
             height,
             radius,
             inner_radius,
             phimax,
             o2w.clone(),
             reverse_orientation,
-        ))
+        );
+        disk.uv_transform = UvTransform::from_params(params);
+        Arc::new(disk)
     }
 }
 
 impl Shape for Disk {
-    fn intersect(&self, r: &Ray) -> Option<(SurfaceInteraction<'_, '_>, f32)> {
+    fn intersect(&self, r: &Ray) -> Option<(SurfaceInteraction, f32)> {
         // Transform ray to object space
         let (ray, _o_err, _d_err) = r.transform(&self.world_to_object);
         // Compute plane intersection for disk
@@ -93,7 +97,8 @@ impl Shape for Disk {
         let one_minus_v = (r_hit - self.inner_radius) / (self.radius - self.inner_radius);
         let v = 1.0 - one_minus_v;
         let dpdu = Vector3f::new(-self.phi_max * p_hit.y, self.phi_max * p_hit.x, 0.0);
-        let dpdv = Vector3f::new(p_hit.x, p_hit.y, 0.0) * (self.radius - self.inner_radius) / r_hit;
+        // v decreases with the radius: dpdv points inwards, so that the normal is along +z
+        let dpdv = Vector3f::new(p_hit.x, p_hit.y, 0.0) * (self.inner_radius - self.radius) / r_hit;
         let dndu = Normal3f::new(0.0, 0.0, 0.0);
         let dndv = Normal3f::new(0.0, 0.0, 0.0);
 
@@ -103,16 +108,11 @@ impl Shape for Disk {
         // Compute error bounds for intersection point
         let p_err = Vector3f::new(0.0, 0.0, 0.0);
         // Initialize SurfaceInteraction from parametric information
+        let (uv, dpdu, dpdv, dndu, dndv) =
+            self.uv_transform
+                .apply(Point2f::new(u, v), dpdu, dpdv, dndu, dndv);
         let isect = SurfaceInteraction::new(
-            p_hit,
-            p_err,
-            Point2f::new(u, v),
-            -ray.d,
-            dpdu,
-            dpdv,
-            dndu,
-            dndv,
-            self,
+            p_hit, p_err, uv, ray.time, -ray.d, dpdu, dpdv, dndu, dndv, self,
         );
         // Update t_hit for quadric intersection
 
@@ -135,12 +135,13 @@ impl Shape for Disk {
         Bounds3f::from_points(&p_min, &p_max)
     }
 
-    fn sample(&self, u: Point2f) -> (Interaction, f32) {
+    fn sample(&self, u: Point2f, time: f32) -> (Interaction, f32) {
         let pd = concentric_sample_disk(u);
         let p_obj = Point3f::new(pd.x * self.radius, pd.y * self.radius, self.height);
         let mut it = Interaction::empty();
+        it.time = time;
         it.n = (&self.object_to_world * &Normal3f::new(0.0, 0.0, 1.0)).normalize();
-        if self.reverse_orientation {
+        if self.flip_normals() {
             it.n = -it.n;
         }
         let (p, p_err) = self
@@ -0,0 +1,254 @@
+//! Loader for the `.hair` binary format by Cem Yuksel (http://www.cemyuksel.com/research/hairmodels/),
+//! used by the standard hair benchmark assets.
+//!
+//! Each strand is a polyline, which is turned into a chain of cubic Bézier curve segments.
+
+use std::fs::File;
+use std::io::{BufReader, Read};
+use std::path::Path;
+
+use anyhow::{bail, Context, Result};
+use byteorder::{LittleEndian, ReadBytesExt};
+use log::{error, info, warn};
+
+use crate::paramset::ParamSet;
+use crate::shapes::ShapeRef;
+use crate::spectrum::Spectrum;
+use crate::transform::Transform;
+use crate::Point3f;
+
+const HAS_SEGMENTS_ARRAY: u32 = 1;
+const HAS_POINTS_ARRAY: u32 = 1 << 1;
+const HAS_THICKNESS_ARRAY: u32 = 1 << 2;
+const HAS_TRANSPARENCY_ARRAY: u32 = 1 << 3;
+const HAS_COLOR_ARRAY: u32 = 1 << 4;
+/// Size of the free-form information field at the end of the header
+const INFO_SIZE: usize = 88;
+
+/// A hair model, as loaded from a `.hair` file.
+#[derive(Debug, Clone)]
+pub struct HairFile {
+    pub strands: Vec<HairStrand>,
+}
+
+/// A strand of hair: a polyline with a width at each of its points.
+#[derive(Debug, Clone)]
+pub struct HairStrand {
+    pub points: Vec<Point3f>,
+    pub widths: Vec<f32>,
+    /// Average of the colours of the strand's points
+    pub colour: Spectrum,
+}
+
+/// A cubic Bézier segment of a strand, with the width of the strand at both ends.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct CurveSegment {
+    pub cp: [Point3f; 4],
+    pub width: [f32; 2],
+}
+
+impl HairFile {
+    pub fn read<P: AsRef<Path>>(filename: P) -> Result<HairFile> {
+        let filename = filename.as_ref();
+        let file = File::open(filename)
+            .with_context(|| format!("Failed to open hair file \"{}\"", filename.display()))?;
+        Self::read_from(&mut BufReader::new(file))
+            .with_context(|| format!("Failed to read hair file \"{}\"", filename.display()))
+    }
+
+    pub fn read_from<R: Read>(r: &mut R) -> Result<HairFile> {
+        let mut signature = [0u8; 4];
+        r.read_exact(&mut signature)?;
+        if &signature != b"HAIR" {
+            bail!("Invalid signature {:?}", signature);
+        }
+        let hair_count = r.read_u32::<LittleEndian>()? as usize;
+        let point_count = r.read_u32::<LittleEndian>()? as usize;
+        let arrays = r.read_u32::<LittleEndian>()?;
+        let default_segments = r.read_u32::<LittleEndian>()? as usize;
+        let default_thickness = r.read_f32::<LittleEndian>()?;
+        let _default_transparency = r.read_f32::<LittleEndian>()?;
+        let mut default_colour = [0f32; 3];
+        r.read_f32_into::<LittleEndian>(&mut default_colour)?;
+        let mut info = [0u8; INFO_SIZE];
+        r.read_exact(&mut info)?;
+
+        if arrays & HAS_POINTS_ARRAY == 0 {
+            bail!("No points array");
+        }
+        let segments = if arrays & HAS_SEGMENTS_ARRAY != 0 {
+            let mut segments = vec![0u16; hair_count];
+            r.read_u16_into::<LittleEndian>(&mut segments)?;
+            segments.into_iter().map(usize::from).collect()
+        } else {
+            vec![default_segments; hair_count]
+        };
+        let n_points: usize = segments.iter().map(|s| s + 1).sum();
+        if n_points != point_count {
+            bail!(
+                "The strands have {} points but the file declares {}",
+                n_points,
+                point_count
+            );
+        }
+
+        let mut coords = vec![0f32; 3 * point_count];
+        r.read_f32_into::<LittleEndian>(&mut coords)?;
+        let thickness = if arrays & HAS_THICKNESS_ARRAY != 0 {
+            let mut thickness = vec![0f32; point_count];
+            r.read_f32_into::<LittleEndian>(&mut thickness)?;
+            thickness
+        } else {
+            vec![default_thickness; point_count]
+        };
+        if arrays & HAS_TRANSPARENCY_ARRAY != 0 {
+            let mut transparency = vec![0f32; point_count];
+            r.read_f32_into::<LittleEndian>(&mut transparency)?;
+        }
+        let colours = if arrays & HAS_COLOR_ARRAY != 0 {
+            let mut colours = vec![0f32; 3 * point_count];
+            r.read_f32_into::<LittleEndian>(&mut colours)?;
+            colours
+        } else {
+            default_colour.repeat(point_count)
+        };
+
+        let mut strands = Vec::with_capacity(hair_count);
+        let mut start = 0;
+        for n_segments in segments {
+            let end = start + n_segments + 1;
+            let points = coords[3 * start..3 * end]
+                .chunks(3)
+                .map(|c| Point3f::new(c[0], c[1], c[2]))
+                .collect();
+            let colour = colours[3 * start..3 * end]
+                .chunks(3)
+                .fold(Spectrum::black(), |sum, c| {
+                    sum + Spectrum::rgb(c[0], c[1], c[2])
+                })
+                / (n_segments + 1) as f32;
+            strands.push(HairStrand {
+                points,
+                widths: thickness[start..end].to_vec(),
+                colour,
+            });
+            start = end;
+        }
+
+        Ok(HairFile { strands })
+    }
+}
+
+impl HairStrand {
+    /// Convert the strand's polyline to a chain of cubic Bézier segments going through all its
+    /// points, using Catmull-Rom tangents (the end points are repeated to get the tangents at the
+    /// ends of the strand).
+    pub fn bezier_segments(&self) -> Vec<CurveSegment> {
+        let p = &self.points;
+        let n = p.len();
+        (1..n)
+            .map(|i| {
+                let (p0, p1, p2) = (p[i.saturating_sub(2)], p[i - 1], p[i]);
+                let p3 = p[usize::min(i + 1, n - 1)];
+                CurveSegment {
+                    cp: [p1, p1 + (p2 - p0) / 6.0, p2 - (p3 - p1) / 6.0, p2],
+                    width: [self.widths[i - 1], self.widths[i]],
+                }
+            })
+            .collect()
+    }
+}
+
+/// Create the shapes for `Shape "hairfile"`.
+pub fn create(_o2w: &Transform, _reverse_orientation: bool, params: &ParamSet) -> Vec<ShapeRef> {
+    let filename = params.find_one_filename("filename", "".into());
+    let hair = match HairFile::read(&filename) {
+        Ok(hair) => hair,
+        Err(e) => {
+            error!("{:#}", e);
+            return Vec::new();
+        }
+    };
+    let n_segments: usize = hair.strands.iter().map(|s| s.points.len() - 1).sum();
+    info!(
+        "Loaded hair file \"{}\" with {} strands and {} segments",
+        filename,
+        hair.strands.len(),
+        n_segments
+    );
+    // TODO create the curves once there is a curve shape
+    warn!(
+        "Curve shapes are not supported yet: ignoring the {} strands of \"{}\"",
+        hair.strands.len(),
+        filename
+    );
+    Vec::new()
+}
+
+#[cfg(test)]
+mod tests {
+    use byteorder::WriteBytesExt;
+
+    use super::*;
+
+    #[test]
+    fn test_read_hair_file() {
+        // 2 strands of 1 and 2 segments, with colours but the default thickness
+        let mut data = Vec::new();
+        data.extend_from_slice(b"HAIR");
+        for v in &[
+            2,
+            5,
+            HAS_SEGMENTS_ARRAY | HAS_POINTS_ARRAY | HAS_COLOR_ARRAY,
+            0,
+        ] {
+            data.write_u32::<LittleEndian>(*v).unwrap();
+        }
+        for v in &[0.5, 1.0, 0.0, 0.0, 0.0] {
+            data.write_f32::<LittleEndian>(*v).unwrap();
+        }
+        data.extend_from_slice(&[0; INFO_SIZE]);
+        for s in &[1, 2] {
+            data.write_u16::<LittleEndian>(*s).unwrap();
+        }
+        for i in 0..5 {
+            for v in &[i as f32, 0.0, 0.0] {
+                data.write_f32::<LittleEndian>(*v).unwrap();
+            }
+        }
+        for i in 0..5 {
+            for v in &[1.0, i as f32, 0.0] {
+                data.write_f32::<LittleEndian>(*v).unwrap();
+            }
+        }
+
+        let hair = HairFile::read_from(&mut &data[..]).unwrap();
+        assert_eq!(hair.strands.len(), 2);
+        let strand = &hair.strands[1];
+        assert_eq!(
+            strand.points,
+            vec![
+                Point3f::new(2.0, 0.0, 0.0),
+                Point3f::new(3.0, 0.0, 0.0),
+                Point3f::new(4.0, 0.0, 0.0),
+            ]
+        );
+        assert_eq!(strand.widths, vec![0.5; 3]);
+        assert_eq!(strand.colour, Spectrum::rgb(1.0, 3.0, 0.0));
+
+        let segments = strand.bezier_segments();
+        assert_eq!(segments.len(), 2);
+        assert_eq!(segments[0].cp[0], strand.points[0]);
+        assert_eq!(segments[0].cp[3], strand.points[1]);
+        assert_eq!(segments[1].cp[0], strand.points[1]);
+        assert_eq!(segments[1].cp[3], strand.points[2]);
+        // The segments join smoothly
+        assert_eq!(
+            segments[0].cp[3] - segments[0].cp[2],
+            segments[1].cp[1] - segments[1].cp[0]
+        );
+
+        data[4] = 3;
+        assert!(HairFile::read_from(&mut &data[..]).is_err());
+    }
+}
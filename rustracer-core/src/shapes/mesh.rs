@@ -2,17 +2,17 @@ use std::collections::HashMap;
 use std::fmt;
 use std::sync::Arc;
 
-use log::error;
+use log::{error, warn};
 use num::zero;
 
-use crate::bounds::Bounds3f;
+use crate::bounds::{Axis, Bounds3f};
 use crate::geometry;
 use crate::interaction::{Interaction, SurfaceInteraction};
 use crate::paramset::ParamSet;
-use crate::ray::Ray;
+use crate::ray::{BackfaceCulling, Ray};
 use crate::sampling;
-use crate::shapes::Shape;
-use crate::texture::{ConstantTexture, Texture, TextureFloat};
+use crate::shapes::{Shape, ShapeRef, UvTriangle};
+use crate::texture::{ConstantTexture, TextureRef};
 use crate::{
     coordinate_system, gamma, max_component, max_dimension, permute_p, permute_v, Normal3f,
     Point2f, Point3f, Transform, Vector3f,
@@ -21,10 +21,22 @@ use crate::{
 stat_percent!("Intersections/Ray-triangle intersection tests", n_hits);
 stat_memory_counter!("Memory/Triangle meshes", tri_mesh_bytes);
 stat_ratio!("Scene/Triangles per triangle mesh", n_tris_per_mesh);
+stat_counter!("Scene/Invalid triangles skipped", n_invalid_triangles);
 pub fn init_stats() {
     n_hits::init();
     tri_mesh_bytes::init();
     n_tris_per_mesh::init();
+    n_invalid_triangles::init();
+}
+
+/// Indices into the normals and uvs of a mesh, 3 per triangle like the vertex indices, for
+/// meshes whose attributes don't share the topology of the positions (e.g. OBJ files, where a
+/// vertex can have different normals or uvs in each face using it). Attributes without indices
+/// are indexed by the vertex indices, and need one value per position.
+#[derive(Debug, Clone, Default)]
+pub struct AttributeIndices {
+    pub n: Option<Vec<usize>>,
+    pub uv: Option<Vec<usize>>,
 }
 
 pub struct TriangleMesh {
@@ -35,8 +47,12 @@ pub struct TriangleMesh {
     n: Option<Vec<Normal3f>>,
     s: Option<Vec<Vector3f>>,
     uv: Option<Vec<Point2f>>,
-    alpha_mask: Option<Arc<TextureFloat>>,
-    shadow_alpha_mask: Option<Arc<TextureFloat>>,
+    attribute_indices: AttributeIndices,
+    alpha_mask: Option<TextureRef<f32>>,
+    shadow_alpha_mask: Option<TextureRef<f32>>,
+    /// Whether camera rays ignore the back faces of the mesh (`"bool backfacecull"`), so that
+    /// the camera sees through them
+    backface_cull: bool,
 }
 
 impl fmt::Debug for TriangleMesh {
@@ -53,12 +69,24 @@ impl TriangleMesh {
         s: Option<&[Vector3f]>,
         n: Option<&[Normal3f]>,
         uv: Option<&[Point2f]>,
-        alpha_mask: Option<Arc<TextureFloat>>,
-        shadow_alpha_mask: Option<Arc<TextureFloat>>,
+        attribute_indices: AttributeIndices,
+        alpha_mask: Option<TextureRef<f32>>,
+        shadow_alpha_mask: Option<TextureRef<f32>>,
+        backface_cull: bool,
     ) -> Self {
         n_tris_per_mesh::inc_total();
         n_tris_per_mesh::add(vertex_indices.len() as u64 / 3);
         let points: Vec<Point3f> = p.iter().map(|pt| object_to_world * pt).collect();
+        tri_mesh_bytes::add(
+            (size_of::<TriangleMesh>()
+                + size_of_val(vertex_indices)
+                + size_of_val(p)
+                + n.map_or(0, size_of_val)
+                + s.map_or(0, size_of_val)
+                + uv.map_or(0, size_of_val)
+                + attribute_indices.n.as_deref().map_or(0, size_of_val)
+                + attribute_indices.uv.as_deref().map_or(0, size_of_val)) as u64,
+        );
         TriangleMesh {
             object_to_world: object_to_world.clone(),
             world_to_object: object_to_world.inverse(),
@@ -67,8 +95,10 @@ impl TriangleMesh {
             n: n.map(Vec::from),
             s: s.map(Vec::from),
             uv: uv.map(Vec::from),
+            attribute_indices,
             alpha_mask,
             shadow_alpha_mask,
+            backface_cull,
         }
     }
 
@@ -78,8 +108,8 @@ impl TriangleMesh {
         _w2o: &Transform,
         reverse_orientation: bool,
         params: &ParamSet,
-        float_textures: &HashMap<String, Arc<dyn Texture<f32>>>,
-    ) -> Vec<Arc<dyn Shape>> {
+        float_textures: &HashMap<String, TextureRef<f32>>,
+    ) -> Vec<ShapeRef> {
         let vi: Vec<usize> = params
             .find_int("indices")
             .unwrap_or_default()
@@ -112,6 +142,15 @@ impl TriangleMesh {
             error!("Vertex positions \"P\" not provided with triangle mesh shape");
             return Vec::new();
         }
+        let attribute_indices = AttributeIndices {
+            n: params
+                .find_int("normalindices")
+                .map(|ni| ni.iter().map(|i| *i as usize).collect()),
+            uv: params
+                .find_int("uvindices")
+                .map(|ui| ui.iter().map(|i| *i as usize).collect()),
+        };
+
         let S = params.find_vector3f("S").and_then(|s| {
             if s.len() != P.len() {
                 error!("Number of \"S\"s for mesh triangle must match \"P\"s");
@@ -122,7 +161,7 @@ impl TriangleMesh {
         });
         // TODO should be Normal3f
         let N = params.find_normal3f("N").and_then(|n| {
-            if n.len() != P.len() {
+            if attribute_indices.n.is_none() && n.len() != P.len() {
                 error!("Number of \"N\"s for mesh triangle must match \"P\"s");
                 None
             } else {
@@ -155,7 +194,7 @@ impl TriangleMesh {
             shadow_alpha_mask = Some(Arc::new(ConstantTexture::new(0.0)));
         }
 
-        let res: Vec<Arc<dyn Shape>> = create_triangle_mesh(
+        let res: Vec<ShapeRef> = create_triangle_mesh(
             o2w,
             reverse_orientation,
             &vi[..],
@@ -163,8 +202,10 @@ impl TriangleMesh {
             S.as_ref().map(|s| &s[..]),
             N.as_ref().map(|n| &n[..]),
             uvs.as_ref().map(|uv| &uv[..]),
+            &attribute_indices,
             alpha_mask,
             shadow_alpha_mask,
+            params.find_one_bool("backfacecull", false),
         );
 
         res
@@ -188,7 +229,7 @@ impl Triangle {
             reverse_orientation,
             swaps_handedness,
         };
-        tri_mesh_bytes::add(::std::mem::size_of_val(&tri) as u64);
+        tri_mesh_bytes::add(size_of_val(&tri) as u64);
 
         tri
     }
@@ -198,9 +239,40 @@ impl Triangle {
         self.mesh.vertex_indices[self.v_start_index + index]
     }
 
+    /// Whether `ray` ignores the back face of the triangle
+    fn culls(&self, ray: &Ray) -> bool {
+        match ray.backface_culling {
+            BackfaceCulling::None => false,
+            BackfaceCulling::Flagged => self.mesh.backface_cull,
+            BackfaceCulling::All => true,
+        }
+    }
+
+    /// Index of the normal of the given vertex of the triangle
+    #[inline(always)]
+    fn n_index(&self, index: usize) -> usize {
+        match self.mesh.attribute_indices.n {
+            Some(ref ni) => ni[self.v_start_index + index],
+            None => self.v(index),
+        }
+    }
+
+    /// Index of the uv of the given vertex of the triangle
+    #[inline(always)]
+    fn uv_index(&self, index: usize) -> usize {
+        match self.mesh.attribute_indices.uv {
+            Some(ref ui) => ui[self.v_start_index + index],
+            None => self.v(index),
+        }
+    }
+
     fn get_uvs(&self) -> [Point2f; 3] {
         if let Some(ref uv) = self.mesh.uv {
-            [uv[self.v(0)], uv[self.v(1)], uv[self.v(2)]]
+            [
+                uv[self.uv_index(0)],
+                uv[self.uv_index(1)],
+                uv[self.uv_index(2)],
+            ]
         } else {
             [
                 Point2f::new(0.0, 0.0),
@@ -212,7 +284,15 @@ impl Triangle {
 }
 
 impl Shape for Triangle {
-    fn intersect(&self, ray: &Ray) -> Option<(SurfaceInteraction<'_, '_>, f32)> {
+    fn triangle_vertices(&self) -> Option<[Point3f; 3]> {
+        Some([
+            self.mesh.p[self.v(0)],
+            self.mesh.p[self.v(1)],
+            self.mesh.p[self.v(2)],
+        ])
+    }
+
+    fn intersect(&self, ray: &Ray) -> Option<(SurfaceInteraction, f32)> {
         n_hits::inc_total();
 
         let p0 = &self.mesh.p[self.v(0)];
@@ -277,6 +357,15 @@ impl Shape for Triangle {
         if det == 0.0 {
             return None;
         }
+        // - cull back faces. The determinant is `n.d / d.z` for the unnormalized geometric normal
+        // `n = (p0 - p2) x (p1 - p2)`, so its sign and that of `d.z` give the side of the
+        // triangle the ray comes from, following the winding order of the vertices (and
+        // ReverseOrientation) rather than the shading normals.
+        if self.culls(ray)
+            && ((det > 0.0) == (d.z > 0.0)) != (self.reverse_orientation ^ self.swaps_handedness)
+        {
+            return None;
+        }
 
         // - compute scaled hit distance to triangle and test against ray t range
         p0t.z *= sz;
@@ -357,6 +446,7 @@ impl Shape for Triangle {
                 p_hit,
                 zero(),
                 uv_hit,
+                ray.time,
                 -ray.d,
                 dpdu,
                 dpdv,
@@ -374,6 +464,7 @@ impl Shape for Triangle {
             p_hit,
             p_error,
             uv_hit,
+            ray.time,
             -ray.d,
             dpdu,
             dpdv,
@@ -388,7 +479,8 @@ impl Shape for Triangle {
         // Initialize triangle shading geometry
         // - shading normal
         let ns = if let Some(ref n) = self.mesh.n {
-            (n[self.v(0)] * b0 + n[self.v(1)] * b1 + n[self.v(2)] * b2).normalize()
+            (n[self.n_index(0)] * b0 + n[self.n_index(1)] * b1 + n[self.n_index(2)] * b2)
+                .normalize()
         } else {
             isect.hit.n
         };
@@ -420,6 +512,12 @@ impl Shape for Triangle {
             isect.hit.n = -isect.hit.n;
             isect.shading.n = isect.hit.n;
         }
+        // The mesh is stored in world space
+        isect.n_object = self
+            .mesh
+            .world_to_object
+            .transform_normal(&isect.shading.n)
+            .normalize();
 
         n_hits::inc();
         Some((isect, t))
@@ -531,53 +629,20 @@ impl Shape for Triangle {
             return false;
         }
 
-        // Test shadow ray intersection against alpha texture, if present
+        // Test shadow ray intersection against alpha textures, if present. Only the hit point
+        // and texture coordinates are needed to evaluate them.
         if self.mesh.alpha_mask.is_some() || self.mesh.shadow_alpha_mask.is_some() {
-            // Compute triangle partial derivatives
-            let mut dpdu = Vector3f::new(0.0, 0.0, 0.0);
-            let mut dpdv = Vector3f::new(0.0, 0.0, 0.0);
             let uv = self.get_uvs();
-            // - compute deltas for partial derivatives
-            let duv02 = uv[0] - uv[2];
-            let duv12 = uv[1] - uv[2];
-            let dp02 = *p0 - *p2;
-            let dp12 = *p1 - *p2;
-            let determinant = duv02[0] * duv12[1] - duv02[1] * duv12[0];
-            let degenerate_uv = determinant.abs() < 1e-8;
-            if !degenerate_uv {
-                let inv_det = 1.0 / determinant;
-                dpdu = (duv12[1] * dp02 - duv02[1] * dp12) / inv_det;
-                dpdv = (-duv12[0] * dp02 + duv02[0] * dp12) / inv_det;
-            }
-            if degenerate_uv || dpdu.cross(&dpdv).length_squared() == 0.0 {
-                // handle zero determinant for triangle partial derivative matric
-                let (v1, v2) = coordinate_system(&(*p2 - *p0).cross(&(*p1 - *p0)).normalize());
-                dpdu = v1;
-                dpdv = v2;
-            }
-            // interpolate (u,v) parametric coordinates and hit point
             let p_hit = *p0 * b0 + *p1 * b1 + *p2 * b2;
             let uv_hit = uv[0] * b0 + uv[1] * b1 + uv[2] * b2;
-            let isect_local = SurfaceInteraction::new(
-                p_hit,
-                zero(),
-                uv_hit,
-                -ray.d,
-                dpdu,
-                dpdv,
-                zero(),
-                zero(),
-                self,
-            );
-            if let Some(ref alpha_mask) = self.mesh.alpha_mask {
-                if alpha_mask.evaluate(&isect_local) == 0.0 {
-                    return false;
-                }
-            }
-            if let Some(ref shadow_alpha_mask) = self.mesh.shadow_alpha_mask {
-                if shadow_alpha_mask.evaluate(&isect_local) == 0.0 {
-                    return false;
-                }
+            let isect_local = SurfaceInteraction::at_uv(p_hit, uv_hit, ray.time);
+            let mut masks = self
+                .mesh
+                .alpha_mask
+                .iter()
+                .chain(self.mesh.shadow_alpha_mask.iter());
+            if masks.any(|mask| mask.evaluate(&isect_local) == 0.0) {
+                return false;
             }
         }
 
@@ -607,7 +672,39 @@ impl Shape for Triangle {
         Bounds3f::union_point(&Bounds3f::from_points(&p0, &p1), &p2)
     }
 
-    fn sample(&self, u: Point2f) -> (Interaction, f32) {
+    fn split_world_bounds(&self, axis: Axis, pos: f32) -> (Bounds3f, Bounds3f) {
+        // Clip the triangle itself rather than its bounds, which matters for long diagonal
+        // triangles.
+        let mut below = Bounds3f::new();
+        let mut above = Bounds3f::new();
+        for i in 0..3 {
+            let a = self.mesh.p[self.v(i)];
+            let b = self.mesh.p[self.v((i + 1) % 3)];
+            if a[axis] <= pos {
+                below.extend(a);
+            }
+            if a[axis] >= pos {
+                above.extend(a);
+            }
+            if (a[axis] < pos && pos < b[axis]) || (b[axis] < pos && pos < a[axis]) {
+                // Pad the clipped vertex by its rounding error so that the bounds stay
+                // conservative
+                let t = (pos - a[axis]) / (b[axis] - a[axis]);
+                let p = a + (b - a) * t;
+                let err = Vector3f::new(p.x.abs(), p.y.abs(), p.z.abs()) * gamma(4);
+                let (mut p_min, mut p_max) = (p - err, p + err);
+                p_min[axis] = pos;
+                p_max[axis] = pos;
+                for p in &[p_min, p_max] {
+                    below.extend(*p);
+                    above.extend(*p);
+                }
+            }
+        }
+        (below, above)
+    }
+
+    fn sample(&self, u: Point2f, time: f32) -> (Interaction, f32) {
         let b = sampling::uniform_sample_triangle(u);
         let p0 = &self.mesh.p[self.v(0)];
         let p1 = &self.mesh.p[self.v(1)];
@@ -619,7 +716,9 @@ impl Shape for Triangle {
         // Ensure correct orientation of the geometric normal; follow the same
         // approach as was used in Triangle::intersect().
         if let Some(n) = self.mesh.n.as_ref() {
-            let ns = b[0] * n[self.v(0)] + b[1] * n[self.v(1)] + (1.0 - b[0] - b[1]) * n[self.v(2)];
+            let ns = b[0] * n[self.n_index(0)]
+                + b[1] * n[self.n_index(1)]
+                + (1.0 - b[0] - b[1]) * n[self.n_index(2)];
             normal = geometry::face_forward_n(&normal, &ns);
         } else if self.reverse_orientation ^ self.swaps_handedness {
             normal *= -1.0;
@@ -628,7 +727,7 @@ impl Shape for Triangle {
         // Compute error bounds for sampled point on triangle
         let p_abs_sum = (b[0] * *p0).abs() + (b[1] * *p1).abs() + ((1.0 - b[0] - b[1]) * *p2).abs();
         let p_error = gamma(6) * p_abs_sum;
-        let it = Interaction::new(p, Vector3f::from(p_error), zero(), normal);
+        let it = Interaction::new(p, Vector3f::from(p_error), time, zero(), normal);
 
         (it, 1.0 / self.area())
     }
@@ -637,6 +736,26 @@ impl Shape for Triangle {
         self.reverse_orientation
     }
 
+    fn uv_triangle(&self) -> Option<UvTriangle> {
+        let uv = self.mesh.uv.as_ref().map(|_| self.get_uvs())?;
+        let p = [
+            self.mesh.p[self.v(0)],
+            self.mesh.p[self.v(1)],
+            self.mesh.p[self.v(2)],
+        ];
+        // Orient the normals like Triangle::intersect() does
+        let n = if let Some(ref n) = self.mesh.n {
+            [n[self.n_index(0)], n[self.n_index(1)], n[self.n_index(2)]]
+        } else {
+            let mut normal = Normal3f::from((p[0] - p[2]).cross(&(p[1] - p[2])).normalize());
+            if self.reverse_orientation ^ self.swaps_handedness {
+                normal = -normal;
+            }
+            [normal; 3]
+        };
+        Some(UvTriangle { uv, p, n })
+    }
+
     fn transform_swaps_handedness(&self) -> bool {
         self.swaps_handedness
     }
@@ -650,22 +769,59 @@ pub fn create_triangle_mesh(
     s: Option<&[Vector3f]>,
     n: Option<&[Normal3f]>,
     uv: Option<&[Point2f]>,
-    alpha_mask: Option<Arc<TextureFloat>>,
-    shadow_alpha_mask: Option<Arc<TextureFloat>>,
-) -> Vec<Arc<dyn Shape>> {
+    attribute_indices: &AttributeIndices,
+    alpha_mask: Option<TextureRef<f32>>,
+    shadow_alpha_mask: Option<TextureRef<f32>>,
+    backface_cull: bool,
+) -> Vec<ShapeRef> {
+    // Attributes with invalid indices are discarded: they can't be indexed by the vertex indices
+    // either
+    let n_indices = attribute_indices.n.as_deref().filter(|ni| {
+        valid_attribute_indices("normal", ni, vertex_indices.len(), n.map_or(0, <[_]>::len))
+    });
+    let n = n.filter(|_| n_indices.is_some() || attribute_indices.n.is_none());
+    let uv_indices = attribute_indices.uv.as_deref().filter(|ui| {
+        valid_attribute_indices("uv", ui, vertex_indices.len(), uv.map_or(0, <[_]>::len))
+    });
+    let uv = uv.filter(|_| uv_indices.is_some() || attribute_indices.uv.is_none());
+
+    let triangles = valid_triangles(vertex_indices, p);
+    let attribute_indices = AttributeIndices {
+        n: n_indices.map(|ni| triangle_indices(ni, &triangles)),
+        uv: uv_indices.map(|ui| triangle_indices(ui, &triangles)),
+    };
+    let vertex_indices = triangle_indices(vertex_indices, &triangles);
+    let n = n.map(|n| fix_normals(n, attribute_indices.n.as_deref(), &vertex_indices, p));
+    let s = s.filter(|s| {
+        let valid = s.len() >= p.len() && s.iter().all(|v| is_finite(v.x, v.y, v.z));
+        if !valid {
+            warn!("Invalid \"S\" values for triangle mesh. Discarding them.");
+        }
+        valid
+    });
+    let uv = uv.filter(|uv| {
+        let valid = (uv_indices.is_some() || uv.len() >= p.len())
+            && uv.iter().all(|t| t.x.is_finite() && t.y.is_finite());
+        if !valid {
+            warn!("Invalid \"uv\" values for triangle mesh. Discarding them.");
+        }
+        valid
+    });
     let mesh = Arc::new(TriangleMesh::new(
         object_to_world,
-        vertex_indices,
+        &vertex_indices,
         p,
         s,
-        n,
+        n.as_deref(),
         uv,
+        attribute_indices,
         alpha_mask,
         shadow_alpha_mask,
+        backface_cull,
     ));
 
     let n_triangles = vertex_indices.len() / 3;
-    let mut tris: Vec<Arc<dyn Shape>> = Vec::with_capacity(n_triangles);
+    let mut tris: Vec<ShapeRef> = Vec::with_capacity(n_triangles);
 
     for i in 0..n_triangles {
         tris.push(Arc::new(Triangle::new(
@@ -677,3 +833,362 @@ pub fn create_triangle_mesh(
 
     tris
 }
+
+fn is_finite(x: f32, y: f32, z: f32) -> bool {
+    x.is_finite() && y.is_finite() && z.is_finite()
+}
+
+/// Check that the indices of an attribute have one index per vertex index and that they
+/// reference values that exist.
+fn valid_attribute_indices(
+    name: &str,
+    indices: &[usize],
+    n_vertex_indices: usize,
+    n_values: usize,
+) -> bool {
+    if indices.len() != n_vertex_indices {
+        warn!(
+            "Number of {} indices for triangle mesh must match the vertex indices (expected {}, got {}). Discarding the {}s.",
+            name,
+            n_vertex_indices,
+            indices.len(),
+            name
+        );
+        false
+    } else if indices.iter().any(|&i| i >= n_values) {
+        warn!(
+            "{} indices out of range for triangle mesh (mesh has {} {}s). Discarding the {}s.",
+            name, n_values, name, name
+        );
+        false
+    } else {
+        true
+    }
+}
+
+/// Return the numbers of the triangles of the mesh that can be rendered, skipping those that
+/// reference vertices that don't exist or whose positions are NaN or infinite (which would
+/// otherwise poison the bounds of the BVH).
+fn valid_triangles(vertex_indices: &[usize], p: &[Point3f]) -> Vec<usize> {
+    if !vertex_indices.len().is_multiple_of(3) {
+        warn!(
+            "Number of vertex indices {} for triangle mesh isn't a multiple of 3. Ignoring the last {}.",
+            vertex_indices.len(),
+            vertex_indices.len() % 3
+        );
+    }
+    let mut out_of_range = 0;
+    let mut non_finite = 0;
+    let mut triangles = Vec::with_capacity(vertex_indices.len() / 3);
+    for (t, tri) in vertex_indices.chunks_exact(3).enumerate() {
+        if tri.iter().any(|&i| i >= p.len()) {
+            out_of_range += 1;
+        } else if tri.iter().any(|&i| !is_finite(p[i].x, p[i].y, p[i].z)) {
+            non_finite += 1;
+        } else {
+            triangles.push(t);
+        }
+    }
+    if out_of_range > 0 {
+        warn!(
+            "Skipping {} triangles with vertex indices out of range (mesh has {} vertices)",
+            out_of_range,
+            p.len()
+        );
+    }
+    if non_finite > 0 {
+        warn!(
+            "Skipping {} triangles with NaN or infinite vertex positions",
+            non_finite
+        );
+    }
+    for _ in 0..(out_of_range + non_finite) {
+        n_invalid_triangles::inc();
+    }
+    triangles
+}
+
+/// The indices of the given triangles, 3 per triangle.
+fn triangle_indices(indices: &[usize], triangles: &[usize]) -> Vec<usize> {
+    triangles
+        .iter()
+        .flat_map(|&t| &indices[3 * t..3 * t + 3])
+        .copied()
+        .collect()
+}
+
+/// Replace the normals that are NaN, infinite or of zero length by the area-weighted average of
+/// the geometric normals of the triangles sharing that normal. The normals are indexed by
+/// `n_indices` if given, or else by the vertex indices.
+fn fix_normals(
+    n: &[Normal3f],
+    n_indices: Option<&[usize]>,
+    vertex_indices: &[usize],
+    p: &[Point3f],
+) -> Vec<Normal3f> {
+    let mut n = n.to_vec();
+    if n_indices.is_none() && n.len() < p.len() {
+        warn!(
+            "Not enough normals for triangle mesh (expected {}, got {}). Computing the missing ones.",
+            p.len(),
+            n.len()
+        );
+        n.resize(p.len(), Normal3f::new(0.0, 0.0, 0.0));
+    }
+    let is_valid = |n: &Normal3f| is_finite(n.x, n.y, n.z) && n.length_squared() > 0.0;
+    let n_invalid = n.iter().filter(|n| !is_valid(n)).count();
+    if n_invalid == 0 {
+        return n;
+    }
+
+    warn!(
+        "Replacing {} NaN, infinite or zero-length normals of triangle mesh",
+        n_invalid
+    );
+    let mut face_normals = vec![Vector3f::new(0.0, 0.0, 0.0); n.len()];
+    let n_indices = n_indices.unwrap_or(vertex_indices);
+    for (tri, tri_n) in vertex_indices
+        .chunks_exact(3)
+        .zip(n_indices.chunks_exact(3))
+    {
+        // Not normalized, so that larger triangles weigh more
+        let face_n = (p[tri[1]] - p[tri[0]]).cross(&(p[tri[2]] - p[tri[0]]));
+        for &i in tri_n {
+            face_normals[i] += face_n;
+        }
+    }
+    for (normal, face_n) in n.iter_mut().zip(face_normals) {
+        if !is_valid(normal) {
+            *normal = if face_n.length_squared() > 0.0 {
+                Normal3f::from(face_n.normalize())
+            } else {
+                // Vertex not used by any (non-degenerate) triangle: any normal will do
+                Normal3f::new(0.0, 0.0, 1.0)
+            };
+        }
+    }
+    n
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::texture::Texture;
+
+    #[test]
+    fn test_invalid_triangles_are_skipped() {
+        let p = [
+            Point3f::new(0.0, 0.0, 0.0),
+            Point3f::new(1.0, 0.0, 0.0),
+            Point3f::new(0.0, 1.0, 0.0),
+            Point3f::new(f32::NAN, 0.0, 0.0),
+        ];
+        // One good triangle, one with a NaN vertex, one out of range, and a stray index
+        let indices = [0, 1, 2, 0, 3, 1, 0, 1, 4, 2];
+        let triangles = valid_triangles(&indices, &p);
+        assert_eq!(triangles, vec![0]);
+        assert_eq!(triangle_indices(&indices, &triangles), vec![0, 1, 2]);
+
+        let shapes = create_triangle_mesh(
+            &Transform::default(),
+            false,
+            &indices,
+            &p,
+            None,
+            None,
+            None,
+            &AttributeIndices::default(),
+            None,
+            None,
+            false,
+        );
+        assert_eq!(shapes.len(), 1);
+        assert!(shapes[0].world_bounds().p_max.x.is_finite());
+    }
+
+    #[test]
+    fn test_invalid_normals_are_recomputed() {
+        let p = [
+            Point3f::new(0.0, 0.0, 0.0),
+            Point3f::new(1.0, 0.0, 0.0),
+            Point3f::new(0.0, 1.0, 0.0),
+        ];
+        let n = [
+            Normal3f::new(0.0, 0.0, 0.0),
+            Normal3f::new(0.0, f32::INFINITY, 0.0),
+            Normal3f::new(0.0, 0.6, 0.8),
+        ];
+        let fixed = fix_normals(&n, None, &[0, 1, 2], &p);
+        assert_eq!(fixed[0], Normal3f::new(0.0, 0.0, 1.0));
+        assert_eq!(fixed[1], Normal3f::new(0.0, 0.0, 1.0));
+        assert_eq!(fixed[2], n[2]);
+    }
+
+    #[test]
+    fn test_uv_triangle() {
+        let p = [
+            Point3f::new(0.0, 0.0, 0.0),
+            Point3f::new(2.0, 0.0, 0.0),
+            Point3f::new(0.0, 2.0, 0.0),
+        ];
+        let uv = [
+            Point2f::new(0.0, 0.0),
+            Point2f::new(1.0, 0.0),
+            Point2f::new(0.0, 1.0),
+        ];
+        let create = |uv: Option<&[Point2f]>, reverse_orientation| {
+            create_triangle_mesh(
+                &Transform::default(),
+                reverse_orientation,
+                &[0, 1, 2],
+                &p,
+                None,
+                None,
+                uv,
+                &AttributeIndices::default(),
+                None,
+                None,
+                false,
+            )
+        };
+        assert!(create(None, false)[0].uv_triangle().is_none());
+
+        let tri = create(Some(&uv), false)[0].uv_triangle().unwrap();
+        let (hit, p_error, n) = tri.lookup(Point2f::new(0.25, 0.5)).unwrap();
+        assert!((hit - Point3f::new(0.5, 1.0, 0.0)).length() < 1e-6);
+        assert!(p_error.x >= 0.0 && p_error.length() < 1e-5);
+        assert_eq!(n, Normal3f::new(0.0, 0.0, 1.0));
+        assert!(tri.lookup(Point2f::new(0.75, 0.5)).is_none());
+
+        let tri = create(Some(&uv), true)[0].uv_triangle().unwrap();
+        assert_eq!(tri.n[0], Normal3f::new(0.0, 0.0, -1.0));
+    }
+
+    #[test]
+    fn test_indexed_attributes() {
+        let p = [
+            Point3f::new(0.0, 0.0, 0.0),
+            Point3f::new(1.0, 0.0, 0.0),
+            Point3f::new(1.0, 1.0, 0.0),
+            Point3f::new(0.0, 1.0, 0.0),
+        ];
+        // A single normal shared by all the vertices, and a uv seam along the diagonal: each
+        // triangle maps to its own half of the texture
+        let n = [Normal3f::new(0.0, 0.0, -1.0)];
+        let uv = [
+            Point2f::new(0.0, 0.0),
+            Point2f::new(0.5, 0.0),
+            Point2f::new(0.5, 1.0),
+            Point2f::new(0.5, 0.0),
+            Point2f::new(1.0, 1.0),
+            Point2f::new(0.5, 1.0),
+        ];
+        let indices = [0, 1, 2, 0, 2, 3];
+        let quad = |attribute_indices: AttributeIndices| {
+            create_triangle_mesh(
+                &Transform::default(),
+                false,
+                &indices,
+                &p,
+                None,
+                Some(&n),
+                Some(&uv),
+                &attribute_indices,
+                None,
+                None,
+                false,
+            )
+        };
+        let hit = |tris: &[ShapeRef], x: f32, y: f32| {
+            let ray = Ray::new(Point3f::new(x, y, -1.0), Vector3f::new(0.0, 0.0, 1.0));
+            tris.iter().find_map(|t| t.intersect(&ray)).unwrap().0
+        };
+
+        let tris = quad(AttributeIndices {
+            n: Some(vec![0; 6]),
+            uv: Some(vec![0, 1, 2, 3, 4, 5]),
+        });
+        let isect = hit(&tris, 0.75, 0.25);
+        assert_eq!(isect.shading.n, n[0]);
+        assert!((isect.uv - Point2f::new(0.375, 0.25)).length() < 1e-5);
+        let isect = hit(&tris, 0.25, 0.75);
+        assert!((isect.uv - Point2f::new(0.625, 0.75)).length() < 1e-5);
+
+        // Attributes with invalid indices are dropped
+        let tris = quad(AttributeIndices {
+            n: Some(vec![0; 5]),
+            uv: Some(vec![0, 1, 2, 3, 4, 6]),
+        });
+        assert!(tris[0].uv_triangle().is_none());
+        let isect = hit(&tris, 0.75, 0.25);
+        assert_eq!(isect.shading.n, Normal3f::new(0.0, 0.0, 1.0));
+    }
+
+    /// Mask cutting out the half of a unit quad with u < 0.5
+    #[derive(Debug)]
+    struct HalfMask;
+
+    impl Texture<f32> for HalfMask {
+        fn evaluate(&self, si: &SurfaceInteraction) -> f32 {
+            if si.uv.x < 0.5 {
+                0.0
+            } else {
+                1.0
+            }
+        }
+    }
+
+    #[test]
+    fn test_shadow_alpha_cutout() {
+        let p = [
+            Point3f::new(0.0, 0.0, 0.0),
+            Point3f::new(1.0, 0.0, 0.0),
+            Point3f::new(1.0, 1.0, 0.0),
+            Point3f::new(0.0, 1.0, 0.0),
+        ];
+        let uv = [
+            Point2f::new(0.0, 0.0),
+            Point2f::new(1.0, 0.0),
+            Point2f::new(1.0, 1.0),
+            Point2f::new(0.0, 1.0),
+        ];
+        let indices = [0, 1, 2, 0, 2, 3];
+        let quad = |alpha: Option<TextureRef<f32>>, shadow_alpha: Option<TextureRef<f32>>| {
+            create_triangle_mesh(
+                &Transform::default(),
+                false,
+                &indices,
+                &p,
+                None,
+                None,
+                Some(&uv),
+                &AttributeIndices::default(),
+                alpha,
+                shadow_alpha,
+                false,
+            )
+        };
+        let occluded = |tris: &[ShapeRef], x: f32| {
+            let ray = Ray::new(Point3f::new(x, 0.6, -1.0), Vector3f::new(0.0, 0.0, 1.0));
+            tris.iter().any(|t| t.intersect_p(&ray))
+        };
+
+        let solid = quad(None, None);
+        assert!(occluded(&solid, 0.25));
+        assert!(occluded(&solid, 0.75));
+        // Both masks cut out shadow rays, but only the alpha mask cuts out camera rays
+        for (alpha, shadow_alpha) in [
+            (Some(Arc::new(HalfMask)), None),
+            (None, Some(Arc::new(HalfMask))),
+        ] {
+            let alpha = alpha.map(|a| a as TextureRef<f32>);
+            let shadow_alpha = shadow_alpha.map(|a| a as TextureRef<f32>);
+            let cutout = quad(alpha.clone(), shadow_alpha);
+            assert!(!occluded(&cutout, 0.25));
+            assert!(occluded(&cutout, 0.75));
+            let ray = Ray::new(Point3f::new(0.25, 0.6, -1.0), Vector3f::new(0.0, 0.0, 1.0));
+            let hit = cutout.iter().any(|t| t.intersect(&ray).is_some());
+            assert_eq!(hit, alpha.is_none());
+        }
+    }
+}
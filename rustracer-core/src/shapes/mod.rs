@@ -1,28 +1,36 @@
 use std::fmt::Debug;
+use std::sync::Arc;
+
+use crate::bounds::{Axis, Bounds2f, Bounds3f};
+use log::warn;
 
-use crate::bounds::Bounds3f;
 use crate::geometry;
 use crate::interaction::{Interaction, SurfaceInteraction};
+use crate::paramset::ParamSet;
 use crate::ray::Ray;
-use crate::{Point2f, Vector3f};
+use crate::{gamma, Normal3f, Point2f, Point3f, Vector2f, Vector3f};
 
 mod cylinder;
 mod disk;
+pub mod hairfile;
 mod mesh;
 pub mod plymesh;
 mod sphere;
 
 pub use self::cylinder::Cylinder;
 pub use self::disk::Disk;
-pub use self::mesh::{Triangle, TriangleMesh};
+pub use self::mesh::{AttributeIndices, Triangle, TriangleMesh};
 pub use self::sphere::Sphere;
 
 pub fn init_stats() {
     mesh::init_stats();
 }
 
+/// Shared reference to a shape, e.g. from the primitives and area lights using it.
+pub type ShapeRef = Arc<dyn Shape>;
+
 pub trait Shape: Debug + Send + Sync {
-    fn intersect(&self, ray: &Ray) -> Option<(SurfaceInteraction<'_, '_>, f32)>;
+    fn intersect(&self, ray: &Ray) -> Option<(SurfaceInteraction, f32)>;
 
     fn intersect_p(&self, ray: &Ray) -> bool {
         self.intersect(ray).is_some()
@@ -34,10 +42,27 @@ pub trait Shape: Debug + Send + Sync {
 
     fn world_bounds(&self) -> Bounds3f;
 
-    fn sample(&self, u: Point2f) -> (Interaction, f32);
+    /// Bounds of the parts of the shape on either side of the plane orthogonal to `axis` at
+    /// `pos`, used to build BVHs with spatial splits. The default implementation just clips the
+    /// world bounds.
+    fn split_world_bounds(&self, axis: Axis, pos: f32) -> (Bounds3f, Bounds3f) {
+        split_bounds(&self.world_bounds(), axis, pos)
+    }
 
+    /// World space vertices of the shape if it is a triangle, e.g. to export the scene's geometry
+    /// (see the `geometrydump` module).
+    fn triangle_vertices(&self) -> Option<[Point3f; 3]> {
+        None
+    }
+
+    /// Sample a point uniformly on the surface of the shape, returning a pdf with respect to
+    /// area. The returned interaction happens at the given `time`.
+    fn sample(&self, u: Point2f, time: f32) -> (Interaction, f32);
+
+    /// Sample a point on the shape as seen from the reference point `si`, returning a pdf with
+    /// respect to solid angle. The returned interaction happens at the same time as `si`.
     fn sample_si(&self, si: &Interaction, u: Point2f) -> (Interaction, f32) {
-        let (intr, mut pdf) = self.sample(u);
+        let (intr, mut pdf) = self.sample(u, si.time);
         let mut wi = intr.p - si.p;
         if wi.length_squared() == 0.0 {
             pdf = 0.0;
@@ -70,4 +95,287 @@ pub trait Shape: Debug + Send + Sync {
     fn reverse_orientation(&self) -> bool;
 
     fn transform_swaps_handedness(&self) -> bool;
+
+    /// Whether the normals given by the cross product of the partial derivatives must be flipped
+    /// to face the side of the shape that is considered outside. Both the normals of
+    /// intersections and of sampled points are flipped the same way, so that one-sided area
+    /// lights emit on the same side however they are reached.
+    fn flip_normals(&self) -> bool {
+        self.reverse_orientation() ^ self.transform_swaps_handedness()
+    }
+
+    /// The shape as a triangle in texture space, if it is a triangle with texture coordinates.
+    /// This is used to bake textures (see `camera::BakeCamera`).
+    fn uv_triangle(&self) -> Option<UvTriangle> {
+        None
+    }
+}
+
+/// A triangle along with the texture coordinates and normals of its vertices.
+#[derive(Debug, Clone, Copy)]
+pub struct UvTriangle {
+    pub uv: [Point2f; 3],
+    pub p: [Point3f; 3],
+    /// Normals at the vertices, on the side of the surface normal
+    pub n: [Normal3f; 3],
+}
+
+impl UvTriangle {
+    pub fn uv_bounds(&self) -> Bounds2f {
+        let mut bounds = Bounds2f::from_points(&self.uv[0], &self.uv[1]);
+        bounds.extend(self.uv[2]);
+        bounds
+    }
+
+    /// The point of the triangle with the given texture coordinates, if the triangle covers
+    /// them. Returns the point, its error bounds and the (normalized) interpolated normal there.
+    pub fn lookup(&self, uv: Point2f) -> Option<(Point3f, Vector3f, Normal3f)> {
+        let cross = |a: Vector2f, b: Vector2f| a.x * b.y - a.y * b.x;
+        let (e1, e2) = (self.uv[1] - self.uv[0], self.uv[2] - self.uv[0]);
+        let det = cross(e1, e2);
+        if det == 0.0 {
+            return None;
+        }
+        let d = uv - self.uv[0];
+        let b1 = cross(d, e2) / det;
+        let b2 = cross(e1, d) / det;
+        let b0 = 1.0 - b1 - b2;
+        if b0 < 0.0 || b1 < 0.0 || b2 < 0.0 {
+            return None;
+        }
+
+        let [p0, p1, p2] = self.p;
+        let p = p0 * b0 + p1 * b1 + p2 * b2;
+        let p_abs_sum = (b0 * p0).abs() + (b1 * p1).abs() + (b2 * p2).abs();
+        let p_error = gamma(7) * Vector3f::from(p_abs_sum);
+        let n = (self.n[0] * b0 + self.n[1] * b1 + self.n[2] * b2).normalize();
+        Some((p, p_error, n))
+    }
+}
+
+/// Affine transform of the (u, v) parameterization of a quadric, given by `"float uscale"`,
+/// `"vscale"`, `"udelta"` and `"vdelta"` on the Shape directive, so that textures can be tiled
+/// per shape rather than per texture.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct UvTransform {
+    pub su: f32,
+    pub sv: f32,
+    pub du: f32,
+    pub dv: f32,
+}
+
+impl Default for UvTransform {
+    fn default() -> Self {
+        UvTransform {
+            su: 1.0,
+            sv: 1.0,
+            du: 0.0,
+            dv: 0.0,
+        }
+    }
+}
+
+impl UvTransform {
+    pub fn from_params(ps: &ParamSet) -> UvTransform {
+        // Negative scales would flip the orientation of the surface
+        let scale = |name: &str| {
+            let s = ps.find_one_float(name, 1.0);
+            if s > 0.0 {
+                s
+            } else {
+                warn!("\"{}\" must be positive. Ignoring {}.", name, s);
+                1.0
+            }
+        };
+        UvTransform {
+            su: scale("uscale"),
+            sv: scale("vscale"),
+            du: ps.find_one_float("udelta", 0.0),
+            dv: ps.find_one_float("vdelta", 0.0),
+        }
+    }
+
+    /// Transform the parametric coordinates `uv` of a point, along with the partial derivatives
+    /// of the position and normal there. The derivatives are with respect to the new
+    /// coordinates, so that texture filtering stays consistent with the scaled coordinates.
+    pub fn apply(
+        &self,
+        uv: Point2f,
+        dpdu: Vector3f,
+        dpdv: Vector3f,
+        dndu: Normal3f,
+        dndv: Normal3f,
+    ) -> (Point2f, Vector3f, Vector3f, Normal3f, Normal3f) {
+        (
+            Point2f::new(self.su * uv.x + self.du, self.sv * uv.y + self.dv),
+            dpdu / self.su,
+            dpdv / self.sv,
+            dndu / self.su,
+            dndv / self.sv,
+        )
+    }
+}
+
+/// Clip `bounds` against the plane orthogonal to `axis` at `pos`, returning the parts below and
+/// above the plane.
+/// Partial derivatives of the unit normal of a parametric surface (oriented like
+/// `dpdu x dpdv`), computed from its first and second derivatives with the Weingarten equations.
+#[allow(non_snake_case)]
+pub fn weingarten(
+    dpdu: &Vector3f,
+    dpdv: &Vector3f,
+    d2Pduu: &Vector3f,
+    d2Pduv: &Vector3f,
+    d2Pdvv: &Vector3f,
+) -> (Normal3f, Normal3f) {
+    // Coefficients of the first and second fundamental forms
+    let E = dpdu.dot(dpdu);
+    let F = dpdu.dot(dpdv);
+    let G = dpdv.dot(dpdv);
+    let N = dpdu.cross(dpdv).normalize();
+    let e = N.dot(d2Pduu);
+    let f = N.dot(d2Pduv);
+    let g = N.dot(d2Pdvv);
+
+    let inv_EGF2 = 1.0 / (E * G - F * F);
+    let dndu =
+        Normal3f::from((f * F - e * G) * inv_EGF2 * *dpdu + (e * F - f * E) * inv_EGF2 * *dpdv);
+    let dndv =
+        Normal3f::from((g * F - f * G) * inv_EGF2 * *dpdu + (f * F - g * E) * inv_EGF2 * *dpdv);
+    (dndu, dndv)
+}
+
+pub fn split_bounds(bounds: &Bounds3f, axis: Axis, pos: f32) -> (Bounds3f, Bounds3f) {
+    let mut below = *bounds;
+    let mut above = *bounds;
+    below.p_max[axis] = below.p_max[axis].min(pos);
+    above.p_min[axis] = above.p_min[axis].max(pos);
+    (below, above)
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use crate::api::{Array, ParamListEntry, ParamType};
+    use crate::transform::Transform;
+
+    #[test]
+    fn test_uv_transform() {
+        let mut ps = ParamSet::default();
+        ps.init(vec![
+            ParamListEntry::new(
+                ParamType::Float,
+                "uscale".to_owned(),
+                Array::NumArray(vec![4.0]),
+            ),
+            ParamListEntry::new(
+                ParamType::Float,
+                "vdelta".to_owned(),
+                Array::NumArray(vec![0.5]),
+            ),
+        ]);
+        let plain = Sphere::create(&Transform::default(), false, &ParamSet::default());
+        let tiled = Sphere::create(&Transform::default(), false, &ps);
+
+        let ray = Ray::new(Point3f::new(2.0, 1.0, 5.0), Vector3f::new(-0.4, -0.2, -1.0));
+        let (si, _) = plain.intersect(&ray).unwrap();
+        let (tiled_si, _) = tiled.intersect(&ray).unwrap();
+        assert_eq!(tiled_si.hit.p, si.hit.p);
+        assert_eq!(tiled_si.hit.n, si.hit.n);
+        assert!((tiled_si.uv.x - 4.0 * si.uv.x).abs() < 1e-5);
+        assert!((tiled_si.uv.y - (si.uv.y + 0.5)).abs() < 1e-5);
+        assert!((tiled_si.dpdu * 4.0 - si.dpdu).length() < 1e-4);
+        assert_eq!(tiled_si.dpdv, si.dpdv);
+    }
+
+    #[test]
+    fn test_sampled_normals_match_intersections() {
+        let mirror = Transform::scale(-1.0, 1.0, 1.0);
+        let transforms = [Transform::default(), mirror];
+        for (t, reverse_orientation) in transforms.iter().flat_map(|t| [(t, false), (t, true)]) {
+            let shapes: Vec<ShapeRef> = vec![
+                Sphere::create(t, reverse_orientation, &ParamSet::default()),
+                Disk::create(t, reverse_orientation, &ParamSet::default()),
+                Cylinder::create(t, reverse_orientation, &ParamSet::default()),
+            ];
+            for shape in shapes {
+                let (it, _) = shape.sample(Point2f::new(0.3, 0.7), 0.0);
+                // Shoot a ray back at the sampled point from the side its normal points to
+                let n = Vector3f::from(it.n);
+                let ray = Ray::new(it.p + 0.01 * n, -n);
+                let (si, _) = shape.intersect(&ray).unwrap();
+                assert!(
+                    (si.hit.p - it.p).length() < 1e-3,
+                    "{:?}: {} vs {}",
+                    shape,
+                    si.hit.p,
+                    it.p
+                );
+                assert!(
+                    si.hit.n.dotn(&it.n) > 0.99,
+                    "{:?}: {:?} vs {:?}",
+                    shape,
+                    si.hit.n,
+                    it.n
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn test_normal_derivatives_match_finite_differences() {
+        let mut ps = ParamSet::default();
+        ps.init(vec![
+            ParamListEntry::new(
+                ParamType::Float,
+                "zmin".to_owned(),
+                Array::NumArray(vec![-0.5]),
+            ),
+            ParamListEntry::new(
+                ParamType::Float,
+                "zmax".to_owned(),
+                Array::NumArray(vec![0.8]),
+            ),
+            ParamListEntry::new(
+                ParamType::Float,
+                "phimax".to_owned(),
+                Array::NumArray(vec![270.0]),
+            ),
+            ParamListEntry::new(
+                ParamType::Float,
+                "uscale".to_owned(),
+                Array::NumArray(vec![2.0]),
+            ),
+        ]);
+        let shapes: Vec<ShapeRef> = vec![
+            Sphere::create(&Transform::default(), false, &ps),
+            Cylinder::create(&Transform::default(), false, &ps),
+        ];
+        for shape in shapes {
+            let hit = |o: Point3f| {
+                let ray = Ray::new(o, Point3f::new(0.0, 0.0, o.z) - o);
+                shape.intersect(&ray).unwrap().0
+            };
+            let si = hit(Point3f::new(3.0, 1.0, 0.2));
+            // Small steps in different directions around the first hit point
+            for o in &[
+                Point3f::new(3.0, 1.01, 0.2),
+                Point3f::new(3.0, 1.0, 0.21),
+                Point3f::new(3.0, 1.01, 0.19),
+            ] {
+                let si2 = hit(*o);
+                let (du, dv) = (si2.uv.x - si.uv.x, si2.uv.y - si.uv.y);
+                let expected = si.dndu * du + si.dndv * dv;
+                let dn = si2.hit.n - si.hit.n;
+                assert!(
+                    (dn - expected).length() < 0.05 * dn.length().max(1e-3),
+                    "{:?}: dn={:?}, dndu*du + dndv*dv={:?}",
+                    shape,
+                    dn,
+                    expected
+                );
+            }
+        }
+    }
 }
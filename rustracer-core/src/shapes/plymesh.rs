@@ -9,9 +9,9 @@ use ply_rs::parser;
 use ply_rs::ply;
 
 use crate::paramset::ParamSet;
-use crate::shapes::mesh::create_triangle_mesh;
-use crate::shapes::Shape;
-use crate::texture::{ConstantTexture, Texture};
+use crate::shapes::mesh::{create_triangle_mesh, AttributeIndices};
+use crate::shapes::ShapeRef;
+use crate::texture::{ConstantTexture, TextureRef};
 use crate::transform::Transform;
 use crate::{Normal3f, Point2f, Point3f};
 
@@ -20,10 +20,16 @@ pub fn create<S: BuildHasher>(
     _w2o: &Transform,
     reverse_orientation: bool,
     params: &ParamSet,
-    float_textures: &HashMap<String, Arc<dyn Texture<f32>>, S>,
-) -> Vec<Arc<dyn Shape>> {
+    float_textures: &HashMap<String, TextureRef<f32>, S>,
+) -> Vec<ShapeRef> {
     let filename = params.find_one_filename("filename", "".into());
-    let f = File::open(&filename).unwrap();
+    let f = match File::open(&filename) {
+        Ok(f) => f,
+        Err(e) => {
+            error!("Couldn't open PLY file \"{}\": {}", filename, e);
+            return Vec::new();
+        }
+    };
     let mut f = BufReader::new(f);
 
     // create a parser
@@ -172,8 +178,10 @@ pub fn create<S: BuildHasher>(
         None,
         if has_normals { Some(&n) } else { None },
         if has_texture { Some(&uv) } else { None },
+        &AttributeIndices::default(),
         alpha_mask,
         shadow_alpha_mask,
+        params.find_one_bool("backfacecull", false),
     )
 }
 
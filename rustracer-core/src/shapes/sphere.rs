@@ -9,7 +9,7 @@ use crate::interaction::{Interaction, SurfaceInteraction};
 use crate::paramset::ParamSet;
 use crate::ray::Ray;
 use crate::sampling::{uniform_cone_pdf, uniform_sample_sphere};
-use crate::shapes::Shape;
+use crate::shapes::{weingarten, Shape, ShapeRef, UvTransform};
 use crate::{clamp, coordinate_system, gamma, Normal3f, Point2f, Point3f, Transform, Vector3f};
 
 #[derive(Debug)]
@@ -24,6 +24,7 @@ pub struct Sphere {
     phi_max: f32,
     reverse_orientation: bool,
     transform_swaps_handedness: bool,
+    uv_transform: UvTransform,
 }
 
 impl Sphere {
@@ -47,28 +48,24 @@ impl Sphere {
             phi_max: f32::to_radians(clamp(phi_max, 0.0, 360.0)),
             reverse_orientation,
             transform_swaps_handedness,
+            uv_transform: UvTransform::default(),
         }
     }
 
-    pub fn create(o2w: &Transform, reverse_orientation: bool, params: &ParamSet) -> Arc<dyn Shape> {
+    pub fn create(o2w: &Transform, reverse_orientation: bool, params: &ParamSet) -> ShapeRef {
         let radius = params.find_one_float("radius", 1.0);
         let zmin = params.find_one_float("zmin", -radius);
         let zmax = params.find_one_float("zmax", radius);
         let phimax = params.find_one_float("phimax", 360.0);
 
-        Arc::new(Sphere::new(
-            o2w.clone(),
-            radius,
-            zmin,
-            zmax,
-            phimax,
-            reverse_orientation,
-        ))
+        let mut sphere = Sphere::new(o2w.clone(), radius, zmin, zmax, phimax, reverse_orientation);
+        sphere.uv_transform = UvTransform::from_params(params);
+        Arc::new(sphere)
     }
 }
 
 impl Shape for Sphere {
-    fn intersect(&self, ray: &Ray) -> Option<(SurfaceInteraction<'_, '_>, f32)> {
+    fn intersect(&self, ray: &Ray) -> Option<(SurfaceInteraction, f32)> {
         // Transform ray into object space
         let (r, o_err, d_err) = ray.transform(&self.world_to_object);
 
@@ -130,7 +127,7 @@ impl Shape for Sphere {
                 if p_hit.x == 0.0 && p_hit.y == 0.0 {
                     p_hit.x = 1e-5 * self.radius;
                 }
-                phi = f32::atan2(p_hit.x, p_hit.y);
+                phi = f32::atan2(p_hit.y, p_hit.x);
                 if phi < 0.0 {
                     phi += 2.0 * consts::PI;
                 }
@@ -167,36 +164,16 @@ impl Shape for Sphere {
             let d2Pdvv = -(self.theta_max - self.theta_min)
                 * (self.theta_max - self.theta_min)
                 * Vector3f::new(p_hit.x, p_hit.y, p_hit.z);
-
-            // Compute coefficients for fundamental forms
-            let E = dpdu.dot(&dpdu);
-            let F = dpdu.dot(&dpdv);
-            let G = dpdv.dot(&dpdv);
-            let N = dpdu.cross(&dpdv).normalize();
-            let e = N.dot(&d2Pduu);
-            let f = N.dot(&d2Pduv);
-            let g = N.dot(&d2Pdvv);
-
-            // Compute dndu and dndv from fundamental from coefficients
-            let invEGF2 = 1.0 / (E * G - F * F);
-            let dndu =
-                Normal3f::from((f * F - e * G) * invEGF2 * dpdu + (e * F - f * E) * invEGF2 * dpdv);
-            let dndv =
-                Normal3f::from((g * F - f * G) * invEGF2 * dpdu + (f * F - g * E) * invEGF2 * dpdv);
+            let (dndu, dndv) = weingarten(&dpdu, &dpdv, &d2Pduu, &d2Pduv, &d2Pdvv);
 
             // Compute error bound for sphere intersection
             let p_error = gamma(5) * Vector3f::from(p_hit).abs();
 
+            let (uv, dpdu, dpdv, dndu, dndv) =
+                self.uv_transform
+                    .apply(Point2f::new(u, v), dpdu, dpdv, dndu, dndv);
             let isect = SurfaceInteraction::new(
-                p_hit,
-                p_error,
-                Point2f::new(u, v),
-                -r.d,
-                dpdu,
-                dpdv,
-                dndu,
-                dndv,
-                self,
+                p_hit, p_error, uv, r.time, -r.d, dpdu, dpdv, dndu, dndv, self,
             );
             Some((isect.transform(&self.object_to_world), t_shape_hit.into()))
         })
@@ -224,13 +201,17 @@ impl Shape for Sphere {
         bounds
     }
 
-    fn sample(&self, u: Point2f) -> (Interaction, f32) {
+    fn sample(&self, u: Point2f, time: f32) -> (Interaction, f32) {
         let mut p_obj = Point3f::new(0.0, 0.0, 0.0) + self.radius * uniform_sample_sphere(u);
         let mut it = Interaction::empty();
+        it.time = time;
         it.n = self
             .object_to_world
             .transform_normal(&Normal3f::new(p_obj.x, p_obj.y, p_obj.z))
             .normalize();
+        if self.flip_normals() {
+            it.n *= -1.0;
+        }
         p_obj = p_obj * self.radius / distance(&p_obj, &Point3f::new(0.0, 0.0, 0.0));
         let p_obj_error = gamma(5) * Vector3f::from(p_obj).abs();
         let (p, p_err) = self
@@ -248,7 +229,7 @@ impl Shape for Sphere {
         // Sample uniformly on sphere if `pt` is inside it
         let p_origin = offset_ray_origin(&si.p, &si.p_error, &si.n, &(p_center - si.p));
         if distance_squared(&p_origin, &p_center) <= self.radius * self.radius {
-            let (intr, mut pdf) = self.sample(u);
+            let (intr, mut pdf) = self.sample(u, si.time);
             let mut wi = intr.p - si.p;
             if wi.length_squared() == 0.0 {
                 pdf = 0.0;
@@ -294,10 +275,11 @@ impl Shape for Sphere {
 
         // Return `Interaction` for sampled point on sphere
         let mut it = Interaction::empty();
+        it.time = si.time;
         it.p = p_world;
         it.p_error = gamma(5) * Vector3f::from(p_world).abs();
         it.n = Normal3f::from(n_world);
-        if self.reverse_orientation {
+        if self.flip_normals() {
             it.n *= -1.0;
         }
 
@@ -1,6 +1,6 @@
 use std::convert::From;
 use std::f32;
-use std::fmt;
+use std::fmt::{self, Debug};
 use std::ops::{Add, AddAssign, Div, Index, IndexMut, Mul, MulAssign, Sub};
 
 use num::{One, Zero};
@@ -8,6 +8,82 @@ use num::{One, Zero};
 use crate::cie;
 use crate::{clamp, find_interval, lerp};
 
+/// Operations common to the representations of spectra (the RGB `Spectrum` for now, sampled
+/// spectra later), so that code that only needs these, like the MIP maps and image textures, can
+/// be generic over the representation. `f32` is a spectrum with a single coefficient, which lets
+/// the same code handle float textures.
+pub trait CoefficientSpectrum:
+    Copy + Debug + Zero + AddAssign + Mul<f32, Output = Self> + Div<f32, Output = Self> + Send + Sync
+{
+    /// Whether all the coefficients are zero.
+    fn is_black(&self) -> bool;
+
+    /// Coefficient-wise square root.
+    fn sqrt(&self) -> Self;
+
+    /// Clamp each coefficient to `[low, high]`.
+    fn clamp(&self, low: f32, high: f32) -> Self;
+
+    /// Largest coefficient.
+    fn max_component_value(&self) -> f32;
+
+    /// Luminance.
+    fn y(&self) -> f32;
+
+    /// Linear interpolation between `s1` (for `t = 0`) and `s2` (for `t = 1`).
+    fn lerp(t: f32, s1: Self, s2: Self) -> Self {
+        s1 * (1.0 - t) + s2 * t
+    }
+}
+
+impl CoefficientSpectrum for f32 {
+    fn is_black(&self) -> bool {
+        *self == 0.0
+    }
+
+    fn sqrt(&self) -> f32 {
+        f32::sqrt(*self)
+    }
+
+    fn clamp(&self, low: f32, high: f32) -> f32 {
+        clamp(*self, low, high)
+    }
+
+    fn max_component_value(&self) -> f32 {
+        *self
+    }
+
+    fn y(&self) -> f32 {
+        *self
+    }
+}
+
+impl CoefficientSpectrum for Spectrum {
+    fn is_black(&self) -> bool {
+        Spectrum::is_black(self)
+    }
+
+    fn sqrt(&self) -> Spectrum {
+        Spectrum::sqrt(self)
+    }
+
+    fn clamp(&self, low: f32, high: f32) -> Spectrum {
+        Spectrum::rgb(
+            clamp(self.r, low, high),
+            clamp(self.g, low, high),
+            clamp(self.b, low, high),
+        )
+    }
+
+    fn max_component_value(&self) -> f32 {
+        Spectrum::max_component_value(self)
+    }
+
+    fn y(&self) -> f32 {
+        Spectrum::y(self)
+    }
+}
+
 /// Represents a linear RGB spectrum.
 /// TODO Rename this to `RGBSpectrum` and make `Spectrum` a type alias to this so we can also support
 /// full spectral rendering.
@@ -89,20 +165,23 @@ impl Spectrum {
 
     /// Convert a linear spectrum in XYZ format to a linear RGB format.
     pub fn from_xyz(xyz: &[f32; 3]) -> Spectrum {
-        let r = 3.240479 * xyz[0] - 1.537150 * xyz[1] - 0.498535 * xyz[2];
-        let g = -0.969256 * xyz[0] + 1.875991 * xyz[1] + 0.041556 * xyz[2];
-        let b = 0.055648 * xyz[0] - 0.204043 * xyz[1] + 1.057311 * xyz[2];
-        Spectrum::rgb(r, g, b)
+        let rgb = cie::ColourSpace::Srgb.from_xyz(xyz);
+        Spectrum::rgb(rgb[0], rgb[1], rgb[2])
     }
 
+    /// Convert a linear RGB spectrum to XYZ tri-stimulus values.
     pub fn to_xyz(self) -> [f32; 3] {
-        let mut xyz = [0.0, 0.0, 0.0];
+        cie::ColourSpace::Srgb.to_xyz(&[self.r, self.g, self.b])
+    }
 
-        xyz[0] = 0.412453 * self.r + 0.357580 * self.g + 0.180423 * self.b;
-        xyz[1] = 0.212671 * self.r + 0.715160 * self.g + 0.072169 * self.b;
-        xyz[2] = 0.019334 * self.r + 0.119193 * self.g + 0.950227 * self.b;
+    /// Convert xyY values (chromaticity + luminance) to a linear RGB spectrum.
+    pub fn from_xyy(xyy: &[f32; 3]) -> Spectrum {
+        Self::from_xyz(&cie::xyy_to_xyz(xyy))
+    }
 
-        xyz
+    /// Convert a linear RGB spectrum to xyY values (chromaticity + luminance).
+    pub fn to_xyy(self) -> [f32; 3] {
+        cie::xyz_to_xyy(&self.to_xyz())
     }
 
     /// Create a spectrum from a series of (wavelength, value) samples from an SPD (Spectral Power
@@ -155,15 +234,31 @@ impl Spectrum {
         self.r.max(self.g).max(self.b)
     }
 
+    /// Clamp the negative components to zero.
     pub fn clamp(&self) -> Spectrum {
-        Spectrum::rgb(
-            clamp(self.r, 0.0, f32::INFINITY),
-            clamp(self.g, 0.0, f32::INFINITY),
-            clamp(self.b, 0.0, f32::INFINITY),
-        )
+        CoefficientSpectrum::clamp(self, 0.0, f32::INFINITY)
+    }
+}
+
+/// Colour of a blackbody emitter at the given temperature (in Kelvin), normalized to unit
+/// luminance.
+pub fn blackbody_colour(temp: f32) -> Spectrum {
+    let le = blackbody(&cie::CIE_LAMBDA, temp);
+    let s = Spectrum::from_sampled(&cie::CIE_LAMBDA, &le, cie::CIE_LAMBDA.len());
+    let y = s.y();
+    if y > 0.0 {
+        s / y
+    } else {
+        Spectrum::black()
     }
 }
 
+/// XYZ white point of a blackbody emitter at the given temperature (in Kelvin), normalized to
+/// Y = 1. Suitable as a white point for `cie::bradford_adaptation()`.
+pub fn blackbody_white_point(temp: f32) -> [f32; 3] {
+    blackbody_colour(temp).to_xyz()
+}
+
 #[allow(non_upper_case_globals)]
 pub fn blackbody(lambda: &[f32], temp: f32) -> Vec<f32> {
     assert!(temp > 0.0);
@@ -0,0 +1,269 @@
+//! Estimate of the number of samples per pixel a render needs to reach a given noise level, to
+//! pick the sample counts of final frames from measurements rather than by trial and error.
+//!
+//! A sparse pilot pass renders a few samples for one pixel out of every `stride` along each axis,
+//! and estimates the variance of each of these pixels' samples. The relative error of a pixel
+//! rendered with `n` samples is then about `sqrt(variance / n) / (mean + 0.01)` (offset like the
+//! relative errors of `imagecompare`), which gives the number of samples each pixel needs to get
+//! under the target error. As the noise is rarely uniform over the image, the suggestion is a
+//! high percentile of these counts rather than their mean. It is conservative for the
+//! low-discrepancy samplers, whose error decreases faster than that of independent samples.
+
+use std::sync::Arc;
+
+use light_arena::MemoryArena;
+use log::info;
+use rayon::prelude::*;
+
+use crate::imagecompare::RELATIVE_EPSILON;
+use crate::renderer::RenderContext;
+use crate::Point2i;
+
+/// Largest number of samples per pixel the estimate suggests, as a guard against pixels with
+/// huge variances (e.g. fireflies) or unreachable target errors.
+pub const MAX_SPP: usize = 1 << 16;
+
+/// How to run the pilot pass and what it should estimate.
+#[derive(Debug, Clone, Copy)]
+pub struct SppEstimateOptions {
+    /// Relative error the pixels should get under (e.g. 0.05 for 5%)
+    pub target_error: f32,
+    /// Samples per pixel of the pilot pass, at least 2 to estimate a variance
+    pub pilot_spp: usize,
+    /// Distance in pixels between the pixels rendered by the pilot pass, along each axis
+    pub stride: i32,
+    /// Fraction of the pixels that should reach the target error with the suggested count
+    pub percentile: f32,
+}
+
+impl SppEstimateOptions {
+    pub fn new(target_error: f32) -> SppEstimateOptions {
+        SppEstimateOptions {
+            target_error,
+            pilot_spp: 8,
+            stride: 4,
+            percentile: 0.95,
+        }
+    }
+}
+
+/// Result of the pilot pass.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct SppEstimate {
+    /// Number of pixels rendered by the pilot pass
+    pub pixels: usize,
+    /// Samples per pixel needed by the median pixel
+    pub median_spp: f32,
+    /// Samples per pixel needed by the pixel at the requested percentile
+    pub percentile_spp: f32,
+    /// The suggested number of samples per pixel, as the scene's sampler would take them (e.g.
+    /// rounded up to a power of 2), at most `MAX_SPP`
+    pub spp: usize,
+}
+
+/// Running mean and variance of the luminance of a pixel's samples (Welford's algorithm).
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct PixelVariance {
+    n: u32,
+    mean: f64,
+    m2: f64,
+}
+
+impl PixelVariance {
+    pub fn add(&mut self, v: f32) {
+        self.n += 1;
+        let delta = f64::from(v) - self.mean;
+        self.mean += delta / f64::from(self.n);
+        self.m2 += delta * (f64::from(v) - self.mean);
+    }
+
+    pub fn mean(&self) -> f32 {
+        self.mean as f32
+    }
+
+    /// Unbiased sample variance, or 0 with fewer than 2 samples.
+    pub fn variance(&self) -> f32 {
+        if self.n < 2 {
+            0.0
+        } else {
+            (self.m2 / f64::from(self.n - 1)) as f32
+        }
+    }
+
+    /// Number of samples needed for the relative error of the pixel's mean to get down to
+    /// `target_error`, at least 1.
+    pub fn required_spp(&self, target_error: f32) -> f32 {
+        let error = target_error * (self.mean().abs() + RELATIVE_EPSILON);
+        f32::max(1.0, self.variance() / (error * error))
+    }
+}
+
+/// The value that the given fraction of the values are less than or equal to (by nearest rank).
+pub fn percentile(values: &mut [f32], fraction: f32) -> f32 {
+    if values.is_empty() {
+        return 1.0;
+    }
+    values.sort_by(f32::total_cmp);
+    let rank = (fraction * values.len() as f32).ceil() as usize;
+    values[rank.clamp(1, values.len()) - 1]
+}
+
+/// Run the pilot pass of the scene and estimate the number of samples per pixel needed to reach
+/// the target error. The film is left untouched.
+pub fn estimate_spp(context: &mut RenderContext, options: &SppEstimateOptions) -> SppEstimate {
+    let mut sampler = context.sampler.with_spp(options.pilot_spp.max(2));
+    context
+        .integrator
+        .preprocess(Arc::clone(&context.scene), sampler.as_mut());
+    let scene = &*context.scene;
+    let integrator = &*context.integrator;
+    let camera = &*context.camera;
+    let sampler = &*sampler;
+
+    let sample_bounds = camera.get_film().get_sample_bounds();
+    let pixel_bounds = integrator.pixel_bounds();
+    let stride = options.stride.max(1);
+    let rows: Vec<i32> = (sample_bounds.p_min.y..sample_bounds.p_max.y)
+        .step_by(stride as usize)
+        .collect();
+    info!(
+        "Rendering pilot pass of {} spp over every {} pixels",
+        sampler.spp(),
+        stride
+    );
+    let pixels: Vec<PixelVariance> = rows
+        .into_par_iter()
+        .flat_map_iter(|y| {
+            let mut sampler = sampler.box_clone();
+            sampler.reseed(y as u64);
+            let mut arena = MemoryArena::new(1);
+            let mut row = Vec::new();
+            for x in (sample_bounds.p_min.x..sample_bounds.p_max.x).step_by(stride as usize) {
+                let p = Point2i::new(x, y);
+                sampler.start_pixel(p);
+                if !pixel_bounds.inside_exclusive(&p) {
+                    continue;
+                }
+                let mut pixel = PixelVariance::default();
+                loop {
+                    let alloc = arena.allocator();
+                    let s = sampler.get_camera_sample(p);
+                    let mut ray = camera.generate_ray_differential(&s);
+                    ray.scale_differentials(1.0 / (sampler.spp() as f32).sqrt());
+                    let l = integrator.li(scene, &mut ray, sampler.as_mut(), &alloc, 0);
+                    // Invalid values are discarded by the render, so they don't count here either
+                    let y = l.y();
+                    if !l.has_nan() && y.is_finite() && y >= -1e-5 {
+                        pixel.add(y);
+                    }
+                    if !sampler.start_next_sample() {
+                        break;
+                    }
+                }
+                row.push(pixel);
+            }
+            row
+        })
+        .collect();
+
+    let mut required: Vec<f32> = pixels
+        .iter()
+        .map(|p| p.required_spp(options.target_error))
+        .collect();
+    let median_spp = percentile(&mut required, 0.5);
+    let percentile_spp = percentile(&mut required, options.percentile);
+    let spp = context
+        .sampler
+        .round_spp((percentile_spp.ceil() as usize).min(MAX_SPP));
+    let estimate = SppEstimate {
+        pixels: pixels.len(),
+        median_spp,
+        percentile_spp,
+        spp,
+    };
+    info!("Estimated samples per pixel: {:?}", estimate);
+    estimate
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{init_stats, pbrt, PbrtOptions};
+
+    #[test]
+    fn test_required_spp() {
+        let mut pixel = PixelVariance::default();
+        for v in &[0.0, 2.0, 0.0, 2.0] {
+            pixel.add(*v);
+        }
+        assert_eq!(pixel.mean(), 1.0);
+        assert!((pixel.variance() - 4.0 / 3.0).abs() < 1e-6);
+        // sqrt(4/3 / n) / 1.01 = 0.1
+        let n = pixel.required_spp(0.1);
+        assert!((n - 4.0 / 3.0 / (0.101f32 * 0.101)).abs() < 1e-2, "{}", n);
+        // A pixel without noise needs a single sample
+        let mut flat = PixelVariance::default();
+        flat.add(0.5);
+        flat.add(0.5);
+        assert_eq!(flat.required_spp(0.01), 1.0);
+
+        let mut values = [4.0, 1.0, 3.0, 2.0];
+        assert_eq!(percentile(&mut values, 0.5), 2.0);
+        assert_eq!(percentile(&mut values, 0.95), 4.0);
+        assert_eq!(percentile(&mut [], 0.95), 1.0);
+    }
+
+    #[test]
+    fn test_estimated_spp_follows_the_noise() {
+        init_stats();
+        let estimate = |world: &str, target_error: f32| {
+            let opts = PbrtOptions {
+                num_threads: 2,
+                defer_render: true,
+                ..PbrtOptions::default()
+            };
+            let scene = format!(
+                r#"
+LookAt 0 0 5  0 0 0  0 1 0
+Camera "perspective" "float fov" [40]
+Film "image" "integer xresolution" [18] "integer yresolution" [12]
+Sampler "02sequence" "integer pixelsamples" [16]
+Integrator "path"
+WorldBegin
+{}
+WorldEnd
+"#,
+                world
+            );
+            let mut context = pbrt::parse_scene_string(opts, &scene).unwrap().unwrap();
+            let estimate = estimate_spp(&mut context, &SppEstimateOptions::new(target_error));
+            // The scene's sampler is left alone
+            assert_eq!(context.sampler.spp(), 16);
+            estimate
+        };
+
+        // Every 4th pixel along each axis
+        let flat = estimate(r#"LightSource "infinite" "rgb L" [0.5 0.5 0.5]"#, 0.01);
+        assert_eq!(flat.pixels, 5 * 3);
+        assert_eq!((flat.median_spp, flat.spp), (1.0, 1));
+
+        let lit = r#"
+AttributeBegin
+  Translate 0 2 2
+  AreaLightSource "diffuse" "rgb L" [10 10 10]
+  Shape "sphere" "float radius" [0.3]
+AttributeEnd
+Material "matte" "rgb Kd" [0.5 0.5 0.5]
+Shape "sphere" "float radius" [1]
+Shape "trianglemesh" "integer indices" [0 1 2 0 2 3]
+    "point P" [-5 -1 -5  5 -1 -5  5 -1 5  -5 -1 5]
+"#;
+        let coarse = estimate(lit, 0.2);
+        let fine = estimate(lit, 0.1);
+        assert!(coarse.percentile_spp > 1.0, "{:?}", coarse);
+        // Halving the error takes 4 times the samples
+        let ratio = fine.percentile_spp / coarse.percentile_spp;
+        assert!((ratio - 4.0).abs() < 1e-3, "{:?} {:?}", coarse, fine);
+        assert!(fine.spp.is_power_of_two() && fine.spp as f32 >= fine.percentile_spp);
+    }
+}
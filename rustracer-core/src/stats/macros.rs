@@ -1,32 +1,31 @@
+// Each statistic is stored in a thread-local variable, so updating it from the render threads is
+// just a non-atomic increment with no synchronisation whatsoever. The `report()` function of each
+// statistic merges the calling thread's value into the global `StatAccumulator` and resets it,
+// which means each thread needs to call `stats::report_stats()` once it's done.
+
 #[macro_export]
 macro_rules! stat_counter(
     ($d:expr, $x:ident) => (
         mod $x {
             use std::cell::Cell;
-            use lazy_static::lazy_static;
-            use state::LocalStorage;
             use $crate::stats::StatAccumulator;
 
-            lazy_static! {
-                static ref VALUE: LocalStorage<Cell<u64>> = LocalStorage::new();
+            thread_local! {
+                static VALUE: Cell<u64> = const { Cell::new(0) };
             }
 
             pub fn init() {
-                VALUE.set(|| Cell::new(0));
-                let mutex = $crate::stats::STAT_REPORTERS.get();
-                let mut vec = mutex.lock();
-                vec.push(Box::new(report));
+                $crate::stats::register_reporter(report);
             }
 
             #[allow(dead_code)]
             #[inline(always)]
             pub fn inc() {
-                let v = VALUE.get();
-                v.set(v.get() + 1);
+                VALUE.with(|v| v.set(v.get() + 1));
             }
 
             pub fn report(acc: &mut StatAccumulator) {
-                acc.report_counter($d, VALUE.get().get());
+                acc.report_counter($d, VALUE.with(|v| v.replace(0)));
             }
         }
     );
@@ -37,30 +36,25 @@ macro_rules! stat_memory_counter(
     ($d:expr, $x:ident) => (
         mod $x {
             use std::cell::Cell;
-            use lazy_static::lazy_static;
-            use state::LocalStorage;
             use $crate::stats::StatAccumulator;
 
-            lazy_static! {
-                static ref VALUE: LocalStorage<Cell<u64>> = LocalStorage::new();
+            thread_local! {
+                static VALUE: Cell<u64> = const { Cell::new(0) };
             }
 
             pub fn init() {
-                VALUE.set(|| Cell::new(0));
-                let mutex = $crate::stats::STAT_REPORTERS.get();
-                let mut vec = mutex.lock();
-                vec.push(Box::new(report));
+                $crate::stats::register_reporter(report);
             }
 
             #[allow(dead_code)]
             #[inline(always)]
             pub fn add(a: u64) {
-                let v = VALUE.get();
-                v.set(v.get() + a);
+                VALUE.with(|v| v.set(v.get() + a));
+                $crate::stats::track_memory($d, a);
             }
 
             pub fn report(acc: &mut StatAccumulator) {
-                acc.report_memory_counter($d, VALUE.get().get());
+                acc.report_memory_counter($d, VALUE.with(|v| v.replace(0)));
             }
         }
     );
@@ -71,48 +65,36 @@ macro_rules! stat_int_distribution(
     ($d:expr, $x:ident) => (
         mod $x {
             use std::cell::Cell;
-            use std::u64;
-            use lazy_static::lazy_static;
-            use state::LocalStorage;
             use $crate::stats::StatAccumulator;
 
-            lazy_static! {
-                static ref SUM: LocalStorage<Cell<u64>> = LocalStorage::new();
-                static ref COUNT: LocalStorage<Cell<u64>> = LocalStorage::new();
-                static ref MIN: LocalStorage<Cell<u64>> = LocalStorage::new();
-                static ref MAX: LocalStorage<Cell<u64>> = LocalStorage::new();
+            thread_local! {
+                static SUM: Cell<u64> = const { Cell::new(0) };
+                static COUNT: Cell<u64> = const { Cell::new(0) };
+                static MIN: Cell<u64> = const { Cell::new(u64::MAX) };
+                static MAX: Cell<u64> = const { Cell::new(u64::MIN) };
             }
 
             pub fn init() {
-                SUM.set(|| Cell::new(0));
-                COUNT.set(|| Cell::new(0));
-                MIN.set(|| Cell::new(u64::MAX));
-                MAX.set(|| Cell::new(u64::MIN));
-                let mutex = $crate::stats::STAT_REPORTERS.get();
-                let mut vec = mutex.lock();
-                vec.push(Box::new(report));
+                $crate::stats::register_reporter(report);
             }
 
             #[allow(dead_code)]
             #[inline(always)]
             pub fn report_value(v: u64) {
-                let s = SUM.get();
-                s.set(s.get() + v);
-                let c = COUNT.get();
-                c.set(c.get() + 1);
-                let min = MIN.get();
-                min.set(u64::min(min.get(), v));
-                let max = MAX.get();
-                max.set(u64::max(max.get(), v));
+                SUM.with(|s| s.set(s.get() + v));
+                COUNT.with(|c| c.set(c.get() + 1));
+                MIN.with(|min| min.set(u64::min(min.get(), v)));
+                MAX.with(|max| max.set(u64::max(max.get(), v)));
             }
 
             pub fn report(acc: &mut StatAccumulator) {
-                acc.report_int_distribution(
-                    $d,
-                    SUM.get().get(),
-                    COUNT.get().get(),
-                    MIN.get().get(),
-                    MAX.get().get());
+                let count = COUNT.with(|c| c.replace(0));
+                let sum = SUM.with(|s| s.replace(0));
+                let min = MIN.with(|m| m.replace(u64::MAX));
+                let max = MAX.with(|m| m.replace(u64::MIN));
+                if count > 0 {
+                    acc.report_int_distribution($d, sum, count, min, max);
+                }
             }
         }
     );
@@ -123,39 +105,34 @@ macro_rules! stat_percent(
     ($d:expr, $x:ident) => (
         mod $x {
             use std::cell::Cell;
-            use lazy_static::lazy_static;
-            use state::LocalStorage;
             use $crate::stats::StatAccumulator;
 
-            lazy_static! {
-                static ref NUM: LocalStorage<Cell<u64>> = LocalStorage::new();
-                static ref DENOM: LocalStorage<Cell<u64>> = LocalStorage::new();
+            thread_local! {
+                static NUM: Cell<u64> = const { Cell::new(0) };
+                static DENOM: Cell<u64> = const { Cell::new(0) };
             }
 
             pub fn init() {
-                NUM.set(|| Cell::new(0));
-                DENOM.set(|| Cell::new(0));
-                let mutex = $crate::stats::STAT_REPORTERS.get();
-                let mut vec = mutex.lock();
-                vec.push(Box::new(report));
+                $crate::stats::register_reporter(report);
             }
 
             #[allow(dead_code)]
             #[inline(always)]
             pub fn inc() {
-                let v = NUM.get();
-                v.set(v.get() + 1);
+                NUM.with(|v| v.set(v.get() + 1));
             }
 
             #[allow(dead_code)]
             #[inline(always)]
             pub fn inc_total() {
-                let v = DENOM.get();
-                v.set(v.get() + 1);
+                DENOM.with(|v| v.set(v.get() + 1));
             }
 
             pub fn report(acc: &mut StatAccumulator) {
-                acc.report_percentage($d, NUM.get().get(), DENOM.get().get());
+                acc.report_percentage(
+                    $d,
+                    NUM.with(|v| v.replace(0)),
+                    DENOM.with(|v| v.replace(0)));
             }
         }
     );
@@ -166,46 +143,46 @@ macro_rules! stat_ratio(
     ($d:expr, $x:ident) => (
         mod $x {
             use std::cell::Cell;
-            use lazy_static::lazy_static;
-            use state::LocalStorage;
             use $crate::stats::StatAccumulator;
 
-            lazy_static! {
-                static ref NUM: LocalStorage<Cell<u64>> = LocalStorage::new();
-                static ref DENOM: LocalStorage<Cell<u64>> = LocalStorage::new();
+            thread_local! {
+                static NUM: Cell<u64> = const { Cell::new(0) };
+                static DENOM: Cell<u64> = const { Cell::new(0) };
             }
 
             pub fn init() {
-                NUM.set(|| Cell::new(0));
-                DENOM.set(|| Cell::new(0));
-                let mutex = $crate::stats::STAT_REPORTERS.get();
-                let mut vec = mutex.lock();
-                vec.push(Box::new(report));
+                $crate::stats::register_reporter(report);
             }
 
             #[allow(dead_code)]
             #[inline(always)]
             pub fn inc() {
-                let v = NUM.get();
-                v.set(v.get() + 1);
+                NUM.with(|v| v.set(v.get() + 1));
             }
 
             #[allow(dead_code)]
             #[inline(always)]
             pub fn add(a: u64) {
-                let v = NUM.get();
-                v.set(v.get() + a);
+                NUM.with(|v| v.set(v.get() + a));
             }
 
             #[allow(dead_code)]
             #[inline(always)]
             pub fn inc_total() {
-                let v = DENOM.get();
-                v.set(v.get() + 1);
+                DENOM.with(|v| v.set(v.get() + 1));
+            }
+
+            #[allow(dead_code)]
+            #[inline(always)]
+            pub fn add_total(a: u64) {
+                DENOM.with(|v| v.set(v.get() + a));
             }
 
             pub fn report(acc: &mut StatAccumulator) {
-                acc.report_ratio($d, NUM.get().get(), DENOM.get().get());
+                acc.report_ratio(
+                    $d,
+                    NUM.with(|v| v.replace(0)),
+                    DENOM.with(|v| v.replace(0)));
             }
         }
     );
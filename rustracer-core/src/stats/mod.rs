@@ -70,14 +70,14 @@ impl StatAccumulator {
 
     pub fn report_percentage(&mut self, name: &str, num: u64, denom: u64) {
         let frac = self.percentages.entry(name.to_owned()).or_insert((0, 0));
-        (*frac).0 += num;
-        (*frac).1 += denom;
+        frac.0 += num;
+        frac.1 += denom;
     }
 
     pub fn report_ratio(&mut self, name: &str, num: u64, denom: u64) {
         let frac = self.ratios.entry(name.to_owned()).or_insert((0, 0));
-        (*frac).0 += num;
-        (*frac).1 += denom;
+        frac.0 += num;
+        frac.1 += denom;
     }
 
     pub fn print_stats(&self) {
@@ -91,7 +91,7 @@ impl StatAccumulator {
             let (category, title) = self.get_category_and_title(desc);
             to_print
                 .entry(category.to_owned())
-                .or_insert_with(Vec::new)
+                .or_default()
                 .push(format!("    {:<42}               {:12}", title, value));
         }
         // Memory counters
@@ -100,33 +100,14 @@ impl StatAccumulator {
                 continue;
             }
             let (category, title) = self.get_category_and_title(desc);
-            let kb = (*value as f64) / 1024.0;
-            if kb < 1024.0 {
-                to_print
-                    .entry(category.to_owned())
-                    .or_insert_with(Vec::new)
-                    .push(format!("    {:<42}                  {:9.2} kiB", title, kb));
-            } else {
-                let mib = kb / 1024.0;
-                if mib < 1024.0 {
-                    to_print
-                        .entry(category.to_owned())
-                        .or_insert_with(Vec::new)
-                        .push(format!(
-                            "    {:<42}                  {:9.2} MiB",
-                            title, mib
-                        ));
-                } else {
-                    let gib = mib / 1024.0;
-                    to_print
-                        .entry(category.to_owned())
-                        .or_insert_with(Vec::new)
-                        .push(format!(
-                            "    {:<42}                  {:9.2} GiB",
-                            title, gib
-                        ));
-                }
-            }
+            to_print
+                .entry(category.to_owned())
+                .or_default()
+                .push(format!(
+                    "    {:<42}                  {}",
+                    title,
+                    format_memory(*value)
+                ));
         }
         // Int distributions
         for (desc, sum) in &self.int_distribution_sums {
@@ -140,7 +121,7 @@ impl StatAccumulator {
             let avg = (*sum as f64) / (count as f64);
             to_print
                 .entry(category.to_owned())
-                .or_insert_with(Vec::new)
+                .or_default()
                 .push(format!(
                     "    {:<42}                      {:.3} avg [range {} - {}]",
                     title, avg, min, max
@@ -155,7 +136,7 @@ impl StatAccumulator {
             let (category, title) = self.get_category_and_title(desc);
             to_print
                 .entry(category.to_owned())
-                .or_insert_with(Vec::new)
+                .or_default()
                 .push(format!(
                     "    {:<42}{:12} / {:12} ({:.2}%)",
                     title,
@@ -173,7 +154,7 @@ impl StatAccumulator {
             let (category, title) = self.get_category_and_title(desc);
             to_print
                 .entry(category.to_owned())
-                .or_insert_with(Vec::new)
+                .or_default()
                 .push(format!(
                     "    {:<42}{:12} / {:12} ({:.2}x)",
                     title,
@@ -201,17 +182,80 @@ impl StatAccumulator {
     }
 }
 
+/// Format a number of bytes in kiB, MiB or GiB, right-aligned.
+pub fn format_memory(bytes: u64) -> String {
+    let kb = (bytes as f64) / 1024.0;
+    if kb < 1024.0 {
+        format!("{:9.2} kiB", kb)
+    } else if kb < 1024.0 * 1024.0 {
+        format!("{:9.2} MiB", kb / 1024.0)
+    } else {
+        format!("{:9.2} GiB", kb / (1024.0 * 1024.0))
+    }
+}
+
 type StatReporterFn = Box<dyn Fn(&mut StatAccumulator) + Send>;
 lazy_static! {
     pub static ref STAT_REPORTERS: Storage<Mutex<Vec<StatReporterFn>>> = Storage::new();
     pub static ref STAT_ACCUMULATOR: Storage<Mutex<StatAccumulator>> = Storage::new();
+    /// Running totals of the memory counters, across all threads.
+    static ref MEMORY_USAGE: Mutex<BTreeMap<&'static str, u64>> = Mutex::new(BTreeMap::new());
 }
 
 pub fn init_stats() {
     STAT_REPORTERS.set(Mutex::new(Vec::new()));
     STAT_ACCUMULATOR.set(Mutex::new(StatAccumulator::default()));
+    MEMORY_USAGE.lock().clear();
+}
+
+/// Add `bytes` to the running total of the memory counter `name`. This is called by the memory
+/// counters of `stat_memory_counter!` on top of updating their per-thread value, so that the
+/// memory used so far can be checked while the scene is being built, without waiting for the
+/// threads to report their stats. Memory is only counted for large allocations, so the lock
+/// doesn't matter.
+pub fn track_memory(name: &'static str, bytes: u64) {
+    *MEMORY_USAGE.lock().entry(name).or_insert(0) += bytes;
+}
+
+/// Memory used so far according to each memory counter.
+pub fn memory_usage() -> Vec<(&'static str, u64)> {
+    MEMORY_USAGE
+        .lock()
+        .iter()
+        .map(|(name, bytes)| (*name, *bytes))
+        .collect()
+}
+
+/// Total memory used so far according to the memory counters.
+pub fn total_memory_usage() -> u64 {
+    MEMORY_USAGE.lock().values().sum()
 }
 
+/// Print how much memory each memory counter has used so far.
+pub fn print_memory_report() {
+    let usage = memory_usage();
+    println!("Memory usage:");
+    for (name, bytes) in &usage {
+        let title = name.split_once('/').map_or(*name, |(_, title)| title);
+        println!("    {:<42}{}", title, format_memory(*bytes));
+    }
+    println!(
+        "    {:<42}{}",
+        "Total",
+        format_memory(usage.iter().map(|(_, bytes)| bytes).sum())
+    );
+}
+
+/// Register the function used to merge a statistic's per-thread value into the accumulator.
+///
+/// This is only called once per statistic at initialisation time by the `stat_*!` macros.
+pub fn register_reporter(f: fn(&mut StatAccumulator)) {
+    STAT_REPORTERS.get().lock().push(Box::new(f));
+}
+
+/// Merge the statistics gathered by the calling thread into the global accumulator, and reset
+/// them. This is the only place that takes a lock, so it should be called once by each thread
+/// when it's done working rather than on the hot path.
 pub fn report_stats() {
     let vec = STAT_REPORTERS.get().lock();
     let mut acc = STAT_ACCUMULATOR.get().lock();
@@ -224,3 +268,42 @@ pub fn print_stats() {
     let acc = STAT_ACCUMULATOR.get().lock();
     (*acc).print_stats();
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    stat_counter!("Test/Per-thread counter", test_counter);
+
+    #[test]
+    fn test_per_thread_counters_are_aggregated() {
+        init_stats();
+        test_counter::init();
+
+        let threads: Vec<_> = (0..4)
+            .map(|_| {
+                std::thread::spawn(|| {
+                    for _ in 0..100 {
+                        test_counter::inc();
+                    }
+                    report_stats();
+                    // Reporting resets the thread's values, so a second report is a no-op
+                    report_stats();
+                })
+            })
+            .collect();
+        for t in threads {
+            t.join().unwrap();
+        }
+
+        let acc = STAT_ACCUMULATOR.get().lock();
+        assert_eq!(acc.counters["Test/Per-thread counter"], 400);
+    }
+
+    #[test]
+    fn test_format_memory() {
+        assert_eq!(format_memory(512), "     0.50 kiB");
+        assert_eq!(format_memory(3 * 1024 * 1024), "     3.00 MiB");
+        assert_eq!(format_memory(5 << 30), "     5.00 GiB");
+    }
+}
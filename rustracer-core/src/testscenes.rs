@@ -0,0 +1,312 @@
+//! Built-in, parameterized test scenes, used by the integration tests and to check that an
+//! installation produces the expected images (see `rustracer gen-test-scene`).
+//!
+//! Each scene is a template in which `{name}` placeholders are replaced by the value of the
+//! corresponding parameter, or by its default value.
+use anyhow::{bail, Result};
+
+/// A built-in test scene.
+#[derive(Debug)]
+pub struct TestScene {
+    pub name: &'static str,
+    pub description: &'static str,
+    /// Parameters of the template, with their default values
+    pub params: &'static [(&'static str, &'static str)],
+    template: &'static str,
+}
+
+impl TestScene {
+    /// Instantiate the scene, using the given values for some of its parameters.
+    pub fn generate(&self, values: &[(String, String)]) -> Result<String> {
+        for (name, _) in values {
+            if !self.params.iter().any(|(p, _)| p == name) {
+                bail!(
+                    "Unknown parameter \"{}\" for test scene \"{}\"",
+                    name,
+                    self.name
+                );
+            }
+        }
+
+        let mut scene = String::with_capacity(self.template.len());
+        let mut rest = self.template;
+        while let Some(start) = rest.find('{') {
+            let end = match rest[start..].find('}') {
+                Some(end) => start + end,
+                None => bail!("Unterminated placeholder in test scene \"{}\"", self.name),
+            };
+            let name = &rest[start + 1..end];
+            let value = values
+                .iter()
+                .rev()
+                .find(|(n, _)| n == name)
+                .map(|(_, v)| v.as_str())
+                .or_else(|| {
+                    self.params
+                        .iter()
+                        .find(|(p, _)| *p == name)
+                        .map(|(_, d)| *d)
+                });
+            match value {
+                Some(value) => {
+                    scene.push_str(&rest[..start]);
+                    scene.push_str(value);
+                }
+                None => bail!(
+                    "Undeclared parameter \"{}\" in test scene \"{}\"",
+                    name,
+                    self.name
+                ),
+            }
+            rest = &rest[end + 1..];
+        }
+        scene.push_str(rest);
+        Ok(scene)
+    }
+}
+
+/// Look up a built-in test scene by name.
+pub fn find(name: &str) -> Option<&'static TestScene> {
+    TEST_SCENES.iter().find(|s| s.name == name)
+}
+
+pub const TEST_SCENES: &[TestScene] = &[
+    TestScene {
+        name: "furnace",
+        description: "White furnace: a diffuse sphere of the given albedo lit by a uniform \
+                      environment. With an albedo of 1, the image should be uniformly equal to \
+                      the environment's radiance.",
+        params: &[
+            ("xres", "64"),
+            ("yres", "64"),
+            ("spp", "16"),
+            ("albedo", "1"),
+            ("radiance", "0.5"),
+            ("maxdepth", "16"),
+            ("output", "furnace.exr"),
+        ],
+        template: FURNACE,
+    },
+    TestScene {
+        name: "mis",
+        description: "Veach's multiple importance sampling test: four plates of decreasing \
+                      glossiness reflecting four spherical lights of increasing size but equal \
+                      power.",
+        params: &[
+            ("xres", "256"),
+            ("yres", "192"),
+            ("spp", "16"),
+            ("output", "mis.exr"),
+        ],
+        template: MIS,
+    },
+    TestScene {
+        name: "texture-filter",
+        description: "Checkerboard ground planes receding to the horizon, one per texture \
+                      antialiasing mode (none, closedform, supersample), to compare texture \
+                      filtering.",
+        params: &[
+            ("xres", "384"),
+            ("yres", "128"),
+            ("spp", "1"),
+            ("checks", "64"),
+            ("aasamples", "4"),
+            ("output", "texture-filter.exr"),
+        ],
+        template: TEXTURE_FILTER,
+    },
+    TestScene {
+        name: "dof",
+        description: "Depth of field calibration chart: a row of cards at increasing \
+                      distances, with the camera focused on the middle one.",
+        params: &[
+            ("xres", "256"),
+            ("yres", "128"),
+            ("spp", "32"),
+            ("lensradius", "0.1"),
+            ("focaldistance", "10"),
+            ("output", "dof.exr"),
+        ],
+        template: DOF,
+    },
+];
+
+const FURNACE: &str = r#"# White furnace test
+LookAt 0 0 5  0 0 0  0 1 0
+Camera "perspective" "float fov" [30]
+Film "image" "integer xresolution" [{xres}] "integer yresolution" [{yres}]
+    "string filename" "{output}"
+Sampler "02sequence" "integer pixelsamples" [{spp}]
+Integrator "path" "integer maxdepth" [{maxdepth}]
+WorldBegin
+LightSource "infinite" "rgb L" [{radiance} {radiance} {radiance}]
+Material "matte" "rgb Kd" [{albedo} {albedo} {albedo}]
+Shape "sphere" "float radius" [1]
+WorldEnd
+"#;
+
+const MIS: &str = r#"# Multiple importance sampling test
+LookAt 0 2 15  0 -2 2.5  0 1 0
+Camera "perspective" "float fov" [28]
+Film "image" "integer xresolution" [{xres}] "integer yresolution" [{yres}]
+    "string filename" "{output}"
+Sampler "02sequence" "integer pixelsamples" [{spp}]
+Integrator "path" "integer maxdepth" [5]
+WorldBegin
+# Four spherical lights of equal power: the radiance decreases with the square of the radius
+AttributeBegin
+  AreaLightSource "diffuse" "rgb L" [800 800 800]
+  Translate -3.75 0 0
+  Shape "sphere" "float radius" [0.03]
+AttributeEnd
+AttributeBegin
+  AreaLightSource "diffuse" "rgb L" [72 72 72]
+  Translate -1.25 0 0
+  Shape "sphere" "float radius" [0.1]
+AttributeEnd
+AttributeBegin
+  AreaLightSource "diffuse" "rgb L" [8 8 8]
+  Translate 1.25 0 0
+  Shape "sphere" "float radius" [0.3]
+AttributeEnd
+AttributeBegin
+  AreaLightSource "diffuse" "rgb L" [0.9 0.9 0.9]
+  Translate 3.75 0 0
+  Shape "sphere" "float radius" [0.9]
+AttributeEnd
+# Plates, from the sharpest to the roughest
+AttributeBegin
+  Material "plastic" "rgb Kd" [0.05 0.05 0.05] "rgb Ks" [0.8 0.8 0.8] "float roughness" [0.002]
+  Translate 0 -0.5 4
+  Rotate 50 1 0 0
+  Shape "trianglemesh" "integer indices" [0 1 2 0 2 3]
+      "point P" [-4 -0.5 0  4 -0.5 0  4 0.5 0  -4 0.5 0]
+AttributeEnd
+AttributeBegin
+  Material "plastic" "rgb Kd" [0.05 0.05 0.05] "rgb Ks" [0.8 0.8 0.8] "float roughness" [0.01]
+  Translate 0 -1.5 5.5
+  Rotate 40 1 0 0
+  Shape "trianglemesh" "integer indices" [0 1 2 0 2 3]
+      "point P" [-4 -0.5 0  4 -0.5 0  4 0.5 0  -4 0.5 0]
+AttributeEnd
+AttributeBegin
+  Material "plastic" "rgb Kd" [0.05 0.05 0.05] "rgb Ks" [0.8 0.8 0.8] "float roughness" [0.05]
+  Translate 0 -2.5 7
+  Rotate 30 1 0 0
+  Shape "trianglemesh" "integer indices" [0 1 2 0 2 3]
+      "point P" [-4 -0.5 0  4 -0.5 0  4 0.5 0  -4 0.5 0]
+AttributeEnd
+AttributeBegin
+  Material "plastic" "rgb Kd" [0.05 0.05 0.05] "rgb Ks" [0.8 0.8 0.8] "float roughness" [0.2]
+  Translate 0 -3.5 8.5
+  Rotate 20 1 0 0
+  Shape "trianglemesh" "integer indices" [0 1 2 0 2 3]
+      "point P" [-4 -0.5 0  4 -0.5 0  4 0.5 0  -4 0.5 0]
+AttributeEnd
+WorldEnd
+"#;
+
+const TEXTURE_FILTER: &str = r#"# Texture filtering test
+LookAt 0 1 0  0 0.8 10  0 1 0
+Camera "perspective" "float fov" [30]
+Film "image" "integer xresolution" [{xres}] "integer yresolution" [{yres}]
+    "string filename" "{output}"
+Sampler "02sequence" "integer pixelsamples" [{spp}]
+Integrator "path" "integer maxdepth" [1]
+WorldBegin
+LightSource "infinite" "rgb L" [1 1 1]
+Texture "none" "spectrum" "checkerboard" "string aamode" "none"
+    "float uscale" [{checks}] "float vscale" [{checks}]
+Texture "closedform" "spectrum" "checkerboard" "string aamode" "closedform"
+    "float uscale" [{checks}] "float vscale" [{checks}]
+Texture "supersample" "spectrum" "checkerboard" "string aamode" "supersample"
+    "integer aasamples" [{aasamples}] "float uscale" [{checks}] "float vscale" [{checks}]
+AttributeBegin
+  Material "matte" "texture Kd" "none"
+  Shape "trianglemesh" "integer indices" [0 1 2 0 2 3]
+      "point P" [-6 0 0  -2 0 0  -2 0 100  -6 0 100] "float uv" [0 0 1 0 1 1 0 1]
+AttributeEnd
+AttributeBegin
+  Material "matte" "texture Kd" "closedform"
+  Shape "trianglemesh" "integer indices" [0 1 2 0 2 3]
+      "point P" [-2 0 0  2 0 0  2 0 100  -2 0 100] "float uv" [0 0 1 0 1 1 0 1]
+AttributeEnd
+AttributeBegin
+  Material "matte" "texture Kd" "supersample"
+  Shape "trianglemesh" "integer indices" [0 1 2 0 2 3]
+      "point P" [2 0 0  6 0 0  6 0 100  2 0 100] "float uv" [0 0 1 0 1 1 0 1]
+AttributeEnd
+WorldEnd
+"#;
+
+const DOF: &str = r#"# Depth of field calibration chart
+LookAt 0 0 0  0 0 1  0 1 0
+Camera "perspective" "float fov" [40] "float lensradius" [{lensradius}]
+    "float focaldistance" [{focaldistance}]
+Film "image" "integer xresolution" [{xres}] "integer yresolution" [{yres}]
+    "string filename" "{output}"
+Sampler "02sequence" "integer pixelsamples" [{spp}]
+Integrator "path" "integer maxdepth" [1]
+WorldBegin
+LightSource "infinite" "rgb L" [1 1 1]
+Texture "checks" "spectrum" "checkerboard" "float uscale" [8] "float vscale" [8]
+Material "matte" "texture Kd" "checks"
+# Cards at 5, 7.5, 10, 12.5 and 15 units: the scale keeps their apparent size constant
+AttributeBegin
+  Translate -3 0 5
+  Scale 0.5 0.5 0.5
+  Shape "trianglemesh" "integer indices" [0 1 2 0 2 3]
+      "point P" [-1 -1 0  1 -1 0  1 1 0  -1 1 0] "float uv" [0 0 1 0 1 1 0 1]
+AttributeEnd
+AttributeBegin
+  Translate -2.25 0 7.5
+  Scale 0.75 0.75 0.75
+  Shape "trianglemesh" "integer indices" [0 1 2 0 2 3]
+      "point P" [-1 -1 0  1 -1 0  1 1 0  -1 1 0] "float uv" [0 0 1 0 1 1 0 1]
+AttributeEnd
+AttributeBegin
+  Translate 0 0 10
+  Shape "trianglemesh" "integer indices" [0 1 2 0 2 3]
+      "point P" [-1 -1 0  1 -1 0  1 1 0  -1 1 0] "float uv" [0 0 1 0 1 1 0 1]
+AttributeEnd
+AttributeBegin
+  Translate 3.75 0 12.5
+  Scale 1.25 1.25 1.25
+  Shape "trianglemesh" "integer indices" [0 1 2 0 2 3]
+      "point P" [-1 -1 0  1 -1 0  1 1 0  -1 1 0] "float uv" [0 0 1 0 1 1 0 1]
+AttributeEnd
+AttributeBegin
+  Translate 9 0 15
+  Scale 1.5 1.5 1.5
+  Shape "trianglemesh" "integer indices" [0 1 2 0 2 3]
+      "point P" [-1 -1 0  1 -1 0  1 1 0  -1 1 0] "float uv" [0 0 1 0 1 1 0 1]
+AttributeEnd
+WorldEnd
+"#;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_generate() {
+        let scene = find("furnace").unwrap();
+        let generated = scene
+            .generate(&[("albedo".to_owned(), "0.25".to_owned())])
+            .unwrap();
+        assert!(generated.contains("\"rgb Kd\" [0.25 0.25 0.25]"));
+        assert!(generated.contains("\"integer pixelsamples\" [16]"));
+        assert!(!generated.contains('{'));
+
+        assert!(scene
+            .generate(&[("nope".to_owned(), "1".to_owned())])
+            .is_err());
+    }
+
+    #[test]
+    fn test_all_placeholders_are_declared() {
+        for scene in TEST_SCENES {
+            assert!(scene.generate(&[]).is_ok(), "{}", scene.name);
+        }
+    }
+}
@@ -0,0 +1,73 @@
+//! Helpers shared by the unit tests that render small scenes.
+use crate::spectrum::Spectrum;
+use crate::{init_stats, pbrt, PbrtOptions};
+
+/// Render `scene` in memory and return its pixels, in scanline order.
+pub fn render_scene(scene: &str, opts: PbrtOptions) -> Vec<Spectrum> {
+    init_stats();
+    let opts = PbrtOptions {
+        defer_render: true,
+        ..opts
+    };
+    let mut context = pbrt::parse_scene_string(opts, scene).unwrap().unwrap();
+    context.render_in_memory();
+    let rgb = context.camera.get_film().rgb();
+    rgb.chunks(3)
+        .map(|c| Spectrum::rgb(c[0], c[1], c[2]))
+        .collect()
+}
+
+/// A matte sphere lit by a spherical area light, rendered with the direct lighting integrator.
+pub fn area_light_scene(sampler_params: &str, integrator_params: &str) -> String {
+    format!(
+        r#"
+LookAt 0 0 5  0 0 0  0 1 0
+Camera "perspective" "float fov" [40]
+Film "image" "integer xresolution" [32] "integer yresolution" [24]
+Sampler "02sequence" "integer pixelsamples" [4] {}
+Integrator "directlighting" {}
+WorldBegin
+AttributeBegin
+Translate 0 3 2
+AreaLightSource "diffuse" "rgb L" [4 4 4] "integer nsamples" [9]
+Shape "sphere" "float radius" [1]
+AttributeEnd
+Material "matte" "rgb Kd" [0.5 0.5 0.5]
+Shape "sphere" "float radius" [1]
+WorldEnd
+"#,
+        sampler_params, integrator_params
+    )
+}
+
+/// A diffuse sphere lit by a point light, seen by the given camera.
+pub fn point_lit_scene(camera: &str, film_params: &str, spp: i32) -> String {
+    format!(
+        r#"
+LookAt 0 0 5  0 0 0  0 1 0
+Camera {} "float fov" [30]
+Film "image" "integer xresolution" [16] "integer yresolution" [12] {}
+PixelFilter "box"
+Sampler "02sequence" "integer pixelsamples" [{}]
+Integrator "directlighting"
+WorldBegin
+LightSource "point" "point from" [0 3 3] "rgb I" [20 20 20]
+Shape "sphere" "float radius" [1]
+WorldEnd
+"#,
+        camera, film_params, spp
+    )
+}
+
+pub fn threads(num_threads: u8) -> PbrtOptions {
+    PbrtOptions {
+        num_threads,
+        ..PbrtOptions::default()
+    }
+}
+
+/// Mean of the RGB components of the pixels.
+pub fn mean(pixels: &[Spectrum]) -> f32 {
+    let sum: f32 = pixels.iter().map(|s| s.r + s.g + s.b).sum();
+    sum / (3 * pixels.len()) as f32
+}
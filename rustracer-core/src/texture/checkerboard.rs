@@ -1,13 +1,12 @@
 use std::fmt::Debug;
 use std::ops::{Add, Mul};
-use std::sync::Arc;
 
 use log::{error, warn};
 
 use crate::interaction::SurfaceInteraction;
 use crate::paramset::TextureParams;
 use crate::spectrum::Spectrum;
-use crate::texture::{PlanarMapping2D, Texture, TextureMapping2D, UVMapping2D};
+use crate::texture::{PlanarMapping2D, Texture, TextureMapping2D, TextureRef, UVMapping2D};
 use crate::{Transform, Vector3f};
 
 #[derive(PartialEq, Eq, Copy, Clone, Debug)]
@@ -18,16 +17,16 @@ pub enum AAMethod {
 
 #[derive(Debug)]
 pub struct CheckerboardTexture<T> {
-    tex1: Arc<dyn Texture<T>>,
-    tex2: Arc<dyn Texture<T>>,
+    tex1: TextureRef<T>,
+    tex2: TextureRef<T>,
     mapping: Box<dyn TextureMapping2D>,
     aa_method: AAMethod,
 }
 
 impl<T> CheckerboardTexture<T> {
     pub fn new(
-        tex1: Arc<dyn Texture<T>>,
-        tex2: Arc<dyn Texture<T>>,
+        tex1: TextureRef<T>,
+        tex2: TextureRef<T>,
         mapping: Box<dyn TextureMapping2D>,
         aa_method: AAMethod,
     ) -> CheckerboardTexture<T> {
@@ -77,7 +76,9 @@ impl CheckerboardTexture<Spectrum> {
 
             // Compute `aaMethod` for `CheckerboardTexture`
             let aa = tp.find_string("aamode", "closedform");
-            let aa_method = if aa == "none" {
+            // With "supersample", the filtering is done by the `SupersampleTexture` wrapping
+            // this one, so each of its samples can be a point sample.
+            let aa_method = if aa == "none" || aa == "supersample" {
                 AAMethod::None
             } else if aa == "closedform" {
                 AAMethod::ClosedForm
@@ -99,11 +100,11 @@ where
     T: Mul<f32, Output = T>,
     T: Add<Output = T>,
 {
-    fn evaluate(&self, si: &SurfaceInteraction<'_, '_>) -> T {
+    fn evaluate(&self, si: &SurfaceInteraction) -> T {
         let (st, dstdx, dstdy) = self.mapping.map(si);
         match self.aa_method {
             AAMethod::None => {
-                if (st.x.floor() as u32 + st.y.floor() as u32) % 2 == 0 {
+                if (st.x.floor() as u32 + st.y.floor() as u32).is_multiple_of(2) {
                     self.tex1.evaluate(si)
                 } else {
                     self.tex2.evaluate(si)
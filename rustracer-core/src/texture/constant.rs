@@ -33,7 +33,7 @@ impl ConstantTexture<Spectrum> {
 }
 
 impl<T: Copy + Debug + Send + Sync> Texture<T> for ConstantTexture<T> {
-    fn evaluate(&self, _si: &SurfaceInteraction<'_, '_>) -> T {
+    fn evaluate(&self, _si: &SurfaceInteraction) -> T {
         self.value
     }
 }
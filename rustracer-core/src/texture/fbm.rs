@@ -7,51 +7,86 @@ use crate::spectrum::Spectrum;
 use crate::texture::{IdentityMapping3D, Texture, TextureMapping3D};
 use crate::Transform;
 
+/// Fractional Brownian motion texture.
+///
+/// The noise is evaluated in texture space (i.e. the space the texture was declared in), so the
+/// pattern sticks to the object rather than swimming through world space when it's transformed.
+/// Parameters:
+/// * `octaves`: maximum number of octaves of noise (default 8)
+/// * `roughness`: amplitude falloff between successive octaves (default 0.5, "omega" is
+///   accepted as an alias)
+/// * `scale`: frequency multiplier applied to texture-space coordinates (default 1)
+/// * `seed`: selects a different noise pattern (default 0)
 #[derive(Debug)]
 pub struct FbmTexture<T> {
     mapping: Box<dyn TextureMapping3D>,
     roughness: f32,
     octaves: u32,
+    scale: f32,
+    seed: u32,
     _phantom: PhantomData<T>,
 }
 
 impl<T> FbmTexture<T> {
-    fn evaluate_as_float(&self, si: &SurfaceInteraction<'_, '_>) -> f32 {
+    pub fn new(
+        tex2world: &Transform,
+        octaves: u32,
+        roughness: f32,
+        scale: f32,
+        seed: u32,
+    ) -> FbmTexture<T> {
+        FbmTexture {
+            mapping: Box::new(IdentityMapping3D::new(tex2world.inverse())),
+            roughness,
+            octaves,
+            scale,
+            seed,
+            _phantom: PhantomData,
+        }
+    }
+
+    fn from_params(tex2world: &Transform, tp: &TextureParams<'_>) -> FbmTexture<T> {
+        let roughness = tp.find_float("omega", 0.5);
+        let roughness = tp.find_float("roughness", roughness);
+        let octaves = tp.find_int("octaves", 8).max(0) as u32;
+        let scale = tp.find_float("scale", 1.0);
+        let seed = tp.find_int("seed", 0) as u32;
+        Self::new(tex2world, octaves, roughness, scale, seed)
+    }
+
+    fn evaluate_as_float(&self, si: &SurfaceInteraction) -> f32 {
         let (p, dpdx, dpdy) = self.mapping.map(si);
-        noise::fbm(&p, &dpdx, &dpdy, self.roughness, self.octaves)
+        noise::fbm_seeded(
+            &(p * self.scale),
+            &(dpdx * self.scale),
+            &(dpdy * self.scale),
+            self.roughness,
+            self.octaves,
+            self.seed,
+        )
     }
 }
 
 impl FbmTexture<f32> {
     pub fn create_float(tex2world: &Transform, tp: &TextureParams<'_>) -> FbmTexture<f32> {
-        FbmTexture {
-            mapping: Box::new(IdentityMapping3D::new(tex2world.clone())),
-            roughness: tp.find_float("omega", 0.5),
-            octaves: tp.find_int("octaves", 8) as u32,
-            _phantom: PhantomData,
-        }
+        Self::from_params(tex2world, tp)
     }
 }
 
 impl FbmTexture<Spectrum> {
     pub fn create_spectrum(tex2world: &Transform, tp: &TextureParams<'_>) -> FbmTexture<Spectrum> {
-        FbmTexture {
-            mapping: Box::new(IdentityMapping3D::new(tex2world.clone())),
-            roughness: tp.find_float("omega", 0.5),
-            octaves: tp.find_int("octaves", 8) as u32,
-            _phantom: PhantomData,
-        }
+        Self::from_params(tex2world, tp)
     }
 }
 
 impl Texture<f32> for FbmTexture<f32> {
-    fn evaluate(&self, si: &SurfaceInteraction<'_, '_>) -> f32 {
+    fn evaluate(&self, si: &SurfaceInteraction) -> f32 {
         self.evaluate_as_float(si)
     }
 }
 
 impl Texture<Spectrum> for FbmTexture<Spectrum> {
-    fn evaluate(&self, si: &SurfaceInteraction<'_, '_>) -> Spectrum {
+    fn evaluate(&self, si: &SurfaceInteraction) -> Spectrum {
         Spectrum::from(self.evaluate_as_float(si))
     }
 }
@@ -1,97 +1,192 @@
-use num::Zero;
+use std::collections::HashMap;
 use std::fmt::Debug;
-use std::ops::{AddAssign, Div, Mul};
 use std::path::Path;
 use std::sync::Arc;
 
+use anyhow::Result;
 use log::{debug, info, warn};
+use parking_lot::RwLock;
 
-use crate::bounds::Bounds2i;
-use crate::fileutil;
-use crate::imageio::read_image;
+use crate::imageio::{is_srgb_format, read_image};
 use crate::interaction::SurfaceInteraction;
-use crate::mipmap::{MIPMap, WrapMode};
+use crate::mipmap::{FilterMode, MIPMap, WrapMode};
 use crate::paramset::TextureParams;
-use crate::spectrum::Spectrum;
+use crate::spectrum::{CoefficientSpectrum, Spectrum};
 use crate::texture::{Texture, TextureMapping2D, UVMapping2D};
 use crate::transform::Transform;
-use crate::{Clampable, Point2i};
+use crate::{Point2f, Point2i, Vector2f};
+
+/// Placeholder for the tile number in the filename of a UDIM texture set, e.g.
+/// `"color.<UDIM>.png"`.
+const UDIM_PATTERN: &str = "<UDIM>";
 
 #[derive(Debug)]
 pub struct ImageTexture<T> {
     mapping: Box<dyn TextureMapping2D>,
-    mipmap: Arc<MIPMap<T>>,
+    images: Images<T>,
+}
+
+/// The image(s) of a texture.
+#[derive(Debug)]
+enum Images<T> {
+    Single(Arc<MIPMap<T>>),
+    Udim(UdimTiles<T>),
+}
+
+/// How the texels of an image file are turned into a MIP map.
+#[derive(Debug, Copy, Clone)]
+pub struct MipMapSettings {
+    pub wrap_mode: WrapMode,
+    pub filter_mode: FilterMode,
+    pub max_aniso: f32,
+    pub max_level: Option<usize>,
+    /// Scale applied to the texel values
+    pub scale: f32,
+    /// Whether to decode the texels from sRGB
+    pub gamma: bool,
+}
+
+impl MipMapSettings {
+    /// Turn a pixel of the image file into a texel.
+    fn texel<T>(&self, p: &Spectrum, convert: fn(&Spectrum) -> T) -> T {
+        if self.gamma {
+            convert(&(self.scale * p.inverse_gamma_correct()))
+        } else {
+            convert(&(self.scale * *p))
+        }
+    }
+}
+
+/// A UDIM texture set: the (u, v) space is tiled with images, tile `1001 + u + 10 * v` covering
+/// `[u, u + 1) x [v, v + 1)`. The tiles are loaded the first time they're looked up, and the
+/// missing ones are black.
+#[derive(Debug)]
+struct UdimTiles<T> {
+    /// Filename of the tiles, with `UDIM_PATTERN` in place of the tile number
+    pattern: String,
+    settings: MipMapSettings,
+    convert: fn(&Spectrum) -> T,
+    tiles: RwLock<HashMap<u32, Option<Arc<MIPMap<T>>>>>,
 }
 
 impl<T> ImageTexture<T>
 where
-    T: Zero,
-    T: Clone,
-    T: Copy,
-    T: Clampable,
-    T: Debug,
-    T: AddAssign<T>,
-    T: Mul<f32, Output = T>,
-    T: Div<f32, Output = T>,
-    T: Sized,
-    T: Send + Sync,
+    T: CoefficientSpectrum,
 {
-    pub fn new<F: Fn(&Spectrum) -> T>(
-        path: &Path,
-        wrap_mode: WrapMode,
-        trilerp: bool,
-        max_aniso: f32,
-        scale: f32,
-        gamma: bool,
+    /// Create a texture from the given image file, or from a UDIM texture set if the filename
+    /// contains `<UDIM>`.
+    pub fn new(
+        filename: &str,
+        settings: MipMapSettings,
         map: Box<dyn TextureMapping2D>,
-        convert: F,
+        convert: fn(&Spectrum) -> T,
     ) -> ImageTexture<T> {
-        debug!("Loading texture {}", path.display());
-        let (res, texels) = match read_image(path) {
-            Ok((mut pixels, res)) => {
-                // Flip image in y; texture coordinate space has (0,0) at the lower
-                // left corner.
-                for y in 0..res.y / 2 {
-                    for x in 0..res.x {
-                        let o1 = (y * res.x + x) as usize;
-                        let o2 = ((res.y - 1 - y) * res.x + x) as usize;
-                        pixels.swap(o1, o2);
-                    }
-                }
-
-                (res, pixels)
-            }
-            Err(e) => {
+        let images = if filename.contains(UDIM_PATTERN) {
+            debug!("Using UDIM texture set {}", filename);
+            Images::Udim(UdimTiles {
+                pattern: filename.to_owned(),
+                settings,
+                convert,
+                tiles: RwLock::new(HashMap::new()),
+            })
+        } else {
+            let mipmap = read_mipmap(Path::new(filename), &settings, convert).unwrap_or_else(|e| {
                 warn!(
                     "Could not open texture file. Using grey texture instead: {}",
                     e
                 );
-                (Point2i::new(1, 1), vec![Spectrum::grey(0.18)])
-            }
+                let grey = settings.texel(&Spectrum::grey(0.18), convert);
+                MIPMap::new(
+                    Point2i::new(1, 1),
+                    &[grey],
+                    settings.filter_mode,
+                    settings.max_aniso,
+                    settings.wrap_mode,
+                    settings.max_level,
+                )
+            });
+            Images::Single(Arc::new(mipmap))
         };
+        ImageTexture {
+            mapping: map,
+            images,
+        }
+    }
+}
+
+/// Read an image file into a MIP map.
+fn read_mipmap<T>(
+    path: &Path,
+    settings: &MipMapSettings,
+    convert: fn(&Spectrum) -> T,
+) -> Result<MIPMap<T>>
+where
+    T: CoefficientSpectrum,
+{
+    debug!("Loading texture {}", path.display());
+    let (mut pixels, res) = read_image(path)?;
+    // Flip image in y; texture coordinate space has (0,0) at the lower
+    // left corner.
+    for y in 0..res.y / 2 {
+        for x in 0..res.x {
+            let o1 = (y * res.x + x) as usize;
+            let o2 = ((res.y - 1 - y) * res.x + x) as usize;
+            pixels.swap(o1, o2);
+        }
+    }
+
+    let converted_texels: Vec<T> = pixels.iter().map(|p| settings.texel(p, convert)).collect();
 
-        let converted_texels: Vec<T> = texels
-            .iter()
-            .map(|p| {
-                let s = if gamma {
-                    scale * p.inverse_gamma_correct()
-                } else {
-                    scale * *p
+    Ok(MIPMap::new(
+        res,
+        &converted_texels[..],
+        settings.filter_mode,
+        settings.max_aniso,
+        settings.wrap_mode,
+        settings.max_level,
+    ))
+}
+
+impl<T> UdimTiles<T>
+where
+    T: CoefficientSpectrum,
+{
+    /// The tile with the given number, loading it if it's the first time it's needed. Returns
+    /// `None` if the set doesn't have this tile.
+    fn tile(&self, number: u32) -> Option<Arc<MIPMap<T>>> {
+        if let Some(tile) = self.tiles.read().get(&number) {
+            return tile.clone();
+        }
+        let mut tiles = self.tiles.write();
+        tiles
+            .entry(number)
+            .or_insert_with(|| {
+                let filename = self.pattern.replace(UDIM_PATTERN, &number.to_string());
+                // Tiles are clamped so that their edges don't bleed into each other
+                let settings = MipMapSettings {
+                    wrap_mode: WrapMode::Clamp,
+                    ..self.settings
                 };
-                convert(&s)
+                match read_mipmap(Path::new(&filename), &settings, self.convert) {
+                    Ok(mipmap) => Some(Arc::new(mipmap)),
+                    Err(e) => {
+                        debug!("No UDIM tile {} ({}): {}", number, filename, e);
+                        None
+                    }
+                }
             })
-            .collect();
+            .clone()
+    }
 
-        let mipmap = Arc::new(MIPMap::new(
-            res,
-            &converted_texels[..],
-            trilerp,
-            max_aniso,
-            wrap_mode,
-        ));
-        ImageTexture {
-            mapping: map,
-            mipmap,
+    fn lookup(&self, st: Point2f, dstdx: Vector2f, dstdy: Vector2f) -> T {
+        let (u, v) = (st.x.floor(), st.y.floor());
+        if !(0.0..10.0).contains(&u) || v < 0.0 {
+            return T::zero();
+        }
+        let number = 1001 + u as u32 + 10 * v as u32;
+        match self.tile(number) {
+            Some(tile) => tile.lookup_diff(Point2f::new(st.x - u, st.y - v), dstdx, dstdy),
+            None => T::zero(),
         }
     }
 }
@@ -110,7 +205,8 @@ impl ImageTexture<Spectrum> {
             unimplemented!()
         };
         let max_aniso = tp.find_float("maxanisotropy", 8.0);
-        let trilerp = tp.find_bool("trilinear", false);
+        let filter_mode = filter_mode(tp);
+        let max_level = max_level(tp);
         let wrap = tp.find_string("wrap", "repeat");
         let wrap_mode = if wrap == "black" {
             WrapMode::Black
@@ -121,47 +217,46 @@ impl ImageTexture<Spectrum> {
         };
         let scale = tp.find_float("scale", 1.0);
         let filename = tp.find_filename("filename", "");
-        let gamma = tp.find_bool(
-            "gamma",
-            fileutil::has_extension(&filename, "tga") || fileutil::has_extension(&filename, "png"),
-        );
-
-        Self::new(
-            Path::new(&filename),
+        let gamma = decode_srgb(tp, &filename, true);
+        let settings = MipMapSettings {
             wrap_mode,
-            trilerp,
+            filter_mode,
             max_aniso,
+            max_level,
             scale,
             gamma,
-            Box::new(map),
-            convert_to_spectrum,
-        )
+        };
+
+        Self::new(&filename, settings, Box::new(map), convert_to_spectrum)
     }
 
     pub fn dump_mipmap(&self) {
+        let mipmap = match self.images {
+            Images::Single(ref mipmap) => mipmap,
+            Images::Udim(_) => {
+                warn!("Can't dump the MIPMap levels of a UDIM texture set");
+                return;
+            }
+        };
         info!("Dumping MIPMap levels for debugging...");
-        self.mipmap
-            .pyramid
-            .iter()
-            .enumerate()
-            .for_each(|(i, level)| {
-                let mut buf = Vec::new();
-                for y in 0..level.v_size() {
-                    for x in 0..level.u_size() {
-                        let p = level[(x, y)];
-                        buf.push(p[0]);
-                        buf.push(p[1]);
-                        buf.push(p[2]);
-                    }
+        mipmap.pyramid.iter().enumerate().for_each(|(i, level)| {
+            let mut buf = Vec::new();
+            for y in 0..level.v_size() {
+                for x in 0..level.u_size() {
+                    let p = level[(x, y)];
+                    buf.push(p[0]);
+                    buf.push(p[1]);
+                    buf.push(p[2]);
                 }
-                crate::imageio::write_image(
-                    format!("mipmap_level_{}.png", i),
-                    &buf[..],
-                    &Bounds2i::from_elements(0, 0, level.u_size() as i32, level.v_size() as i32),
-                    Point2i::new(level.u_size() as i32, level.v_size() as i32),
-                )
-                .unwrap();
-            });
+            }
+            crate::imageio::write_image(
+                format!("mipmap_level_{}.png", i),
+                &buf[..],
+                Point2i::new(level.u_size() as i32, level.v_size() as i32),
+                &Default::default(),
+            )
+            .unwrap();
+        });
     }
 }
 
@@ -179,7 +274,8 @@ impl ImageTexture<f32> {
             unimplemented!()
         };
         let max_aniso = tp.find_float("maxanisotropy", 8.0);
-        let trilerp = tp.find_bool("trilinear", false);
+        let filter_mode = filter_mode(tp);
+        let max_level = max_level(tp);
         let wrap = tp.find_string("wrap", "repeat");
         let wrap_mode = if wrap == "black" {
             WrapMode::Black
@@ -190,23 +286,66 @@ impl ImageTexture<f32> {
         };
         let scale = tp.find_float("scale", 1.0);
         let filename = tp.find_filename("filename", "");
-        let gamma = tp.find_bool(
-            "gamma",
-            fileutil::has_extension(&filename, "tga") || fileutil::has_extension(&filename, "png"),
-        );
-
-        Self::new(
-            Path::new(&filename),
+        let gamma = decode_srgb(tp, &filename, false);
+        let settings = MipMapSettings {
             wrap_mode,
-            trilerp,
+            filter_mode,
             max_aniso,
+            max_level,
             scale,
             gamma,
-            Box::new(map),
-            convert_to_float,
-        )
+        };
+
+        Self::new(&filename, settings, Box::new(map), convert_to_float)
     }
 }
+/// Filtering used for lookups: `"string filter"` is one of "ewa", "trilinear" or "nearest". If
+/// it's not given, `"bool trilinear"` selects between the first two.
+fn filter_mode(tp: &TextureParams<'_>) -> FilterMode {
+    let trilerp = tp.find_bool("trilinear", false);
+    let default = if trilerp { "trilinear" } else { "ewa" };
+    match tp.find_string("filter", default).as_str() {
+        "nearest" => FilterMode::Nearest,
+        "trilinear" => FilterMode::Trilinear,
+        "ewa" => FilterMode::Ewa,
+        f => {
+            warn!("Texture filter \"{}\" unknown. Using \"{}\".", f, default);
+            if trilerp {
+                FilterMode::Trilinear
+            } else {
+                FilterMode::Ewa
+            }
+        }
+    }
+}
+
+/// Index of the coarsest MIP level to build (`"integer maxmiplevel"`), or `None` to go all the
+/// way down to 1x1.
+fn max_level(tp: &TextureParams<'_>) -> Option<usize> {
+    let max_level = tp.find_int("maxmiplevel", -1);
+    if max_level < 0 {
+        None
+    } else {
+        Some(max_level as usize)
+    }
+}
+
+/// Whether the texels of `filename` should be decoded from sRGB to linear values. This is given
+/// by `"string colorspace"` ("srgb" or "linear") or `"bool gamma"`. By default, 8-bit formats
+/// are decoded for colour textures, but float textures (bump maps, masks...) are read as is.
+fn decode_srgb(tp: &TextureParams<'_>, filename: &str, colour: bool) -> bool {
+    let gamma = tp.find_bool("gamma", colour && is_srgb_format(filename));
+    match tp.find_string("colorspace", "").as_str() {
+        "" => gamma,
+        "srgb" => true,
+        "linear" => false,
+        cs => {
+            warn!("Texture colorspace \"{}\" unknown. Ignoring.", cs);
+            gamma
+        }
+    }
+}
+
 fn convert_to_spectrum(from: &Spectrum) -> Spectrum {
     *from
 }
@@ -217,20 +356,179 @@ fn convert_to_float(from: &Spectrum) -> f32 {
 
 impl<T> Texture<T> for ImageTexture<T>
 where
-    T: Zero,
-    T: Clone,
-    T: Copy,
-    T: Send,
-    T: Sync,
-    T: Clampable,
-    T: Debug,
-    T: AddAssign<T>,
-    T: Mul<f32, Output = T>,
-    T: Div<f32, Output = T>,
-    T: Sized,
+    T: CoefficientSpectrum,
 {
-    fn evaluate(&self, si: &SurfaceInteraction<'_, '_>) -> T {
+    fn evaluate(&self, si: &SurfaceInteraction) -> T {
         let (st, dstdx, dstdy) = self.mapping.map(si);
-        self.mipmap.lookup_diff(st, dstdx, dstdy)
+        match self.images {
+            Images::Single(ref mipmap) => mipmap.lookup_diff(st, dstdx, dstdy),
+            Images::Udim(ref tiles) => tiles.lookup(st, dstdx, dstdy),
+        }
+    }
+
+    fn evaluate_many(&self, sis: &[SurfaceInteraction]) -> Vec<T> {
+        let mipmap = match self.images {
+            Images::Single(ref mipmap) => mipmap,
+            Images::Udim(_) => return sis.iter().map(|si| self.evaluate(si)).collect(),
+        };
+        let lookups: Vec<_> = sis.iter().map(|si| self.mapping.map(si)).collect();
+        // Sort the lookups by MIP level and texel block, so that large textures are read a block
+        // at a time rather than randomly.
+        let mut order: Vec<usize> = (0..lookups.len()).collect();
+        order.sort_by_cached_key(|&i| {
+            let (st, dstdx, dstdy) = lookups[i];
+            mipmap.lookup_key(st, dstdx, dstdy)
+        });
+        let mut results = vec![T::zero(); lookups.len()];
+        for i in order {
+            let (st, dstdx, dstdy) = lookups[i];
+            results[i] = mipmap.lookup_diff(st, dstdx, dstdy);
+        }
+        results
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use super::*;
+    use crate::api::{Array, ParamListEntry, ParamType};
+    use crate::paramset::ParamSet;
+    use crate::rng::RNG;
+    use crate::shapes::Sphere;
+    use crate::{Normal3f, Point2f, Point3f, Vector3f};
+
+    #[test]
+    fn test_decode_srgb() {
+        let textures = (HashMap::new(), HashMap::new());
+        let decode = |entries: Vec<ParamListEntry>, filename: &str, colour: bool| {
+            let mut ps = ParamSet::default();
+            ps.init(entries);
+            let empty = ParamSet::default();
+            let tp = TextureParams::new(&ps, &empty, &textures.0, &textures.1);
+            decode_srgb(&tp, filename, colour)
+        };
+        let string = |name: &str, value: &str| {
+            ParamListEntry::new(
+                ParamType::String,
+                name.to_owned(),
+                Array::StrArray(vec![value.to_owned()]),
+            )
+        };
+
+        assert!(decode(vec![], "a.png", true));
+        assert!(!decode(vec![], "a.png", false));
+        assert!(!decode(vec![], "a.exr", true));
+        assert!(decode(vec![string("colorspace", "srgb")], "a.png", false));
+        assert!(!decode(vec![string("colorspace", "linear")], "a.png", true));
+        let gamma = ParamListEntry::new(
+            ParamType::Bool,
+            "gamma".to_owned(),
+            Array::StrArray(vec!["true".to_owned()]),
+        );
+        assert!(decode(vec![gamma], "a.exr", false));
+    }
+
+    #[test]
+    fn test_evaluate_many_matches_evaluate() {
+        let res = Point2i::new(64, 32);
+        let texels: Vec<f32> = (0..res.x * res.y).map(|i| (i % 7) as f32).collect();
+        let tex = ImageTexture {
+            mapping: Box::new(UVMapping2D::new(1.0, 1.0, 0.0, 0.0)),
+            images: Images::Single(Arc::new(MIPMap::new(
+                res,
+                &texels,
+                FilterMode::Ewa,
+                8.0,
+                WrapMode::Repeat,
+                None,
+            ))),
+        };
+
+        let sphere = Sphere::new(Transform::default(), 1.0, -1.0, 1.0, 360.0, false);
+        let mut rng = RNG::new();
+        let sis: Vec<_> = (0..100)
+            .map(|_| {
+                let mut si = SurfaceInteraction::new(
+                    Point3f::new(0.0, 0.0, 1.0),
+                    Vector3f::new(0.0, 0.0, 0.0),
+                    Point2f::new(rng.uniform_f32(), rng.uniform_f32()),
+                    0.0,
+                    Vector3f::new(0.0, 0.0, 1.0),
+                    Vector3f::new(1.0, 0.0, 0.0),
+                    Vector3f::new(0.0, 1.0, 0.0),
+                    Normal3f::new(0.0, 0.0, 0.0),
+                    Normal3f::new(0.0, 0.0, 0.0),
+                    &sphere,
+                );
+                si.dudx = 0.1 * rng.uniform_f32();
+                si.dvdy = 0.1 * rng.uniform_f32();
+                si
+            })
+            .collect();
+
+        let expected: Vec<f32> = sis.iter().map(|si| tex.evaluate(si)).collect();
+        assert_eq!(tex.evaluate_many(&sis), expected);
+    }
+
+    #[test]
+    fn test_udim_tiles() {
+        let dir = std::env::temp_dir().join("rustracer_udim");
+        std::fs::create_dir_all(&dir).unwrap();
+        for (tile, value) in &[(1001, 0.25), (1002, 0.75)] {
+            let path = dir.join(format!("tile.{}.exr", tile));
+            crate::imageio::write_image(
+                &path,
+                &[*value; 3 * 4],
+                Point2i::new(2, 2),
+                &Default::default(),
+            )
+            .unwrap();
+        }
+        let settings = MipMapSettings {
+            wrap_mode: WrapMode::Repeat,
+            filter_mode: FilterMode::Nearest,
+            max_aniso: 8.0,
+            max_level: None,
+            scale: 1.0,
+            gamma: false,
+        };
+        let tex = ImageTexture::new(
+            dir.join("tile.<UDIM>.exr").to_str().unwrap(),
+            settings,
+            Box::new(UVMapping2D::new(1.0, 1.0, 0.0, 0.0)),
+            convert_to_float,
+        );
+
+        let sphere = Sphere::new(Transform::default(), 1.0, -1.0, 1.0, 360.0, false);
+        let lookup = |u: f32, v: f32| {
+            let si = SurfaceInteraction::new(
+                Point3f::new(0.0, 0.0, 1.0),
+                Vector3f::new(0.0, 0.0, 0.0),
+                Point2f::new(u, v),
+                0.0,
+                Vector3f::new(0.0, 0.0, 1.0),
+                Vector3f::new(1.0, 0.0, 0.0),
+                Vector3f::new(0.0, 1.0, 0.0),
+                Normal3f::new(0.0, 0.0, 0.0),
+                Normal3f::new(0.0, 0.0, 0.0),
+                &sphere,
+            );
+            tex.evaluate(&si)
+        };
+        assert!((lookup(0.5, 0.5) - 0.25).abs() < 1e-3);
+        assert!((lookup(1.5, 0.5) - 0.75).abs() < 1e-3);
+        // Missing tiles are black, and only the tiles that are looked up are loaded
+        assert_eq!(lookup(0.5, 1.5), 0.0);
+        assert_eq!(lookup(-0.5, 0.5), 0.0);
+        match tex.images {
+            Images::Udim(ref tiles) => {
+                let tiles = tiles.tiles.read();
+                assert_eq!(tiles.len(), 3);
+                assert!(tiles[&1011].is_none());
+            }
+            Images::Single(_) => panic!("not a UDIM texture set"),
+        }
     }
 }
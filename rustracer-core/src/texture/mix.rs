@@ -1,18 +1,17 @@
 use std::fmt::Debug;
 use std::ops::{Add, Mul};
-use std::sync::Arc;
 
 use crate::interaction::SurfaceInteraction;
 use crate::paramset::TextureParams;
 use crate::spectrum::Spectrum;
-use crate::texture::{Texture, TextureFloat};
+use crate::texture::{Texture, TextureRef};
 use crate::Transform;
 
 #[derive(Debug)]
 pub struct MixTexture<T> {
-    tex1: Arc<dyn Texture<T>>,
-    tex2: Arc<dyn Texture<T>>,
-    amount: Arc<TextureFloat>,
+    tex1: TextureRef<T>,
+    tex2: TextureRef<T>,
+    amount: TextureRef<f32>,
 }
 
 impl<T> Texture<T> for MixTexture<T>
@@ -21,7 +20,7 @@ where
     T: Mul<f32, Output = T>,
     T: Add<Output = T>,
 {
-    fn evaluate(&self, si: &SurfaceInteraction<'_, '_>) -> T {
+    fn evaluate(&self, si: &SurfaceInteraction) -> T {
         let t1 = self.tex1.evaluate(si);
         let t2 = self.tex2.evaluate(si);
         let amt = self.amount.evaluate(si);
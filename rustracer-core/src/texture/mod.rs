@@ -1,4 +1,5 @@
 use std::fmt::Debug;
+use std::sync::Arc;
 
 use crate::interaction::SurfaceInteraction;
 use crate::spectrum::Spectrum;
@@ -10,6 +11,7 @@ mod fbm;
 mod imagemap;
 mod mix;
 mod scale;
+mod supersample;
 mod uv;
 
 pub use self::checkerboard::CheckerboardTexture;
@@ -18,20 +20,31 @@ pub use self::fbm::FbmTexture;
 pub use self::imagemap::ImageTexture;
 pub use self::mix::MixTexture;
 pub use self::scale::ScaleTexture;
+pub use self::supersample::{supersample_if_requested, SupersampleTexture};
 pub use self::uv::UVTexture;
 
 pub trait Texture<T>: Debug + Send + Sync {
-    fn evaluate(&self, si: &SurfaceInteraction<'_, '_>) -> T;
+    fn evaluate(&self, si: &SurfaceInteraction) -> T;
+
+    /// Evaluate the texture at several points at once. Textures can override this to reorder the
+    /// lookups in a more cache-friendly way, but the results are always in the same order as
+    /// `sis`.
+    fn evaluate_many(&self, sis: &[SurfaceInteraction]) -> Vec<T> {
+        sis.iter().map(|si| self.evaluate(si)).collect()
+    }
 }
 
 // Some convenient aliases
 pub type TextureSpectrum = dyn Texture<Spectrum>;
 pub type TextureFloat = dyn Texture<f32>;
+/// Shared reference to a texture. Textures are shared by the materials and named textures using
+/// them, and evaluated concurrently by the rendering threads.
+pub type TextureRef<T> = Arc<dyn Texture<T>>;
 
 // Texture mappings
 
 pub trait TextureMapping2D: Debug + Send + Sync {
-    fn map(&self, si: &SurfaceInteraction<'_, '_>) -> (Point2f, Vector2f, Vector2f);
+    fn map(&self, si: &SurfaceInteraction) -> (Point2f, Vector2f, Vector2f);
 }
 
 #[derive(Debug)]
@@ -49,7 +62,7 @@ impl UVMapping2D {
 }
 
 impl TextureMapping2D for UVMapping2D {
-    fn map(&self, si: &SurfaceInteraction<'_, '_>) -> (Point2f, Vector2f, Vector2f) {
+    fn map(&self, si: &SurfaceInteraction) -> (Point2f, Vector2f, Vector2f) {
         (
             Point2f::new(self.su * si.uv.x + self.du, self.sv * si.uv.y + self.dv),
             // dstdx
@@ -75,7 +88,7 @@ impl PlanarMapping2D {
 }
 
 impl TextureMapping2D for PlanarMapping2D {
-    fn map(&self, si: &SurfaceInteraction<'_, '_>) -> (Point2f, Vector2f, Vector2f) {
+    fn map(&self, si: &SurfaceInteraction) -> (Point2f, Vector2f, Vector2f) {
         let vec = Vector3f::from(si.hit.p);
         (
             Point2f::new(self.ds + vec.dot(&self.vs), self.dt + vec.dot(&self.vt)),
@@ -86,7 +99,7 @@ impl TextureMapping2D for PlanarMapping2D {
 }
 
 pub trait TextureMapping3D: Debug + Send + Sync {
-    fn map(&self, si: &SurfaceInteraction<'_, '_>) -> (Point3f, Vector3f, Vector3f);
+    fn map(&self, si: &SurfaceInteraction) -> (Point3f, Vector3f, Vector3f);
 }
 
 #[derive(Debug, Default)]
@@ -95,15 +108,13 @@ pub struct IdentityMapping3D {
 }
 
 impl IdentityMapping3D {
-    pub fn new(tex2world: Transform) -> IdentityMapping3D {
-        IdentityMapping3D {
-            world_to_texture: tex2world,
-        }
+    pub fn new(world_to_texture: Transform) -> IdentityMapping3D {
+        IdentityMapping3D { world_to_texture }
     }
 }
 
 impl TextureMapping3D for IdentityMapping3D {
-    fn map(&self, si: &SurfaceInteraction<'_, '_>) -> (Point3f, Vector3f, Vector3f) {
+    fn map(&self, si: &SurfaceInteraction) -> (Point3f, Vector3f, Vector3f) {
         let dpdx = &self.world_to_texture * &si.dpdx;
         let dpdy = &self.world_to_texture * &si.dpdy;
         let p = &self.world_to_texture * &si.hit.p;
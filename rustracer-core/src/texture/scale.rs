@@ -1,16 +1,21 @@
 use std::fmt::Debug;
 use std::ops::Mul;
-use std::sync::Arc;
 
 use crate::interaction::SurfaceInteraction;
 use crate::paramset::TextureParams;
 use crate::spectrum::Spectrum;
-use crate::texture::Texture;
+use crate::texture::{Texture, TextureRef};
 
 #[derive(Debug)]
 pub struct ScaleTexture<T> {
-    tex1: Arc<dyn Texture<T>>,
-    tex2: Arc<dyn Texture<T>>,
+    tex1: TextureRef<T>,
+    tex2: TextureRef<T>,
+}
+
+impl<T> ScaleTexture<T> {
+    pub fn new(tex1: TextureRef<T>, tex2: TextureRef<T>) -> ScaleTexture<T> {
+        ScaleTexture { tex1, tex2 }
+    }
 }
 
 impl<T> Texture<T> for ScaleTexture<T>
@@ -20,7 +25,7 @@ where
     T: Sync,
     T: Mul<Output = T>,
 {
-    fn evaluate(&self, si: &SurfaceInteraction<'_, '_>) -> T {
+    fn evaluate(&self, si: &SurfaceInteraction) -> T {
         self.tex1.evaluate(si) * self.tex2.evaluate(si)
     }
 }
@@ -0,0 +1,144 @@
+use std::fmt::Debug;
+use std::ops::{Add, Mul};
+use std::sync::Arc;
+
+use log::warn;
+
+use crate::interaction::SurfaceInteraction;
+use crate::paramset::TextureParams;
+use crate::rng::RNG;
+use crate::texture::{Texture, TextureRef};
+
+/// Generic antialiasing for textures that can't filter themselves (e.g. procedural ones): the
+/// wrapped texture is evaluated at several jittered positions within the footprint of the pixel
+/// (as given by the ray differentials), and the results are averaged.
+#[derive(Debug)]
+pub struct SupersampleTexture<T> {
+    tex: TextureRef<T>,
+    /// Number of samples along each axis of the footprint
+    n_samples: u32,
+}
+
+impl<T> SupersampleTexture<T> {
+    pub fn new(tex: TextureRef<T>, n_samples: u32) -> SupersampleTexture<T> {
+        SupersampleTexture {
+            tex,
+            n_samples: n_samples.max(1),
+        }
+    }
+}
+
+impl<T> Texture<T> for SupersampleTexture<T>
+where
+    T: Debug,
+    T: Mul<f32, Output = T>,
+    T: Add<Output = T>,
+{
+    fn evaluate(&self, si: &SurfaceInteraction) -> T {
+        let n = self.n_samples;
+        let inv_n = 1.0 / n as f32;
+        // Seed the jitter with the lookup point so that neighbouring pixels don't share the same
+        // pattern, while keeping evaluation deterministic.
+        let mut rng = RNG::new();
+        rng.set_sequence(u64::from(si.uv.x.to_bits()) << 32 | u64::from(si.uv.y.to_bits()));
+
+        let mut sub = si.clone();
+        // Each sample covers 1/n of the footprint along each axis
+        sub.dpdx = si.dpdx * inv_n;
+        sub.dpdy = si.dpdy * inv_n;
+        sub.dudx = si.dudx * inv_n;
+        sub.dvdx = si.dvdx * inv_n;
+        sub.dudy = si.dudy * inv_n;
+        sub.dvdy = si.dvdy * inv_n;
+
+        let mut subs = Vec::with_capacity((n * n) as usize);
+        for y in 0..n {
+            for x in 0..n {
+                // Stratified offsets in [-0.5, 0.5)^2
+                let dx = (x as f32 + rng.uniform_f32()) * inv_n - 0.5;
+                let dy = (y as f32 + rng.uniform_f32()) * inv_n - 0.5;
+                sub.hit.p = si.hit.p + si.dpdx * dx + si.dpdy * dy;
+                sub.uv.x = si.uv.x + si.dudx * dx + si.dudy * dy;
+                sub.uv.y = si.uv.y + si.dvdx * dx + si.dvdy * dy;
+                subs.push(sub.clone());
+            }
+        }
+
+        self.tex
+            .evaluate_many(&subs)
+            .into_iter()
+            .reduce(|acc, v| acc + v)
+            .expect("at least one sample")
+            * (inv_n * inv_n)
+    }
+}
+
+/// Wrap `tex` in a `SupersampleTexture` if its parameters contain `"string aamode"
+/// "supersample"`. The number of samples along each axis is given by `"integer aasamples"`.
+pub fn supersample_if_requested<T>(tex: TextureRef<T>, tp: &TextureParams<'_>) -> TextureRef<T>
+where
+    T: Debug + 'static,
+    T: Mul<f32, Output = T>,
+    T: Add<Output = T>,
+{
+    if tp.find_string("aamode", "") != "supersample" {
+        return tex;
+    }
+    let n_samples = tp.find_int("aasamples", 4);
+    if n_samples < 1 {
+        warn!(
+            "Invalid \"aasamples\" value {}. Using 1 instead.",
+            n_samples
+        );
+    }
+    Arc::new(SupersampleTexture::new(tex, n_samples.max(1) as u32))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::shapes::Sphere;
+    use crate::{Normal3f, Point2f, Point3f, Transform, Vector3f};
+
+    /// 0 for u < 0.5, 1 otherwise.
+    #[derive(Debug)]
+    struct StepTexture;
+
+    impl Texture<f32> for StepTexture {
+        fn evaluate(&self, si: &SurfaceInteraction) -> f32 {
+            if si.uv.x < 0.5 {
+                0.0
+            } else {
+                1.0
+            }
+        }
+    }
+
+    #[test]
+    fn test_supersampling_averages_over_footprint() {
+        let sphere = Sphere::new(Transform::default(), 1.0, -1.0, 1.0, 360.0, false);
+        let mut si = SurfaceInteraction::new(
+            Point3f::new(0.0, 0.0, 1.0),
+            Vector3f::new(0.0, 0.0, 0.0),
+            Point2f::new(0.5, 0.5),
+            0.0,
+            Vector3f::new(0.0, 0.0, 1.0),
+            Vector3f::new(1.0, 0.0, 0.0),
+            Vector3f::new(0.0, 1.0, 0.0),
+            Normal3f::new(0.0, 0.0, 0.0),
+            Normal3f::new(0.0, 0.0, 0.0),
+            &sphere,
+        );
+
+        let tex = SupersampleTexture::new(Arc::new(StepTexture), 8);
+        // Without differentials, every sample is at the same point
+        assert_eq!(tex.evaluate(&si), 1.0);
+
+        // The footprint straddles the edge of the step, so roughly half the samples see 1
+        si.dudx = 0.2;
+        si.dvdy = 0.2;
+        let v = tex.evaluate(&si);
+        assert!((v - 0.5).abs() < 0.1, "v = {}", v);
+        assert_eq!(tex.evaluate(&si), v);
+    }
+}
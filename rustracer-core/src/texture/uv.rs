@@ -48,7 +48,7 @@ impl Default for UVTexture {
 }
 
 impl Texture<Spectrum> for UVTexture {
-    fn evaluate(&self, si: &SurfaceInteraction<'_, '_>) -> Spectrum {
+    fn evaluate(&self, si: &SurfaceInteraction) -> Spectrum {
         let (st, _dstdx, _dstdy) = self.mapping.map(si);
         Spectrum::rgb(st[0] - st[0].floor(), st[1] - st[1].floor(), 0.0)
     }
@@ -4,7 +4,8 @@ use log::error;
 
 use crate::bounds::Bounds3f;
 use crate::geometry::Matrix4x4;
-use crate::{gamma, Normal3f, Point3f, Vector2f, Vector3f};
+use crate::quaternion::Quaternion;
+use crate::{gamma, lerp, Normal3f, Point3f, Vector2f, Vector3f};
 
 #[derive(Debug, Clone, Default)]
 pub struct Transform {
@@ -252,6 +253,43 @@ impl Transform {
         )
     }
 
+    /// Transform the box `b`, returning a box that bounds the result (i.e. the bounds of its
+    /// transformed corners).
+    pub fn transform_bounds(&self, b: &Bounds3f) -> Bounds3f {
+        let mut ret =
+            Bounds3f::from_point(&(self * &Point3f::new(b.p_min.x, b.p_min.y, b.p_min.z)));
+        ret = Bounds3f::union_point(
+            &ret,
+            &(self * &Point3f::new(b.p_max.x, b.p_min.y, b.p_min.z)),
+        );
+        ret = Bounds3f::union_point(
+            &ret,
+            &(self * &Point3f::new(b.p_min.x, b.p_max.y, b.p_min.z)),
+        );
+        ret = Bounds3f::union_point(
+            &ret,
+            &(self * &Point3f::new(b.p_min.x, b.p_min.y, b.p_max.z)),
+        );
+        ret = Bounds3f::union_point(
+            &ret,
+            &(self * &Point3f::new(b.p_min.x, b.p_max.y, b.p_max.z)),
+        );
+        ret = Bounds3f::union_point(
+            &ret,
+            &(self * &Point3f::new(b.p_max.x, b.p_max.y, b.p_min.z)),
+        );
+        ret = Bounds3f::union_point(
+            &ret,
+            &(self * &Point3f::new(b.p_max.x, b.p_min.y, b.p_max.z)),
+        );
+        ret = Bounds3f::union_point(
+            &ret,
+            &(self * &Point3f::new(b.p_max.x, b.p_max.y, b.p_max.z)),
+        );
+
+        ret
+    }
+
     pub fn swaps_handedness(&self) -> bool {
         let m = self.m.m;
         let det = m[0][0] * (m[1][1] * m[2][2] - m[1][2] * m[2][1])
@@ -261,7 +299,7 @@ impl Transform {
     }
 }
 
-impl<'a, 'b> Mul<&'a Point3f> for &'b Transform {
+impl<'a> Mul<&'a Point3f> for &Transform {
     type Output = Point3f;
 
     #[allow(clippy::suspicious_arithmetic_impl)]
@@ -285,7 +323,7 @@ impl<'a, 'b> Mul<&'a Point3f> for &'b Transform {
     }
 }
 
-impl<'a, 'b> Mul<&'a Vector3f> for &'b Transform {
+impl<'a> Mul<&'a Vector3f> for &Transform {
     type Output = Vector3f;
 
     fn mul(self, v: &'a Vector3f) -> Vector3f {
@@ -302,7 +340,7 @@ impl<'a, 'b> Mul<&'a Vector3f> for &'b Transform {
     }
 }
 
-impl<'a, 'b> Mul<&'a Normal3f> for &'b Transform {
+impl<'a> Mul<&'a Normal3f> for &Transform {
     type Output = Normal3f;
 
     fn mul(self, n: &'a Normal3f) -> Normal3f {
@@ -317,7 +355,7 @@ impl<'a, 'b> Mul<&'a Normal3f> for &'b Transform {
     }
 }
 
-impl<'a, 'b> Mul<&'a Transform> for &'b Transform {
+impl<'a> Mul<&'a Transform> for &Transform {
     type Output = Transform;
 
     fn mul(self, t: &'a Transform) -> Transform {
@@ -339,43 +377,196 @@ impl Mul<Transform> for Transform {
     }
 }
 
-impl<'a, 'b> Mul<&'a Bounds3f> for &'b Transform {
+impl<'a> Mul<&'a Bounds3f> for &Transform {
     type Output = Bounds3f;
 
     fn mul(self, b: &'a Bounds3f) -> Bounds3f {
-        let mut ret =
-            Bounds3f::from_point(&(self * &Point3f::new(b.p_min.x, b.p_min.y, b.p_min.z)));
-        ret = Bounds3f::union_point(
-            &ret,
-            &(self * &Point3f::new(b.p_max.x, b.p_min.y, b.p_min.z)),
-        );
-        ret = Bounds3f::union_point(
-            &ret,
-            &(self * &Point3f::new(b.p_min.x, b.p_max.y, b.p_min.z)),
-        );
-        ret = Bounds3f::union_point(
-            &ret,
-            &(self * &Point3f::new(b.p_min.x, b.p_min.y, b.p_max.z)),
-        );
-        ret = Bounds3f::union_point(
-            &ret,
-            &(self * &Point3f::new(b.p_min.x, b.p_max.y, b.p_max.z)),
-        );
-        ret = Bounds3f::union_point(
-            &ret,
-            &(self * &Point3f::new(b.p_max.x, b.p_max.y, b.p_min.z)),
-        );
-        ret = Bounds3f::union_point(
-            &ret,
-            &(self * &Point3f::new(b.p_max.x, b.p_min.y, b.p_max.z)),
-        );
-        ret = Bounds3f::union_point(
-            &ret,
-            &(self * &Point3f::new(b.p_max.x, b.p_max.y, b.p_max.z)),
-        );
+        self.transform_bounds(b)
+    }
+}
 
-        ret
+/// Number of intervals the shutter interval is split into to bound the motion of rotating
+/// transforms (see `AnimatedTransform::motion_bounds()`)
+const MOTION_BOUNDS_STEPS: usize = 16;
+
+/// Transform that moves between two keyframes over the shutter interval. Each keyframe is
+/// decomposed into a translation, a rotation and a scale, which are interpolated separately
+/// (the rotation with `Quaternion::slerp()`) so that rotations don't shrink objects midway.
+#[derive(Debug, Clone)]
+pub struct AnimatedTransform {
+    start_transform: Transform,
+    end_transform: Transform,
+    start_time: f32,
+    end_time: f32,
+    actually_animated: bool,
+    has_rotation: bool,
+    t: [Vector3f; 2],
+    r: [Quaternion; 2],
+    s: [Matrix4x4; 2],
+}
+
+impl AnimatedTransform {
+    pub fn new(
+        start_transform: Transform,
+        start_time: f32,
+        end_transform: Transform,
+        end_time: f32,
+    ) -> AnimatedTransform {
+        let actually_animated = start_transform.m != end_transform.m;
+        let (t0, mut r0, s0) = decompose(&start_transform.m);
+        let (t1, mut r1, s1) = decompose(&end_transform.m);
+        // Flip one of the rotations if needed to take the shortest path between them
+        if r0.dot(&r1) < 0.0 {
+            r1 = -r1;
+        }
+        r0 = r0.normalize();
+        r1 = r1.normalize();
+        let has_rotation = r0.dot(&r1) < 0.9995;
+        AnimatedTransform {
+            start_transform,
+            end_transform,
+            start_time,
+            end_time,
+            actually_animated,
+            has_rotation,
+            t: [t0, t1],
+            r: [r0, r1],
+            s: [s0, s1],
+        }
+    }
+
+    pub fn is_animated(&self) -> bool {
+        self.actually_animated
+    }
+
+    /// The transform at the start of the shutter interval.
+    pub fn start(&self) -> &Transform {
+        &self.start_transform
+    }
+
+    /// The transform at the given time, which is clamped to the shutter interval.
+    pub fn interpolate(&self, time: f32) -> Transform {
+        if !self.actually_animated || time <= self.start_time {
+            return self.start_transform.clone();
+        }
+        if time >= self.end_time {
+            return self.end_transform.clone();
+        }
+        let dt = (time - self.start_time) / (self.end_time - self.start_time);
+        let (translation, rotation, scale) = self.components(dt);
+        &(&Transform::translate(&translation) * &rotation.to_transform())
+            * &Transform::from_matrix(scale)
+    }
+
+    /// Bounds of `b` transformed over the whole shutter interval.
+    ///
+    /// Without rotation, the corners of `b` move along straight lines, so the bounds of `b` at
+    /// both ends of the interval are enough. With one, they move along curves: their positions
+    /// are sampled at `MOTION_BOUNDS_STEPS + 1` times, and the resulting bounds are padded by
+    /// half of the farthest any corner can move between two samples, given the speed of the
+    /// translation, of the rotation and of the change of scale. This is conservative, so that
+    /// moving objects aren't clipped by their bounds.
+    pub fn motion_bounds(&self, b: &Bounds3f) -> Bounds3f {
+        if !self.actually_animated {
+            return &self.start_transform * b;
+        }
+        if !self.has_rotation {
+            return Bounds3f::union(&(&self.start_transform * b), &(&self.end_transform * b));
+        }
+        let steps = MOTION_BOUNDS_STEPS;
+        let transforms: Vec<_> = (0..=steps)
+            .map(|i| {
+                let (translation, rotation, scale) = self.components(i as f32 / steps as f32);
+                (translation, rotation.to_transform(), scale)
+            })
+            .collect();
+        let translation_speed = (self.t[1] - self.t[0]).length();
+        let angular_speed = self.r[0].angle(&self.r[1]);
+
+        let mut bounds = &self.start_transform * b;
+        let mut pad = 0.0f32;
+        for corner in 0..8 {
+            let p = b.corner(corner);
+            let (p0, p1) = (
+                transform_point(&self.s[0], &p),
+                transform_point(&self.s[1], &p),
+            );
+            // Bound on the speed of the corner, as a distance per unit of interpolation
+            let speed = translation_speed
+                + angular_speed * Vector3f::from(p0).length().max(Vector3f::from(p1).length())
+                + (p1 - p0).length();
+            pad = pad.max(0.5 * speed / steps as f32);
+            for (translation, rotation, scale) in &transforms {
+                let scaled = transform_point(scale, &p);
+                bounds = Bounds3f::union_point(&bounds, &((rotation * &scaled) + *translation));
+            }
+        }
+        let pad = Vector3f::new(pad, pad, pad);
+        Bounds3f::from_points(&(bounds.p_min - pad), &(bounds.p_max + pad))
+    }
+
+    /// Translation, rotation and scale at `dt` (from 0 to 1) in the shutter interval.
+    fn components(&self, dt: f32) -> (Vector3f, Quaternion, Matrix4x4) {
+        let translation = lerp(dt, self.t[0], self.t[1]);
+        let rotation = Quaternion::slerp(dt, &self.r[0], &self.r[1]);
+        let mut scale = Matrix4x4::new();
+        for i in 0..3 {
+            for j in 0..3 {
+                scale.m[i][j] = lerp(dt, self.s[0].m[i][j], self.s[1].m[i][j]);
+            }
+        }
+        (translation, rotation, scale)
+    }
+}
+
+/// Decompose the affine transformation `m` into a translation, a rotation and a scale (which may
+/// also have some shear), with a polar decomposition.
+fn decompose(m: &Matrix4x4) -> (Vector3f, Quaternion, Matrix4x4) {
+    let translation = Vector3f::new(m.m[0][3], m.m[1][3], m.m[2][3]);
+
+    // The rest is the upper 3x3 part of the matrix
+    let mut upper = *m;
+    for i in 0..3 {
+        upper.m[i][3] = 0.0;
+        upper.m[3][i] = 0.0;
     }
+    upper.m[3][3] = 1.0;
+
+    // Average the matrix with its inverse transpose until it converges to the rotation
+    let mut r = upper;
+    for _ in 0..100 {
+        let r_it = r.transpose().inverse();
+        let mut r_next = Matrix4x4::new();
+        for i in 0..4 {
+            for j in 0..4 {
+                r_next.m[i][j] = 0.5 * (r.m[i][j] + r_it.m[i][j]);
+            }
+        }
+        let norm = (0..3)
+            .map(|i| {
+                (0..3)
+                    .map(|j| (r.m[i][j] - r_next.m[i][j]).abs())
+                    .sum::<f32>()
+            })
+            .fold(0.0, f32::max);
+        r = r_next;
+        if norm <= 1e-4 {
+            break;
+        }
+    }
+    let rotation = Quaternion::from_transform(&Transform::from_matrix(r));
+    let scale = &r.inverse() * &upper;
+    (translation, rotation, scale)
+}
+
+/// Apply the upper 3x3 part of `m` to `p`.
+fn transform_point(m: &Matrix4x4, p: &Point3f) -> Point3f {
+    let m = m.m;
+    Point3f::new(
+        m[0][0] * p.x + m[0][1] * p.y + m[0][2] * p.z,
+        m[1][0] * p.x + m[1][1] * p.y + m[1][2] * p.z,
+        m[2][0] * p.x + m[2][1] * p.y + m[2][2] * p.z,
+    )
 }
 
 #[allow(non_snake_case)]
@@ -412,4 +603,62 @@ mod tests {
         println!("v = {}, n = {}", v2, n2);
         assert_relative_eq!(v2.dotn(&n2), 0.0);
     }
+
+    #[test]
+    fn test_transform_bounds() {
+        let b = Bounds3f::from_points(&Point3f::new(0.0, 0.0, 0.0), &Point3f::new(1.0, 2.0, 3.0));
+        let t = Transform::translate(&Vector3f::new(1.0, 0.0, 0.0)) * Transform::rot_z(90.0);
+        let tb = t.transform_bounds(&b);
+        assert_relative_eq!(tb.p_min.x, -1.0, epsilon = 1e-5);
+        assert_relative_eq!(tb.p_max.x, 1.0, epsilon = 1e-5);
+        assert_relative_eq!(tb.p_min.y, 0.0, epsilon = 1e-5);
+        assert_relative_eq!(tb.p_max.y, 1.0, epsilon = 1e-5);
+        assert_relative_eq!(tb.p_max.z, 3.0, epsilon = 1e-5);
+    }
+
+    #[test]
+    fn test_animated_transform() {
+        let start = &Transform::translate(&Vector3f::new(1.0, 0.0, 0.0)) * &Transform::rot_z(10.0);
+        let end = &Transform::translate(&Vector3f::new(3.0, 0.0, 0.0))
+            * &(&Transform::rot_z(100.0) * &Transform::scale(2.0, 2.0, 2.0));
+        let animated = AnimatedTransform::new(start.clone(), 0.0, end, 1.0);
+        assert!(animated.is_animated());
+        let p = Point3f::new(1.0, 0.0, 0.0);
+        let at = |time: f32| &animated.interpolate(time) * &p;
+        assert_relative_eq!((at(-1.0) - &start * &p).length(), 0.0, epsilon = 1e-5);
+        // Halfway: translated by 2, rotated by 55° and scaled by 1.5
+        let (sin, cos) = 55f32.to_radians().sin_cos();
+        let expected = Point3f::new(2.0 + 1.5 * cos, 1.5 * sin, 0.0);
+        assert_relative_eq!((at(0.5) - expected).length(), 0.0, epsilon = 1e-4);
+
+        // The bounds contain every position along the motion, not just those at both ends
+        let b = Bounds3f::from_points(
+            &Point3f::new(-1.0, -1.0, -1.0),
+            &Point3f::new(1.0, 1.0, 1.0),
+        );
+        let bounds = animated.motion_bounds(&b);
+        for i in 0..=1000 {
+            let t = animated.interpolate(i as f32 / 1000.0);
+            for corner in 0..8 {
+                let q = &t * &b.corner(corner);
+                assert!(bounds.inside(&q), "{} outside of {:?}", q, bounds);
+            }
+        }
+        let ends = Bounds3f::union(
+            &(&animated.interpolate(0.0) * &b),
+            &(&animated.interpolate(1.0) * &b),
+        );
+        assert!(bounds.p_min.x < ends.p_min.x);
+
+        // Without rotation, the bounds at both ends are enough
+        let sliding = AnimatedTransform::new(
+            Transform::default(),
+            0.0,
+            Transform::translate(&Vector3f::new(0.0, 5.0, 0.0)),
+            1.0,
+        );
+        let bounds = sliding.motion_bounds(&b);
+        assert_eq!(bounds.p_min, Point3f::new(-1.0, -1.0, -1.0));
+        assert_eq!(bounds.p_max, Point3f::new(1.0, 6.0, 1.0));
+    }
 }
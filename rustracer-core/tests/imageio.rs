@@ -0,0 +1,138 @@
+use std::path::PathBuf;
+
+use rustracer_core::imageio::{read_image, read_image_linear, resize, write_image, ImageMetadata};
+use rustracer_core::Point2i;
+
+fn temp_path(name: &str) -> PathBuf {
+    let mut path = std::env::temp_dir();
+    path.push(format!("rustracer-imageio-{}-{}", std::process::id(), name));
+    path
+}
+
+fn test_image() -> (Vec<f32>, Point2i) {
+    let res = Point2i::new(4, 3);
+    let rgb = (0..res.x * res.y * 3)
+        .map(|i| (i as f32) / (res.x * res.y * 3) as f32)
+        .collect();
+    (rgb, res)
+}
+
+fn round_trip(name: &str, tolerance: f32) {
+    let (rgb, res) = test_image();
+    let path = temp_path(name);
+    write_image(&path, &rgb, res, &ImageMetadata::default()).unwrap();
+    let (pixels, read_res) = read_image_linear(&path).unwrap();
+    std::fs::remove_file(&path).unwrap();
+
+    assert_eq!(read_res, res);
+    assert_eq!(pixels.len(), (res.x * res.y) as usize);
+    for (p, expected) in pixels.iter().zip(rgb.chunks(3)) {
+        for c in 0..3 {
+            assert!(
+                (p[c] - expected[c]).abs() <= tolerance,
+                "{}: {} != {}",
+                name,
+                p[c],
+                expected[c]
+            );
+        }
+    }
+}
+
+#[test]
+fn round_trip_pfm() {
+    round_trip("test.pfm", 0.0);
+}
+
+#[test]
+fn round_trip_exr() {
+    round_trip("test.exr", 0.0);
+}
+
+#[test]
+fn round_trip_hdr() {
+    // RGBE has an 8-bit mantissa per channel
+    round_trip("test.hdr", 1.0 / 128.0);
+}
+
+#[test]
+fn round_trip_png() {
+    round_trip("test.png", 1.0 / 64.0);
+}
+
+#[test]
+fn round_trip_tga() {
+    round_trip("test.TGA", 1.0 / 64.0);
+}
+
+#[test]
+fn png_is_stored_gamma_corrected() {
+    let path = temp_path("gamma.png");
+    write_image(
+        &path,
+        &[0.5, 0.5, 0.5],
+        Point2i::new(1, 1),
+        &ImageMetadata::default(),
+    )
+    .unwrap();
+    let (pixels, _) = read_image(&path).unwrap();
+    std::fs::remove_file(&path).unwrap();
+
+    // sRGB encoding of 0.5 is ~0.735
+    assert!((pixels[0][0] - 0.735).abs() < 0.01);
+}
+
+#[test]
+fn wrong_buffer_size_is_an_error() {
+    let path = temp_path("wrong.pfm");
+    assert!(write_image(
+        &path,
+        &[0.0; 5],
+        Point2i::new(1, 2),
+        &ImageMetadata::default()
+    )
+    .is_err());
+}
+
+#[test]
+fn dithering_preserves_average_and_extremes() {
+    // A flat value halfway between two 8-bit levels, plus pure black and white
+    let res = Point2i::new(64, 64);
+    let level = 100.5 / 255.0;
+    // Linear value that gamma corrects to `level`
+    let linear = ((level + 0.055) / 1.055f32).powf(2.4);
+    let mut rgb = vec![linear; (3 * res.x * res.y) as usize];
+    rgb[0] = 0.0;
+    rgb[1] = 1.0;
+
+    let path = temp_path("dither.png");
+    let metadata = ImageMetadata {
+        dither: true,
+        ..ImageMetadata::default()
+    };
+    write_image(&path, &rgb, res, &metadata).unwrap();
+    let (pixels, _) = read_image(&path).unwrap();
+    std::fs::remove_file(&path).unwrap();
+
+    assert_eq!(pixels[0][0], 0.0);
+    assert_eq!(pixels[0][1], 1.0);
+    let values: Vec<f32> = pixels[1..].iter().map(|p| p[2] * 255.0).collect();
+    // Without dithering every pixel would round to the same level
+    assert!(values.iter().any(|v| v.round() != values[0].round()));
+    let mean = values.iter().sum::<f32>() / values.len() as f32;
+    assert!((mean - 100.5).abs() < 0.1, "mean = {}", mean);
+}
+
+#[test]
+fn resize_averages_covered_pixels() {
+    // 4x2 image with a single channel ramp
+    let rgb: Vec<f32> = (0..8).flat_map(|i| [i as f32; 3]).collect();
+    let half = resize(&rgb, Point2i::new(4, 2), Point2i::new(2, 1));
+    assert_eq!(half, vec![2.5, 2.5, 2.5, 4.5, 4.5, 4.5]);
+
+    // Enlarging picks the nearest pixel
+    let double = resize(&half, Point2i::new(2, 1), Point2i::new(4, 2));
+    assert_eq!(double.len(), 3 * 8);
+    assert_eq!(&double[0..6], &[2.5; 6]);
+    assert_eq!(&double[21..24], &[4.5; 3]);
+}
@@ -0,0 +1,50 @@
+use rustracer_core::{init_stats, pbrt, PbrtOptions};
+
+/// A light above a diffuse floor and a plastic sphere (diffuse and glossy lobes)
+const SCENE: &str = r#"
+Film "image" "integer xresolution" [16] "integer yresolution" [16]
+    "string aovs" ["emission" "diffuse" "specular" "all"]
+    "string aovexpressions" ["CL" "CD.*L" "CS.*L" "C.*L"]
+LookAt 0 3 -6  0 0.5 0  0 1 0
+Camera "perspective" "float fov" [50]
+Sampler "02sequence" "integer pixelsamples" [4]
+WorldBegin
+AttributeBegin
+    AreaLightSource "diffuse" "rgb L" [4 4 4]
+    Translate 0 2.5 0
+    Shape "sphere" "float radius" [0.5]
+AttributeEnd
+Material "matte"
+Shape "trianglemesh" "integer indices" [0 1 2 0 2 3]
+    "point P" [-5 -1 -5  5 -1 -5  5 -1 5  -5 -1 5]
+Material "plastic" "float roughness" [0.05]
+Shape "sphere" "float radius" [1]
+WorldEnd
+"#;
+
+#[test]
+fn aovs_partition_the_image() {
+    init_stats();
+    let opts = PbrtOptions {
+        num_threads: 2,
+        defer_render: true,
+        ..PbrtOptions::default()
+    };
+    let mut context = pbrt::parse_scene_string(opts, SCENE).unwrap().unwrap();
+    context.render_in_memory();
+    let film = context.camera.get_film();
+    assert_eq!(film.aovs().len(), 4);
+    let rgb = film.rgb();
+    let aovs: Vec<_> = (0..4).map(|i| film.aov_rgb(i)).collect();
+
+    for (name, aov) in ["emission", "diffuse", "specular"].iter().zip(&aovs) {
+        assert!(aov.iter().any(|v| *v > 0.0), "{} is black", name);
+    }
+    let sum = |v: &[f32]| v.iter().sum::<f32>();
+    // Every path starts with either an emission, a diffuse or a specular event
+    let parts = sum(&aovs[0]) + sum(&aovs[1]) + sum(&aovs[2]);
+    assert!((parts - sum(&rgb)).abs() < 1e-3 * sum(&rgb));
+    for (v, all) in rgb.iter().zip(&aovs[3]) {
+        assert!((v - all).abs() < 1e-4 * v.max(1.0));
+    }
+}
@@ -0,0 +1,83 @@
+use std::fs;
+
+use rustracer_core::{init_stats, pbrt, PbrtOptions};
+
+/// A 2x2 quad facing the camera
+const QUAD: &str = "ply
+format ascii 1.0
+element vertex 4
+property float x
+property float y
+property float z
+element face 1
+property list uchar int vertex_indices
+end_header
+-1 -1 0
+1 -1 0
+1 1 0
+-1 1 0
+4 0 1 2 3
+";
+
+fn render(shape: &str) -> Vec<f32> {
+    init_stats();
+    let scene = format!(
+        "Film \"image\" \"integer xresolution\" [8] \"integer yresolution\" [8]
+LookAt 0 0 -5  0 0 0  0 1 0
+Camera \"perspective\" \"float fov\" [40]
+Sampler \"02sequence\" \"integer pixelsamples\" [1]
+Integrator \"path\" \"integer maxdepth\" [1]
+WorldBegin
+LightSource \"infinite\"
+Material \"matte\"
+{}
+WorldEnd
+",
+        shape
+    );
+    let opts = PbrtOptions {
+        num_threads: 2,
+        defer_render: true,
+        ..PbrtOptions::default()
+    };
+    let mut context = pbrt::parse_scene_string(opts, &scene).unwrap().unwrap();
+    context.render_in_memory();
+    context.camera.get_film().rgb()
+}
+
+#[test]
+fn proxies_render_like_meshes() {
+    let dir = std::env::temp_dir().join("rustracer_proxy");
+    fs::create_dir_all(&dir).unwrap();
+    let ply = dir.join("quad.ply");
+    fs::write(&ply, QUAD).unwrap();
+    let ply = ply.display();
+
+    let mesh = render(&format!(
+        "Shape \"plymesh\" \"string filename\" \"{}\"",
+        ply
+    ));
+    let proxy = render(&format!(
+        "Shape \"proxy\" \"string filename\" \"{}\" \"float bounds\" [-1 -1 0 1 1 0]",
+        ply
+    ));
+    let computed_bounds = render(&format!("Shape \"proxy\" \"string filename\" \"{}\"", ply));
+    assert_ne!(mesh, render(""));
+    assert_eq!(mesh, proxy);
+    assert_eq!(mesh, computed_bounds);
+}
+
+#[test]
+fn missing_proxy_mesh_is_an_error() {
+    init_stats();
+    let scene = "Camera \"perspective\"
+WorldBegin
+Shape \"proxy\" \"string filename\" \"/nonexistent/mesh.ply\"
+WorldEnd
+";
+    let opts = PbrtOptions {
+        defer_render: true,
+        ..PbrtOptions::default()
+    };
+    assert!(pbrt::parse_scene_string(opts, scene).is_err());
+}
@@ -0,0 +1,371 @@
+use std::sync::Arc;
+
+use rustracer_core::bounds::Bounds2i;
+use rustracer_core::bvh::{SplitMethod, BVH};
+use rustracer_core::film;
+use rustracer_core::imageio;
+use rustracer_core::pbrt;
+use rustracer_core::ray::Ray;
+use rustracer_core::sampledump::{SampleDumpOptions, Strategy};
+use rustracer_core::scene::Scene;
+use rustracer_core::spectrum::Spectrum;
+use rustracer_core::{init_stats, PbrtOptions, Point2i, Point3f, Transform, Vector3f};
+
+/// Render `scene` in memory and return its pixels, in scanline order.
+fn render_scene(scene: &str, opts: PbrtOptions) -> Vec<Spectrum> {
+    init_stats();
+    let opts = PbrtOptions {
+        defer_render: true,
+        ..opts
+    };
+    let mut context = pbrt::parse_scene_string(opts, scene).unwrap().unwrap();
+    context.render_in_memory();
+    let rgb = context.camera.get_film().rgb();
+    rgb.chunks(3)
+        .map(|c| Spectrum::rgb(c[0], c[1], c[2]))
+        .collect()
+}
+
+fn threads(num_threads: u8) -> PbrtOptions {
+    PbrtOptions {
+        num_threads,
+        ..PbrtOptions::default()
+    }
+}
+
+#[test]
+fn empty_scene_renders_the_environment() {
+    init_stats();
+    let bvh = BVH::new(4, &[], SplitMethod::SAH);
+    let scene = Scene::new(Arc::new(bvh), Vec::new());
+    let mut ray = Ray::new(Point3f::new(0.0, 0.0, -5.0), Vector3f::new(0.0, 0.0, 1.0));
+    assert!(scene.intersect(&mut ray).is_none());
+    assert!(!scene.intersect_p(&ray));
+
+    let pixels = render_scene(
+        r#"
+Film "image" "integer xresolution" [4] "integer yresolution" [4]
+Sampler "02sequence" "integer pixelsamples" [1]
+Integrator "path"
+WorldBegin
+LightSource "infinite" "rgb L" [0.5 0.5 0.5]
+WorldEnd
+"#,
+        threads(1),
+    );
+    assert!(
+        pixels
+            .iter()
+            .all(|s| (0..3).all(|c| (s[c] - 0.5).abs() < 1e-3)),
+        "{:?}",
+        pixels
+    );
+}
+
+#[test]
+fn motion_vectors_point_to_the_previous_frame() {
+    init_stats();
+    let opts = PbrtOptions {
+        num_threads: 2,
+        defer_render: true,
+        ..PbrtOptions::default()
+    };
+    // A wall facing the camera, of which a pixel covers 5 tan(20°) / 6 units at the centre
+    let scene = r#"
+LookAt 0 0 5  0 0 0  0 1 0
+Camera "perspective" "float fov" [40]
+Film "image" "integer xresolution" [16] "integer yresolution" [12] "bool jitter" "false"
+    "string aovs" ["P" "motion"] "string aovexpressions" ["position" "motion"]
+PixelFilter "box"
+Sampler "02sequence" "integer pixelsamples" [1]
+Integrator "directlighting"
+WorldBegin
+Shape "trianglemesh" "integer indices" [0 1 2 0 2 3]
+    "point P" [-20 -20 0  20 -20 0  20 20 0  -20 20 0]
+WorldEnd
+"#;
+    let mut context = pbrt::parse_scene_string(opts, scene).unwrap().unwrap();
+    context.render_in_memory();
+    let film = context.camera.get_film();
+    let previous_positions = film.aov_rgb(0);
+    assert!(film.aov_rgb(1).iter().all(|&v| v == 0.0));
+
+    // Move the camera sideways by 2 pixels
+    let shift = 2.0 * 5.0 * 20f32.to_radians().tan() / 6.0;
+    context.camera.end_frame();
+    context.camera.set_camera_to_world(
+        Transform::look_at(
+            &Point3f::new(shift, 0.0, 5.0),
+            &Point3f::new(shift, 0.0, 0.0),
+            &Vector3f::new(0.0, 1.0, 0.0),
+        )
+        .inverse(),
+    );
+    context.render_in_memory();
+    let film = context.camera.get_film();
+    let (positions, motion) = (film.aov_rgb(0), film.aov_rgb(1));
+    for y in 0..12 {
+        for x in 2..14 {
+            let i = 3 * (y * 16 + x);
+            assert!(
+                (motion[i].abs() - 2.0).abs() < 1e-3,
+                "{:?}",
+                &motion[i..i + 3]
+            );
+            assert!(motion[i + 1].abs() < 1e-3 && motion[i + 2] == 0.0);
+            // The point seen through the pixel was seen where its motion vector points to
+            let previous = 3 * (y * 16 + (x as f32 + motion[i]).round() as usize);
+            for c in 0..3 {
+                assert!(
+                    (positions[i + c] - previous_positions[previous + c]).abs() < 1e-3,
+                    "{}, {}: {:?}",
+                    x,
+                    y,
+                    &positions[i..i + 3]
+                );
+            }
+        }
+    }
+}
+
+/// A diffuse sphere lit by a point light, seen by the given camera.
+fn point_lit_scene(camera: &str, film_params: &str, spp: i32) -> String {
+    format!(
+        r#"
+LookAt 0 0 5  0 0 0  0 1 0
+Camera {} "float fov" [30]
+Film "image" "integer xresolution" [16] "integer yresolution" [12] {}
+PixelFilter "box"
+Sampler "02sequence" "integer pixelsamples" [{}]
+Integrator "directlighting"
+WorldBegin
+LightSource "point" "point from" [0 3 3] "rgb I" [20 20 20]
+Shape "sphere" "float radius" [1]
+WorldEnd
+"#,
+        camera, film_params, spp
+    )
+}
+
+#[test]
+fn stereo_views_are_rendered_side_by_side() {
+    // The scene is symmetric, so the views converging on the sphere mirror each other
+    let film = "\"bool jitter\" \"false\"";
+    let stereo = render_scene(
+        &point_lit_scene(
+            "\"stereo\" \"float interocular\" [0.5] \"float convergence\" [5] \"string layout\" \"sidebyside\"",
+            film,
+            1,
+        ),
+        threads(2),
+    );
+    for y in 0..12 {
+        for x in 0..8 {
+            let (a, b) = (stereo[y * 16 + x], stereo[y * 16 + 15 - x]);
+            assert!(
+                (0..3).all(|c| (a[c] - b[c]).abs() < 1e-4),
+                "({}, {}): {} != {}",
+                x,
+                y,
+                a,
+                b
+            );
+        }
+    }
+    // ... but differ from each other
+    assert!((0..12 * 8).any(|i| {
+        let (y, x) = (i / 8, i % 8);
+        stereo[y * 16 + x].r != stereo[y * 16 + x + 8].r
+    }));
+
+    // Separate views are written to their own images
+    let dir = std::env::temp_dir().join("rustracer_stereo");
+    let opts = PbrtOptions {
+        defer_render: true,
+        ..PbrtOptions::default()
+    };
+    let scene = format!(
+        "Camera \"stereo\"\nFilm \"image\" \"integer xresolution\" [16] \"integer yresolution\" [12] \"string filename\" \"{}\"\nSampler \"02sequence\"\nWorldBegin\nShape \"sphere\"\nWorldEnd\n",
+        dir.join("stereo.exr").display()
+    );
+    let context = pbrt::parse_scene_string(opts, &scene).unwrap().unwrap();
+    let film = context.camera.get_film();
+    film.write_image().unwrap();
+    let filename = std::path::Path::new(&film.filename);
+    for view in &["L", "R"] {
+        let path = filename.with_file_name(format!("rt-stereo_{}.exr", view));
+        let (_, resolution) = imageio::read_image(&path).unwrap();
+        assert_eq!(resolution, Point2i::new(8, 12));
+    }
+    assert!(!filename.exists());
+}
+
+#[test]
+fn cryptomatte_mattes_identify_objects_and_materials() {
+    use exr::prelude::{AttributeValue, FlatSamples};
+    use rustracer_core::cryptomatte;
+
+    init_stats();
+    let dir = std::env::temp_dir().join("rustracer_cryptomatte");
+    let opts = PbrtOptions {
+        num_threads: 2,
+        defer_render: true,
+        ..PbrtOptions::default()
+    };
+    let scene = format!(
+        r#"
+LookAt 0 0 5  0 0 0  0 1 0
+Camera "perspective" "float fov" [30]
+Film "image" "integer xresolution" [15] "integer yresolution" [11] "bool jitter" "false"
+    "bool cryptomatte" "true" "integer cryptomattedepth" [2] "string filename" "{}"
+PixelFilter "box"
+Sampler "02sequence" "integer pixelsamples" [1]
+Integrator "directlighting"
+WorldBegin
+MakeNamedMaterial "gold" "string type" "metal"
+AttributeBegin
+Translate -1 0 0
+Shape "sphere" "float radius" [0.5] "string name" "ball"
+AttributeEnd
+AttributeBegin
+Translate 1 0 0
+NamedMaterial "gold"
+Shape "sphere" "float radius" [0.5]
+AttributeEnd
+WorldEnd
+"#,
+        dir.join("mattes.exr").display()
+    );
+    let mut context = pbrt::parse_scene_string(opts, &scene).unwrap().unwrap();
+    context.render_in_memory();
+    let film = context.camera.get_film();
+    film.write_image().unwrap();
+
+    let image =
+        exr::prelude::read_first_flat_layer_from_file(film::cryptomatte_filename(&film.filename))
+            .unwrap();
+    let channel = |name: &str| -> Vec<f32> {
+        let channel = image
+            .layer_data
+            .channel_data
+            .list
+            .iter()
+            .find(|c| c.name == *name)
+            .unwrap_or_else(|| panic!("No channel {}", name));
+        match &channel.sample_data {
+            FlatSamples::F32(values) => values.clone(),
+            _ => panic!("Channel {} isn't made of floats", name),
+        }
+    };
+    let (objects, materials) = (channel("CryptoObject00.R"), channel("CryptoMaterial00.R"));
+    let object_coverage = channel("CryptoObject00.G");
+    // pbrt's camera space is left-handed: the named sphere is on the right of the image
+    let (named, unnamed) = (5 * 15 + 11, 5 * 15 + 3);
+
+    assert_eq!(objects[named], cryptomatte::name_to_id("ball"));
+    assert_eq!(materials[named], cryptomatte::name_to_id("matte"));
+    assert_eq!(object_coverage[named], 1.0);
+    // The second shape is named after its type and position in the scene
+    assert_eq!(objects[unnamed], cryptomatte::name_to_id("sphere.1"));
+    assert_eq!(materials[unnamed], cryptomatte::name_to_id("gold"));
+    // Nothing is hit in the corners
+    assert_eq!((objects[0], object_coverage[0]), (0.0, 0.0));
+
+    // The names are in the manifests of the file's metadata
+    let manifests: Vec<String> = image
+        .layer_data
+        .attributes
+        .other
+        .iter()
+        .filter_map(|(name, value)| match value {
+            AttributeValue::Text(text) if name.to_string().ends_with("/manifest") => {
+                Some(text.to_string())
+            }
+            _ => None,
+        })
+        .collect();
+    assert_eq!(manifests.len(), 2);
+    for name in &["ball", "sphere.1", "matte", "gold"] {
+        let entry = format!(
+            "\"{}\":\"{:08x}\"",
+            name,
+            cryptomatte::name_to_id(name).to_bits()
+        );
+        assert!(
+            manifests.iter().any(|m| m.contains(&entry)),
+            "{:?}",
+            manifests
+        );
+    }
+}
+
+#[test]
+fn dumped_samples_add_up_to_their_radiance() {
+    init_stats();
+    let dir = std::env::temp_dir().join("rustracer_sampledump");
+    let path = dir.join("samples.csv");
+    let opts = PbrtOptions {
+        num_threads: 2,
+        defer_render: true,
+        dump_samples: Some(SampleDumpOptions {
+            pixels: Bounds2i::from_elements(6, 4, 9, 6),
+            path: path.clone(),
+        }),
+        ..PbrtOptions::default()
+    };
+    let scene = format!(
+        r#"
+LookAt 0 4 4  0 0 0  0 1 0
+Camera "perspective" "float fov" [40]
+Film "image" "integer xresolution" [16] "integer yresolution" [12] "string filename" "{}"
+Sampler "02sequence" "integer pixelsamples" [8]
+Integrator "path" "integer maxdepth" [3]
+WorldBegin
+AttributeBegin
+Translate 0 3 0
+AreaLightSource "diffuse" "rgb L" [4 4 4]
+Shape "sphere" "float radius" [0.5]
+AttributeEnd
+Shape "trianglemesh" "integer indices" [0 1 2 0 2 3]
+    "point P" [-5 0 -5  5 0 -5  5 0 5  -5 0 5]
+WorldEnd
+"#,
+        dir.join("image.exr").display()
+    );
+    let mut context = pbrt::parse_scene_string(opts, &scene).unwrap().unwrap();
+    context.render_in_memory();
+    let film = context.camera.get_film();
+    let records = film.sample_dump().unwrap().records();
+    assert_eq!(records.len(), 3 * 2 * 8);
+    assert_eq!(
+        (records[0].pixel, records[0].sample),
+        (Point2i::new(6, 4), 0)
+    );
+    let mut strategies = Vec::new();
+    for r in &records {
+        let total = r
+            .contributions
+            .iter()
+            .fold(Spectrum::black(), |sum, c| sum + c.value);
+        assert!(
+            (total - r.radiance).y().abs() < 1e-4 * r.radiance.y().max(1.0),
+            "{:?}",
+            r
+        );
+        for c in &r.contributions {
+            // The area light is the only light
+            assert_eq!(c.light, 0);
+            assert!(c.length >= 1 && c.length <= r.path_length + 1, "{:?}", r);
+            assert!(c.mis_weight > 0.0 && c.mis_weight <= 1.0, "{:?}", r);
+            strategies.push(c.strategy);
+        }
+    }
+    assert!(strategies.contains(&Strategy::LightSample));
+    assert!(strategies.contains(&Strategy::BsdfSample));
+
+    // One row per contribution, or per sample without any
+    film.write_image().unwrap();
+    let csv = std::fs::read_to_string(&path).unwrap();
+    let rows: usize = records.iter().map(|r| r.contributions.len().max(1)).sum();
+    assert_eq!(csv.lines().count(), 1 + rows);
+}
@@ -4,7 +4,7 @@ use std::f32;
 use rustracer_core::ray::Ray;
 use rustracer_core::sampling;
 use rustracer_core::shapes::{Shape, Sphere};
-use rustracer_core::{Point2f, Point3f, Transform};
+use rustracer_core::{Point2f, Point3f, Transform, Vector3f};
 
 fn pexp<T: Rng>(rng: &mut T, exp: f32) -> f32 {
     // let range = Range::new(-exp, exp);
@@ -52,3 +52,28 @@ fn test_reintersection_convex<T: Shape>(shape: &T, rng: &mut StdRng) {
         }
     }
 }
+
+#[test]
+fn sampled_ray_differentials() {
+    let sphere = Sphere::new(Transform::default(), 1.0, -1.0, 1.0, 360.0, false);
+    let ray = Ray::new(Point3f::new(0.0, 0.0, 5.0), Vector3f::new(0.0, 0.0, -1.0));
+    let (isect, _) = sphere.intersect(&ray).unwrap();
+    let dir = Vector3f::new(0.3, 0.2, 1.0);
+
+    let spread = |r: &Ray| {
+        let diff = r.differential.unwrap();
+        let d = r.d.normalize();
+        f32::max(
+            1.0 - d.dot(&diff.rx_direction),
+            1.0 - d.dot(&diff.ry_direction),
+        )
+    };
+    // Lower density samples stand for a wider solid angle
+    let diffuse = isect.spawn_sampled_ray(&dir, 1.0 / f32::consts::PI, false);
+    let glossy = isect.spawn_sampled_ray(&dir, 100.0, false);
+    assert!(spread(&diffuse) > spread(&glossy));
+    assert!(spread(&glossy) > 0.0);
+    // ... and specular samples don't spread at all
+    let specular = isect.spawn_sampled_ray(&dir, 1.0, true);
+    assert!(spread(&specular).abs() < 1e-6);
+}
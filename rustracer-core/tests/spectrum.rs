@@ -0,0 +1,112 @@
+use rustracer_core::cie::{
+    adapt_xyz, bradford_adaptation, xyy_to_xyz, xyz_to_xyy, ColourSpace, D65_WHITE_XYZ,
+};
+use rustracer_core::spectrum::{
+    blackbody_colour, blackbody_white_point, CoefficientSpectrum, Spectrum,
+};
+
+fn assert_close(a: &[f32; 3], b: &[f32; 3], eps: f32) {
+    for i in 0..3 {
+        assert!((a[i] - b[i]).abs() < eps, "{:?} != {:?}", a, b);
+    }
+}
+
+#[test]
+fn blackbody_colour_has_unit_luminance() {
+    for t in &[1000.0, 2700.0, 5500.0, 6500.0, 10000.0] {
+        let c = blackbody_colour(*t);
+        assert!((c.y() - 1.0).abs() < 1e-3, "{}K: {:?}", t, c);
+    }
+}
+
+#[test]
+fn blackbody_colour_gets_bluer_with_temperature() {
+    let warm = blackbody_colour(2700.0);
+    let cold = blackbody_colour(10000.0);
+    assert!(warm.r > warm.b);
+    assert!(cold.b > cold.r);
+    assert!(warm.b / warm.r < cold.b / cold.r);
+}
+
+#[test]
+fn xyy_round_trip() {
+    let xyz = Spectrum::rgb(0.2, 0.5, 0.8).to_xyz();
+    assert_close(&xyy_to_xyz(&xyz_to_xyy(&xyz)), &xyz, 1e-5);
+
+    // D65 chromaticity
+    let white = Spectrum::white().to_xyy();
+    assert_close(&white, &[0.3127, 0.3290, 1.0], 1e-4);
+    // Black keeps the white chromaticity
+    assert_close(&xyz_to_xyy(&[0.0; 3]), &[white[0], white[1], 0.0], 1e-6);
+    let s = Spectrum::from_xyy(&[white[0], white[1], 0.5]);
+    assert_close(&[s.r, s.g, s.b], &[0.5, 0.5, 0.5], 1e-4);
+}
+
+#[test]
+fn bradford_adaptation_maps_whites() {
+    // Adapting to the same white is a no-op
+    let identity = bradford_adaptation(&D65_WHITE_XYZ, &D65_WHITE_XYZ);
+    let xyz = [0.3, 0.4, 0.5];
+    assert_close(&adapt_xyz(&identity, &xyz), &xyz, 1e-5);
+
+    // The source white maps to the destination white, preserving luminance
+    let tungsten = blackbody_white_point(2700.0);
+    let m = bradford_adaptation(&tungsten, &D65_WHITE_XYZ);
+    assert_close(&adapt_xyz(&m, &tungsten), &D65_WHITE_XYZ, 1e-4);
+    let half = [tungsten[0] * 0.5, 0.5, tungsten[2] * 0.5];
+    let adapted = Spectrum::from_xyz(&adapt_xyz(&m, &half));
+    assert_close(&[adapted.r, adapted.g, adapted.b], &[0.5, 0.5, 0.5], 1e-3);
+}
+
+#[test]
+fn colour_spaces() {
+    for cs in &[ColourSpace::Srgb, ColourSpace::AcesCg, ColourSpace::Rec2020] {
+        assert_eq!(ColourSpace::from_name(cs.name()), Some(*cs));
+        // The matrices are inverses of each other
+        let rgb = [0.2, 0.5, 0.8];
+        assert_close(&cs.from_xyz(&cs.to_xyz(&rgb)), &rgb, 1e-4);
+        // The white point is RGB white
+        assert_close(&cs.from_xyz(&cs.white()), &[1.0, 1.0, 1.0], 1e-3);
+        // ...with the chromaticity given for it
+        let [_, _, _, w] = cs.chromaticities();
+        assert_close(&xyz_to_xyy(&cs.white()), &[w[0], w[1], 1.0], 1e-4);
+        // The primaries have the given chromaticities
+        for (i, primary) in cs.chromaticities()[..3].iter().enumerate() {
+            let mut rgb = [0.0; 3];
+            rgb[i] = 1.0;
+            let xyy = xyz_to_xyy(&cs.to_xyz(&rgb));
+            assert_close(&[xyy[0], xyy[1], 0.0], &[primary[0], primary[1], 0.0], 1e-3);
+        }
+    }
+    assert_eq!(ColourSpace::from_name("adobergb"), None);
+
+    // sRGB colours are inside the wider gamuts
+    let red = Spectrum::rgb(1.0, 0.0, 0.0).to_xyz();
+    for cs in &[ColourSpace::AcesCg, ColourSpace::Rec2020] {
+        let rgb = cs.from_xyz(&adapt_xyz(
+            &bradford_adaptation(&D65_WHITE_XYZ, &cs.white()),
+            &red,
+        ));
+        assert!(rgb.iter().all(|&c| c > 0.0), "{:?}: {:?}", cs, rgb);
+        assert!(rgb[0] < 1.0);
+    }
+}
+
+fn mix<S: CoefficientSpectrum>(a: S, b: S) -> S {
+    S::lerp(0.25, a, b).clamp(0.0, 1.0).sqrt()
+}
+
+#[test]
+fn coefficient_spectrum_is_generic_over_representation() {
+    assert_eq!(mix(0.0f32, 4.0f32), 1.0);
+    let s = mix(Spectrum::rgb(0.0, 0.0, 8.0), Spectrum::rgb(4.0, -4.0, 8.0));
+    assert_eq!(s, Spectrum::rgb(1.0, 0.0, 1.0));
+    assert_eq!(CoefficientSpectrum::max_component_value(&s), 1.0);
+    assert!(CoefficientSpectrum::is_black(&Spectrum::black()));
+    assert!(!CoefficientSpectrum::is_black(&s));
+    assert_eq!(CoefficientSpectrum::y(&2.0f32), 2.0);
+    assert_eq!(
+        Spectrum::rgb(-1.0, 2.0, 0.5).clamp(),
+        Spectrum::rgb(0.0, 2.0, 0.5)
+    );
+}
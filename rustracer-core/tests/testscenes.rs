@@ -0,0 +1,59 @@
+use rustracer_core::testscenes::{self, TEST_SCENES};
+use rustracer_core::{init_stats, pbrt, PbrtOptions};
+
+fn render(scene: &str, params: &[(&str, &str)]) -> Vec<f32> {
+    init_stats();
+    let params: Vec<_> = params
+        .iter()
+        .map(|(n, v)| (n.to_string(), v.to_string()))
+        .collect();
+    let scene = testscenes::find(scene).unwrap().generate(&params).unwrap();
+    let opts = PbrtOptions {
+        num_threads: 2,
+        defer_render: true,
+        ..PbrtOptions::default()
+    };
+    let mut context = pbrt::parse_scene_string(opts, &scene).unwrap().unwrap();
+    context.render_in_memory();
+    context.camera.get_film().rgb()
+}
+
+#[test]
+fn all_test_scenes_render() {
+    for scene in TEST_SCENES {
+        let rgb = render(scene.name, &[("xres", "16"), ("yres", "8"), ("spp", "1")]);
+        assert!(rgb.iter().all(|v| v.is_finite()), "{}", scene.name);
+        assert!(rgb.iter().any(|v| *v > 0.0), "{}", scene.name);
+    }
+}
+
+#[test]
+fn white_furnace_is_uniform() {
+    let rgb = render("furnace", &[("xres", "16"), ("yres", "16"), ("spp", "16")]);
+    let mean = rgb.iter().sum::<f32>() / rgb.len() as f32;
+    assert!((mean - 0.5).abs() < 0.01, "mean = {}", mean);
+    assert!(
+        rgb.iter().all(|v| (v - 0.5).abs() < 0.1),
+        "min = {}, max = {}",
+        rgb.iter().cloned().fold(f32::INFINITY, f32::min),
+        rgb.iter().cloned().fold(0.0, f32::max)
+    );
+}
+
+#[test]
+fn grey_furnace_darkens_the_sphere() {
+    let rgb = render(
+        "furnace",
+        &[
+            ("xres", "16"),
+            ("yres", "16"),
+            ("spp", "16"),
+            ("albedo", "0.5"),
+        ],
+    );
+    // The centre of the image is on the sphere, which reflects half of the environment
+    let centre = 3 * (8 * 16 + 8);
+    assert!((rgb[centre] - 0.25).abs() < 0.05, "{}", rgb[centre]);
+    // The corners see the environment directly
+    assert!((rgb[0] - 0.5).abs() < 1e-3, "{}", rgb[0]);
+}
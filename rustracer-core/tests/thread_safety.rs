@@ -0,0 +1,22 @@
+use rustracer_core::light::{AreaLightRef, LightRef};
+use rustracer_core::material::MaterialRef;
+use rustracer_core::primitive::PrimitiveRef;
+use rustracer_core::scene::Scene;
+use rustracer_core::shapes::ShapeRef;
+use rustracer_core::spectrum::Spectrum;
+use rustracer_core::texture::TextureRef;
+
+fn assert_send_sync<T: Send + Sync + ?Sized>() {}
+
+/// The scene and everything it references are shared by the rendering threads.
+#[test]
+fn scene_objects_are_send_and_sync() {
+    assert_send_sync::<ShapeRef>();
+    assert_send_sync::<PrimitiveRef>();
+    assert_send_sync::<MaterialRef>();
+    assert_send_sync::<LightRef>();
+    assert_send_sync::<AreaLightRef>();
+    assert_send_sync::<TextureRef<f32>>();
+    assert_send_sync::<TextureRef<Spectrum>>();
+    assert_send_sync::<Scene>();
+}